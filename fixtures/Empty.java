@@ -0,0 +1 @@
+public record Empty() {}
@@ -0,0 +1,75 @@
+import java.io.IOException;
+import java.util.ArrayList;
+import java.util.List;
+import java.util.function.Supplier;
+
+public class Kitchen<T extends Comparable<T>> {
+
+    public static final long LONG_CONSTANT = 1234567890123L;
+    public static final double DOUBLE_CONSTANT = 3.14159;
+    public static final String STRING_CONSTANT = "caf\u00e9 \u0000 \ud83d\ude00";
+    public static final char CHAR_CONSTANT = 'c';
+
+    private final List<T> items = new ArrayList<>();
+    @Deprecated
+    protected volatile int counter;
+
+    public record Point(int x, int y) {}
+
+    public interface Visitor {
+        void visit(Object value);
+    }
+
+    public class Inner {
+        int read() {
+            return counter;
+        }
+    }
+
+    public void add(T item) throws IOException {
+        if (item == null) throw new IOException("null");
+        items.add(item);
+    }
+
+    public int loop(int[] values, double scale) {
+        int total = 0;
+        for (int value : values) {
+            if (value > 10) {
+                total += (int) (value * scale);
+            } else {
+                total -= value;
+            }
+        }
+        return total;
+    }
+
+    public String tryCatch(String input) {
+        try {
+            return input.substring(1);
+        } catch (IndexOutOfBoundsException | NullPointerException exception) {
+            return "bad";
+        } finally {
+            counter++;
+        }
+    }
+
+    public int lookup(int key) {
+        switch (key) {
+            case 1: return 10;
+            case 2: return 20;
+            case 1000: return 30;
+            default: return -1;
+        }
+    }
+
+    public Supplier<String> lambda(String prefix) {
+        Runnable ignored = () -> counter++;
+        return () -> prefix + items.size();
+    }
+
+    public synchronized long wide(long a, long b) {
+        long[][] grid = new long[3][4];
+        grid[1][2] = a * b;
+        return grid[1][2] + LONG_CONSTANT;
+    }
+}
@@ -0,0 +1,18 @@
+import java.util.ArrayList;
+import java.util.List;
+
+// Compiled with javac -g, then each LineNumberTable, LocalVariableTable and LocalVariableTypeTable was split in two.
+public class SplitTables {
+    public static int sum(int count) {
+        List<Integer> values = new ArrayList<>();
+        for (int i = 0; i < count; i++) {
+            values.add(i);
+        }
+        List<Integer> copy = values.subList(0, values.size());
+        int total = 0;
+        for (int value : copy) {
+            total += value;
+        }
+        return total;
+    }
+}
@@ -0,0 +1,4 @@
+public class Surrogates {
+    public static final String HIGH = "\uD800";
+    public static final String LOW = "x\uDC00y";
+}
@@ -3,7 +3,7 @@ extern crate proc_macro;
 use proc_macro::TokenStream;
 use proc_macro2::TokenStream as TokenStream2;
 use quote::{format_ident, quote};
-use syn::{DeriveInput, ItemStruct, parse_macro_input};
+use syn::{AttributeArgs, DeriveInput, FnArg, ItemFn, ItemStruct, Lit, NestedMeta, parse_macro_input};
 
 #[proc_macro_derive(Nameable)]
 pub fn derive_nameable(input: TokenStream) -> TokenStream {
@@ -50,6 +50,22 @@ pub fn derive_versioned(input: TokenStream) -> TokenStream {
     })
 }
 
+#[proc_macro_derive(Attributed)]
+pub fn derive_attributed(input: TokenStream) -> TokenStream {
+    let name = parse_macro_input!(input as DeriveInput).ident;
+    TokenStream::from(quote! {
+        impl #name {
+            pub fn attributes(&self) -> &[crate::class_file::attributes::Attribute] {
+                self.attributes.as_slice()
+            }
+
+            pub fn get_attribute(&self, name: &str) -> Option<&crate::class_file::attributes::Attribute> {
+                self.attributes.iter().find(|attribute| attribute.name() == name)
+            }
+        }
+    })
+}
+
 fn gen_impl(input: TokenStream, function: TokenStream2) -> TokenStream {
     let name = parse_macro_input!(input as DeriveInput).ident;
     TokenStream::from(quote! {
@@ -88,3 +104,52 @@ fn gen_accessible_function(name: &str) -> TokenStream2 {
         }
     }
 }
+
+// Turns a function that takes a NativeEnv followed by the native's parameters as Rust types in to a
+// NativeMethod named after the function in upper case, for the native with the given class, name and
+// descriptor. The glue it generates reads the parameters in order and converts what the function
+// returns in to a NativeResult.
+#[proc_macro_attribute]
+pub fn native(attribute: TokenStream, input: TokenStream) -> TokenStream {
+    let attribute = parse_macro_input!(attribute as AttributeArgs);
+    let function = parse_macro_input!(input as ItemFn);
+    let strings = attribute.iter()
+        .map(|argument| match argument {
+            NestedMeta::Lit(Lit::Str(value)) => value,
+            _ => panic!("Invalid native! Expected the class, name and descriptor as strings!")
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(strings.len(), 3, "Invalid native! Expected the class, name and descriptor as strings!");
+    let (class, name, descriptor) = (strings[0], strings[1], strings[2]);
+
+    let visibility = &function.vis;
+    let function_name = &function.sig.ident;
+    let constant_name = format_ident!("{}", function_name.to_string().to_uppercase());
+    let types = function.sig.inputs.iter()
+        .skip(1)
+        .map(|input| match input {
+            FnArg::Typed(parameter) => &parameter.ty,
+            FnArg::Receiver(_) => panic!("Invalid native {}! Natives cannot take self!", function_name)
+        })
+        .collect::<Vec<_>>();
+    let names = (0..types.len()).map(|index| format_ident!("parameter{}", index)).collect::<Vec<_>>();
+    TokenStream::from(quote! {
+        #function
+
+        #visibility const #constant_name: crate::code::NativeMethod = crate::code::NativeMethod {
+            class: #class,
+            name: #name,
+            descriptor: #descriptor,
+            function: {
+                fn glue(
+                    env: &mut crate::code::NativeEnv,
+                    arguments: &mut crate::code::Arguments
+                ) -> crate::code::NativeResult {
+                    #(let #names = <#types as crate::code::FromArguments>::from_arguments(arguments);)*
+                    crate::code::IntoNativeResult::into_native_result(#function_name(env, #(#names),*))
+                }
+                glue
+            }
+        };
+    })
+}
@@ -0,0 +1,450 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+
+use bytes::{BufMut, Bytes, BytesMut};
+use std::collections::HashMap;
+use std::sync::Arc;
+use crate::utils::IStr;
+use crate::class_file::{ClassLoader, write_attribute};
+use crate::class_file::verification::StackMapTable;
+use crate::constants::*;
+use crate::types::Class;
+use crate::utils::constants::JAVA_LANG_OBJECT_NAME;
+use crate::utils::descriptors::{FieldDescriptor, MethodDescriptor};
+use crate::verifier::{ClassHierarchy, ClassInfo, generate_stack_map};
+use super::AssembleError;
+use super::code::{CodeBuilder, Constant, MethodContext};
+use super::pool::PoolBuilder;
+
+// Builds a class file from scratch. Classes default to being public, extending Object, and using
+// the latest class file version we support.
+#[derive(Debug)]
+pub struct ClassBuilder {
+    name: String,
+    super_name: Option<String>,
+    interfaces: Vec<String>,
+    access_flags: u16,
+    major_version: u16,
+    minor_version: u16,
+    source_file: Option<String>,
+    fields: Vec<FieldBuilder>,
+    methods: Vec<MethodBuilder>
+}
+
+impl ClassBuilder {
+    // Classes extend java/lang/Object unless they say otherwise, apart from java/lang/Object itself.
+    pub fn new(name: &str) -> Self {
+        ClassBuilder {
+            name: name.to_string(),
+            super_name: Some(String::from("java/lang/Object")).filter(|object| object != name),
+            interfaces: Vec::new(),
+            access_flags: (JVM_ACC_PUBLIC | JVM_ACC_SUPER) as u16,
+            major_version: JAVA_VERSION_17,
+            minor_version: 0,
+            source_file: None,
+            fields: Vec::new(),
+            methods: Vec::new()
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    pub fn access_flags(&mut self, access_flags: u16) -> &mut Self {
+        self.access_flags = access_flags;
+        self
+    }
+
+    pub fn version(&mut self, major_version: u16, minor_version: u16) -> &mut Self {
+        self.major_version = major_version;
+        self.minor_version = minor_version;
+        self
+    }
+
+    // Only java/lang/Object has no super class.
+    pub fn super_class(&mut self, name: Option<&str>) -> &mut Self {
+        self.super_name = name.map(str::to_string);
+        self
+    }
+
+    pub fn interface(&mut self, name: &str) -> &mut Self {
+        self.interfaces.push(name.to_string());
+        self
+    }
+
+    pub fn source_file(&mut self, name: &str) -> &mut Self {
+        self.source_file = Some(name.to_string());
+        self
+    }
+
+    pub fn field(&mut self, access_flags: u16, name: &str, descriptor: &str) -> &mut FieldBuilder {
+        self.fields.push(FieldBuilder {
+            access_flags,
+            name: name.to_string(),
+            descriptor: descriptor.to_string(),
+            constant_value: None
+        });
+        self.fields.last_mut().unwrap()
+    }
+
+    pub fn method(&mut self, access_flags: u16, name: &str, descriptor: &str) -> &mut MethodBuilder {
+        let abstract_or_native = (access_flags as u32) & (JVM_ACC_ABSTRACT | JVM_ACC_NATIVE) != 0;
+        self.methods.push(MethodBuilder {
+            access_flags,
+            name: name.to_string(),
+            descriptor: descriptor.to_string(),
+            exceptions: Vec::new(),
+            code: if abstract_or_native { None } else { Some(CodeBuilder::new()) }
+        });
+        self.methods.last_mut().unwrap()
+    }
+
+    pub(crate) fn method_count(&self) -> usize {
+        self.methods.len()
+    }
+
+    pub(crate) fn method_at(&mut self, index: usize) -> &mut MethodBuilder {
+        &mut self.methods[index]
+    }
+
+    // Builds the class knowing only about the class itself. Methods that need other classes to verify, like
+    // ones that catch exceptions, are left without frames, so classes that will be verified should be
+    // built with a hierarchy.
+    pub fn build(&self) -> Result<Bytes, AssembleError> {
+        self.build_with(&HashMap::<String, ClassInfo>::new())
+    }
+
+    // Builds the class, asking the hierarchy what the classes it uses have in common for the stack map
+    // frames, like a class loader with the classes this one refers to.
+    pub fn build_with(&self, hierarchy: &dyn ClassHierarchy) -> Result<Bytes, AssembleError> {
+        let mut pool = PoolBuilder::new();
+        let bytes = self.write(&mut pool, &[])?;
+        if self.major_version < JAVA_VERSION_6 {
+            return Ok(bytes);
+        }
+
+        // The verifier works out the frames from the class as it was written without them. The second
+        // build adds to the same pool, so the constants in the code get the same indices, and the code
+        // has the same layout as the first time. Code that doesn't verify is still assembled, for running
+        // unverified or testing the verifier, but it's left without frames, since there's no telling
+        // what they should be.
+        let class = Class::from_bytes(Arc::new(ClassLoader::new()), bytes.clone());
+        let hierarchy = BuiltHierarchy {
+            name: &self.name,
+            info: ClassInfo {
+                super_class: self.super_name.as_deref().map(IStr::new),
+                is_interface: (self.access_flags as u32) & JVM_ACC_INTERFACE != 0
+            },
+            hierarchy
+        };
+        let mut error = None;
+        let mut stack_maps = Vec::with_capacity(class.methods().len());
+        for method in class.methods() {
+            let table = generate_stack_map(&class, method, &hierarchy, &mut |name| {
+                pool.class(name).unwrap_or_else(|value| {
+                    error.get_or_insert(value);
+                    0
+                })
+            });
+            stack_maps.push(table.unwrap_or_else(|_| StackMapTable::new(Vec::new())));
+        }
+        if let Some(error) = error {
+            return Err(error);
+        }
+        if stack_maps.iter().all(StackMapTable::is_empty) {
+            return Ok(bytes);
+        }
+        self.write(&mut pool, &stack_maps)
+    }
+
+    fn write(&self, pool: &mut PoolBuilder, stack_maps: &[StackMapTable]) -> Result<Bytes, AssembleError> {
+        let mut body = BytesMut::new();
+        body.put_u16(self.access_flags);
+        body.put_u16(pool.class(&self.name)?);
+        match &self.super_name {
+            Some(name) => body.put_u16(pool.class(name)?),
+            None => body.put_u16(0)
+        }
+        body.put_u16(self.interfaces.len() as u16);
+        for interface in &self.interfaces {
+            body.put_u16(pool.class(interface)?);
+        }
+        body.put_u16(self.fields.len() as u16);
+        for field in &self.fields {
+            field.write(pool, &mut body)?;
+        }
+        body.put_u16(self.methods.len() as u16);
+        for (index, method) in self.methods.iter().enumerate() {
+            method.write(pool, &mut body, stack_maps.get(index))?;
+        }
+        match &self.source_file {
+            Some(name) => {
+                body.put_u16(1);
+                let name_index = pool.utf8(JVM_ATTRIBUTE_SOURCE_FILE)?;
+                let value_index = pool.utf8(name)?;
+                write_attribute(&mut body, name_index, |buf| buf.put_u16(value_index));
+            },
+            None => body.put_u16(0)
+        }
+
+        let mut buf = BytesMut::new();
+        buf.put_u32(JAVA_CLASS_FILE_MAGIC);
+        buf.put_u16(self.minor_version);
+        buf.put_u16(self.major_version);
+        pool.write(&mut buf);
+        buf.put_slice(&body);
+        Ok(buf.freeze())
+    }
+}
+
+// The hierarchy the builder was given, along with the class being built, which the hierarchy won't know
+// about yet.
+struct BuiltHierarchy<'a> {
+    name: &'a str,
+    info: ClassInfo,
+    hierarchy: &'a dyn ClassHierarchy
+}
+
+impl ClassHierarchy for BuiltHierarchy<'_> {
+    fn lookup(&self, name: &str) -> Option<ClassInfo> {
+        if name == self.name {
+            return Some(self.info.clone());
+        }
+        self.hierarchy.lookup(name)
+    }
+
+    fn is_subclass(&self, name: &str, other: &str) -> bool {
+        if name != self.name || name == other {
+            return self.hierarchy.is_subclass(name, other);
+        }
+        match &self.info.super_class {
+            Some(super_class) => self.is_subclass(super_class, other),
+            None => other == JAVA_LANG_OBJECT_NAME
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct FieldBuilder {
+    access_flags: u16,
+    name: String,
+    descriptor: String,
+    constant_value: Option<Constant>
+}
+
+impl FieldBuilder {
+    pub fn constant_value(&mut self, value: Constant) -> &mut Self {
+        self.constant_value = Some(value);
+        self
+    }
+
+    fn write(&self, pool: &mut PoolBuilder, buf: &mut BytesMut) -> Result<(), AssembleError> {
+        if FieldDescriptor::parse(&self.descriptor).is_none() {
+            return Err(AssembleError::new(format!("Invalid descriptor {} for field {}!", self.descriptor,
+                                                  self.name)));
+        }
+        buf.put_u16(self.access_flags);
+        buf.put_u16(pool.utf8(&self.name)?);
+        buf.put_u16(pool.utf8(&self.descriptor)?);
+        match &self.constant_value {
+            Some(value) => {
+                let value_index = match value {
+                    Constant::Integer(value) => pool.integer(*value)?,
+                    Constant::Float(value) => pool.float(*value)?,
+                    Constant::Long(value) => pool.long(*value)?,
+                    Constant::Double(value) => pool.double(*value)?,
+                    Constant::String(value) => pool.string(value)?,
+                    _ => return Err(AssembleError::new(format!("Invalid constant value for field {}! \
+                        Expected a number or string!", self.name)))
+                };
+                buf.put_u16(1);
+                let name_index = pool.utf8(JVM_ATTRIBUTE_CONSTANT_VALUE)?;
+                write_attribute(buf, name_index, |buf| buf.put_u16(value_index));
+            },
+            None => buf.put_u16(0)
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct MethodBuilder {
+    access_flags: u16,
+    name: String,
+    descriptor: String,
+    exceptions: Vec<String>,
+    code: Option<CodeBuilder>
+}
+
+impl MethodBuilder {
+    // Abstract and native methods don't have any code, so asking for it is a mistake.
+    pub fn code(&mut self) -> &mut CodeBuilder {
+        let name = &self.name;
+        self.code.as_mut().unwrap_or_else(|| panic!("Method {} is abstract or native, and can't have code!", name))
+    }
+
+    pub fn throws(&mut self, class_name: &str) -> &mut Self {
+        self.exceptions.push(class_name.to_string());
+        self
+    }
+
+    fn write(
+        &self,
+        pool: &mut PoolBuilder,
+        buf: &mut BytesMut,
+        stack_map: Option<&StackMapTable>
+    ) -> Result<(), AssembleError> {
+        let descriptor = MethodDescriptor::parse(&self.descriptor).ok_or_else(|| {
+            AssembleError::new(format!("Invalid descriptor {} for method {}!", self.descriptor, self.name))
+        })?;
+        buf.put_u16(self.access_flags);
+        buf.put_u16(pool.utf8(&self.name)?);
+        buf.put_u16(pool.utf8(&self.descriptor)?);
+        let attribute_count = self.code.is_some() as u16 + !self.exceptions.is_empty() as u16;
+        buf.put_u16(attribute_count);
+        if let Some(code) = &self.code {
+            let context = MethodContext {
+                name: &self.name,
+                descriptor,
+                is_static: (self.access_flags as u32) & JVM_ACC_STATIC != 0,
+                stack_map
+            };
+            let name_index = pool.utf8(JVM_ATTRIBUTE_CODE)?;
+            let mut info = BytesMut::new();
+            code.write(pool, &mut info, &context)?;
+            write_attribute(buf, name_index, |buf| buf.put_slice(&info));
+        }
+        if !self.exceptions.is_empty() {
+            let name_index = pool.utf8(JVM_ATTRIBUTE_EXCEPTIONS)?;
+            let indices = self.exceptions.iter()
+                .map(|name| pool.class(name))
+                .collect::<Result<Vec<_>, _>>()?;
+            write_attribute(buf, name_index, |buf| {
+                buf.put_u16(indices.len() as u16);
+                indices.iter().for_each(|index| buf.put_u16(*index));
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use crate::utils::IStr;
+    use crate::class_file::ClassLoader;
+    use crate::constants::*;
+    use crate::types::Class;
+    use crate::verifier::ClassInfo;
+    use super::super::Constant;
+    use super::ClassBuilder;
+
+    #[test]
+    fn builds_class() {
+        let mut builder = ClassBuilder::new("Point");
+        builder.field(JVM_ACC_PRIVATE as u16, "x", "J");
+        builder.field((JVM_ACC_PUBLIC | JVM_ACC_STATIC | JVM_ACC_FINAL) as u16, "ORIGIN", "D")
+            .constant_value(Constant::Double(0.5));
+
+        let code = builder.method(JVM_ACC_PUBLIC as u16, "<init>", "(J)V").code();
+        code.local(JVM_OPCODE_ALOAD, 0)
+            .invoke(JVM_OPCODE_INVOKESPECIAL, "java/lang/Object", "<init>", "()V")
+            .local(JVM_OPCODE_ALOAD, 0)
+            .local(JVM_OPCODE_LLOAD, 1)
+            .field(JVM_OPCODE_PUTFIELD, "Point", "x", "J")
+            .op(JVM_OPCODE_RETURN);
+
+        // Creates a point, or null for negative values, using a wide local along the way
+        let code = builder.method((JVM_ACC_PUBLIC | JVM_ACC_STATIC) as u16, "create", "(J)LPoint;").code();
+        let negative = code.new_label();
+        let done = code.new_label();
+        code.local(JVM_OPCODE_LLOAD, 0)
+            .local(JVM_OPCODE_LSTORE, 300)
+            .local(JVM_OPCODE_LLOAD, 300)
+            .op(JVM_OPCODE_LCONST_0)
+            .op(JVM_OPCODE_LCMP)
+            .jump(JVM_OPCODE_IFLT, negative)
+            .type_op(JVM_OPCODE_NEW, "Point")
+            .op(JVM_OPCODE_DUP)
+            .local(JVM_OPCODE_LLOAD, 0)
+            .invoke(JVM_OPCODE_INVOKESPECIAL, "Point", "<init>", "(J)V")
+            .jump(JVM_OPCODE_GOTO, done)
+            .place(negative)
+            .op(JVM_OPCODE_ACONST_NULL)
+            .place(done)
+            .op(JVM_OPCODE_ARETURN);
+
+        let bytes = builder.build().unwrap();
+        let class = Class::from_bytes(Arc::new(ClassLoader::new()), bytes);
+        assert_eq!(class.super_class_name().as_deref(), Some("java/lang/Object"));
+
+        let create = class.methods().iter().find(|method| method.name() == "create").unwrap();
+        let code = create.code().unwrap();
+        assert_eq!(code.max_locals(), 302);
+        assert_eq!(code.max_stack(), 4);
+        let table = code.stack_map_table().unwrap();
+        assert_eq!(table.len(), 2);
+        // The join after creating the point has the point merged with null, which is just the point
+        let joined = table.get(1).unwrap();
+        assert_eq!(joined.stack().len(), 1);
+        assert_eq!(joined.get_stack(0).unwrap().item(), JVM_ITEM_OBJECT);
+        let index = joined.get_stack(0).unwrap().offset() as usize;
+        assert_eq!(class.constant_pool().get_class_name(index).as_deref(), Some("Point"));
+    }
+
+    #[test]
+    fn merges_classes_with_the_hierarchy() {
+        let mut builder = ClassBuilder::new("Picker");
+        let code = builder.method((JVM_ACC_PUBLIC | JVM_ACC_STATIC) as u16, "pick", "(ZLFirst;LSecond;)LBase;")
+            .code();
+        let other = code.new_label();
+        let end = code.new_label();
+        code.local(JVM_OPCODE_ILOAD, 0)
+            .jump(JVM_OPCODE_IFEQ, other)
+            .local(JVM_OPCODE_ALOAD, 1)
+            .jump(JVM_OPCODE_GOTO, end)
+            .place(other)
+            .local(JVM_OPCODE_ALOAD, 2)
+            .place(end)
+            .op(JVM_OPCODE_ARETURN);
+
+        let mut hierarchy = HashMap::new();
+        for (name, super_class) in [("First", "Base"), ("Second", "Base"), ("Base", "java/lang/Object")] {
+            let info = ClassInfo { super_class: Some(IStr::new(super_class)), is_interface: false };
+            hierarchy.insert(String::from(name), info);
+        }
+        let class = Class::from_bytes(Arc::new(ClassLoader::new()), builder.build_with(&hierarchy).unwrap());
+        let table = class.methods()[0].code().unwrap().stack_map_table().unwrap();
+        let index = table.get(1).unwrap().get_stack(0).unwrap().offset() as usize;
+        assert_eq!(class.constant_pool().get_class_name(index).as_deref(), Some("Base"));
+
+        // Without the hierarchy, there's no way to show the result is a Base, so there are no frames
+        let class = Class::from_bytes(Arc::new(ClassLoader::new()), builder.build().unwrap());
+        assert!(class.methods()[0].code().unwrap().stack_map_table().is_none());
+    }
+
+    #[test]
+    fn rejects_unreachable_code() {
+        let mut builder = ClassBuilder::new("Dead");
+        builder.method((JVM_ACC_PUBLIC | JVM_ACC_STATIC) as u16, "f", "()V").code()
+            .op(JVM_OPCODE_RETURN)
+            .op(JVM_OPCODE_NOP);
+        assert!(builder.build().unwrap_err().message().contains("Unreachable"));
+    }
+}
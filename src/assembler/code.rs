@@ -0,0 +1,567 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+
+use bytes::{BufMut, BytesMut};
+use crate::utils::IStr;
+use crate::class_file::write_attribute;
+use crate::class_file::verification::StackMapTable;
+use crate::constants::*;
+use crate::utils::descriptors::MethodDescriptor;
+use super::AssembleError;
+use super::frames::{self, FrameContext, Handler};
+use super::pool::PoolBuilder;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Label(pub(crate) usize);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Constant {
+    Integer(i32),
+    Float(f32),
+    Long(i64),
+    Double(f64),
+    String(String),
+    Class(String),
+    MethodType(String)
+}
+
+impl Constant {
+    fn index(&self, pool: &mut PoolBuilder) -> Result<u16, AssembleError> {
+        match self {
+            Constant::Integer(value) => pool.integer(*value),
+            Constant::Float(value) => pool.float(*value),
+            Constant::Long(value) => pool.long(*value),
+            Constant::Double(value) => pool.double(*value),
+            Constant::String(value) => pool.string(value),
+            Constant::Class(value) => pool.class(value),
+            Constant::MethodType(value) => pool.method_type(value)
+        }
+    }
+
+    fn is_wide(&self) -> bool {
+        matches!(self, Constant::Long(_) | Constant::Double(_))
+    }
+}
+
+// Instructions as they are written, with labels and constants that are only turned in to offsets
+// and constant pool indices once the method is assembled.
+#[derive(Debug, Clone)]
+pub(crate) enum Insn {
+    Simple(u8),
+    Push(u8, i32),
+    NewArray(u8),
+    Local(u8, u16),
+    Iinc(u16, i16),
+    Constant(Constant),
+    Type(u8, IStr),
+    MultiANewArray(IStr, u8),
+    Field(u8, IStr, IStr, IStr),
+    Method(u8, IStr, IStr, IStr, bool),
+    Jump(u8, Label),
+    TableSwitch(i32, Label, Vec<Label>),
+    LookupSwitch(Label, Vec<(i32, Label)>),
+    Label(Label),
+    Line(u16)
+}
+
+#[derive(Debug, Clone)]
+struct TryCatch {
+    start: Label,
+    end: Label,
+    handler: Label,
+    catch_type: Option<IStr>
+}
+
+#[derive(Debug, Default)]
+pub struct CodeBuilder {
+    instructions: Vec<Insn>,
+    try_catches: Vec<TryCatch>,
+    label_count: usize,
+    max_stack: Option<u16>,
+    max_locals: Option<u16>
+}
+
+impl CodeBuilder {
+    pub fn new() -> Self {
+        CodeBuilder::default()
+    }
+
+    pub fn new_label(&mut self) -> Label {
+        self.label_count += 1;
+        Label(self.label_count - 1)
+    }
+
+    pub fn place(&mut self, label: Label) -> &mut Self {
+        self.instructions.push(Insn::Label(label));
+        self
+    }
+
+    pub fn line(&mut self, line: u16) -> &mut Self {
+        self.instructions.push(Insn::Line(line));
+        self
+    }
+
+    // Overrides the computed limits, for when something other than the minimum is wanted.
+    pub fn max_stack(&mut self, max_stack: u16) -> &mut Self {
+        self.max_stack = Some(max_stack);
+        self
+    }
+
+    pub fn max_locals(&mut self, max_locals: u16) -> &mut Self {
+        self.max_locals = Some(max_locals);
+        self
+    }
+
+    // Any opcode that doesn't take operands, such as iadd or areturn.
+    pub fn op(&mut self, opcode: u8) -> &mut Self {
+        self.instructions.push(Insn::Simple(opcode));
+        self
+    }
+
+    // Pushes an integer using the shortest instruction that can hold it.
+    pub fn push_int(&mut self, value: i32) -> &mut Self {
+        let insn = match value {
+            -1..=5 => Insn::Simple((JVM_OPCODE_ICONST_0 as i32 + value) as u8),
+            -128..=127 => Insn::Push(JVM_OPCODE_BIPUSH, value),
+            -32768..=32767 => Insn::Push(JVM_OPCODE_SIPUSH, value),
+            _ => Insn::Constant(Constant::Integer(value))
+        };
+        self.instructions.push(insn);
+        self
+    }
+
+    pub fn bipush(&mut self, value: i8) -> &mut Self {
+        self.instructions.push(Insn::Push(JVM_OPCODE_BIPUSH, value as i32));
+        self
+    }
+
+    pub fn sipush(&mut self, value: i16) -> &mut Self {
+        self.instructions.push(Insn::Push(JVM_OPCODE_SIPUSH, value as i32));
+        self
+    }
+
+    pub fn ldc(&mut self, constant: Constant) -> &mut Self {
+        self.instructions.push(Insn::Constant(constant));
+        self
+    }
+
+    // Any of the load and store instructions that take a local index. The short forms and wide are
+    // picked automatically, so the opcode should be one of iload, istore, etc.
+    pub fn local(&mut self, opcode: u8, index: u16) -> &mut Self {
+        self.instructions.push(Insn::Local(opcode, index));
+        self
+    }
+
+    pub fn iinc(&mut self, index: u16, amount: i16) -> &mut Self {
+        self.instructions.push(Insn::Iinc(index, amount));
+        self
+    }
+
+    // One of new, anewarray, checkcast or instanceof.
+    pub fn type_op(&mut self, opcode: u8, class_name: &str) -> &mut Self {
+        self.instructions.push(Insn::Type(opcode, IStr::new(class_name)));
+        self
+    }
+
+    pub fn new_array(&mut self, array_type: u8) -> &mut Self {
+        self.instructions.push(Insn::NewArray(array_type));
+        self
+    }
+
+    pub fn multi_new_array(&mut self, descriptor: &str, dimensions: u8) -> &mut Self {
+        self.instructions.push(Insn::MultiANewArray(IStr::new(descriptor), dimensions));
+        self
+    }
+
+    pub fn field(&mut self, opcode: u8, owner: &str, name: &str, descriptor: &str) -> &mut Self {
+        self.instructions.push(Insn::Field(opcode, IStr::new(owner), IStr::new(name), IStr::new(descriptor)));
+        self
+    }
+
+    pub fn invoke(&mut self, opcode: u8, owner: &str, name: &str, descriptor: &str) -> &mut Self {
+        let interface = opcode == JVM_OPCODE_INVOKEINTERFACE;
+        self.instructions.push(Insn::Method(opcode, IStr::new(owner), IStr::new(name), IStr::new(descriptor),
+                                            interface));
+        self
+    }
+
+    // For invokestatic and invokespecial calls to methods declared in interfaces.
+    pub fn invoke_interface_method(&mut self, opcode: u8, owner: &str, name: &str, descriptor: &str) -> &mut Self {
+        self.instructions.push(Insn::Method(opcode, IStr::new(owner), IStr::new(name), IStr::new(descriptor),
+                                            true));
+        self
+    }
+
+    pub fn jump(&mut self, opcode: u8, target: Label) -> &mut Self {
+        self.instructions.push(Insn::Jump(opcode, target));
+        self
+    }
+
+    pub fn table_switch(&mut self, low: i32, default: Label, targets: &[Label]) -> &mut Self {
+        self.instructions.push(Insn::TableSwitch(low, default, targets.to_vec()));
+        self
+    }
+
+    pub fn lookup_switch(&mut self, default: Label, pairs: &[(i32, Label)]) -> &mut Self {
+        self.instructions.push(Insn::LookupSwitch(default, pairs.to_vec()));
+        self
+    }
+
+    pub fn try_catch(&mut self, start: Label, end: Label, handler: Label, catch_type: Option<&str>) -> &mut Self {
+        self.try_catches.push(TryCatch { start, end, handler, catch_type: catch_type.map(IStr::new) });
+        self
+    }
+
+    // Lays out and writes the code attribute, computing limits and writing any stack map frames
+    // that were generated for it.
+    pub(crate) fn write(
+        &self,
+        pool: &mut PoolBuilder,
+        buf: &mut BytesMut,
+        context: &MethodContext
+    ) -> Result<(), AssembleError> {
+        let layout = self.layout(pool)?;
+        let code = self.emit(&layout, context)?;
+
+        let handlers = self.try_catches.iter()
+            .map(|try_catch| {
+                let start = layout.label(try_catch.start)?;
+                let end = layout.label(try_catch.end)?;
+                let handler = layout.label(try_catch.handler)?;
+                if start >= end {
+                    return Err(AssembleError::new(format!("Invalid exception handler in {}! Expected start \
+                        offset {} to be before end offset {}!", context.name, start, end)));
+                }
+                Ok(Handler { start, end, handler, catch_type: try_catch.catch_type.clone() })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let frame_context = FrameContext {
+            instructions: &self.instructions,
+            offsets: &layout.offsets,
+            labels: &layout.labels,
+            handlers: &handlers,
+            method_name: context.name,
+            descriptor: &context.descriptor,
+            is_static: context.is_static
+        };
+        let analysis = frames::analyze(&frame_context)?;
+
+        buf.put_u16(self.max_stack.unwrap_or(analysis.max_stack));
+        buf.put_u16(self.max_locals.unwrap_or(analysis.max_locals));
+        buf.put_u32(code.len() as u32);
+        buf.put_slice(code.as_slice());
+        buf.put_u16(handlers.len() as u16);
+        for handler in &handlers {
+            buf.put_u16(handler.start as u16);
+            buf.put_u16(handler.end as u16);
+            buf.put_u16(handler.handler as u16);
+            match &handler.catch_type {
+                Some(name) => buf.put_u16(pool.class(name)?),
+                None => buf.put_u16(0)
+            }
+        }
+
+        let mut attribute_count = 0;
+        let count_offset = buf.len();
+        buf.put_u16(0);
+        if let Some(table) = context.stack_map.filter(|table| !table.is_empty()) {
+            let name_index = pool.utf8(JVM_ATTRIBUTE_STACK_MAP_TABLE)?;
+            write_attribute(buf, name_index, |buf| table.write(buf));
+            attribute_count += 1;
+        }
+        if !layout.lines.is_empty() {
+            let name_index = pool.utf8(JVM_ATTRIBUTE_LINE_NUMBER_TABLE)?;
+            write_attribute(buf, name_index, |buf| {
+                buf.put_u16(layout.lines.len() as u16);
+                for (start_pc, line) in &layout.lines {
+                    buf.put_u16(*start_pc as u16);
+                    buf.put_u16(*line);
+                }
+            });
+            attribute_count += 1;
+        }
+        buf[count_offset..count_offset + 2].copy_from_slice(&(attribute_count as u16).to_be_bytes());
+        Ok(())
+    }
+
+    // Works out where every instruction and label will end up, resolving constant pool indices on
+    // the way, as the size of ldc depends on them.
+    fn layout(&self, pool: &mut PoolBuilder) -> Result<Layout, AssembleError> {
+        let mut offsets = Vec::with_capacity(self.instructions.len());
+        let mut labels = vec![None; self.label_count];
+        let mut indices = Vec::with_capacity(self.instructions.len());
+        let mut lines = Vec::new();
+        let mut offset = 0u32;
+        for insn in &self.instructions {
+            offsets.push(offset);
+            let index = match insn {
+                Insn::Constant(constant) => constant.index(pool)?,
+                Insn::Type(_, name) | Insn::MultiANewArray(name, _) => pool.class(name)?,
+                Insn::Field(_, owner, name, descriptor) => pool.field_ref(owner, name, descriptor)?,
+                Insn::Method(_, owner, name, descriptor, interface) => {
+                    pool.method_ref(owner, name, descriptor, *interface)?
+                },
+                _ => 0
+            };
+            indices.push(index);
+            offset += match insn {
+                Insn::Simple(_) => 1,
+                Insn::Push(opcode, _) => if *opcode == JVM_OPCODE_BIPUSH { 2 } else { 3 },
+                Insn::NewArray(_) => 2,
+                Insn::Local(_, index) => if *index <= 3 { 1 } else if *index <= 255 { 2 } else { 4 },
+                Insn::Iinc(index, amount) => {
+                    if *index <= 255 && *amount >= i8::MIN as i16 && *amount <= i8::MAX as i16 { 3 } else { 6 }
+                },
+                Insn::Constant(constant) => if constant.is_wide() || index > 255 { 3 } else { 2 },
+                Insn::Type(_, _) | Insn::Field(_, _, _, _) => 3,
+                Insn::MultiANewArray(_, _) => 4,
+                Insn::Method(opcode, _, _, _, _) => if *opcode == JVM_OPCODE_INVOKEINTERFACE { 5 } else { 3 },
+                Insn::Jump(opcode, _) => {
+                    if *opcode == JVM_OPCODE_GOTO_W || *opcode == JVM_OPCODE_JSR_W { 5 } else { 3 }
+                },
+                Insn::TableSwitch(_, _, targets) => 1 + switch_padding(offset) + 12 + 4 * targets.len() as u32,
+                Insn::LookupSwitch(_, pairs) => 1 + switch_padding(offset) + 8 + 8 * pairs.len() as u32,
+                Insn::Label(label) => {
+                    if labels[label.0].is_some() {
+                        return Err(AssembleError::new(format!("Label {} placed more than once!", label.0)));
+                    }
+                    labels[label.0] = Some(offset);
+                    0
+                },
+                Insn::Line(line) => {
+                    lines.push((offset, *line));
+                    0
+                }
+            };
+        }
+        if offset == 0 || offset > u16::MAX as u32 {
+            return Err(AssembleError::new(format!("Invalid code length {}! Expected between 1 and {}!",
+                                                  offset, u16::MAX)));
+        }
+        Ok(Layout { offsets, labels, indices, lines })
+    }
+
+    fn emit(&self, layout: &Layout, context: &MethodContext) -> Result<Vec<u8>, AssembleError> {
+        let mut code = Vec::new();
+        for (position, insn) in self.instructions.iter().enumerate() {
+            let offset = layout.offsets[position];
+            let index = layout.indices[position];
+            let branch = |target: Label| -> Result<i32, AssembleError> {
+                Ok(layout.label(target)? as i32 - offset as i32)
+            };
+            match insn {
+                Insn::Simple(opcode) => {
+                    if !is_simple(*opcode) {
+                        return Err(AssembleError::new(format!("Invalid instruction in {}! Opcode {} requires \
+                            operands!", context.name, opcode_name(*opcode).unwrap_or("unknown"))));
+                    }
+                    code.push(*opcode);
+                },
+                Insn::Push(opcode, value) => {
+                    code.push(*opcode);
+                    if *opcode == JVM_OPCODE_BIPUSH {
+                        code.push(*value as i8 as u8);
+                    } else {
+                        code.extend_from_slice(&(*value as i16).to_be_bytes());
+                    }
+                },
+                Insn::NewArray(array_type) => {
+                    if BasicType::from(*array_type).is_none() {
+                        return Err(AssembleError::new(format!("Invalid array type {} for newarray!", array_type)));
+                    }
+                    code.push(JVM_OPCODE_NEWARRAY);
+                    code.push(*array_type);
+                },
+                Insn::Local(opcode, index) => {
+                    let short_base = match *opcode {
+                        JVM_OPCODE_ILOAD..=JVM_OPCODE_ALOAD => {
+                            JVM_OPCODE_ILOAD_0 + (opcode - JVM_OPCODE_ILOAD) * 4
+                        },
+                        JVM_OPCODE_ISTORE..=JVM_OPCODE_ASTORE => {
+                            JVM_OPCODE_ISTORE_0 + (opcode - JVM_OPCODE_ISTORE) * 4
+                        },
+                        _ => return Err(AssembleError::new(format!("Invalid local variable instruction {} in \
+                            {}!", opcode_name(*opcode).unwrap_or("unknown"), context.name)))
+                    };
+                    if *index <= 3 {
+                        code.push(short_base + *index as u8);
+                    } else if *index <= 255 {
+                        code.push(*opcode);
+                        code.push(*index as u8);
+                    } else {
+                        code.push(JVM_OPCODE_WIDE);
+                        code.push(*opcode);
+                        code.extend_from_slice(&index.to_be_bytes());
+                    }
+                },
+                Insn::Iinc(index, amount) => {
+                    if *index <= 255 && *amount >= i8::MIN as i16 && *amount <= i8::MAX as i16 {
+                        code.push(JVM_OPCODE_IINC);
+                        code.push(*index as u8);
+                        code.push(*amount as i8 as u8);
+                    } else {
+                        code.push(JVM_OPCODE_WIDE);
+                        code.push(JVM_OPCODE_IINC);
+                        code.extend_from_slice(&index.to_be_bytes());
+                        code.extend_from_slice(&amount.to_be_bytes());
+                    }
+                },
+                Insn::Constant(constant) => {
+                    if constant.is_wide() {
+                        code.push(JVM_OPCODE_LDC2_W);
+                        code.extend_from_slice(&index.to_be_bytes());
+                    } else if index > 255 {
+                        code.push(JVM_OPCODE_LDC_W);
+                        code.extend_from_slice(&index.to_be_bytes());
+                    } else {
+                        code.push(JVM_OPCODE_LDC);
+                        code.push(index as u8);
+                    }
+                },
+                Insn::Type(opcode, _) => {
+                    if !matches!(*opcode, JVM_OPCODE_NEW | JVM_OPCODE_ANEWARRAY | JVM_OPCODE_CHECKCAST |
+                        JVM_OPCODE_INSTANCEOF) {
+                        return Err(AssembleError::new(format!("Invalid type instruction {} in {}!",
+                                                              opcode_name(*opcode).unwrap_or("unknown"),
+                                                              context.name)));
+                    }
+                    code.push(*opcode);
+                    code.extend_from_slice(&index.to_be_bytes());
+                },
+                Insn::MultiANewArray(_, dimensions) => {
+                    if *dimensions == 0 {
+                        return Err(AssembleError::new("Invalid multianewarray! Expected at least one dimension!"));
+                    }
+                    code.push(JVM_OPCODE_MULTIANEWARRAY);
+                    code.extend_from_slice(&index.to_be_bytes());
+                    code.push(*dimensions);
+                },
+                Insn::Field(opcode, _, _, _) => {
+                    if !(JVM_OPCODE_GETSTATIC..=JVM_OPCODE_PUTFIELD).contains(opcode) {
+                        return Err(AssembleError::new(format!("Invalid field instruction {} in {}!",
+                                                              opcode_name(*opcode).unwrap_or("unknown"),
+                                                              context.name)));
+                    }
+                    code.push(*opcode);
+                    code.extend_from_slice(&index.to_be_bytes());
+                },
+                Insn::Method(opcode, _, _, descriptor, _) => {
+                    code.push(*opcode);
+                    code.extend_from_slice(&index.to_be_bytes());
+                    match *opcode {
+                        JVM_OPCODE_INVOKEINTERFACE => {
+                            let parsed = MethodDescriptor::parse(descriptor).ok_or_else(|| {
+                                AssembleError::new(format!("Invalid method descriptor {}!", descriptor))
+                            })?;
+                            code.push(1 + frames::parameters_size(&parsed) as u8);
+                            code.push(0);
+                        },
+                        JVM_OPCODE_INVOKEVIRTUAL | JVM_OPCODE_INVOKESPECIAL | JVM_OPCODE_INVOKESTATIC => {},
+                        _ => return Err(AssembleError::new(format!("Invalid invoke instruction {} in {}!",
+                                                                   opcode_name(*opcode).unwrap_or("unknown"),
+                                                                   context.name)))
+                    }
+                },
+                Insn::Jump(opcode, target) => {
+                    let delta = branch(*target)?;
+                    match *opcode {
+                        JVM_OPCODE_GOTO_W => {
+                            code.push(*opcode);
+                            code.extend_from_slice(&delta.to_be_bytes());
+                        },
+                        JVM_OPCODE_IFEQ..=JVM_OPCODE_GOTO | JVM_OPCODE_IFNULL | JVM_OPCODE_IFNONNULL => {
+                            if delta < i16::MIN as i32 || delta > i16::MAX as i32 {
+                                return Err(AssembleError::new(format!("Branch at offset {} in {} is too far \
+                                    from its target!", offset, context.name)));
+                            }
+                            code.push(*opcode);
+                            code.extend_from_slice(&(delta as i16).to_be_bytes());
+                        },
+                        _ => return Err(AssembleError::new(format!("Invalid jump instruction {} in {}! \
+                            Subroutines are not supported!", opcode_name(*opcode).unwrap_or("unknown"),
+                                                                   context.name)))
+                    }
+                },
+                Insn::TableSwitch(low, default, targets) => {
+                    if targets.is_empty() {
+                        return Err(AssembleError::new("Invalid tableswitch! Expected at least one target!"));
+                    }
+                    code.push(JVM_OPCODE_TABLESWITCH);
+                    code.resize(code.len() + switch_padding(offset) as usize, 0);
+                    code.extend_from_slice(&branch(*default)?.to_be_bytes());
+                    code.extend_from_slice(&low.to_be_bytes());
+                    code.extend_from_slice(&(low + targets.len() as i32 - 1).to_be_bytes());
+                    for target in targets {
+                        code.extend_from_slice(&branch(*target)?.to_be_bytes());
+                    }
+                },
+                Insn::LookupSwitch(default, pairs) => {
+                    let mut pairs = pairs.clone();
+                    pairs.sort_by_key(|pair| pair.0);
+                    if pairs.windows(2).any(|window| window[0].0 == window[1].0) {
+                        return Err(AssembleError::new("Invalid lookupswitch! Duplicate keys!"));
+                    }
+                    code.push(JVM_OPCODE_LOOKUPSWITCH);
+                    code.resize(code.len() + switch_padding(offset) as usize, 0);
+                    code.extend_from_slice(&branch(*default)?.to_be_bytes());
+                    code.extend_from_slice(&(pairs.len() as i32).to_be_bytes());
+                    for (key, target) in pairs {
+                        code.extend_from_slice(&key.to_be_bytes());
+                        code.extend_from_slice(&branch(target)?.to_be_bytes());
+                    }
+                },
+                Insn::Label(_) | Insn::Line(_) => {}
+            }
+        }
+        Ok(code)
+    }
+}
+
+pub(crate) struct MethodContext<'a> {
+    pub name: &'a str,
+    pub descriptor: MethodDescriptor,
+    pub is_static: bool,
+    // The frames the verifier generated from an earlier build of the class, for class files that need them
+    pub stack_map: Option<&'a StackMapTable>
+}
+
+struct Layout {
+    offsets: Vec<u32>,
+    labels: Vec<Option<u32>>,
+    indices: Vec<u16>,
+    lines: Vec<(u32, u16)>
+}
+
+impl Layout {
+    fn label(&self, label: Label) -> Result<u32, AssembleError> {
+        self.labels.get(label.0)
+            .copied()
+            .flatten()
+            .ok_or_else(|| AssembleError::new(format!("Label {} was never placed!", label.0)))
+    }
+}
+
+fn switch_padding(offset: u32) -> u32 {
+    3 - (offset % 4)
+}
+
+fn is_simple(opcode: u8) -> bool {
+    matches!(opcode, JVM_OPCODE_NOP..=JVM_OPCODE_DCONST_1 | JVM_OPCODE_ILOAD_0..=JVM_OPCODE_SALOAD |
+        JVM_OPCODE_ISTORE_0..=JVM_OPCODE_LXOR | JVM_OPCODE_I2L..=JVM_OPCODE_DCMPG |
+        JVM_OPCODE_IRETURN..=JVM_OPCODE_RETURN | JVM_OPCODE_ARRAYLENGTH | JVM_OPCODE_ATHROW |
+        JVM_OPCODE_MONITORENTER | JVM_OPCODE_MONITOREXIT)
+}
@@ -0,0 +1,466 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+
+use std::collections::{HashMap, VecDeque};
+use crate::utils::IStr;
+use crate::constants::*;
+use crate::utils::descriptors::{FieldDescriptor, FieldType, MethodDescriptor};
+use super::AssembleError;
+use super::code::{Constant, Insn, Label};
+
+// What the limits need to know about a value in the locals or on the stack, which is only how many
+// slots it takes. The types themselves are left to the verifier, which writes the frames in the class.
+// Locals that haven't been set, or that hold the second half of a long or double, are Top.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Category {
+    Top,
+    Single,
+    Double
+}
+
+impl Category {
+    fn size(self) -> usize {
+        if self == Category::Double { 2 } else { 1 }
+    }
+
+    fn of(descriptor: &FieldDescriptor) -> Self {
+        match descriptor.base() {
+            FieldType::Long | FieldType::Double if descriptor.array_dimensions() == 0 => Category::Double,
+            _ => Category::Single
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Frame {
+    locals: Vec<Category>,
+    stack: Vec<Category>
+}
+
+impl Frame {
+    fn stack_size(&self) -> usize {
+        self.stack.iter().map(|value| value.size()).sum()
+    }
+
+    fn push(&mut self, value: Category) {
+        self.stack.push(value);
+    }
+
+    fn pop(&mut self, offset: u32) -> Result<Category, AssembleError> {
+        self.stack.pop()
+            .ok_or_else(|| AssembleError::new(format!("Stack underflow at offset {}!", offset)))
+    }
+
+    fn pop_many(&mut self, count: usize, offset: u32) -> Result<(), AssembleError> {
+        for _ in 0..count {
+            self.pop(offset)?;
+        }
+        Ok(())
+    }
+
+    // Pops values totalling exactly the given number of slots, for the untyped stack instructions.
+    fn pop_slots(&mut self, slots: usize, offset: u32) -> Result<Vec<Category>, AssembleError> {
+        let mut values = Vec::new();
+        let mut size = 0;
+        while size < slots {
+            let value = self.pop(offset)?;
+            size += value.size();
+            values.insert(0, value);
+        }
+        if size != slots {
+            return Err(AssembleError::new(format!("Instruction at offset {} splits a long or double on \
+                the stack!", offset)));
+        }
+        Ok(values)
+    }
+
+    fn get_local(&self, index: usize) -> Category {
+        self.locals.get(index).copied().unwrap_or(Category::Top)
+    }
+
+    fn set_local(&mut self, index: usize, value: Category) {
+        let size = value.size();
+        if self.locals.len() < index + size {
+            self.locals.resize(index + size, Category::Top);
+        }
+        if index > 0 && self.locals[index - 1] == Category::Double {
+            self.locals[index - 1] = Category::Top;
+        }
+        if size == 2 {
+            self.locals[index + 1] = Category::Top;
+        }
+        self.locals[index] = value;
+    }
+
+    fn merge(&self, other: &Frame, offset: u32) -> Result<Frame, AssembleError> {
+        if self.stack != other.stack {
+            return Err(AssembleError::new(format!("Inconsistent stacks at offset {}! Got {:?} and {:?}!", offset,
+                                                  self.stack, other.stack)));
+        }
+        let length = self.locals.len().max(other.locals.len());
+        let locals = (0..length)
+            .map(|index| {
+                let value = self.get_local(index);
+                if value == other.get_local(index) { value } else { Category::Top }
+            })
+            .collect();
+        Ok(Frame { locals, stack: self.stack.clone() })
+    }
+}
+
+pub(crate) struct Handler {
+    pub start: u32,
+    pub end: u32,
+    pub handler: u32,
+    pub catch_type: Option<IStr>
+}
+
+pub(crate) struct FrameContext<'a> {
+    pub instructions: &'a [Insn],
+    pub offsets: &'a [u32],
+    pub labels: &'a [Option<u32>],
+    pub handlers: &'a [Handler],
+    pub method_name: &'a str,
+    pub descriptor: &'a MethodDescriptor,
+    pub is_static: bool
+}
+
+pub(crate) struct FrameAnalysis {
+    pub max_stack: u16,
+    pub max_locals: u16
+}
+
+// Runs through the possible paths of execution in a method, tracking how many slots the values in the
+// locals and on the stack take, to find the limits for the method.
+pub(crate) fn analyze(context: &FrameContext) -> Result<FrameAnalysis, AssembleError> {
+    let instructions = context.instructions.iter()
+        .zip(context.offsets.iter())
+        .filter(|(insn, _)| !matches!(insn, Insn::Label(_) | Insn::Line(_)))
+        .map(|(insn, offset)| (*offset, insn))
+        .collect::<Vec<_>>();
+    let positions = instructions.iter()
+        .enumerate()
+        .map(|(position, (offset, _))| (*offset, position))
+        .collect::<HashMap<_, _>>();
+    let position_of = |offset: u32| -> Result<usize, AssembleError> {
+        positions.get(&offset).copied().ok_or_else(|| {
+            AssembleError::new(format!("Invalid jump target {} in {}! Expected an instruction!", offset,
+                                       context.method_name))
+        })
+    };
+    let label_position = |label: Label| -> Result<usize, AssembleError> {
+        let offset = context.labels.get(label.0).copied().flatten()
+            .ok_or_else(|| AssembleError::new(format!("Label {} was never placed!", label.0)))?;
+        position_of(offset)
+    };
+
+    let initial = initial_frame(context);
+    let mut max_locals = initial.locals.len();
+    let mut max_stack = 0;
+    let mut states: Vec<Option<Frame>> = vec![None; instructions.len()];
+    let mut queue = VecDeque::new();
+    states[0] = Some(initial);
+    queue.push_back(0);
+
+    let handlers = context.handlers.iter()
+        .map(|handler| Ok((handler, position_of(handler.handler)?)))
+        .collect::<Result<Vec<_>, AssembleError>>()?;
+
+    let merge = |states: &mut Vec<Option<Frame>>, queue: &mut VecDeque<usize>, position: usize,
+                     frame: Frame| -> Result<(), AssembleError> {
+        let merged = match &states[position] {
+            Some(existing) => {
+                let merged = existing.merge(&frame, instructions[position].0)?;
+                if &merged == existing {
+                    return Ok(());
+                }
+                merged
+            },
+            None => frame
+        };
+        states[position] = Some(merged);
+        if !queue.contains(&position) {
+            queue.push_back(position);
+        }
+        Ok(())
+    };
+
+    while let Some(position) = queue.pop_front() {
+        let (offset, insn) = instructions[position];
+        let mut frame = states[position].clone().unwrap();
+        let before = frame.locals.clone();
+        let flow = execute(context, &mut frame, insn, offset)?;
+        max_stack = max_stack.max(frame.stack_size()).max(flow.peak);
+        max_locals = max_locals.max(frame.locals.len());
+
+        // Handlers start with just the exception on the stack.
+        for (handler, handler_position) in &handlers {
+            if offset < handler.start || offset >= handler.end {
+                continue;
+            }
+            let stack = vec![Category::Single];
+            max_stack = max_stack.max(1);
+            merge(&mut states, &mut queue, *handler_position, Frame { locals: before.clone(), stack: stack.clone() })?;
+            merge(&mut states, &mut queue, *handler_position, Frame { locals: frame.locals.clone(), stack })?;
+        }
+        for target in flow.targets {
+            merge(&mut states, &mut queue, label_position(target)?, frame.clone())?;
+        }
+        if flow.falls_through {
+            if position + 1 >= instructions.len() {
+                return Err(AssembleError::new(format!("Execution falls off the end of the code in {}!",
+                                                      context.method_name)));
+            }
+            merge(&mut states, &mut queue, position + 1, frame)?;
+        }
+    }
+
+    if let Some(position) = states.iter().position(Option::is_none) {
+        return Err(AssembleError::new(format!("Unreachable code at offset {} in {}!", instructions[position].0,
+                                              context.method_name)));
+    }
+    if max_stack > u16::MAX as usize || max_locals > u16::MAX as usize {
+        return Err(AssembleError::new(format!("Method {} exceeds the limits for locals or stack!",
+                                              context.method_name)));
+    }
+    Ok(FrameAnalysis { max_stack: max_stack as u16, max_locals: max_locals as u16 })
+}
+
+fn initial_frame(context: &FrameContext) -> Frame {
+    let mut frame = Frame { locals: Vec::new(), stack: Vec::new() };
+    if !context.is_static {
+        frame.locals.push(Category::Single);
+    }
+    for parameter in context.descriptor.parameters() {
+        let index = frame.locals.len();
+        frame.set_local(index, Category::of(parameter));
+    }
+    frame
+}
+
+pub(crate) fn parameters_size(descriptor: &MethodDescriptor) -> usize {
+    descriptor.parameters().iter().map(|parameter| Category::of(parameter).size()).sum()
+}
+
+struct Flow {
+    falls_through: bool,
+    targets: Vec<Label>,
+    // The deepest the stack gets part way through an instruction, which matters for instructions
+    // like invokes that pop their arguments before pushing a result.
+    peak: usize
+}
+
+fn execute(context: &FrameContext, frame: &mut Frame, insn: &Insn, offset: u32) -> Result<Flow, AssembleError> {
+    let mut flow = Flow { falls_through: true, targets: Vec::new(), peak: frame.stack_size() };
+    match insn {
+        Insn::Simple(opcode) => execute_simple(context, frame, *opcode, offset, &mut flow)?,
+        Insn::Push(_, _) => frame.push(Category::Single),
+        Insn::NewArray(array_type) => {
+            if BasicType::from(*array_type).is_none() {
+                return Err(AssembleError::new(format!("Invalid array type {} for newarray!", array_type)));
+            }
+            frame.pop(offset)?;
+            frame.push(Category::Single);
+        },
+        Insn::Local(opcode, index) => {
+            let index = *index as usize;
+            match *opcode {
+                JVM_OPCODE_ILOAD..=JVM_OPCODE_ALOAD => frame.push(kind_category(*opcode - JVM_OPCODE_ILOAD)),
+                _ => store(frame, index, offset)?
+            }
+        },
+        Insn::Iinc(_, _) => {},
+        Insn::Constant(constant) => frame.push(match constant {
+            Constant::Long(_) | Constant::Double(_) => Category::Double,
+            _ => Category::Single
+        }),
+        Insn::Type(opcode, _) => {
+            if *opcode != JVM_OPCODE_NEW {
+                frame.pop(offset)?;
+            }
+            frame.push(Category::Single);
+        },
+        Insn::MultiANewArray(_, dimensions) => {
+            frame.pop_many(*dimensions as usize, offset)?;
+            frame.push(Category::Single);
+        },
+        Insn::Field(opcode, _, _, descriptor) => {
+            let value = FieldDescriptor::parse(descriptor)
+                .map(|value| Category::of(&value))
+                .ok_or_else(|| AssembleError::new(format!("Invalid field descriptor {}!", descriptor)))?;
+            match *opcode {
+                JVM_OPCODE_GETSTATIC => frame.push(value),
+                JVM_OPCODE_PUTSTATIC => {
+                    frame.pop(offset)?;
+                },
+                JVM_OPCODE_GETFIELD => {
+                    frame.pop(offset)?;
+                    frame.push(value);
+                },
+                _ => frame.pop_many(2, offset)?
+            }
+        },
+        Insn::Method(opcode, _, _, descriptor, _) => {
+            let parsed = MethodDescriptor::parse(descriptor)
+                .ok_or_else(|| AssembleError::new(format!("Invalid method descriptor {}!", descriptor)))?;
+            frame.pop_many(parsed.parameters().len(), offset)?;
+            if *opcode != JVM_OPCODE_INVOKESTATIC {
+                frame.pop(offset)?;
+            }
+            if let Some(return_type) = parsed.return_type() {
+                frame.push(Category::of(return_type));
+            }
+        },
+        Insn::Jump(opcode, target) => {
+            match *opcode {
+                JVM_OPCODE_IFEQ..=JVM_OPCODE_IFLE | JVM_OPCODE_IFNULL | JVM_OPCODE_IFNONNULL => {
+                    frame.pop(offset)?;
+                },
+                JVM_OPCODE_IF_ICMPEQ..=JVM_OPCODE_IF_ACMPNE => frame.pop_many(2, offset)?,
+                _ => flow.falls_through = false
+            }
+            flow.targets.push(*target);
+        },
+        Insn::TableSwitch(_, default, targets) => {
+            frame.pop(offset)?;
+            flow.falls_through = false;
+            flow.targets.push(*default);
+            flow.targets.extend(targets.iter().copied());
+        },
+        Insn::LookupSwitch(default, pairs) => {
+            frame.pop(offset)?;
+            flow.falls_through = false;
+            flow.targets.push(*default);
+            flow.targets.extend(pairs.iter().map(|pair| pair.1));
+        },
+        Insn::Label(_) | Insn::Line(_) => {}
+    }
+    Ok(flow)
+}
+
+// The loads and stores go int, long, float, double and then reference.
+fn kind_category(kind: u8) -> Category {
+    if kind == 1 || kind == 3 { Category::Double } else { Category::Single }
+}
+
+fn store(frame: &mut Frame, index: usize, offset: u32) -> Result<(), AssembleError> {
+    let value = frame.pop(offset)?;
+    frame.set_local(index, value);
+    Ok(())
+}
+
+fn execute_simple(
+    context: &FrameContext,
+    frame: &mut Frame,
+    opcode: u8,
+    offset: u32,
+    flow: &mut Flow
+) -> Result<(), AssembleError> {
+    match opcode {
+        JVM_OPCODE_NOP => {},
+        JVM_OPCODE_ACONST_NULL | JVM_OPCODE_ICONST_M1..=JVM_OPCODE_ICONST_5 => frame.push(Category::Single),
+        JVM_OPCODE_FCONST_0..=JVM_OPCODE_FCONST_2 => frame.push(Category::Single),
+        JVM_OPCODE_LCONST_0 | JVM_OPCODE_LCONST_1 | JVM_OPCODE_DCONST_0 | JVM_OPCODE_DCONST_1 => {
+            frame.push(Category::Double)
+        },
+        JVM_OPCODE_ILOAD_0..=JVM_OPCODE_ALOAD_3 => frame.push(kind_category((opcode - JVM_OPCODE_ILOAD_0) / 4)),
+        JVM_OPCODE_IALOAD..=JVM_OPCODE_SALOAD => {
+            frame.pop_many(2, offset)?;
+            frame.push(match opcode {
+                JVM_OPCODE_LALOAD | JVM_OPCODE_DALOAD => Category::Double,
+                _ => Category::Single
+            });
+        },
+        JVM_OPCODE_ISTORE_0..=JVM_OPCODE_ASTORE_3 => {
+            store(frame, ((opcode - JVM_OPCODE_ISTORE_0) % 4) as usize, offset)?
+        },
+        JVM_OPCODE_IASTORE..=JVM_OPCODE_SASTORE => frame.pop_many(3, offset)?,
+        JVM_OPCODE_POP => {
+            frame.pop_slots(1, offset)?;
+        },
+        JVM_OPCODE_POP2 => {
+            frame.pop_slots(2, offset)?;
+        },
+        JVM_OPCODE_DUP..=JVM_OPCODE_DUP2_X2 => {
+            // Each of the dup instructions copies the top one or two slots, and inserts the copy
+            // under zero, one or two more slots.
+            let (copied, skipped) = match opcode {
+                JVM_OPCODE_DUP => (1, 0),
+                JVM_OPCODE_DUP_X1 => (1, 1),
+                JVM_OPCODE_DUP_X2 => (1, 2),
+                JVM_OPCODE_DUP2 => (2, 0),
+                JVM_OPCODE_DUP2_X1 => (2, 1),
+                _ => (2, 2)
+            };
+            let top = frame.pop_slots(copied, offset)?;
+            let under = frame.pop_slots(skipped, offset)?;
+            frame.stack.extend(top.iter().copied());
+            frame.stack.extend(under);
+            frame.stack.extend(top);
+        },
+        JVM_OPCODE_SWAP => {
+            let first = frame.pop_slots(1, offset)?;
+            let second = frame.pop_slots(1, offset)?;
+            frame.stack.extend(first);
+            frame.stack.extend(second);
+        },
+        JVM_OPCODE_IADD..=JVM_OPCODE_DREM | JVM_OPCODE_ISHL..=JVM_OPCODE_LXOR => {
+            frame.pop_many(2, offset)?;
+            frame.push(arithmetic_category(opcode));
+        },
+        JVM_OPCODE_INEG..=JVM_OPCODE_DNEG => {
+            frame.pop(offset)?;
+            frame.push(arithmetic_category(opcode));
+        },
+        JVM_OPCODE_I2L..=JVM_OPCODE_I2S => {
+            frame.pop(offset)?;
+            frame.push(match opcode {
+                JVM_OPCODE_I2L | JVM_OPCODE_F2L | JVM_OPCODE_D2L | JVM_OPCODE_I2D | JVM_OPCODE_L2D | JVM_OPCODE_F2D => {
+                    Category::Double
+                },
+                _ => Category::Single
+            });
+        },
+        JVM_OPCODE_LCMP..=JVM_OPCODE_DCMPG => {
+            frame.pop_many(2, offset)?;
+            frame.push(Category::Single);
+        },
+        JVM_OPCODE_IRETURN..=JVM_OPCODE_ARETURN | JVM_OPCODE_ATHROW => {
+            frame.pop(offset)?;
+            flow.falls_through = false;
+        },
+        JVM_OPCODE_RETURN => flow.falls_through = false,
+        JVM_OPCODE_ARRAYLENGTH => {
+            frame.pop(offset)?;
+            frame.push(Category::Single);
+        },
+        JVM_OPCODE_MONITORENTER | JVM_OPCODE_MONITOREXIT => {
+            frame.pop(offset)?;
+        },
+        _ => return Err(AssembleError::new(format!("Invalid instruction {} at offset {} in {}!",
+                                                   opcode_name(opcode).unwrap_or("unknown"), offset,
+                                                   context.method_name)))
+    }
+    Ok(())
+}
+
+// The arithmetic instructions cycle through int, long, float and double, except for the shifts
+// and bitwise operations, which only have int and long forms.
+fn arithmetic_category(opcode: u8) -> Category {
+    let kind = if opcode >= JVM_OPCODE_ISHL { (opcode - JVM_OPCODE_ISHL) % 2 } else { (opcode - JVM_OPCODE_IADD) % 4 };
+    kind_category(kind)
+}
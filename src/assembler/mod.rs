@@ -0,0 +1,65 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+
+mod builder;
+mod code;
+mod frames;
+mod pool;
+mod text;
+
+use std::fmt::{Display, Formatter};
+
+pub use builder::{ClassBuilder, FieldBuilder, MethodBuilder};
+pub use code::{CodeBuilder, Constant, Label};
+pub use pool::PoolBuilder;
+pub use text::{assemble, parse};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssembleError {
+    message: String,
+    line: Option<usize>
+}
+
+impl AssembleError {
+    pub(crate) fn new(message: impl Into<String>) -> Self {
+        AssembleError { message: message.into(), line: None }
+    }
+
+    pub(crate) fn at_line(mut self, line: usize) -> Self {
+        self.line = Some(line);
+        self
+    }
+
+    pub fn message(&self) -> &str {
+        self.message.as_str()
+    }
+
+    pub fn line(&self) -> Option<usize> {
+        self.line
+    }
+}
+
+impl Display for AssembleError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "Line {}: {}", line, self.message),
+            None => f.write_str(&self.message)
+        }
+    }
+}
+
+impl std::error::Error for AssembleError {}
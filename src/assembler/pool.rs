@@ -0,0 +1,195 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+
+use bytes::{BufMut, BytesMut};
+use std::collections::HashMap;
+use crate::types::constant_pool::*;
+use super::AssembleError;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum PoolEntry {
+    Utf8(String),
+    Integer(i32),
+    Float(u32),
+    Long(i64),
+    Double(u64),
+    Class(u16),
+    String(u16),
+    FieldRef(u16, u16),
+    MethodRef(u16, u16),
+    InterfaceMethodRef(u16, u16),
+    NameAndType(u16, u16),
+    MethodType(u16)
+}
+
+// Builds up a constant pool for an assembled class, handing out the same index for constants that
+// are added more than once.
+#[derive(Debug, Default)]
+pub struct PoolBuilder {
+    entries: Vec<PoolEntry>,
+    indices: HashMap<PoolEntry, u16>,
+    next_index: u32
+}
+
+impl PoolBuilder {
+    pub fn new() -> Self {
+        PoolBuilder { entries: Vec::new(), indices: HashMap::new(), next_index: 1 }
+    }
+
+    fn add(&mut self, entry: PoolEntry) -> Result<u16, AssembleError> {
+        if let Some(index) = self.indices.get(&entry) {
+            return Ok(*index);
+        }
+        let size = if matches!(entry, PoolEntry::Long(_) | PoolEntry::Double(_)) { 2 } else { 1 };
+        if self.next_index + size > u16::MAX as u32 {
+            return Err(AssembleError::new("Constant pool overflow! Too many constants in class!"));
+        }
+        let index = self.next_index as u16;
+        self.next_index += size;
+        self.indices.insert(entry.clone(), index);
+        self.entries.push(entry);
+        Ok(index)
+    }
+
+    pub fn utf8(&mut self, value: &str) -> Result<u16, AssembleError> {
+        if encode_modified_utf8(value).len() > u16::MAX as usize {
+            return Err(AssembleError::new(format!("String constant of length {} is too long!", value.len())));
+        }
+        self.add(PoolEntry::Utf8(value.to_string()))
+    }
+
+    pub fn integer(&mut self, value: i32) -> Result<u16, AssembleError> {
+        self.add(PoolEntry::Integer(value))
+    }
+
+    pub fn float(&mut self, value: f32) -> Result<u16, AssembleError> {
+        self.add(PoolEntry::Float(value.to_bits()))
+    }
+
+    pub fn long(&mut self, value: i64) -> Result<u16, AssembleError> {
+        self.add(PoolEntry::Long(value))
+    }
+
+    pub fn double(&mut self, value: f64) -> Result<u16, AssembleError> {
+        self.add(PoolEntry::Double(value.to_bits()))
+    }
+
+    pub fn class(&mut self, name: &str) -> Result<u16, AssembleError> {
+        let name_index = self.utf8(name)?;
+        self.add(PoolEntry::Class(name_index))
+    }
+
+    pub fn string(&mut self, value: &str) -> Result<u16, AssembleError> {
+        let value_index = self.utf8(value)?;
+        self.add(PoolEntry::String(value_index))
+    }
+
+    pub fn name_and_type(&mut self, name: &str, descriptor: &str) -> Result<u16, AssembleError> {
+        let name_index = self.utf8(name)?;
+        let descriptor_index = self.utf8(descriptor)?;
+        self.add(PoolEntry::NameAndType(name_index, descriptor_index))
+    }
+
+    pub fn field_ref(&mut self, owner: &str, name: &str, descriptor: &str) -> Result<u16, AssembleError> {
+        let class_index = self.class(owner)?;
+        let name_and_type_index = self.name_and_type(name, descriptor)?;
+        self.add(PoolEntry::FieldRef(class_index, name_and_type_index))
+    }
+
+    pub fn method_ref(
+        &mut self,
+        owner: &str,
+        name: &str,
+        descriptor: &str,
+        interface: bool
+    ) -> Result<u16, AssembleError> {
+        let class_index = self.class(owner)?;
+        let name_and_type_index = self.name_and_type(name, descriptor)?;
+        if interface {
+            self.add(PoolEntry::InterfaceMethodRef(class_index, name_and_type_index))
+        } else {
+            self.add(PoolEntry::MethodRef(class_index, name_and_type_index))
+        }
+    }
+
+    pub fn method_type(&mut self, descriptor: &str) -> Result<u16, AssembleError> {
+        let descriptor_index = self.utf8(descriptor)?;
+        self.add(PoolEntry::MethodType(descriptor_index))
+    }
+
+    pub(crate) fn write(&self, buf: &mut BytesMut) {
+        buf.put_u16(self.next_index as u16);
+        for entry in &self.entries {
+            match entry {
+                PoolEntry::Utf8(value) => {
+                    let bytes = encode_modified_utf8(value);
+                    buf.put_u8(UTF8_TAG);
+                    buf.put_u16(bytes.len() as u16);
+                    buf.put_slice(bytes.as_slice());
+                },
+                PoolEntry::Integer(value) => {
+                    buf.put_u8(INT_TAG);
+                    buf.put_i32(*value);
+                },
+                PoolEntry::Float(value) => {
+                    buf.put_u8(FLOAT_TAG);
+                    buf.put_u32(*value);
+                },
+                PoolEntry::Long(value) => {
+                    buf.put_u8(LONG_TAG);
+                    buf.put_i64(*value);
+                },
+                PoolEntry::Double(value) => {
+                    buf.put_u8(DOUBLE_TAG);
+                    buf.put_u64(*value);
+                },
+                PoolEntry::Class(index) => {
+                    buf.put_u8(CLASS_TAG);
+                    buf.put_u16(*index);
+                },
+                PoolEntry::String(index) => {
+                    buf.put_u8(STRING_TAG);
+                    buf.put_u16(*index);
+                },
+                PoolEntry::FieldRef(class_index, name_and_type_index) => {
+                    buf.put_u8(FIELD_REF_TAG);
+                    buf.put_u16(*class_index);
+                    buf.put_u16(*name_and_type_index);
+                },
+                PoolEntry::MethodRef(class_index, name_and_type_index) => {
+                    buf.put_u8(METHOD_REF_TAG);
+                    buf.put_u16(*class_index);
+                    buf.put_u16(*name_and_type_index);
+                },
+                PoolEntry::InterfaceMethodRef(class_index, name_and_type_index) => {
+                    buf.put_u8(INTERFACE_METHOD_REF_TAG);
+                    buf.put_u16(*class_index);
+                    buf.put_u16(*name_and_type_index);
+                },
+                PoolEntry::NameAndType(name_index, descriptor_index) => {
+                    buf.put_u8(NAME_AND_TYPE_TAG);
+                    buf.put_u16(*name_index);
+                    buf.put_u16(*descriptor_index);
+                },
+                PoolEntry::MethodType(index) => {
+                    buf.put_u8(METHOD_TYPE_TAG);
+                    buf.put_u16(*index);
+                }
+            }
+        }
+    }
+}
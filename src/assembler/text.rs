@@ -0,0 +1,597 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+
+use bytes::Bytes;
+use std::collections::HashMap;
+use crate::constants::*;
+use super::AssembleError;
+use super::builder::{ClassBuilder, MethodBuilder};
+use super::code::{Constant, Label};
+
+// A Jasmin-like syntax for writing classes by hand. For example:
+//
+// .class public Hello
+// .super java/lang/Object
+//
+// .method public static main([Ljava/lang/String;)V
+//     getstatic java/lang/System/out Ljava/io/PrintStream;
+//     ldc "Hello, world!"
+//     invokevirtual java/io/PrintStream/println(Ljava/lang/String;)V
+//     return
+// .end method
+//
+// Comments start with a semicolon at the start of a word, labels end with a colon, and limits are
+// computed when they're not given with .limit.
+pub fn assemble(source: &str) -> Result<Bytes, AssembleError> {
+    parse(source)?.build()
+}
+
+pub fn parse(source: &str) -> Result<ClassBuilder, AssembleError> {
+    let mut parser = Parser { class: None, method: None, switch: None };
+    for (number, line) in source.lines().enumerate() {
+        let tokens = tokenize(line).map_err(|error| error.at_line(number + 1))?;
+        if tokens.is_empty() {
+            continue;
+        }
+        parser.parse_line(&tokens).map_err(|error| error.at_line(number + 1))?;
+    }
+    if parser.method.is_some() {
+        return Err(AssembleError::new("Missing .end method at end of input!"));
+    }
+    parser.class.ok_or_else(|| AssembleError::new("Expected a .class or .interface directive!"))
+}
+
+struct MethodState {
+    index: usize,
+    labels: HashMap<String, Label>
+}
+
+enum SwitchState {
+    Table(i32, Vec<Label>),
+    Lookup(Vec<(i32, Label)>)
+}
+
+struct Parser {
+    class: Option<ClassBuilder>,
+    method: Option<MethodState>,
+    switch: Option<SwitchState>
+}
+
+impl Parser {
+    fn class(&mut self) -> Result<&mut ClassBuilder, AssembleError> {
+        self.class.as_mut().ok_or_else(|| AssembleError::new("Expected a .class or .interface directive first!"))
+    }
+
+    fn method(&mut self) -> Result<(&mut MethodBuilder, &mut HashMap<String, Label>), AssembleError> {
+        let state = self.method.as_mut().ok_or_else(|| AssembleError::new("Expected to be inside a method!"))?;
+        let method = self.class.as_mut().unwrap().method_at(state.index);
+        Ok((method, &mut state.labels))
+    }
+
+    fn label(&mut self, name: &str) -> Result<Label, AssembleError> {
+        let (method, labels) = self.method()?;
+        Ok(*labels.entry(name.to_string()).or_insert_with(|| method.code().new_label()))
+    }
+
+    fn parse_line(&mut self, tokens: &[String]) -> Result<(), AssembleError> {
+        if self.switch.is_some() {
+            return self.parse_switch_entry(tokens);
+        }
+        let first = tokens[0].as_str();
+        if first.starts_with('.') {
+            return self.parse_directive(tokens);
+        }
+        if let Some(name) = first.strip_suffix(':') {
+            let label = self.label(name)?;
+            self.method()?.0.code().place(label);
+            return if tokens.len() > 1 { self.parse_instruction(&tokens[1..]) } else { Ok(()) };
+        }
+        self.parse_instruction(tokens)
+    }
+
+    fn parse_directive(&mut self, tokens: &[String]) -> Result<(), AssembleError> {
+        let directive = tokens[0].as_str();
+        let arguments = &tokens[1..];
+        match directive {
+            ".class" | ".interface" => {
+                if self.class.is_some() {
+                    return Err(AssembleError::new("Only one class can be defined per file!"));
+                }
+                let (name, flags) = arguments.split_last()
+                    .ok_or_else(|| AssembleError::new(format!("Expected a class name after {}!", directive)))?;
+                let mut access_flags = parse_flags(flags, CLASS_FLAGS)?;
+                if directive == ".interface" {
+                    access_flags |= JVM_ACC_INTERFACE | JVM_ACC_ABSTRACT;
+                } else {
+                    access_flags |= JVM_ACC_SUPER;
+                }
+                let mut class = ClassBuilder::new(name);
+                class.access_flags(access_flags as u16);
+                self.class = Some(class);
+            },
+            ".super" => {
+                let name = single_argument(directive, arguments)?;
+                self.class()?.super_class(Some(name));
+            },
+            ".implements" => {
+                let name = single_argument(directive, arguments)?;
+                self.class()?.interface(name);
+            },
+            ".source" => {
+                let name = single_argument(directive, arguments)?;
+                self.class()?.source_file(name);
+            },
+            ".bytecode" => {
+                let version = single_argument(directive, arguments)?;
+                let (major, minor) = version.split_once('.').unwrap_or((version, "0"));
+                let major = parse_number::<u16>(major)?;
+                let minor = parse_number::<u16>(minor)?;
+                self.class()?.version(major, minor);
+            },
+            ".field" => {
+                let (definition, value) = match arguments.iter().position(|token| token == "=") {
+                    Some(index) => (&arguments[..index], Some(&arguments[index + 1..])),
+                    None => (arguments, None)
+                };
+                if definition.len() < 2 {
+                    return Err(AssembleError::new("Expected a name and descriptor for .field!"));
+                }
+                let (flags, name_and_descriptor) = definition.split_at(definition.len() - 2);
+                let access_flags = parse_flags(flags, FIELD_FLAGS)? as u16;
+                let field = self.class()?.field(access_flags, &name_and_descriptor[0], &name_and_descriptor[1]);
+                if let Some(value) = value {
+                    let value = single_argument(".field", value)?;
+                    let wide = name_and_descriptor[1] == "J" || name_and_descriptor[1] == "D";
+                    field.constant_value(parse_constant(value, wide)?);
+                }
+            },
+            ".method" => {
+                if self.method.is_some() {
+                    return Err(AssembleError::new("Missing .end method before next .method!"));
+                }
+                let (signature, flags) = arguments.split_last()
+                    .ok_or_else(|| AssembleError::new("Expected a name and descriptor after .method!"))?;
+                let split = signature.find(JVM_SIGNATURE_METHOD)
+                    .ok_or_else(|| AssembleError::new(format!("Invalid method signature {}!", signature)))?;
+                let access_flags = parse_flags(flags, METHOD_FLAGS)? as u16;
+                let class = self.class()?;
+                class.method(access_flags, &signature[..split], &signature[split..]);
+                let index = class.method_count() - 1;
+                self.method = Some(MethodState { index, labels: HashMap::new() });
+            },
+            ".end" => {
+                if arguments.first().map(String::as_str) != Some("method") || self.method.is_none() {
+                    return Err(AssembleError::new("Unexpected .end! Expected .end method inside a method!"));
+                }
+                self.method = None;
+            },
+            ".limit" => {
+                if arguments.len() != 2 {
+                    return Err(AssembleError::new("Expected .limit stack or .limit locals with a value!"));
+                }
+                let value = parse_number::<u16>(&arguments[1])?;
+                let code = self.method()?.0.code();
+                match arguments[0].as_str() {
+                    "stack" => code.max_stack(value),
+                    "locals" => code.max_locals(value),
+                    other => return Err(AssembleError::new(format!("Unknown limit {}!", other)))
+                };
+            },
+            ".throws" => {
+                let name = single_argument(directive, arguments)?;
+                self.method()?.0.throws(name);
+            },
+            ".line" => {
+                let line = parse_number::<u16>(single_argument(directive, arguments)?)?;
+                self.method()?.0.code().line(line);
+            },
+            ".catch" => {
+                // .catch <class> from <start> to <end> using <handler>
+                if arguments.len() != 7 || arguments[1] != "from" || arguments[3] != "to" || arguments[5] != "using" {
+                    return Err(AssembleError::new("Expected .catch <class> from <label> to <label> using <label>!"));
+                }
+                let start = self.label(&arguments[2])?;
+                let end = self.label(&arguments[4])?;
+                let handler = self.label(&arguments[6])?;
+                let catch_type = if arguments[0] == "all" { None } else { Some(arguments[0].as_str()) };
+                self.method()?.0.code().try_catch(start, end, handler, catch_type);
+            },
+            _ => return Err(AssembleError::new(format!("Unknown directive {}!", directive)))
+        }
+        Ok(())
+    }
+
+    fn parse_instruction(&mut self, tokens: &[String]) -> Result<(), AssembleError> {
+        let mut tokens = tokens;
+        // Wide forms are picked automatically, so an explicit wide prefix can be ignored.
+        if tokens[0] == "wide" {
+            tokens = &tokens[1..];
+            if tokens.is_empty() {
+                return Err(AssembleError::new("Expected an instruction after wide!"));
+            }
+        }
+        let mnemonic = tokens[0].as_str();
+        let arguments = &tokens[1..];
+        let opcode = opcode_from_name(mnemonic)
+            .ok_or_else(|| AssembleError::new(format!("Unknown instruction {}!", mnemonic)))?;
+        let expect = |count: usize| {
+            if arguments.len() == count {
+                Ok(())
+            } else {
+                Err(AssembleError::new(format!("Expected {} operands for {}, got {}!", count, mnemonic,
+                                               arguments.len())))
+            }
+        };
+        match opcode {
+            JVM_OPCODE_BIPUSH | JVM_OPCODE_SIPUSH => {
+                expect(1)?;
+                let code = self.method()?.0.code();
+                if opcode == JVM_OPCODE_BIPUSH {
+                    code.bipush(parse_number::<i8>(&arguments[0])?);
+                } else {
+                    code.sipush(parse_number::<i16>(&arguments[0])?);
+                }
+            },
+            JVM_OPCODE_LDC | JVM_OPCODE_LDC_W | JVM_OPCODE_LDC2_W => {
+                expect(1)?;
+                let constant = parse_constant(&arguments[0], opcode == JVM_OPCODE_LDC2_W)?;
+                self.method()?.0.code().ldc(constant);
+            },
+            JVM_OPCODE_ILOAD..=JVM_OPCODE_ALOAD | JVM_OPCODE_ISTORE..=JVM_OPCODE_ASTORE => {
+                expect(1)?;
+                let index = parse_number::<u16>(&arguments[0])?;
+                self.method()?.0.code().local(opcode, index);
+            },
+            JVM_OPCODE_IINC => {
+                expect(2)?;
+                let index = parse_number::<u16>(&arguments[0])?;
+                let amount = parse_number::<i16>(&arguments[1])?;
+                self.method()?.0.code().iinc(index, amount);
+            },
+            JVM_OPCODE_NEW | JVM_OPCODE_ANEWARRAY | JVM_OPCODE_CHECKCAST | JVM_OPCODE_INSTANCEOF => {
+                expect(1)?;
+                self.method()?.0.code().type_op(opcode, &arguments[0]);
+            },
+            JVM_OPCODE_NEWARRAY => {
+                expect(1)?;
+                let array_type = match arguments[0].as_str() {
+                    "boolean" => JVM_T_BOOLEAN,
+                    "char" => JVM_T_CHAR,
+                    "float" => JVM_T_FLOAT,
+                    "double" => JVM_T_DOUBLE,
+                    "byte" => JVM_T_BYTE,
+                    "short" => JVM_T_SHORT,
+                    "int" => JVM_T_INT,
+                    "long" => JVM_T_LONG,
+                    other => return Err(AssembleError::new(format!("Unknown array type {}!", other)))
+                };
+                self.method()?.0.code().new_array(array_type);
+            },
+            JVM_OPCODE_MULTIANEWARRAY => {
+                expect(2)?;
+                let dimensions = parse_number::<u8>(&arguments[1])?;
+                self.method()?.0.code().multi_new_array(&arguments[0], dimensions);
+            },
+            JVM_OPCODE_GETSTATIC..=JVM_OPCODE_PUTFIELD => {
+                expect(2)?;
+                let (owner, name) = split_member(&arguments[0])?;
+                self.method()?.0.code().field(opcode, owner, name, &arguments[1]);
+            },
+            JVM_OPCODE_INVOKEVIRTUAL..=JVM_OPCODE_INVOKEINTERFACE => {
+                // Jasmin requires the argument count for invokeinterface, but we can work it out.
+                if opcode != JVM_OPCODE_INVOKEINTERFACE || arguments.len() != 2 {
+                    expect(1)?;
+                }
+                let split = arguments[0].find(JVM_SIGNATURE_METHOD)
+                    .ok_or_else(|| AssembleError::new(format!("Invalid method reference {}!", arguments[0])))?;
+                let (owner, name) = split_member(&arguments[0][..split])?;
+                self.method()?.0.code().invoke(opcode, owner, name, &arguments[0][split..]);
+            },
+            JVM_OPCODE_IFEQ..=JVM_OPCODE_GOTO | JVM_OPCODE_IFNULL | JVM_OPCODE_IFNONNULL | JVM_OPCODE_GOTO_W => {
+                expect(1)?;
+                let label = self.label(&arguments[0])?;
+                self.method()?.0.code().jump(opcode, label);
+            },
+            JVM_OPCODE_TABLESWITCH => {
+                if arguments.is_empty() || arguments.len() > 2 {
+                    return Err(AssembleError::new("Expected tableswitch <low> [high]!"));
+                }
+                let low = parse_number::<i32>(&arguments[0])?;
+                self.method()?;
+                self.switch = Some(SwitchState::Table(low, Vec::new()));
+            },
+            JVM_OPCODE_LOOKUPSWITCH => {
+                expect(0)?;
+                self.method()?;
+                self.switch = Some(SwitchState::Lookup(Vec::new()));
+            },
+            JVM_OPCODE_JSR | JVM_OPCODE_JSR_W | JVM_OPCODE_RET => {
+                return Err(AssembleError::new(format!("Subroutine instruction {} is not supported!", mnemonic)));
+            },
+            JVM_OPCODE_INVOKEDYNAMIC | JVM_OPCODE_WIDE => {
+                return Err(AssembleError::new(format!("Instruction {} is not supported!", mnemonic)));
+            },
+            _ => {
+                expect(0)?;
+                self.method()?.0.code().op(opcode);
+            }
+        }
+        Ok(())
+    }
+
+    // Switch entries are written one per line after the switch, ending with the default.
+    fn parse_switch_entry(&mut self, tokens: &[String]) -> Result<(), AssembleError> {
+        let tokens = tokens.iter().map(String::as_str).filter(|token| *token != ":").collect::<Vec<_>>();
+        let (key, target) = match tokens.as_slice() {
+            [target] => (None, target.trim_start_matches(':')),
+            [key, target] => (Some(key.trim_end_matches(':')), target.trim_start_matches(':')),
+            _ => return Err(AssembleError::new("Invalid switch entry!"))
+        };
+        let label = self.label(target)?;
+        let is_default = key == Some("default");
+        match self.switch.as_mut().unwrap() {
+            SwitchState::Table(_, targets) if key.is_none() => targets.push(label),
+            SwitchState::Lookup(pairs) if key.is_some() && !is_default => {
+                pairs.push((parse_number::<i32>(key.unwrap())?, label));
+            },
+            _ if is_default => {},
+            _ => return Err(AssembleError::new("Invalid switch entry!"))
+        }
+        if is_default {
+            let switch = self.switch.take().unwrap();
+            let code = self.method()?.0.code();
+            match switch {
+                SwitchState::Table(low, targets) => code.table_switch(low, label, &targets),
+                SwitchState::Lookup(pairs) => code.lookup_switch(label, &pairs)
+            };
+        }
+        Ok(())
+    }
+}
+
+const CLASS_FLAGS: &[(&str, u32)] = &[
+    ("public", JVM_ACC_PUBLIC), ("final", JVM_ACC_FINAL), ("abstract", JVM_ACC_ABSTRACT),
+    ("synthetic", JVM_ACC_SYNTHETIC), ("annotation", JVM_ACC_ANNOTATION), ("enum", JVM_ACC_ENUM)
+];
+const FIELD_FLAGS: &[(&str, u32)] = &[
+    ("public", JVM_ACC_PUBLIC), ("private", JVM_ACC_PRIVATE), ("protected", JVM_ACC_PROTECTED),
+    ("static", JVM_ACC_STATIC), ("final", JVM_ACC_FINAL), ("volatile", JVM_ACC_VOLATILE),
+    ("transient", JVM_ACC_TRANSIENT), ("synthetic", JVM_ACC_SYNTHETIC), ("enum", JVM_ACC_ENUM)
+];
+const METHOD_FLAGS: &[(&str, u32)] = &[
+    ("public", JVM_ACC_PUBLIC), ("private", JVM_ACC_PRIVATE), ("protected", JVM_ACC_PROTECTED),
+    ("static", JVM_ACC_STATIC), ("final", JVM_ACC_FINAL), ("synchronized", JVM_ACC_SYNCHRONIZED),
+    ("bridge", JVM_ACC_BRIDGE), ("varargs", JVM_ACC_VARARGS), ("native", JVM_ACC_NATIVE),
+    ("abstract", JVM_ACC_ABSTRACT), ("strict", JVM_ACC_STRICT), ("synthetic", JVM_ACC_SYNTHETIC)
+];
+
+fn parse_flags(tokens: &[String], names: &[(&str, u32)]) -> Result<u32, AssembleError> {
+    tokens.iter().try_fold(0, |flags, token| {
+        names.iter()
+            .find(|(name, _)| name == token)
+            .map(|(_, flag)| flags | flag)
+            .ok_or_else(|| AssembleError::new(format!("Unknown access flag {}!", token)))
+    })
+}
+
+fn single_argument<'a>(directive: &str, arguments: &'a [String]) -> Result<&'a str, AssembleError> {
+    match arguments {
+        [argument] => Ok(argument.as_str()),
+        _ => Err(AssembleError::new(format!("Expected a single argument for {}!", directive)))
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str) -> Result<T, AssembleError> {
+    value.parse::<T>().map_err(|_| AssembleError::new(format!("Invalid number {}!", value)))
+}
+
+// Splits owner/name references, where the name is everything after the last slash.
+fn split_member(value: &str) -> Result<(&str, &str), AssembleError> {
+    value.rsplit_once(JVM_SIGNATURE_SEPARATOR)
+        .filter(|(owner, name)| !owner.is_empty() && !name.is_empty())
+        .ok_or_else(|| AssembleError::new(format!("Invalid member reference {}! Expected owner/name!", value)))
+}
+
+// Strings are kept in quotes by the tokenizer, so they can be told apart from class names.
+fn parse_constant(value: &str, wide: bool) -> Result<Constant, AssembleError> {
+    if let Some(string) = value.strip_prefix('"') {
+        return Ok(Constant::String(string.strip_suffix('"').unwrap_or(string).to_string()));
+    }
+    let first = value.chars().next().unwrap_or(' ');
+    if !first.is_ascii_digit() && first != '-' && first != '+' {
+        return Ok(Constant::Class(value.to_string()));
+    }
+    let is_floating = value.contains(['.', 'e', 'E']) || value.ends_with(['f', 'F', 'd', 'D']);
+    let trimmed = value.trim_end_matches(['f', 'F', 'd', 'D', 'l', 'L']);
+    match (wide, is_floating) {
+        (false, false) => parse_number(trimmed).map(Constant::Integer),
+        (false, true) => parse_number(trimmed).map(Constant::Float),
+        (true, false) => parse_number(trimmed).map(Constant::Long),
+        (true, true) => parse_number(trimmed).map(Constant::Double)
+    }
+}
+
+fn tokenize(line: &str) -> Result<Vec<String>, AssembleError> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&next) = chars.peek() {
+        if next.is_whitespace() {
+            chars.next();
+        } else if next == ';' {
+            break;
+        } else if next == '"' {
+            chars.next();
+            let mut token = String::from('"');
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => token.push(parse_escape(&mut chars)?),
+                    Some(value) => token.push(value),
+                    None => return Err(AssembleError::new("Unterminated string!"))
+                }
+            }
+            token.push('"');
+            tokens.push(token);
+        } else {
+            let mut token = String::new();
+            while let Some(&value) = chars.peek() {
+                if value.is_whitespace() {
+                    break;
+                }
+                token.push(value);
+                chars.next();
+            }
+            tokens.push(token);
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_escape(chars: &mut impl Iterator<Item = char>) -> Result<char, AssembleError> {
+    match chars.next() {
+        Some('n') => Ok('\n'),
+        Some('t') => Ok('\t'),
+        Some('r') => Ok('\r'),
+        Some('0') => Ok('\0'),
+        Some('"') => Ok('"'),
+        Some('\\') => Ok('\\'),
+        Some('u') => {
+            let hex = chars.take(4).collect::<String>();
+            u32::from_str_radix(&hex, 16)
+                .ok()
+                .and_then(char::from_u32)
+                .ok_or_else(|| AssembleError::new(format!("Invalid unicode escape \\u{}!", hex)))
+        },
+        other => Err(AssembleError::new(format!("Invalid escape sequence \\{}!", other.unwrap_or(' '))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use crate::utils::IStr;
+    use crate::class_file::ClassLoader;
+    use crate::types::Class;
+    use crate::verifier::ClassInfo;
+    use super::{assemble, parse};
+
+    const SOURCE: &str = r#"
+.class public Counter
+.super java/lang/Object
+.source Counter.j
+
+.field private static final LIMIT I = 10
+.field private count J
+
+.method public <init>()V
+    aload_0
+    invokespecial java/lang/Object/<init>()V
+    return
+.end method
+
+; Sums the numbers from 0 up to the limit, skipping those the switch ignores
+.method public static sum(I)I
+.line 1
+    iconst_0
+    istore_1
+    iconst_0
+    istore_2
+Loop:
+    iload_2
+    getstatic Counter/LIMIT I
+    if_icmpge End
+    iload_2
+    tableswitch 3 4
+        Skip
+        Skip
+        default : Add
+Add:
+    iload_1
+    iload_2
+    iadd
+    istore_1
+Skip:
+    iinc 2 1
+    goto Loop
+End:
+    iload_1
+    ireturn
+.end method
+
+.method public static parse(Ljava/lang/String;)I
+Start:
+    aload_0
+    invokestatic java/lang/Integer/parseInt(Ljava/lang/String;)I
+End:
+    ireturn
+Handler:
+    pop
+    ldc -1
+    ireturn
+.catch java/lang/NumberFormatException from Start to End using Handler
+.end method
+"#;
+
+    #[test]
+    fn assembles_text() {
+        // The handler's frame needs to know the exception is a Throwable
+        let mut hierarchy = HashMap::new();
+        for (name, super_class) in [
+            ("java/lang/NumberFormatException", "java/lang/IllegalArgumentException"),
+            ("java/lang/IllegalArgumentException", "java/lang/RuntimeException"),
+            ("java/lang/RuntimeException", "java/lang/Exception"),
+            ("java/lang/Exception", "java/lang/Throwable"),
+            ("java/lang/Throwable", "java/lang/Object")
+        ] {
+            let info = ClassInfo { super_class: Some(IStr::new(super_class)), is_interface: false };
+            hierarchy.insert(String::from(name), info);
+        }
+        let bytes = parse(SOURCE).unwrap().build_with(&hierarchy).unwrap();
+        let class = Class::from_bytes(Arc::new(ClassLoader::new()), Bytes::clone(&bytes));
+        assert_eq!(class.name(), "Counter");
+        assert_eq!(class.source_file_name(), Some("Counter.j"));
+        assert_eq!(class.fields().len(), 2);
+
+        let sum = class.methods().iter().find(|method| method.name() == "sum").unwrap();
+        let code = sum.code().unwrap();
+        assert_eq!(code.max_stack(), 2);
+        assert_eq!(code.max_locals(), 3);
+        // Loop, Add, Skip and End all need frames
+        assert_eq!(code.stack_map_table().unwrap().len(), 4);
+        assert_eq!(code.line_numbers()[0].len(), 1);
+
+        let parse = class.methods().iter().find(|method| method.name() == "parse").unwrap();
+        let code = parse.code().unwrap();
+        assert_eq!(code.exception_handlers().len(), 1);
+        assert_eq!(code.exception_handlers().get(0).unwrap().catch_type(),
+                   Some("java/lang/NumberFormatException"));
+        assert_eq!(code.stack_map_table().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn reports_lines() {
+        let error = assemble(".class Broken\n.method public static f()V\n    iadd\n    return\n.end method\n")
+            .unwrap_err();
+        assert!(error.message().contains("underflow"), "{}", error);
+
+        let error = assemble(".class Broken\n.method public static f()V\n    frobnicate\n.end method\n")
+            .unwrap_err();
+        assert_eq!(error.line(), Some(3));
+    }
+}
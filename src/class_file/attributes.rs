@@ -0,0 +1,190 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+use bytes::{Buf, Bytes};
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+use crate::utils::IStr;
+use crate::types::ConstantPool;
+
+pub type AttributeValue = Arc<dyn Any + Send + Sync>;
+pub type AttributeParser = dyn Fn(&ConstantPool, Bytes) -> AttributeValue + Send + Sync;
+
+// An attribute that the class file parser doesn't understand itself, kept around as its name and
+// the raw bytes of its info, along with the value produced by a registered parser, if there is one.
+#[derive(Clone)]
+pub struct Attribute {
+    name: IStr,
+    info: Bytes,
+    value: Option<AttributeValue>
+}
+
+impl Attribute {
+    pub fn new(name: IStr, info: Bytes) -> Self {
+        Attribute { name, info, value: None }
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    pub fn info(&self) -> &[u8] {
+        &self.info
+    }
+
+    pub fn len(&self) -> usize {
+        self.info.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.info.is_empty()
+    }
+
+    pub fn is_parsed(&self) -> bool {
+        self.value.is_some()
+    }
+
+    pub fn value<T: Any>(&self) -> Option<&T> {
+        self.value.as_ref().and_then(|value| value.downcast_ref::<T>())
+    }
+}
+
+impl Debug for Attribute {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Attribute")
+            .field("name", &self.name)
+            .field("length", &self.info.len())
+            .field("parsed", &self.value.is_some())
+            .finish()
+    }
+}
+
+// Decides what happens to attributes that aren't part of the specification. By default, these are
+// skipped, as the VM has no use for them, but tools that inspect or rewrite class files can opt in
+// to keeping them, and register parsers for the ones they understand.
+#[derive(Default)]
+pub struct AttributeRegistry {
+    retain_unknown: bool,
+    parsers: HashMap<IStr, Arc<AttributeParser>>
+}
+
+impl AttributeRegistry {
+    pub fn new(retain_unknown: bool) -> Self {
+        AttributeRegistry { retain_unknown, parsers: HashMap::new() }
+    }
+
+    pub fn retains_unknown(&self) -> bool {
+        self.retain_unknown
+    }
+
+    pub fn set_retain_unknown(&mut self, retain_unknown: bool) {
+        self.retain_unknown = retain_unknown;
+    }
+
+    pub fn register<T: Any + Send + Sync>(
+        &mut self,
+        name: &str,
+        parser: impl Fn(&ConstantPool, Bytes) -> T + Send + Sync + 'static
+    ) {
+        let parser: Arc<AttributeParser> = Arc::new(move |pool, info| Arc::new(parser(pool, info)));
+        self.parsers.insert(IStr::new(name), parser);
+    }
+
+    pub fn is_registered(&self, name: &str) -> bool {
+        self.parsers.contains_key(name)
+    }
+
+    // Called by the attribute parsers when they come across an attribute they don't recognise.
+    // Registered attributes are always kept, as someone has explicitly asked for them.
+    pub(crate) fn read(
+        &self,
+        pool: &ConstantPool,
+        name: IStr,
+        buf: &mut Bytes,
+        length: u32
+    ) -> Option<Attribute> {
+        assert!(buf.remaining() >= length as usize, "Truncated {} attribute! Expected {} bytes, \
+            only {} remaining!", name, length, buf.remaining());
+        let info = buf.copy_to_bytes(length as usize);
+        match self.parsers.get(&name) {
+            Some(parser) => {
+                let value = parser(pool, info.clone());
+                Some(Attribute { name, info, value: Some(value) })
+            },
+            None if self.retain_unknown => Some(Attribute::new(name, info)),
+            None => None
+        }
+    }
+}
+
+impl Debug for AttributeRegistry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AttributeRegistry")
+            .field("retain_unknown", &self.retain_unknown)
+            .field("parsers", &self.parsers.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{Buf, BufMut, Bytes, BytesMut};
+    use crate::utils::IStr;
+    use crate::types::ConstantPool;
+    use super::AttributeRegistry;
+
+    fn empty_pool() -> ConstantPool {
+        ConstantPool::parse(&mut Bytes::from_static(&[0, 1]))
+    }
+
+    fn attribute_bytes() -> Bytes {
+        let mut buf = BytesMut::new();
+        buf.put_slice(&[1, 2, 3, 4]);
+        buf.put_u8(0xFF);
+        buf.freeze()
+    }
+
+    #[test]
+    fn unknown_skipped_by_default() {
+        let registry = AttributeRegistry::default();
+        let mut buf = attribute_bytes();
+        assert!(registry.read(&empty_pool(), IStr::new("Foo"), &mut buf, 4).is_none());
+        assert_eq!(buf.get_u8(), 0xFF);
+    }
+
+    #[test]
+    fn unknown_retained() {
+        let registry = AttributeRegistry::new(true);
+        let mut buf = attribute_bytes();
+        let attribute = registry.read(&empty_pool(), IStr::new("Foo"), &mut buf, 4).unwrap();
+        assert_eq!(attribute.name(), "Foo");
+        assert_eq!(attribute.info(), &[1, 2, 3, 4]);
+        assert!(!attribute.is_parsed());
+        assert_eq!(buf.get_u8(), 0xFF);
+    }
+
+    #[test]
+    fn registered_parser() {
+        let mut registry = AttributeRegistry::default();
+        registry.register("Foo", |_, mut info| info.get_u32());
+        let mut buf = attribute_bytes();
+        let attribute = registry.read(&empty_pool(), IStr::new("Foo"), &mut buf, 4).unwrap();
+        assert_eq!(attribute.value::<u32>(), Some(&0x01020304));
+        assert_eq!(attribute.value::<u16>(), None);
+    }
+}
@@ -0,0 +1,649 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use crate::constants::*;
+
+// A single decoded instruction. Instructions that only differ in how their operands are encoded, such
+// as iload_0, iload 0 and wide iload 0, decode to the same value, and the original opcode is kept on
+// DecodedInstruction for anything that needs to tell them apart. Branch targets are absolute offsets
+// into the code, rather than being relative to the branch like they are in the class file.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instruction {
+    Nop,
+    AConstNull,
+    IConst(i32),
+    LConst(i64),
+    FConst(f32),
+    DConst(f64),
+    Ldc(u16),
+    Load(ValueKind, u16),
+    Store(ValueKind, u16),
+    ArrayLoad(ArrayKind),
+    ArrayStore(ArrayKind),
+    Pop,
+    Pop2,
+    Dup,
+    DupX1,
+    DupX2,
+    Dup2,
+    Dup2X1,
+    Dup2X2,
+    Swap,
+    Arithmetic(ValueKind, ArithmeticOp),
+    IInc(u16, i16),
+    Convert(ValueKind, ValueKind),
+    IntToByte,
+    IntToChar,
+    IntToShort,
+    LCmp,
+    FCmpL,
+    FCmpG,
+    DCmpL,
+    DCmpG,
+    If(Condition, u32),
+    IfICmp(Condition, u32),
+    IfACmp(Condition, u32),
+    IfNull(u32),
+    IfNonNull(u32),
+    Goto(u32),
+    Jsr(u32),
+    Ret(u16),
+    TableSwitch { default: u32, low: i32, targets: Vec<u32> },
+    LookupSwitch { default: u32, pairs: Vec<(i32, u32)> },
+    Return(Option<ValueKind>),
+    GetStatic(u16),
+    PutStatic(u16),
+    GetField(u16),
+    PutField(u16),
+    InvokeVirtual(u16),
+    InvokeSpecial(u16),
+    InvokeStatic(u16),
+    InvokeInterface(u16, u8),
+    InvokeDynamic(u16),
+    New(u16),
+    NewArray(BasicType),
+    ANewArray(u16),
+    ArrayLength,
+    AThrow,
+    CheckCast(u16),
+    InstanceOf(u16),
+    MonitorEnter,
+    MonitorExit,
+    MultiANewArray(u16, u8)
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum ValueKind {
+    Int,
+    Long,
+    Float,
+    Double,
+    Reference
+}
+
+// Byte arrays and boolean arrays share the same instructions.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum ArrayKind {
+    Int,
+    Long,
+    Float,
+    Double,
+    Reference,
+    Byte,
+    Char,
+    Short
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum ArithmeticOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Neg,
+    Shl,
+    Shr,
+    UShr,
+    And,
+    Or,
+    Xor
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Condition {
+    Eq,
+    Ne,
+    Lt,
+    Ge,
+    Gt,
+    Le
+}
+
+impl Condition {
+    pub fn test(&self, first: i32, second: i32) -> bool {
+        match self {
+            Condition::Eq => first == second,
+            Condition::Ne => first != second,
+            Condition::Lt => first < second,
+            Condition::Ge => first >= second,
+            Condition::Gt => first > second,
+            Condition::Le => first <= second
+        }
+    }
+}
+
+const VALUE_KINDS: [ValueKind; 5] = [
+    ValueKind::Int, ValueKind::Long, ValueKind::Float, ValueKind::Double, ValueKind::Reference
+];
+const ARRAY_KINDS: [ArrayKind; 8] = [
+    ArrayKind::Int, ArrayKind::Long, ArrayKind::Float, ArrayKind::Double, ArrayKind::Reference, ArrayKind::Byte,
+    ArrayKind::Char, ArrayKind::Short
+];
+const CONDITIONS: [Condition; 6] = [
+    Condition::Eq, Condition::Ne, Condition::Lt, Condition::Ge, Condition::Gt, Condition::Le
+];
+const ARITHMETIC_OPS: [ArithmeticOp; 12] = [
+    ArithmeticOp::Add, ArithmeticOp::Sub, ArithmeticOp::Mul, ArithmeticOp::Div, ArithmeticOp::Rem, ArithmeticOp::Neg,
+    ArithmeticOp::Shl, ArithmeticOp::Shr, ArithmeticOp::UShr, ArithmeticOp::And, ArithmeticOp::Or, ArithmeticOp::Xor
+];
+// i2l through d2f, in opcode order
+const CONVERSIONS: [(ValueKind, ValueKind); 12] = [
+    (ValueKind::Int, ValueKind::Long), (ValueKind::Int, ValueKind::Float), (ValueKind::Int, ValueKind::Double),
+    (ValueKind::Long, ValueKind::Int), (ValueKind::Long, ValueKind::Float), (ValueKind::Long, ValueKind::Double),
+    (ValueKind::Float, ValueKind::Int), (ValueKind::Float, ValueKind::Long), (ValueKind::Float, ValueKind::Double),
+    (ValueKind::Double, ValueKind::Int), (ValueKind::Double, ValueKind::Long), (ValueKind::Double, ValueKind::Float)
+];
+
+impl Instruction {
+    // The offsets that this instruction may jump to, not including the instruction that follows it.
+    pub fn branch_targets(&self) -> Vec<u32> {
+        match self {
+            Instruction::If(_, target) | Instruction::IfICmp(_, target) | Instruction::IfACmp(_, target) |
+            Instruction::IfNull(target) | Instruction::IfNonNull(target) | Instruction::Goto(target) |
+            Instruction::Jsr(target) => vec![*target],
+            Instruction::TableSwitch { default, targets, .. } => {
+                let mut result = vec![*default];
+                result.extend(targets);
+                result
+            },
+            Instruction::LookupSwitch { default, pairs } => {
+                let mut result = vec![*default];
+                result.extend(pairs.iter().map(|pair| pair.1));
+                result
+            },
+            _ => Vec::new()
+        }
+    }
+
+    // Whether execution can never continue on to the next instruction after this one.
+    pub fn is_unconditional(&self) -> bool {
+        matches!(self, Instruction::Goto(_) | Instruction::Ret(_) | Instruction::TableSwitch { .. } |
+            Instruction::LookupSwitch { .. } | Instruction::Return(_) | Instruction::AThrow)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedInstruction {
+    offset: u32,
+    length: u32,
+    opcode: u8,
+    wide: bool,
+    instruction: Instruction
+}
+
+impl DecodedInstruction {
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+
+    pub fn length(&self) -> u32 {
+        self.length
+    }
+
+    pub fn next_offset(&self) -> u32 {
+        self.offset + self.length
+    }
+
+    // For wide instructions, this is the opcode of the instruction being widened.
+    pub fn opcode(&self) -> u8 {
+        self.opcode
+    }
+
+    pub fn is_wide(&self) -> bool {
+        self.wide
+    }
+
+    pub fn instruction(&self) -> &Instruction {
+        &self.instruction
+    }
+
+    pub fn name(&self) -> String {
+        let name = opcode_name(self.opcode).unwrap_or("<illegal>");
+        if self.wide { format!("{}_w", name) } else { name.to_string() }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeError {
+    offset: u32,
+    message: String
+}
+
+impl DecodeError {
+    fn new(offset: usize, message: impl Into<String>) -> Self {
+        DecodeError { offset: offset as u32, message: message.into() }
+    }
+
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid instruction at offset {}: {}", self.offset, self.message)
+    }
+}
+
+impl Error for DecodeError {}
+
+// Decodes instructions one after the other from the start of the code. Iteration stops after the first
+// error, since there's no way of knowing where the next instruction starts after that.
+pub struct InstructionDecoder<'a> {
+    code: &'a [u8],
+    offset: usize,
+    failed: bool
+}
+
+impl<'a> InstructionDecoder<'a> {
+    pub fn new(code: &'a [u8]) -> Self {
+        InstructionDecoder { code, offset: 0, failed: false }
+    }
+
+    pub fn decode_all(code: &[u8]) -> Result<Vec<DecodedInstruction>, DecodeError> {
+        InstructionDecoder::new(code).collect()
+    }
+
+    // Decodes the single instruction that starts at the given offset.
+    pub fn decode(code: &[u8], offset: u32) -> Result<DecodedInstruction, DecodeError> {
+        let offset = offset as usize;
+        let reader = OperandReader { code, start: offset };
+        let opcode = reader.u8(0)?;
+        let mut wide = false;
+        let (instruction, length) = match opcode {
+            JVM_OPCODE_NOP => (Instruction::Nop, 1),
+            JVM_OPCODE_ACONST_NULL => (Instruction::AConstNull, 1),
+            JVM_OPCODE_ICONST_M1..=JVM_OPCODE_ICONST_5 => {
+                (Instruction::IConst(opcode as i32 - JVM_OPCODE_ICONST_0 as i32), 1)
+            },
+            JVM_OPCODE_LCONST_0 | JVM_OPCODE_LCONST_1 => {
+                (Instruction::LConst((opcode - JVM_OPCODE_LCONST_0) as i64), 1)
+            },
+            JVM_OPCODE_FCONST_0..=JVM_OPCODE_FCONST_2 => {
+                (Instruction::FConst((opcode - JVM_OPCODE_FCONST_0) as f32), 1)
+            },
+            JVM_OPCODE_DCONST_0 | JVM_OPCODE_DCONST_1 => {
+                (Instruction::DConst((opcode - JVM_OPCODE_DCONST_0) as f64), 1)
+            },
+            JVM_OPCODE_BIPUSH => (Instruction::IConst(reader.u8(1)? as i8 as i32), 2),
+            JVM_OPCODE_SIPUSH => (Instruction::IConst(reader.u16(1)? as i16 as i32), 3),
+            JVM_OPCODE_LDC => (Instruction::Ldc(reader.u8(1)? as u16), 2),
+            JVM_OPCODE_LDC_W | JVM_OPCODE_LDC2_W => (Instruction::Ldc(reader.u16(1)?), 3),
+            JVM_OPCODE_ILOAD..=JVM_OPCODE_ALOAD => {
+                (Instruction::Load(VALUE_KINDS[(opcode - JVM_OPCODE_ILOAD) as usize], reader.u8(1)? as u16), 2)
+            },
+            JVM_OPCODE_ILOAD_0..=JVM_OPCODE_ALOAD_3 => {
+                let index = opcode - JVM_OPCODE_ILOAD_0;
+                (Instruction::Load(VALUE_KINDS[(index / 4) as usize], (index % 4) as u16), 1)
+            },
+            JVM_OPCODE_IALOAD..=JVM_OPCODE_SALOAD => {
+                (Instruction::ArrayLoad(ARRAY_KINDS[(opcode - JVM_OPCODE_IALOAD) as usize]), 1)
+            },
+            JVM_OPCODE_ISTORE..=JVM_OPCODE_ASTORE => {
+                (Instruction::Store(VALUE_KINDS[(opcode - JVM_OPCODE_ISTORE) as usize], reader.u8(1)? as u16), 2)
+            },
+            JVM_OPCODE_ISTORE_0..=JVM_OPCODE_ASTORE_3 => {
+                let index = opcode - JVM_OPCODE_ISTORE_0;
+                (Instruction::Store(VALUE_KINDS[(index / 4) as usize], (index % 4) as u16), 1)
+            },
+            JVM_OPCODE_IASTORE..=JVM_OPCODE_SASTORE => {
+                (Instruction::ArrayStore(ARRAY_KINDS[(opcode - JVM_OPCODE_IASTORE) as usize]), 1)
+            },
+            JVM_OPCODE_POP => (Instruction::Pop, 1),
+            JVM_OPCODE_POP2 => (Instruction::Pop2, 1),
+            JVM_OPCODE_DUP => (Instruction::Dup, 1),
+            JVM_OPCODE_DUP_X1 => (Instruction::DupX1, 1),
+            JVM_OPCODE_DUP_X2 => (Instruction::DupX2, 1),
+            JVM_OPCODE_DUP2 => (Instruction::Dup2, 1),
+            JVM_OPCODE_DUP2_X1 => (Instruction::Dup2X1, 1),
+            JVM_OPCODE_DUP2_X2 => (Instruction::Dup2X2, 1),
+            JVM_OPCODE_SWAP => (Instruction::Swap, 1),
+            // Everything up to neg exists for all four kinds, and everything after only for int and long
+            JVM_OPCODE_IADD..=JVM_OPCODE_DNEG => {
+                let index = opcode - JVM_OPCODE_IADD;
+                let op = ARITHMETIC_OPS[(index / 4) as usize];
+                (Instruction::Arithmetic(VALUE_KINDS[(index % 4) as usize], op), 1)
+            },
+            JVM_OPCODE_ISHL..=JVM_OPCODE_LXOR => {
+                let index = opcode - JVM_OPCODE_ISHL;
+                let op = ARITHMETIC_OPS[(6 + index / 2) as usize];
+                (Instruction::Arithmetic(VALUE_KINDS[(index % 2) as usize], op), 1)
+            },
+            JVM_OPCODE_IINC => (Instruction::IInc(reader.u8(1)? as u16, reader.u8(2)? as i8 as i16), 3),
+            JVM_OPCODE_I2L..=JVM_OPCODE_D2F => {
+                let (from, to) = CONVERSIONS[(opcode - JVM_OPCODE_I2L) as usize];
+                (Instruction::Convert(from, to), 1)
+            },
+            JVM_OPCODE_I2B => (Instruction::IntToByte, 1),
+            JVM_OPCODE_I2C => (Instruction::IntToChar, 1),
+            JVM_OPCODE_I2S => (Instruction::IntToShort, 1),
+            JVM_OPCODE_LCMP => (Instruction::LCmp, 1),
+            JVM_OPCODE_FCMPL => (Instruction::FCmpL, 1),
+            JVM_OPCODE_FCMPG => (Instruction::FCmpG, 1),
+            JVM_OPCODE_DCMPL => (Instruction::DCmpL, 1),
+            JVM_OPCODE_DCMPG => (Instruction::DCmpG, 1),
+            JVM_OPCODE_IFEQ..=JVM_OPCODE_IFLE => {
+                let condition = CONDITIONS[(opcode - JVM_OPCODE_IFEQ) as usize];
+                (Instruction::If(condition, reader.branch_target(1, false)?), 3)
+            },
+            JVM_OPCODE_IF_ICMPEQ..=JVM_OPCODE_IF_ICMPLE => {
+                let condition = CONDITIONS[(opcode - JVM_OPCODE_IF_ICMPEQ) as usize];
+                (Instruction::IfICmp(condition, reader.branch_target(1, false)?), 3)
+            },
+            JVM_OPCODE_IF_ACMPEQ | JVM_OPCODE_IF_ACMPNE => {
+                let condition = CONDITIONS[(opcode - JVM_OPCODE_IF_ACMPEQ) as usize];
+                (Instruction::IfACmp(condition, reader.branch_target(1, false)?), 3)
+            },
+            JVM_OPCODE_GOTO => (Instruction::Goto(reader.branch_target(1, false)?), 3),
+            JVM_OPCODE_JSR => (Instruction::Jsr(reader.branch_target(1, false)?), 3),
+            JVM_OPCODE_RET => (Instruction::Ret(reader.u8(1)? as u16), 2),
+            JVM_OPCODE_TABLESWITCH => reader.table_switch()?,
+            JVM_OPCODE_LOOKUPSWITCH => reader.lookup_switch()?,
+            JVM_OPCODE_IRETURN..=JVM_OPCODE_ARETURN => {
+                (Instruction::Return(Some(VALUE_KINDS[(opcode - JVM_OPCODE_IRETURN) as usize])), 1)
+            },
+            JVM_OPCODE_RETURN => (Instruction::Return(None), 1),
+            JVM_OPCODE_GETSTATIC => (Instruction::GetStatic(reader.u16(1)?), 3),
+            JVM_OPCODE_PUTSTATIC => (Instruction::PutStatic(reader.u16(1)?), 3),
+            JVM_OPCODE_GETFIELD => (Instruction::GetField(reader.u16(1)?), 3),
+            JVM_OPCODE_PUTFIELD => (Instruction::PutField(reader.u16(1)?), 3),
+            JVM_OPCODE_INVOKEVIRTUAL => (Instruction::InvokeVirtual(reader.u16(1)?), 3),
+            JVM_OPCODE_INVOKESPECIAL => (Instruction::InvokeSpecial(reader.u16(1)?), 3),
+            JVM_OPCODE_INVOKESTATIC => (Instruction::InvokeStatic(reader.u16(1)?), 3),
+            JVM_OPCODE_INVOKEINTERFACE => {
+                let count = reader.u8(3)?;
+                if count == 0 {
+                    return Err(DecodeError::new(offset, "invokeinterface count must not be zero"));
+                }
+                if reader.u8(4)? != 0 {
+                    return Err(DecodeError::new(offset, "Fourth operand byte of invokeinterface must be zero"));
+                }
+                (Instruction::InvokeInterface(reader.u16(1)?, count), 5)
+            },
+            JVM_OPCODE_INVOKEDYNAMIC => {
+                if reader.u16(3)? != 0 {
+                    return Err(DecodeError::new(offset, "Third and fourth operand bytes of invokedynamic \
+                        must be zero"));
+                }
+                (Instruction::InvokeDynamic(reader.u16(1)?), 5)
+            },
+            JVM_OPCODE_NEW => (Instruction::New(reader.u16(1)?), 3),
+            JVM_OPCODE_NEWARRAY => {
+                let array_type = reader.u8(1)?;
+                let array_type = BasicType::from(array_type)
+                    .ok_or_else(|| DecodeError::new(offset, format!("Invalid newarray type {}", array_type)))?;
+                (Instruction::NewArray(array_type), 2)
+            },
+            JVM_OPCODE_ANEWARRAY => (Instruction::ANewArray(reader.u16(1)?), 3),
+            JVM_OPCODE_ARRAYLENGTH => (Instruction::ArrayLength, 1),
+            JVM_OPCODE_ATHROW => (Instruction::AThrow, 1),
+            JVM_OPCODE_CHECKCAST => (Instruction::CheckCast(reader.u16(1)?), 3),
+            JVM_OPCODE_INSTANCEOF => (Instruction::InstanceOf(reader.u16(1)?), 3),
+            JVM_OPCODE_MONITORENTER => (Instruction::MonitorEnter, 1),
+            JVM_OPCODE_MONITOREXIT => (Instruction::MonitorExit, 1),
+            JVM_OPCODE_WIDE => {
+                wide = true;
+                reader.wide()?
+            },
+            JVM_OPCODE_MULTIANEWARRAY => {
+                let dimensions = reader.u8(3)?;
+                if dimensions == 0 {
+                    return Err(DecodeError::new(offset, "multianewarray dimensions must not be zero"));
+                }
+                (Instruction::MultiANewArray(reader.u16(1)?, dimensions), 4)
+            },
+            JVM_OPCODE_IFNULL => (Instruction::IfNull(reader.branch_target(1, false)?), 3),
+            JVM_OPCODE_IFNONNULL => (Instruction::IfNonNull(reader.branch_target(1, false)?), 3),
+            JVM_OPCODE_GOTO_W => (Instruction::Goto(reader.branch_target(1, true)?), 5),
+            JVM_OPCODE_JSR_W => (Instruction::Jsr(reader.branch_target(1, true)?), 5),
+            _ => return Err(DecodeError::new(offset, format!("Unknown opcode {}", opcode)))
+        };
+        let opcode = if wide { reader.u8(1)? } else { opcode };
+        Ok(DecodedInstruction { offset: offset as u32, length, opcode, wide, instruction })
+    }
+}
+
+impl Iterator for InstructionDecoder<'_> {
+    type Item = Result<DecodedInstruction, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.offset >= self.code.len() {
+            return None;
+        }
+        let result = InstructionDecoder::decode(self.code, self.offset as u32);
+        match &result {
+            Ok(instruction) => self.offset = instruction.next_offset() as usize,
+            Err(_) => self.failed = true
+        }
+        Some(result)
+    }
+}
+
+// Reads operands relative to the start of the instruction being decoded.
+struct OperandReader<'a> {
+    code: &'a [u8],
+    start: usize
+}
+
+impl OperandReader<'_> {
+    fn bytes<const N: usize>(&self, index: usize) -> Result<[u8; N], DecodeError> {
+        let start = self.start + index;
+        self.code.get(start..start + N)
+            .and_then(|value| value.try_into().ok())
+            .ok_or_else(|| DecodeError::new(self.start, "Instruction is truncated by the end of the code"))
+    }
+
+    fn u8(&self, index: usize) -> Result<u8, DecodeError> {
+        self.bytes::<1>(index).map(|value| value[0])
+    }
+
+    fn u16(&self, index: usize) -> Result<u16, DecodeError> {
+        self.bytes(index).map(u16::from_be_bytes)
+    }
+
+    fn i32(&self, index: usize) -> Result<i32, DecodeError> {
+        self.bytes(index).map(i32::from_be_bytes)
+    }
+
+    fn target(&self, relative: i64) -> Result<u32, DecodeError> {
+        let target = self.start as i64 + relative;
+        if target < 0 || target > u32::MAX as i64 {
+            return Err(DecodeError::new(self.start, format!("Branch target {} is out of range", target)));
+        }
+        Ok(target as u32)
+    }
+
+    fn branch_target(&self, index: usize, wide: bool) -> Result<u32, DecodeError> {
+        let relative = if wide { self.i32(index)? as i64 } else { self.u16(index)? as i16 as i64 };
+        self.target(relative)
+    }
+
+    // The operands of switches start at the next multiple of 4 from the start of the code, so there can be
+    // up to 3 bytes of padding before them.
+    fn padding(&self) -> usize {
+        3 - (self.start % 4)
+    }
+
+    fn table_switch(&self) -> Result<(Instruction, u32), DecodeError> {
+        let base = 1 + self.padding();
+        let default = self.target(self.i32(base)? as i64)?;
+        let low = self.i32(base + 4)?;
+        let high = self.i32(base + 8)?;
+        if low > high {
+            return Err(DecodeError::new(self.start, format!("tableswitch low {} is greater than high {}", low,
+                                                            high)));
+        }
+        let count = (high as i64 - low as i64 + 1) as usize;
+        if self.start + base + 12 + count * 4 > self.code.len() {
+            return Err(DecodeError::new(self.start, "Instruction is truncated by the end of the code"));
+        }
+        let targets = (0..count)
+            .map(|index| self.i32(base + 12 + index * 4).and_then(|value| self.target(value as i64)))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok((Instruction::TableSwitch { default, low, targets }, (base + 12 + count * 4) as u32))
+    }
+
+    fn lookup_switch(&self) -> Result<(Instruction, u32), DecodeError> {
+        let base = 1 + self.padding();
+        let default = self.target(self.i32(base)? as i64)?;
+        let count = self.i32(base + 4)?;
+        if count < 0 {
+            return Err(DecodeError::new(self.start, format!("lookupswitch has negative pair count {}", count)));
+        }
+        let count = count as usize;
+        if self.start + base + 8 + count * 8 > self.code.len() {
+            return Err(DecodeError::new(self.start, "Instruction is truncated by the end of the code"));
+        }
+        let mut pairs: Vec<(i32, u32)> = Vec::with_capacity(count);
+        for index in 0..count {
+            let key = self.i32(base + 8 + index * 8)?;
+            if pairs.last().map_or(false, |last| last.0 >= key) {
+                return Err(DecodeError::new(self.start, "lookupswitch keys must be sorted in increasing order"));
+            }
+            pairs.push((key, self.target(self.i32(base + 12 + index * 8)? as i64)?));
+        }
+        Ok((Instruction::LookupSwitch { default, pairs }, (base + 8 + count * 8) as u32))
+    }
+
+    fn wide(&self) -> Result<(Instruction, u32), DecodeError> {
+        let opcode = self.u8(1)?;
+        let index = self.u16(2)?;
+        let instruction = match opcode {
+            JVM_OPCODE_ILOAD..=JVM_OPCODE_ALOAD => {
+                Instruction::Load(VALUE_KINDS[(opcode - JVM_OPCODE_ILOAD) as usize], index)
+            },
+            JVM_OPCODE_ISTORE..=JVM_OPCODE_ASTORE => {
+                Instruction::Store(VALUE_KINDS[(opcode - JVM_OPCODE_ISTORE) as usize], index)
+            },
+            JVM_OPCODE_RET => Instruction::Ret(index),
+            JVM_OPCODE_IINC => return Ok((Instruction::IInc(index, self.u16(4)? as i16), 6)),
+            _ => return Err(DecodeError::new(self.start, format!("Opcode {} cannot be modified by wide", opcode)))
+        };
+        Ok((instruction, 4))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::constants::*;
+    use super::{Condition, Instruction, InstructionDecoder, ValueKind};
+
+    #[test]
+    fn decodes_operands() {
+        let code = [
+            JVM_OPCODE_ILOAD_1,
+            JVM_OPCODE_BIPUSH, 0xFF,
+            JVM_OPCODE_WIDE, JVM_OPCODE_IINC, 0x01, 0x2C, 0xFF, 0x9C,
+            JVM_OPCODE_IF_ICMPLT, 0xFF, 0xF7,
+            JVM_OPCODE_WIDE, JVM_OPCODE_ASTORE, 0x01, 0x00,
+            JVM_OPCODE_RETURN
+        ];
+        let instructions = InstructionDecoder::decode_all(&code).unwrap();
+        let decoded = instructions.iter().map(|value| value.instruction().clone()).collect::<Vec<_>>();
+        assert_eq!(decoded, vec![
+            Instruction::Load(ValueKind::Int, 1),
+            Instruction::IConst(-1),
+            Instruction::IInc(300, -100),
+            Instruction::IfICmp(Condition::Lt, 0),
+            Instruction::Store(ValueKind::Reference, 256),
+            Instruction::Return(None)
+        ]);
+        assert!(instructions[2].is_wide());
+        assert_eq!(instructions[2].name(), "iinc_w");
+        assert_eq!(instructions[3].offset(), 9);
+        assert_eq!(instructions[5].offset(), 16);
+    }
+
+    #[test]
+    fn decodes_switches() {
+        // Padding depends on where the switch starts, so decode the same switch at two different offsets
+        for prefix in [0, 2] {
+            let mut code = vec![JVM_OPCODE_NOP; prefix];
+            code.push(JVM_OPCODE_TABLESWITCH);
+            code.resize(code.len() + 3 - (prefix % 4), 0);
+            for value in [20, 1, 2, 30, 40] {
+                code.extend_from_slice(&i32::to_be_bytes(value));
+            }
+            code.push(JVM_OPCODE_LOOKUPSWITCH);
+            let lookup_offset = code.len() - 1;
+            code.resize(code.len() + 3 - (lookup_offset % 4), 0);
+            for value in [10, 1, -5, 16] {
+                code.extend_from_slice(&i32::to_be_bytes(value));
+            }
+            code.push(JVM_OPCODE_RETURN);
+
+            let instructions = InstructionDecoder::decode_all(&code).unwrap();
+            let base = prefix as u32;
+            assert_eq!(instructions[prefix].instruction(), &Instruction::TableSwitch {
+                default: base + 20,
+                low: 1,
+                targets: vec![base + 30, base + 40]
+            });
+            assert_eq!(instructions[prefix + 1].offset() as usize, lookup_offset);
+            assert_eq!(instructions[prefix + 1].instruction(), &Instruction::LookupSwitch {
+                default: lookup_offset as u32 + 10,
+                pairs: vec![(-5, lookup_offset as u32 + 16)]
+            });
+            assert_eq!(instructions[prefix + 2].instruction(), &Instruction::Return(None));
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_encodings() {
+        let cases: [&[u8]; 6] = [
+            &[JVM_OPCODE_NOP, 0xCB],
+            &[JVM_OPCODE_SIPUSH, 0x01],
+            &[JVM_OPCODE_WIDE, JVM_OPCODE_IADD, 0x00, 0x00],
+            &[JVM_OPCODE_GOTO, 0xFF, 0xFF],
+            &[JVM_OPCODE_NEWARRAY, 3],
+            &[JVM_OPCODE_TABLESWITCH, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 1]
+        ];
+        for code in cases {
+            let result = InstructionDecoder::decode_all(code);
+            assert!(result.is_err(), "Expected {:?} to fail to decode!", code);
+        }
+        let error = InstructionDecoder::decode_all(&[JVM_OPCODE_NOP, 0xCB]).unwrap_err();
+        assert_eq!(error.offset(), 1);
+    }
+}
@@ -15,20 +15,92 @@
  */
 
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
-use internship::IStr;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use bytes::Bytes;
+use crate::code::{NativeLibraries, NativeRegistry};
+use crate::constants::JVM_SIGNATURE_ARRAY;
+use crate::utils::IStr;
+use crate::utils::constants::{JAVA_IO_SERIALIZABLE_NAME, JAVA_LANG_CLONEABLE_NAME, JAVA_LANG_OBJECT_NAME};
+use crate::utils::descriptors::class_name_of;
 use crate::types::Class;
+use crate::verifier::VerifyMode;
+use super::attributes::AttributeRegistry;
 
 // TODO: Maybe locking the entire map with a single lock for reading and writing
 //  isn't the greatest idea?
 #[derive(Debug)]
 pub struct ClassLoader {
-    classes: Mutex<HashMap<IStr, Arc<Class>>>
+    classes: Mutex<HashMap<IStr, Arc<Class>>>,
+    attributes: AttributeRegistry,
+    verify_mode: VerifyMode,
+    natives: NativeRegistry,
+    libraries: NativeLibraries,
+    class_path: RwLock<Vec<PathBuf>>,
+    properties: RwLock<HashMap<String, String>>
 }
 
 impl ClassLoader {
     pub fn new() -> Self {
-        ClassLoader { classes: Mutex::new(HashMap::new()) }
+        ClassLoader::with_attributes(AttributeRegistry::default())
+    }
+
+    pub fn with_attributes(attributes: AttributeRegistry) -> Self {
+        ClassLoader::with_options(attributes, VerifyMode::default())
+    }
+
+    pub fn with_options(attributes: AttributeRegistry, verify_mode: VerifyMode) -> Self {
+        ClassLoader {
+            classes: Mutex::new(HashMap::new()),
+            attributes,
+            verify_mode,
+            natives: NativeRegistry::default(),
+            libraries: NativeLibraries::default(),
+            class_path: RwLock::new(Vec::new()),
+            properties: RwLock::new(HashMap::new())
+        }
+    }
+
+    pub fn attributes(&self) -> &AttributeRegistry {
+        &self.attributes
+    }
+
+    pub fn verify_mode(&self) -> VerifyMode {
+        self.verify_mode
+    }
+
+    // The natives that the native methods of classes loaded by this loader are dispatched to.
+    pub fn natives(&self) -> &NativeRegistry {
+        &self.natives
+    }
+
+    // The libraries that natives are looked up in when there's no Rust native registered for them.
+    pub fn libraries(&self) -> &NativeLibraries {
+        &self.libraries
+    }
+
+    // Adds a directory to look for class files in, with the packages of classes as subdirectories,
+    // like an entry of the class path.
+    pub fn add_class_path(&self, directory: impl Into<PathBuf>) {
+        self.class_path.write().unwrap().push(directory.into());
+    }
+
+    pub fn class_path(&self) -> Vec<PathBuf> {
+        self.class_path.read().unwrap().clone()
+    }
+
+    // The system properties, which the loader holds for the VM as a whole, like the natives.
+    pub fn set_property(&self, key: &str, value: &str) {
+        self.properties.write().unwrap().insert(String::from(key), String::from(value));
+    }
+
+    pub fn property(&self, key: &str) -> Option<String> {
+        self.properties.read().unwrap().get(key).cloned()
+    }
+
+    pub fn properties(&self) -> HashMap<String, String> {
+        self.properties.read().unwrap().clone()
     }
 
     pub fn get_class(&self, name: &str) -> Option<Arc<Class>> {
@@ -36,9 +108,69 @@ impl ClassLoader {
     }
 
     pub fn load_class(self: Arc<ClassLoader>, name: &str) -> Arc<Class> {
-        Arc::clone(self.classes.lock()
-            .unwrap()
-            .entry(IStr::new(name))
-            .or_insert_with(|| Arc::new(Class::parse(Arc::clone(&self), name))))
+        self.find_class(name).unwrap_or_else(|| panic!("Class file for {} could not be found!", name))
+    }
+
+    // Links a class from bytes that didn't come from a file, and makes it visible to load_class under
+    // its own name. The class is visible before it's verified, so that the verifier finds it rather
+    // than loading it again when the class refers to itself.
+    pub fn define_class(self: &Arc<ClassLoader>, bytes: Bytes) -> Arc<Class> {
+        let class = Arc::new(Class::from_bytes(Arc::clone(self), bytes));
+        self.classes.lock().unwrap().insert(IStr::new(class.name()), Arc::clone(&class));
+        class.initialize()
+    }
+
+    // Like load_class, but gives up rather than panicking when there's no class file for the name.
+    pub fn find_class(self: &Arc<ClassLoader>, name: &str) -> Option<Arc<Class>> {
+        if let Some(class) = self.get_class(name) {
+            return Some(class);
+        }
+        let path = self.class_file(name)?;
+        let bytes = fs::read(&path)
+            .unwrap_or_else(|error| panic!("Class file {} could not be read! {}", path.display(), error));
+        Some(self.define_class(Bytes::from(bytes)))
+    }
+
+    // Whether a value of the class named from can be used where the class named to is expected, by the
+    // rules that checkcast, instanceof and aastore follow (JVMS §6.5). Arrays are named by their
+    // descriptors, like [Ljava/lang/String;
+    pub fn is_assignable(self: &Arc<ClassLoader>, from: &str, to: &str) -> bool {
+        if from == to || to == JAVA_LANG_OBJECT_NAME {
+            return true;
+        }
+        match (from.strip_prefix(JVM_SIGNATURE_ARRAY), to.strip_prefix(JVM_SIGNATURE_ARRAY)) {
+            // Arrays of primitives are only assignable to arrays of the same primitive, which have the same name
+            (Some(from), Some(to)) => match (class_name_of(from), class_name_of(to)) {
+                (Some(from), Some(to)) => self.is_assignable(from, to),
+                _ => false
+            },
+            (Some(_), None) => to == JAVA_LANG_CLONEABLE_NAME || to == JAVA_IO_SERIALIZABLE_NAME,
+            (None, Some(_)) => false,
+            (None, None) => self.extends(from, to)
+        }
+    }
+
+    // Whether the class is the other class or inherits from it, through its super classes or the interfaces
+    // that it or they implement. Classes that can't be found don't inherit from anything.
+    fn extends(self: &Arc<ClassLoader>, name: &str, other: &str) -> bool {
+        if name == other {
+            return true;
+        }
+        let class = match self.find_class(name) {
+            Some(class) => class,
+            None => return false
+        };
+        class.interface_names().iter().any(|interface| self.extends(interface, other)) ||
+            class.super_class_name().map_or(false, |super_name| self.extends(&super_name, other))
+    }
+
+    // Classes are looked for in each directory of the class path in turn, and then at the name itself
+    // taken as a path, which is how classes are loaded from files given on the command line.
+    fn class_file(&self, name: &str) -> Option<PathBuf> {
+        let file_name = format!("{}.class", name);
+        self.class_path.read().unwrap().iter()
+            .map(|directory| directory.join(&file_name))
+            .chain(std::iter::once(PathBuf::from(name)))
+            .find(|path| path.is_file())
     }
 }
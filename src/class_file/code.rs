@@ -14,29 +14,33 @@
  * Street, Fifth Floor, Boston, MA 02110-1301, USA.
  */
 
-use bytes::{Buf, Bytes};
-use internship::IStr;
-use std::collections::HashMap;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use std::sync::Arc;
-use astatine_macros::{FieldDescribable, Nameable};
+use astatine_macros::{Attributed, FieldDescribable, Generic, Nameable};
+use crate::utils::IStr;
 use crate::code::StackFrame;
 use crate::constants::*;
 use crate::types::{Class, ConstantPool};
-use crate::utils::{BufferExtras, IdentEq};
+use crate::utils::BufferExtras;
 use crate::utils::descriptors::FieldDescriptor;
 use super::ClassLoader;
+use super::attributes::{Attribute, AttributeRegistry};
+use super::bytecode::InstructionDecoder;
+use super::utils::{write_attribute, write_attributes};
 use super::verification::*;
 
-#[derive(Debug)]
+#[derive(Debug, Attributed)]
 pub struct CodeBlock {
     max_stack: u16,
     max_locals: u16,
     code: Vec<u8>,
     exception_handlers: ExceptionHandlerTable,
-    line_numbers: Option<HashMap<u16, u16>>,
-    local_variables: Option<LocalVariableTable>,
-    local_variable_types: Option<LocalVariableTable>,
-    stack_map_table: Option<StackMapTable>
+    line_numbers: Vec<LineNumberTable>,
+    local_variables: Vec<LocalVariableTable>,
+    local_variable_types: Vec<LocalVariableTypeTable>,
+    stack_map_table: Option<StackMapTable>,
+    attribute_order: Vec<u16>,
+    attributes: Vec<Attribute>
 }
 
 const MAX_CODE_BYTES: usize = 65535;
@@ -49,8 +53,8 @@ impl CodeBlock {
         assert!(code_length > 0 && code_length <= MAX_CODE_BYTES, "Invalid code attribute! Code \
             length must be > 0 and < {}!", MAX_CODE_BYTES);
         let code = buf.get_u8_array(code_length);
-        let exception_handlers = ExceptionHandlerTable::parse(loader, pool, buf);
-        let attributes = parse_attributes(pool, buf);
+        let exception_handlers = ExceptionHandlerTable::parse(pool, buf);
+        let attributes = parse_attributes(pool, buf, loader.attributes());
         CodeBlock {
             max_stack,
             max_locals,
@@ -59,10 +63,45 @@ impl CodeBlock {
             line_numbers: attributes.0,
             local_variables: attributes.1,
             local_variable_types: attributes.2,
-            stack_map_table: attributes.3
+            stack_map_table: attributes.3,
+            attribute_order: attributes.4,
+            attributes: attributes.5
         }
     }
 
+    pub(crate) fn write(&self, pool: &ConstantPool, buf: &mut BytesMut) {
+        buf.put_u16(self.max_stack);
+        buf.put_u16(self.max_locals);
+        buf.put_u32(self.code.len() as u32);
+        buf.put_slice(self.code.as_slice());
+        self.exception_handlers.write(buf);
+        // A code attribute can have any number of each table, which go back in the order they came in.
+        let mut line_numbers = self.line_numbers.iter();
+        let mut local_variables = self.local_variables.iter();
+        let mut local_variable_types = self.local_variable_types.iter();
+        write_attributes(buf, pool, &self.attribute_order, &self.attributes, |buf, name_index, name| {
+            match name {
+                JVM_ATTRIBUTE_LINE_NUMBER_TABLE => match line_numbers.next() {
+                    Some(table) => write_attribute(buf, name_index, |buf| table.write(buf)),
+                    None => return false
+                },
+                JVM_ATTRIBUTE_LOCAL_VARIABLE_TABLE => match local_variables.next() {
+                    Some(table) => write_attribute(buf, name_index, |buf| table.write(buf)),
+                    None => return false
+                },
+                JVM_ATTRIBUTE_LOCAL_VARIABLE_TYPE_TABLE => match local_variable_types.next() {
+                    Some(table) => write_attribute(buf, name_index, |buf| table.write(buf)),
+                    None => return false
+                },
+                JVM_ATTRIBUTE_STACK_MAP_TABLE if self.stack_map_table.is_some() => {
+                    write_attribute(buf, name_index, |buf| self.stack_map_table.as_ref().unwrap().write(buf));
+                },
+                _ => return false
+            }
+            true
+        });
+    }
+
     pub fn max_stack(&self) -> u16 {
         self.max_stack
     }
@@ -79,12 +118,21 @@ impl CodeBlock {
         &self.exception_handlers
     }
 
-    pub fn local_variables(&self) -> Option<&LocalVariableTable> {
-        self.local_variables.as_ref()
+    pub fn line_numbers(&self) -> &[LineNumberTable] {
+        self.line_numbers.as_slice()
+    }
+
+    // The source line of the instruction at the pc, out of all of the code's LineNumberTables.
+    pub fn line_number(&self, pc: u16) -> Option<u16> {
+        closest_line(self.line_numbers.iter().flat_map(LineNumberTable::iter), pc)
+    }
+
+    pub fn local_variables(&self) -> &[LocalVariableTable] {
+        self.local_variables.as_slice()
     }
 
-    pub fn local_variable_types(&self) -> Option<&LocalVariableTable> {
-        self.local_variable_types.as_ref()
+    pub fn local_variable_types(&self) -> &[LocalVariableTypeTable] {
+        self.local_variable_types.as_slice()
     }
 
     pub fn stack_map_table(&self) -> Option<&StackMapTable> {
@@ -98,6 +146,10 @@ impl CodeBlock {
     pub fn new_code_reader(&self) -> Bytes {
         Bytes::copy_from_slice(self.code.as_slice())
     }
+
+    pub fn instructions(&self) -> InstructionDecoder<'_> {
+        InstructionDecoder::new(self.code.as_slice())
+    }
 }
 
 #[derive(Debug)]
@@ -106,39 +158,93 @@ pub struct ExceptionHandlerTable {
 }
 
 impl ExceptionHandlerTable {
-    pub(crate) fn parse(loader: Arc<ClassLoader>, pool: &ConstantPool, buf: &mut Bytes) -> Self {
+    pub(crate) fn parse(pool: &ConstantPool, buf: &mut Bytes) -> Self {
         let handler_count = buf.get_u16();
         let mut handlers = Vec::with_capacity(handler_count as usize);
         for _ in 0..handler_count {
-            handlers.push(ExceptionHandlerBlock::parse(Arc::clone(&loader), pool, buf))
+            handlers.push(ExceptionHandlerBlock::parse(pool, buf))
         }
         ExceptionHandlerTable { handlers }
     }
 
+    pub(crate) fn write(&self, buf: &mut BytesMut) {
+        buf.put_u16(self.handlers.len() as u16);
+        self.handlers.iter().for_each(|handler| handler.write(buf));
+    }
+
     pub fn get(&self, index: usize) -> Option<&ExceptionHandlerBlock> {
         self.handlers.get(index)
     }
 
+    pub fn len(&self) -> usize {
+        self.handlers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handlers.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ExceptionHandlerBlock> {
+        self.handlers.iter()
+    }
+
     pub fn get_handler(&self, exception: &Class) -> Option<&ExceptionHandlerBlock> {
-        self.handlers.iter().find(|element| element.catch_type().ident_eq(exception))
+        self.handlers.iter().find(|element| element.catches(exception))
     }
 }
 
+// Catch types are stored as their index, which is what the writer puts back, and by name, which is only
+// compared against the class of thrown exceptions. Loading every exception class a method might catch
+// when it's parsed would mean that writing a method out needed those classes too, just like super classes.
 #[derive(Debug)]
 pub struct ExceptionHandlerBlock {
     start_pc: u16,
     end_pc: u16,
     handler_pc: u16,
-    catch_type: Arc<Class>
+    catch_type_index: u16,
+    catch_type: Option<IStr>
 }
 
 impl ExceptionHandlerBlock {
-    pub(crate) fn parse(loader: Arc<ClassLoader>, pool: &ConstantPool, buf: &mut Bytes) -> Self {
+    pub(crate) fn parse(pool: &ConstantPool, buf: &mut Bytes) -> Self {
         let start_pc = buf.get_u16();
         let end_pc = buf.get_u16();
         let handler_pc = buf.get_u16();
-        let catch_type = pool.get_class_no_holder(buf.get_u16() as usize, loader).unwrap();
-        ExceptionHandlerBlock { start_pc, end_pc, handler_pc, catch_type }
+        let catch_type_index = buf.get_u16();
+        let catch_type = if catch_type_index == 0 {
+            None
+        } else {
+            let name = pool.get_class_name(catch_type_index as usize)
+                .unwrap_or_else(|| panic!("Invalid exception handler! Expected catch type index {} \
+                    to be a class in constant pool!", catch_type_index));
+            Some(name)
+        };
+        ExceptionHandlerBlock { start_pc, end_pc, handler_pc, catch_type_index, catch_type }
+    }
+
+    pub(crate) fn write(&self, buf: &mut BytesMut) {
+        buf.put_u16(self.start_pc);
+        buf.put_u16(self.end_pc);
+        buf.put_u16(self.handler_pc);
+        buf.put_u16(self.catch_type_index);
+    }
+
+    pub fn catches(&self, exception: &Class) -> bool {
+        let catch_type = match &self.catch_type {
+            Some(value) => value,
+            None => return true
+        };
+        if exception.name() == catch_type.as_str() {
+            return true;
+        }
+        let mut super_class = exception.super_class();
+        while let Some(class) = super_class {
+            if class.name() == catch_type.as_str() {
+                return true;
+            }
+            super_class = class.super_class();
+        }
+        false
     }
 
     pub fn start_pc(&self) -> u16 {
@@ -153,8 +259,78 @@ impl ExceptionHandlerBlock {
         self.handler_pc
     }
 
-    pub fn catch_type(&self) -> &Class {
-        &self.catch_type
+    pub fn catch_type_index(&self) -> u16 {
+        self.catch_type_index
+    }
+
+    pub fn catch_type(&self) -> Option<&str> {
+        self.catch_type.as_ref().map(IStr::as_str)
+    }
+}
+
+#[derive(Debug)]
+pub struct LineNumberTable {
+    entries: Vec<LineNumber>
+}
+
+impl LineNumberTable {
+    pub(crate) fn parse(buf: &mut Bytes) -> Self {
+        let entries = buf.get_generic_u16_array(|buf| {
+            LineNumber { start_pc: buf.get_u16(), line_number: buf.get_u16() }
+        });
+        LineNumberTable { entries }
+    }
+
+    pub(crate) fn write(&self, buf: &mut BytesMut) {
+        buf.put_u16(self.entries.len() as u16);
+        for entry in &self.entries {
+            buf.put_u16(entry.start_pc);
+            buf.put_u16(entry.line_number);
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<&LineNumber> {
+        self.entries.get(index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &LineNumber> {
+        self.entries.iter()
+    }
+
+    // The line that the instruction at the pc is on, which is that of the entry with the closest start
+    // at or before it. Entries can be in any order, and a line can have more than one of them.
+    pub fn line_number_at(&self, pc: u16) -> Option<u16> {
+        closest_line(self.entries.iter(), pc)
+    }
+}
+
+fn closest_line<'a>(entries: impl Iterator<Item = &'a LineNumber>, pc: u16) -> Option<u16> {
+    entries.filter(|entry| entry.start_pc <= pc)
+        .max_by_key(|entry| entry.start_pc)
+        .map(|entry| entry.line_number)
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct LineNumber {
+    start_pc: u16,
+    line_number: u16
+}
+
+impl LineNumber {
+    pub fn start_pc(&self) -> u16 {
+        self.start_pc
+    }
+
+    pub fn line_number(&self) -> u16 {
+        self.line_number
     }
 }
 
@@ -169,15 +345,34 @@ impl LocalVariableTable {
         LocalVariableTable { variables }
     }
 
+    pub(crate) fn write(&self, buf: &mut BytesMut) {
+        buf.put_u16(self.variables.len() as u16);
+        self.variables.iter().for_each(|variable| variable.write(buf));
+    }
+
     pub fn get(&self, index: usize) -> Option<&LocalVariable> {
         self.variables.get(index)
     }
+
+    pub fn len(&self) -> usize {
+        self.variables.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.variables.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &LocalVariable> {
+        self.variables.iter()
+    }
 }
 
 #[derive(Debug, Nameable, FieldDescribable)]
 pub struct LocalVariable {
     name: IStr,
+    name_index: u16,
     descriptor: FieldDescriptor,
+    descriptor_index: u16,
     start_pc: u16,
     length: u16,
     index: u16
@@ -187,14 +382,29 @@ impl LocalVariable {
     pub(crate) fn parse(pool: &ConstantPool, buf: &mut Bytes) -> Self {
         let start_pc = buf.get_u16();
         let length = buf.get_u16();
-        let name = pool.get_utf8(buf.get_u16() as usize)
-            .expect(&format!("Invalid local variable in table for method! Expected name index to be \
-                in constant pool!"));
-        let descriptor = pool.get_utf8(buf.get_u16() as usize)
+        let name_index = buf.get_u16();
+        let name = pool.get_utf8(name_index as usize)
+            .expect("Invalid local variable in table for method! Expected name index to be in \
+                constant pool!");
+        let descriptor_index = buf.get_u16();
+        let descriptor = pool.get_utf8(descriptor_index as usize)
             .and_then(|value| FieldDescriptor::parse(value.as_str()))
-            .unwrap();
+            .expect("Invalid local variable in table for method! Expected descriptor index to be \
+                in constant pool!");
         let index = buf.get_u16();
-        LocalVariable { name, descriptor, start_pc, length, index }
+        LocalVariable { name, name_index, descriptor, descriptor_index, start_pc, length, index }
+    }
+
+    pub(crate) fn write(&self, buf: &mut BytesMut) {
+        buf.put_u16(self.start_pc);
+        buf.put_u16(self.length);
+        buf.put_u16(self.name_index);
+        buf.put_u16(self.descriptor_index);
+        buf.put_u16(self.index);
+    }
+
+    pub fn descriptor_index(&self) -> u16 {
+        self.descriptor_index
     }
 
     pub fn start_pc(&self) -> u16 {
@@ -210,41 +420,121 @@ impl LocalVariable {
     }
 }
 
-type CodeAttributes = (Option<HashMap<u16, u16>>, Option<LocalVariableTable>,
-                       Option<LocalVariableTable>, Option<StackMapTable>);
+// Local variable type tables have the same layout as local variable tables, but store generic
+// signatures rather than descriptors, which can't be parsed as field descriptors.
+#[derive(Debug)]
+pub struct LocalVariableTypeTable {
+    variables: Vec<LocalVariableType>
+}
+
+impl LocalVariableTypeTable {
+    pub(crate) fn parse(pool: &ConstantPool, buf: &mut Bytes) -> Self {
+        let variables = buf.get_generic_u16_array(|buf| LocalVariableType::parse(pool, buf));
+        LocalVariableTypeTable { variables }
+    }
+
+    pub(crate) fn write(&self, buf: &mut BytesMut) {
+        buf.put_u16(self.variables.len() as u16);
+        self.variables.iter().for_each(|variable| variable.write(buf));
+    }
+
+    pub fn get(&self, index: usize) -> Option<&LocalVariableType> {
+        self.variables.get(index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.variables.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.variables.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &LocalVariableType> {
+        self.variables.iter()
+    }
+}
 
-fn parse_attributes(pool: &ConstantPool, buf: &mut Bytes) -> CodeAttributes {
-    let mut line_number_table = None;
-    let mut local_variable_table = None;
-    let mut local_variable_type_table = None;
+#[derive(Debug, Nameable, Generic)]
+pub struct LocalVariableType {
+    name: IStr,
+    name_index: u16,
+    generic_signature: Option<IStr>,
+    signature_index: u16,
+    start_pc: u16,
+    length: u16,
+    index: u16
+}
+
+impl LocalVariableType {
+    pub(crate) fn parse(pool: &ConstantPool, buf: &mut Bytes) -> Self {
+        let start_pc = buf.get_u16();
+        let length = buf.get_u16();
+        let name_index = buf.get_u16();
+        let name = pool.get_utf8(name_index as usize)
+            .expect("Invalid local variable type in table for method! Expected name index to be in \
+                constant pool!");
+        let signature_index = buf.get_u16();
+        let generic_signature = pool.get_utf8(signature_index as usize);
+        assert!(generic_signature.is_some(), "Invalid local variable type in table for method! \
+            Expected signature index {} to be in constant pool!", signature_index);
+        let index = buf.get_u16();
+        LocalVariableType { name, name_index, generic_signature, signature_index, start_pc, length, index }
+    }
+
+    pub(crate) fn write(&self, buf: &mut BytesMut) {
+        buf.put_u16(self.start_pc);
+        buf.put_u16(self.length);
+        buf.put_u16(self.name_index);
+        buf.put_u16(self.signature_index);
+        buf.put_u16(self.index);
+    }
+
+    pub fn start_pc(&self) -> u16 {
+        self.start_pc
+    }
+
+    pub fn length(&self) -> u16 {
+        self.length
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+}
+
+type CodeAttributes = (Vec<LineNumberTable>, Vec<LocalVariableTable>, Vec<LocalVariableTypeTable>,
+                       Option<StackMapTable>, Vec<u16>, Vec<Attribute>);
+
+fn parse_attributes(pool: &ConstantPool, buf: &mut Bytes, registry: &AttributeRegistry) -> CodeAttributes {
+    let mut line_number_tables = Vec::new();
+    let mut local_variable_tables = Vec::new();
+    let mut local_variable_type_tables = Vec::new();
     let mut stack_map_table = None;
+    let mut attribute_order = Vec::new();
+    let mut attributes = Vec::new();
 
     let mut attribute_count = buf.get_u16();
     while attribute_count > 0 {
-        assert!(buf.len() > 6, "Truncated code attributes!");
-        let attribute_name = pool.get_utf8(buf.get_u16() as usize).unwrap();
+        assert!(buf.len() >= 6, "Truncated code attributes!");
+        let name_index = buf.get_u16();
+        let attribute_name = pool.get_utf8(name_index as usize).unwrap();
         let attribute_length = buf.get_u32();
+        attribute_order.push(name_index);
 
         if attribute_name == JVM_ATTRIBUTE_LINE_NUMBER_TABLE {
-            let table_length = buf.get_u16();
-            let mut table = HashMap::with_capacity(table_length as usize);
-            for _ in 0..table_length {
-                let start_pc = buf.get_u16();
-                let line_number = buf.get_u16();
-                table.insert(start_pc, line_number);
-            }
-            line_number_table = Some(table)
+            line_number_tables.push(LineNumberTable::parse(buf));
         } else if attribute_name == JVM_ATTRIBUTE_LOCAL_VARIABLE_TABLE {
-            local_variable_table = Some(LocalVariableTable::parse(pool, buf));
+            local_variable_tables.push(LocalVariableTable::parse(pool, buf));
         } else if attribute_name == JVM_ATTRIBUTE_LOCAL_VARIABLE_TYPE_TABLE {
-            local_variable_type_table = Some(LocalVariableTable::parse(pool, buf));
+            local_variable_type_tables.push(LocalVariableTypeTable::parse(pool, buf));
         } else if attribute_name == JVM_ATTRIBUTE_STACK_MAP_TABLE {
             stack_map_table = Some(StackMapTable::parse(buf));
-        } else {
-            // Skip past any attribute that we don't recognise
-            buf.advance(attribute_length as usize);
+        } else if let Some(attribute) = registry.read(pool, attribute_name, buf, attribute_length) {
+            attributes.push(attribute);
         }
         attribute_count -= 1;
     }
-    (line_number_table, local_variable_table, local_variable_type_table, stack_map_table)
+    (line_number_tables, local_variable_tables, local_variable_type_tables, stack_map_table, attribute_order,
+     attributes)
 }
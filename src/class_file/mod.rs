@@ -15,9 +15,11 @@
  */
 
 mod utils;
+pub mod attributes;
+pub mod bytecode;
 pub mod verification;
 pub mod code;
 mod class_loader;
 
-pub(crate) use utils::parse_generic_signature;
+pub(crate) use utils::{parse_generic_signature, write_attribute, write_attributes};
 pub use class_loader::ClassLoader;
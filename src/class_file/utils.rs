@@ -14,12 +14,18 @@
  * Street, Fifth Floor, Boston, MA 02110-1301, USA.
  */
 
-use bytes::{Buf, Bytes};
-use internship::IStr;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use crate::utils::IStr;
 use crate::types::ConstantPool;
 use crate::types::constant_pool::UTF8_TAG;
+use super::attributes::Attribute;
 
-pub(crate) fn parse_generic_signature(pool: &ConstantPool, buf: &mut Bytes, length: u32, type_name: &str) -> Option<IStr> {
+pub(crate) fn parse_generic_signature(
+    pool: &ConstantPool,
+    buf: &mut Bytes,
+    length: u32,
+    type_name: &str
+) -> (u16, IStr) {
     assert!(length == 2 || buf.len() < 2, "Invalid generic signature attribute for {}! Expected \
         length of 2, was {}!", type_name, length);
     let index = buf.get_u16();
@@ -30,7 +36,49 @@ pub(crate) fn parse_generic_signature(pool: &ConstantPool, buf: &mut Bytes, leng
             constant pool!", type_name, index));
     assert_eq!(tag, UTF8_TAG, "Invalid generic signature attribute for {}! Expected UTF-8 string \
         at {}, was {}!", type_name, index, tag);
-    let value = pool.get_utf8(index as usize);
-    assert!(value.is_some(), "Invalid {}! Expected generic signature to be at index {}!", type_name, index);
-    value
+    let value = pool.get_utf8(index as usize)
+        .unwrap_or_else(|| panic!("Invalid {}! Expected generic signature to be at index {}!", type_name, index));
+    (index, value)
+}
+
+pub(crate) fn write_attribute(buf: &mut BytesMut, name_index: u16, writer: impl FnOnce(&mut BytesMut)) {
+    buf.put_u16(name_index);
+    let length_offset = buf.len();
+    buf.put_u32(0);
+    writer(buf);
+    let length = (buf.len() - length_offset - 4) as u32;
+    buf[length_offset..length_offset + 4].copy_from_slice(&length.to_be_bytes());
+}
+
+// Writes out attributes in the order they were originally read in. The known writer is given the
+// name of each attribute, and should write it out and return true if it knows how to. Everything it
+// doesn't write is matched up against the attributes that were retained, and written out as is.
+// Attributes that were skipped when parsing are lost.
+pub(crate) fn write_attributes(
+    buf: &mut BytesMut,
+    pool: &ConstantPool,
+    order: &[u16],
+    retained: &[Attribute],
+    mut known_writer: impl FnMut(&mut BytesMut, u16, &str) -> bool
+) {
+    let count_offset = buf.len();
+    buf.put_u16(0);
+    let mut count = 0u16;
+    let mut retained = retained.iter().peekable();
+    for name_index in order {
+        let name = pool.get_utf8(*name_index as usize)
+            .expect("Invalid attribute! Expected name to be in constant pool!");
+        let written = known_writer(buf, *name_index, name.as_str()) ||
+            match retained.next_if(|attribute| attribute.name() == name.as_str()) {
+                Some(attribute) => {
+                    write_attribute(buf, *name_index, |buf| buf.put_slice(attribute.info()));
+                    true
+                },
+                None => false
+            };
+        if written {
+            count += 1;
+        }
+    }
+    buf[count_offset..count_offset + 2].copy_from_slice(&count.to_be_bytes());
 }
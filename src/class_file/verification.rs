@@ -14,7 +14,7 @@
  * Street, Fifth Floor, Boston, MA 02110-1301, USA.
  */
 
-use bytes::{Buf, Bytes};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use crate::constants::*;
 use crate::utils::BufferExtras;
 
@@ -24,10 +24,38 @@ pub struct StackMapTable {
 }
 
 impl StackMapTable {
+    pub fn new(frames: Vec<StackMapFrame>) -> Self {
+        StackMapTable { frames }
+    }
+
     pub(crate) fn parse(buf: &mut Bytes) -> Self {
         StackMapTable { frames: buf.get_generic_u16_array(StackMapFrame::parse) }
     }
 
+    pub(crate) fn write(&self, buf: &mut BytesMut) {
+        buf.put_u16(self.frames.len() as u16);
+        self.frames.iter().for_each(|frame| frame.write(buf));
+    }
+
+    // The contents of the StackMapTable attribute, without the attribute name and length.
+    pub fn to_bytes(&self) -> Bytes {
+        let mut buf = BytesMut::new();
+        self.write(&mut buf);
+        buf.freeze()
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &StackMapFrame> {
+        self.frames.iter()
+    }
+
     pub fn get(&self, index: usize) -> Option<&StackMapFrame> {
         self.frames.get(index)
     }
@@ -42,6 +70,10 @@ pub struct StackMapFrame {
 }
 
 impl StackMapFrame {
+    pub fn new(frame_type: u8, offset_delta: u16, locals: Vec<VerificationType>, stack: Vec<VerificationType>) -> Self {
+        StackMapFrame { frame_type, offset_delta, stack, locals }
+    }
+
     pub(crate) fn parse(buf: &mut Bytes) -> Self {
         let frame_type = buf.get_u8();
         let offset_delta;
@@ -74,6 +106,30 @@ impl StackMapFrame {
         StackMapFrame { frame_type, offset_delta, stack, locals }
     }
 
+    pub(crate) fn write(&self, buf: &mut BytesMut) {
+        buf.put_u8(self.frame_type);
+        match self.frame_type {
+            0..=63 => {},
+            64..=127 => self.stack[0].write(buf),
+            247 => {
+                buf.put_u16(self.offset_delta);
+                self.stack[0].write(buf);
+            },
+            248..=254 => {
+                buf.put_u16(self.offset_delta);
+                self.locals.iter().for_each(|local| local.write(buf));
+            },
+            255 => {
+                buf.put_u16(self.offset_delta);
+                buf.put_u16(self.locals.len() as u16);
+                self.locals.iter().for_each(|local| local.write(buf));
+                buf.put_u16(self.stack.len() as u16);
+                self.stack.iter().for_each(|item| item.write(buf));
+            },
+            _ => panic!("Invalid stack map frame type {}!", self.frame_type)
+        }
+    }
+
     #[inline]
     fn parse_types(count: usize, result: &mut Vec<VerificationType>, buf: &mut Bytes) {
         for _ in 0..count {
@@ -96,6 +152,14 @@ impl StackMapFrame {
     pub fn get_locals(&self, index: usize) -> Option<&VerificationType> {
         self.locals.get(index)
     }
+
+    pub fn stack(&self) -> &[VerificationType] {
+        self.stack.as_slice()
+    }
+
+    pub fn locals(&self) -> &[VerificationType] {
+        self.locals.as_slice()
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -105,12 +169,25 @@ pub struct VerificationType {
 }
 
 impl VerificationType {
+    // The offset is the class index for objects and the offset of the new instruction for uninitialized
+    // types, and is ignored for everything else.
+    pub fn new(item: u8, offset: u16) -> Self {
+        VerificationType { item, offset }
+    }
+
     fn parse(buf: &mut Bytes) -> Self {
         let item = buf.get_u8();
         let offset = if item == JVM_ITEM_OBJECT || item == JVM_ITEM_UNINITIALIZED { buf.get_u16() } else { 0 };
         VerificationType { item, offset }
     }
 
+    fn write(&self, buf: &mut BytesMut) {
+        buf.put_u8(self.item);
+        if self.item == JVM_ITEM_OBJECT || self.item == JVM_ITEM_UNINITIALIZED {
+            buf.put_u16(self.offset);
+        }
+    }
+
     pub fn item(&self) -> u8 {
         self.item
     }
@@ -17,257 +17,436 @@
 mod instructions;
 mod primitive_ops;
 
-use paste::paste;
+use std::sync::Arc;
 use instructions::*;
 use primitive_ops::*;
-use crate::class_file::code::CodeBlock;
-use crate::constants::*;
+use crate::class_file::bytecode::{ArrayKind, Instruction, InstructionDecoder, ValueKind};
 use crate::objects::*;
-use crate::types::Class;
+use crate::runtime::Threads;
+use crate::types::{Class, Method};
+use super::{natives, Intrinsic};
 
 pub struct Interpreter {
     _singleton: ()
 }
 
 impl Interpreter {
-    pub fn execute(
-        heap: &HeapSpace,
-        class: &Class,
-        code: &CodeBlock,
+    pub fn execute<'h>(
+        heap: &'h HeapSpace,
+        class: &Arc<Class>,
+        method: &Arc<Method>,
         parameters: &[u32]
-    ) -> MethodResult {
-        let mut frame = code.new_stack_frame();
-        for parameter in parameters {
-            frame.push_op(*parameter);
+    ) -> MethodResult<'h> {
+        if let Some(intrinsic) = Interpreter::intrinsic(class, method) {
+            return intrinsic.invoke(heap, parameters);
+        }
+        if method.is_native() {
+            return natives::invoke(heap, class, method, parameters);
         }
+        let code = method.code()
+            .unwrap_or_else(|| panic!("Cannot execute method {} with no code!", method.name()));
+        let mut frame = code.new_stack_frame();
+        frame.set_parameters(method, parameters);
+        // Threads record the methods they're in, so that thread dumps can show where they are.
+        let thread = Threads::current();
+        let _entered = thread.as_ref().map(|thread| thread.enter_frame(class, method));
+        let thread = thread.as_deref();
 
-        let mut parser = CodeParser::new(code.code());
-        while !parser.is_empty() {
-            let op = parser.next();
-            match op {
-                JVM_OPCODE_NOP => {}
-                JVM_OPCODE_ACONST_NULL => frame.push_null_op(),
-                JVM_OPCODE_ICONST_M1..=JVM_OPCODE_ICONST_5 => {
-                    frame.push_int_op((op as i32) - (JVM_OPCODE_ICONST_0 as i32))
+        // Safepoints are polled for before returning and when branching backwards, which is enough for
+        // every thread to reach one soon after it's asked to, as loops can't go without branching back.
+        let mut pc = 0;
+        while (pc as usize) < code.code().len() {
+            let decoded = InstructionDecoder::decode(code.code(), pc).unwrap_or_else(|error| panic!("{}", error));
+            if matches!(decoded.instruction(), Instruction::Return(_)) {
+                safepoint(heap, thread, &mut frame, pc);
+            }
+            let mut next = decoded.next_offset();
+            match decoded.instruction() {
+                Instruction::Nop => {}
+                Instruction::AConstNull => frame.push_null_op(),
+                Instruction::IConst(value) => frame.push_int_op(*value),
+                Instruction::LConst(value) => frame.push_long_op(*value),
+                Instruction::FConst(value) => frame.push_float_op(*value),
+                Instruction::DConst(value) => frame.push_double_op(*value),
+                // TODO: LDC, LDC_W, and LDC2_W
+                Instruction::Load(ValueKind::Int, index) => jvm_load_int(&mut frame, *index),
+                Instruction::Load(ValueKind::Long, index) => jvm_load_long(&mut frame, *index),
+                Instruction::Load(ValueKind::Float, index) => jvm_load_float(&mut frame, *index),
+                Instruction::Load(ValueKind::Double, index) => jvm_load_double(&mut frame, *index),
+                Instruction::Load(ValueKind::Reference, index) => load_ref(&mut frame, *index),
+                Instruction::ArrayLoad(ArrayKind::Int) => load_array_int(heap, &mut frame),
+                Instruction::ArrayLoad(ArrayKind::Long) => load_array_long(heap, &mut frame),
+                Instruction::ArrayLoad(ArrayKind::Float) => load_array_float(heap, &mut frame),
+                Instruction::ArrayLoad(ArrayKind::Double) => load_array_double(heap, &mut frame),
+                Instruction::ArrayLoad(ArrayKind::Reference) => load_array_ref(heap, &mut frame),
+                Instruction::ArrayLoad(ArrayKind::Byte) => load_array_byte(heap, &mut frame),
+                Instruction::ArrayLoad(ArrayKind::Char) => load_array_char(heap, &mut frame),
+                Instruction::ArrayLoad(ArrayKind::Short) => load_array_short(heap, &mut frame),
+                Instruction::Store(ValueKind::Int, index) => jvm_store_int(&mut frame, *index),
+                Instruction::Store(ValueKind::Long, index) => jvm_store_long(&mut frame, *index),
+                Instruction::Store(ValueKind::Float, index) => jvm_store_float(&mut frame, *index),
+                Instruction::Store(ValueKind::Double, index) => jvm_store_double(&mut frame, *index),
+                Instruction::Store(ValueKind::Reference, index) => store_ref(&mut frame, *index),
+                Instruction::ArrayStore(ArrayKind::Int) => store_array_int(heap, &mut frame),
+                Instruction::ArrayStore(ArrayKind::Long) => store_array_long(heap, &mut frame),
+                Instruction::ArrayStore(ArrayKind::Float) => store_array_float(heap, &mut frame),
+                Instruction::ArrayStore(ArrayKind::Double) => store_array_double(heap, &mut frame),
+                Instruction::ArrayStore(ArrayKind::Reference) => {
+                    if let Err(exception) = store_array_ref(heap, class, &mut frame) {
+                        match throw_new(heap, class, thread, code, &mut frame, exception, pc) {
+                            Ok(handler) => next = handler,
+                            Err(result) => return result
+                        }
+                    }
                 }
-                JVM_OPCODE_LCONST_0 | JVM_OPCODE_LCONST_1 => {
-                    frame.push_long_op((op as i64) - (JVM_OPCODE_LCONST_0 as i64))
+                Instruction::ArrayStore(ArrayKind::Byte) => store_array_byte(heap, &mut frame),
+                Instruction::ArrayStore(ArrayKind::Char) => store_array_char(heap, &mut frame),
+                Instruction::ArrayStore(ArrayKind::Short) => store_array_short(heap, &mut frame),
+                Instruction::Pop => pop(&mut frame, false),
+                Instruction::Pop2 => pop(&mut frame, true),
+                Instruction::Dup => dup(&mut frame),
+                Instruction::DupX1 => dup_x1(&mut frame),
+                Instruction::DupX2 => dup_x2(&mut frame),
+                Instruction::Dup2 => dup2(&mut frame),
+                Instruction::Dup2X1 => dup2_x1(&mut frame),
+                Instruction::Dup2X2 => dup2_x2(&mut frame),
+                Instruction::Swap => swap(&mut frame),
+                Instruction::Arithmetic(kind, op) => arithmetic(&mut frame, *kind, *op),
+                Instruction::IInc(index, value) => jvm_int_inc(&mut frame, *index, *value),
+                Instruction::Convert(ValueKind::Int, ValueKind::Long) => jvm_int_to_long(&mut frame),
+                Instruction::Convert(ValueKind::Int, ValueKind::Float) => jvm_int_to_float(&mut frame),
+                Instruction::Convert(ValueKind::Int, ValueKind::Double) => jvm_int_to_double(&mut frame),
+                Instruction::Convert(ValueKind::Long, ValueKind::Int) => jvm_long_to_int(&mut frame),
+                Instruction::Convert(ValueKind::Long, ValueKind::Float) => jvm_long_to_float(&mut frame),
+                Instruction::Convert(ValueKind::Long, ValueKind::Double) => jvm_long_to_double(&mut frame),
+                Instruction::Convert(ValueKind::Float, ValueKind::Int) => jvm_float_to_int(&mut frame),
+                Instruction::Convert(ValueKind::Float, ValueKind::Long) => jvm_float_to_long(&mut frame),
+                Instruction::Convert(ValueKind::Float, ValueKind::Double) => jvm_float_to_double(&mut frame),
+                Instruction::Convert(ValueKind::Double, ValueKind::Int) => jvm_double_to_int(&mut frame),
+                Instruction::Convert(ValueKind::Double, ValueKind::Long) => jvm_double_to_long(&mut frame),
+                Instruction::Convert(ValueKind::Double, ValueKind::Float) => jvm_double_to_float(&mut frame),
+                Instruction::IntToByte => jvm_int_to_byte(&mut frame),
+                Instruction::IntToChar => jvm_int_to_char(&mut frame),
+                Instruction::IntToShort => jvm_int_to_short(&mut frame),
+                Instruction::LCmp => jvm_cmp_long(&mut frame),
+                Instruction::FCmpL => jvm_cmp_float(&mut frame, false),
+                Instruction::FCmpG => jvm_cmp_float(&mut frame, true),
+                Instruction::DCmpL => jvm_cmp_double(&mut frame, false),
+                Instruction::DCmpG => jvm_cmp_double(&mut frame, true),
+                Instruction::If(condition, target) => {
+                    if branch(&mut frame, *condition) {
+                        next = *target;
+                    }
                 }
-                JVM_OPCODE_FCONST_0..=JVM_OPCODE_FCONST_2 => {
-                    frame.push_float_op((op as f32) - (JVM_OPCODE_FCONST_0 as f32))
+                Instruction::IfICmp(condition, target) => {
+                    if int_branch(&mut frame, *condition) {
+                        next = *target;
+                    }
                 }
-                JVM_OPCODE_DCONST_0 | JVM_OPCODE_DCONST_1 => {
-                    frame.push_double_op((op as f64) - (JVM_OPCODE_DCONST_0 as f64))
+                Instruction::IfACmp(condition, target) => {
+                    if ref_branch(heap, &mut frame, *condition) {
+                        next = *target;
+                    }
                 }
-                JVM_OPCODE_BIPUSH => frame.push_byte_op(parser.next() as i8),
-                JVM_OPCODE_SIPUSH => {
-                    frame.push_short_op((((parser.next() as i32) << 8) | (parser.next() as i32)) as i16)
+                Instruction::Goto(target) => next = *target,
+                Instruction::Jsr(target) => {
+                    frame.push_op(next);
+                    next = *target;
                 }
-                // TODO: LDC, LDC_W, and LDC2_W
-                JVM_OPCODE_ILOAD => jvm_load_int(&mut frame, parser.next()),
-                JVM_OPCODE_LLOAD => jvm_load_long(&mut frame, parser.next()),
-                JVM_OPCODE_FLOAD => jvm_load_float(&mut frame, parser.next()),
-                JVM_OPCODE_DLOAD => jvm_load_double(&mut frame, parser.next()),
-                JVM_OPCODE_ALOAD => load_ref(heap, &mut frame, parser.next()),
-                JVM_OPCODE_ILOAD_0..=JVM_OPCODE_ILOAD_3 => jvm_load_int(&mut frame, iload_index(op)),
-                JVM_OPCODE_LLOAD_0..=JVM_OPCODE_LLOAD_3 => jvm_load_long(&mut frame, lload_index(op)),
-                JVM_OPCODE_FLOAD_0..=JVM_OPCODE_FLOAD_3 => jvm_load_float(&mut frame, fload_index(op)),
-                JVM_OPCODE_DLOAD_0..=JVM_OPCODE_DLOAD_3 => jvm_load_double(&mut frame, dload_index(op)),
-                JVM_OPCODE_ALOAD_0..=JVM_OPCODE_ALOAD_3 => load_ref(heap, &mut frame, aload_index(op)),
-                JVM_OPCODE_IALOAD => load_array_int(heap, &mut frame),
-                JVM_OPCODE_LALOAD => load_array_long(heap, &mut frame),
-                JVM_OPCODE_FALOAD => load_array_float(heap, &mut frame),
-                JVM_OPCODE_DALOAD => load_array_double(heap, &mut frame),
-                JVM_OPCODE_AALOAD => load_array_ref(heap, &mut frame),
-                JVM_OPCODE_BALOAD => load_array_byte(heap, &mut frame),
-                JVM_OPCODE_CALOAD => load_array_char(heap, &mut frame),
-                JVM_OPCODE_SALOAD => load_array_short(heap, &mut frame),
-                JVM_OPCODE_ISTORE => jvm_store_int(&mut frame, parser.next()),
-                JVM_OPCODE_LSTORE => jvm_store_long(&mut frame, parser.next()),
-                JVM_OPCODE_FSTORE => jvm_store_float(&mut frame, parser.next()),
-                JVM_OPCODE_DSTORE => jvm_store_double(&mut frame, parser.next()),
-                JVM_OPCODE_ASTORE => store_ref(heap, &mut frame, parser.next()),
-                JVM_OPCODE_ISTORE_0..=JVM_OPCODE_ISTORE_3 => jvm_store_int(&mut frame, istore_index(op)),
-                JVM_OPCODE_LSTORE_0..=JVM_OPCODE_LSTORE_3 => jvm_store_long(&mut frame, lstore_index(op)),
-                JVM_OPCODE_FSTORE_0..=JVM_OPCODE_FSTORE_3 => jvm_store_float(&mut frame, fstore_index(op)),
-                JVM_OPCODE_DSTORE_0..=JVM_OPCODE_DSTORE_3 => jvm_store_double(&mut frame, dstore_index(op)),
-                JVM_OPCODE_ASTORE_0..=JVM_OPCODE_ASTORE_3 => store_ref(heap, &mut frame, astore_index(op)),
-                JVM_OPCODE_IASTORE => store_array_int(heap, &mut frame),
-                JVM_OPCODE_LASTORE => store_array_long(heap, &mut frame),
-                JVM_OPCODE_FASTORE => store_array_float(heap, &mut frame),
-                JVM_OPCODE_DASTORE => store_array_double(heap, &mut frame),
-                JVM_OPCODE_AASTORE => store_array_ref(heap, &mut frame),
-                JVM_OPCODE_BASTORE => store_array_byte(heap, &mut frame),
-                JVM_OPCODE_CASTORE => store_array_char(heap, &mut frame),
-                JVM_OPCODE_SASTORE => store_array_short(heap, &mut frame),
-                JVM_OPCODE_POP => pop(&mut frame, false),
-                JVM_OPCODE_POP2 => pop(&mut frame, true),
-                JVM_OPCODE_DUP => dup(&mut frame),
-                JVM_OPCODE_DUP_X1 => dup_x1(&mut frame),
-                JVM_OPCODE_DUP_X2 => dup_x2(&mut frame),
-                JVM_OPCODE_DUP2 => dup2(&mut frame),
-                JVM_OPCODE_DUP2_X1 => dup2_x1(&mut frame),
-                JVM_OPCODE_DUP2_X2 => dup2_x2(&mut frame),
-                JVM_OPCODE_SWAP => swap(&mut frame),
-                JVM_OPCODE_IADD => jvm_int_add(&mut frame),
-                JVM_OPCODE_LADD => jvm_long_add(&mut frame),
-                JVM_OPCODE_FADD => jvm_float_add(&mut frame),
-                JVM_OPCODE_DADD => jvm_double_add(&mut frame),
-                JVM_OPCODE_ISUB => jvm_int_sub(&mut frame),
-                JVM_OPCODE_LSUB => jvm_long_sub(&mut frame),
-                JVM_OPCODE_FSUB => jvm_float_sub(&mut frame),
-                JVM_OPCODE_DSUB => jvm_double_sub(&mut frame),
-                JVM_OPCODE_IMUL => jvm_int_mul(&mut frame),
-                JVM_OPCODE_LMUL => jvm_long_mul(&mut frame),
-                JVM_OPCODE_FMUL => jvm_float_mul(&mut frame),
-                JVM_OPCODE_DMUL => jvm_double_mul(&mut frame),
-                JVM_OPCODE_IDIV => jvm_int_div(&mut frame),
-                JVM_OPCODE_LDIV => jvm_long_div(&mut frame),
-                JVM_OPCODE_FDIV => jvm_float_div(&mut frame),
-                JVM_OPCODE_DDIV => jvm_double_div(&mut frame),
-                JVM_OPCODE_IREM => jvm_int_rem(&mut frame),
-                JVM_OPCODE_LREM => jvm_long_rem(&mut frame),
-                JVM_OPCODE_FREM => jvm_float_rem(&mut frame),
-                JVM_OPCODE_DREM => jvm_double_rem(&mut frame),
-                JVM_OPCODE_INEG => jvm_int_neg(&mut frame),
-                JVM_OPCODE_LNEG => jvm_long_neg(&mut frame),
-                JVM_OPCODE_FNEG => jvm_float_neg(&mut frame),
-                JVM_OPCODE_DNEG => jvm_double_neg(&mut frame),
-                JVM_OPCODE_ISHL => jvm_int_shl(&mut frame),
-                JVM_OPCODE_LSHL => jvm_long_shl(&mut frame),
-                JVM_OPCODE_ISHR => jvm_int_shr(&mut frame),
-                JVM_OPCODE_LSHR => jvm_long_shr(&mut frame),
-                JVM_OPCODE_IUSHR => jvm_int_ushr(&mut frame),
-                JVM_OPCODE_LUSHR => jvm_long_ushr(&mut frame),
-                JVM_OPCODE_IAND => jvm_int_and(&mut frame),
-                JVM_OPCODE_LAND => jvm_long_and(&mut frame),
-                JVM_OPCODE_IOR => jvm_int_or(&mut frame),
-                JVM_OPCODE_LOR => jvm_long_or(&mut frame),
-                JVM_OPCODE_IXOR => jvm_int_xor(&mut frame),
-                JVM_OPCODE_LXOR => jvm_long_xor(&mut frame),
-                JVM_OPCODE_IINC => jvm_int_inc(&mut frame),
-                JVM_OPCODE_I2L => jvm_int_to_long(&mut frame),
-                JVM_OPCODE_I2F => jvm_int_to_float(&mut frame),
-                JVM_OPCODE_I2D => jvm_int_to_double(&mut frame),
-                JVM_OPCODE_L2I => jvm_long_to_int(&mut frame),
-                JVM_OPCODE_L2F => jvm_long_to_float(&mut frame),
-                JVM_OPCODE_L2D => jvm_long_to_double(&mut frame),
-                JVM_OPCODE_F2I => jvm_float_to_int(&mut frame),
-                JVM_OPCODE_F2L => jvm_float_to_long(&mut frame),
-                JVM_OPCODE_F2D => jvm_float_to_double(&mut frame),
-                JVM_OPCODE_D2I => jvm_double_to_int(&mut frame),
-                JVM_OPCODE_D2L => jvm_double_to_long(&mut frame),
-                JVM_OPCODE_D2F => jvm_double_to_float(&mut frame),
-                JVM_OPCODE_I2B => jvm_int_to_byte(&mut frame),
-                JVM_OPCODE_I2C => jvm_int_to_char(&mut frame),
-                JVM_OPCODE_I2S => jvm_int_to_short(&mut frame),
-                JVM_OPCODE_LCMP => jvm_cmp_long(&mut frame),
-                JVM_OPCODE_FCMPL => jvm_cmp_float(&mut frame, false),
-                JVM_OPCODE_FCMPG => jvm_cmp_float(&mut frame, true),
-                JVM_OPCODE_DCMPL => jvm_cmp_double(&mut frame, false),
-                JVM_OPCODE_DCMPG => jvm_cmp_double(&mut frame, true),
-                JVM_OPCODE_IFEQ..=JVM_OPCODE_IFLE => branch(&mut frame, &mut parser, op),
-                JVM_OPCODE_IF_ICMPEQ..=JVM_OPCODE_IF_ICMPLE => int_branch(&mut frame, &mut parser, op),
-                JVM_OPCODE_IF_ACMPEQ | JVM_OPCODE_IF_ACMPNE => ref_branch(heap, &mut frame, &mut parser, op),
-                JVM_OPCODE_GOTO => branch_seek(&mut parser),
-                JVM_OPCODE_JSR => jump_subroutine(&mut frame, &mut parser, false),
                 // TODO: RET, TABLESWITCH, LOOKUPSWITCH
-                JVM_OPCODE_IRETURN => return MethodResult::Integer(frame.pop_int_op()),
-                JVM_OPCODE_LRETURN => return MethodResult::Long(frame.pop_long_op()),
-                JVM_OPCODE_FRETURN => return MethodResult::Float(frame.pop_float_op()),
-                JVM_OPCODE_DRETURN => return MethodResult::Double(frame.pop_double_op()),
-                JVM_OPCODE_ARETURN => return MethodResult::Reference(frame.pop_ref_op(heap)),
-                JVM_OPCODE_RETURN => return MethodResult::Void,
-                // TODO: GETSTATIC, PUTSTATIC, GETFIELD, PUTFIELD, INVOKEVIRTUAL, INVOKESPECIAL,
-                //  INVOKESTATIC, INVOKEINTERFACE, INVOKEDYNAMIC
-                JVM_OPCODE_NEW => new_ref(heap, class, &mut frame, &mut parser),
-                JVM_OPCODE_NEWARRAY => new_type_array(heap, &mut frame, &mut parser),
-                JVM_OPCODE_ANEWARRAY => new_ref_array(heap, class, &mut frame, &mut parser),
-                JVM_OPCODE_ARRAYLENGTH => array_length(heap, &mut frame),
-                JVM_OPCODE_ATHROW => {
-                    if let Some(result) = throw(heap, code, &mut frame, &mut parser) {
-                        return result;
+                Instruction::Return(Some(ValueKind::Int)) => return MethodResult::Integer(frame.pop_int_op()),
+                Instruction::Return(Some(ValueKind::Long)) => return MethodResult::Long(frame.pop_long_op()),
+                Instruction::Return(Some(ValueKind::Float)) => return MethodResult::Float(frame.pop_float_op()),
+                Instruction::Return(Some(ValueKind::Double)) => return MethodResult::Double(frame.pop_double_op()),
+                Instruction::Return(Some(ValueKind::Reference)) => {
+                    return MethodResult::Reference(frame.pop_ref_op(heap))
+                }
+                Instruction::Return(None) => {
+                    if method.is_constructor() {
+                        freeze_final_fields(class);
+                    }
+                    return MethodResult::Void
+                }
+                // TODO: GETSTATIC, PUTSTATIC
+                Instruction::GetField(index) => get_field(heap, class, &mut frame, *index),
+                Instruction::PutField(index) => put_field(heap, class, &mut frame, *index),
+                // TODO: INVOKEVIRTUAL, INVOKESPECIAL, INVOKESTATIC, INVOKEINTERFACE, INVOKEDYNAMIC
+                Instruction::New(index) => {
+                    if let Err(error) = new_ref(heap, class, &mut frame, *index) {
+                        return MethodResult::OutOfMemory(error);
+                    }
+                }
+                Instruction::NewArray(array_type) => {
+                    if let Err(error) = new_type_array(heap, &mut frame, *array_type) {
+                        return MethodResult::OutOfMemory(error);
+                    }
+                }
+                Instruction::ANewArray(index) => {
+                    if let Err(error) = new_ref_array(heap, class, &mut frame, *index) {
+                        return MethodResult::OutOfMemory(error);
+                    }
+                }
+                Instruction::ArrayLength => array_length(heap, &mut frame),
+                Instruction::AThrow => {
+                    match throw(heap, code, &mut frame) {
+                        Ok(handler) => next = handler,
+                        Err(exception) => return MethodResult::Exception(exception)
+                    }
+                }
+                Instruction::CheckCast(index) => check_cast(heap, class, &mut frame, *index),
+                Instruction::InstanceOf(index) => instanceof(heap, class, &mut frame, *index),
+                Instruction::MonitorEnter => {
+                    if let Err(exception) = monitor_enter(heap, thread, &mut frame, pc) {
+                        match throw_new(heap, class, thread, code, &mut frame, exception, pc) {
+                            Ok(handler) => next = handler,
+                            Err(result) => return result
+                        }
                     }
                 }
-                JVM_OPCODE_CHECKCAST => check_cast(heap, class, &mut frame, &mut parser),
-                JVM_OPCODE_INSTANCEOF => instanceof(heap, class, &mut frame, &mut parser),
-                // TODO: MONITORENTER, MONITOREXIT, WIDE, MULTIANEWARRAY
-                JVM_OPCODE_IFNULL => branch_null(heap, &mut frame, &mut parser, true),
-                JVM_OPCODE_IFNONNULL => branch_null(heap, &mut frame, &mut parser, false),
-                JVM_OPCODE_GOTO_W => branch_seek_wide(&mut parser),
-                JVM_OPCODE_JSR_W => jump_subroutine(&mut frame, &mut parser, true),
-                _ => panic!("Unrecognised bytecode {}!", op)
+                Instruction::MonitorExit => {
+                    if let Err(exception) = monitor_exit(heap, thread, &mut frame) {
+                        match throw_new(heap, class, thread, code, &mut frame, exception, pc) {
+                            Ok(handler) => next = handler,
+                            Err(result) => return result
+                        }
+                    }
+                }
+                // TODO: MULTIANEWARRAY
+                Instruction::IfNull(target) => {
+                    if branch_null(heap, &mut frame, true) {
+                        next = *target;
+                    }
+                }
+                Instruction::IfNonNull(target) => {
+                    if branch_null(heap, &mut frame, false) {
+                        next = *target;
+                    }
+                }
+                _ => panic!("Unsupported instruction {}!", decoded.name())
+            }
+            if next <= pc {
+                safepoint(heap, thread, &mut frame, pc);
             }
+            pc = next;
         }
         panic!("Method should have returned by this point!");
     }
+
+    fn intrinsic(class: &Class, method: &Method) -> Option<Intrinsic> {
+        let descriptor = class.constant_pool().get_utf8(method.descriptor_index() as usize)?;
+        Intrinsic::find(class.name(), method.name(), &descriptor)
+    }
 }
 
-struct CodeParser<'a> {
-    bytes: &'a [u8],
-    index: u16
+pub enum MethodResult<'h> {
+    Integer(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    Reference(Reference<InstanceObject<'h>>),
+    Void,
+    Exception(InstanceObject<'h>),
+    OutOfMemory(OutOfMemoryError)
 }
 
-impl<'a> CodeParser<'a> {
-    pub const fn new(bytes: &'a [u8]) -> Self {
-        CodeParser { bytes, index: 0 }
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use crate::assembler::{assemble, parse};
+    use crate::class_file::ClassLoader;
+    use crate::code::{Interpreter, MethodResult};
+    use crate::objects::{FieldLayout, HeapSpace, NoRoots, RootSource};
+    use crate::test_support::Offsets;
+    use crate::types::Class;
+    use crate::utils::descriptors::{FieldDescriptor, FieldType};
+
+    const SOURCE: &str = r#"
+.class Link
+.super java/lang/Object
+.field value I
+.field next LLink;
+.field volatile total J
+.method public static churn(I)V
+.limit stack 1
+.limit locals 1
+Loop:
+    iload_0
+    ifle End
+    new Link
+    pop
+    iinc 0 -1
+    goto Loop
+End:
+    return
+.end method
+.method public static hoard()V
+.limit stack 3
+    new Link
+    new Link
+    new Link
+    return
+.end method
+.method public setNext(LLink;)V
+.limit stack 2
+.limit locals 2
+    aload_0
+    aload_1
+    putfield Link/next LLink;
+    return
+.end method
+.method public static lock(LLink;)V
+.limit stack 2
+.limit locals 1
+    aload_0
+    dup
+    monitorenter
+    aload_0
+    monitorenter
+    monitorexit
+    return
+.end method
+.method public static unlock(LLink;)V
+.limit stack 1
+.limit locals 1
+    aload_0
+    monitorexit
+    return
+.end method
+.method public static lockNull()I
+.limit stack 2
+.catch java/lang/NullPointerException from Start to End using Handler
+Start:
+    aconst_null
+    monitorenter
+End:
+    iconst_0
+    ireturn
+Handler:
+    iconst_1
+    ireturn
+.end method
+.method public exchangeTotal(J)J
+.limit stack 5
+.limit locals 3
+    aload_0
+    getfield Link/total J
+    aload_0
+    lload_1
+    putfield Link/total J
+    lreturn
+.end method
+.method public getNext()LLink;
+.limit stack 1
+.limit locals 1
+    aload_0
+    getfield Link/next LLink;
+    areturn
+.end method
+"#;
+
+    const EXCEPTION_SOURCES: [&str; 3] = [
+        ".class java/lang/Throwable\n.super java/lang/Object\n",
+        ".class java/lang/NullPointerException\n.super java/lang/Throwable\n",
+        ".class java/lang/IllegalMonitorStateException\n.super java/lang/Throwable\n"
+    ];
+
+    const LINK_SIZE: usize = 32;
+
+    fn link_class() -> Arc<Class> {
+        let loader = Arc::new(ClassLoader::new());
+        for source in EXCEPTION_SOURCES {
+            loader.define_class(assemble(source).unwrap());
+        }
+        let class = loader.define_class(parse(SOURCE).unwrap().build_with(&loader).unwrap());
+        assert_eq!(FieldLayout::of(&class).size(), LINK_SIZE);
+        class
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.bytes.is_empty()
+    fn run<'h>(heap: &'h HeapSpace, class: &Arc<Class>, name: &str, parameters: &[u32]) -> MethodResult<'h> {
+        let method = class.methods().iter().find(|method| method.name() == name).unwrap();
+        Interpreter::execute(heap, class, method, parameters)
     }
 
-    pub fn next(&mut self) -> u8 {
-        let next = self.bytes[self.index as usize];
-        self.index += 1;
-        next
+    fn allocate(heap: &HeapSpace, class: &Arc<Class>, roots: &mut dyn RootSource) -> usize {
+        heap.allocate_ref(class, roots).unwrap().offset()
     }
 
-    pub fn next_index(&self) -> u32 {
-        (self.index + 1) as u32
+    #[test]
+    fn reclaims_garbage_during_execution() {
+        let heap = HeapSpace::with_sizes(LINK_SIZE * 4, LINK_SIZE);
+        assert!(matches!(run(&heap, &link_class(), "churn", &[100]), MethodResult::Void));
+        assert_eq!(heap.object_count(), 2);
     }
 
-    pub fn seek(&mut self, index: usize) {
-        self.index = index as u16;
+    #[test]
+    fn runs_out_of_memory_when_everything_is_live() {
+        let heap = HeapSpace::with_sizes(LINK_SIZE * 2, LINK_SIZE);
+        match run(&heap, &link_class(), "hoard", &[]) {
+            MethodResult::OutOfMemory(error) => assert_eq!(error.used(), LINK_SIZE * 2),
+            _ => panic!("Expected hoard to run out of memory!")
+        }
     }
 
-    pub fn seek_relative(&mut self, offset: usize) {
-        self.index += offset as u16;
+    #[test]
+    fn records_old_to_young_field_stores() {
+        let class = link_class();
+        let heap = HeapSpace::with_sizes(LINK_SIZE * 8, LINK_SIZE * 8);
+        let mut roots = Offsets(vec![allocate(&heap, &class, &mut NoRoots)]);
+        heap.collect(&mut roots);
+        let old = roots.0[0];
+        assert!(!heap.is_young(old));
+
+        let young = allocate(&heap, &class, &mut roots);
+        let young_ref = heap.get_ref(young).unwrap();
+        let value = young_ref.field_offset("value", &FieldDescriptor::new(FieldType::Int, 0)).unwrap();
+        young_ref.set_int(value, 42);
+        assert!(matches!(run(&heap, &class, "setNext", &[old as u32, young as u32]), MethodResult::Void));
+        heap.collect_nursery(&mut roots);
+        let next = match run(&heap, &class, "getNext", &[old as u32]) {
+            MethodResult::Reference(next) => next.unwrap(),
+            _ => panic!("Expected getNext to return a reference!")
+        };
+        assert_ne!(next.offset(), young);
+        assert_eq!(next.get_int(value), 42);
     }
-}
 
-pub enum MethodResult {
-    Integer(i32),
-    Long(i64),
-    Float(f32),
-    Double(f64),
-    Reference(Reference<InstanceObject>),
-    Void,
-    Exception
-}
+    #[test]
+    fn counts_monitor_entries() {
+        let class = link_class();
+        let heap = HeapSpace::new(LINK_SIZE * 8);
+        let link = allocate(&heap, &class, &mut NoRoots);
+        assert!(matches!(run(&heap, &class, "lock", &[link as u32]), MethodResult::Void));
+        // The method entered the monitor twice and only exited it once.
+        assert!(heap.monitor_exit(link, 0));
+        assert!(!heap.monitor_exit(link, 0));
+    }
 
-macro_rules! generate_load_store_index {
-    ($name:ident, $prefix:ident) => {
-        paste! {
-            fn [<$name _index>](op: u8) -> u8 {
-                if op < [<$prefix _0>] || op > [<$prefix _3>] {
-                    panic!("{} called with op < {} or > {}! Op was {}!", "[<$name _index>]",
-                        "[<$prefix _0>]", "[<$prefix _3>]", op);
-                }
-                op - [<$prefix _0>]
+    #[test]
+    fn throws_for_null_and_unowned_monitors() {
+        let class = link_class();
+        let heap = HeapSpace::new(LINK_SIZE * 8);
+        assert!(matches!(run(&heap, &class, "lockNull", &[]), MethodResult::Integer(1)));
+
+        let link = allocate(&heap, &class, &mut NoRoots);
+        match run(&heap, &class, "unlock", &[link as u32]) {
+            MethodResult::Exception(exception) => {
+                assert_eq!(exception.class().name(), "java/lang/IllegalMonitorStateException");
             }
+            _ => panic!("Expected unlock to throw!")
         }
-    };
-    ($prefix:ident) => {
-        paste! {
-            generate_load_store_index!([<$prefix load>], [<JVM_OPCODE_ $prefix:upper LOAD>]);
-            generate_load_store_index!([<$prefix store>], [<JVM_OPCODE_ $prefix:upper STORE>]);
+    }
+
+    #[test]
+    fn exchanges_whole_volatile_longs() {
+        let class = link_class();
+        let heap = HeapSpace::new(LINK_SIZE * 8);
+        let link = allocate(&heap, &class, &mut NoRoots) as u32;
+        let value = 0x0123_4567_89AB_CDEFi64;
+        let parameters = [link, (value >> 32) as u32, value as u32];
+        assert!(matches!(run(&heap, &class, "exchangeTotal", &parameters), MethodResult::Long(0)));
+        match run(&heap, &class, "exchangeTotal", &[link, 0, 0]) {
+            MethodResult::Long(previous) => assert_eq!(previous, value),
+            _ => panic!("Expected exchangeTotal to return a long!")
         }
     }
 }
-
-generate_load_store_index!(a);
-generate_load_store_index!(d);
-generate_load_store_index!(f);
-generate_load_store_index!(i);
-generate_load_store_index!(l);
@@ -14,15 +14,18 @@
  * Street, Fifth Floor, Boston, MA 02110-1301, USA.
  */
 
-use std::ops::Deref;
+use std::sync::atomic::{fence, Ordering};
+use std::time::Duration;
 use paste::paste;
-use std::sync::Arc;
 use crate::class_file::code::CodeBlock;
-use crate::code::StackFrame;
+use crate::code::{MethodResult, StackFrame};
+use crate::code::natives::{can_store, is_instance_of, new_exception};
 use crate::constants::*;
 use crate::objects::*;
+use crate::runtime::{JavaThread, Monitor};
 use crate::types::Class;
-use super::{CodeParser, MethodResult};
+use crate::utils::descriptors::FieldType;
+use crate::class_file::bytecode::Condition;
 
 macro_rules! load_store_array_primitive {
     ($name:ident, $instruction_prefix:literal, $expected:literal, $array_type:pat) => {
@@ -53,22 +56,28 @@ macro_rules! load_store_array_primitive {
 }
 
 pub(super) fn load_array_ref(heap: &HeapSpace, frame: &mut StackFrame) {
-    let array_ref = frame.pop_ref_array_op(heap).expect("Invalid array reference on operand stack!");
     let index = frame.pop_int_op();
-    let value = array_ref.get(index as usize).expect("Invalid array index on operand stack!");
-    frame.push_ref_op(value.offset() as u32);
+    let array_ref = frame.pop_ref_array_op(heap).expect("Invalid array reference on operand stack!");
+    frame.push_ref_op(array_ref.get(index as usize));
 }
 
-pub(super) fn store_array_ref(heap: &HeapSpace, frame: &mut StackFrame) {
-    let array_ref = frame.pop_ref_array_op(heap).expect("Invalid array reference on operand stack!");
+// Returns the class of the exception to throw if the value can't go in the array.
+pub(super) fn store_array_ref(heap: &HeapSpace, class: &Class, frame: &mut StackFrame) -> Result<(), &'static str> {
+    let value = frame.pop_op();
     let index = frame.pop_int_op();
-    let value = frame.pop_ref_op(heap).expect("Invalid array value on operand stack!");
+    let array_ref = frame.pop_ref_array_op(heap).expect("Invalid array reference on operand stack!");
+    if !can_store(heap, &class.loader(), array_ref.offset(), value as usize) {
+        return Err(ARRAY_STORE_EXCEPTION);
+    }
     array_ref.set(index as usize, value);
+    heap.write_barrier(array_ref.offset(), value as usize);
+    Ok(())
 }
 
-pub(super) fn load_ref(heap: &HeapSpace, frame: &mut StackFrame, index: u8) {
-    let reference = frame.get_local_ref(index as usize, heap).expect("Invalid local reference index!");
-    frame.push_ref_op(reference.offset() as u32);
+// Null is as valid in a local variable as anywhere else, so the offset is copied without looking it
+// up in the heap.
+pub(super) fn load_ref(frame: &mut StackFrame, index: u16) {
+    frame.push_ref_op(frame.get_local_slot(index as usize).value());
 }
 
 pub(super) fn array_length(heap: &HeapSpace, frame: &mut StackFrame) {
@@ -76,27 +85,170 @@ pub(super) fn array_length(heap: &HeapSpace, frame: &mut StackFrame) {
     frame.push_int_op(array_ref.len() as i32);
 }
 
-pub(super) fn store_ref(heap: &HeapSpace, frame: &mut StackFrame, index: u8) {
-    let reference = frame.pop_ref_op(heap)
-        .expect("Invalid reference on operand stack! Reference cannot be null!");
-    frame.set_local_ref(index as usize, reference.offset() as u32);
+// Records where the thread is before it stops, as that's when anything else might look.
+pub(super) fn safepoint(heap: &HeapSpace, thread: Option<&JavaThread>, frame: &mut StackFrame, pc: u32) {
+    if let Some(thread) = thread {
+        thread.set_pc(pc);
+    }
+    heap.safepoint(frame);
 }
 
-pub(super) fn throw(
+const NULL_POINTER_EXCEPTION: &str = "java/lang/NullPointerException";
+const ILLEGAL_MONITOR_STATE_EXCEPTION: &str = "java/lang/IllegalMonitorStateException";
+const ARRAY_STORE_EXCEPTION: &str = "java/lang/ArrayStoreException";
+
+// Arrays have monitors just like any other object, so the offset is used without looking at what it is.
+// A thread that finds the monitor held by another one stops until it's let go of, leaving the reference
+// on the stack while it waits, as the object can move in the meantime. Threads that aren't attached all
+// enter monitors as the same owner. Returns the class of the exception to throw if the reference is null.
+pub(super) fn monitor_enter(
     heap: &HeapSpace,
-    code: &CodeBlock,
+    thread: Option<&JavaThread>,
     frame: &mut StackFrame,
-    parser: &mut CodeParser
-) -> Option<MethodResult> {
+    pc: u32
+) -> Result<(), &'static str> {
+    let owner = thread.map_or(0, JavaThread::id);
+    loop {
+        let offset = frame.peek_op() as usize;
+        if offset == 0 {
+            frame.pop_op();
+            return Err(NULL_POINTER_EXCEPTION);
+        }
+        if heap.monitor_enter(offset, owner) {
+            frame.pop_op();
+            if let Some(thread) = thread {
+                thread.monitor_entered(monitor(heap, offset));
+            }
+            return Ok(());
+        }
+        if let Some(thread) = thread {
+            thread.set_pc(pc);
+            thread.monitor_blocked(monitor(heap, offset));
+        }
+        heap.blocking(frame, || std::thread::sleep(MONITOR_RETRY_INTERVAL));
+    }
+}
+
+// How long a thread blocked on a monitor waits before trying to enter it again.
+const MONITOR_RETRY_INTERVAL: Duration = Duration::from_millis(1);
+
+// Like monitor_enter, returns the class of the exception to throw, which is also thrown if the current
+// thread doesn't hold the monitor.
+pub(super) fn monitor_exit(
+    heap: &HeapSpace,
+    thread: Option<&JavaThread>,
+    frame: &mut StackFrame
+) -> Result<(), &'static str> {
+    let offset = frame.pop_op() as usize;
+    if offset == 0 {
+        return Err(NULL_POINTER_EXCEPTION);
+    }
+    let owner = thread.map_or(0, JavaThread::id);
+    if !heap.monitor_exit(offset, owner) {
+        return Err(ILLEGAL_MONITOR_STATE_EXCEPTION);
+    }
+    if let Some(thread) = thread {
+        thread.monitor_exited(heap.identity_hash(offset));
+    }
+    Ok(())
+}
+
+fn monitor(heap: &HeapSpace, offset: usize) -> Monitor {
+    Monitor::new(heap.identity_hash(offset), heap.class_name(offset))
+}
+
+pub(super) fn store_ref(frame: &mut StackFrame, index: u16) {
+    let value = frame.pop_op();
+    frame.set_local_ref(index as usize, value);
+}
+
+// Volatile fields are read and written with volatile accesses, and every other field with plain ones,
+// which are still atomic, so that even longs and doubles can never tear.
+pub(super) fn get_field(heap: &HeapSpace, class: &Class, frame: &mut StackFrame, index: u16) {
+    let field = class.constant_pool().get_field_ref(index as usize)
+        .unwrap_or_else(|| panic!("Invalid field access! Expected index {} to be in constant pool!", index));
+    let object = frame.pop_ref_op(heap).expect("Cannot get a field of null!");
+    let descriptor = field.descriptor();
+    let (offset, order) = object.find_field(field.name(), descriptor)
+        .unwrap_or_else(|| panic!("No field {} found in {}!", field.name(), object.class().name()));
+    let value = heap.load(object.offset(), offset, field_size(descriptor), order);
+    match descriptor.base() {
+        _ if descriptor.is_reference() => frame.push_ref_op(value as u32),
+        FieldType::Boolean => frame.push_bool_op(value != 0),
+        FieldType::Byte => frame.push_byte_op(value as i8),
+        // Chars are read as shorts, as a lone surrogate is a valid Java char but not a valid Rust one.
+        FieldType::Char => frame.push_int_op(value as u16 as i32),
+        FieldType::Short => frame.push_short_op(value as i16),
+        FieldType::Int => frame.push_int_op(value as i32),
+        FieldType::Float => frame.push_float_op(f32::from_bits(value as u32)),
+        FieldType::Long => frame.push_long_op(value as i64),
+        FieldType::Double => frame.push_double_op(f64::from_bits(value)),
+        FieldType::Reference(_) => unreachable!()
+    }
+}
+
+pub(super) fn put_field(heap: &HeapSpace, class: &Class, frame: &mut StackFrame, index: u16) {
+    let field = class.constant_pool().get_field_ref(index as usize)
+        .unwrap_or_else(|| panic!("Invalid field access! Expected index {} to be in constant pool!", index));
+    let descriptor = field.descriptor();
+    // Longs and doubles are popped together, so that the bits of both halves end up in one value.
+    let value = match descriptor.slot_size() {
+        2 => frame.pop_long_op() as u64,
+        _ => frame.pop_op() as u64
+    };
+    let object = frame.pop_ref_op(heap).expect("Cannot set a field of null!");
+    let (offset, order) = object.find_field(field.name(), descriptor)
+        .unwrap_or_else(|| panic!("No field {} found in {}!", field.name(), object.class().name()));
+    let value = match descriptor.base() {
+        FieldType::Boolean if !descriptor.is_reference() => value & 1,
+        _ => value
+    };
+    heap.store(object.offset(), offset, field_size(descriptor), value, order);
+    if descriptor.is_reference() {
+        heap.write_barrier(object.offset(), value as usize);
+    }
+}
+
+// Freezes the final fields of an object once its constructor returns, so that any thread that sees a
+// reference to the object published after that also sees the values of its final fields. Like
+// HotSpot, this is a barrier that stops the stores to the fields from being reordered with later
+// stores, and is only needed when the class has any final fields.
+pub(super) fn freeze_final_fields(class: &Class) {
+    if class.fields().iter().any(|field| field.is_final() && !field.is_static()) {
+        fence(Ordering::Release);
+    }
+}
+
+// Returns the offset of the handler for the exception, if there is one.
+// Returns the exception if the method doesn't handle it, so that it can be thrown on to the caller.
+pub(super) fn throw<'h>(
+    heap: &'h HeapSpace,
+    code: &CodeBlock,
+    frame: &mut StackFrame
+) -> Result<u32, InstanceObject<'h>> {
     let exception = frame.pop_ref_op(heap)
         .expect("Invalid exception on operand stack! Reference cannot be null!");
-    match code.exception_handlers().get_handler(exception.class()) {
-        Some(value) => {
-            parser.seek(value.start_pc() as usize);
-            None
-        },
-        None => Some(MethodResult::Exception)
+    let handler = code.exception_handlers().get_handler(&exception.class()).map(|handler| handler.handler_pc() as u32);
+    handler.ok_or(exception)
+}
+
+// Throws a new exception of the given class from an instruction, which goes to a handler in the method
+// if there is one, just like it would if the method had thrown it with athrow.
+pub(super) fn throw_new<'h>(
+    heap: &'h HeapSpace,
+    class: &Class,
+    thread: Option<&JavaThread>,
+    code: &CodeBlock,
+    frame: &mut StackFrame,
+    class_name: &str,
+    pc: u32
+) -> Result<u32, MethodResult<'h>> {
+    if let Some(thread) = thread {
+        thread.set_pc(pc);
     }
+    let exception = new_exception(heap, &class.loader(), class_name, None, frame).map_err(MethodResult::OutOfMemory)?;
+    frame.push_ref_op(exception as u32);
+    throw(heap, code, frame).map_err(MethodResult::Exception)
 }
 
 pub(super) fn load_array_byte(heap: &HeapSpace, frame: &mut StackFrame) {
@@ -132,23 +284,17 @@ load_store_array_primitive!(int, "I", "int", JVM_T_INT);
 load_store_array_primitive!(long, "L", "long", JVM_T_LONG);
 load_store_array_primitive!(short, "S", "short", JVM_T_SHORT);
 
-pub(super) fn check_cast(
-    heap: &HeapSpace,
-    class: &Class,
-    frame: &mut StackFrame,
-    parser: &mut CodeParser
-) {
+pub(super) fn check_cast(heap: &HeapSpace, class: &Class, frame: &mut StackFrame, class_index: u16) {
     let reference = frame.pop_ref_op(heap);
     if matches!(reference, Reference::Null) {
         return;
     }
     let reference = reference.unwrap();
 
-    let class_index = ((parser.next() as u16) << 8) | (parser.next() as u16);
     let class = class.constant_pool().get_class(class_index as usize)
         .expect(&format!("Invalid cast check! Expected index {} to be in constant pool!", class_index));
-    assert!(reference.class().is_subclass(&class), "Cannot cast {} to {}!",
-        reference.class().name(), class.name());
+    assert!(is_instance_of(heap, &class.loader(), reference.offset(), class.name()), "Cannot cast {} to {}!",
+        heap.class_name(reference.offset()), class.name());
     frame.push_ref_op(reference.offset() as u32);
 }
 
@@ -156,7 +302,7 @@ pub(super) fn check_cast(
 fn common_array_primitive(
     heap: &HeapSpace,
     frame: &mut StackFrame,
-    mapper: impl Fn(&mut StackFrame, Arc<TypeArrayObject>, u8, usize)
+    mapper: impl Fn(&mut StackFrame, TypeArrayObject, u8, usize)
 ) {
     let array_ref = frame.pop_type_array_op(heap)
         .expect("Invalid array reference on operand stack! Reference cannot be null!");
@@ -172,7 +318,7 @@ fn array_primitive(
     instruction: &str,
     expected_type: &str,
     checker: impl Fn(u8) -> bool,
-    mapper: impl Fn(&mut StackFrame, Arc<TypeArrayObject>, usize)
+    mapper: impl Fn(&mut StackFrame, TypeArrayObject, usize)
 ) {
     common_array_primitive(heap, frame, |frame, array, array_type, index| {
         if checker(array_type) {
@@ -191,100 +337,81 @@ pub(super) fn pop(frame: &mut StackFrame, double: bool) {
 }
 
 pub(super) fn dup(frame: &mut StackFrame) {
-    frame.push_op(frame.get_op(0));
+    frame.push_slot_op(frame.get_slot_op(0));
 }
 
 pub(super) fn dup_x1(frame: &mut StackFrame) {
-    let first = frame.get_op(0);
-    let second = frame.get_op(1);
-    frame.set_op(1, first);
-    frame.set_op(0, second);
-    frame.push_op(first);
+    let first = frame.get_slot_op(0);
+    let second = frame.get_slot_op(1);
+    frame.set_slot_op(1, first);
+    frame.set_slot_op(0, second);
+    frame.push_slot_op(first);
 }
 
 pub(super) fn dup_x2(frame: &mut StackFrame) {
-    let first = frame.get_op(0);
-    let third = frame.get_op(2);
-    frame.set_op(2, first);
-    frame.push_op(first);
-    let second = frame.get_op(2);
-    frame.set_op(2, third);
-    frame.set_op(1, second);
+    let first = frame.get_slot_op(0);
+    let third = frame.get_slot_op(2);
+    frame.set_slot_op(2, first);
+    frame.push_slot_op(first);
+    let second = frame.get_slot_op(2);
+    frame.set_slot_op(2, third);
+    frame.set_slot_op(1, second);
 }
 
 pub(super) fn dup2(frame: &mut StackFrame) {
-    let high = frame.get_op(1);
-    frame.push_op(high);
-    let low = frame.get_op(1);
-    frame.push_op(low);
+    let high = frame.get_slot_op(1);
+    frame.push_slot_op(high);
+    let low = frame.get_slot_op(1);
+    frame.push_slot_op(low);
 }
 
 pub(super) fn dup2_x1(frame: &mut StackFrame) {
-    let first = frame.get_op(0);
-    let second = frame.get_op(1);
-    frame.push_op(second);
-    frame.push_op(first);
-    frame.set_op(3, first);
-    let third = frame.get_op(4);
-    frame.set_op(2, third);
-    frame.set_op(4, second);
+    let first = frame.get_slot_op(0);
+    let second = frame.get_slot_op(1);
+    frame.push_slot_op(second);
+    frame.push_slot_op(first);
+    frame.set_slot_op(3, first);
+    let third = frame.get_slot_op(4);
+    frame.set_slot_op(2, third);
+    frame.set_slot_op(4, second);
 }
 
 pub(super) fn dup2_x2(frame: &mut StackFrame) {
-    let first = frame.get_op(0);
-    let second = frame.get_op(1);
-    frame.push_op(second);
-    frame.push_op(first);
-    let third = frame.get_op(4);
-    frame.set_op(2, third);
-    frame.set_op(4, first);
-    let fourth = frame.get_op(5);
-    let fifth = frame.get_op(3);
-    frame.set_op(3, fourth);
-    frame.set_op(5, fifth);
+    let first = frame.get_slot_op(0);
+    let second = frame.get_slot_op(1);
+    frame.push_slot_op(second);
+    frame.push_slot_op(first);
+    let third = frame.get_slot_op(4);
+    frame.set_slot_op(2, third);
+    frame.set_slot_op(4, first);
+    let fourth = frame.get_slot_op(5);
+    let fifth = frame.get_slot_op(3);
+    frame.set_slot_op(3, fourth);
+    frame.set_slot_op(5, fifth);
 }
 
 pub(super) fn swap(frame: &mut StackFrame) {
-    let first = frame.get_op(1);
-    let second = frame.get_op(0);
-    frame.set_op(0, first);
-    frame.set_op(1, second);
+    let first = frame.get_slot_op(1);
+    let second = frame.get_slot_op(0);
+    frame.set_slot_op(0, first);
+    frame.set_slot_op(1, second);
 }
 
-pub(super) fn branch<'a>(frame: &mut StackFrame, parser: &mut CodeParser<'a>, op: u8) {
+// The branch functions all return whether the branch should be taken.
+pub(super) fn branch(frame: &mut StackFrame, condition: Condition) -> bool {
     let value = frame.pop_int_op();
-    let success = (op == JVM_OPCODE_IFEQ && value == 0) ||
-        (op == JVM_OPCODE_IFNE && value != 0) ||
-        (op == JVM_OPCODE_IFLT && value < 0) ||
-        (op == JVM_OPCODE_IFLE && value <= 0) ||
-        (op == JVM_OPCODE_IFGT && value > 0) ||
-        (op == JVM_OPCODE_IFGE && value >= 0);
-    if success {
-        branch_seek(parser);
-    }
+    condition.test(value, 0)
 }
 
-pub(super) fn branch_null(
-    heap: &HeapSpace,
-    frame: &mut StackFrame,
-    parser: &mut CodeParser,
-    null: bool
-) {
+pub(super) fn branch_null(heap: &HeapSpace, frame: &mut StackFrame, null: bool) -> bool {
     match frame.pop_ref_op(heap) {
-        Reference::Value(_) if !null => branch_seek(parser),
-        Reference::Null if null => branch_seek(parser),
-        _ => {}
+        Reference::Value(_) => !null,
+        Reference::Null => null
     }
 }
 
-pub(super) fn instanceof(
-    heap: &HeapSpace,
-    class: &Class,
-    frame: &mut StackFrame,
-    parser: &mut CodeParser
-) {
+pub(super) fn instanceof(heap: &HeapSpace, class: &Class, frame: &mut StackFrame, index: u16) {
     let reference = frame.pop_ref_op(heap);
-    let index = ((parser.next() as u16) << 8) | (parser.next() as u16);
     if let Reference::Null = reference {
         frame.push_int_op(0);
         return;
@@ -292,62 +419,33 @@ pub(super) fn instanceof(
     let reference = reference.unwrap();
     let class = class.constant_pool().get_class(index as usize)
         .expect("Invalid class for instanceof check! Expected index to be in constant pool!");
-    let result = if reference.class().is_subclass(class.deref()) { 1 } else { 0 };
+    let result = if is_instance_of(heap, &class.loader(), reference.offset(), class.name()) { 1 } else { 0 };
     frame.push_int_op(result);
 }
 
-pub(super) fn ref_branch(heap: &HeapSpace, frame: &mut StackFrame, parser: &mut CodeParser, op: u8) {
+pub(super) fn ref_branch(heap: &HeapSpace, frame: &mut StackFrame, condition: Condition) -> bool {
     let first_ref = frame.pop_ref_op(heap);
     let second_ref = frame.pop_ref_op(heap);
     let ref_compare = first_ref.equals(second_ref);
-    if (op == JVM_OPCODE_IF_ACMPEQ && ref_compare) || (op == JVM_OPCODE_IF_ACMPNE && !ref_compare) {
-        branch_seek(parser);
+    match condition {
+        Condition::Eq => ref_compare,
+        Condition::Ne => !ref_compare,
+        _ => panic!("Invalid reference comparison {:?}!", condition)
     }
 }
 
-pub(super) fn int_branch(frame: &mut StackFrame, parser: &mut CodeParser, op: u8) {
-    let first = frame.pop_int_op();
+pub(super) fn int_branch(frame: &mut StackFrame, condition: Condition) -> bool {
     let second = frame.pop_int_op();
-    let success = (op == JVM_OPCODE_IF_ICMPEQ && first == second) ||
-        (op == JVM_OPCODE_IF_ICMPNE && first != second) ||
-        (op == JVM_OPCODE_IF_ICMPLT && first < second) ||
-        (op == JVM_OPCODE_IF_ICMPLE && first <= second) ||
-        (op == JVM_OPCODE_IF_ICMPGT && first > second) ||
-        (op == JVM_OPCODE_IF_ICMPGE && first >= second);
-    if success {
-        branch_seek(parser);
-    }
-}
-
-pub(super) fn jump_subroutine(frame: &mut StackFrame, parser: &mut CodeParser, wide: bool) {
-    frame.push_op(parser.next_index());
-    if wide {
-        branch_seek_wide(parser);
-    } else {
-        branch_seek(parser);
-    }
-}
-
-pub(super) fn branch_seek(parser: &mut CodeParser) {
-    let index = ((parser.next() as i16) << 8) | (parser.next() as i16);
-    parser.seek_relative(index as usize);
-}
-
-pub(super) fn branch_seek_wide(parser: &mut CodeParser) {
-    let index = ((parser.next() as i32) << 24) |
-        ((parser.next() as i32) << 16) |
-        ((parser.next() as i32) << 8) |
-        (parser.next() as i32);
-    parser.seek_relative(index as usize);
+    let first = frame.pop_int_op();
+    condition.test(first, second)
 }
 
 pub(super) fn new_ref(
     heap: &HeapSpace,
     class: &Class,
     frame: &mut StackFrame,
-    parser: &mut CodeParser
-) {
-    let index = ((parser.next() as u16) << 8) | (parser.next() as u16);
+    index: u16
+) -> Result<(), OutOfMemoryError> {
     let class = class.constant_pool().get_class(index as usize)
         .expect(&format!("Invalid object instantiation! Expected index {} to be in constant \
             pool!", index));
@@ -355,40 +453,35 @@ pub(super) fn new_ref(
         panic!("Attempted to instantiate an interface or abstract class!");
     }
 
-    let offset = heap.len(); // Index of next element will be the current length
-    let field_count = class.field_count();
-    let instance = InstanceObject::new(offset, Arc::clone(&class), field_count);
-    for i in 0..field_count {
-        // Everything gets initialised to default values. For primitives, this is 0.
-        // For references, this is null, but the offset of null references is 0.
-        instance.set(i, 0);
-    }
-
-    heap.push_ref(Arc::new(instance));
-    frame.push_ref_op(offset as u32);
+    // Everything gets initialised to default values. For primitives, this is 0.
+    // For references, this is null, but the offset of null references is 0.
+    let instance = heap.allocate_ref(&class, frame)?;
+    frame.push_ref_op(instance.offset() as u32);
+    Ok(())
 }
 
 pub(super) fn new_ref_array(
     heap: &HeapSpace,
     class: &Class,
     frame: &mut StackFrame,
-    parser: &mut CodeParser
-) {
+    index: u16
+) -> Result<(), OutOfMemoryError> {
     let count = frame.pop_int_op();
-    let index = ((parser.next() as u16) << 8) | (parser.next() as u16);
     let class = class.constant_pool().get_class(index as usize)
         .expect(&format!("Invalid class type index {}!", index));
 
-    let offset = heap.len(); // Index of next element will be the current length
-    let array = ReferenceArrayObject::new(offset, Arc::clone(&class), class, count as usize);
-    heap.push_ref_array(Arc::new(array));
-    frame.push_ref_op(offset as u32);
+    let array = heap.allocate_ref_array(&class, &class, count as usize, frame)?;
+    frame.push_ref_op(array.offset() as u32);
+    Ok(())
 }
 
-pub(super) fn new_type_array(heap: &HeapSpace, frame: &mut StackFrame, parser: &mut CodeParser) {
+pub(super) fn new_type_array(
+    heap: &HeapSpace,
+    frame: &mut StackFrame,
+    array_type: BasicType
+) -> Result<(), OutOfMemoryError> {
     let count = frame.pop_int_op();
-    let offset = heap.len(); // Index of next element will be the current length
-    let array = TypeArrayObject::new(offset, parser.next(), count as usize);
-    heap.push_type_array(Arc::new(array));
-    frame.push_ref_op(offset as u32);
+    let array = heap.allocate_type_array(array_type as u8, count as usize, frame)?;
+    frame.push_ref_op(array.offset() as u32);
+    Ok(())
 }
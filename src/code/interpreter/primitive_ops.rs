@@ -15,6 +15,7 @@
  */
 
 use paste::paste;
+use crate::class_file::bytecode::{ArithmeticOp, ValueKind};
 use crate::code::stack_frame::StackFrame;
 
 macro_rules! primitive_op {
@@ -99,12 +100,12 @@ macro_rules! floating_compare {
 macro_rules! primitive_load_store {
     ($name:ident, $primitive:ty) => {
         paste! {
-            pub fn [<jvm_load_ $name>](frame: &mut StackFrame, index: u8) {
+            pub fn [<jvm_load_ $name>](frame: &mut StackFrame, index: u16) {
                 let value = frame.[<get_local_ $name>](index as usize);
                 frame.[<push_ $name _op>](value as $primitive);
             }
 
-            pub fn [<jvm_store_ $name>](frame: &mut StackFrame, index: u8) {
+            pub fn [<jvm_store_ $name>](frame: &mut StackFrame, index: u16) {
                 let value = frame.[<pop_ $name _op>]();
                 frame.[<set_local_ $name>](index as usize, value);
             }
@@ -167,9 +168,9 @@ generate_int_long_functions!(long, i64, u64);
 generate_shared_functions!(float, f32);
 generate_shared_functions!(double, f64);
 
-pub fn jvm_int_inc(frame: &mut StackFrame) {
-    let value = frame.pop_int_op();
-    frame.push_int_op(value + 1);
+pub fn jvm_int_inc(frame: &mut StackFrame, index: u16, value: i16) {
+    let local = frame.get_local_int(index as usize);
+    frame.set_local_int(index as usize, local.wrapping_add(value as i32));
 }
 
 // Integer conversion
@@ -204,3 +205,45 @@ pub fn jvm_cmp_long(frame: &mut StackFrame) {
     let result = if first > second { 1 } else if second > first { -1 } else { 0 };
     frame.push_int_op(result);
 }
+
+pub fn arithmetic(frame: &mut StackFrame, kind: ValueKind, op: ArithmeticOp) {
+    match (kind, op) {
+        (ValueKind::Int, ArithmeticOp::Add) => jvm_int_add(frame),
+        (ValueKind::Long, ArithmeticOp::Add) => jvm_long_add(frame),
+        (ValueKind::Float, ArithmeticOp::Add) => jvm_float_add(frame),
+        (ValueKind::Double, ArithmeticOp::Add) => jvm_double_add(frame),
+        (ValueKind::Int, ArithmeticOp::Sub) => jvm_int_sub(frame),
+        (ValueKind::Long, ArithmeticOp::Sub) => jvm_long_sub(frame),
+        (ValueKind::Float, ArithmeticOp::Sub) => jvm_float_sub(frame),
+        (ValueKind::Double, ArithmeticOp::Sub) => jvm_double_sub(frame),
+        (ValueKind::Int, ArithmeticOp::Mul) => jvm_int_mul(frame),
+        (ValueKind::Long, ArithmeticOp::Mul) => jvm_long_mul(frame),
+        (ValueKind::Float, ArithmeticOp::Mul) => jvm_float_mul(frame),
+        (ValueKind::Double, ArithmeticOp::Mul) => jvm_double_mul(frame),
+        (ValueKind::Int, ArithmeticOp::Div) => jvm_int_div(frame),
+        (ValueKind::Long, ArithmeticOp::Div) => jvm_long_div(frame),
+        (ValueKind::Float, ArithmeticOp::Div) => jvm_float_div(frame),
+        (ValueKind::Double, ArithmeticOp::Div) => jvm_double_div(frame),
+        (ValueKind::Int, ArithmeticOp::Rem) => jvm_int_rem(frame),
+        (ValueKind::Long, ArithmeticOp::Rem) => jvm_long_rem(frame),
+        (ValueKind::Float, ArithmeticOp::Rem) => jvm_float_rem(frame),
+        (ValueKind::Double, ArithmeticOp::Rem) => jvm_double_rem(frame),
+        (ValueKind::Int, ArithmeticOp::Neg) => jvm_int_neg(frame),
+        (ValueKind::Long, ArithmeticOp::Neg) => jvm_long_neg(frame),
+        (ValueKind::Float, ArithmeticOp::Neg) => jvm_float_neg(frame),
+        (ValueKind::Double, ArithmeticOp::Neg) => jvm_double_neg(frame),
+        (ValueKind::Int, ArithmeticOp::Shl) => jvm_int_shl(frame),
+        (ValueKind::Long, ArithmeticOp::Shl) => jvm_long_shl(frame),
+        (ValueKind::Int, ArithmeticOp::Shr) => jvm_int_shr(frame),
+        (ValueKind::Long, ArithmeticOp::Shr) => jvm_long_shr(frame),
+        (ValueKind::Int, ArithmeticOp::UShr) => jvm_int_ushr(frame),
+        (ValueKind::Long, ArithmeticOp::UShr) => jvm_long_ushr(frame),
+        (ValueKind::Int, ArithmeticOp::And) => jvm_int_and(frame),
+        (ValueKind::Long, ArithmeticOp::And) => jvm_long_and(frame),
+        (ValueKind::Int, ArithmeticOp::Or) => jvm_int_or(frame),
+        (ValueKind::Long, ArithmeticOp::Or) => jvm_long_or(frame),
+        (ValueKind::Int, ArithmeticOp::Xor) => jvm_int_xor(frame),
+        (ValueKind::Long, ArithmeticOp::Xor) => jvm_long_xor(frame),
+        _ => panic!("Invalid arithmetic operation {:?} for {:?}!", op, kind)
+    }
+}
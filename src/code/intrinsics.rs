@@ -0,0 +1,366 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+use std::sync::atomic::{fence, Ordering};
+use crate::class_file::bytecode::ValueKind;
+use crate::objects::{HeapSpace, MemoryOrder, REFERENCE_SIZE};
+use crate::utils::descriptors::FieldDescriptor;
+use super::MethodResult;
+use super::natives::Arguments;
+
+const ATOMIC_INTEGER: &str = "java/util/concurrent/atomic/AtomicInteger";
+const ATOMIC_LONG: &str = "java/util/concurrent/atomic/AtomicLong";
+const ATOMIC_REFERENCE: &str = "java/util/concurrent/atomic/AtomicReference";
+const UNSAFE: &str = "jdk/internal/misc/Unsafe";
+const SUN_UNSAFE: &str = "sun/misc/Unsafe";
+
+// Where an intrinsic reads and writes. The atomic classes all keep what they hold in a volatile field
+// called value, and Unsafe takes an object and the byte offset of a field or element in it.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Target {
+    Value,
+    Address
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Operation {
+    Get,
+    Set,
+    CompareAndSet,
+    CompareAndExchange,
+    GetAndSet,
+    // Adds the argument, or the given delta if there is one, returning the new value if asked to
+    // rather than the old one.
+    Add(Option<i64>, bool),
+    Fence(Ordering)
+}
+
+// A method that the VM implements itself rather than running its bytecode, if it has any. These are
+// the atomic operations that java.util.concurrent is built on, which VarHandles end up calling too,
+// as the JDK's VarHandle implementations are all written in terms of Unsafe.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Intrinsic {
+    target: Target,
+    operation: Operation,
+    kind: ValueKind,
+    order: MemoryOrder
+}
+
+impl Intrinsic {
+    // Finds the intrinsic for the method with the given name and descriptor in the given class, if it
+    // has one.
+    pub fn find(class: &str, name: &str, descriptor: &str) -> Option<Intrinsic> {
+        let intrinsic = match class {
+            ATOMIC_INTEGER => Intrinsic::atomic(ValueKind::Int, name)?,
+            ATOMIC_LONG => Intrinsic::atomic(ValueKind::Long, name)?,
+            ATOMIC_REFERENCE => Intrinsic::atomic(ValueKind::Reference, name)?,
+            UNSAFE | SUN_UNSAFE => Intrinsic::unsafe_access(name)?,
+            _ => return None
+        };
+        Some(intrinsic).filter(|intrinsic| intrinsic.descriptor() == descriptor)
+    }
+
+    fn atomic(kind: ValueKind, name: &str) -> Option<Intrinsic> {
+        let (operation, order) = match name {
+            "get" => (Operation::Get, MemoryOrder::Volatile),
+            "getPlain" => (Operation::Get, MemoryOrder::Plain),
+            "getOpaque" => (Operation::Get, MemoryOrder::Opaque),
+            "getAcquire" => (Operation::Get, MemoryOrder::AcquireRelease),
+            "set" => (Operation::Set, MemoryOrder::Volatile),
+            "setPlain" => (Operation::Set, MemoryOrder::Plain),
+            "setOpaque" => (Operation::Set, MemoryOrder::Opaque),
+            "setRelease" | "lazySet" => (Operation::Set, MemoryOrder::AcquireRelease),
+            "compareAndSet" | "weakCompareAndSetVolatile" => (Operation::CompareAndSet, MemoryOrder::Volatile),
+            "weakCompareAndSet" | "weakCompareAndSetPlain" => (Operation::CompareAndSet, MemoryOrder::Plain),
+            "weakCompareAndSetAcquire" | "weakCompareAndSetRelease" => {
+                (Operation::CompareAndSet, MemoryOrder::AcquireRelease)
+            }
+            "compareAndExchange" => (Operation::CompareAndExchange, MemoryOrder::Volatile),
+            "compareAndExchangeAcquire" | "compareAndExchangeRelease" => {
+                (Operation::CompareAndExchange, MemoryOrder::AcquireRelease)
+            }
+            "getAndSet" => (Operation::GetAndSet, MemoryOrder::Volatile),
+            "getAndAdd" => (Operation::Add(None, false), MemoryOrder::Volatile),
+            "addAndGet" => (Operation::Add(None, true), MemoryOrder::Volatile),
+            "getAndIncrement" => (Operation::Add(Some(1), false), MemoryOrder::Volatile),
+            "incrementAndGet" => (Operation::Add(Some(1), true), MemoryOrder::Volatile),
+            "getAndDecrement" => (Operation::Add(Some(-1), false), MemoryOrder::Volatile),
+            "decrementAndGet" => (Operation::Add(Some(-1), true), MemoryOrder::Volatile),
+            _ => return None
+        };
+        if matches!(operation, Operation::Add(_, _)) && kind == ValueKind::Reference {
+            return None;
+        }
+        Some(Intrinsic { target: Target::Value, operation, kind, order })
+    }
+
+    // Unsafe's names are made up of the operation, the type and then the ordering, like
+    // compareAndSetIntAcquire, where accesses are plain and updates volatile if no ordering is given.
+    fn unsafe_access(name: &str) -> Option<Intrinsic> {
+        let fence = match name {
+            "fullFence" => Some(Ordering::SeqCst),
+            "loadFence" => Some(Ordering::Acquire),
+            "storeFence" => Some(Ordering::Release),
+            _ => None
+        };
+        if let Some(ordering) = fence {
+            let (kind, order) = (ValueKind::Int, MemoryOrder::Plain);
+            return Some(Intrinsic { target: Target::Address, operation: Operation::Fence(ordering), kind, order });
+        }
+
+        let (operation, rest) = UNSAFE_OPERATIONS.iter()
+            .find_map(|(prefix, operation)| name.strip_prefix(prefix).map(|rest| (*operation, rest)))?;
+        let (kind, rest) = UNSAFE_KINDS.iter()
+            .find_map(|(prefix, kind)| rest.strip_prefix(prefix).map(|rest| (*kind, rest)))?;
+        let order = match rest {
+            "" if name.starts_with("putOrdered") => MemoryOrder::AcquireRelease,
+            "" if matches!(operation, Operation::Get | Operation::Set) => MemoryOrder::Plain,
+            "" | "Volatile" => MemoryOrder::Volatile,
+            "Acquire" | "Release" => MemoryOrder::AcquireRelease,
+            "Opaque" => MemoryOrder::Opaque,
+            "Plain" => MemoryOrder::Plain,
+            _ => return None
+        };
+        if matches!(operation, Operation::Add(_, _)) && kind == ValueKind::Reference {
+            return None;
+        }
+        Some(Intrinsic { target: Target::Address, operation, kind, order })
+    }
+
+    // The descriptor that the method has to have for this to be its intrinsic.
+    fn descriptor(&self) -> String {
+        let value = match self.kind {
+            ValueKind::Int => "I",
+            ValueKind::Long => "J",
+            _ => "Ljava/lang/Object;"
+        };
+        let target = match self.target {
+            Target::Value => "",
+            Target::Address => "Ljava/lang/Object;J"
+        };
+        match self.operation {
+            Operation::Get => format!("({}){}", target, value),
+            Operation::Set => format!("({}{})V", target, value),
+            Operation::CompareAndSet => format!("({}{}{})Z", target, value, value),
+            Operation::CompareAndExchange => format!("({}{}{}){}", target, value, value, value),
+            Operation::GetAndSet | Operation::Add(None, _) => format!("({}{}){}", target, value, value),
+            Operation::Add(Some(_), _) => format!("({}){}", target, value),
+            Operation::Fence(_) => String::from("()V")
+        }
+    }
+
+    // The parameters are those that the method would have been given, starting with the receiver.
+    pub fn invoke<'h>(&self, heap: &'h HeapSpace, parameters: &[u32]) -> MethodResult<'h> {
+        if let Operation::Fence(ordering) = self.operation {
+            fence(ordering);
+            return MethodResult::Void;
+        }
+        let mut arguments = Arguments::new(parameters);
+        let receiver = arguments.reference();
+        let (object, position) = match self.target {
+            Target::Value => (receiver, self.value_field(heap, receiver)),
+            Target::Address => (arguments.reference(), arguments.long() as usize)
+        };
+        assert_ne!(object, 0, "Invalid atomic access! Object cannot be null!");
+        let size = match self.kind {
+            ValueKind::Long => 8,
+            ValueKind::Reference => REFERENCE_SIZE,
+            _ => 4
+        };
+
+        match self.operation {
+            Operation::Get => self.result(heap, heap.load(object, position, size, self.order)),
+            Operation::Set => {
+                let value = arguments.value(self.kind);
+                heap.store(object, position, size, value, self.order);
+                self.write_barrier(heap, object, value);
+                MethodResult::Void
+            }
+            Operation::CompareAndSet | Operation::CompareAndExchange => {
+                let expected = arguments.value(self.kind);
+                let value = arguments.value(self.kind);
+                let result = heap.compare_exchange(object, position, size, expected, value, self.order);
+                if result.is_ok() {
+                    self.write_barrier(heap, object, value);
+                }
+                match self.operation {
+                    Operation::CompareAndSet => MethodResult::Integer(result.is_ok() as i32),
+                    _ => self.result(heap, result.unwrap_or_else(|witness| witness))
+                }
+            }
+            Operation::GetAndSet => {
+                let value = arguments.value(self.kind);
+                let previous = heap.get_and_set(object, position, size, value, self.order);
+                self.write_barrier(heap, object, value);
+                self.result(heap, previous)
+            }
+            Operation::Add(delta, new) => {
+                let delta = delta.map_or_else(|| arguments.value(self.kind), |delta| delta as u64);
+                let previous = heap.get_and_add(object, position, size, delta, self.order);
+                self.result(heap, if new { previous.wrapping_add(delta) } else { previous })
+            }
+            Operation::Fence(_) => unreachable!()
+        }
+    }
+
+    fn value_field(&self, heap: &HeapSpace, receiver: usize) -> usize {
+        let descriptor = match self.kind {
+            ValueKind::Int => "I",
+            ValueKind::Long => "J",
+            _ => "Ljava/lang/Object;"
+        };
+        let descriptor = FieldDescriptor::parse(descriptor).unwrap();
+        heap.get_ref(receiver)
+            .expect("Invalid atomic access! Receiver cannot be null!")
+            .field_offset("value", &descriptor)
+            .unwrap_or_else(|| {
+                panic!("Invalid atomic class! Expected a field value {}!", descriptor.descriptor_string())
+            })
+    }
+
+    fn write_barrier(&self, heap: &HeapSpace, object: usize, value: u64) {
+        if self.kind == ValueKind::Reference {
+            heap.write_barrier(object, value as usize);
+        }
+    }
+
+    fn result<'h>(&self, heap: &'h HeapSpace, value: u64) -> MethodResult<'h> {
+        match self.kind {
+            ValueKind::Long => MethodResult::Long(value as i64),
+            ValueKind::Reference => MethodResult::Reference(heap.get_ref(value as usize)),
+            _ => MethodResult::Integer(value as u32 as i32)
+        }
+    }
+}
+
+// Longer prefixes come before the ones that they start with.
+const UNSAFE_OPERATIONS: [(&str, Operation); 9] = [
+    ("compareAndSet", Operation::CompareAndSet),
+    ("compareAndSwap", Operation::CompareAndSet),
+    ("weakCompareAndSet", Operation::CompareAndSet),
+    ("compareAndExchange", Operation::CompareAndExchange),
+    ("getAndAdd", Operation::Add(None, false)),
+    ("getAndSet", Operation::GetAndSet),
+    ("putOrdered", Operation::Set),
+    ("get", Operation::Get),
+    ("put", Operation::Set)
+];
+
+// Object is what sun.misc.Unsafe and older versions of jdk.internal.misc.Unsafe call references.
+const UNSAFE_KINDS: [(&str, ValueKind); 4] = [
+    ("Int", ValueKind::Int),
+    ("Long", ValueKind::Long),
+    ("Reference", ValueKind::Reference),
+    ("Object", ValueKind::Reference)
+];
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use crate::assembler::assemble;
+    use crate::class_file::ClassLoader;
+    use crate::code::{Interpreter, MethodResult};
+    use crate::objects::{HeapSpace, NoRoots};
+    use crate::types::Class;
+    use crate::utils::descriptors::FieldDescriptor;
+    use super::Intrinsic;
+
+    const SOURCE: &str = r#"
+.class java/util/concurrent/atomic/AtomicInteger
+.super java/lang/Object
+.field private volatile value I
+.method public final native get()I
+.end method
+.method public final native incrementAndGet()I
+.end method
+.method public final native compareAndSet(II)Z
+.end method
+"#;
+
+    const UNSAFE_SOURCE: &str = r#"
+.class jdk/internal/misc/Unsafe
+.super java/lang/Object
+.field private volatile value J
+.method public final native compareAndSetLong(Ljava/lang/Object;JJJ)Z
+.end method
+.method public final native getLongVolatile(Ljava/lang/Object;J)J
+.end method
+"#;
+
+    fn run<'h>(heap: &'h HeapSpace, class: &Arc<Class>, name: &str, parameters: &[u32]) -> MethodResult<'h> {
+        let method = class.methods().iter().find(|method| method.name() == name).unwrap();
+        Interpreter::execute(heap, class, method, parameters)
+    }
+
+    fn long_parameters(value: i64) -> [u32; 2] {
+        [(value >> 32) as u32, value as u32]
+    }
+
+    #[test]
+    fn finds_intrinsics_by_name_and_descriptor() {
+        let unsafe_class = "jdk/internal/misc/Unsafe";
+        assert!(Intrinsic::find(unsafe_class, "compareAndSetInt", "(Ljava/lang/Object;JII)Z").is_some());
+        let descriptor = "(Ljava/lang/Object;J)Ljava/lang/Object;";
+        assert!(Intrinsic::find(unsafe_class, "getReferenceAcquire", descriptor).is_some());
+        assert!(Intrinsic::find("sun/misc/Unsafe", "putOrderedInt", "(Ljava/lang/Object;JI)V").is_some());
+        assert!(Intrinsic::find(unsafe_class, "fullFence", "()V").is_some());
+        assert!(Intrinsic::find(unsafe_class, "compareAndSetInt", "(Ljava/lang/Object;JJJ)Z").is_none());
+        let descriptor = "(Ljava/lang/Object;JLjava/lang/Object;)Ljava/lang/Object;";
+        assert!(Intrinsic::find(unsafe_class, "getAndAddReference", descriptor).is_none());
+        assert!(Intrinsic::find("java/lang/Object", "get", "()I").is_none());
+    }
+
+    #[test]
+    fn increments_atomically_across_threads() {
+        let class = Arc::new(ClassLoader::new()).define_class(assemble(SOURCE).unwrap());
+        let heap = Arc::new(HeapSpace::new(1 << 10));
+        let counter = heap.allocate_ref(&class, &mut NoRoots).unwrap().offset() as u32;
+
+        let threads = (0..4).map(|_| {
+            let (heap, class) = (Arc::clone(&heap), Arc::clone(&class));
+            std::thread::spawn(move || {
+                for _ in 0..1000 {
+                    run(&heap, &class, "incrementAndGet", &[counter]);
+                }
+            })
+        }).collect::<Vec<_>>();
+        threads.into_iter().for_each(|thread| thread.join().unwrap());
+        assert!(matches!(run(&heap, &class, "get", &[counter]), MethodResult::Integer(4000)));
+        assert!(matches!(run(&heap, &class, "compareAndSet", &[counter, 3999, 0]), MethodResult::Integer(0)));
+        assert!(matches!(run(&heap, &class, "compareAndSet", &[counter, 4000, 1]), MethodResult::Integer(1)));
+        assert!(matches!(run(&heap, &class, "get", &[counter]), MethodResult::Integer(1)));
+    }
+
+    #[test]
+    fn compares_and_sets_whole_longs() {
+        let class = Arc::new(ClassLoader::new()).define_class(assemble(UNSAFE_SOURCE).unwrap());
+        let heap = HeapSpace::new(1 << 10);
+        let object = heap.allocate_ref(&class, &mut NoRoots).unwrap();
+        let offset = object.field_offset("value", &FieldDescriptor::parse("J").unwrap()).unwrap() as i64;
+        let object = object.offset() as u32;
+
+        let value = 0x1234_5678_9ABC_DEF0;
+        let parameters = [[0, object].as_ref(), &long_parameters(offset), &long_parameters(0), &long_parameters(value)]
+            .concat();
+        assert!(matches!(run(&heap, &class, "compareAndSetLong", &parameters), MethodResult::Integer(1)));
+        assert!(matches!(run(&heap, &class, "compareAndSetLong", &parameters), MethodResult::Integer(0)));
+        let parameters = [[0, object].as_ref(), &long_parameters(offset)].concat();
+        match run(&heap, &class, "getLongVolatile", &parameters) {
+            MethodResult::Long(read) => assert_eq!(read, value),
+            _ => panic!("Expected getLongVolatile to return a long!")
+        }
+    }
+}
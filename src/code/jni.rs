@@ -0,0 +1,699 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+
+mod functions;
+mod invocation;
+mod library;
+
+pub use library::{map_library_name, NativeLibraries, NativeLibrary, NativeLibraryError};
+
+use std::ffi::c_void;
+use std::ptr::null_mut;
+use std::sync::Arc;
+use jni::sys::{jobject, jvalue, JNIEnv, JNINativeInterface_};
+use crate::class_file::ClassLoader;
+use crate::objects::{HeapSpace, OutOfMemoryError, RootSource, Unblocked};
+use crate::types::{Class, Method};
+use crate::utils::descriptors::{FieldDescriptor, FieldType};
+use super::{Interpreter, MethodResult};
+use super::natives::{class_mirror, new_exception, parameter_slots};
+
+// What a JNIEnv points to. The function table has to come first, as it's all that natives know about,
+// and the rest is the state of the native call that the functions work on: the local references it
+// has made and the exception it's going to throw, if there is one.
+#[repr(C)]
+struct JniEnv<'a> {
+    functions: *const JNINativeInterface_,
+    heap: &'a HeapSpace,
+    loader: Arc<ClassLoader>,
+    locals: Vec<usize>,
+    frames: Vec<usize>,
+    exception: usize,
+    out_of_memory: Option<OutOfMemoryError>
+}
+
+// References are handed out as handles rather than as offsets, as objects move when the heap is
+// collected. Local handles index in to the call's local references and global ones in to the heap's
+// global handles, with the lowest bit telling them apart.
+const GLOBAL_HANDLE: usize = 1;
+
+impl<'a> JniEnv<'a> {
+    fn new(heap: &'a HeapSpace, loader: Arc<ClassLoader>) -> Self {
+        JniEnv {
+            functions: functions::table(),
+            heap,
+            loader,
+            locals: Vec::new(),
+            frames: Vec::new(),
+            exception: 0,
+            out_of_memory: None
+        }
+    }
+
+    // Natives run with the thread stopped, so the functions that they call have it running again, as
+    // they use the heap, until what's returned along with the env is dropped.
+    // Safety: the pointer has to be one that was given to a native by invoke, whose call hasn't returned.
+    unsafe fn enter<'e>(env: *mut JNIEnv) -> (&'e mut JniEnv<'e>, Unblocked) {
+        let env = &mut *(env as *mut JniEnv);
+        let unblocked = env.heap.unblocked();
+        (env, unblocked)
+    }
+
+    fn loader(&self) -> Arc<ClassLoader> {
+        Arc::clone(&self.loader)
+    }
+
+    fn new_local(&mut self, offset: usize) -> jobject {
+        if offset == 0 {
+            return null_mut();
+        }
+        self.locals.push(offset);
+        (self.locals.len() << 1) as jobject
+    }
+
+    fn new_global(&self, offset: usize) -> jobject {
+        if offset == 0 {
+            return null_mut();
+        }
+        (((self.heap.roots().new_global_handle(offset) + 1) << 1) | GLOBAL_HANDLE) as jobject
+    }
+
+    fn resolve(&self, handle: jobject) -> usize {
+        let handle = handle as usize;
+        match handle {
+            0 => 0,
+            _ if handle & GLOBAL_HANDLE != 0 => self.heap.roots().global_handle((handle >> 1) - 1),
+            _ => *self.locals.get((handle >> 1) - 1).unwrap_or_else(|| panic!("Invalid local reference {}!", handle))
+        }
+    }
+
+    fn delete_local(&mut self, handle: jobject) {
+        let handle = handle as usize;
+        if handle != 0 && handle & GLOBAL_HANDLE == 0 {
+            if let Some(local) = self.locals.get_mut((handle >> 1) - 1) {
+                *local = 0;
+            }
+        }
+    }
+
+    fn delete_global(&self, handle: jobject) {
+        let handle = handle as usize;
+        if handle & GLOBAL_HANDLE != 0 {
+            self.heap.roots().delete_global_handle((handle >> 1) - 1);
+        }
+    }
+
+    // The class that a java.lang.Class object handed to a native is the mirror of.
+    fn class_of(&self, mirror: jobject) -> Option<Arc<Class>> {
+        let name = self.heap.roots().mirrored_class(self.resolve(mirror))?;
+        self.loader().find_class(&name)
+    }
+
+    // Fails with null, leaving the error to be thrown when the native returns, if the heap runs out.
+    fn mirror(&mut self, class_name: &str) -> jobject {
+        let (heap, loader) = (self.heap, self.loader());
+        match class_mirror(heap, &loader, class_name, self) {
+            Ok(mirror) => self.new_local(mirror),
+            Err(error) => self.fail(error)
+        }
+    }
+
+    fn throw_new(&mut self, class_name: &str, message: Option<&[u16]>) {
+        let (heap, loader) = (self.heap, self.loader());
+        match new_exception(heap, &loader, class_name, message, self) {
+            Ok(exception) => self.exception = exception,
+            Err(error) => {
+                self.fail(error);
+            }
+        }
+    }
+
+    fn fail(&mut self, error: OutOfMemoryError) -> jobject {
+        self.out_of_memory = Some(error);
+        null_mut()
+    }
+
+    fn has_pending(&self) -> bool {
+        self.exception != 0 || self.out_of_memory.is_some()
+    }
+
+    // Runs a method on behalf of the native, with the parameters converted from the JNI values that it
+    // gave, and leaves anything it throws pending. The native's references are held as roots while the
+    // method runs.
+    fn call(
+        &mut self,
+        class: &Arc<Class>,
+        method: &Arc<Method>,
+        receiver: Option<usize>,
+        arguments: *const jvalue
+    ) -> Option<MethodResult<'a>> {
+        let mut parameters = Vec::new();
+        parameters.extend(receiver.map(|receiver| receiver as u32));
+        for (index, parameter) in method.descriptor().parameters().iter().enumerate() {
+            // Safety: natives give a value for each parameter of the method they're calling.
+            let argument = unsafe { *arguments.add(index) };
+            let slots = unsafe { to_slots(self, parameter, argument) };
+            parameters.extend(slots);
+        }
+        let heap = self.heap;
+        match heap.holding(self, || Interpreter::execute(heap, class, method, &parameters)) {
+            MethodResult::Exception(exception) => {
+                self.exception = exception.offset();
+                None
+            }
+            MethodResult::OutOfMemory(error) => {
+                self.fail(error);
+                None
+            }
+            result => Some(result)
+        }
+    }
+}
+
+impl RootSource for JniEnv<'_> {
+    fn visit_roots(&mut self, visitor: &mut dyn FnMut(&mut usize)) {
+        self.locals.iter_mut().filter(|local| **local != 0).for_each(&mut *visitor);
+        if self.exception != 0 {
+            visitor(&mut self.exception);
+        }
+    }
+}
+
+// Safety: the value has to be of the parameter's type, like JNI says it is.
+unsafe fn to_slots(env: &JniEnv, parameter: &FieldDescriptor, value: jvalue) -> Vec<u32> {
+    let bits = if parameter.is_reference() {
+        env.resolve(value.l) as u64
+    } else {
+        match parameter.base() {
+            FieldType::Long => value.j as u64,
+            FieldType::Double => value.d.to_bits(),
+            FieldType::Float => value.f.to_bits() as u64,
+            FieldType::Boolean => value.z as u64,
+            FieldType::Byte => value.b as i32 as u32 as u64,
+            FieldType::Char => value.c as u64,
+            FieldType::Short => value.s as i32 as u32 as u64,
+            _ => value.i as u32 as u64
+        }
+    };
+    parameter_slots(parameter, bits)
+}
+
+// Calls a native that a library provides, passing the JNIEnv and the class, or the receiver, before the
+// parameters like JNI does. Natives run with the thread stopped and the env as its roots, so that one
+// that blocks never holds up a safepoint, and the functions they call back in to run it again.
+pub(super) fn invoke<'h>(
+    heap: &'h HeapSpace,
+    class: &Arc<Class>,
+    method: &Method,
+    parameters: &[u32],
+    function: *mut c_void
+) -> MethodResult<'h> {
+    let mut env = JniEnv::new(heap, class.loader());
+    let mut arguments = Vec::new();
+    let mut slots = parameters.iter().copied();
+    let mut next = || slots.next().unwrap_or_else(|| panic!("Invalid native call! Too few parameters!"));
+    if method.is_static() {
+        let mirror = env.mirror(class.name());
+        arguments.push(Argument::Integer(mirror as u64));
+    } else {
+        let receiver = env.new_local(next() as usize);
+        arguments.push(Argument::Integer(receiver as u64));
+    }
+    for parameter in method.descriptor().parameters() {
+        let argument = match parameter.base() {
+            _ if parameter.is_reference() => Argument::Integer(env.new_local(next() as usize) as u64),
+            FieldType::Long => Argument::Integer(((next() as u64) << 32) | next() as u64),
+            FieldType::Double => Argument::Float(f64::from_bits(((next() as u64) << 32) | next() as u64)),
+            FieldType::Float => Argument::Float(f64::from_bits(next() as u64)),
+            _ => Argument::Integer(next() as i32 as i64 as u64)
+        };
+        arguments.push(argument);
+    }
+    if let Some(error) = env.out_of_memory.take() {
+        return MethodResult::OutOfMemory(error);
+    }
+
+    let return_type = method.descriptor().return_type();
+    let float_result = matches!(return_type.map(FieldDescriptor::base), Some(FieldType::Float | FieldType::Double))
+        && !return_type.map_or(false, FieldDescriptor::is_reference);
+    let env_pointer = &mut env as *mut JniEnv;
+    arguments.insert(0, Argument::Integer(env_pointer as u64));
+    // Safety: the function is the native for the method, so it takes what the method does after the
+    // JNIEnv and the class or receiver. The env outlives the call, and is only used through the pointer.
+    let result = heap.blocking(unsafe { &mut *env_pointer }, || {
+        invocation::with_current_env(env_pointer, || unsafe { call(function, &arguments, float_result) })
+    });
+
+    if let Some(error) = env.out_of_memory.take() {
+        return MethodResult::OutOfMemory(error);
+    }
+    if env.exception != 0 {
+        return MethodResult::Exception(heap.get_ref(env.exception).expect("Invalid native exception!"));
+    }
+    let return_type = match return_type {
+        Some(return_type) => return_type,
+        None => return MethodResult::Void
+    };
+    if return_type.is_reference() {
+        return MethodResult::Reference(heap.get_ref(env.resolve(result as jobject)));
+    }
+    match return_type.base() {
+        FieldType::Boolean => MethodResult::Integer((result as u8 != 0) as i32),
+        FieldType::Byte => MethodResult::Integer(result as i8 as i32),
+        FieldType::Char => MethodResult::Integer(result as u16 as i32),
+        FieldType::Short => MethodResult::Integer(result as i16 as i32),
+        FieldType::Long => MethodResult::Long(result as i64),
+        FieldType::Float => MethodResult::Float(f32::from_bits(result as u32)),
+        FieldType::Double => MethodResult::Double(f64::from_bits(result)),
+        _ => MethodResult::Integer(result as i32)
+    }
+}
+
+// How an argument is passed, which is in an integer register for pointers and integers and in a
+// floating point one for floats and doubles.
+enum Argument {
+    Integer(u64),
+    Float(f64)
+}
+
+#[cfg(target_arch = "x86_64")]
+const INTEGER_REGISTERS: usize = 6;
+#[cfg(not(target_arch = "x86_64"))]
+const INTEGER_REGISTERS: usize = 8;
+const FLOAT_REGISTERS: usize = 8;
+const STACK_SLOTS: usize = 16;
+
+macro_rules! slot {
+    ($index:literal, $type:ty) => { $type }
+}
+
+macro_rules! call_with {
+    (
+        $function:expr,
+        $float_result:expr,
+        $integers:ident [$($integer:literal)*],
+        $floats:ident [$($float:literal)*],
+        $stack:ident [$($slot:literal)*]
+    ) => {{
+        type Native<R> = unsafe extern "system" fn(
+            $(slot!($integer, u64)),*,
+            $(slot!($float, f64)),*,
+            $(slot!($slot, u64)),*
+        ) -> R;
+        if $float_result {
+            let native = std::mem::transmute::<*mut c_void, Native<f64>>($function);
+            native($($integers[$integer]),*, $($floats[$float]),*, $($stack[$slot]),*).to_bits()
+        } else {
+            let native = std::mem::transmute::<*mut c_void, Native<u64>>($function);
+            native($($integers[$integer]),*, $($floats[$float]),*, $($stack[$slot]),*)
+        }
+    }}
+}
+
+// Natives have signatures that are only known at run time, which there's no way to call without
+// generating code. Instead, they're called through one signature that fills every argument register of
+// both kinds and then a run of stack slots. Arguments are given registers of their kind in order and
+// spill on to the stack in order once their kind runs out, so laying them out the same way here puts
+// each one where the native looks for it. That holds for the System V calling convention on x86-64 and
+// the standard one on AArch64, which is what natives use on the platforms this supports. Floats are
+// passed in the low half of a register or slot, which is where they're read from, and the result is
+// read from whichever register the native returns its kind of value in.
+#[cfg(all(unix, any(target_arch = "x86_64", all(target_arch = "aarch64", not(target_vendor = "apple")))))]
+unsafe fn call(function: *mut c_void, arguments: &[Argument], float_result: bool) -> u64 {
+    let mut integers = [0u64; INTEGER_REGISTERS];
+    let mut floats = [0f64; FLOAT_REGISTERS];
+    let mut stack = [0u64; STACK_SLOTS];
+    let (mut integer_count, mut float_count, mut stack_count) = (0, 0, 0);
+    for argument in arguments {
+        let spilled = match *argument {
+            Argument::Integer(value) if integer_count < INTEGER_REGISTERS => {
+                integers[integer_count] = value;
+                integer_count += 1;
+                continue;
+            }
+            Argument::Float(value) if float_count < FLOAT_REGISTERS => {
+                floats[float_count] = value;
+                float_count += 1;
+                continue;
+            }
+            Argument::Integer(value) => value,
+            Argument::Float(value) => value.to_bits()
+        };
+        assert!(stack_count < STACK_SLOTS, "Invalid native call! Too many parameters!");
+        stack[stack_count] = spilled;
+        stack_count += 1;
+    }
+    #[cfg(target_arch = "x86_64")]
+    return call_with!(function, float_result, integers[0 1 2 3 4 5], floats[0 1 2 3 4 5 6 7],
+        stack[0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15]);
+    #[cfg(target_arch = "aarch64")]
+    return call_with!(function, float_result, integers[0 1 2 3 4 5 6 7], floats[0 1 2 3 4 5 6 7],
+        stack[0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15]);
+}
+
+#[cfg(not(all(unix, any(target_arch = "x86_64", all(target_arch = "aarch64", not(target_vendor = "apple"))))))]
+unsafe fn call(_: *mut c_void, _: &[Argument], _: bool) -> u64 {
+    panic!("Invalid native call! Natives in libraries can't be called on this platform!")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
+    use std::sync::Arc;
+    use crate::assembler::assemble;
+    use crate::code::MethodResult;
+    use crate::objects::{HeapSpace, NoRoots};
+    use crate::runtime::{new_string, string_value, Threads};
+    use crate::test_support::{call, jdk_loader};
+    use crate::utils::descriptors::{FieldDescriptor, FieldType};
+    use super::map_library_name;
+
+    // A library like the ones javah's headers are written for, which only goes through the function
+    // table, so that it can be built with nothing but rustc.
+    const LIBRARY_SOURCE: &str = r#"
+use std::ffi::{c_void, CStr};
+use std::os::raw::c_char;
+
+type Env = *mut *const *const c_void;
+
+const FIND_CLASS: usize = 6;
+const THROW_NEW: usize = 14;
+const GET_STATIC_METHOD_ID: usize = 113;
+const CALL_STATIC_VOID_METHOD_A: usize = 143;
+const NEW_STRING_UTF: usize = 167;
+const GET_STRING_UTF_CHARS: usize = 169;
+const RELEASE_STRING_UTF_CHARS: usize = 170;
+
+unsafe fn function<F: Copy>(env: Env, index: usize) -> F {
+    *((*env).add(index) as *const F)
+}
+
+#[no_mangle]
+pub extern "system" fn Java_Adder_add(_: Env, _: *mut c_void, first: i32, second: i32) -> i32 {
+    first.wrapping_add(second)
+}
+
+#[no_mangle]
+pub extern "system" fn Java_Adder_mix(_: Env, _: *mut c_void, first: i64, second: f64, third: f32) -> f64 {
+    first as f64 * second + third as f64
+}
+
+#[no_mangle]
+pub unsafe extern "system" fn Java_Adder_greet(env: Env, _: *mut c_void, name: *mut c_void) -> *mut c_void {
+    type GetChars = unsafe extern "system" fn(Env, *mut c_void, *mut u8) -> *const c_char;
+    type ReleaseChars = unsafe extern "system" fn(Env, *mut c_void, *const c_char);
+    type NewString = unsafe extern "system" fn(Env, *const c_char) -> *mut c_void;
+    let chars = function::<GetChars>(env, GET_STRING_UTF_CHARS)(env, name, std::ptr::null_mut());
+    let greeting = format!("Hello, {}!\0", CStr::from_ptr(chars).to_str().unwrap());
+    function::<ReleaseChars>(env, RELEASE_STRING_UTF_CHARS)(env, name, chars);
+    function::<NewString>(env, NEW_STRING_UTF)(env, greeting.as_ptr() as *const c_char)
+}
+
+#[no_mangle]
+pub unsafe extern "system" fn Java_Adder_fail(env: Env, _: *mut c_void) {
+    type FindClass = unsafe extern "system" fn(Env, *const c_char) -> *mut c_void;
+    type ThrowNew = unsafe extern "system" fn(Env, *mut c_void, *const c_char) -> i32;
+    let class = function::<FindClass>(env, FIND_CLASS)(env, "java/lang/IllegalStateException\0".as_ptr() as _);
+    function::<ThrowNew>(env, THROW_NEW)(env, class, "Too many\0".as_ptr() as _);
+}
+
+#[no_mangle]
+pub unsafe extern "system" fn Java_Adder_keep(env: Env, class: *mut c_void, object: *mut c_void) -> *mut c_void {
+    type GetMethodId = unsafe extern "system" fn(Env, *mut c_void, *const c_char, *const c_char) -> *mut c_void;
+    type CallVoid = unsafe extern "system" fn(Env, *mut c_void, *mut c_void, *const c_void);
+    let (name, signature) = ("churn\0".as_ptr() as *const c_char, "()V\0".as_ptr() as *const c_char);
+    let churn = function::<GetMethodId>(env, GET_STATIC_METHOD_ID)(env, class, name, signature);
+    function::<CallVoid>(env, CALL_STATIC_VOID_METHOD_A)(env, class, churn, std::ptr::null());
+    object
+}
+"#;
+
+    // A library that registers its natives from JNI_OnLoad rather than exporting them, and that gets
+    // back to the VM through the JavaVM, from the thread that called it and from one of its own.
+    const ON_LOAD_LIBRARY_SOURCE: &str = r#"
+use std::ffi::c_void;
+use std::os::raw::c_char;
+use std::ptr::null_mut;
+
+type Env = *mut *const *const c_void;
+type Vm = *mut *const *const c_void;
+
+const FIND_CLASS: usize = 6;
+const REGISTER_NATIVES: usize = 215;
+const GET_JAVA_VM: usize = 219;
+const ATTACH_CURRENT_THREAD: usize = 4;
+const DETACH_CURRENT_THREAD: usize = 5;
+const GET_ENV: usize = 6;
+const JNI_VERSION_1_8: i32 = 0x00010008;
+
+type FindClass = unsafe extern "system" fn(Env, *const c_char) -> *mut c_void;
+type GetJavaVm = unsafe extern "system" fn(Env, *mut Vm) -> i32;
+type GetEnv = unsafe extern "system" fn(Vm, *mut Env, i32) -> i32;
+type AttachCurrentThread = unsafe extern "system" fn(Vm, *mut Env, *mut c_void) -> i32;
+type DetachCurrentThread = unsafe extern "system" fn(Vm) -> i32;
+
+#[repr(C)]
+struct NativeMethod {
+    name: *const c_char,
+    signature: *const c_char,
+    function: *mut c_void
+}
+
+unsafe fn function<F: Copy>(table: *mut *const *const c_void, index: usize) -> F {
+    *((*table).add(index) as *const F)
+}
+
+unsafe fn find_greeter(env: Env) -> *mut c_void {
+    function::<FindClass>(env, FIND_CLASS)(env, "Greeter\0".as_ptr() as _)
+}
+
+unsafe extern "system" fn twice(_: Env, _: *mut c_void, value: i32) -> i32 {
+    value * 2
+}
+
+unsafe extern "system" fn same_env(env: Env, _: *mut c_void) -> u8 {
+    let mut vm = null_mut();
+    function::<GetJavaVm>(env, GET_JAVA_VM)(env, &mut vm);
+    let mut current = null_mut();
+    let result = function::<GetEnv>(vm, GET_ENV)(vm, &mut current, JNI_VERSION_1_8);
+    (result == 0 && current == env) as u8
+}
+
+// Counts the steps of attaching a thread, finding a class from it and detaching it again that work.
+unsafe extern "system" fn from_thread(env: Env, _: *mut c_void) -> i32 {
+    let mut vm = null_mut();
+    function::<GetJavaVm>(env, GET_JAVA_VM)(env, &mut vm);
+    let vm = vm as usize;
+    std::thread::spawn(move || unsafe {
+        let vm = vm as Vm;
+        let mut env = null_mut();
+        let mut steps = 0;
+        steps += (function::<AttachCurrentThread>(vm, ATTACH_CURRENT_THREAD)(vm, &mut env, null_mut()) == 0) as i32;
+        steps += (!find_greeter(env).is_null()) as i32;
+        steps += (function::<DetachCurrentThread>(vm, DETACH_CURRENT_THREAD)(vm) == 0) as i32;
+        steps += (function::<GetEnv>(vm, GET_ENV)(vm, &mut env, JNI_VERSION_1_8) == -2) as i32;
+        steps
+    }).join().unwrap()
+}
+
+#[no_mangle]
+pub unsafe extern "system" fn JNI_OnLoad(vm: Vm, _: *mut c_void) -> i32 {
+    type RegisterNatives = unsafe extern "system" fn(Env, *mut c_void, *const NativeMethod, i32) -> i32;
+    let mut env = null_mut();
+    if function::<GetEnv>(vm, GET_ENV)(vm, &mut env, JNI_VERSION_1_8) != 0 {
+        return -1;
+    }
+    let methods = [
+        NativeMethod { name: "twice\0".as_ptr() as _, signature: "(I)I\0".as_ptr() as _, function: twice as _ },
+        NativeMethod { name: "sameEnv\0".as_ptr() as _, signature: "()Z\0".as_ptr() as _, function: same_env as _ },
+        NativeMethod {
+            name: "fromThread\0".as_ptr() as _,
+            signature: "()I\0".as_ptr() as _,
+            function: from_thread as _
+        }
+    ];
+    function::<RegisterNatives>(env, REGISTER_NATIVES)(env, find_greeter(env), methods.as_ptr(), 3);
+    JNI_VERSION_1_8
+}
+"#;
+
+    // A library that needs a version of JNI from the future.
+    const NEWER_LIBRARY_SOURCE: &str = r#"
+#[no_mangle]
+pub extern "system" fn JNI_OnLoad(_: *mut std::ffi::c_void, _: *mut std::ffi::c_void) -> i32 {
+    0x00630000
+}
+"#;
+
+    const GREETER_SOURCE: &str = r#"
+.class Greeter
+.super java/lang/Object
+.method public static native twice(I)I
+.end method
+.method public static native sameEnv()Z
+.end method
+.method public static native fromThread()I
+.end method
+"#;
+
+    const ADDER_SOURCE: &str = r#"
+.class Adder
+.super java/lang/Object
+.field value I
+.method public static native add(II)I
+.end method
+.method public static native mix(JDF)D
+.end method
+.method public static native greet(Ljava/lang/String;)Ljava/lang/String;
+.end method
+.method public static native fail()V
+.end method
+.method public static native keep(LAdder;)LAdder;
+.end method
+.method public static churn()V
+.limit stack 1
+.limit locals 1
+    sipush 1000
+    istore_0
+Loop:
+    iload_0
+    ifle End
+    new Adder
+    pop
+    iinc 0 -1
+    goto Loop
+End:
+    return
+.end method
+"#;
+
+    const SYSTEM_SOURCE: &str = r#"
+.class java/lang/System
+.super java/lang/Object
+.method public static native loadLibrary(Ljava/lang/String;)V
+.end method
+"#;
+
+    fn build_library(directory: &Path, name: &str, contents: &str) -> PathBuf {
+        std::fs::create_dir_all(directory).unwrap();
+        let source = directory.join(format!("{}.rs", name));
+        std::fs::write(&source, contents).unwrap();
+        let library = directory.join(map_library_name(name));
+        let status = Command::new(std::env::var("RUSTC").unwrap_or_else(|_| String::from("rustc")))
+            .args(["--crate-type", "cdylib", "--edition", "2018", "-o"])
+            .arg(&library)
+            .arg(&source)
+            .status()
+            .unwrap();
+        assert!(status.success(), "Expected the test library to build!");
+        library
+    }
+
+    #[test]
+    fn calls_natives_in_loaded_libraries_through_the_function_table() {
+        let directory = std::env::temp_dir().join(format!("astatine-jni-{}", std::process::id()));
+        build_library(&directory, "adder", LIBRARY_SOURCE);
+        let loader = jdk_loader();
+        let adder = loader.define_class(assemble(ADDER_SOURCE).unwrap());
+        let system = loader.define_class(assemble(SYSTEM_SOURCE).unwrap());
+        let heap = HeapSpace::new(1 << 12);
+
+        let chars = "adder".encode_utf16().collect::<Vec<_>>();
+        let name = new_string(&heap, &loader, &chars, &mut NoRoots).unwrap();
+        let error = match call(&heap, &system, "loadLibrary", &[name as u32]) {
+            MethodResult::Exception(error) => error,
+            _ => panic!("Expected loadLibrary to throw without a search path!")
+        };
+        assert_eq!(error.class().name(), "java/lang/UnsatisfiedLinkError");
+        loader.libraries().add_search_path(&directory);
+        let name = new_string(&heap, &loader, &chars, &mut NoRoots).unwrap();
+        assert!(matches!(call(&heap, &system, "loadLibrary", &[name as u32]), MethodResult::Void));
+
+        assert!(matches!(call(&heap, &adder, "add", &[40, 2]), MethodResult::Integer(42)));
+        let (first, second) = ((-3i64) as u64, 1.5f64.to_bits());
+        let parameters = [(first >> 32) as u32, first as u32, (second >> 32) as u32, second as u32, 0.25f32.to_bits()];
+        assert!(matches!(call(&heap, &adder, "mix", &parameters), MethodResult::Double(value) if value == -4.25));
+
+        let chars = "astatine".encode_utf16().collect::<Vec<_>>();
+        let name = new_string(&heap, &loader, &chars, &mut NoRoots).unwrap();
+        let greeting = match call(&heap, &adder, "greet", &[name as u32]) {
+            MethodResult::Reference(greeting) => greeting.expect("Expected a greeting!").offset(),
+            _ => panic!("Expected greet to return a string!")
+        };
+        assert_eq!(string_value(&heap, greeting), "Hello, astatine!");
+
+        let error = match call(&heap, &adder, "fail", &[]) {
+            MethodResult::Exception(error) => error,
+            _ => panic!("Expected fail to throw!")
+        };
+        assert_eq!(error.class().name(), "java/lang/IllegalStateException");
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn keeps_local_references_of_natives_that_call_back_in_to_java() {
+        let directory = std::env::temp_dir().join(format!("astatine-jni-roots-{}", std::process::id()));
+        build_library(&directory, "adder", LIBRARY_SOURCE);
+        let loader = jdk_loader();
+        let adder = loader.define_class(assemble(ADDER_SOURCE).unwrap());
+        loader.libraries().add_search_path(&directory);
+        let heap = Arc::new(HeapSpace::new(1 << 12));
+        loader.libraries().load_library(&heap, &loader, "adder").unwrap();
+        let threads = Threads::new(&heap);
+        threads.attach_current_thread("main", false, None);
+
+        let object = heap.allocate_ref(&adder, &mut NoRoots).unwrap();
+        let value = object.field_offset("value", &FieldDescriptor::new(FieldType::Int, 0)).unwrap();
+        object.set_int(value, 42);
+        // Churning moves the object, which the native's local reference to it has to follow.
+        match call(&heap, &adder, "keep", &[object.offset() as u32]) {
+            MethodResult::Reference(kept) => {
+                let kept = kept.expect("Expected keep to return the object!");
+                assert_ne!(kept.offset(), object.offset());
+                assert_eq!(kept.get_int(value), 42);
+            }
+            _ => panic!("Expected keep to return a reference!")
+        }
+        threads.detach_current_thread();
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn registers_natives_from_on_load_and_attaches_threads_through_the_java_vm() {
+        let directory = std::env::temp_dir().join(format!("astatine-jni-on-load-{}", std::process::id()));
+        build_library(&directory, "greeter", ON_LOAD_LIBRARY_SOURCE);
+        build_library(&directory, "newer", NEWER_LIBRARY_SOURCE);
+        let loader = jdk_loader();
+        let greeter = loader.define_class(assemble(GREETER_SOURCE).unwrap());
+        loader.libraries().add_search_path(&directory);
+        let heap = Arc::new(HeapSpace::new(1 << 12));
+        let threads = Threads::new(&heap);
+        threads.attach_current_thread("main", false, None);
+
+        loader.libraries().load_library(&heap, &loader, "greeter").unwrap();
+        assert!(matches!(call(&heap, &greeter, "twice", &[21]), MethodResult::Integer(42)));
+        assert!(matches!(call(&heap, &greeter, "sameEnv", &[]), MethodResult::Integer(1)));
+        assert!(matches!(call(&heap, &greeter, "fromThread", &[]), MethodResult::Integer(4)));
+        assert_eq!(threads.all().len(), 1);
+
+        let error = loader.libraries().load_library(&heap, &loader, "newer").unwrap_err();
+        assert!(error.to_string().starts_with("unsupported JNI version 0x630000 required by"));
+        threads.detach_current_thread();
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,963 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+
+use std::ffi::{c_void, CStr};
+use std::mem::size_of;
+use std::os::raw::c_char;
+use std::ptr::{self, null_mut};
+use std::sync::{Arc, Once};
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::time::Duration;
+use bytes::Bytes;
+use jni::sys::*;
+use paste::paste;
+use crate::code::MethodResult;
+use crate::code::natives;
+use crate::constants::*;
+use crate::objects::{FieldLayout, HeapSpace, MemoryOrder, Reference, OBJECT_HEADER_SIZE, REFERENCE_SIZE};
+use crate::runtime::{
+    from_modified_utf8, new_string, print_uncaught_exception, string_chars, to_modified_utf8, Monitor, Threads
+};
+use crate::types::{Class, Method};
+use crate::utils::descriptors::{FieldDescriptor, MethodDescriptor};
+use super::{JniEnv, GLOBAL_HANDLE};
+use super::invocation::get_java_vm;
+
+const NO_CLASS_DEF_FOUND_ERROR: &str = "java/lang/NoClassDefFoundError";
+const NO_SUCH_METHOD_ERROR: &str = "java/lang/NoSuchMethodError";
+const NO_SUCH_FIELD_ERROR: &str = "java/lang/NoSuchFieldError";
+const NEGATIVE_ARRAY_SIZE_EXCEPTION: &str = "java/lang/NegativeArraySizeException";
+const ARRAY_INDEX_OUT_OF_BOUNDS_EXCEPTION: &str = "java/lang/ArrayIndexOutOfBoundsException";
+const STRING_INDEX_OUT_OF_BOUNDS_EXCEPTION: &str = "java/lang/StringIndexOutOfBoundsException";
+
+// How long a native blocked on a monitor waits before trying to enter it again, like monitorenter.
+const MONITOR_RETRY_INTERVAL: Duration = Duration::from_millis(1);
+
+static TABLE: AtomicPtr<JNINativeInterface_> = AtomicPtr::new(null_mut());
+static TABLE_BUILT: Once = Once::new();
+
+// The function table that every JNIEnv points to, which is built the first time a native is called.
+pub(super) fn table() -> *const JNINativeInterface_ {
+    TABLE_BUILT.call_once(|| TABLE.store(Box::into_raw(Box::new(build())), Ordering::Release));
+    TABLE.load(Ordering::Acquire)
+}
+
+macro_rules! set_typed_functions {
+    (
+        $table:ident,
+        values: [$($value:ident: $value_type:ty),*],
+        primitives: [$($primitive:ident: $primitive_type:ty),*]
+    ) => {
+        paste! {
+            $(
+                $table.[<Call $value MethodA>] = Some(call_method::<$value_type>);
+                $table.[<CallNonvirtual $value MethodA>] = Some(call_nonvirtual_method::<$value_type>);
+                $table.[<CallStatic $value MethodA>] = Some(call_static_method::<$value_type>);
+            )*
+            $(
+                $table.[<Get $primitive Field>] = Some(get_field::<$primitive_type>);
+                $table.[<Set $primitive Field>] = Some(set_field::<$primitive_type>);
+                $table.[<New $primitive Array>] = Some(new_array::<$primitive_type>);
+                $table.[<Get $primitive ArrayElements>] = Some(get_array_elements::<$primitive_type>);
+                $table.[<Release $primitive ArrayElements>] = Some(release_array_elements::<$primitive_type>);
+                $table.[<Get $primitive ArrayRegion>] = Some(get_array_region::<$primitive_type>);
+                $table.[<Set $primitive ArrayRegion>] = Some(set_array_region::<$primitive_type>);
+            )*
+        }
+    }
+}
+
+// Every function starts off calling unimplemented, which aborts rather than leaving natives to call
+// through null. That includes the variadic functions and the ones that take a va_list, as Rust can't
+// define either, so natives have to use the ones that take arrays of jvalues, like the jni crate does.
+// Static fields aren't implemented either, as nothing stores them yet.
+fn build() -> JNINativeInterface_ {
+    const SLOTS: usize = size_of::<JNINativeInterface_>() / size_of::<*mut c_void>();
+    const RESERVED_SLOTS: usize = 4;
+    let mut slots = [unimplemented as unsafe extern "system" fn(*mut JNIEnv) as *mut c_void; SLOTS];
+    slots[..RESERVED_SLOTS].iter_mut().for_each(|slot| *slot = null_mut());
+    // Safety: the table is nothing but pointers, every one of which is either null or a function.
+    let mut table = unsafe { std::mem::transmute::<[*mut c_void; SLOTS], JNINativeInterface_>(slots) };
+
+    table.GetVersion = Some(get_version);
+    table.DefineClass = Some(define_class);
+    table.FindClass = Some(find_class);
+    table.GetSuperclass = Some(get_superclass);
+    table.IsAssignableFrom = Some(is_assignable_from);
+    table.Throw = Some(throw);
+    table.ThrowNew = Some(throw_new);
+    table.ExceptionOccurred = Some(exception_occurred);
+    table.ExceptionDescribe = Some(exception_describe);
+    table.ExceptionClear = Some(exception_clear);
+    table.ExceptionCheck = Some(exception_check);
+    table.FatalError = Some(fatal_error);
+    table.PushLocalFrame = Some(push_local_frame);
+    table.PopLocalFrame = Some(pop_local_frame);
+    table.NewGlobalRef = Some(new_global_ref);
+    table.DeleteGlobalRef = Some(delete_global_ref);
+    table.DeleteLocalRef = Some(delete_local_ref);
+    table.IsSameObject = Some(is_same_object);
+    table.NewLocalRef = Some(new_local_ref);
+    table.EnsureLocalCapacity = Some(ensure_local_capacity);
+    table.GetObjectRefType = Some(get_object_ref_type);
+    table.AllocObject = Some(alloc_object);
+    table.NewObjectA = Some(new_object);
+    table.GetObjectClass = Some(get_object_class);
+    table.IsInstanceOf = Some(is_instance_of);
+    table.GetMethodID = Some(get_method_id);
+    table.GetStaticMethodID = Some(get_static_method_id);
+    table.GetFieldID = Some(get_field_id);
+    table.GetObjectField = Some(get_field::<jobject>);
+    table.SetObjectField = Some(set_field::<jobject>);
+    table.NewString = Some(new_java_string);
+    table.GetStringLength = Some(get_string_length);
+    table.GetStringChars = Some(get_string_chars);
+    table.ReleaseStringChars = Some(release_string_chars);
+    table.GetStringRegion = Some(get_string_region);
+    table.NewStringUTF = Some(new_string_utf);
+    table.GetStringUTFLength = Some(get_string_utf_length);
+    table.GetStringUTFChars = Some(get_string_utf_chars);
+    table.ReleaseStringUTFChars = Some(release_string_utf_chars);
+    table.GetStringUTFRegion = Some(get_string_utf_region);
+    table.GetArrayLength = Some(get_array_length);
+    table.NewObjectArray = Some(new_object_array);
+    table.GetObjectArrayElement = Some(get_object_array_element);
+    table.SetObjectArrayElement = Some(set_object_array_element);
+    table.RegisterNatives = Some(register_natives);
+    table.UnregisterNatives = Some(unregister_natives);
+    table.MonitorEnter = Some(monitor_enter);
+    table.MonitorExit = Some(monitor_exit);
+    table.GetJavaVM = Some(get_java_vm);
+    set_typed_functions!(
+        table,
+        values: [Object: jobject, Boolean: jboolean, Byte: jbyte, Char: jchar, Short: jshort, Int: jint, Long: jlong,
+            Float: jfloat, Double: jdouble, Void: ()],
+        primitives: [Boolean: jboolean, Byte: jbyte, Char: jchar, Short: jshort, Int: jint, Long: jlong,
+            Float: jfloat, Double: jdouble]
+    );
+    table
+}
+
+unsafe extern "system" fn unimplemented(_: *mut JNIEnv) {
+    fatal("Called a JNI function that isn't implemented");
+}
+
+fn fatal(message: &str) -> ! {
+    eprintln!("FATAL ERROR in native method: {}", message);
+    std::process::abort()
+}
+
+// The values that JNI passes around, and how they're loaded from and stored in to the heap and taken
+// from what a method returns. References are handed to natives as local references.
+trait JniValue: Copy {
+    const SIZE: usize;
+    const IS_REFERENCE: bool = false;
+
+    fn zero() -> Self;
+
+    fn from_raw(env: &mut JniEnv, raw: u64) -> Self;
+
+    fn into_raw(self, env: &JniEnv) -> u64;
+
+    fn from_result(env: &mut JniEnv, result: MethodResult) -> Self;
+}
+
+// The values that primitive arrays hold, with the JVM_T_* type of the arrays that hold them.
+trait JniPrimitive: JniValue {
+    const ARRAY_TYPE: u8;
+}
+
+macro_rules! jni_primitives {
+    ($($type:ty: $size:literal, $array_type:ident, |$raw:ident| $from_raw:expr, |$value:ident| $into_raw:expr,
+        $result:ident($result_value:ident) => $from_result:expr);*) => {
+        $(
+            impl JniValue for $type {
+                const SIZE: usize = $size;
+
+                fn zero() -> Self {
+                    Self::default()
+                }
+
+                fn from_raw(_: &mut JniEnv, $raw: u64) -> Self {
+                    $from_raw
+                }
+
+                fn into_raw(self, _: &JniEnv) -> u64 {
+                    let $value = self;
+                    $into_raw
+                }
+
+                fn from_result(_: &mut JniEnv, result: MethodResult) -> Self {
+                    match result {
+                        MethodResult::$result($result_value) => $from_result,
+                        _ => panic!("Invalid JNI call! The method returned a different type to the one called for!")
+                    }
+                }
+            }
+
+            impl JniPrimitive for $type {
+                const ARRAY_TYPE: u8 = $array_type;
+            }
+        )*
+    }
+}
+
+jni_primitives!(
+    jboolean: 1, JVM_T_BOOLEAN, |raw| raw as jboolean, |value| value as u64, Integer(value) => value as jboolean;
+    jbyte: 1, JVM_T_BYTE, |raw| raw as jbyte, |value| value as u8 as u64, Integer(value) => value as jbyte;
+    jchar: 2, JVM_T_CHAR, |raw| raw as jchar, |value| value as u64, Integer(value) => value as jchar;
+    jshort: 2, JVM_T_SHORT, |raw| raw as jshort, |value| value as u16 as u64, Integer(value) => value as jshort;
+    jint: 4, JVM_T_INT, |raw| raw as jint, |value| value as u32 as u64, Integer(value) => value;
+    jlong: 8, JVM_T_LONG, |raw| raw as jlong, |value| value as u64, Long(value) => value;
+    jfloat: 4, JVM_T_FLOAT, |raw| f32::from_bits(raw as u32), |value| value.to_bits() as u64, Float(value) => value;
+    jdouble: 8, JVM_T_DOUBLE, |raw| f64::from_bits(raw), |value| value.to_bits(), Double(value) => value
+);
+
+impl JniValue for jobject {
+    const SIZE: usize = REFERENCE_SIZE;
+    const IS_REFERENCE: bool = true;
+
+    fn zero() -> Self {
+        null_mut()
+    }
+
+    fn from_raw(env: &mut JniEnv, raw: u64) -> Self {
+        env.new_local(raw as usize)
+    }
+
+    fn into_raw(self, env: &JniEnv) -> u64 {
+        env.resolve(self) as u64
+    }
+
+    fn from_result(env: &mut JniEnv, result: MethodResult) -> Self {
+        match result {
+            MethodResult::Reference(Reference::Value(object)) => env.new_local(object.offset()),
+            MethodResult::Reference(Reference::Null) => null_mut(),
+            _ => panic!("Invalid JNI call! The method returned a different type to the one called for!")
+        }
+    }
+}
+
+impl JniValue for () {
+    const SIZE: usize = 0;
+
+    fn zero() -> Self {}
+
+    fn from_raw(_: &mut JniEnv, _: u64) -> Self {}
+
+    fn into_raw(self, _: &JniEnv) -> u64 {
+        0
+    }
+
+    fn from_result(_: &mut JniEnv, _: MethodResult) -> Self {}
+}
+
+// Safety: the string has to be null terminated, like every string that JNI is given.
+unsafe fn read_utf(value: *const c_char) -> Vec<u16> {
+    from_modified_utf8(CStr::from_ptr(value).to_bytes())
+}
+
+unsafe fn read_string(value: *const c_char) -> String {
+    String::from_utf16_lossy(&read_utf(value))
+}
+
+fn utf16(value: &str) -> Vec<u16> {
+    value.encode_utf16().collect()
+}
+
+unsafe extern "system" fn get_version(_: *mut JNIEnv) -> jint {
+    JNI_VERSION_1_8
+}
+
+unsafe extern "system" fn define_class(
+    env: *mut JNIEnv,
+    _: *const c_char,
+    _: jobject,
+    buffer: *const jbyte,
+    length: jsize
+) -> jclass {
+    let (env, _unblocked) = JniEnv::enter(env);
+    let bytes = Bytes::copy_from_slice(std::slice::from_raw_parts(buffer as *const u8, length as usize));
+    let class = env.loader().define_class(bytes);
+    env.mirror(class.name())
+}
+
+unsafe extern "system" fn find_class(env: *mut JNIEnv, name: *const c_char) -> jclass {
+    let (env, _unblocked) = JniEnv::enter(env);
+    let name = read_string(name);
+    match env.loader().find_class(&name) {
+        Some(class) => env.mirror(class.name()),
+        None => {
+            env.throw_new(NO_CLASS_DEF_FOUND_ERROR, Some(&utf16(&name)));
+            null_mut()
+        }
+    }
+}
+
+unsafe extern "system" fn get_superclass(env: *mut JNIEnv, class: jclass) -> jclass {
+    let (env, _unblocked) = JniEnv::enter(env);
+    let class = class_of(env, class);
+    match class.super_class_name() {
+        Some(super_name) if !class.is_interface() => env.mirror(&super_name),
+        _ => null_mut()
+    }
+}
+
+unsafe extern "system" fn is_assignable_from(env: *mut JNIEnv, class: jclass, target: jclass) -> jboolean {
+    let (env, _unblocked) = JniEnv::enter(env);
+    env.loader().is_assignable(class_of(env, class).name(), class_of(env, target).name()) as jboolean
+}
+
+unsafe extern "system" fn throw(env: *mut JNIEnv, throwable: jthrowable) -> jint {
+    let (env, _unblocked) = JniEnv::enter(env);
+    env.exception = env.resolve(throwable);
+    JNI_OK
+}
+
+unsafe extern "system" fn throw_new(env: *mut JNIEnv, class: jclass, message: *const c_char) -> jint {
+    let (env, _unblocked) = JniEnv::enter(env);
+    let class = class_of(env, class);
+    let message = if message.is_null() { None } else { Some(read_utf(message)) };
+    env.throw_new(class.name(), message.as_deref());
+    JNI_OK
+}
+
+unsafe extern "system" fn exception_occurred(env: *mut JNIEnv) -> jthrowable {
+    let (env, _unblocked) = JniEnv::enter(env);
+    env.new_local(env.exception)
+}
+
+unsafe extern "system" fn exception_describe(env: *mut JNIEnv) {
+    let (env, _unblocked) = JniEnv::enter(env);
+    if env.exception != 0 {
+        let name = Threads::current().map_or_else(|| String::from("main"), |thread| String::from(thread.name()));
+        print_uncaught_exception(env.heap, &name, env.exception);
+        env.exception = 0;
+    }
+}
+
+unsafe extern "system" fn exception_clear(env: *mut JNIEnv) {
+    let (env, _unblocked) = JniEnv::enter(env);
+    env.exception = 0;
+}
+
+unsafe extern "system" fn exception_check(env: *mut JNIEnv) -> jboolean {
+    let (env, _unblocked) = JniEnv::enter(env);
+    env.has_pending() as jboolean
+}
+
+unsafe extern "system" fn fatal_error(_: *mut JNIEnv, message: *const c_char) -> ! {
+    fatal(&read_string(message))
+}
+
+unsafe extern "system" fn push_local_frame(env: *mut JNIEnv, _: jint) -> jint {
+    let (env, _unblocked) = JniEnv::enter(env);
+    env.frames.push(env.locals.len());
+    JNI_OK
+}
+
+unsafe extern "system" fn pop_local_frame(env: *mut JNIEnv, result: jobject) -> jobject {
+    let (env, _unblocked) = JniEnv::enter(env);
+    let result = env.resolve(result);
+    if let Some(length) = env.frames.pop() {
+        env.locals.truncate(length);
+    }
+    env.new_local(result)
+}
+
+unsafe extern "system" fn new_global_ref(env: *mut JNIEnv, object: jobject) -> jobject {
+    let (env, _unblocked) = JniEnv::enter(env);
+    env.new_global(env.resolve(object))
+}
+
+unsafe extern "system" fn delete_global_ref(env: *mut JNIEnv, object: jobject) {
+    let (env, _unblocked) = JniEnv::enter(env);
+    env.delete_global(object);
+}
+
+unsafe extern "system" fn delete_local_ref(env: *mut JNIEnv, object: jobject) {
+    let (env, _unblocked) = JniEnv::enter(env);
+    env.delete_local(object);
+}
+
+unsafe extern "system" fn is_same_object(env: *mut JNIEnv, first: jobject, second: jobject) -> jboolean {
+    let (env, _unblocked) = JniEnv::enter(env);
+    (env.resolve(first) == env.resolve(second)) as jboolean
+}
+
+unsafe extern "system" fn new_local_ref(env: *mut JNIEnv, object: jobject) -> jobject {
+    let (env, _unblocked) = JniEnv::enter(env);
+    env.new_local(env.resolve(object))
+}
+
+// Local references are only limited by memory, so there's always room for more.
+unsafe extern "system" fn ensure_local_capacity(_: *mut JNIEnv, _: jint) -> jint {
+    JNI_OK
+}
+
+unsafe extern "system" fn get_object_ref_type(env: *mut JNIEnv, object: jobject) -> jobjectRefType {
+    let (env, _unblocked) = JniEnv::enter(env);
+    match object as usize {
+        _ if env.resolve(object) == 0 => jobjectRefType::JNIInvalidRefType,
+        handle if handle & GLOBAL_HANDLE != 0 => jobjectRefType::JNIGlobalRefType,
+        _ => jobjectRefType::JNILocalRefType
+    }
+}
+
+unsafe extern "system" fn alloc_object(env: *mut JNIEnv, class: jclass) -> jobject {
+    let (env, _unblocked) = JniEnv::enter(env);
+    let class = class_of(env, class);
+    let heap = env.heap;
+    match heap.allocate_ref(&class, env) {
+        Ok(object) => env.new_local(object.offset()),
+        Err(error) => env.fail(error)
+    }
+}
+
+unsafe extern "system" fn new_object(
+    env: *mut JNIEnv,
+    class: jclass,
+    constructor: jmethodID,
+    arguments: *const jvalue
+) -> jobject {
+    let object = alloc_object(env, class);
+    if object.is_null() {
+        return object;
+    }
+    let (env, _unblocked) = JniEnv::enter(env);
+    let class = class_of(env, class);
+    let constructor = class.find_method(|method| ptr::eq(method, constructor as *const Method))
+        .unwrap_or_else(|| panic!("Invalid JNI call! The constructor isn't one of {}'s!", class.name()));
+    let receiver = env.resolve(object);
+    env.call(&constructor.0, &constructor.1, Some(receiver), arguments);
+    if env.has_pending() { null_mut() } else { object }
+}
+
+unsafe extern "system" fn get_object_class(env: *mut JNIEnv, object: jobject) -> jclass {
+    let (env, _unblocked) = JniEnv::enter(env);
+    let class_name = env.heap.class_name(env.resolve(object)).replace('.', "/");
+    env.mirror(&class_name)
+}
+
+unsafe extern "system" fn is_instance_of(env: *mut JNIEnv, object: jobject, class: jclass) -> jboolean {
+    let (env, _unblocked) = JniEnv::enter(env);
+    let class = class_of(env, class);
+    match env.heap.get_ref(env.resolve(object)) {
+        Reference::Value(object) => {
+            natives::is_instance_of(env.heap, &env.loader(), object.offset(), class.name()) as jboolean
+        }
+        Reference::Null if object.is_null() => JNI_TRUE,
+        Reference::Null => (class.super_class_name().is_none() && !class.is_interface()) as jboolean
+    }
+}
+
+unsafe extern "system" fn get_method_id(
+    env: *mut JNIEnv,
+    class: jclass,
+    name: *const c_char,
+    signature: *const c_char
+) -> jmethodID {
+    method_id(env, class, name, signature, false)
+}
+
+unsafe extern "system" fn get_static_method_id(
+    env: *mut JNIEnv,
+    class: jclass,
+    name: *const c_char,
+    signature: *const c_char
+) -> jmethodID {
+    method_id(env, class, name, signature, true)
+}
+
+// Method IDs point to the methods themselves, which live for as long as their classes do.
+unsafe fn method_id(
+    env: *mut JNIEnv,
+    class: jclass,
+    name: *const c_char,
+    signature: *const c_char,
+    is_static: bool
+) -> jmethodID {
+    let (env, _unblocked) = JniEnv::enter(env);
+    let class = class_of(env, class);
+    let name = read_string(name);
+    let descriptor = MethodDescriptor::parse(&read_string(signature));
+    let found = descriptor.and_then(|descriptor| class.find_method(|method| {
+        method.name() == name && method.descriptor() == &descriptor && method.is_static() == is_static
+    }));
+    match found {
+        Some((_, method)) => Arc::as_ptr(&method) as jmethodID,
+        None => {
+            env.throw_new(NO_SUCH_METHOD_ERROR, Some(&utf16(&name)));
+            null_mut()
+        }
+    }
+}
+
+// Safety: the ID has to be one that GetMethodID or GetStaticMethodID gave out.
+unsafe fn method_of<'m>(method: jmethodID) -> &'m Method {
+    &*(method as *const Method)
+}
+
+// Calls are dispatched on the class of the receiver, so overrides of the method are the ones called.
+unsafe extern "system" fn call_method<T: JniValue>(
+    env: *mut JNIEnv,
+    object: jobject,
+    method: jmethodID,
+    arguments: *const jvalue
+) -> T {
+    let (env, _unblocked) = JniEnv::enter(env);
+    let receiver = env.resolve(object);
+    let class = env.heap.get_ref(receiver).expect("Invalid JNI call! Receiver cannot be null!").class();
+    let declared = method_of(method);
+    let (class, method) = class.find_method(|method| {
+        method.name() == declared.name() && method.descriptor() == declared.descriptor() && !method.is_static()
+    }).unwrap_or_else(|| panic!("Invalid JNI call! {} has no method {}!", class.name(), declared.name()));
+    call(env, &class, &method, Some(receiver), arguments)
+}
+
+unsafe extern "system" fn call_nonvirtual_method<T: JniValue>(
+    env: *mut JNIEnv,
+    object: jobject,
+    class: jclass,
+    method: jmethodID,
+    arguments: *const jvalue
+) -> T {
+    let (env, _unblocked) = JniEnv::enter(env);
+    let receiver = env.resolve(object);
+    let (class, method) = declared_method(env, class, method);
+    call(env, &class, &method, Some(receiver), arguments)
+}
+
+unsafe extern "system" fn call_static_method<T: JniValue>(
+    env: *mut JNIEnv,
+    class: jclass,
+    method: jmethodID,
+    arguments: *const jvalue
+) -> T {
+    let (env, _unblocked) = JniEnv::enter(env);
+    let (class, method) = declared_method(env, class, method);
+    call(env, &class, &method, None, arguments)
+}
+
+unsafe fn declared_method(env: &JniEnv, class: jclass, method: jmethodID) -> (Arc<Class>, Arc<Method>) {
+    let class = class_of(env, class);
+    class.find_method(|candidate| ptr::eq(candidate, method as *const Method))
+        .unwrap_or_else(|| panic!("Invalid JNI call! {} has no method {}!", class.name(), method_of(method).name()))
+}
+
+unsafe fn call<T: JniValue>(
+    env: &mut JniEnv,
+    class: &Arc<Class>,
+    method: &Arc<Method>,
+    receiver: Option<usize>,
+    arguments: *const jvalue
+) -> T {
+    match env.call(class, method, receiver, arguments) {
+        Some(result) => T::from_result(env, result),
+        None => T::zero()
+    }
+}
+
+// Field IDs are the positions of the fields in their objects, with the lowest bit set for volatile ones.
+unsafe extern "system" fn get_field_id(
+    env: *mut JNIEnv,
+    class: jclass,
+    name: *const c_char,
+    signature: *const c_char
+) -> jfieldID {
+    let (env, _unblocked) = JniEnv::enter(env);
+    let class = class_of(env, class);
+    let name = read_string(name);
+    let field = FieldDescriptor::parse(&read_string(signature))
+        .and_then(|descriptor| FieldLayout::of(&class).find_field(&name, &descriptor));
+    match field {
+        Some((position, order)) => ((position << 1) | (order == MemoryOrder::Volatile) as usize) as jfieldID,
+        None => {
+            env.throw_new(NO_SUCH_FIELD_ERROR, Some(&utf16(&name)));
+            null_mut()
+        }
+    }
+}
+
+fn field_of(field: jfieldID) -> (usize, MemoryOrder) {
+    let field = field as usize;
+    let order = if field & 1 != 0 { MemoryOrder::Volatile } else { MemoryOrder::Plain };
+    (field >> 1, order)
+}
+
+unsafe extern "system" fn get_field<T: JniValue>(env: *mut JNIEnv, object: jobject, field: jfieldID) -> T {
+    let (env, _unblocked) = JniEnv::enter(env);
+    let object = env.resolve(object);
+    assert_ne!(object, 0, "Invalid JNI field access! Object cannot be null!");
+    let (position, order) = field_of(field);
+    let raw = env.heap.load(object, position, T::SIZE, order);
+    T::from_raw(env, raw)
+}
+
+unsafe extern "system" fn set_field<T: JniValue>(env: *mut JNIEnv, object: jobject, field: jfieldID, value: T) {
+    let (env, _unblocked) = JniEnv::enter(env);
+    let object = env.resolve(object);
+    assert_ne!(object, 0, "Invalid JNI field access! Object cannot be null!");
+    let (position, order) = field_of(field);
+    let raw = value.into_raw(env);
+    env.heap.store(object, position, T::SIZE, raw, order);
+    if T::IS_REFERENCE {
+        env.heap.write_barrier(object, raw as usize);
+    }
+}
+
+fn new_jstring(env: &mut JniEnv, chars: &[u16]) -> jstring {
+    let (heap, loader) = (env.heap, env.loader());
+    match new_string(heap, &loader, chars, env) {
+        Ok(string) => env.new_local(string),
+        Err(error) => env.fail(error)
+    }
+}
+
+fn chars_of(env: &JniEnv, string: jstring) -> Vec<u16> {
+    let string = env.resolve(string);
+    assert_ne!(string, 0, "Invalid JNI string access! String cannot be null!");
+    string_chars(env.heap, string)
+}
+
+// Strings and arrays are copied out for natives, as their contents can move, so the functions that get
+// their contents always say that they made a copy.
+unsafe fn set_copied(is_copy: *mut jboolean) {
+    if !is_copy.is_null() {
+        *is_copy = JNI_TRUE;
+    }
+}
+
+unsafe extern "system" fn new_java_string(env: *mut JNIEnv, chars: *const jchar, length: jsize) -> jstring {
+    let (env, _unblocked) = JniEnv::enter(env);
+    new_jstring(env, std::slice::from_raw_parts(chars, length as usize))
+}
+
+unsafe extern "system" fn get_string_length(env: *mut JNIEnv, string: jstring) -> jsize {
+    let (env, _unblocked) = JniEnv::enter(env);
+    chars_of(env, string).len() as jsize
+}
+
+unsafe extern "system" fn get_string_chars(env: *mut JNIEnv, string: jstring, is_copy: *mut jboolean) -> *const jchar {
+    let (env, _unblocked) = JniEnv::enter(env);
+    let chars = chars_of(env, string);
+    set_copied(is_copy);
+    Box::into_raw(chars.into_boxed_slice()) as *const jchar
+}
+
+unsafe extern "system" fn release_string_chars(env: *mut JNIEnv, string: jstring, chars: *const jchar) {
+    let length = get_string_length(env, string) as usize;
+    drop(Box::from_raw(ptr::slice_from_raw_parts_mut(chars as *mut jchar, length)));
+}
+
+unsafe extern "system" fn get_string_region(
+    env: *mut JNIEnv,
+    string: jstring,
+    start: jsize,
+    length: jsize,
+    buffer: *mut jchar
+) {
+    let (env, _unblocked) = JniEnv::enter(env);
+    if let Some(chars) = string_region(env, string, start, length) {
+        ptr::copy_nonoverlapping(chars.as_ptr(), buffer, chars.len());
+    }
+}
+
+unsafe extern "system" fn new_string_utf(env: *mut JNIEnv, bytes: *const c_char) -> jstring {
+    let (env, _unblocked) = JniEnv::enter(env);
+    new_jstring(env, &read_utf(bytes))
+}
+
+unsafe extern "system" fn get_string_utf_length(env: *mut JNIEnv, string: jstring) -> jsize {
+    let (env, _unblocked) = JniEnv::enter(env);
+    to_modified_utf8(&chars_of(env, string)).len() as jsize
+}
+
+unsafe extern "system" fn get_string_utf_chars(
+    env: *mut JNIEnv,
+    string: jstring,
+    is_copy: *mut jboolean
+) -> *const c_char {
+    let (env, _unblocked) = JniEnv::enter(env);
+    let mut bytes = to_modified_utf8(&chars_of(env, string));
+    bytes.push(0);
+    set_copied(is_copy);
+    Box::into_raw(bytes.into_boxed_slice()) as *const c_char
+}
+
+unsafe extern "system" fn release_string_utf_chars(_: *mut JNIEnv, _: jstring, bytes: *const c_char) {
+    let length = CStr::from_ptr(bytes).to_bytes_with_nul().len();
+    drop(Box::from_raw(ptr::slice_from_raw_parts_mut(bytes as *mut u8, length)));
+}
+
+unsafe extern "system" fn get_string_utf_region(
+    env: *mut JNIEnv,
+    string: jstring,
+    start: jsize,
+    length: jsize,
+    buffer: *mut c_char
+) {
+    let (env, _unblocked) = JniEnv::enter(env);
+    if let Some(chars) = string_region(env, string, start, length) {
+        let mut bytes = to_modified_utf8(&chars);
+        bytes.push(0);
+        ptr::copy_nonoverlapping(bytes.as_ptr(), buffer as *mut u8, bytes.len());
+    }
+}
+
+fn string_region(env: &mut JniEnv, string: jstring, start: jsize, length: jsize) -> Option<Vec<u16>> {
+    let chars = chars_of(env, string);
+    if !in_bounds(start, length, chars.len()) {
+        env.throw_new(STRING_INDEX_OUT_OF_BOUNDS_EXCEPTION, None);
+        return None;
+    }
+    Some(chars[start as usize..(start + length) as usize].to_vec())
+}
+
+fn in_bounds(start: jsize, length: jsize, array_length: usize) -> bool {
+    start >= 0 && length >= 0 && start as i64 + length as i64 <= array_length as i64
+}
+
+fn array_length(heap: &HeapSpace, array: usize) -> usize {
+    if let Reference::Value(array) = heap.get_ref_array(array) {
+        return array.len();
+    }
+    heap.get_type_array(array).expect("Invalid JNI array access! Array cannot be null!").len()
+}
+
+fn element(index: usize, size: usize) -> usize {
+    OBJECT_HEADER_SIZE + index * size
+}
+
+unsafe extern "system" fn get_array_length(env: *mut JNIEnv, array: jarray) -> jsize {
+    let (env, _unblocked) = JniEnv::enter(env);
+    array_length(env.heap, env.resolve(array)) as jsize
+}
+
+unsafe extern "system" fn new_object_array(
+    env: *mut JNIEnv,
+    length: jsize,
+    class: jclass,
+    initial: jobject
+) -> jobjectArray {
+    let (env, _unblocked) = JniEnv::enter(env);
+    if length < 0 {
+        env.throw_new(NEGATIVE_ARRAY_SIZE_EXCEPTION, Some(&utf16(&length.to_string())));
+        return null_mut();
+    }
+    let class = class_of(env, class);
+    let heap = env.heap;
+    let array = match heap.allocate_ref_array(&class, &class, length as usize, env) {
+        Ok(array) => array,
+        Err(error) => return env.fail(error)
+    };
+    let initial = env.resolve(initial);
+    if initial != 0 {
+        (0..array.len()).for_each(|index| array.set(index, initial as u32));
+        heap.write_barrier(array.offset(), initial);
+    }
+    env.new_local(array.offset())
+}
+
+unsafe extern "system" fn get_object_array_element(env: *mut JNIEnv, array: jobjectArray, index: jsize) -> jobject {
+    let (env, _unblocked) = JniEnv::enter(env);
+    let array = env.heap.get_ref_array(env.resolve(array)).expect("Invalid JNI array access! Array cannot be null!");
+    if !in_bounds(index, 1, array.len()) {
+        env.throw_new(ARRAY_INDEX_OUT_OF_BOUNDS_EXCEPTION, Some(&out_of_bounds(index, array.len())));
+        return null_mut();
+    }
+    let element = array.get(index as usize) as usize;
+    env.new_local(element)
+}
+
+unsafe extern "system" fn set_object_array_element(
+    env: *mut JNIEnv,
+    array: jobjectArray,
+    index: jsize,
+    value: jobject
+) {
+    let (env, _unblocked) = JniEnv::enter(env);
+    let array = env.heap.get_ref_array(env.resolve(array)).expect("Invalid JNI array access! Array cannot be null!");
+    if !in_bounds(index, 1, array.len()) {
+        env.throw_new(ARRAY_INDEX_OUT_OF_BOUNDS_EXCEPTION, Some(&out_of_bounds(index, array.len())));
+        return;
+    }
+    let value = env.resolve(value);
+    array.set(index as usize, value as u32);
+    env.heap.write_barrier(array.offset(), value);
+}
+
+fn out_of_bounds(index: jsize, length: usize) -> Vec<u16> {
+    utf16(&format!("Index {} out of bounds for length {}", index, length))
+}
+
+unsafe extern "system" fn new_array<T: JniPrimitive>(env: *mut JNIEnv, length: jsize) -> jarray {
+    let (env, _unblocked) = JniEnv::enter(env);
+    if length < 0 {
+        env.throw_new(NEGATIVE_ARRAY_SIZE_EXCEPTION, Some(&utf16(&length.to_string())));
+        return null_mut();
+    }
+    let heap = env.heap;
+    match heap.allocate_type_array(T::ARRAY_TYPE, length as usize, env) {
+        Ok(array) => env.new_local(array.offset()),
+        Err(error) => env.fail(error)
+    }
+}
+
+unsafe extern "system" fn get_array_elements<T: JniPrimitive>(
+    env: *mut JNIEnv,
+    array: jarray,
+    is_copy: *mut jboolean
+) -> *mut T {
+    let length = get_array_length(env, array);
+    let mut elements = vec![T::zero(); length as usize];
+    get_array_region(env, array, 0, length, elements.as_mut_ptr());
+    set_copied(is_copy);
+    Box::into_raw(elements.into_boxed_slice()) as *mut T
+}
+
+unsafe extern "system" fn release_array_elements<T: JniPrimitive>(
+    env: *mut JNIEnv,
+    array: jarray,
+    elements: *mut T,
+    mode: jint
+) {
+    let length = get_array_length(env, array);
+    if mode != JNI_ABORT {
+        set_array_region(env, array, 0, length, elements);
+    }
+    if mode != JNI_COMMIT {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(elements, length as usize)));
+    }
+}
+
+unsafe extern "system" fn get_array_region<T: JniPrimitive>(
+    env: *mut JNIEnv,
+    array: jarray,
+    start: jsize,
+    length: jsize,
+    buffer: *mut T
+) {
+    let (env, _unblocked) = JniEnv::enter(env);
+    let array = env.resolve(array);
+    if check_region(env, array, start, length) {
+        for index in 0..length as usize {
+            let raw = env.heap.load(array, element(start as usize + index, T::SIZE), T::SIZE, MemoryOrder::Plain);
+            *buffer.add(index) = T::from_raw(env, raw);
+        }
+    }
+}
+
+unsafe extern "system" fn set_array_region<T: JniPrimitive>(
+    env: *mut JNIEnv,
+    array: jarray,
+    start: jsize,
+    length: jsize,
+    buffer: *const T
+) {
+    let (env, _unblocked) = JniEnv::enter(env);
+    let array = env.resolve(array);
+    if check_region(env, array, start, length) {
+        for index in 0..length as usize {
+            let raw = (*buffer.add(index)).into_raw(env);
+            env.heap.store(array, element(start as usize + index, T::SIZE), T::SIZE, raw, MemoryOrder::Plain);
+        }
+    }
+}
+
+fn check_region(env: &mut JniEnv, array: usize, start: jsize, length: jsize) -> bool {
+    let array_length = array_length(env.heap, array);
+    if in_bounds(start, length, array_length) {
+        return true;
+    }
+    let index = if start < 0 { start } else { start.saturating_add(length.max(1)) - 1 };
+    env.throw_new(ARRAY_INDEX_OUT_OF_BOUNDS_EXCEPTION, Some(&out_of_bounds(index, array_length)));
+    false
+}
+
+unsafe extern "system" fn register_natives(
+    env: *mut JNIEnv,
+    class: jclass,
+    methods: *const JNINativeMethod,
+    count: jint
+) -> jint {
+    let (env, _unblocked) = JniEnv::enter(env);
+    let class = class_of(env, class);
+    for index in 0..count as usize {
+        let native = &*methods.add(index);
+        let name = read_string(native.name);
+        let signature = read_string(native.signature);
+        let descriptor = MethodDescriptor::parse(&signature);
+        let declared = class.methods().iter().any(|method| {
+            method.is_native() && method.name() == name && Some(method.descriptor()) == descriptor.as_ref()
+        });
+        if !declared {
+            env.throw_new(NO_SUCH_METHOD_ERROR, Some(&utf16(&format!("{}.{}{}", class.name(), name, signature))));
+            return JNI_ERR;
+        }
+        env.loader().libraries().register(class.name(), &name, &signature, native.fnPtr);
+    }
+    JNI_OK
+}
+
+unsafe extern "system" fn unregister_natives(env: *mut JNIEnv, class: jclass) -> jint {
+    let (env, _unblocked) = JniEnv::enter(env);
+    env.loader().libraries().unregister(class_of(env, class).name());
+    JNI_OK
+}
+
+// Natives that find the monitor held by another thread stop until it's let go of, like monitorenter.
+unsafe extern "system" fn monitor_enter(env: *mut JNIEnv, object: jobject) -> jint {
+    let (env, _unblocked) = JniEnv::enter(env);
+    let thread = Threads::current();
+    let owner = thread.as_ref().map_or(0, |thread| thread.id());
+    let heap = env.heap;
+    loop {
+        let offset = env.resolve(object);
+        if offset == 0 {
+            return JNI_ERR;
+        }
+        let monitor = Monitor::new(heap.identity_hash(offset), heap.class_name(offset));
+        if heap.monitor_enter(offset, owner) {
+            if let Some(thread) = &thread {
+                thread.monitor_entered(monitor);
+            }
+            return JNI_OK;
+        }
+        if let Some(thread) = &thread {
+            thread.monitor_blocked(monitor);
+        }
+        heap.blocking(env, || std::thread::sleep(MONITOR_RETRY_INTERVAL));
+    }
+}
+
+unsafe extern "system" fn monitor_exit(env: *mut JNIEnv, object: jobject) -> jint {
+    let (env, _unblocked) = JniEnv::enter(env);
+    let thread = Threads::current();
+    let offset = env.resolve(object);
+    if offset == 0 || !env.heap.monitor_exit(offset, thread.as_ref().map_or(0, |thread| thread.id())) {
+        return JNI_ERR;
+    }
+    if let Some(thread) = thread {
+        thread.monitor_exited(env.heap.identity_hash(offset));
+    }
+    JNI_OK
+}
+
+fn class_of(env: &JniEnv, class: jclass) -> Arc<Class> {
+    env.class_of(class).unwrap_or_else(|| panic!("Invalid JNI call! Expected a class!"))
+}
@@ -0,0 +1,235 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+
+use std::cell::{Cell, RefCell};
+use std::ffi::{c_void, CStr};
+use std::ptr::null_mut;
+use std::sync::{Arc, Once, RwLock, Weak};
+use std::sync::atomic::{AtomicPtr, Ordering};
+use jni::sys::*;
+use crate::class_file::ClassLoader;
+use crate::objects::HeapSpace;
+use crate::runtime::Threads;
+use super::{JniEnv, NativeLibrary, NativeLibraryError};
+
+const DEFAULT_THREAD_NAME: &str = "Attached Thread";
+
+static INTERFACE: AtomicPtr<JNIInvokeInterface_> = AtomicPtr::new(null_mut());
+static INTERFACE_BUILT: Once = Once::new();
+
+thread_local! {
+    // The env of the native that's running on the OS thread, if there is one, which is what GetEnv gives.
+    static CURRENT_ENV: Cell<*mut JNIEnv> = Cell::new(null_mut());
+    // The env of an OS thread that native code has attached, which lives until it's detached again.
+    static ATTACHED_ENV: RefCell<Option<AttachedEnv>> = RefCell::new(None);
+}
+
+// What a JavaVM points to. There's one for each class loader's libraries, so that threads attached
+// through it find classes with the loader of the library that attached them. The threads and the loader
+// are those of the last library loaded, as the VM that the loader belongs to isn't known before then.
+#[repr(C)]
+#[derive(Debug)]
+pub(crate) struct JavaVm {
+    functions: *const JNIInvokeInterface_,
+    threads: RwLock<Weak<Threads>>,
+    loader: RwLock<Weak<ClassLoader>>
+}
+
+// The function table is never changed once it's built, and the rest is behind locks.
+unsafe impl Send for JavaVm {}
+unsafe impl Sync for JavaVm {}
+
+impl JavaVm {
+    pub(super) fn bind(&self, loader: &Arc<ClassLoader>) {
+        *self.loader.write().unwrap() = Arc::downgrade(loader);
+        if let Some(threads) = Threads::attached() {
+            *self.threads.write().unwrap() = Arc::downgrade(&threads);
+        }
+    }
+
+    pub(super) fn as_raw(&self) -> *mut JavaVM {
+        self as *const JavaVm as *mut JavaVM
+    }
+}
+
+impl Default for JavaVm {
+    fn default() -> Self {
+        JavaVm { functions: interface(), threads: RwLock::new(Weak::new()), loader: RwLock::new(Weak::new()) }
+    }
+}
+
+// An env for a thread that native code attached, along with the heap that it uses, which has to outlive it.
+// The thread counts as stopped, with the env as its roots, whenever it isn't in a JNI function.
+struct AttachedEnv {
+    env: Box<JniEnv<'static>>,
+    threads: Arc<Threads>,
+    _heap: Arc<HeapSpace>
+}
+
+fn interface() -> *const JNIInvokeInterface_ {
+    INTERFACE_BUILT.call_once(|| {
+        let interface = JNIInvokeInterface_ {
+            reserved0: null_mut(),
+            reserved1: null_mut(),
+            reserved2: null_mut(),
+            DestroyJavaVM: Some(destroy_java_vm),
+            AttachCurrentThread: Some(attach_current_thread),
+            DetachCurrentThread: Some(detach_current_thread),
+            GetEnv: Some(get_env),
+            AttachCurrentThreadAsDaemon: Some(attach_current_thread_as_daemon)
+        };
+        INTERFACE.store(Box::into_raw(Box::new(interface)), Ordering::Release);
+    });
+    INTERFACE.load(Ordering::Acquire)
+}
+
+// Makes the env the one that GetEnv gives for as long as the native runs, putting back whichever one
+// was there before, as natives can call Java that calls other natives.
+pub(super) fn with_current_env<R>(env: *mut JniEnv, native: impl FnOnce() -> R) -> R {
+    let previous = CURRENT_ENV.with(|current| current.replace(env as *mut JNIEnv));
+    let result = native();
+    CURRENT_ENV.with(|current| current.set(previous));
+    result
+}
+
+// Calls JNI_OnLoad if the library has one, which it uses to register its natives and to say which
+// version of JNI it needs. Libraries without one only need the first version.
+pub(super) fn on_load(
+    heap: &HeapSpace,
+    loader: &Arc<ClassLoader>,
+    library: &NativeLibrary
+) -> Result<(), NativeLibraryError> {
+    let function = match library.symbol("JNI_OnLoad") {
+        Some(function) => function,
+        None => return Ok(())
+    };
+    let vm = loader.libraries().java_vm();
+    vm.bind(loader);
+    type OnLoad = unsafe extern "system" fn(*mut JavaVM, *mut c_void) -> jint;
+    // Safety: JNI_OnLoad takes the JavaVM and a reserved pointer, and returns the version it needs.
+    let on_load = unsafe { std::mem::transmute::<*mut c_void, OnLoad>(function) };
+    let mut env = JniEnv::new(heap, Arc::clone(loader));
+    let env_pointer = &mut env as *mut JniEnv;
+    let version = heap.blocking(unsafe { &mut *env_pointer }, || {
+        with_current_env(env_pointer, || unsafe { on_load(vm.as_raw(), null_mut()) })
+    });
+    if env.has_pending() {
+        return Err(NativeLibraryError::OnLoadFailed(library.path().to_path_buf()));
+    }
+    if !is_supported(version) {
+        return Err(NativeLibraryError::UnsupportedVersion(library.path().to_path_buf(), version));
+    }
+    Ok(())
+}
+
+fn is_supported(version: jint) -> bool {
+    matches!(version, JNI_VERSION_1_1 | JNI_VERSION_1_2 | JNI_VERSION_1_4 | JNI_VERSION_1_6 | JNI_VERSION_1_8)
+}
+
+pub(super) unsafe extern "system" fn get_java_vm(env: *mut JNIEnv, vm: *mut *mut JavaVM) -> jint {
+    let (env, _unblocked) = JniEnv::enter(env);
+    let loader = env.loader();
+    let java_vm = loader.libraries().java_vm();
+    java_vm.bind(&loader);
+    *vm = java_vm.as_raw();
+    JNI_OK
+}
+
+unsafe extern "system" fn destroy_java_vm(_: *mut JavaVM) -> jint {
+    JNI_ERR
+}
+
+unsafe extern "system" fn get_env(_: *mut JavaVM, env: *mut *mut c_void, version: jint) -> jint {
+    if !is_supported(version) {
+        *env = null_mut();
+        return JNI_EVERSION;
+    }
+    let current = CURRENT_ENV.with(Cell::get);
+    *env = current as *mut c_void;
+    if current.is_null() { JNI_EDETACHED } else { JNI_OK }
+}
+
+unsafe extern "system" fn attach_current_thread(vm: *mut JavaVM, env: *mut *mut c_void, args: *mut c_void) -> jint {
+    attach(vm, env, args, false)
+}
+
+unsafe extern "system" fn attach_current_thread_as_daemon(
+    vm: *mut JavaVM,
+    env: *mut *mut c_void,
+    args: *mut c_void
+) -> jint {
+    attach(vm, env, args, true)
+}
+
+// Threads that are already running a native just get its env back. Other Java threads can't be given
+// one, as they aren't stopped while they run Rust code, so only OS threads that the VM doesn't know
+// about yet are attached.
+unsafe fn attach(vm: *mut JavaVM, env: *mut *mut c_void, args: *mut c_void, daemon: bool) -> jint {
+    let current = CURRENT_ENV.with(Cell::get);
+    if !current.is_null() {
+        *env = current as *mut c_void;
+        return JNI_OK;
+    }
+    if Threads::current().is_some() {
+        return JNI_ERR;
+    }
+    let vm = &*(vm as *const JavaVm);
+    let threads = match vm.threads.read().unwrap().upgrade() {
+        Some(threads) => threads,
+        None => return JNI_ERR
+    };
+    let (heap, loader) = match (threads.heap(), vm.loader.read().unwrap().upgrade()) {
+        (Some(heap), Some(loader)) => (heap, loader),
+        _ => return JNI_ERR
+    };
+    let args = args as *const JavaVMAttachArgs;
+    let name = if args.is_null() || (*args).name.is_null() {
+        String::from(DEFAULT_THREAD_NAME)
+    } else {
+        CStr::from_ptr((*args).name).to_string_lossy().into_owned()
+    };
+
+    threads.attach_current_thread(&name, daemon, None);
+    // Safety: the heap is kept alive alongside the env, which is dropped first.
+    let heap_ref = &*Arc::as_ptr(&heap);
+    let mut attached = AttachedEnv { env: Box::new(JniEnv::new(heap_ref, loader)), threads, _heap: heap };
+    attached.threads.enter_native(&mut *attached.env);
+    let pointer = &mut *attached.env as *mut JniEnv as *mut JNIEnv;
+    CURRENT_ENV.with(|current| current.set(pointer));
+    ATTACHED_ENV.with(|env| *env.borrow_mut() = Some(attached));
+    *env = pointer as *mut c_void;
+    JNI_OK
+}
+
+// Only threads that were attached through the JavaVM can be detached, and only once they've returned
+// from every native they called.
+unsafe extern "system" fn detach_current_thread(_: *mut JavaVM) -> jint {
+    let current = CURRENT_ENV.with(Cell::get);
+    let attached = ATTACHED_ENV.with(|env| {
+        let mut env = env.borrow_mut();
+        let pointer = env.as_mut().map(|attached| &mut *attached.env as *mut JniEnv as *mut JNIEnv);
+        if pointer == Some(current) { env.take() } else { None }
+    });
+    let attached = match attached {
+        Some(attached) => attached,
+        None => return JNI_ERR
+    };
+    attached.threads.leave_native();
+    attached.threads.detach_current_thread();
+    CURRENT_ENV.with(|current| current.set(null_mut()));
+    JNI_OK
+}
@@ -0,0 +1,222 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::ffi::c_void;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use crate::class_file::ClassLoader;
+use crate::objects::HeapSpace;
+use crate::utils::IStr;
+use super::invocation::{self, JavaVm};
+
+// A shared library opened with dlopen, which stays open until the loader that opened it is dropped.
+#[derive(Debug)]
+pub struct NativeLibrary {
+    path: PathBuf,
+    handle: usize
+}
+
+impl NativeLibrary {
+    #[cfg(unix)]
+    pub fn open(path: &Path) -> Result<Self, NativeLibraryError> {
+        use std::os::unix::ffi::OsStrExt;
+        let name = std::ffi::CString::new(path.as_os_str().as_bytes())
+            .map_err(|_| NativeLibraryError::Open(path.to_path_buf(), String::from("Path contains a null byte")))?;
+        // Safety: the name is a valid C string, and dlerror is only read straight after dlopen fails.
+        let handle = unsafe { libc::dlopen(name.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        if handle.is_null() {
+            let message = unsafe { std::ffi::CStr::from_ptr(libc::dlerror()) };
+            return Err(NativeLibraryError::Open(path.to_path_buf(), message.to_string_lossy().into_owned()));
+        }
+        Ok(NativeLibrary { path: path.to_path_buf(), handle: handle as usize })
+    }
+
+    #[cfg(not(unix))]
+    pub fn open(path: &Path) -> Result<Self, NativeLibraryError> {
+        Err(NativeLibraryError::Open(path.to_path_buf(), String::from("Native libraries are only supported on Unix")))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    #[cfg(unix)]
+    pub fn symbol(&self, name: &str) -> Option<*mut c_void> {
+        let name = std::ffi::CString::new(name).ok()?;
+        // Safety: the handle stays open for as long as this library does.
+        let symbol = unsafe { libc::dlsym(self.handle as *mut c_void, name.as_ptr()) };
+        if symbol.is_null() { None } else { Some(symbol) }
+    }
+
+    #[cfg(not(unix))]
+    pub fn symbol(&self, _: &str) -> Option<*mut c_void> {
+        None
+    }
+}
+
+impl Drop for NativeLibrary {
+    #[cfg(unix)]
+    fn drop(&mut self) {
+        // Safety: nothing resolved from the library is called once the loader that opened it is gone.
+        unsafe { libc::dlclose(self.handle as *mut c_void) };
+    }
+
+    #[cfg(not(unix))]
+    fn drop(&mut self) {}
+}
+
+// The libraries that a class loader has loaded, which the native methods of its classes are looked up
+// in when nothing has been registered for them in Rust. Natives that libraries register themselves with
+// RegisterNatives are looked up first, then the libraries' exports in the order they were loaded.
+#[derive(Debug, Default)]
+pub struct NativeLibraries {
+    libraries: RwLock<Vec<NativeLibrary>>,
+    search_path: RwLock<Vec<PathBuf>>,
+    registered: RwLock<HashMap<(IStr, IStr, IStr), usize>>,
+    // Boxed, as the libraries keep hold of the pointer to it that they're given.
+    java_vm: Box<JavaVm>
+}
+
+impl NativeLibraries {
+    // Like System.load, which loads the library at the path unless it's already been loaded. The loader is
+    // the one that these are the libraries of, which the library's JNI_OnLoad finds classes with, and a
+    // library that fails in there, or that needs a version of JNI that isn't supported, isn't loaded.
+    pub fn load(
+        &self,
+        heap: &HeapSpace,
+        loader: &Arc<ClassLoader>,
+        path: impl AsRef<Path>
+    ) -> Result<(), NativeLibraryError> {
+        let path = path.as_ref();
+        if self.libraries.read().unwrap().iter().any(|library| library.path() == path) {
+            return Ok(());
+        }
+        let library = NativeLibrary::open(path)?;
+        invocation::on_load(heap, loader, &library)?;
+        self.libraries.write().unwrap().push(library);
+        Ok(())
+    }
+
+    // Like System.loadLibrary, which looks for the library with the platform's name for it in each
+    // directory of the search path in turn.
+    pub fn load_library(
+        &self,
+        heap: &HeapSpace,
+        loader: &Arc<ClassLoader>,
+        name: &str
+    ) -> Result<(), NativeLibraryError> {
+        let file_name = map_library_name(name);
+        let path = self.search_path.read().unwrap().iter()
+            .map(|directory| directory.join(&file_name))
+            .find(|path| path.is_file())
+            .ok_or_else(|| NativeLibraryError::NotFound(String::from(name)))?;
+        self.load(heap, loader, path)
+    }
+
+    // Adds a directory to look for libraries in, which is what java.library.path lists.
+    pub fn add_search_path(&self, directory: impl Into<PathBuf>) {
+        self.search_path.write().unwrap().push(directory.into());
+    }
+
+    pub fn search_path(&self) -> Vec<PathBuf> {
+        self.search_path.read().unwrap().clone()
+    }
+
+    pub(crate) fn java_vm(&self) -> &JavaVm {
+        &self.java_vm
+    }
+
+    pub(crate) fn register(&self, class_name: &str, name: &str, descriptor: &str, function: *mut c_void) {
+        let key = (IStr::new(class_name), IStr::new(name), IStr::new(descriptor));
+        self.registered.write().unwrap().insert(key, function as usize);
+    }
+
+    pub(crate) fn unregister(&self, class_name: &str) {
+        self.registered.write().unwrap().retain(|(class, _, _), _| class.as_str() != class_name);
+    }
+
+    // The function for the native, looked up by its short JNI name first and then by its long one,
+    // which has the parameter descriptor added to tell overloads apart.
+    pub(crate) fn find(&self, class_name: &str, name: &str, descriptor: &str) -> Option<*mut c_void> {
+        let key = (IStr::new(class_name), IStr::new(name), IStr::new(descriptor));
+        if let Some(function) = self.registered.read().unwrap().get(&key) {
+            return Some(*function as *mut c_void);
+        }
+        let short_name = format!("Java_{}_{}", mangle(class_name), mangle(name));
+        let parameters = descriptor.strip_prefix('(').and_then(|rest| rest.split(')').next()).unwrap_or("");
+        let long_name = format!("{}__{}", short_name, mangle(parameters));
+        let libraries = self.libraries.read().unwrap();
+        libraries.iter().find_map(|library| library.symbol(&short_name))
+            .or_else(|| libraries.iter().find_map(|library| library.symbol(&long_name)))
+    }
+}
+
+// The file name of the library with the given name on this platform, like System.mapLibraryName.
+pub fn map_library_name(name: &str) -> String {
+    if cfg!(target_os = "macos") {
+        format!("lib{}.dylib", name)
+    } else if cfg!(windows) {
+        format!("{}.dll", name)
+    } else {
+        format!("lib{}.so", name)
+    }
+}
+
+// Escapes a name for a JNI symbol, where slashes separate the parts of a class name and anything that
+// wouldn't be valid in a C identifier is escaped.
+fn mangle(name: &str) -> String {
+    let mut mangled = String::with_capacity(name.len());
+    for char in name.encode_utf16() {
+        match char::from_u32(char as u32) {
+            Some('/') => mangled.push('_'),
+            Some('_') => mangled.push_str("_1"),
+            Some(';') => mangled.push_str("_2"),
+            Some('[') => mangled.push_str("_3"),
+            Some(char) if char.is_ascii_alphanumeric() => mangled.push(char),
+            _ => mangled.push_str(&format!("_0{:04x}", char))
+        }
+    }
+    mangled
+}
+
+#[derive(Debug)]
+pub enum NativeLibraryError {
+    NotFound(String),
+    Open(PathBuf, String),
+    OnLoadFailed(PathBuf),
+    UnsupportedVersion(PathBuf, i32)
+}
+
+impl Display for NativeLibraryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NativeLibraryError::NotFound(name) => write!(f, "no {} in java.library.path", name),
+            NativeLibraryError::Open(path, message) => {
+                write!(f, "Can't load library: {} ({})", path.display(), message)
+            }
+            NativeLibraryError::OnLoadFailed(path) => write!(f, "JNI_OnLoad of {} threw an exception", path.display()),
+            NativeLibraryError::UnsupportedVersion(path, version) => {
+                write!(f, "unsupported JNI version 0x{:x} required by {}", version, path.display())
+            }
+        }
+    }
+}
+
+impl Error for NativeLibraryError {}
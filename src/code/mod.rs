@@ -16,7 +16,16 @@
 
 mod stack_frame;
 mod interpreter;
+mod intrinsics;
+mod jni;
+mod natives;
 
-pub use stack_frame::StackFrame;
+pub use stack_frame::{Slot, StackFrame};
 pub use interpreter::Interpreter;
 pub use interpreter::MethodResult;
+pub use intrinsics::Intrinsic;
+pub use self::jni::{map_library_name, NativeLibraries, NativeLibrary, NativeLibraryError};
+pub use natives::{
+    Arguments, FromArguments, IntoNativeResult, NativeEnv, NativeError, NativeFunction, NativeMethod, NativeRegistry,
+    NativeResult, NativeValue, ObjectRef, parameter_slots
+};
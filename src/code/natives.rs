@@ -0,0 +1,837 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+
+mod builtins;
+
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::sync::{Arc, RwLock};
+use crate::class_file::bytecode::ValueKind;
+use crate::class_file::ClassLoader;
+use crate::constants::JVM_SIGNATURE_SEPARATOR;
+use crate::objects::{HeapSpace, OutOfMemoryError, RootSource, WithRoot};
+use crate::runtime::{fill_in_stack_trace, new_string, Threads};
+use crate::types::{Class, Method};
+use crate::utils::IStr;
+use crate::utils::descriptors::{class_name_of, FieldDescriptor, FieldType};
+use super::{jni, MethodResult};
+
+const UNSATISFIED_LINK_ERROR: &str = "java/lang/UnsatisfiedLinkError";
+const CLASS_CLASS_NAME: &str = "java/lang/Class";
+
+// Reads parameters in order, with longs taking up two slots, the most significant half first.
+pub struct Arguments<'a>(&'a [u32]);
+
+impl<'a> Arguments<'a> {
+    pub fn new(parameters: &'a [u32]) -> Self {
+        Arguments(parameters)
+    }
+
+    pub fn next_slot(&mut self) -> u32 {
+        let (first, rest) = self.0.split_first()
+            .unwrap_or_else(|| panic!("Invalid native call! Too few parameters!"));
+        self.0 = rest;
+        *first
+    }
+
+    pub fn reference(&mut self) -> usize {
+        self.next_slot() as usize
+    }
+
+    pub fn long(&mut self) -> i64 {
+        let most = self.next_slot() as u64;
+        ((most << 32) | self.next_slot() as u64) as i64
+    }
+
+    pub fn value(&mut self, kind: ValueKind) -> u64 {
+        match kind {
+            ValueKind::Long => self.long() as u64,
+            _ => self.next_slot() as u64
+        }
+    }
+}
+
+// The slots that a parameter of the type takes up, given the bits of its value, in the order that Arguments
+// reads them back. Longs and doubles take two slots, the most significant half first.
+pub fn parameter_slots(parameter: &FieldDescriptor, bits: u64) -> Vec<u32> {
+    match parameter.base() {
+        FieldType::Long | FieldType::Double if !parameter.is_reference() => vec![(bits >> 32) as u32, bits as u32],
+        _ => vec![bits as u32]
+    }
+}
+
+// A reference that a native was given or is giving back, which is the offset of the object, or 0 for
+// null. Objects move when the heap is collected, so one is only good until the native next allocates or
+// blocks, after which it has to be read back from the env with get.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ObjectRef(pub usize);
+
+impl ObjectRef {
+    pub const NULL: ObjectRef = ObjectRef(0);
+
+    pub fn is_null(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+// The types that natives can take their parameters as, read from the slots that the parameter takes.
+pub trait FromArguments {
+    fn from_arguments(arguments: &mut Arguments) -> Self;
+}
+
+macro_rules! from_arguments {
+    ($($type:ty => |$arguments:ident| $read:expr),*) => {
+        $(
+            impl FromArguments for $type {
+                fn from_arguments($arguments: &mut Arguments) -> Self {
+                    $read
+                }
+            }
+        )*
+    }
+}
+
+from_arguments!(
+    i32 => |arguments| arguments.next_slot() as i32,
+    i64 => |arguments| arguments.long(),
+    f32 => |arguments| f32::from_bits(arguments.next_slot()),
+    f64 => |arguments| f64::from_bits(arguments.long() as u64),
+    bool => |arguments| arguments.next_slot() != 0,
+    i8 => |arguments| arguments.next_slot() as i8,
+    i16 => |arguments| arguments.next_slot() as i16,
+    u16 => |arguments| arguments.next_slot() as u16,
+    ObjectRef => |arguments| ObjectRef(arguments.reference())
+);
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum NativeValue {
+    Void,
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    Reference(ObjectRef)
+}
+
+// Why a native returned without a value, which is either because it threw or because the heap ran out
+// of room for something it had to allocate.
+#[derive(Debug)]
+pub enum NativeError {
+    Exception(ObjectRef),
+    OutOfMemory(OutOfMemoryError)
+}
+
+impl From<OutOfMemoryError> for NativeError {
+    fn from(error: OutOfMemoryError) -> Self {
+        NativeError::OutOfMemory(error)
+    }
+}
+
+pub type NativeResult = Result<NativeValue, NativeError>;
+
+// The types that natives can return, either on their own or in a result for natives that can throw.
+pub trait IntoNativeResult {
+    fn into_native_result(self) -> NativeResult;
+}
+
+macro_rules! into_native_result {
+    ($($type:ty => |$value:ident| $convert:expr),*) => {
+        $(
+            impl IntoNativeResult for $type {
+                fn into_native_result(self) -> NativeResult {
+                    let $value = self;
+                    Ok($convert)
+                }
+            }
+
+            impl IntoNativeResult for Result<$type, NativeError> {
+                fn into_native_result(self) -> NativeResult {
+                    self.and_then(IntoNativeResult::into_native_result)
+                }
+            }
+        )*
+    }
+}
+
+into_native_result!(
+    () => |_value| NativeValue::Void,
+    i32 => |value| NativeValue::Int(value),
+    i64 => |value| NativeValue::Long(value),
+    f32 => |value| NativeValue::Float(value),
+    f64 => |value| NativeValue::Double(value),
+    bool => |value| NativeValue::Int(value as i32),
+    i8 => |value| NativeValue::Int(value as i32),
+    i16 => |value| NativeValue::Int(value as i32),
+    u16 => |value| NativeValue::Int(value as i32),
+    ObjectRef => |value| NativeValue::Reference(value)
+);
+
+// What a native gets to work with besides its parameters. The references it was given are kept in
+// slots that are roots for as long as it runs, starting with the receiver, if there is one, followed by
+// the reference parameters in order. The slots are updated when the objects move, unlike the ObjectRefs
+// that the native holds, and it can add references of its own to them with push.
+pub struct NativeEnv<'a> {
+    heap: &'a HeapSpace,
+    class: &'a Arc<Class>,
+    roots: Vec<usize>
+}
+
+impl<'a> NativeEnv<'a> {
+    pub fn heap(&self) -> &'a HeapSpace {
+        self.heap
+    }
+
+    // The class that declares the native.
+    pub fn class(&self) -> &Arc<Class> {
+        self.class
+    }
+
+    // Where the object in the slot with the given index is now.
+    pub fn get(&self, index: usize) -> ObjectRef {
+        let offset = *self.roots.get(index)
+            .unwrap_or_else(|| panic!("Invalid native reference! There is no slot {}!", index));
+        ObjectRef(offset)
+    }
+
+    // Keeps the object alive for the rest of the native, returning the index of the slot to get it from.
+    pub fn push(&mut self, reference: ObjectRef) -> usize {
+        self.roots.push(reference.0);
+        self.roots.len() - 1
+    }
+
+    pub fn allocate(&mut self, class: &Arc<Class>) -> Result<ObjectRef, NativeError> {
+        let heap = self.heap;
+        Ok(ObjectRef(heap.allocate_ref(class, self)?.offset()))
+    }
+
+    pub fn new_string(&mut self, value: &str) -> Result<ObjectRef, NativeError> {
+        let (heap, loader) = (self.heap, self.class.loader());
+        let chars = value.encode_utf16().collect::<Vec<_>>();
+        Ok(ObjectRef(new_string(heap, &loader, &chars, self)?))
+    }
+
+    // Creates an exception of the given class with its stack trace filled in, for the native to return
+    // as its error.
+    pub fn throw_new(&mut self, class_name: &str) -> NativeError {
+        self.throw(class_name, None)
+    }
+
+    // Like throw_new, but with the message as the exception's detail message.
+    pub fn throw_with_message(&mut self, class_name: &str, message: &str) -> NativeError {
+        self.throw(class_name, Some(&message.encode_utf16().collect::<Vec<_>>()))
+    }
+
+    fn throw(&mut self, class_name: &str, message: Option<&[u16]>) -> NativeError {
+        let (heap, loader) = (self.heap, self.class.loader());
+        match new_exception(heap, &loader, class_name, message, self) {
+            Ok(exception) => NativeError::Exception(ObjectRef(exception)),
+            Err(error) => NativeError::OutOfMemory(error)
+        }
+    }
+
+    // The java.lang.Class object for the class with the given internal name.
+    pub fn class_mirror(&mut self, class_name: &str) -> Result<ObjectRef, NativeError> {
+        let (heap, loader) = (self.heap, self.class.loader());
+        Ok(ObjectRef(class_mirror(heap, &loader, class_name, self)?))
+    }
+}
+
+impl RootSource for NativeEnv<'_> {
+    fn visit_roots(&mut self, visitor: &mut dyn FnMut(&mut usize)) {
+        self.roots.iter_mut().filter(|root| **root != 0).for_each(visitor);
+    }
+}
+
+pub type NativeFunction = fn(&mut NativeEnv, &mut Arguments) -> NativeResult;
+
+// A native implemented in Rust, which is what the native attribute generates from a function that
+// takes its parameters as Rust types.
+#[derive(Copy, Clone)]
+pub struct NativeMethod {
+    pub class: &'static str,
+    pub name: &'static str,
+    pub descriptor: &'static str,
+    pub function: NativeFunction
+}
+
+// The natives that calls to native methods are dispatched to, keyed by the internal name of the class
+// that declares them along with their name and descriptor. The default registry has the natives that
+// the core classes need.
+pub struct NativeRegistry {
+    natives: RwLock<HashMap<(IStr, IStr, IStr), NativeFunction>>
+}
+
+impl NativeRegistry {
+    pub fn new() -> Self {
+        NativeRegistry { natives: RwLock::new(HashMap::new()) }
+    }
+
+    // Registers the native, replacing any that was registered for the same method.
+    pub fn register(&self, native: NativeMethod) {
+        let key = (IStr::new(native.class), IStr::new(native.name), IStr::new(native.descriptor));
+        self.natives.write().unwrap().insert(key, native.function);
+    }
+
+    pub fn find(&self, class_name: &str, name: &str, descriptor: &str) -> Option<NativeFunction> {
+        let key = (IStr::new(class_name), IStr::new(name), IStr::new(descriptor));
+        self.natives.read().unwrap().get(&key).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.natives.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for NativeRegistry {
+    fn default() -> Self {
+        let registry = NativeRegistry::new();
+        builtins::BUILTINS.iter().for_each(|native| registry.register(*native));
+        registry
+    }
+}
+
+impl Debug for NativeRegistry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NativeRegistry").field("natives", &self.len()).finish()
+    }
+}
+
+// Calls the native registered for the method in Rust, or failing that the one that a loaded library has
+// for it, throwing UnsatisfiedLinkError if there's neither. Natives get frames of their own, so that they
+// show up in stack traces like they do in HotSpot.
+pub(super) fn invoke<'h>(
+    heap: &'h HeapSpace,
+    class: &Arc<Class>,
+    method: &Arc<Method>,
+    parameters: &[u32]
+) -> MethodResult<'h> {
+    let thread = Threads::current();
+    let _entered = thread.as_ref().map(|thread| thread.enter_frame(class, method));
+    let mut env = NativeEnv { heap, class, roots: reference_parameters(method, parameters) };
+
+    let descriptor = class.constant_pool().get_utf8(method.descriptor_index() as usize)
+        .unwrap_or_else(|| panic!("Invalid native method {}! Expected its descriptor to be in constant pool!",
+            method.name()));
+    let loader = class.loader();
+    let result = match loader.natives().find(class.name(), method.name(), &descriptor) {
+        Some(function) => function(&mut env, &mut Arguments::new(parameters)),
+        None => match loader.libraries().find(class.name(), method.name(), &descriptor) {
+            Some(function) => return jni::invoke(heap, class, method, parameters, function),
+            None => Err(env.throw_with_message(UNSATISFIED_LINK_ERROR, &java_signature(class, method)))
+        }
+    };
+    match result {
+        Ok(NativeValue::Void) => MethodResult::Void,
+        Ok(NativeValue::Int(value)) => MethodResult::Integer(value),
+        Ok(NativeValue::Long(value)) => MethodResult::Long(value),
+        Ok(NativeValue::Float(value)) => MethodResult::Float(value),
+        Ok(NativeValue::Double(value)) => MethodResult::Double(value),
+        Ok(NativeValue::Reference(value)) => MethodResult::Reference(heap.get_ref(value.0)),
+        Err(NativeError::Exception(exception)) => {
+            MethodResult::Exception(heap.get_ref(exception.0).expect("Invalid native exception! Cannot throw null!"))
+        }
+        Err(NativeError::OutOfMemory(error)) => MethodResult::OutOfMemory(error)
+    }
+}
+
+// The java.lang.Class object for the class with the given internal name, which is created the first
+// time it's asked for and is the same object from then on.
+pub(super) fn class_mirror(
+    heap: &HeapSpace,
+    loader: &Arc<ClassLoader>,
+    class_name: &str,
+    roots: &mut dyn RootSource
+) -> Result<usize, OutOfMemoryError> {
+    if let Some(mirror) = heap.roots().class_mirror(class_name) {
+        return Ok(mirror);
+    }
+    let class = loader.find_class(CLASS_CLASS_NAME)
+        .unwrap_or_else(|| panic!("Invalid class mirror! Class {} could not be found!", CLASS_CLASS_NAME));
+    let mirror = heap.allocate_ref(&class, roots)?.offset();
+    heap.roots().set_class_mirror(class_name, mirror);
+    Ok(mirror)
+}
+
+// Creates an exception of the given class with its stack trace filled in and the message, if there is
+// one, as its detail message. The class has to be loadable, as there'd be nothing left to throw if it
+// wasn't.
+pub(super) fn new_exception(
+    heap: &HeapSpace,
+    loader: &Arc<ClassLoader>,
+    class_name: &str,
+    message: Option<&[u16]>,
+    roots: &mut dyn RootSource
+) -> Result<usize, OutOfMemoryError> {
+    let class = loader.find_class(class_name)
+        .unwrap_or_else(|| panic!("Invalid native exception! Class {} could not be found!", class_name));
+    let mut message = match message {
+        Some(message) => new_string(heap, loader, message, roots)?,
+        None => 0
+    };
+    let exception = heap.allocate_ref(&class, &mut WithRoot::new(roots, &mut message))?;
+    if message != 0 {
+        let descriptor = FieldDescriptor::parse("Ljava/lang/String;").unwrap();
+        let field = exception.field_offset("detailMessage", &descriptor)
+            .unwrap_or_else(|| panic!("Invalid exception class {}! Expected a field detailMessage!", class_name));
+        exception.set_ref(field, message as u32);
+        heap.write_barrier(exception.offset(), message);
+    }
+    fill_in_stack_trace(heap, exception.offset());
+    Ok(exception.offset())
+}
+
+// Whether the object can be used where the class with the given internal name is expected, which is what
+// checkcast and instanceof check. Null isn't an instance of anything, so it's up to callers to allow it.
+pub(super) fn is_instance_of(heap: &HeapSpace, loader: &Arc<ClassLoader>, offset: usize, class_name: &str) -> bool {
+    loader.is_assignable(&heap.class_name(offset).replace('.', "/"), class_name)
+}
+
+// Whether the value can go in the array of references, which aastore and System.arraycopy check.
+pub(super) fn can_store(heap: &HeapSpace, loader: &Arc<ClassLoader>, array: usize, value: usize) -> bool {
+    if value == 0 {
+        return true;
+    }
+    let array_name = heap.class_name(array).replace('.', "/");
+    let element_name = class_name_of(&array_name[1..])
+        .unwrap_or_else(|| panic!("Invalid array store! Expected {} to be an array of references!", array_name));
+    is_instance_of(heap, loader, value, element_name)
+}
+
+// The method the way HotSpot names it in linkage errors, such as 'void java.lang.Thread.start0()'.
+fn java_signature(class: &Class, method: &Method) -> String {
+    let descriptor = method.descriptor();
+    let parameters = descriptor.parameters().iter().map(FieldDescriptor::java_type).collect::<Vec<_>>();
+    format!("'{} {}.{}({})'", descriptor.return_type().map_or(String::from("void"), FieldDescriptor::java_type),
+            class.name().replace(JVM_SIGNATURE_SEPARATOR, "."), method.name(), parameters.join(", "))
+}
+
+// The slots of the parameters that hold references, starting with the receiver, if there is one.
+fn reference_parameters(method: &Method, parameters: &[u32]) -> Vec<usize> {
+    let mut references = Vec::new();
+    let mut slot = 0;
+    if !method.is_static() {
+        references.push(parameters[0] as usize);
+        slot += 1;
+    }
+    for parameter in method.descriptor().parameters() {
+        if parameter.is_reference() {
+            references.push(parameters[slot] as usize);
+        }
+        slot += parameter.slot_size();
+    }
+    references
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use astatine_macros::native;
+    use crate::assembler::assemble;
+    use crate::code::MethodResult;
+    use crate::constants::JVM_T_INT;
+    use crate::objects::{HeapSpace, NoRoots, Reference};
+    use crate::runtime::{stack_trace, string_value, StackTraceElement, Threads};
+    use crate::test_support::{call, jdk_loader};
+    use crate::types::Class;
+    use crate::utils::descriptors::{FieldDescriptor, FieldType};
+    use super::{NativeEnv, NativeError, ObjectRef};
+
+    const BITS_SOURCE: &str = r#"
+.class Bits
+.super java/lang/Object
+.field value I
+.method public static native scale(IJD)D
+.end method
+.method public static native copy(LBits;)LBits;
+.end method
+.method public static native absent()V
+.end method
+"#;
+
+    const FLOAT_SOURCE: &str = r#"
+.class java/lang/Float
+.super java/lang/Object
+.method public static native floatToRawIntBits(F)I
+.end method
+"#;
+
+    const SYSTEM_SOURCE: &str = r#"
+.class java/lang/System
+.super java/lang/Object
+.method public static native arraycopy(Ljava/lang/Object;ILjava/lang/Object;II)V
+.end method
+"#;
+
+    const CLONEABLE_SOURCE: &str = ".interface java/lang/Cloneable\n.super java/lang/Object\n";
+
+    const OBJECT_SOURCES: [&str; 2] = [
+        ".class java/lang/Object\n.method public final native wait(J)V\n.end method\n\
+            .method public final native notify()V\n.end method\n",
+        ".class java/lang/IllegalMonitorStateException\n.super java/lang/Throwable\n"
+    ];
+
+    const THREAD_SOURCES: [&str; 2] = [
+        r#"
+.class java/lang/Thread
+.super java/lang/Object
+.field daemon Z
+.method public native start0()V
+.end method
+.method public native join()V
+.end method
+.method public native isAlive()Z
+.end method
+.method public run()V
+.limit stack 0
+.limit locals 1
+    return
+.end method
+"#,
+        r#"
+.class Counter
+.super java/lang/Thread
+.field count I
+.method public run()V
+.limit stack 3
+.limit locals 1
+    aload_0
+    dup
+    getfield Counter/count I
+    iconst_1
+    iadd
+    putfield Counter/count I
+    return
+.end method
+"#
+    ];
+
+    // The walker keeps its buffer and the anchor of its walk in fields, like StackStreamFactory's do.
+    const STACK_WALK_SOURCES: [&str; 5] = [
+        r#"
+.class java/lang/StackStreamFactory$AbstractStackWalker
+.super java/lang/Object
+.field mode J
+.field anchor J
+.field frames [Ljava/lang/Object;
+.method private native callStackWalk(JIII[Ljava/lang/Object;)Ljava/lang/Object;
+.end method
+.method private native fetchStackFrames(JJII[Ljava/lang/Object;)I
+.end method
+.method private native doStackWalk(JIIII)Ljava/lang/Object;
+.end method
+"#,
+        concat!(".class java/lang/StackFrameInfo\n.super java/lang/Object\n.field memberName Ljava/lang/Object;\n",
+            ".field bci I\n"),
+        concat!(".class java/lang/invoke/MemberName\n.super java/lang/Object\n.field clazz Ljava/lang/Class;\n",
+            ".field name Ljava/lang/String;\n.field type Ljava/lang/Object;\n.field flags I\n"),
+        ".class App\n.super java/lang/Object\n.method public static main()V\n    return\n.end method\n",
+        ".class Logger\n.super java/lang/Object\n.method public static log(I)V\n    return\n.end method\n"
+    ];
+
+    #[native("Bits", "scale", "(IJD)D")]
+    fn scale(_env: &mut NativeEnv, shift: i32, value: i64, factor: f64) -> Result<f64, NativeError> {
+        Ok((value << shift) as f64 * factor)
+    }
+
+    // Copies the object in to a new one, with a collection in between that moves both of them.
+    #[native("Bits", "copy", "(LBits;)LBits;")]
+    fn copy(env: &mut NativeEnv, original: ObjectRef) -> Result<ObjectRef, NativeError> {
+        let class = Arc::clone(env.class());
+        let copy = env.allocate(&class)?;
+        let copy = env.push(copy);
+        let heap = env.heap();
+        heap.collect(env);
+        assert_ne!(env.get(0), original);
+        let (original, copy) = (heap.get_ref(env.get(0).0).unwrap(), heap.get_ref(env.get(copy).0).unwrap());
+        let value = original.field_offset("value", &FieldDescriptor::new(FieldType::Int, 0)).unwrap();
+        copy.set_int(value, original.get_int(value));
+        Ok(ObjectRef(copy.offset()))
+    }
+
+    // Fetches the rest of the walk in to the walker's buffer a batch at a time, keeping the anchor so that
+    // the test can try to fetch with it once the walk is over. The walker is what the walk returns.
+    #[native("java/lang/StackStreamFactory$AbstractStackWalker", "doStackWalk", "(JIIII)Ljava/lang/Object;")]
+    fn do_stack_walk(
+        env: &mut NativeEnv,
+        this: ObjectRef,
+        anchor: i64,
+        _skip_frames: i32,
+        batch_size: i32,
+        _start_index: i32,
+        end_index: i32
+    ) -> Result<ObjectRef, NativeError> {
+        let (heap, class) = (env.heap(), Arc::clone(env.class()));
+        let walker = heap.get_ref(this.0).unwrap();
+        let long = FieldDescriptor::new(FieldType::Long, 0);
+        walker.set_long(walker.field_offset("anchor", &long).unwrap(), anchor);
+        let mode = walker.get_long(walker.field_offset("mode", &long).unwrap()) as u64;
+        let frames = FieldDescriptor::parse("[Ljava/lang/Object;").unwrap();
+        let frames = env.push(ObjectRef(walker.get_ref(walker.field_offset("frames", &frames).unwrap()) as usize));
+        let mut start = end_index;
+        while (start as usize) < heap.get_ref_array(env.get(frames).0).unwrap().len() {
+            let parameters = [env.get(0).0 as u32, (mode >> 32) as u32, mode as u32, (anchor as u64 >> 32) as u32,
+                anchor as u32, batch_size as u32, start as u32, env.get(frames).0 as u32];
+            start = match heap.holding(env, || call(heap, &class, "fetchStackFrames", &parameters)) {
+                MethodResult::Integer(end) => end,
+                _ => panic!("Expected fetchStackFrames to return where the batch ends!")
+            };
+        }
+        Ok(env.get(0))
+    }
+
+    fn define_classes(sources: &[&str]) -> Vec<Arc<Class>> {
+        let loader = jdk_loader();
+        loader.natives().register(SCALE);
+        loader.natives().register(COPY);
+        sources.iter().map(|source| loader.define_class(assemble(source).unwrap())).collect()
+    }
+
+    #[test]
+    fn dispatches_native_methods_to_registered_natives() {
+        let classes = define_classes(&[BITS_SOURCE, FLOAT_SOURCE]);
+        let heap = HeapSpace::new(1 << 10);
+        let value = (-3i64) as u64;
+        let factor = 1.5f64.to_bits();
+        let parameters = [2, (value >> 32) as u32, value as u32, (factor >> 32) as u32, factor as u32];
+        assert!(matches!(call(&heap, &classes[0], "scale", &parameters),
+            MethodResult::Double(value) if value == -18.0));
+        let bits = (-0.0f32).to_bits();
+        assert!(matches!(call(&heap, &classes[1], "floatToRawIntBits", &[bits]),
+            MethodResult::Integer(value) if value as u32 == bits));
+    }
+
+    #[test]
+    fn keeps_references_up_to_date_when_objects_move() {
+        let classes = define_classes(&[BITS_SOURCE]);
+        let heap = HeapSpace::new(1 << 10);
+        let bits = heap.allocate_ref(&classes[0], &mut NoRoots).unwrap();
+        let value = bits.field_offset("value", &FieldDescriptor::new(FieldType::Int, 0)).unwrap();
+        bits.set_int(value, 42);
+
+        match call(&heap, &classes[0], "copy", &[bits.offset() as u32]) {
+            MethodResult::Reference(copy) => assert_eq!(copy.unwrap().get_int(value), 42),
+            _ => panic!("Expected copy to return a reference!")
+        }
+    }
+
+    #[test]
+    fn throws_unsatisfied_link_error_for_unregistered_natives() {
+        let classes = define_classes(&[BITS_SOURCE]);
+        let heap = Arc::new(HeapSpace::new(1 << 10));
+        let threads = Threads::new(&heap);
+        threads.attach_current_thread("main", false, None);
+
+        let error = match call(&heap, &classes[0], "absent", &[]) {
+            MethodResult::Exception(error) => error,
+            _ => panic!("Expected absent to throw!")
+        };
+        assert_eq!(error.class().name(), "java/lang/UnsatisfiedLinkError");
+        let message = error.field_offset("detailMessage", &FieldDescriptor::parse("Ljava/lang/String;").unwrap());
+        assert_eq!(string_value(&heap, error.get_ref(message.unwrap()) as usize), "'void Bits.absent()'");
+        let expected = StackTraceElement::new("Bits", "absent", None, -2);
+        assert_eq!(&*stack_trace(&heap, error.offset()), [expected]);
+        threads.detach_current_thread();
+    }
+
+    #[test]
+    fn starts_and_joins_threads() {
+        let classes = define_classes(&THREAD_SOURCES);
+        let heap = Arc::new(HeapSpace::new(1 << 10));
+        let threads = Threads::new(&heap);
+        threads.attach_current_thread("main", false, None);
+
+        let counter = heap.allocate_ref(&classes[1], &mut NoRoots).unwrap().offset();
+        assert!(matches!(call(&heap, &classes[0], "isAlive", &[counter as u32]), MethodResult::Integer(0)));
+        assert!(matches!(call(&heap, &classes[0], "start0", &[counter as u32]), MethodResult::Void));
+        assert!(matches!(call(&heap, &classes[0], "join", &[counter as u32]), MethodResult::Void));
+        assert!(matches!(call(&heap, &classes[0], "isAlive", &[counter as u32]), MethodResult::Integer(0)));
+
+        let counter = heap.get_ref(counter).unwrap();
+        let count = counter.field_offset("count", &FieldDescriptor::new(FieldType::Int, 0)).unwrap();
+        assert_eq!(counter.get_int(count), 1);
+        assert!(threads.all().iter().all(|thread| thread.name() == "main"));
+        threads.detach_current_thread();
+    }
+
+    #[test]
+    fn lets_go_of_monitors_while_waiting_for_notify() {
+        let classes = define_classes(&OBJECT_SOURCES);
+        let heap = Arc::new(HeapSpace::new(1 << 10));
+        let threads = Threads::new(&heap);
+        let thread = threads.attach_current_thread("main", false, None);
+        let object = heap.allocate_ref(&classes[0], &mut NoRoots).unwrap().offset();
+        let error = match call(&heap, &classes[0], "notify", &[object as u32]) {
+            MethodResult::Exception(error) => error,
+            _ => panic!("Expected notify to throw without the monitor!")
+        };
+        assert_eq!(error.class().name(), "java/lang/IllegalMonitorStateException");
+
+        assert!(heap.monitor_enter(object, thread.id()) && heap.monitor_enter(object, thread.id()));
+        let notifier = {
+            let (heap, threads, class) = (Arc::clone(&heap), Arc::clone(&threads), Arc::clone(&classes[0]));
+            std::thread::spawn(move || {
+                let thread = threads.attach_current_thread("notifier", false, None);
+                while !heap.monitor_enter(object, thread.id()) {
+                    std::thread::yield_now();
+                }
+                let result = call(&heap, &class, "notify", &[object as u32]);
+                assert!(heap.monitor_exit(object, thread.id()));
+                threads.detach_current_thread();
+                matches!(result, MethodResult::Void)
+            })
+        };
+        let timeout = 60_000u32;
+        assert!(matches!(call(&heap, &classes[0], "wait", &[object as u32, 0, timeout]), MethodResult::Void));
+        assert!(notifier.join().unwrap());
+        // Both times the monitor was entered are back once the wait is over.
+        assert!(heap.monitor_exit(object, thread.id()) && heap.monitor_exit(object, thread.id()));
+        assert!(!heap.monitor_exit(object, thread.id()));
+        threads.detach_current_thread();
+    }
+
+    #[test]
+    fn copies_overlapping_elements_and_checks_bounds() {
+        let classes = define_classes(&[SYSTEM_SOURCE]);
+        let heap = HeapSpace::new(1 << 10);
+        let array = heap.allocate_type_array(JVM_T_INT, 5, &mut NoRoots).unwrap();
+        (0..5).for_each(|index| array.set_int(index, index as i32));
+        let offset = array.offset() as u32;
+
+        assert!(matches!(call(&heap, &classes[0], "arraycopy", &[offset, 0, offset, 1, 4]), MethodResult::Void));
+        assert_eq!((0..5).map(|index| array.get_int(index)).collect::<Vec<_>>(), [0, 0, 1, 2, 3]);
+        let error = match call(&heap, &classes[0], "arraycopy", &[offset, 3, offset, 0, 3]) {
+            MethodResult::Exception(error) => error,
+            _ => panic!("Expected arraycopy to throw!")
+        };
+        assert_eq!(error.class().name(), "java/lang/ArrayIndexOutOfBoundsException");
+    }
+
+    #[test]
+    fn checks_arrays_copied_in_to_arrays_of_arrays() {
+        let classes = define_classes(&[SYSTEM_SOURCE, CLONEABLE_SOURCE]);
+        let (system, cloneable) = (&classes[0], &classes[1]);
+        let loader = system.loader();
+        let throwable = loader.find_class("java/lang/Throwable").unwrap();
+        let exception = loader.find_class("java/lang/IllegalStateException").unwrap();
+        let heap = HeapSpace::new(1 << 12);
+        let ints = heap.allocate_type_array(JVM_T_INT, 1, &mut NoRoots).unwrap().offset() as u32;
+        let exceptions = heap.allocate_ref_array(&exception, &exception, 1, &mut NoRoots).unwrap().offset() as u32;
+        let throwables = heap.allocate_ref_array(&throwable, &throwable, 1, &mut NoRoots).unwrap().offset() as u32;
+        let source = heap.allocate_ref_array(cloneable, cloneable, 3, &mut NoRoots).unwrap();
+        [exceptions, ints, throwables].iter().enumerate().for_each(|(index, array)| source.set(index, *array));
+        let source = source.offset() as u32;
+
+        // Every array is Cloneable, but only arrays of references can go in arrays of Throwable arrays.
+        let all = heap.allocate_ref_array(cloneable, cloneable, 3, &mut NoRoots).unwrap();
+        let parameters = [source, 0, all.offset() as u32, 0, 3];
+        assert!(matches!(call(&heap, system, "arraycopy", &parameters), MethodResult::Void));
+        assert_eq!((0..3).map(|index| all.get(index)).collect::<Vec<_>>(), [exceptions, ints, throwables]);
+        let nested = heap.allocate_nested_ref_array(&throwable, 2, 3, &mut NoRoots).unwrap();
+        assert_eq!(heap.class_name(nested.offset()), "[[Ljava.lang.Throwable;");
+        let error = match call(&heap, system, "arraycopy", &[source, 0, nested.offset() as u32, 0, 3]) {
+            MethodResult::Exception(error) => error,
+            _ => panic!("Expected arraycopy to throw for int[]!")
+        };
+        assert_eq!(error.class().name(), "java/lang/ArrayStoreException");
+        assert_eq!((0..3).map(|index| nested.get(index)).collect::<Vec<_>>(), [exceptions, 0, 0]);
+
+        // Throwable[] isn't an IllegalStateException[], even though it's the other way around.
+        let exception_arrays = heap.allocate_nested_ref_array(&exception, 2, 1, &mut NoRoots).unwrap().offset() as u32;
+        assert!(matches!(call(&heap, system, "arraycopy", &[source, 0, exception_arrays, 0, 1]), MethodResult::Void));
+        assert!(matches!(call(&heap, system, "arraycopy", &[source, 2, exception_arrays, 0, 1]),
+            MethodResult::Exception(_)));
+    }
+    #[test]
+    fn walks_frames_in_batches() {
+        let classes = define_classes(&STACK_WALK_SOURCES);
+        let (walker, info, member_name) = (&classes[0], &classes[1], &classes[2]);
+        let (app, logger) = (&classes[3], &classes[4]);
+        walker.loader().natives().register(DO_STACK_WALK);
+        let heap = Arc::new(HeapSpace::new(1 << 14));
+        let threads = Threads::new(&heap);
+        let thread = threads.attach_current_thread("main", false, None);
+        let main = thread.enter_frame(app, &app.methods()[0]);
+        let log = thread.enter_frame(logger, &logger.methods()[0]);
+        let field = |object: usize, name: &str, descriptor: &str| {
+            let object = heap.get_ref(object).unwrap();
+            (object.field_offset(name, &FieldDescriptor::parse(descriptor).unwrap()).unwrap(), object)
+        };
+        // Walks a batch of a frame at a time, so that every frame after the first is fetched by the walker.
+        let walk = |mode: u64, frames: usize| {
+            let this = heap.allocate_ref(walker, &mut NoRoots).unwrap().offset();
+            let (offset, object) = field(this, "mode", "J");
+            object.set_long(offset, mode as i64);
+            let (offset, object) = field(this, "frames", "[Ljava/lang/Object;");
+            object.set_ref(offset, frames as u32);
+            let parameters = [this as u32, (mode >> 32) as u32, mode as u32, 0, 1, 0, frames as u32];
+            match call(&heap, walker, "callStackWalk", &parameters) {
+                MethodResult::Reference(Reference::Value(result)) => assert_eq!(result.offset(), this),
+                _ => panic!("Expected callStackWalk to return the walker!")
+            }
+            this
+        };
+
+        let class = walker.loader().find_class("java/lang/Class").unwrap();
+        let mirrors = heap.allocate_ref_array(&class, &class, 2, &mut NoRoots).unwrap();
+        let this = walk(0x2, mirrors.offset());
+        let mirror = |name: &str| heap.roots().class_mirror(name).unwrap() as u32;
+        assert_eq!([mirrors.get(0), mirrors.get(1)], [mirror("Logger"), mirror("App")]);
+
+        // Anchors are only good for as long as their walk goes on.
+        let (offset, object) = field(this, "anchor", "J");
+        let anchor = object.get_long(offset) as u64;
+        let parameters = [this as u32, 0, 0x2, (anchor >> 32) as u32, anchor as u32, 1, 0, mirrors.offset() as u32];
+        let error = match call(&heap, walker, "fetchStackFrames", &parameters) {
+            MethodResult::Exception(error) => error,
+            _ => panic!("Expected fetchStackFrames to throw once the walk is over!")
+        };
+        assert_eq!(error.class().name(), "java/lang/InternalError");
+
+        let infos = heap.allocate_ref_array(info, info, 2, &mut NoRoots).unwrap();
+        for index in 0..2 {
+            let info = heap.allocate_ref(info, &mut NoRoots).unwrap();
+            let name = heap.allocate_ref(member_name, &mut NoRoots).unwrap();
+            let field = info.field_offset("memberName", &FieldDescriptor::parse("Ljava/lang/Object;").unwrap());
+            info.set_ref(field.unwrap(), name.offset() as u32);
+            infos.set(index, info.offset() as u32);
+        }
+        walk(0, infos.offset());
+        let member_name = |index: usize| {
+            let (offset, info) = field(infos.get(index) as usize, "memberName", "Ljava/lang/Object;");
+            info.get_ref(offset) as usize
+        };
+        let string = |object: usize, name: &str, descriptor: &str| {
+            let (offset, object) = field(object, name, descriptor);
+            string_value(&heap, object.get_ref(offset) as usize)
+        };
+        let (first, second) = (member_name(0), member_name(1));
+        assert_eq!((string(first, "name", "Ljava/lang/String;"), string(first, "type", "Ljava/lang/Object;")),
+            (String::from("log"), String::from("(I)V")));
+        let (offset, object) = field(first, "clazz", "Ljava/lang/Class;");
+        assert_eq!(object.get_ref(offset), mirror("Logger"));
+        let (offset, object) = field(first, "flags", "I");
+        assert_eq!(object.get_int(offset), 0x10009);
+        assert_eq!(string(second, "name", "Ljava/lang/String;"), "main");
+
+        drop((log, main));
+        threads.detach_current_thread();
+    }
+}
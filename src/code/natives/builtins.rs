@@ -0,0 +1,496 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+
+use std::sync::Arc;
+use std::time::Duration;
+use astatine_macros::native;
+use crate::class_file::ClassLoader;
+use crate::code::{Interpreter, MethodResult};
+use crate::objects::{
+    element_size, HeapSpace, InstanceObject, MemoryOrder, Reference, OBJECT_HEADER_SIZE, REFERENCE_SIZE
+};
+use crate::runtime::{
+    caller_class, fill_in_stack_trace, string_value, FrameBatches, FrameRecord, JavaThread, Monitor, Threads
+};
+use crate::utils::descriptors::{FieldDescriptor, FieldType};
+use super::{can_store, parameter_slots, NativeEnv, NativeError, NativeMethod, ObjectRef};
+
+const NULL_POINTER_EXCEPTION: &str = "java/lang/NullPointerException";
+const ARRAY_STORE_EXCEPTION: &str = "java/lang/ArrayStoreException";
+const ARRAY_INDEX_OUT_OF_BOUNDS_EXCEPTION: &str = "java/lang/ArrayIndexOutOfBoundsException";
+const UNSATISFIED_LINK_ERROR: &str = "java/lang/UnsatisfiedLinkError";
+const ILLEGAL_ARGUMENT_EXCEPTION: &str = "java/lang/IllegalArgumentException";
+const INTERRUPTED_EXCEPTION: &str = "java/lang/InterruptedException";
+const ILLEGAL_MONITOR_STATE_EXCEPTION: &str = "java/lang/IllegalMonitorStateException";
+const INTERNAL_ERROR: &str = "java/lang/InternalError";
+
+// The modes of StackStreamFactory's walks that the natives look at.
+const FILL_CLASS_REFS_ONLY: i64 = 0x2;
+const SHOW_HIDDEN_FRAMES: i64 = 0x20;
+// The flags of a MemberName that say what kind of member it is.
+const IS_METHOD: i32 = 0x10000;
+const IS_CONSTRUCTOR: i32 = 0x20000;
+
+// How long a thread that wakes up from wait to find the monitor held waits before trying to enter it again.
+const MONITOR_RETRY_INTERVAL: Duration = Duration::from_millis(1);
+
+pub(super) const BUILTINS: [NativeMethod; 27] = [
+    GET_CLASS,
+    HASH_CODE,
+    OBJECT_WAIT,
+    OBJECT_NOTIFY,
+    OBJECT_NOTIFY_ALL,
+    IDENTITY_HASH_CODE,
+    ARRAYCOPY,
+    LOAD,
+    LOAD_LIBRARY,
+    CURRENT_THREAD,
+    THREAD_START,
+    THREAD_IS_ALIVE,
+    THREAD_SLEEP,
+    THREAD_YIELD,
+    THREAD_JOIN,
+    THREAD_INTERRUPT,
+    THREAD_CLEAR_INTERRUPT_EVENT,
+    THREAD_SET_PRIORITY,
+    FLOAT_TO_RAW_INT_BITS,
+    INT_BITS_TO_FLOAT,
+    DOUBLE_TO_RAW_LONG_BITS,
+    LONG_BITS_TO_DOUBLE,
+    THROWABLE_FILL_IN_STACK_TRACE,
+    GET_CALLER_CLASS,
+    CHECK_STACK_WALK_MODES,
+    CALL_STACK_WALK,
+    FETCH_STACK_FRAMES
+];
+
+#[native("java/lang/Object", "getClass", "()Ljava/lang/Class;")]
+fn get_class(env: &mut NativeEnv, this: ObjectRef) -> Result<ObjectRef, NativeError> {
+    let class_name = env.heap().class_name(this.0).replace('.', "/");
+    env.class_mirror(&class_name)
+}
+
+#[native("java/lang/Object", "hashCode", "()I")]
+fn hash_code(env: &mut NativeEnv, this: ObjectRef) -> i32 {
+    env.heap().identity_hash(this.0)
+}
+
+// The thread lets go of the monitor for as long as it waits, however many times it had entered it, and
+// enters it that many times again before it returns, even when it was interrupted.
+#[native("java/lang/Object", "wait", "(J)V")]
+fn object_wait(env: &mut NativeEnv, this: ObjectRef, millis: i64) -> Result<(), NativeError> {
+    if millis < 0 {
+        return Err(env.throw_with_message(ILLEGAL_ARGUMENT_EXCEPTION, "timeout value is negative"));
+    }
+    let (threads, thread) = (attached_threads("wait"), monitor_owner(env, this, "wait")?);
+    let heap = env.heap();
+    let timeout = if millis == 0 { None } else { Some(Duration::from_millis(millis as u64)) };
+    let mut count = 0;
+    let result = threads.wait(heap.identity_hash(this.0), timeout, env, || {
+        count = heap.monitor_release(this.0, thread.id());
+        (0..count).for_each(|_| thread.monitor_exited(heap.identity_hash(this.0)));
+    });
+    reenter_monitor(env, &thread, count);
+    result.map_err(|_| env.throw_new(INTERRUPTED_EXCEPTION))
+}
+
+#[native("java/lang/Object", "notify", "()V")]
+fn object_notify(env: &mut NativeEnv, this: ObjectRef) -> Result<(), NativeError> {
+    monitor_owner(env, this, "notify")?;
+    attached_threads("notify").notify(env.heap().identity_hash(this.0));
+    Ok(())
+}
+
+#[native("java/lang/Object", "notifyAll", "()V")]
+fn object_notify_all(env: &mut NativeEnv, this: ObjectRef) -> Result<(), NativeError> {
+    monitor_owner(env, this, "notifyAll")?;
+    attached_threads("notifyAll").notify_all(env.heap().identity_hash(this.0));
+    Ok(())
+}
+
+// The current thread, as long as it holds the monitor of the object, which wait and notify need it to.
+fn monitor_owner(env: &mut NativeEnv, object: ObjectRef, operation: &str) -> Result<Arc<JavaThread>, NativeError> {
+    let thread = Threads::current()
+        .unwrap_or_else(|| panic!("Invalid {}! The current thread isn't attached!", operation));
+    if !env.heap().holds_monitor(object.0, thread.id()) {
+        return Err(env.throw_with_message(ILLEGAL_MONITOR_STATE_EXCEPTION, "current thread is not owner"));
+    }
+    Ok(thread)
+}
+
+// Enters the monitor of the receiver, which can have moved while the thread waited, as many times as wait
+// let go of it, stopping for as long as another thread holds it like monitorenter does.
+fn reenter_monitor(env: &mut NativeEnv, thread: &JavaThread, count: u64) {
+    let heap = env.heap();
+    let mut entered = 0;
+    while entered < count {
+        let offset = env.get(0).0;
+        let monitor = Monitor::new(heap.identity_hash(offset), heap.class_name(offset));
+        if heap.monitor_enter(offset, thread.id()) {
+            thread.monitor_entered(monitor);
+            entered += 1;
+            continue;
+        }
+        thread.monitor_blocked(monitor);
+        heap.blocking(env, || std::thread::sleep(MONITOR_RETRY_INTERVAL));
+    }
+}
+
+#[native("java/lang/System", "identityHashCode", "(Ljava/lang/Object;)I")]
+fn identity_hash_code(env: &mut NativeEnv, object: ObjectRef) -> i32 {
+    if object.is_null() { 0 } else { env.heap().identity_hash(object.0) }
+}
+
+// Copies elements between arrays of the same kind, going through a buffer so that it works when the
+// source and destination overlap. References that can't be stored in the destination stop the copy
+// with ArrayStoreException once the ones before them have been copied, like they do in HotSpot.
+#[native("java/lang/System", "arraycopy", "(Ljava/lang/Object;ILjava/lang/Object;II)V")]
+fn arraycopy(
+    env: &mut NativeEnv,
+    source: ObjectRef,
+    source_position: i32,
+    destination: ObjectRef,
+    destination_position: i32,
+    length: i32
+) -> Result<(), NativeError> {
+    if source.is_null() || destination.is_null() {
+        return Err(env.throw_new(NULL_POINTER_EXCEPTION));
+    }
+    let heap = env.heap();
+    let (source_kind, source_length) = match array_shape(heap, source.0) {
+        Some(shape) => shape,
+        None => return Err(env.throw_new(ARRAY_STORE_EXCEPTION))
+    };
+    let (destination_kind, destination_length) = match array_shape(heap, destination.0) {
+        Some(shape) => shape,
+        None => return Err(env.throw_new(ARRAY_STORE_EXCEPTION))
+    };
+    let references = match (&source_kind, &destination_kind) {
+        (ArrayKind::References, ArrayKind::References) => true,
+        (ArrayKind::Primitives(first), ArrayKind::Primitives(second)) if first == second => false,
+        _ => return Err(env.throw_new(ARRAY_STORE_EXCEPTION))
+    };
+    let in_bounds = |position: i32, array_length: usize| {
+        position >= 0 && length >= 0 && position as i64 + length as i64 <= array_length as i64
+    };
+    if !in_bounds(source_position, source_length) || !in_bounds(destination_position, destination_length) {
+        return Err(env.throw_new(ARRAY_INDEX_OUT_OF_BOUNDS_EXCEPTION));
+    }
+
+    let size = match source_kind {
+        ArrayKind::References => REFERENCE_SIZE,
+        ArrayKind::Primitives(array_type) => element_size(array_type)
+    };
+    let position = |array_position: i32, index: usize| OBJECT_HEADER_SIZE + (array_position as usize + index) * size;
+    let values = (0..length as usize)
+        .map(|index| heap.load(source.0, position(source_position, index), size, MemoryOrder::Plain))
+        .collect::<Vec<_>>();
+    let loader = env.class().loader();
+    let storable = if references {
+        values.iter().take_while(|value| can_store(heap, &loader, destination.0, **value as usize)).count()
+    } else {
+        values.len()
+    };
+    for (index, value) in values.iter().take(storable).enumerate() {
+        heap.store(destination.0, position(destination_position, index), size, *value, MemoryOrder::Plain);
+        if references {
+            heap.write_barrier(destination.0, *value as usize);
+        }
+    }
+    if storable < values.len() {
+        return Err(env.throw_new(ARRAY_STORE_EXCEPTION));
+    }
+    Ok(())
+}
+
+enum ArrayKind {
+    References,
+    Primitives(u8)
+}
+
+// The kind of the array along with its length, or None if the object isn't an array.
+fn array_shape(heap: &HeapSpace, offset: usize) -> Option<(ArrayKind, usize)> {
+    if let Reference::Value(array) = heap.get_ref_array(offset) {
+        return Some((ArrayKind::References, array.len()));
+    }
+    match heap.get_type_array(offset) {
+        Reference::Value(array) => Some((ArrayKind::Primitives(array.array_type()), array.len())),
+        Reference::Null => None
+    }
+}
+
+// Opens the library at the given path, for the natives of the classes that the caller's loader defines. Without
+// a Java caller, such as when the VM loads a library itself, that's System's loader.
+#[native("java/lang/System", "load", "(Ljava/lang/String;)V")]
+fn load(env: &mut NativeEnv, path: ObjectRef) -> Result<(), NativeError> {
+    if path.is_null() {
+        return Err(env.throw_new(NULL_POINTER_EXCEPTION));
+    }
+    let path = string_value(env.heap(), path.0);
+    let loader = caller_loader(env);
+    loader.libraries().load(env.heap(), &loader, path)
+        .map_err(|error| env.throw_with_message(UNSATISFIED_LINK_ERROR, &error.to_string()))
+}
+
+// Like load, but with the library's name mapped to a file name and looked for on the search path.
+#[native("java/lang/System", "loadLibrary", "(Ljava/lang/String;)V")]
+fn load_library(env: &mut NativeEnv, name: ObjectRef) -> Result<(), NativeError> {
+    if name.is_null() {
+        return Err(env.throw_new(NULL_POINTER_EXCEPTION));
+    }
+    let name = string_value(env.heap(), name.0);
+    let loader = caller_loader(env);
+    loader.libraries().load_library(env.heap(), &loader, &name)
+        .map_err(|error| env.throw_with_message(UNSATISFIED_LINK_ERROR, &error.to_string()))
+}
+
+fn caller_loader(env: &NativeEnv) -> Arc<ClassLoader> {
+    caller_class().map_or_else(|| env.class().loader(), |class| class.loader())
+}
+
+#[native("java/lang/Thread", "currentThread", "()Ljava/lang/Thread;")]
+fn current_thread(env: &mut NativeEnv) -> ObjectRef {
+    Threads::current()
+        .and_then(|thread| thread.handle())
+        .map_or(ObjectRef::NULL, |handle| ObjectRef(env.heap().roots().global_handle(handle)))
+}
+
+#[native("java/lang/Thread", "start0", "()V")]
+fn thread_start(_env: &mut NativeEnv, this: ObjectRef) {
+    attached_threads("thread start").start(this.0);
+}
+
+#[native("java/lang/Thread", "isAlive", "()Z")]
+fn thread_is_alive(_env: &mut NativeEnv, this: ObjectRef) -> bool {
+    attached_threads("isAlive").find(this.0).map_or(false, |thread| thread.is_alive())
+}
+
+#[native("java/lang/Thread", "sleep", "(J)V")]
+fn thread_sleep(env: &mut NativeEnv, millis: i64) -> Result<(), NativeError> {
+    if millis < 0 {
+        return Err(env.throw_with_message(ILLEGAL_ARGUMENT_EXCEPTION, "timeout value is negative"));
+    }
+    attached_threads("sleep").sleep(Duration::from_millis(millis as u64), env)
+        .map_err(|_| env.throw_with_message(INTERRUPTED_EXCEPTION, "sleep interrupted"))
+}
+
+#[native("java/lang/Thread", "yield", "()V")]
+fn thread_yield(_env: &mut NativeEnv) {
+    std::thread::yield_now();
+}
+
+// The JDK joins by waiting on the thread object, but a Thread that declares join native gets this,
+// which returns straight away for threads that haven't started or have already terminated.
+#[native("java/lang/Thread", "join", "()V")]
+fn thread_join(env: &mut NativeEnv, this: ObjectRef) -> Result<(), NativeError> {
+    let threads = attached_threads("join");
+    match threads.find(this.0) {
+        Some(thread) => threads.join(&thread, env).map_err(|_| env.throw_new(INTERRUPTED_EXCEPTION)),
+        None => Ok(())
+    }
+}
+
+// Threads that haven't started yet have nothing to interrupt, and Thread.interrupt has already set the
+// object's own interrupted field for them.
+#[native("java/lang/Thread", "interrupt0", "()V")]
+fn thread_interrupt(_env: &mut NativeEnv, this: ObjectRef) {
+    if let Some(thread) = attached_threads("interrupt").find(this.0) {
+        thread.interrupt();
+    }
+}
+
+// Thread.interrupted clears the object's interrupted field and then calls this, which is where the
+// current thread's own flag gets cleared along with it.
+#[native("java/lang/Thread", "clearInterruptEvent", "()V")]
+fn thread_clear_interrupt_event(_env: &mut NativeEnv) {
+    if let Some(thread) = Threads::current() {
+        thread.clear_interrupted();
+    }
+}
+
+// Priorities are left to the OS, which schedules every thread the same.
+#[native("java/lang/Thread", "setPriority0", "(I)V")]
+fn thread_set_priority(_env: &mut NativeEnv, _this: ObjectRef, _priority: i32) {}
+
+fn attached_threads(operation: &str) -> Arc<Threads> {
+    Threads::attached().unwrap_or_else(|| panic!("Invalid {}! The current thread isn't attached!", operation))
+}
+
+#[native("java/lang/Float", "floatToRawIntBits", "(F)I")]
+fn float_to_raw_int_bits(_env: &mut NativeEnv, value: f32) -> i32 {
+    value.to_bits() as i32
+}
+
+#[native("java/lang/Float", "intBitsToFloat", "(I)F")]
+fn int_bits_to_float(_env: &mut NativeEnv, bits: i32) -> f32 {
+    f32::from_bits(bits as u32)
+}
+
+#[native("java/lang/Double", "doubleToRawLongBits", "(D)J")]
+fn double_to_raw_long_bits(_env: &mut NativeEnv, value: f64) -> i64 {
+    value.to_bits() as i64
+}
+
+#[native("java/lang/Double", "longBitsToDouble", "(J)D")]
+fn long_bits_to_double(_env: &mut NativeEnv, bits: i64) -> f64 {
+    f64::from_bits(bits as u64)
+}
+
+// The parameter is a dummy that the JDK passes to tell this apart from the public fillInStackTrace.
+#[native("java/lang/Throwable", "fillInStackTrace", "(I)Ljava/lang/Throwable;")]
+fn throwable_fill_in_stack_trace(env: &mut NativeEnv, this: ObjectRef, _dummy: i32) -> ObjectRef {
+    fill_in_stack_trace(env.heap(), this.0);
+    this
+}
+
+// This native's own frame is in a reflection package, so the caller that's found is the caller of the
+// method that asked.
+#[native("jdk/internal/reflect/Reflection", "getCallerClass", "()Ljava/lang/Class;")]
+fn get_caller_class(env: &mut NativeEnv) -> Result<ObjectRef, NativeError> {
+    match caller_class() {
+        Some(class) => env.class_mirror(class.name()),
+        None => Ok(ObjectRef::NULL)
+    }
+}
+
+// Asked once StackStreamFactory is initialized, to check that the modes above are what it thinks they are.
+#[native("java/lang/StackStreamFactory", "checkStackWalkModes", "()Z")]
+fn check_stack_walk_modes(_env: &mut NativeEnv) -> bool {
+    true
+}
+
+// Starts a walk of the current thread's frames and calls back in to the walker's doStackWalk with the
+// first batch of them in the buffer, along with the anchor that it fetches the rest by for as long as it
+// runs. Whatever doStackWalk returns is what the walk returns.
+#[native("java/lang/StackStreamFactory$AbstractStackWalker", "callStackWalk",
+    "(JIII[Ljava/lang/Object;)Ljava/lang/Object;")]
+fn call_stack_walk(
+    env: &mut NativeEnv,
+    _this: ObjectRef,
+    mode: i64,
+    skip_frames: i32,
+    batch_size: i32,
+    start_index: i32,
+    _frames: ObjectRef
+) -> Result<ObjectRef, NativeError> {
+    let walk = FrameBatches::start(skip_frames.max(0) as usize, mode & SHOW_HIDDEN_FRAMES != 0);
+    let end_index = fill_in_frames(env, mode, walk.anchor(), batch_size, start_index)?;
+    // Filling in the frames can move the walker, so it has to be read back from its slot.
+    let (heap, this) = (env.heap(), env.get(0));
+    let class = heap.get_ref(this.0).expect("Invalid stack walk! The walker is null!").class();
+    let (class, method) = class.find_method(|method| method.name() == "doStackWalk")
+        .unwrap_or_else(|| panic!("Invalid stack walker {}! Expected a method doStackWalk!", class.name()));
+    let mut parameters = vec![this.0 as u32];
+    parameters.extend(parameter_slots(&FieldDescriptor::new(FieldType::Long, 0), walk.anchor() as u64));
+    parameters.extend([skip_frames, batch_size, start_index, end_index].iter().map(|value| *value as u32));
+    match heap.holding(env, || Interpreter::execute(heap, &class, &method, &parameters)) {
+        MethodResult::Reference(Reference::Value(result)) => Ok(ObjectRef(result.offset())),
+        MethodResult::Reference(Reference::Null) => Ok(ObjectRef::NULL),
+        MethodResult::Exception(exception) => Err(NativeError::Exception(ObjectRef(exception.offset()))),
+        MethodResult::OutOfMemory(error) => Err(NativeError::OutOfMemory(error)),
+        _ => panic!("Invalid stack walker {}! Expected doStackWalk to return an object!", class.name())
+    }
+}
+
+// Fills the buffer with the next batch of the walk with the anchor, returning where the batch ends in it.
+#[native("java/lang/StackStreamFactory$AbstractStackWalker", "fetchStackFrames", "(JJII[Ljava/lang/Object;)I")]
+fn fetch_stack_frames(
+    env: &mut NativeEnv,
+    _this: ObjectRef,
+    mode: i64,
+    anchor: i64,
+    batch_size: i32,
+    start_index: i32,
+    _frames: ObjectRef
+) -> Result<i32, NativeError> {
+    fill_in_frames(env, mode, anchor, batch_size, start_index)
+}
+
+// The buffer is in the native's second slot, after the walker. Walks that only want classes are given
+// Class objects, and the rest are given StackFrameInfos that the buffer already holds to fill in.
+fn fill_in_frames(
+    env: &mut NativeEnv,
+    mode: i64,
+    anchor: i64,
+    batch_size: i32,
+    start_index: i32
+) -> Result<i32, NativeError> {
+    let heap = env.heap();
+    let length = match heap.get_ref_array(env.get(1).0) {
+        Reference::Value(frames) => frames.len(),
+        Reference::Null => return Err(env.throw_new(NULL_POINTER_EXCEPTION))
+    };
+    if start_index < 0 || batch_size < 0 || start_index as usize + batch_size as usize > length {
+        return Err(env.throw_with_message(ILLEGAL_ARGUMENT_EXCEPTION, "not enough space in buffers"));
+    }
+    let batch = match FrameBatches::next_batch(anchor, batch_size as usize) {
+        Some(batch) => batch,
+        None => return Err(env.throw_with_message(INTERNAL_ERROR, "doStackWalk: corrupted buffers"))
+    };
+    for (index, frame) in (start_index as usize..).zip(&batch) {
+        if mode & FILL_CLASS_REFS_ONLY != 0 {
+            let mirror = env.class_mirror(frame.class().name())?;
+            let frames = heap.get_ref_array(env.get(1).0).unwrap();
+            frames.set(index, mirror.0 as u32);
+            heap.write_barrier(frames.offset(), mirror.0);
+        } else {
+            fill_in_frame_info(env, index, frame)?;
+        }
+    }
+    Ok(start_index + batch.len() as i32)
+}
+
+// Like HotSpot, this points the MemberName of the StackFrameInfo at the frame's method, but fills in its
+// name and type straight away too, as there's nothing that Java could ask to expand it with.
+fn fill_in_frame_info(env: &mut NativeEnv, index: usize, frame: &FrameRecord) -> Result<(), NativeError> {
+    let (class, method) = (frame.class(), frame.method());
+    let descriptor = class.constant_pool().get_utf8(method.descriptor_index() as usize)
+        .unwrap_or_else(|| panic!("Invalid method {}! Descriptor isn't in constant pool!", method.name()));
+    let mirror = env.class_mirror(class.name())?;
+    let mirror = env.push(mirror);
+    let name = env.new_string(method.name())?;
+    let name = env.push(name);
+    let descriptor = env.new_string(&descriptor)?;
+
+    let heap = env.heap();
+    let info = match heap.get_ref(heap.get_ref_array(env.get(1).0).unwrap().get(index) as usize) {
+        Reference::Value(info) => info,
+        Reference::Null => return Err(env.throw_new(NULL_POINTER_EXCEPTION))
+    };
+    info.set_int(field(&info, "bci", "I"), frame.pc() as i32);
+    let member_name = match heap.get_ref(info.get_ref(field(&info, "memberName", "Ljava/lang/Object;")) as usize) {
+        Reference::Value(member_name) => member_name,
+        Reference::Null => return Err(env.throw_new(NULL_POINTER_EXCEPTION))
+    };
+    let references = [
+        ("clazz", "Ljava/lang/Class;", env.get(mirror)),
+        ("name", "Ljava/lang/String;", env.get(name)),
+        ("type", "Ljava/lang/Object;", descriptor)
+    ];
+    for (name, descriptor, value) in references {
+        member_name.set_ref(field(&member_name, name, descriptor), value.0 as u32);
+        heap.write_barrier(member_name.offset(), value.0);
+    }
+    let kind = if method.is_constructor() { IS_CONSTRUCTOR } else { IS_METHOD };
+    member_name.set_int(field(&member_name, "flags", "I"), method.raw_access_flags() as i32 | kind);
+    Ok(())
+}
+
+fn field(object: &InstanceObject, name: &str, descriptor: &str) -> usize {
+    object.field_offset(name, &FieldDescriptor::parse(descriptor).unwrap())
+        .unwrap_or_else(|| panic!("Invalid {}! Expected a field {}!", object.class().name(), name))
+}
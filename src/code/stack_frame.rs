@@ -16,15 +16,16 @@
 
 use paste::paste;
 use crate::objects::*;
+use crate::types::Method;
 
 macro_rules! get_pop_ref {
-    ($name:ident, $ty:ty) => {
+    ($name:ident, $ty:ident) => {
         paste! {
-            pub fn [<get_local_ $name>](&self, index: usize, heap: &HeapSpace) -> Reference<$ty> {
+            pub fn [<get_local_ $name>]<'h>(&self, index: usize, heap: &'h HeapSpace) -> Reference<$ty<'h>> {
                 StackFrame::get_ref(self.get_local(index), |index| heap.[<get_ $name>](index))
             }
 
-            pub fn [<pop_ $name _op>](&mut self, heap: &HeapSpace) -> Reference<$ty> {
+            pub fn [<pop_ $name _op>]<'h>(&mut self, heap: &'h HeapSpace) -> Reference<$ty<'h>> {
                 StackFrame::get_ref(self.pop_op(), |index| heap.[<get_ $name>](index))
             }
         }
@@ -45,18 +46,52 @@ macro_rules! set_local_push_op {
     }
 }
 
+// Every slot remembers whether it holds a reference, so that the collector can find the roots in a
+// frame precisely rather than guessing from the values.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Slot {
+    value: u32,
+    is_reference: bool
+}
+
+impl Slot {
+    pub fn value(&self) -> u32 {
+        self.value
+    }
+
+    pub fn is_reference(&self) -> bool {
+        self.is_reference
+    }
+}
+
 pub struct StackFrame {
-    local_variables: Vec<u32>,
-    operand_stack: Vec<u32>
+    local_variables: Vec<Slot>,
+    operand_stack: Vec<Slot>
 }
 
 impl StackFrame {
     pub fn new(max_stack: u16, max_locals: u16) -> StackFrame {
-        let local_variables = Vec::with_capacity(max_locals as usize);
+        let local_variables = vec![Slot::default(); max_locals as usize];
         let operand_stack = Vec::with_capacity(max_stack as usize);
         StackFrame { local_variables, operand_stack }
     }
 
+    // Puts the receiver and arguments of an invocation in to the first local variables, with longs
+    // and doubles already split in to two values.
+    pub fn set_parameters(&mut self, method: &Method, parameters: &[u32]) {
+        let mut index = 0;
+        if !method.is_static() {
+            self.set_local_ref(0, parameters[0]);
+            index = 1;
+        }
+        for parameter in method.descriptor().parameters() {
+            for _ in 0..parameter.slot_size() {
+                self.set_slot(index, Slot { value: parameters[index], is_reference: parameter.is_reference() });
+                index += 1;
+            }
+        }
+    }
+
     pub fn get_local_bool(&self, index: usize) -> bool {
         self.get_local(index) != 0
     }
@@ -95,11 +130,15 @@ impl StackFrame {
     get_pop_ref!(type_array, TypeArrayObject);
 
     pub fn get_local_return_address(&self, index: usize) -> Option<u32> {
-        self.local_variables.get(index).map(|value| *value)
+        self.local_variables.get(index).map(|slot| slot.value)
+    }
+
+    pub fn get_local_slot(&self, index: usize) -> Slot {
+        *self.local_variables.get(index).unwrap_or_else(|| panic!("Invalid local variable index {}!", index))
     }
 
     fn get_local(&self, index: usize) -> u32 {
-        *self.local_variables.get(index).expect(&format!("Invalid local variable index {}!", index))
+        self.local_variables.get(index).expect(&format!("Invalid local variable index {}!", index)).value
     }
 
     set_local_push_op!(bool, bool);
@@ -111,21 +150,27 @@ impl StackFrame {
 
     pub fn set_local_long(&mut self, index: usize, value: i64) {
         self.set_local(index, (value >> 32) as u32);
-        self.set_local(index, value as u32);
+        self.set_local(index + 1, value as u32);
     }
 
     pub fn set_local_double(&mut self, index: usize, value: f64) {
         let bits = value.to_bits();
         self.set_local(index, (bits >> 32) as u32);
-        self.set_local(index, bits as u32);
+        self.set_local(index + 1, bits as u32);
     }
 
     pub fn set_local_ref(&mut self, index: usize, value: u32) {
-        self.set_local(index, value);
+        self.set_slot(index, Slot { value, is_reference: true });
     }
 
     fn set_local(&mut self, index: usize, value: u32) {
-        self.local_variables.insert(index, value);
+        self.set_slot(index, Slot { value, is_reference: false });
+    }
+
+    fn set_slot(&mut self, index: usize, slot: Slot) {
+        let local = self.local_variables.get_mut(index)
+            .unwrap_or_else(|| panic!("Invalid local variable index {}!", index));
+        *local = slot;
     }
 
     pub fn push_long_op(&mut self, value: i64) {
@@ -140,19 +185,24 @@ impl StackFrame {
     }
 
     pub fn push_ref_op(&mut self, offset: u32) {
-        self.push_op(offset);
+        self.push_slot_op(Slot { value: offset, is_reference: true });
     }
 
     pub fn push_null_op(&mut self) {
-        self.push_op(0);
+        self.push_ref_op(0);
     }
 
     pub fn push_op(&mut self, value: u32) {
-        self.operand_stack.push(value);
+        self.push_slot_op(Slot { value, is_reference: false });
     }
 
-    pub fn set_op(&mut self, offset: usize, value: u32) {
-        self.operand_stack.insert(self.operand_stack.len() - 1 - offset, value)
+    pub fn push_slot_op(&mut self, slot: Slot) {
+        self.operand_stack.push(slot);
+    }
+
+    pub fn set_slot_op(&mut self, offset: usize, slot: Slot) {
+        let index = self.operand_stack.len() - 1 - offset;
+        self.operand_stack[index] = slot;
     }
 
     pub fn pop_bool_op(&mut self) -> bool {
@@ -179,38 +229,53 @@ impl StackFrame {
         f32::from_bits(self.pop_op())
     }
 
+    // The least significant half is on top, as it was pushed last.
     pub fn pop_long_op(&mut self) -> i64 {
-        parts_to_long(self.pop_op(), self.pop_op())
+        let least = self.pop_op();
+        parts_to_long(self.pop_op(), least)
     }
 
     pub fn pop_double_op(&mut self) -> f64 {
-        parts_to_double(self.pop_op(), self.pop_op())
+        let least = self.pop_op();
+        parts_to_double(self.pop_op(), least)
     }
 
     pub fn pop_op(&mut self) -> u32 {
         self.operand_stack.pop().expect("Nothing left to pop on the stack! If verification \
-            succeeded, this should be impossible!")
+            succeeded, this should be impossible!").value
     }
 
     pub fn peek_op(&self) -> u32 {
-        *self.operand_stack.last().expect("Nothing left to pop on the stack! If verification \
-            succeeded, this should be impossible!")
+        self.get_slot_op(0).value
     }
 
     pub fn get_op(&self, offset: usize) -> u32 {
-        if offset == 0 {
-            return self.peek_op();
-        }
-        *self.operand_stack.get(self.operand_stack.len() - 1 - offset)
-            .expect(&format!("Could not pop element at offset {} from end of stack!", offset))
+        self.get_slot_op(offset).value
+    }
+
+    pub fn get_slot_op(&self, offset: usize) -> Slot {
+        *self.operand_stack.len().checked_sub(offset + 1)
+            .and_then(|index| self.operand_stack.get(index))
+            .unwrap_or_else(|| panic!("Could not pop element at offset {} from end of stack!", offset))
     }
 
     #[inline]
     fn get_ref<T>(offset: u32, mapper: impl Fn(usize) -> Reference<T>) -> Reference<T> {
-        let ref_index = (offset + 1) as usize;
         match offset {
             0 => Reference::Null,
-            _ => mapper(ref_index)
+            _ => mapper(offset as usize)
+        }
+    }
+}
+
+impl RootSource for StackFrame {
+    fn visit_roots(&mut self, visitor: &mut dyn FnMut(&mut usize)) {
+        for slot in self.local_variables.iter_mut().chain(self.operand_stack.iter_mut()) {
+            if slot.is_reference && slot.value != 0 {
+                let mut offset = slot.value as usize;
+                visitor(&mut offset);
+                slot.value = offset as u32;
+            }
         }
     }
 }
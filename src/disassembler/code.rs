@@ -0,0 +1,194 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+
+use std::fmt::{Result, Write};
+use crate::class_file::bytecode::{Instruction, InstructionDecoder};
+use crate::class_file::code::CodeBlock;
+use crate::constants::*;
+use crate::types::ConstantPool;
+use super::{Disassembler, class_comment, with_comment};
+
+// Code lines are indented by 6, so their comments end up 6 columns further along.
+const CODE_INDENT: usize = 6;
+
+pub(super) fn write_code(disassembler: &mut Disassembler, code: &CodeBlock) -> Result {
+    write_instructions(disassembler, code.code())?;
+    let pool = disassembler.pool;
+    let out = &mut *disassembler.out;
+    if !code.exception_handlers().is_empty() {
+        writeln!(out, "      Exception table:")?;
+        writeln!(out, "         from    to  target type")?;
+        for handler in code.exception_handlers().iter() {
+            let catch_type = handler.catch_type().map_or(String::from("any"), |name| format!("Class {}", name));
+            writeln!(out, "{:>14}{:>6}{:>6}   {}", handler.start_pc(), handler.end_pc(), handler.handler_pc(),
+                     catch_type)?;
+        }
+    }
+    for line_numbers in code.line_numbers() {
+        writeln!(out, "      LineNumberTable:")?;
+        for line in line_numbers.iter() {
+            writeln!(out, "        line {}: {}", line.line_number(), line.start_pc())?;
+        }
+    }
+    for local_variables in code.local_variables() {
+        writeln!(out, "      LocalVariableTable:")?;
+        writeln!(out, "        Start  Length  Slot  Name   Signature")?;
+        for local in local_variables.iter() {
+            let descriptor = pool.get_utf8(local.descriptor_index() as usize)
+                .map(|value| value.to_string())
+                .unwrap_or_default();
+            writeln!(out, "{:>13}{:>8}{:>6} {:>5}   {}", local.start_pc(), local.length(), local.index(),
+                     local.name(), descriptor)?;
+        }
+    }
+    for local_variable_types in code.local_variable_types() {
+        writeln!(out, "      LocalVariableTypeTable:")?;
+        writeln!(out, "        Start  Length  Slot  Name   Signature")?;
+        for local in local_variable_types.iter() {
+            writeln!(out, "{:>13}{:>8}{:>6} {:>5}   {}", local.start_pc(), local.length(), local.index(),
+                     local.name(), local.generic_signature().unwrap_or_default())?;
+        }
+    }
+    if let Some(stack_map_table) = code.stack_map_table() {
+        writeln!(out, "      StackMapTable: number_of_entries = {}", stack_map_table.len())?;
+        for frame in stack_map_table.iter() {
+            let frame_type = frame.frame_type();
+            let name = match frame_type {
+                0..=63 => "same",
+                64..=127 => "same_locals_1_stack_item",
+                247 => "same_locals_1_stack_item_extended",
+                248..=250 => "chop",
+                251 => "same_frame_extended",
+                252..=254 => "append",
+                _ => "full_frame"
+            };
+            writeln!(out, "        frame_type = {} /* {} */", frame_type, name)?;
+            if frame_type >= 247 {
+                writeln!(out, "          offset_delta = {}", frame.offset_delta())?;
+            }
+            if (252..=255).contains(&frame_type) {
+                let locals = frame.locals().iter()
+                    .map(|local| verification_type(pool, local.item(), local.offset()))
+                    .collect::<Vec<_>>();
+                writeln!(out, "          locals = {}", type_list(&locals))?;
+            }
+            if (64..=127).contains(&frame_type) || frame_type == 247 || frame_type == 255 {
+                let stack = frame.stack().iter()
+                    .map(|item| verification_type(pool, item.item(), item.offset()))
+                    .collect::<Vec<_>>();
+                writeln!(out, "          stack = {}", type_list(&stack))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_instructions(disassembler: &mut Disassembler, code: &[u8]) -> Result {
+    for decoded in InstructionDecoder::new(code) {
+        let decoded = match decoded {
+            Ok(value) => value,
+            Err(error) => {
+                writeln!(disassembler.out, "{:>10}: <{}>", error.offset(), error.message())?;
+                break;
+            }
+        };
+        let name = decoded.name();
+        let mut line = format!("{:>10}: {}", decoded.offset(), name);
+        let mut comment = None;
+        match decoded.instruction() {
+            // Short forms like iconst_1 and iload_1 have their operand in the opcode
+            Instruction::IConst(value) if decoded.length() > 1 => operand(&mut line, &name, value),
+            Instruction::Load(_, index) | Instruction::Store(_, index) | Instruction::Ret(index)
+                if decoded.length() > 1 => operand(&mut line, &name, index),
+            Instruction::IInc(index, value) => operand(&mut line, &name, format!("{}, {}", index, value)),
+            Instruction::Ldc(index) | Instruction::GetStatic(index) | Instruction::PutStatic(index) |
+            Instruction::GetField(index) | Instruction::PutField(index) | Instruction::InvokeVirtual(index) |
+            Instruction::InvokeSpecial(index) | Instruction::InvokeStatic(index) | Instruction::New(index) |
+            Instruction::ANewArray(index) | Instruction::CheckCast(index) | Instruction::InstanceOf(index) => {
+                comment = Some(disassembler.constant_comment(*index));
+                operand(&mut line, &name, format!("#{}", index));
+            },
+            Instruction::InvokeInterface(index, count) | Instruction::MultiANewArray(index, count) => {
+                comment = Some(disassembler.constant_comment(*index));
+                operand(&mut line, &name, format!("#{},  {}", index, count));
+            },
+            Instruction::InvokeDynamic(index) => {
+                comment = Some(disassembler.constant_comment(*index));
+                operand(&mut line, &name, format!("#{},  0", index));
+            },
+            Instruction::NewArray(array_type) => {
+                operand(&mut line, &name, format!("{:?}", array_type).to_lowercase());
+            },
+            Instruction::If(_, target) | Instruction::IfICmp(_, target) | Instruction::IfACmp(_, target) |
+            Instruction::IfNull(target) | Instruction::IfNonNull(target) | Instruction::Goto(target) |
+            Instruction::Jsr(target) => operand(&mut line, &name, target),
+            Instruction::TableSwitch { default, low, targets } => {
+                write!(line, "   {{ // {} to {}", low, *low as i64 + targets.len() as i64 - 1)?;
+                for (index, target) in targets.iter().enumerate() {
+                    write!(line, "\n{:>24}: {}", *low as i64 + index as i64, target)?;
+                }
+                write!(line, "\n{:>24}: {}\n            }}", "default", default)?;
+            },
+            Instruction::LookupSwitch { default, pairs } => {
+                write!(line, "  {{ // {}", pairs.len())?;
+                for (key, target) in pairs {
+                    write!(line, "\n{:>24}: {}", key, target)?;
+                }
+                write!(line, "\n{:>24}: {}\n            }}", "default", default)?;
+            },
+            _ => {}
+        }
+        let out = &mut *disassembler.out;
+        match comment {
+            Some(comment) => writeln!(out, "{}", with_comment(line, CODE_INDENT, &comment))?,
+            None => writeln!(out, "{}", line)?
+        }
+    }
+    Ok(())
+}
+
+// Pads the instruction name out to the operand column and appends the operand.
+fn operand(line: &mut String, name: &str, value: impl ToString) {
+    line.push_str(&" ".repeat(13usize.saturating_sub(name.len()) + 1));
+    line.push_str(&value.to_string());
+}
+
+fn verification_type(pool: &ConstantPool, item: u8, offset: u16) -> String {
+    match item {
+        JVM_ITEM_TOP => String::from("top"),
+        JVM_ITEM_INTEGER => String::from("int"),
+        JVM_ITEM_FLOAT => String::from("float"),
+        JVM_ITEM_DOUBLE => String::from("double"),
+        JVM_ITEM_LONG => String::from("long"),
+        JVM_ITEM_NULL => String::from("null"),
+        JVM_ITEM_UNINITIALIZED_THIS => String::from("uninitialized_this"),
+        JVM_ITEM_OBJECT => {
+            let name = pool.get_class_name(offset as usize).map(|value| value.to_string()).unwrap_or_default();
+            format!("class {}", class_comment(&name))
+        },
+        JVM_ITEM_UNINITIALIZED => format!("uninitialized {}", offset),
+        _ => String::from("<illegal>")
+    }
+}
+
+fn type_list(types: &[String]) -> String {
+    if types.is_empty() {
+        String::from("[]")
+    } else {
+        format!("[ {} ]", types.join(", "))
+    }
+}
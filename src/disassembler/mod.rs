@@ -0,0 +1,611 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+
+mod code;
+
+use std::fmt::{Result, Write};
+use crate::class_file::attributes::Attribute;
+use crate::constants::*;
+use crate::types::{Class, ConstantPool, Field, Method};
+use crate::types::constant_pool::PoolConstant;
+use crate::types::field::ConstantValue;
+use crate::utils::constants::JAVA_LANG_OBJECT_NAME;
+use crate::utils::descriptors::FieldDescriptor;
+
+// Prints a class in the same format as javap -c -v, with the constant pool, declarations, flags,
+// decoded instructions, and the tables from the code attribute.
+pub fn disassemble(class: &Class, path: &str) -> String {
+    let mut out = String::new();
+    Disassembler { class, pool: class.constant_pool(), out: &mut out }.write_class(path)
+        .expect("Failed to write disassembly!");
+    out
+}
+
+struct Disassembler<'a> {
+    class: &'a Class,
+    pool: &'a ConstantPool,
+    out: &'a mut String
+}
+
+const CLASS_FLAGS: &[(u32, &str)] = &[
+    (JVM_ACC_PUBLIC, "ACC_PUBLIC"), (JVM_ACC_FINAL, "ACC_FINAL"), (JVM_ACC_SUPER, "ACC_SUPER"),
+    (JVM_ACC_INTERFACE, "ACC_INTERFACE"), (JVM_ACC_ABSTRACT, "ACC_ABSTRACT"), (JVM_ACC_SYNTHETIC, "ACC_SYNTHETIC"),
+    (JVM_ACC_ANNOTATION, "ACC_ANNOTATION"), (JVM_ACC_ENUM, "ACC_ENUM"), (JVM_ACC_MODULE, "ACC_MODULE")
+];
+const FIELD_FLAGS: &[(u32, &str)] = &[
+    (JVM_ACC_PUBLIC, "ACC_PUBLIC"), (JVM_ACC_PRIVATE, "ACC_PRIVATE"), (JVM_ACC_PROTECTED, "ACC_PROTECTED"),
+    (JVM_ACC_STATIC, "ACC_STATIC"), (JVM_ACC_FINAL, "ACC_FINAL"), (JVM_ACC_VOLATILE, "ACC_VOLATILE"),
+    (JVM_ACC_TRANSIENT, "ACC_TRANSIENT"), (JVM_ACC_SYNTHETIC, "ACC_SYNTHETIC"), (JVM_ACC_ENUM, "ACC_ENUM")
+];
+const METHOD_FLAGS: &[(u32, &str)] = &[
+    (JVM_ACC_PUBLIC, "ACC_PUBLIC"), (JVM_ACC_PRIVATE, "ACC_PRIVATE"), (JVM_ACC_PROTECTED, "ACC_PROTECTED"),
+    (JVM_ACC_STATIC, "ACC_STATIC"), (JVM_ACC_FINAL, "ACC_FINAL"), (JVM_ACC_SYNCHRONIZED, "ACC_SYNCHRONIZED"),
+    (JVM_ACC_BRIDGE, "ACC_BRIDGE"), (JVM_ACC_VARARGS, "ACC_VARARGS"), (JVM_ACC_NATIVE, "ACC_NATIVE"),
+    (JVM_ACC_ABSTRACT, "ACC_ABSTRACT"), (JVM_ACC_STRICT, "ACC_STRICT"), (JVM_ACC_SYNTHETIC, "ACC_SYNTHETIC")
+];
+
+const ACC_MANDATED: u32 = 0x8000;
+
+// Comments line up at the same column relative to the indentation of the line they're on.
+const COMMENT_COLUMN: usize = 40;
+
+impl Disassembler<'_> {
+    fn write_class(&mut self, path: &str) -> Result {
+        let class = self.class;
+        writeln!(self.out, "Classfile {}", path)?;
+        if let Some(source_file) = class.source_file_name() {
+            writeln!(self.out, "  Compiled from \"{}\"", source_file)?;
+        }
+        writeln!(self.out, "{}", self.class_declaration())?;
+        writeln!(self.out, "  minor version: {}", class.minor_version())?;
+        writeln!(self.out, "  major version: {}", class.major_version())?;
+        writeln!(self.out, "  flags: {}", flags_string(class.raw_access_flags(), CLASS_FLAGS))?;
+        let this_class = format!("  this_class: #{}", class.this_class_index());
+        writeln!(self.out, "{}", with_comment(this_class, 2, class.name()))?;
+        let super_class = format!("  super_class: #{}", class.super_class_index());
+        let super_name = class.super_class_name().map(|value| value.to_string()).unwrap_or_default();
+        writeln!(self.out, "{}", with_comment(super_class, 2, &super_name))?;
+        writeln!(self.out, "  interfaces: {}, fields: {}, methods: {}, attributes: {}",
+                 class.interface_indices().len(), class.fields().len(), class.methods().len(),
+                 self.class_attribute_count())?;
+
+        self.write_constant_pool()?;
+        writeln!(self.out, "{{")?;
+        let mut first = true;
+        for field in class.fields() {
+            if !first {
+                writeln!(self.out)?;
+            }
+            first = false;
+            self.write_field(field)?;
+        }
+        for method in class.methods() {
+            if !first {
+                writeln!(self.out)?;
+            }
+            first = false;
+            self.write_method(method)?;
+        }
+        writeln!(self.out, "}}")?;
+        self.write_class_attributes()
+    }
+
+    fn class_declaration(&self) -> String {
+        let class = self.class;
+        let flags = class.raw_access_flags() as u32;
+        let is_interface = flags & JVM_ACC_INTERFACE != 0;
+        let mut declaration = String::new();
+        if flags & JVM_ACC_PUBLIC != 0 {
+            declaration.push_str("public ");
+        }
+        if !is_interface {
+            if flags & JVM_ACC_FINAL != 0 {
+                declaration.push_str("final ");
+            }
+            if flags & JVM_ACC_ABSTRACT != 0 {
+                declaration.push_str("abstract ");
+            }
+        }
+        declaration.push_str(if is_interface { "interface " } else { "class " });
+        declaration.push_str(&java_name(class.name()));
+        let interfaces = class.interface_indices().iter()
+            .filter_map(|index| self.pool.get_class_name(*index as usize))
+            .map(|name| java_name(&name))
+            .collect::<Vec<_>>();
+        if !is_interface {
+            let super_name = class.super_class_name().filter(|name| name != JAVA_LANG_OBJECT_NAME);
+            if let Some(super_name) = super_name {
+                declaration.push_str(" extends ");
+                declaration.push_str(&java_name(&super_name));
+            }
+        }
+        if !interfaces.is_empty() {
+            declaration.push_str(if is_interface { " extends " } else { " implements " });
+            declaration.push_str(&interfaces.join(", "));
+        }
+        declaration
+    }
+
+    fn class_attribute_count(&self) -> usize {
+        let class = self.class;
+        class.source_file_name().is_some() as usize + !class.inner_classes().is_empty() as usize +
+            !class.record_components().is_empty() as usize + !class.bootstrap_methods().is_empty() as usize +
+            class.attributes().len()
+    }
+
+    fn write_constant_pool(&mut self) -> Result {
+        writeln!(self.out, "Constant pool:")?;
+        let width = format!("#{}", self.pool.len()).len() + 2;
+        for index in 1..=self.pool.len() {
+            let constant = match self.pool.get_constant(index) {
+                Some(value) => value,
+                None => continue
+            };
+            let (kind, operands, comment) = self.describe_constant(constant);
+            let line = format!("{:>width$} = {:<18} {}", format!("#{}", index), kind, operands, width = width);
+            match comment {
+                Some(comment) => writeln!(self.out, "{}", with_comment(line, 2, &comment))?,
+                None => writeln!(self.out, "{}", line.trim_end())?
+            }
+        }
+        Ok(())
+    }
+
+    fn describe_constant(&self, constant: &PoolConstant) -> (&'static str, String, Option<String>) {
+        match constant {
+            PoolConstant::Utf8(value, _) => ("Utf8", escape(value), None),
+            PoolConstant::Int(value) => ("Integer", value.to_string(), None),
+            PoolConstant::Float(value) => ("Float", format!("{}f", float_string(*value as f64)), None),
+            PoolConstant::Long(value) => ("Long", format!("{}l", value), None),
+            PoolConstant::Double(value) => ("Double", format!("{}d", float_string(*value)), None),
+            PoolConstant::Class { name_index } => {
+                ("Class", format!("#{}", name_index), Some(class_comment(&self.utf8(*name_index))))
+            },
+            PoolConstant::String { value_index } => {
+                ("String", format!("#{}", value_index), Some(escape(&self.utf8(*value_index))))
+            },
+            PoolConstant::FieldRef { class_index, nat_index } => {
+                ("Fieldref", format!("#{}.#{}", class_index, nat_index), Some(self.member(*class_index, *nat_index)))
+            },
+            PoolConstant::MethodRef { class_index, nat_index } => {
+                ("Methodref", format!("#{}.#{}", class_index, nat_index), Some(self.member(*class_index, *nat_index)))
+            },
+            PoolConstant::InterfaceMethodRef { class_index, nat_index } => {
+                ("InterfaceMethodref", format!("#{}.#{}", class_index, nat_index),
+                 Some(self.member(*class_index, *nat_index)))
+            },
+            PoolConstant::NameAndType { name_index, descriptor_index } => {
+                ("NameAndType", format!("#{}:#{}", name_index, descriptor_index),
+                 Some(self.name_and_type_of(*name_index, *descriptor_index)))
+            },
+            PoolConstant::MethodHandle { reference_kind, reference_index } => {
+                ("MethodHandle", format!("{}:#{}", reference_kind, reference_index),
+                 Some(self.method_handle(*reference_kind, *reference_index)))
+            },
+            PoolConstant::MethodType { descriptor_index } => {
+                ("MethodType", format!("#{}", descriptor_index), Some(format!(" {}", self.utf8(*descriptor_index))))
+            },
+            PoolConstant::Dynamic { bootstrap_method_index, nat_index } => {
+                ("Dynamic", format!("#{}:#{}", bootstrap_method_index, nat_index),
+                 Some(format!("#{}:{}", bootstrap_method_index, self.name_and_type(*nat_index))))
+            },
+            PoolConstant::InvokeDynamic { bootstrap_method_index, nat_index } => {
+                ("InvokeDynamic", format!("#{}:#{}", bootstrap_method_index, nat_index),
+                 Some(format!("#{}:{}", bootstrap_method_index, self.name_and_type(*nat_index))))
+            },
+            PoolConstant::Module { name_index } => ("Module", format!("#{}", name_index), Some(self.utf8(*name_index))),
+            PoolConstant::Package { name_index } => {
+                ("Package", format!("#{}", name_index), Some(self.utf8(*name_index)))
+            },
+            PoolConstant::Unusable => ("", String::new(), None)
+        }
+    }
+
+    fn utf8(&self, index: u16) -> String {
+        self.pool.get_utf8(index as usize).map(|value| value.to_string()).unwrap_or_default()
+    }
+
+    fn name_and_type_of(&self, name_index: u16, descriptor_index: u16) -> String {
+        format!("{}:{}", quote_name(&self.utf8(name_index)), self.utf8(descriptor_index))
+    }
+
+    fn name_and_type(&self, index: u16) -> String {
+        match self.pool.get_constant(index as usize) {
+            Some(PoolConstant::NameAndType { name_index, descriptor_index }) => {
+                self.name_and_type_of(*name_index, *descriptor_index)
+            },
+            _ => String::new()
+        }
+    }
+
+    fn member(&self, class_index: u16, nat_index: u16) -> String {
+        let owner = self.pool.get_class_name(class_index as usize).map(|value| value.to_string()).unwrap_or_default();
+        format!("{}.{}", class_comment(&owner), self.name_and_type(nat_index))
+    }
+
+    fn method_handle(&self, kind: u8, reference_index: u16) -> String {
+        let reference = match self.pool.get_constant(reference_index as usize) {
+            Some(PoolConstant::FieldRef { class_index, nat_index }) |
+            Some(PoolConstant::MethodRef { class_index, nat_index }) |
+            Some(PoolConstant::InterfaceMethodRef { class_index, nat_index }) => self.member(*class_index, *nat_index),
+            _ => String::new()
+        };
+        format!("{} {}", reference_kind_name(kind), reference)
+    }
+
+    // The comment shown next to instructions and attributes that refer to a constant.
+    fn constant_comment(&self, index: u16) -> String {
+        let constant = match self.pool.get_constant(index as usize) {
+            Some(value) => value,
+            None => return String::from("<invalid>")
+        };
+        match constant {
+            PoolConstant::Int(value) => format!("int {}", value),
+            PoolConstant::Float(value) => format!("float {}f", float_string(*value as f64)),
+            PoolConstant::Long(value) => format!("long {}l", value),
+            PoolConstant::Double(value) => format!("double {}d", float_string(*value)),
+            PoolConstant::Class { name_index } => format!("class {}", class_comment(&self.utf8(*name_index))),
+            PoolConstant::String { value_index } => format!("String {}", escape(&self.utf8(*value_index))),
+            PoolConstant::FieldRef { class_index, nat_index } => {
+                format!("Field {}", self.local_member(*class_index, *nat_index))
+            },
+            PoolConstant::MethodRef { class_index, nat_index } => {
+                format!("Method {}", self.local_member(*class_index, *nat_index))
+            },
+            PoolConstant::InterfaceMethodRef { class_index, nat_index } => {
+                format!("InterfaceMethod {}", self.local_member(*class_index, *nat_index))
+            },
+            PoolConstant::MethodType { descriptor_index } => format!("MethodType {}", self.utf8(*descriptor_index)),
+            PoolConstant::MethodHandle { reference_kind, reference_index } => {
+                format!("MethodHandle {}", self.method_handle(*reference_kind, *reference_index))
+            },
+            other => {
+                let (kind, _, comment) = self.describe_constant(other);
+                format!("{} {}", kind, comment.unwrap_or_default())
+            }
+        }
+    }
+
+    // Members of the class being disassembled are shown without their owner.
+    fn local_member(&self, class_index: u16, nat_index: u16) -> String {
+        let owner = self.pool.get_class_name(class_index as usize);
+        if owner.as_deref() == Some(self.class.name()) {
+            self.name_and_type(nat_index)
+        } else {
+            self.member(class_index, nat_index)
+        }
+    }
+
+    fn write_field(&mut self, field: &Field) -> Result {
+        let flags = field.raw_access_flags() as u32;
+        let mut declaration = String::from("  ");
+        declaration.push_str(&modifiers(flags, &[
+            (JVM_ACC_PUBLIC, "public"), (JVM_ACC_PRIVATE, "private"), (JVM_ACC_PROTECTED, "protected"),
+            (JVM_ACC_STATIC, "static"), (JVM_ACC_FINAL, "final"), (JVM_ACC_VOLATILE, "volatile"),
+            (JVM_ACC_TRANSIENT, "transient")
+        ]));
+        writeln!(self.out, "{}{} {};", declaration, field.descriptor().java_type(), field.name())?;
+        writeln!(self.out, "    descriptor: {}", self.utf8(field.descriptor_index()))?;
+        writeln!(self.out, "    flags: {}", flags_string(field.raw_access_flags(), FIELD_FLAGS))?;
+        if let Some(value) = field.constant_value() {
+            let value = match value {
+                ConstantValue::Integer(value) => format!("int {}", value),
+                ConstantValue::Long(value) => format!("long {}l", value),
+                ConstantValue::Float(value) => format!("float {}f", float_string(*value as f64)),
+                ConstantValue::Double(value) => format!("double {}d", float_string(*value)),
+                ConstantValue::String(value) => format!("String {}", escape(value))
+            };
+            writeln!(self.out, "    ConstantValue: {}", value)?;
+        }
+        if let Some(signature) = field.generic_signature() {
+            let line = format!("    Signature: #{}", field.generic_signature_index());
+            writeln!(self.out, "{}", with_comment(line, 4, signature))?;
+        }
+        write_attributes(self.out, field.attributes(), 4)
+    }
+
+    fn write_method(&mut self, method: &Method) -> Result {
+        writeln!(self.out, "  {};", self.method_declaration(method))?;
+        writeln!(self.out, "    descriptor: {}", self.utf8(method.descriptor_index()))?;
+        writeln!(self.out, "    flags: {}", flags_string(method.raw_access_flags(), METHOD_FLAGS))?;
+        if let Some(code) = method.code() {
+            let is_static = method.raw_access_flags() as u32 & JVM_ACC_STATIC != 0;
+            let args_size = method.descriptor().parameters().len() + !is_static as usize;
+            writeln!(self.out, "    Code:")?;
+            writeln!(self.out, "      stack={}, locals={}, args_size={}", code.max_stack(), code.max_locals(),
+                     args_size)?;
+            code::write_code(self, code)?;
+        }
+        if !method.checked_exception_indices().is_empty() {
+            writeln!(self.out, "    Exceptions:")?;
+            let names = method.checked_exception_indices().iter()
+                .filter_map(|index| self.pool.get_class_name(*index as usize))
+                .map(|name| java_name(&name))
+                .collect::<Vec<_>>();
+            writeln!(self.out, "      throws {}", names.join(", "))?;
+        }
+        if !method.parameters().is_empty() {
+            writeln!(self.out, "    MethodParameters:")?;
+            writeln!(self.out, "      Name                           Flags")?;
+            for parameter in method.parameters() {
+                let flags = modifiers(parameter.raw_access_flags() as u32, &[
+                    (JVM_ACC_FINAL, "final"), (JVM_ACC_SYNTHETIC, "synthetic"), (ACC_MANDATED, "mandated")
+                ]);
+                let line = format!("      {:<30} {}", parameter.name().unwrap_or("<no name>"), flags);
+                writeln!(self.out, "{}", line.trim_end())?;
+            }
+        }
+        if let Some(signature) = method.generic_signature() {
+            let line = format!("    Signature: #{}", method.generic_signature_index());
+            writeln!(self.out, "{}", with_comment(line, 4, signature))?;
+        }
+        write_attributes(self.out, method.attributes(), 4)
+    }
+
+    fn method_declaration(&self, method: &Method) -> String {
+        let flags = method.raw_access_flags() as u32;
+        let mut declaration = modifiers(flags, &[
+            (JVM_ACC_PUBLIC, "public"), (JVM_ACC_PRIVATE, "private"), (JVM_ACC_PROTECTED, "protected"),
+            (JVM_ACC_STATIC, "static"), (JVM_ACC_FINAL, "final"), (JVM_ACC_SYNCHRONIZED, "synchronized"),
+            (JVM_ACC_NATIVE, "native"), (JVM_ACC_ABSTRACT, "abstract"), (JVM_ACC_STRICT, "strictfp")
+        ]);
+        if method.name() == JVM_CLASS_INITIALIZER_NAME {
+            return String::from("static {}");
+        }
+        let descriptor = method.descriptor();
+        if method.name() == JVM_OBJECT_INITIALIZER_NAME {
+            declaration.push_str(&java_name(self.class.name()));
+        } else {
+            declaration.push_str(&descriptor.return_type().map_or(String::from("void"), FieldDescriptor::java_type));
+            declaration.push(' ');
+            declaration.push_str(method.name());
+        }
+        let mut parameters = descriptor.parameters().iter().map(FieldDescriptor::java_type).collect::<Vec<_>>();
+        if flags & JVM_ACC_VARARGS != 0 {
+            if let Some(last) = parameters.last_mut() {
+                if last.ends_with("[]") {
+                    last.truncate(last.len() - 2);
+                    last.push_str("...");
+                }
+            }
+        }
+        declaration.push('(');
+        declaration.push_str(&parameters.join(", "));
+        declaration.push(')');
+        if !method.checked_exception_indices().is_empty() {
+            let names = method.checked_exception_indices().iter()
+                .filter_map(|index| self.pool.get_class_name(*index as usize))
+                .map(|name| java_name(&name))
+                .collect::<Vec<_>>();
+            declaration.push_str(" throws ");
+            declaration.push_str(&names.join(", "));
+        }
+        declaration
+    }
+
+    fn write_class_attributes(&mut self) -> Result {
+        let class = self.class;
+        if let Some(source_file) = class.source_file_name() {
+            writeln!(self.out, "SourceFile: \"{}\"", source_file)?;
+        }
+        if !class.bootstrap_methods().is_empty() {
+            writeln!(self.out, "BootstrapMethods:")?;
+            for (index, method) in class.bootstrap_methods().iter().enumerate() {
+                let handle = match self.pool.get_constant(method.handle_index() as usize) {
+                    Some(PoolConstant::MethodHandle { reference_kind, reference_index }) => {
+                        self.method_handle(*reference_kind, *reference_index)
+                    },
+                    _ => String::new()
+                };
+                writeln!(self.out, "  {}: #{} {}", index, method.handle_index(), handle)?;
+                writeln!(self.out, "    Method arguments:")?;
+                for argument in method.arguments() {
+                    let comment = self.constant_comment(*argument);
+                    // The kind of constant is left off, except for method handles
+                    let value = match comment.split_once(' ') {
+                        Some(("MethodHandle", value)) | Some(("MethodType", value)) | Some(("String", value)) |
+                        Some(("class", value)) => value.to_string(),
+                        Some((_, value)) => value.to_string(),
+                        None => comment.clone()
+                    };
+                    writeln!(self.out, "      #{} {}", argument, value)?;
+                }
+            }
+        }
+        if !class.inner_classes().is_empty() {
+            writeln!(self.out, "InnerClasses:")?;
+            for inner in class.inner_classes() {
+                let flags = inner.raw_access_flags() as u32;
+                let mut line = String::from("  ");
+                line.push_str(&modifiers(flags, &[
+                    (JVM_ACC_PUBLIC, "public"), (JVM_ACC_PRIVATE, "private"), (JVM_ACC_PROTECTED, "protected"),
+                    (JVM_ACC_STATIC, "static"), (JVM_ACC_FINAL, "final")
+                ]));
+                let inner_name = self.pool.get_class_name(inner.index() as usize)
+                    .map(|value| value.to_string())
+                    .unwrap_or_default();
+                let mut comment = String::new();
+                if let Some(name) = inner.name() {
+                    write!(line, "#{}= ", inner.name_index())?;
+                    write!(comment, "{}=", name)?;
+                }
+                write!(line, "#{}", inner.index())?;
+                write!(comment, "class {}", class_comment(&inner_name))?;
+                if inner.outer_index() != 0 {
+                    let outer_name = self.pool.get_class_name(inner.outer_index() as usize)
+                        .map(|value| value.to_string())
+                        .unwrap_or_default();
+                    write!(line, " of #{}", inner.outer_index())?;
+                    write!(comment, " of class {}", class_comment(&outer_name))?;
+                }
+                line.push(';');
+                writeln!(self.out, "{}", with_comment(line, 2, &comment))?;
+            }
+        }
+        if !class.record_components().is_empty() {
+            writeln!(self.out, "Record:")?;
+            for component in class.record_components() {
+                writeln!(self.out, "  {} {};", component.descriptor().java_type(), component.name())?;
+                writeln!(self.out, "    descriptor: {}", self.utf8(component.descriptor_index()))?;
+                writeln!(self.out)?;
+            }
+        }
+        write_attributes(self.out, class.attributes(), 0)
+    }
+}
+
+// Attributes that we don't understand are printed out as hex, like javap does.
+fn write_attributes(out: &mut String, attributes: &[Attribute], indent: usize) -> Result {
+    for attribute in attributes {
+        writeln!(out, "{:indent$}{}: length = 0x{:X} (unknown attribute)", "", attribute.name(), attribute.len(),
+                 indent = indent)?;
+        for chunk in attribute.info().chunks(16) {
+            let bytes = chunk.iter().map(|value| format!("{:02X}", value)).collect::<Vec<_>>();
+            writeln!(out, "{:indent$}  {}", "", bytes.join(" "), indent = indent)?;
+        }
+    }
+    Ok(())
+}
+
+fn with_comment(mut line: String, indent: usize, comment: &str) -> String {
+    let column = indent + COMMENT_COLUMN;
+    if line.len() < column {
+        line.push_str(&" ".repeat(column - line.len()));
+    } else {
+        line.push(' ');
+    }
+    line.push_str("// ");
+    line.push_str(comment);
+    line
+}
+
+fn flags_string(flags: u16, names: &[(u32, &str)]) -> String {
+    let names = names.iter()
+        .filter(|(flag, _)| flags as u32 & flag != 0)
+        .map(|(_, name)| *name)
+        .collect::<Vec<_>>();
+    format!("(0x{:04x}) {}", flags, names.join(", ")).trim_end().to_string()
+}
+
+fn modifiers(flags: u32, names: &[(u32, &str)]) -> String {
+    names.iter()
+        .filter(|(flag, _)| flags & flag != 0)
+        .map(|(_, name)| format!("{} ", name))
+        .collect()
+}
+
+fn java_name(name: &str) -> String {
+    name.replace(JVM_SIGNATURE_SEPARATOR, ".")
+}
+
+// Array classes are quoted, to tell them apart from the descriptors they look like.
+fn class_comment(name: &str) -> String {
+    if name.starts_with(JVM_SIGNATURE_ARRAY) {
+        format!("\"{}\"", name)
+    } else {
+        name.to_string()
+    }
+}
+
+fn quote_name(name: &str) -> String {
+    if name.starts_with('<') {
+        format!("\"{}\"", name)
+    } else {
+        name.to_string()
+    }
+}
+
+fn reference_kind_name(kind: u8) -> &'static str {
+    match kind {
+        JVM_REF_GET_FIELD => "REF_getField",
+        JVM_REF_GET_STATIC => "REF_getStatic",
+        JVM_REF_PUT_FIELD => "REF_putField",
+        JVM_REF_PUT_STATIC => "REF_putStatic",
+        JVM_REF_INVOKE_VIRTUAL => "REF_invokeVirtual",
+        JVM_REF_INVOKE_STATIC => "REF_invokeStatic",
+        JVM_REF_INVOKE_SPECIAL => "REF_invokeSpecial",
+        JVM_REF_NEW_INVOKE_SPECIAL => "REF_newInvokeSpecial",
+        JVM_REF_INVOKE_INTERFACE => "REF_invokeInterface",
+        _ => "REF_unknown"
+    }
+}
+
+fn float_string(value: f64) -> String {
+    if value.is_finite() && value.fract() == 0.0 && value.abs() < 1e16 {
+        format!("{:.1}", value)
+    } else {
+        value.to_string()
+    }
+}
+
+// Control characters and anything outside of ASCII are written as unicode escapes.
+fn escape(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    for unit in value.encode_utf16() {
+        match unit {
+            0x09 => result.push_str("\\t"),
+            0x0A => result.push_str("\\n"),
+            0x0D => result.push_str("\\r"),
+            0x20..=0x7E => result.push(unit as u8 as char),
+            _ => {
+                let _ = write!(result, "\\u{:04x}", unit);
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use std::fs;
+    use std::path::Path;
+    use std::sync::Arc;
+    use crate::class_file::ClassLoader;
+    use crate::class_file::attributes::AttributeRegistry;
+    use crate::types::Class;
+    use super::disassemble;
+
+    fn disassemble_fixture(name: &str) -> String {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures").join(name);
+        let loader = Arc::new(ClassLoader::with_attributes(AttributeRegistry::new(true)));
+        let class = Class::from_bytes(loader, Bytes::from(fs::read(&path).unwrap()));
+        disassemble(&class, name)
+    }
+
+    #[test]
+    fn disassembles_like_javap() {
+        let output = disassemble_fixture("Kitchen.class");
+        let expected = [
+            "  Compiled from \"Kitchen.java\"",
+            "  flags: (0x0021) ACC_PUBLIC, ACC_SUPER",
+            "    #1 = Methodref          #2.#3         // java/lang/Object.\"<init>\":()V",
+            "        19: invokeinterface #23,  2           // InterfaceMethod java/util/List.add:(Ljava/lang/Object;)Z",
+            "         1: lookupswitch  { // 3",
+            "                    1000: 42",
+            "                 default: 45",
+            "             0     6    34   any",
+            "        frame_type = 252 /* append */",
+            "          locals = [ class Kitchen, class \"[I\", double, int, class \"[I\", int, int ]",
+            "            0      16     0  this   LKitchen;",
+            "  public static final #171= #139 of #11;  // Point=class Kitchen$Point of class Kitchen"
+        ];
+        for line in expected {
+            assert!(output.lines().any(|value| value == line), "Missing line {:?} in:\n{}", line, output);
+        }
+    }
+}
@@ -14,25 +14,135 @@
  * Street, Fifth Floor, Boston, MA 02110-1301, USA.
  */
 
-use std::io;
+use std::{env, fs, io};
 use std::sync::Arc;
-use crate::class_file::ClassLoader;
-use crate::types::Class;
-
-pub mod class_file;
-pub mod types;
-pub mod utils;
-pub mod code;
-pub mod objects;
-pub mod constants;
+use bytes::Bytes;
+use astatine::class_file::ClassLoader;
+use astatine::class_file::attributes::AttributeRegistry;
+use astatine::objects::HeapSpace;
+use astatine::runtime::{print_uncaught_exception, Threads, Value, Vm, VmError};
+use astatine::types::Class;
+use astatine::verifier::VerifyMode;
 
 fn main() {
+    let args = env::args().collect::<Vec<_>>();
+    if args.len() == 3 && args[1] == "disassemble" {
+        disassemble(&args[2]);
+        return;
+    }
+    if args.len() >= 3 && args[1] == "run" {
+        run(&args[2], &args[3..]);
+        return;
+    }
     let mut buffer = String::new();
     io::stdin().read_line(&mut buffer).expect("Expected input!");
     let input = buffer.trim_end();
     println!("{}", input);
-    let loader = Arc::new(ClassLoader::new());
-    let class = Arc::new(Class::parse(loader, input)).initialize();
+    let loader = Arc::new(ClassLoader::with_options(AttributeRegistry::default(), verify_mode(&args)));
+    let class = loader.load_class(input);
     println!("{:#?}", class);
     println!("{}", class.is_public());
 }
+
+fn verify_mode(args: &[String]) -> VerifyMode {
+    let value = match args.iter().find_map(|arg| arg.strip_prefix("-Xverify:")) {
+        Some(value) => value,
+        None => return VerifyMode::default()
+    };
+    VerifyMode::parse(value)
+        .unwrap_or_else(|| panic!("Invalid -Xverify option {}! Expected one of none, remote or all!", value))
+}
+
+// Runs the static main method of the class in the given file, with -D options as system properties.
+// Main is given null for its arguments, as they aren't passed on yet.
+fn run(path: &str, options: &[String]) {
+    let bytes = fs::read(path).unwrap_or_else(|error| panic!("Failed to read class file {}! {}", path, error));
+    let mut builder = Vm::builder();
+    builder.verify_mode(verify_mode(options));
+    if let Some(heap_size) = heap_size(options) {
+        builder.heap_size(heap_size);
+    }
+    options.iter().filter_map(|option| option.strip_prefix("-D")).for_each(|property| {
+        let (key, value) = property.split_once('=').unwrap_or((property, ""));
+        builder.property(key, value);
+    });
+    let vm = builder.build().unwrap_or_else(|error| panic!("Failed to create the VM! {}!", error));
+    let class = vm.define_class(Bytes::from(bytes));
+
+    let (heap, threads) = (vm.heap(), vm.threads());
+    threads.attach_current_thread("main", false, None);
+    if options.iter().any(|option| option == "-XX:+HeapDumpOnOutOfMemoryError") {
+        let path = options.iter().find_map(|option| option.strip_prefix("-XX:HeapDumpPath=")).unwrap_or(".");
+        heap.dump_heap_on_out_of_memory(path, io::stdout());
+    }
+    // Like HotSpot, a SIGQUIT, which Ctrl-\ sends from a terminal, prints a dump of every thread, and
+    // the histogram too if it's printed on exit.
+    let print_histogram = options.iter().any(|option| option == "-XX:+PrintClassHistogram");
+    dump_threads_on_signal(threads, heap, print_histogram);
+    let failed = match vm.call_static(class.name(), "main", "([Ljava/lang/String;)V", &[Value::Reference(None)]) {
+        Ok(_) => false,
+        Err(VmError::OutOfMemory(error)) => {
+            eprintln!("Exception in thread \"main\" java.lang.OutOfMemoryError: {}", error);
+            true
+        }
+        Err(VmError::Exception(exception)) => {
+            print_uncaught_exception(heap, "main", exception.throwable().offset());
+            true
+        }
+        Err(error) => panic!("Invalid class {}! {}!", class.name(), error)
+    };
+    // The VM only exits once every non-daemon thread has finished, even when main didn't.
+    threads.wait_for_non_daemon_threads();
+    if print_histogram {
+        println!("{}", heap.histogram());
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+#[cfg(unix)]
+fn dump_threads_on_signal(threads: &Arc<Threads>, heap: &Arc<HeapSpace>, print_histogram: bool) {
+    let mut signals = signal_hook::iterator::Signals::new([signal_hook::consts::SIGQUIT])
+        .unwrap_or_else(|error| panic!("Failed to register a handler for SIGQUIT! {}", error));
+    let threads = Arc::clone(threads);
+    let heap = Arc::clone(heap);
+    std::thread::Builder::new()
+        .name(String::from("Signal Dispatcher"))
+        .spawn(move || {
+            for _ in signals.forever() {
+                threads.request_thread_dump(|dump| println!("{}", dump));
+                if print_histogram {
+                    let heap = Arc::clone(&heap);
+                    threads.submit(move |_| println!("{}", heap.histogram()));
+                }
+            }
+        })
+        .unwrap_or_else(|error| panic!("Failed to start the signal dispatcher! {}", error));
+}
+
+#[cfg(not(unix))]
+fn dump_threads_on_signal(_: &Arc<Threads>, _: &Arc<HeapSpace>, _: bool) {}
+
+fn heap_size(options: &[String]) -> Option<usize> {
+    let value = options.iter().find_map(|option| option.strip_prefix("-Xmx"))?;
+    Some(parse_size(value).unwrap_or_else(|| panic!("Invalid -Xmx option {}! Expected a size like 64m!", value)))
+}
+
+// Parses sizes like the JVM's memory options take, with an optional k, m or g suffix.
+fn parse_size(value: &str) -> Option<usize> {
+    let (number, shift) = match value.chars().last()?.to_ascii_lowercase() {
+        'k' => (&value[..value.len() - 1], 10),
+        'm' => (&value[..value.len() - 1], 20),
+        'g' => (&value[..value.len() - 1], 30),
+        _ => (value, 0)
+    };
+    number.parse::<usize>().ok()?.checked_mul(1 << shift)
+}
+
+fn disassemble(path: &str) {
+    let bytes = fs::read(path).unwrap_or_else(|error| panic!("Failed to read class file {}! {}", path, error));
+    let loader = Arc::new(ClassLoader::with_attributes(AttributeRegistry::new(true)));
+    let class = Class::from_bytes(loader, Bytes::from(bytes));
+    print!("{}", astatine::disassembler::disassemble(&class, path));
+}
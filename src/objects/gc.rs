@@ -0,0 +1,270 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, RwLock};
+use crate::utils::IStr;
+
+// Anything that holds references in to the heap that the collector has to treat as live, like a
+// thread's stack frames. The visitor may change the offsets it is given, as the objects they point
+// to get moved. Offsets may be 0 (null), which the visitor leaves alone.
+pub trait RootSource {
+    fn visit_roots(&mut self, visitor: &mut dyn FnMut(&mut usize));
+}
+
+impl<T: RootSource> RootSource for [T] {
+    fn visit_roots(&mut self, visitor: &mut dyn FnMut(&mut usize)) {
+        self.iter_mut().for_each(|source| source.visit_roots(visitor));
+    }
+}
+
+// The threads that run code against the heap, which hold references in their frames. Before the heap
+// can be collected, every one of them has to be stopped somewhere that those references can be found
+// and updated, which is what this does.
+pub trait Mutators: Send + Sync {
+    // Called by a thread at a safepoint poll with every reference it holds, stopping it there for as
+    // long as another thread needs the world stopped.
+    fn poll(&self, roots: &mut dyn RootSource);
+
+    // Stops every other thread, then runs the operation with the roots of all of them and the ones
+    // given by the calling thread. If another thread is already stopping the world, the calling one
+    // stops for it first.
+    fn stop_the_world(&self, roots: &mut dyn RootSource, operation: &mut dyn FnMut(&mut dyn RootSource));
+
+    // Runs something that might take a while with the calling thread stopped, and its roots left where
+    // a thread stopping the world can get to them, so that it never holds up a safepoint.
+    fn block(&self, roots: &mut dyn RootSource, blocking: &mut dyn FnMut());
+
+    // Counts the calling thread as running again while it's blocked in native code, until reblock is
+    // called. Returns whether it was blocked.
+    fn unblock(&self) -> bool;
+
+    fn reblock(&self);
+
+    // Runs the operation with the roots counted among those of the calling thread until it returns.
+    fn hold(&self, roots: &mut dyn RootSource, operation: &mut dyn FnMut());
+}
+
+// A thread that's running again while it's blocked in native code, which it stops being once this is
+// dropped.
+pub struct Unblocked(pub(super) Option<Arc<dyn Mutators>>);
+
+impl Drop for Unblocked {
+    fn drop(&mut self) {
+        if let Some(mutators) = &self.0 {
+            mutators.reblock();
+        }
+    }
+}
+
+// A source with no roots, for allocating when nothing outside of the heap's own roots is live.
+pub struct NoRoots;
+
+impl RootSource for NoRoots {
+    fn visit_roots(&mut self, _: &mut dyn FnMut(&mut usize)) {}
+}
+
+// The roots of the allocating thread along with one more, for something that's been allocated but
+// isn't reachable from them yet.
+pub struct WithRoot<'a> {
+    roots: &'a mut dyn RootSource,
+    root: &'a mut usize
+}
+
+impl<'a> WithRoot<'a> {
+    pub fn new(roots: &'a mut dyn RootSource, root: &'a mut usize) -> Self {
+        WithRoot { roots, root }
+    }
+}
+
+impl RootSource for WithRoot<'_> {
+    fn visit_roots(&mut self, visitor: &mut dyn FnMut(&mut usize)) {
+        self.roots.visit_roots(visitor);
+        if *self.root != 0 {
+            visitor(self.root);
+        }
+    }
+}
+
+// The roots that live for as long as the VM does rather than for as long as a thread's frame does.
+// Everything in here is keyed by heap offset, and a slot holding 0 holds null.
+pub struct RootSet {
+    statics: RwLock<HashMap<(IStr, IStr), usize>>,
+    interned_strings: RwLock<HashMap<String, usize>>,
+    // Deleted handles leave a free slot, which is different from a handle to null
+    global_handles: RwLock<Vec<Option<usize>>>,
+    class_mirrors: RwLock<HashMap<IStr, usize>>
+}
+
+impl RootSet {
+    pub fn new() -> Self {
+        RootSet {
+            statics: RwLock::new(HashMap::new()),
+            interned_strings: RwLock::new(HashMap::new()),
+            global_handles: RwLock::new(Vec::new()),
+            class_mirrors: RwLock::new(HashMap::new())
+        }
+    }
+
+    pub fn get_static(&self, class_name: &str, field_name: &str) -> usize {
+        let key = (IStr::new(class_name), IStr::new(field_name));
+        self.statics.read().unwrap().get(&key).map_or(0, |offset| *offset)
+    }
+
+    pub fn set_static(&self, class_name: &str, field_name: &str, offset: usize) {
+        let key = (IStr::new(class_name), IStr::new(field_name));
+        self.statics.write().unwrap().insert(key, offset);
+    }
+
+    pub fn interned_string(&self, value: &str) -> Option<usize> {
+        self.interned_strings.read().unwrap().get(value).copied()
+    }
+
+    // Returns the offset of the string that is interned for the value, which will be the given
+    // offset unless the value has already been interned.
+    pub fn intern_string(&self, value: &str, offset: usize) -> usize {
+        *self.interned_strings.write().unwrap().entry(String::from(value)).or_insert(offset)
+    }
+
+    // Returns the index of the new handle, which stays valid until it is deleted.
+    pub fn new_global_handle(&self, offset: usize) -> usize {
+        insert_handle(&mut self.global_handles.write().unwrap(), offset)
+    }
+
+    // A new handle to the object that the handle at the index refers to. It's read and copied under the one
+    // lock, so a collection can't move the object in between and leave the copy pointing at where it was.
+    pub fn copy_global_handle(&self, index: usize) -> usize {
+        let mut handles = self.global_handles.write().unwrap();
+        let offset = handles.get(index).copied().flatten().unwrap_or(0);
+        insert_handle(&mut handles, offset)
+    }
+
+    // Whether the two handles refer to the same object, which like copying reads both under the one lock.
+    pub fn same_global_handles(&self, first: usize, second: usize) -> bool {
+        let handles = self.global_handles.read().unwrap();
+        let offset = |index: usize| handles.get(index).copied().flatten().unwrap_or(0);
+        offset(first) == offset(second)
+    }
+
+    pub fn global_handle(&self, index: usize) -> usize {
+        self.global_handles.read().unwrap().get(index).copied().flatten().unwrap_or(0)
+    }
+
+    pub fn delete_global_handle(&self, index: usize) {
+        if let Some(handle) = self.global_handles.write().unwrap().get_mut(index) {
+            *handle = None;
+        }
+    }
+
+    pub fn class_mirror(&self, class_name: &str) -> Option<usize> {
+        self.class_mirrors.read().unwrap().get(class_name).copied()
+    }
+
+    pub fn set_class_mirror(&self, class_name: &str, offset: usize) {
+        self.class_mirrors.write().unwrap().insert(IStr::new(class_name), offset);
+    }
+
+    // The name of the class that the object at the offset is the mirror of, if it's a mirror at all.
+    pub fn mirrored_class(&self, offset: usize) -> Option<IStr> {
+        self.class_mirrors.read().unwrap().iter().find(|(_, mirror)| **mirror == offset).map(|(name, _)| name.clone())
+    }
+}
+
+impl Default for RootSet {
+    fn default() -> Self {
+        RootSet::new()
+    }
+}
+
+impl RootSet {
+    // Like RootSource::visit_roots, but the set is shared, so it is locked while it's visited.
+    pub(super) fn visit_roots(&self, visitor: &mut dyn FnMut(&mut usize)) {
+        self.statics.write().unwrap().values_mut().for_each(&mut *visitor);
+        self.interned_strings.write().unwrap().values_mut().for_each(&mut *visitor);
+        self.global_handles.write().unwrap().iter_mut().flatten().for_each(&mut *visitor);
+        self.class_mirrors.write().unwrap().values_mut().for_each(&mut *visitor);
+    }
+
+    // The class name, field name and value of every static field that holds a reference.
+    pub(super) fn statics(&self) -> Vec<(IStr, IStr, usize)> {
+        self.statics.read().unwrap().iter()
+            .map(|((class_name, field_name), offset)| (class_name.clone(), field_name.clone(), *offset))
+            .collect()
+    }
+
+    pub(super) fn interned_strings(&self) -> Vec<usize> {
+        self.interned_strings.read().unwrap().values().copied().collect()
+    }
+
+    pub(super) fn global_handles(&self) -> Vec<Option<usize>> {
+        self.global_handles.read().unwrap().clone()
+    }
+
+    pub(super) fn class_mirrors(&self) -> Vec<usize> {
+        self.class_mirrors.read().unwrap().values().copied().collect()
+    }
+}
+
+// Reuses the first free slot for the handle, if there is one.
+fn insert_handle(handles: &mut Vec<Option<usize>>, offset: usize) -> usize {
+    match handles.iter().position(Option::is_none) {
+        Some(index) => {
+            handles[index] = Some(offset);
+            index
+        }
+        None => {
+            handles.push(Some(offset));
+            handles.len() - 1
+        }
+    }
+}
+
+// Thrown when an allocation still doesn't fit in the heap after a full collection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutOfMemoryError {
+    requested: usize,
+    used: usize,
+    maximum_size: usize
+}
+
+impl OutOfMemoryError {
+    pub(super) fn new(requested: usize, used: usize, maximum_size: usize) -> Self {
+        OutOfMemoryError { requested, used, maximum_size }
+    }
+
+    pub fn requested(&self) -> usize {
+        self.requested
+    }
+
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    pub fn maximum_size(&self) -> usize {
+        self.maximum_size
+    }
+}
+
+impl Display for OutOfMemoryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Java heap space: failed to allocate {} bytes with {} of {} bytes in use", self.requested,
+               self.used, self.maximum_size)
+    }
+}
+
+impl Error for OutOfMemoryError {}
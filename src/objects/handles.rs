@@ -15,10 +15,10 @@
  */
 
 use enum_as_inner::EnumAsInner;
-use internship::IStr;
 use std::fmt::Debug;
 use std::sync::Arc;
 use astatine_macros::{Nameable, FieldDescribable, MethodDescribable};
+use crate::utils::IStr;
 use crate::constants::*;
 use crate::types::{Class, ConstantPool};
 use crate::utils::descriptors::{FieldDescriptor, MethodDescriptor};
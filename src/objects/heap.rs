@@ -14,52 +14,754 @@
  * Street, Fifth Floor, Boston, MA 02110-1301, USA.
  */
 
-use std::sync::{Arc, RwLock};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use paste::paste;
+use crate::runtime::StackTrace;
+use crate::types::Class;
+use crate::utils::descriptors::FieldDescriptor;
+use super::gc::{Mutators, OutOfMemoryError, RootSet, RootSource, Unblocked};
+use super::histogram::{self, ClassHistogram};
+use super::hprof;
+use super::memory::MemoryOrder;
 use super::object::*;
 use super::reference::Reference;
+use super::spaces::{Klass, Spaces};
 
-// TODO: Look in to some more low-level allocation here, to maximise performance, minimise footprint,
-//  allow lookups that are not thread-safe, and also so we can actually reserve the memory in
-//  advanced so that no other processes can use our memory.
+// Objects are addressed by offsets, with 0 being null. Collections move objects, so an offset held
+// anywhere that isn't a root or a field of another object goes stale at the next safepoint, and so
+// do the views of objects that the heap hands out.
 pub struct HeapSpace {
-    allocated: RwLock<Vec<HeapEntry>>,
-    maximum_size: usize
+    spaces: RwLock<Spaces>,
+    nursery_size: usize,
+    old_size: usize,
+    nursery_base: usize,
+    roots: RootSet,
+    collection_requested: AtomicBool,
+    references_pending: (Mutex<bool>, Condvar),
+    heap_dump: Mutex<Option<(PathBuf, Box<dyn Write + Send>)>>,
+    mutators: RwLock<Option<Arc<dyn Mutators>>>
 }
 
-macro_rules! ref_get_push {
-    ($name:ident, $type:ty, $entry:ident) => {
+macro_rules! ref_get {
+    ($name:ident, $type:ident, $klass:pat) => {
         paste! {
-            pub fn [<get_ $name>](&self, index: usize) -> Reference<$type> {
-                match self.allocated.read().unwrap().get(index) {
-                    Some(HeapEntry::$entry(object)) => Reference::Value(Arc::clone(object)),
-                    _ => Reference::Null
+            pub fn [<get_ $name>](&self, offset: usize) -> Reference<$type<'_>> {
+                let spaces = self.spaces.read().unwrap();
+                if offset != 0 && spaces.contains(offset) && matches!(spaces.klass(offset), $klass) {
+                    return Reference::Value($type::new(self, offset));
                 }
-            }
-
-            pub fn [<push_ $name>](&self, object: Arc<$type>) {
-                self.allocated.write().unwrap().push(HeapEntry::$entry(object))
+                Reference::Null
             }
         }
     }
 }
 
 impl HeapSpace {
+    // Gives a quarter of the heap to the nursery.
     pub fn new(maximum_size: usize) -> Self {
-        HeapSpace { allocated: RwLock::new(Vec::new()), maximum_size }
+        HeapSpace::with_sizes(maximum_size / 4, maximum_size - maximum_size / 4)
+    }
+
+    // The nursery is split in to two semispaces, only one of which is allocated in at a time.
+    pub fn with_sizes(nursery_size: usize, old_size: usize) -> Self {
+        let spaces = Spaces::new(nursery_size, old_size);
+        HeapSpace {
+            nursery_base: spaces.nursery_base(),
+            spaces: RwLock::new(spaces),
+            nursery_size,
+            old_size,
+            roots: RootSet::new(),
+            collection_requested: AtomicBool::new(false),
+            references_pending: (Mutex::new(false), Condvar::new()),
+            heap_dump: Mutex::new(None),
+            mutators: RwLock::new(None)
+        }
+    }
+
+    pub fn maximum_size(&self) -> usize {
+        self.nursery_size + self.old_size
+    }
+
+    pub fn nursery_size(&self) -> usize {
+        self.nursery_size
+    }
+
+    pub fn old_size(&self) -> usize {
+        self.old_size
+    }
+
+    // The number of bytes taken up by objects that haven't been collected yet.
+    pub fn used(&self) -> usize {
+        self.spaces.read().unwrap().used()
+    }
+
+    pub fn object_count(&self) -> usize {
+        self.spaces.read().unwrap().object_count()
+    }
+
+    pub fn is_young(&self, offset: usize) -> bool {
+        offset >= self.nursery_base
+    }
+
+    pub fn roots(&self) -> &RootSet {
+        &self.roots
+    }
+
+    // Until this is called, the heap assumes that it only ever gets used by one thread at a time.
+    pub fn set_mutators(&self, mutators: Arc<dyn Mutators>) {
+        *self.mutators.write().unwrap() = Some(mutators);
+    }
+
+    ref_get!(ref, InstanceObject, Klass::Instance(_, _, _));
+    ref_get!(ref_array, ReferenceArrayObject, Klass::ReferenceArray(_, _, _));
+    ref_get!(type_array, TypeArrayObject, Klass::TypeArray(_));
+
+    // Allocates an instance of the class with every field set to its default value, collecting first
+    // if it won't fit. The roots are those of the allocating thread, as a collection here can't wait
+    // for a safepoint.
+    pub fn allocate_ref(
+        &self,
+        class: &Arc<Class>,
+        roots: &mut dyn RootSource
+    ) -> Result<InstanceObject<'_>, OutOfMemoryError> {
+        let offset = self.allocate(roots, 0, |spaces| spaces.instance_klass(class))?;
+        Ok(InstanceObject::new(self, offset))
+    }
+
+    pub fn allocate_ref_array(
+        &self,
+        class: &Arc<Class>,
+        element_class: &Arc<Class>,
+        length: usize,
+        roots: &mut dyn RootSource
+    ) -> Result<ReferenceArrayObject<'_>, OutOfMemoryError> {
+        let offset = self.allocate(roots, length, |spaces| spaces.reference_array_klass(class, element_class, 1))?;
+        Ok(ReferenceArrayObject::new(self, offset))
+    }
+
+    // An array of arrays, like String[][] for two dimensions of String, with every element null.
+    pub fn allocate_nested_ref_array(
+        &self,
+        element_class: &Arc<Class>,
+        dimensions: u8,
+        length: usize,
+        roots: &mut dyn RootSource
+    ) -> Result<ReferenceArrayObject<'_>, OutOfMemoryError> {
+        let offset = self.allocate(roots, length, |spaces| {
+            spaces.reference_array_klass(element_class, element_class, dimensions)
+        })?;
+        Ok(ReferenceArrayObject::new(self, offset))
+    }
+
+    // The array type is one of the JVM_T_* constants.
+    pub fn allocate_type_array(
+        &self,
+        array_type: u8,
+        length: usize,
+        roots: &mut dyn RootSource
+    ) -> Result<TypeArrayObject<'_>, OutOfMemoryError> {
+        let offset = self.allocate(roots, length, |spaces| spaces.type_array_klass(array_type))?;
+        Ok(TypeArrayObject::new(self, offset))
+    }
+
+    // Must be called after storing a reference in to a field or array element of an object, so that
+    // minor collections can find old objects that point in to the nursery.
+    pub fn write_barrier(&self, holder: usize, value: usize) {
+        if !self.is_young(holder) && self.is_young(value) {
+            self.spaces.write().unwrap().mark_card(holder);
+        }
+    }
+
+    // Accesses the given number of bytes at a position relative to the start of an object with the
+    // given ordering, which is how volatile fields, VarHandles and Unsafe get at fields and elements.
+    // Storing a reference this way still needs a write barrier afterwards.
+    pub fn load(&self, offset: usize, position: usize, size: usize, order: MemoryOrder) -> u64 {
+        self.spaces.read().unwrap().memory().load(offset * OBJECT_ALIGNMENT + position, size, order)
+    }
+
+    pub fn store(&self, offset: usize, position: usize, size: usize, value: u64, order: MemoryOrder) {
+        self.spaces.read().unwrap().memory().store(offset * OBJECT_ALIGNMENT + position, size, value, order)
+    }
+
+    // Replaces the value if it's the expected one, returning the value that was there before either way.
+    pub fn compare_exchange(
+        &self,
+        offset: usize,
+        position: usize,
+        size: usize,
+        expected: u64,
+        value: u64,
+        order: MemoryOrder
+    ) -> Result<u64, u64> {
+        let spaces = self.spaces.read().unwrap();
+        spaces.memory().compare_exchange(offset * OBJECT_ALIGNMENT + position, size, expected, value, order)
+    }
+
+    pub fn get_and_add(&self, offset: usize, position: usize, size: usize, delta: u64, order: MemoryOrder) -> u64 {
+        self.spaces.read().unwrap().memory().get_and_add(offset * OBJECT_ALIGNMENT + position, size, delta, order)
+    }
+
+    pub fn get_and_set(&self, offset: usize, position: usize, size: usize, value: u64, order: MemoryOrder) -> u64 {
+        self.spaces.read().unwrap().memory().get_and_set(offset * OBJECT_ALIGNMENT + position, size, value, order)
+    }
+
+    // What Object.hashCode and System.identityHashCode return, which stays the same even as the object
+    // gets moved around. Null's hash code is 0.
+    pub fn identity_hash(&self, offset: usize) -> i32 {
+        if offset == 0 {
+            return 0;
+        }
+        self.spaces.write().unwrap().identity_hash(offset)
+    }
+
+    // Enters the monitor of the object for the owner, which is the id of the thread entering it, or
+    // returns false if another thread holds it.
+    pub fn monitor_enter(&self, offset: usize, owner: u64) -> bool {
+        self.spaces.write().unwrap().monitor_enter(offset, owner)
+    }
+
+    // Returns false if the monitor of the object isn't held by the owner.
+    pub fn monitor_exit(&self, offset: usize, owner: u64) -> bool {
+        self.spaces.write().unwrap().monitor_exit(offset, owner)
+    }
+
+    // The name of the object's class in the form that Class.getName returns, which works for arrays
+    // as well as instances.
+    pub fn class_name(&self, offset: usize) -> String {
+        histogram::class_name(self.spaces.read().unwrap().klass(offset))
+    }
+
+    // Runs something that might take a while, like waiting for a monitor, with the calling thread
+    // stopped, so that the rest of the world can carry on collecting without it.
+    pub fn blocking<R>(&self, roots: &mut dyn RootSource, blocking: impl FnOnce() -> R) -> R {
+        let mutators = match self.mutators() {
+            Some(mutators) => mutators,
+            None => return blocking()
+        };
+        let mut blocking = Some(blocking);
+        let mut result = None;
+        mutators.block(roots, &mut || {
+            let blocking = blocking.take().unwrap_or_else(|| panic!("Invalid block! Operation ran twice!"));
+            result = Some(blocking());
+        });
+        result.unwrap_or_else(|| panic!("Invalid block! Operation never ran!"))
+    }
+
+    // The other side of blocking, for code that native code calls to use the heap while the thread is
+    // blocked, like the JNI functions. The thread counts as running again until what's returned is dropped.
+    pub fn unblocked(&self) -> Unblocked {
+        Unblocked(self.mutators().filter(|mutators| mutators.unblock()))
+    }
+
+    // Runs the operation with the roots kept up to date for as long as it runs, for references that the
+    // calling thread holds while it runs code that can collect without them, like interpreted code.
+    pub fn holding<R>(&self, roots: &mut dyn RootSource, operation: impl FnOnce() -> R) -> R {
+        let mutators = match self.mutators() {
+            Some(mutators) => mutators,
+            None => return operation()
+        };
+        let mut operation = Some(operation);
+        let mut result = None;
+        mutators.hold(roots, &mut || {
+            let operation = operation.take().unwrap_or_else(|| panic!("Invalid hold! Operation ran twice!"));
+            result = Some(operation());
+        });
+        result.unwrap_or_else(|| panic!("Invalid hold! Operation never ran!"))
+    }
+
+    // Asks for a collection at the next safepoint.
+    pub fn request_collection(&self) {
+        self.collection_requested.store(true, Ordering::SeqCst);
+    }
+
+    // Called by threads at safepoint polls, where every reference they hold is in their frames.
+    pub fn safepoint(&self, roots: &mut dyn RootSource) {
+        if let Some(mutators) = self.mutators() {
+            mutators.poll(roots);
+        }
+        if self.collection_requested.swap(false, Ordering::SeqCst) {
+            self.collect(roots);
+        }
+    }
+
+    // Collects the whole heap, returning the number of bytes that were freed.
+    pub fn collect(&self, roots: &mut dyn RootSource) -> usize {
+        self.with_world_stopped(roots, |spaces, roots| {
+            let freed = spaces.collect(&mut self.root_visitor(roots), false);
+            self.references_discovered(spaces);
+            freed
+        })
+    }
+
+    // Collects just the nursery, unless the old generation is too full to take what would be
+    // promoted, in which case the whole heap is collected. Returns the number of bytes freed.
+    pub fn collect_nursery(&self, roots: &mut dyn RootSource) -> usize {
+        self.with_world_stopped(roots, |spaces, roots| self.collect_nursery_or_all(spaces, roots))
+    }
+
+    // Waits for up to the timeout for collections to clear references, returning whether any have been
+    // since the last time this was called. This is what the reference handler thread sleeps in.
+    pub fn wait_for_pending_references(&self, roots: &mut dyn RootSource, timeout: Duration) -> bool {
+        self.blocking(roots, || {
+            let (pending, discovered) = &self.references_pending;
+            let pending = pending.lock().unwrap();
+            let (mut pending, _) = discovered.wait_timeout_while(pending, timeout, |pending| !*pending).unwrap();
+            std::mem::replace(&mut *pending, false)
+        })
+    }
+
+    // Takes the references that collections have cleared off of the pending list, for the reference
+    // handler to enqueue. They're only kept alive by whatever the caller keeps them in from then on.
+    pub fn take_pending_references(&self) -> Vec<usize> {
+        self.spaces.write().unwrap().take_pending_references()
+    }
+
+    // Returns how many times the owner had entered the monitor of the object, after letting go of it
+    // every one of those times, for Object.wait. That's 0 if the owner doesn't hold it.
+    pub fn monitor_release(&self, offset: usize, owner: u64) -> u64 {
+        self.spaces.write().unwrap().monitor_release(offset, owner)
+    }
+
+    pub fn holds_monitor(&self, offset: usize, owner: u64) -> bool {
+        self.spaces.read().unwrap().holds_monitor(offset, owner)
+    }
+
+    // Keeps the stack trace of a throwable for as long as the throwable lives, replacing any that it
+    // already had, like Throwable.fillInStackTrace does.
+    pub fn set_stack_trace(&self, offset: usize, stack_trace: StackTrace) {
+        self.spaces.write().unwrap().set_stack_trace(offset, stack_trace);
+    }
+
+    pub fn stack_trace(&self, offset: usize) -> Option<StackTrace> {
+        self.spaces.read().unwrap().stack_trace(offset)
+    }
+
+    // Counts everything in the heap by class, including objects that are no longer reachable but
+    // haven't been collected yet.
+    pub fn histogram(&self) -> ClassHistogram {
+        ClassHistogram::new(&self.spaces.read().unwrap(), self.maximum_size())
+    }
+
+    // Writes everything in the heap out in the HPROF format, with the given roots as those of the
+    // thread asking for the dump.
+    pub fn dump_heap(&self, out: &mut dyn Write, roots: &mut dyn RootSource) -> io::Result<()> {
+        self.with_world_stopped(roots, |spaces, roots| hprof::write_heap_dump(spaces, &self.roots, roots, out))
+    }
+
+    // Makes the first allocation that runs out of memory dump the heap to the given file, or to a file
+    // named after the process in it if it's a directory, like -XX:+HeapDumpOnOutOfMemoryError does.
+    // How the dump went is reported to the log, as nothing else would get to hear about it.
+    pub fn dump_heap_on_out_of_memory(&self, path: impl Into<PathBuf>, log: impl Write + Send + 'static) {
+        *self.heap_dump.lock().unwrap() = Some((path.into(), Box::new(log)));
     }
 
-    pub fn len(&self) -> usize {
-        self.allocated.read().unwrap().len()
+    // Reads the given number of bytes at a position relative to the start of an object.
+    pub(super) fn read(&self, offset: usize, position: usize, size: usize) -> u64 {
+        self.spaces.read().unwrap().memory().read(offset * OBJECT_ALIGNMENT + position, size)
     }
 
-    ref_get_push!(ref, InstanceObject, Instance);
-    ref_get_push!(ref_array, ReferenceArrayObject, ReferenceArray);
-    ref_get_push!(type_array, TypeArrayObject, TypeArray);
+    pub(super) fn write(&self, offset: usize, position: usize, size: usize, value: u64) {
+        self.spaces.read().unwrap().memory().write(offset * OBJECT_ALIGNMENT + position, size, value)
+    }
+
+    pub(super) fn class_of(&self, offset: usize) -> Arc<Class> {
+        match self.spaces.read().unwrap().klass(offset) {
+            Klass::Instance(class, _, _) | Klass::ReferenceArray(class, _, _) => Arc::clone(class),
+            Klass::TypeArray(array_type) => panic!("Invalid class lookup for array of type {}!", array_type)
+        }
+    }
+
+    pub(super) fn field_of(
+        &self,
+        offset: usize,
+        name: &str,
+        descriptor: &FieldDescriptor
+    ) -> Option<(usize, MemoryOrder)> {
+        match self.spaces.read().unwrap().klass(offset) {
+            Klass::Instance(_, layout, _) => layout.find_field(name, descriptor),
+            _ => panic!("Invalid field lookup for array at {}!", offset)
+        }
+    }
+
+    pub(super) fn element_class_of(&self, offset: usize) -> Arc<Class> {
+        match self.spaces.read().unwrap().klass(offset) {
+            Klass::ReferenceArray(_, element_class, _) => Arc::clone(element_class),
+            _ => panic!("Invalid element class lookup for object at {}!", offset)
+        }
+    }
+
+    pub(super) fn dimensions_of(&self, offset: usize) -> u8 {
+        match self.spaces.read().unwrap().klass(offset) {
+            Klass::ReferenceArray(_, _, dimensions) => *dimensions,
+            _ => panic!("Invalid dimensions lookup for object at {}!", offset)
+        }
+    }
+
+    pub(super) fn array_type_of(&self, offset: usize) -> u8 {
+        match self.spaces.read().unwrap().klass(offset) {
+            Klass::TypeArray(array_type) => *array_type,
+            _ => panic!("Invalid array type lookup for object at {}!", offset)
+        }
+    }
+
+    pub(super) fn length_of(&self, offset: usize) -> usize {
+        self.spaces.read().unwrap().length(offset)
+    }
+
+    fn collect_nursery_or_all(&self, spaces: &mut Spaces, roots: &mut dyn RootSource) -> usize {
+        let mut visitor = self.root_visitor(roots);
+        let freed = match spaces.collect_nursery(&mut visitor) {
+            Some(freed) => freed,
+            None => spaces.collect(&mut visitor, false)
+        };
+        self.references_discovered(spaces);
+        freed
+    }
+
+    fn references_discovered(&self, spaces: &Spaces) {
+        if spaces.has_pending_references() {
+            let (pending, discovered) = &self.references_pending;
+            *pending.lock().unwrap() = true;
+            discovered.notify_all();
+        }
+    }
+
+    fn mutators(&self) -> Option<Arc<dyn Mutators>> {
+        self.mutators.read().unwrap().as_ref().map(Arc::clone)
+    }
+
+    // Runs the operation with every other thread stopped, and with the roots of all of them along with
+    // the given ones. The lock on the spaces is only taken once they have stopped, as a thread can't
+    // stop while it's waiting for it.
+    fn with_world_stopped<R>(
+        &self,
+        roots: &mut dyn RootSource,
+        operation: impl FnOnce(&mut Spaces, &mut dyn RootSource) -> R
+    ) -> R {
+        let mutators = match self.mutators() {
+            Some(mutators) => mutators,
+            None => return operation(&mut self.spaces.write().unwrap(), roots)
+        };
+        let mut operation = Some(operation);
+        let mut result = None;
+        mutators.stop_the_world(roots, &mut |roots| {
+            let operation = operation.take().unwrap_or_else(|| panic!("Invalid safepoint! Operation ran twice!"));
+            result = Some(operation(&mut self.spaces.write().unwrap(), roots));
+        });
+        result.unwrap_or_else(|| panic!("Invalid safepoint! Operation never ran!"))
+    }
+
+    fn root_visitor<'a>(&'a self, roots: &'a mut dyn RootSource) -> impl FnMut(&mut dyn FnMut(&mut usize)) + 'a {
+        move |visitor| {
+            self.roots.visit_roots(visitor);
+            roots.visit_roots(visitor);
+        }
+    }
+
+    // Finds room for an object, first with a minor collection and then with a full one if there
+    // isn't any, returning its offset. Soft references are only cleared by one last full collection
+    // before giving up.
+    fn allocate(
+        &self,
+        roots: &mut dyn RootSource,
+        length: usize,
+        klass: impl FnOnce(&mut Spaces) -> u32
+    ) -> Result<usize, OutOfMemoryError> {
+        let klass = {
+            let mut spaces = self.spaces.write().unwrap();
+            let klass = klass(&mut spaces);
+            if let Some(offset) = spaces.allocate(klass, length) {
+                return Ok(offset);
+            }
+            klass
+        };
+        self.with_world_stopped(roots, |spaces, roots| self.allocate_slow(spaces, roots, klass, length))
+    }
+
+    fn allocate_slow(
+        &self,
+        spaces: &mut Spaces,
+        roots: &mut dyn RootSource,
+        klass: u32,
+        length: usize
+    ) -> Result<usize, OutOfMemoryError> {
+        // Another thread might have collected while this one was waiting for the world to stop.
+        if let Some(offset) = spaces.allocate(klass, length) {
+            return Ok(offset);
+        }
+        if spaces.nursery_used() > 0 {
+            self.collect_nursery_or_all(spaces, roots);
+            if let Some(offset) = spaces.allocate(klass, length) {
+                return Ok(offset);
+            }
+        }
+        for clear_soft_references in [false, true] {
+            spaces.collect(&mut self.root_visitor(roots), clear_soft_references);
+            self.references_discovered(spaces);
+            if let Some(offset) = spaces.allocate(klass, length) {
+                return Ok(offset);
+            }
+        }
+        if let Some((path, mut log)) = self.heap_dump.lock().unwrap().take() {
+            // The allocation fails either way, so there's nothing to be done if the log can't be written.
+            let _ = self.dump_heap_to_file(spaces, roots, path, &mut log);
+        }
+        Err(OutOfMemoryError::new(spaces.object_size(klass, length), spaces.used(), self.maximum_size()))
+    }
+
+    // Reports how the dump went to the log in the same way that HotSpot does on the console.
+    fn dump_heap_to_file(
+        &self,
+        spaces: &Spaces,
+        roots: &mut dyn RootSource,
+        mut path: PathBuf,
+        log: &mut dyn Write
+    ) -> io::Result<()> {
+        if path.is_dir() {
+            path.push(format!("java_pid{}.hprof", std::process::id()));
+        }
+        writeln!(log, "Dumping heap to {} ...", path.display())?;
+        let start = Instant::now();
+        let result = File::create(&path).and_then(|file| {
+            hprof::write_heap_dump(spaces, &self.roots, roots, &mut BufWriter::new(file))?;
+            std::fs::metadata(&path).map(|metadata| metadata.len())
+        });
+        match result {
+            Ok(size) => {
+                writeln!(log, "Heap dump file created [{} bytes in {:.3} secs]", size, start.elapsed().as_secs_f64())
+            }
+            Err(error) => writeln!(log, "Unable to create {}: {}", path.display(), error)
+        }
+    }
 }
 
-enum HeapEntry {
-    Instance(Arc<InstanceObject>),
-    ReferenceArray(Arc<ReferenceArrayObject>),
-    TypeArray(Arc<TypeArrayObject>)
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use crate::assembler::assemble;
+    use crate::class_file::ClassLoader;
+    use crate::objects::{FieldLayout, HeapSpace, NoRoots, RootSource};
+    use crate::objects::object::OBJECT_ALIGNMENT;
+    use crate::objects::spaces::TENURING_THRESHOLD;
+    use crate::test_support::Offsets;
+    use crate::types::Class;
+    use crate::utils::descriptors::FieldDescriptor;
+
+    fn node_class() -> Arc<Class> {
+        let source = ".class Node\n.super java/lang/Object\n.field static first LNode;\n.field value J\n\
+            .field next LNode;\n";
+        Arc::new(ClassLoader::new()).define_class(assemble(source).unwrap())
+    }
+
+    fn node_size(class: &Class) -> usize {
+        FieldLayout::of(class).size()
+    }
+
+    fn allocate(heap: &HeapSpace, class: &Arc<Class>, roots: &mut dyn RootSource) -> Option<usize> {
+        heap.allocate_ref(class, roots).ok().map(|instance| instance.offset())
+    }
+
+    fn field_offset(heap: &HeapSpace, offset: usize, name: &str, descriptor: &str) -> usize {
+        heap.get_ref(offset).unwrap().field_offset(name, &FieldDescriptor::parse(descriptor).unwrap()).unwrap()
+    }
+
+    fn link(heap: &HeapSpace, first: usize, second: usize) {
+        let next = field_offset(heap, first, "next", "LNode;");
+        heap.get_ref(first).unwrap().set_ref(next, second as u32);
+        heap.write_barrier(first, second);
+    }
+
+    fn next(heap: &HeapSpace, offset: usize) -> usize {
+        let next = field_offset(heap, offset, "next", "LNode;");
+        heap.get_ref(offset).unwrap().get_ref(next) as usize
+    }
+
+    #[test]
+    fn collects_unreachable_objects() {
+        let class = node_class();
+        let size = node_size(&class);
+        let heap = HeapSpace::new(size * 64);
+        let first = allocate(&heap, &class, &mut NoRoots).unwrap();
+        let second = allocate(&heap, &class, &mut NoRoots).unwrap();
+        let garbage = allocate(&heap, &class, &mut NoRoots).unwrap();
+        link(&heap, first, second);
+        // Only the reference field is followed, even if the long happens to hold an offset.
+        let value = field_offset(&heap, second, "value", "J");
+        heap.get_ref(second).unwrap().set_long(value, garbage as i64);
+
+        let handle = heap.roots().new_global_handle(first);
+        assert_eq!(heap.collect(&mut NoRoots), size);
+        assert_eq!(heap.object_count(), 2);
+        assert_eq!(heap.used(), size * 2);
+        let first = heap.roots().global_handle(handle);
+        assert!(heap.get_ref(first).is_not_null());
+        assert!(heap.get_ref(next(&heap, first)).is_not_null());
+
+        // A handle to null keeps its slot, so the next handle doesn't take it over
+        let null = heap.roots().new_global_handle(0);
+        let other = heap.roots().new_global_handle(0);
+        assert_ne!(other, null);
+        heap.roots().delete_global_handle(null);
+        heap.roots().delete_global_handle(other);
+
+        heap.roots().delete_global_handle(handle);
+        heap.roots().set_static("Node", "first", next(&heap, first));
+        let mut roots = Offsets(vec![allocate(&heap, &class, &mut NoRoots).unwrap()]);
+        assert_eq!(heap.collect(&mut roots), size);
+        assert_eq!(heap.object_count(), 2);
+        assert!(heap.get_ref(roots.0[0]).is_not_null());
+        assert!(heap.get_ref(heap.roots().get_static("Node", "first")).is_not_null());
+    }
+
+    #[test]
+    fn promotes_survivors_by_age() {
+        let class = node_class();
+        let heap = HeapSpace::with_sizes(node_size(&class) * 8, node_size(&class) * 8);
+        let mut roots = Offsets(vec![allocate(&heap, &class, &mut NoRoots).unwrap()]);
+        allocate(&heap, &class, &mut NoRoots).unwrap();
+        assert!(heap.is_young(roots.0[0]));
+        let mut freed = 0;
+        for _ in 1..TENURING_THRESHOLD {
+            let before = roots.0[0];
+            freed += heap.collect_nursery(&mut roots);
+            assert_ne!(roots.0[0], before);
+            assert!(heap.is_young(roots.0[0]));
+        }
+        assert_eq!(freed, node_size(&class));
+        heap.collect_nursery(&mut roots);
+        assert!(!heap.is_young(roots.0[0]));
+        assert!(heap.get_ref(roots.0[0]).is_not_null());
+        assert_eq!(heap.object_count(), 1);
+    }
+
+    #[test]
+    fn finds_young_objects_through_old_ones() {
+        let class = node_class();
+        let size = node_size(&class);
+        // Nothing fits in the nursery, so the first node goes straight in to the old generation.
+        let heap = HeapSpace::with_sizes(0, size * 8);
+        let old = allocate(&heap, &class, &mut NoRoots).unwrap();
+        assert!(!heap.is_young(old));
+
+        let heap = HeapSpace::with_sizes(size * 8, size * 8);
+        let mut roots = Offsets(vec![allocate(&heap, &class, &mut NoRoots).unwrap()]);
+        for _ in 0..TENURING_THRESHOLD {
+            heap.collect_nursery(&mut roots);
+        }
+        let old = roots.0[0];
+        assert!(!heap.is_young(old));
+        let young = allocate(&heap, &class, &mut roots).unwrap();
+        link(&heap, old, young);
+        heap.collect_nursery(&mut roots);
+        let moved = next(&heap, old);
+        assert_ne!(moved, young);
+        assert!(heap.is_young(moved));
+        assert!(heap.get_ref(moved).is_not_null());
+    }
+
+    #[test]
+    fn compacts_the_old_generation() {
+        let class = node_class();
+        let size = node_size(&class);
+        let heap = HeapSpace::with_sizes(0, size * 8);
+        // Objects are laid out one after the other, from the first offset after null.
+        let stride = size / OBJECT_ALIGNMENT;
+        let offsets = (0..4).map(|_| allocate(&heap, &class, &mut NoRoots).unwrap()).collect::<Vec<_>>();
+        assert_eq!(offsets, vec![1, 1 + stride, 1 + stride * 2, 1 + stride * 3]);
+        link(&heap, offsets[3], offsets[1]);
+        let value = field_offset(&heap, offsets[1], "value", "J");
+        heap.get_ref(offsets[1]).unwrap().set_long(value, -1);
+        let mut roots = Offsets(vec![offsets[3]]);
+        assert_eq!(heap.collect(&mut roots), size * 2);
+        assert_eq!(roots.0, vec![1 + stride]);
+        assert_eq!(next(&heap, 1 + stride), 1);
+        assert_eq!(heap.get_ref(1).unwrap().get_long(value), -1);
+        assert_eq!(allocate(&heap, &class, &mut NoRoots), Some(1 + stride * 2));
+    }
+
+    #[test]
+    fn enforces_maximum_size() {
+        let class = node_class();
+        let size = node_size(&class);
+        let heap = HeapSpace::with_sizes(size * 2, size * 2);
+        let mut roots = Offsets(Vec::new());
+        for _ in 0..3 {
+            let offset = allocate(&heap, &class, &mut roots).unwrap();
+            roots.0.push(offset);
+        }
+        let error = heap.allocate_ref(&class, &mut roots).err().unwrap();
+        assert_eq!((error.requested(), error.used(), error.maximum_size()), (size, size * 3, size * 4));
+        roots.0.pop();
+        assert!(allocate(&heap, &class, &mut roots).is_some());
+        assert_eq!(heap.object_count(), 3);
+    }
+
+    #[test]
+    fn collects_at_safepoints_when_requested() {
+        let class = node_class();
+        let heap = HeapSpace::new(1024);
+        allocate(&heap, &class, &mut NoRoots).unwrap();
+        heap.safepoint(&mut NoRoots);
+        assert_eq!(heap.object_count(), 1);
+        heap.request_collection();
+        heap.safepoint(&mut NoRoots);
+        assert_eq!(heap.object_count(), 0);
+        assert_eq!(heap.used(), 0);
+    }
+
+    #[test]
+    fn keeps_identity_hashes_when_objects_move() {
+        let class = node_class();
+        let heap = HeapSpace::with_sizes(node_size(&class) * 8, node_size(&class) * 8);
+        let mut roots = Offsets(vec![allocate(&heap, &class, &mut NoRoots).unwrap()]);
+        let other = allocate(&heap, &class, &mut NoRoots).unwrap();
+        let hash = heap.get_ref(roots.0[0]).unwrap().identity_hash();
+        assert_ne!(hash, 0);
+        assert_eq!(heap.identity_hash(roots.0[0]), hash);
+        assert_ne!(heap.identity_hash(other), hash);
+        assert_eq!(heap.identity_hash(0), 0);
+        assert!(heap.monitor_enter(roots.0[0], 1));
+
+        let before = roots.0[0];
+        heap.collect_nursery(&mut roots);
+        assert_ne!(roots.0[0], before);
+        assert_eq!(heap.identity_hash(roots.0[0]), hash);
+        heap.collect(&mut roots);
+        assert!(!heap.is_young(roots.0[0]));
+        assert_eq!(heap.identity_hash(roots.0[0]), hash);
+        // The monitor that was entered before the object moved is still held by the thread that entered it.
+        assert!(!heap.monitor_enter(roots.0[0], 2));
+        assert!(heap.monitor_exit(roots.0[0], 1));
+        assert!(!heap.monitor_exit(roots.0[0], 1));
+    }
+
+    #[test]
+    fn inflates_monitors_that_outgrow_the_mark_word() {
+        let class = node_class();
+        let heap = HeapSpace::with_sizes(node_size(&class) * 8, node_size(&class) * 8);
+        let mut roots = Offsets(vec![allocate(&heap, &class, &mut NoRoots).unwrap()]);
+        // Thread ids that only differ above the bits the mark word has room for are still different owners
+        let owner = 0x1_0001;
+        assert!(heap.monitor_enter(roots.0[0], owner));
+        assert!(!heap.monitor_enter(roots.0[0], 1));
+        assert!(!heap.monitor_exit(roots.0[0], 1));
+        assert!(heap.monitor_exit(roots.0[0], owner));
+
+        for _ in 0..1000 {
+            assert!(heap.monitor_enter(roots.0[0], 1));
+        }
+        heap.collect_nursery(&mut roots);
+        heap.collect(&mut roots);
+        assert!(!heap.monitor_enter(roots.0[0], 2));
+        for _ in 0..1000 {
+            assert!(heap.monitor_exit(roots.0[0], 1));
+        }
+        assert!(!heap.monitor_exit(roots.0[0], 1));
+        assert!(heap.monitor_enter(roots.0[0], 2));
+    }
 }
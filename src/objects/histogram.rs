@@ -0,0 +1,202 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use crate::constants::*;
+use super::spaces::{reference_array_name, Klass, Spaces};
+
+// How many objects of each class there are in the heap and how much space they take up, like
+// `jmap -histo` prints. Sizes are shallow, so they only count the objects themselves and not what
+// they point to.
+#[derive(Debug, Clone)]
+pub struct ClassHistogram {
+    entries: Vec<HistogramEntry>,
+    total_memory: usize,
+    used_memory: usize,
+    max_memory: usize
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistogramEntry {
+    class_name: String,
+    array_type: Option<u8>,
+    instance_count: usize,
+    shallow_size: usize
+}
+
+impl ClassHistogram {
+    pub(super) fn new(spaces: &Spaces, max_memory: usize) -> Self {
+        let mut entries = HashMap::new();
+        for offset in spaces.all_objects() {
+            let entry = entries.entry(class_name(spaces.klass(offset))).or_insert_with_key(|name| HistogramEntry {
+                class_name: name.clone(),
+                array_type: array_type(spaces.klass(offset)),
+                instance_count: 0,
+                shallow_size: 0
+            });
+            entry.instance_count += 1;
+            entry.shallow_size += spaces.size_of(offset);
+        }
+        let mut entries = entries.into_values().collect::<Vec<_>>();
+        entries.sort_by(|first, second| {
+            second.shallow_size.cmp(&first.shallow_size).then_with(|| first.class_name.cmp(&second.class_name))
+        });
+        ClassHistogram { entries, total_memory: spaces.capacity(), used_memory: spaces.used(), max_memory }
+    }
+
+    // Every class with objects in the heap, with the ones taking up the most space first.
+    pub fn entries(&self) -> &[HistogramEntry] {
+        &self.entries
+    }
+
+    pub fn entry(&self, class_name: &str) -> Option<&HistogramEntry> {
+        self.entries.iter().find(|entry| entry.class_name == class_name)
+    }
+
+    // The entries for arrays of primitives, one for each array type.
+    pub fn array_types(&self) -> impl Iterator<Item = &HistogramEntry> {
+        self.entries.iter().filter(|entry| entry.array_type.is_some())
+    }
+
+    pub fn instance_count(&self) -> usize {
+        self.entries.iter().map(|entry| entry.instance_count).sum()
+    }
+
+    pub fn shallow_size(&self) -> usize {
+        self.entries.iter().map(|entry| entry.shallow_size).sum()
+    }
+
+    pub fn total_memory(&self) -> usize {
+        self.total_memory
+    }
+
+    pub fn used_memory(&self) -> usize {
+        self.used_memory
+    }
+
+    pub fn max_memory(&self) -> usize {
+        self.max_memory
+    }
+}
+
+impl HistogramEntry {
+    // The name of the class in the form that Class.getName returns, so with dots rather than
+    // slashes, and arrays named by their descriptors.
+    pub fn class_name(&self) -> &str {
+        &self.class_name
+    }
+
+    // The JVM_T_* type of the elements, if this is an entry for arrays of primitives.
+    pub fn array_type(&self) -> Option<u8> {
+        self.array_type
+    }
+
+    pub fn instance_count(&self) -> usize {
+        self.instance_count
+    }
+
+    pub fn shallow_size(&self) -> usize {
+        self.shallow_size
+    }
+}
+
+impl Display for ClassHistogram {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, " num     #instances         #bytes  class name")?;
+        writeln!(f, "----------------------------------------------")?;
+        for (index, entry) in self.entries.iter().enumerate() {
+            let (count, size) = (entry.instance_count, entry.shallow_size);
+            writeln!(f, "{:4}: {:13} {:14}  {}", index + 1, count, size, entry.class_name)?;
+        }
+        writeln!(f, "Total {:13} {:14}", self.instance_count(), self.shallow_size())?;
+        write!(f, "Heap total {}K, used {}K, max {}K", self.total_memory / 1024, self.used_memory / 1024,
+               self.max_memory / 1024)
+    }
+}
+
+pub(super) fn class_name(klass: &Klass) -> String {
+    match klass {
+        Klass::Instance(class, _, _) => class.name().replace('/', "."),
+        Klass::ReferenceArray(_, element_class, dimensions) => {
+            reference_array_name(element_class, *dimensions).replace('/', ".")
+        }
+        Klass::TypeArray(array_type) => format!("[{}", match *array_type {
+            JVM_T_BOOLEAN => 'Z',
+            JVM_T_CHAR => 'C',
+            JVM_T_FLOAT => 'F',
+            JVM_T_DOUBLE => 'D',
+            JVM_T_BYTE => 'B',
+            JVM_T_SHORT => 'S',
+            JVM_T_INT => 'I',
+            JVM_T_LONG => 'J',
+            _ => panic!("Invalid primitive array type {}!", array_type)
+        })
+    }
+}
+
+fn array_type(klass: &Klass) -> Option<u8> {
+    match klass {
+        Klass::TypeArray(array_type) => Some(*array_type),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use crate::assembler::assemble;
+    use crate::class_file::ClassLoader;
+    use crate::constants::*;
+    use crate::objects::{HeapSpace, NoRoots};
+
+    #[test]
+    fn counts_objects_by_class() {
+        let class = Arc::new(ClassLoader::new())
+            .define_class(assemble(".class pkg/Node\n.super java/lang/Object\n.field next Lpkg/Node;\n").unwrap());
+        let heap = HeapSpace::with_sizes(0, 4096);
+        for _ in 0..3 {
+            heap.allocate_ref(&class, &mut NoRoots).unwrap();
+        }
+        heap.allocate_ref_array(&class, &class, 4, &mut NoRoots).unwrap();
+        heap.allocate_type_array(JVM_T_BYTE, 100, &mut NoRoots).unwrap();
+        heap.allocate_type_array(JVM_T_INT, 2, &mut NoRoots).unwrap();
+        heap.allocate_type_array(JVM_T_INT, 4, &mut NoRoots).unwrap();
+
+        let histogram = heap.histogram();
+        let rows = histogram.entries().iter()
+            .map(|entry| (entry.class_name(), entry.instance_count(), entry.shallow_size()))
+            .collect::<Vec<_>>();
+        assert_eq!(rows, vec![("[B", 1, 120), ("pkg.Node", 3, 72), ("[I", 2, 56), ("[Lpkg.Node;", 1, 32)]);
+        let array_types = histogram.array_types().map(|entry| entry.array_type().unwrap()).collect::<Vec<_>>();
+        assert_eq!(array_types, vec![JVM_T_BYTE, JVM_T_INT]);
+        assert_eq!(histogram.entry("pkg.Node").unwrap().array_type(), None);
+        assert_eq!((histogram.instance_count(), histogram.shallow_size()), (7, 280));
+        assert_eq!((histogram.total_memory(), histogram.used_memory(), histogram.max_memory()), (4096, 280, 4096));
+
+        let expected = [
+            " num     #instances         #bytes  class name",
+            "----------------------------------------------",
+            "   1:             1            120  [B",
+            "   2:             3             72  pkg.Node",
+            "   3:             2             56  [I",
+            "   4:             1             32  [Lpkg.Node;",
+            "Total             7            280",
+            "Heap total 4K, used 0K, max 4K"
+        ];
+        assert_eq!(histogram.to_string(), expected.join("\n"));
+    }
+}
@@ -0,0 +1,567 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use bytes::{BufMut, BytesMut};
+use crate::constants::*;
+use crate::utils::descriptors::{FieldDescriptor, FieldType};
+use super::gc::{RootSet, RootSource};
+use super::object::{element_size, FieldLayout, OBJECT_ALIGNMENT, OBJECT_HEADER_SIZE, REFERENCE_SIZE};
+use super::spaces::{reference_array_name, Klass, Spaces};
+
+// Writes heaps out in the binary format that HotSpot's heap dumps use, which is described in
+// share/demo/jvmti/hprof/manual.html in older JDKs, so that tools like Eclipse MAT and VisualVM can
+// read them.
+const MAGIC: &[u8] = b"JAVA PROFILE 1.0.2\0";
+const IDENTIFIER_SIZE: u32 = 8;
+
+const TAG_STRING: u8 = 0x01;
+const TAG_LOAD_CLASS: u8 = 0x02;
+const TAG_STACK_TRACE: u8 = 0x05;
+const TAG_HEAP_DUMP_SEGMENT: u8 = 0x1C;
+const TAG_HEAP_DUMP_END: u8 = 0x2C;
+
+const ROOT_UNKNOWN: u8 = 0xFF;
+const ROOT_JNI_GLOBAL: u8 = 0x01;
+const ROOT_JAVA_FRAME: u8 = 0x03;
+const ROOT_STICKY_CLASS: u8 = 0x05;
+const CLASS_DUMP: u8 = 0x20;
+const INSTANCE_DUMP: u8 = 0x21;
+const OBJECT_ARRAY_DUMP: u8 = 0x22;
+const PRIMITIVE_ARRAY_DUMP: u8 = 0x23;
+
+// The basic type of references. The primitive types use the same values as the JVM_T_* constants.
+const TYPE_OBJECT: u8 = 2;
+
+// Objects are identified by their address in the heap, and classes and strings, which don't live in
+// the heap, by identifiers above anything the heap could address.
+const CLASS_ID_BASE: u64 = 1 << 40;
+const STRING_ID_BASE: u64 = 1 << 41;
+
+// Every root that comes from a thread is put down to the one thread, with the one empty stack trace,
+// as frames don't know which thread they belong to.
+const STACK_TRACE_SERIAL: u32 = 1;
+const THREAD_SERIAL: u32 = 1;
+
+struct ClassRecord {
+    super_name: Option<String>,
+    instance_size: usize,
+    // The name, basic type and offset in the object of every instance field.
+    fields: Vec<(String, u8, usize)>,
+    statics: Vec<(String, usize)>
+}
+
+impl ClassRecord {
+    fn new(super_name: Option<String>) -> Self {
+        ClassRecord { super_name, instance_size: 0, fields: Vec::new(), statics: Vec::new() }
+    }
+}
+
+struct HprofWriter<'a> {
+    out: &'a mut dyn Write,
+    start: Instant,
+    strings: HashMap<String, u64>
+}
+
+impl HprofWriter<'_> {
+    fn record(&mut self, tag: u8, body: &[u8]) -> io::Result<()> {
+        let length = u32::try_from(body.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Heap dump record is too large!"))?;
+        let mut header = BytesMut::with_capacity(9);
+        header.put_u8(tag);
+        header.put_u32(self.start.elapsed().as_micros() as u32);
+        header.put_u32(length);
+        self.out.write_all(&header)?;
+        self.out.write_all(body)
+    }
+
+    // Returns the identifier of the string, writing a record for it the first time it's used.
+    fn string(&mut self, value: &str) -> io::Result<u64> {
+        if let Some(id) = self.strings.get(value) {
+            return Ok(*id);
+        }
+        let id = STRING_ID_BASE + self.strings.len() as u64 * OBJECT_ALIGNMENT as u64;
+        let mut body = BytesMut::with_capacity(value.len() + 8);
+        body.put_u64(id);
+        body.put_slice(value.as_bytes());
+        self.record(TAG_STRING, &body)?;
+        self.strings.insert(String::from(value), id);
+        Ok(id)
+    }
+}
+
+// Writes every object in the heap, along with records for their classes and the roots that keep
+// them alive. Objects that are no longer reachable are written too, as tools work that out for
+// themselves.
+pub(super) fn write_heap_dump(
+    spaces: &Spaces,
+    root_set: &RootSet,
+    roots: &mut dyn RootSource,
+    out: &mut dyn Write
+) -> io::Result<()> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_millis() as u64);
+    let mut header = BytesMut::with_capacity(MAGIC.len() + 12);
+    header.put_slice(MAGIC);
+    header.put_u32(IDENTIFIER_SIZE);
+    header.put_u64(timestamp);
+    out.write_all(&header)?;
+    let mut writer = HprofWriter { out, start: Instant::now(), strings: HashMap::new() };
+
+    let objects = spaces.all_objects();
+    let classes = collect_classes(spaces, root_set, &objects);
+    let class_ids = classes.keys().enumerate()
+        .map(|(index, name)| (name.clone(), CLASS_ID_BASE + (index * OBJECT_ALIGNMENT) as u64))
+        .collect::<HashMap<_, _>>();
+
+    let mut trace = BytesMut::new();
+    trace.put_u32(STACK_TRACE_SERIAL);
+    trace.put_u32(THREAD_SERIAL);
+    trace.put_u32(0);
+    writer.record(TAG_STACK_TRACE, &trace)?;
+    for (serial, name) in classes.keys().enumerate() {
+        let name_id = writer.string(name)?;
+        let mut body = BytesMut::new();
+        body.put_u32(serial as u32 + 1);
+        body.put_u64(class_ids[name]);
+        body.put_u32(STACK_TRACE_SERIAL);
+        body.put_u64(name_id);
+        writer.record(TAG_LOAD_CLASS, &body)?;
+    }
+
+    let mut dump = BytesMut::new();
+    write_roots(&mut dump, root_set, roots, &classes, &class_ids);
+    for (name, class) in &classes {
+        dump.put_u8(CLASS_DUMP);
+        dump.put_u64(class_ids[name]);
+        dump.put_u32(STACK_TRACE_SERIAL);
+        dump.put_u64(class.super_name.as_ref().and_then(|name| class_ids.get(name)).copied().unwrap_or(0));
+        // The class loader, signers, protection domain and two reserved identifiers, which we don't have.
+        for _ in 0..5 {
+            dump.put_u64(0);
+        }
+        dump.put_u32(class.instance_size as u32);
+        dump.put_u16(0);
+        dump.put_u16(class.statics.len() as u16);
+        for (field_name, value) in &class.statics {
+            dump.put_u64(writer.string(field_name)?);
+            dump.put_u8(TYPE_OBJECT);
+            dump.put_u64(object_id(*value));
+        }
+        dump.put_u16(class.fields.len() as u16);
+        for (field_name, basic_type, _) in &class.fields {
+            dump.put_u64(writer.string(field_name)?);
+            dump.put_u8(*basic_type);
+        }
+    }
+    for offset in objects {
+        write_object(&mut dump, spaces, offset, &classes, &class_ids);
+    }
+    writer.record(TAG_HEAP_DUMP_SEGMENT, &dump)?;
+    writer.record(TAG_HEAP_DUMP_END, &[])?;
+    writer.out.flush()
+}
+
+// Finds every class that has instances or arrays in the heap or holds a static reference, along with
+// the superclasses of the ones with instances. Classes are keyed by name in the internal form.
+fn collect_classes(spaces: &Spaces, root_set: &RootSet, objects: &[usize]) -> BTreeMap<String, ClassRecord> {
+    let mut classes = BTreeMap::new();
+    for offset in objects {
+        match spaces.klass(*offset) {
+            Klass::Instance(class, _, _) => {
+                // Each class only describes the fields it declares, so the superclasses need records too.
+                let mut class = Some(Arc::clone(class));
+                while let Some(current) = class.take() {
+                    if classes.contains_key(current.name()) {
+                        break;
+                    }
+                    let layout = FieldLayout::of(&current);
+                    let super_name = current.super_class_name();
+                    let mut record = ClassRecord::new(super_name.as_ref().map(|name| name.to_string()));
+                    record.instance_size = layout.size();
+                    record.fields = layout.declared_fields()
+                        .map(|(name, descriptor, offset)| (String::from(name), basic_type(descriptor), offset))
+                        .collect();
+                    classes.insert(String::from(current.name()), record);
+                    class = super_name.and_then(|name| current.loader().find_class(name.as_str()));
+                }
+            }
+            Klass::ReferenceArray(_, element_class, dimensions) => {
+                let name = reference_array_name(element_class, *dimensions);
+                classes.entry(name).or_insert_with(|| ClassRecord::new(Some(String::from("java/lang/Object"))));
+            }
+            Klass::TypeArray(_) => {}
+        }
+    }
+    for (class_name, field_name, offset) in root_set.statics() {
+        classes.entry(class_name.to_string())
+            .or_insert_with(|| ClassRecord::new(None))
+            .statics
+            .push((field_name.to_string(), offset));
+    }
+    let missing = classes.values()
+        .filter_map(|class| class.super_name.clone())
+        .filter(|name| !classes.contains_key(name))
+        .collect::<Vec<_>>();
+    for name in missing {
+        classes.insert(name, ClassRecord::new(None));
+    }
+    classes
+}
+
+fn write_roots(
+    dump: &mut BytesMut,
+    root_set: &RootSet,
+    roots: &mut dyn RootSource,
+    classes: &BTreeMap<String, ClassRecord>,
+    class_ids: &HashMap<String, u64>
+) {
+    roots.visit_roots(&mut |offset| {
+        if *offset != 0 {
+            dump.put_u8(ROOT_JAVA_FRAME);
+            dump.put_u64(object_id(*offset));
+            dump.put_u32(THREAD_SERIAL);
+            dump.put_u32(u32::MAX);
+        }
+    });
+    let global_handles = root_set.global_handles().into_iter()
+        .enumerate()
+        .filter_map(|(index, offset)| offset.filter(|offset| *offset != 0).map(|offset| (index, offset)));
+    for (index, offset) in global_handles {
+        dump.put_u8(ROOT_JNI_GLOBAL);
+        dump.put_u64(object_id(offset));
+        dump.put_u64(index as u64 + 1);
+    }
+    for offset in root_set.class_mirrors() {
+        dump.put_u8(ROOT_STICKY_CLASS);
+        dump.put_u64(object_id(offset));
+    }
+    for offset in root_set.interned_strings() {
+        dump.put_u8(ROOT_UNKNOWN);
+        dump.put_u64(object_id(offset));
+    }
+    // Classes with static fields keep what those point to alive, so they are roots themselves.
+    for (name, _) in classes.iter().filter(|(_, class)| !class.statics.is_empty()) {
+        dump.put_u8(ROOT_STICKY_CLASS);
+        dump.put_u64(class_ids[name]);
+    }
+}
+
+fn write_object(
+    dump: &mut BytesMut,
+    spaces: &Spaces,
+    offset: usize,
+    classes: &BTreeMap<String, ClassRecord>,
+    class_ids: &HashMap<String, u64>
+) {
+    let memory = spaces.memory();
+    let position = offset * OBJECT_ALIGNMENT;
+    let length = spaces.length(offset);
+    match spaces.klass(offset) {
+        Klass::Instance(class, _, _) => {
+            // The values of the class's own fields come first, followed by those of each superclass in turn.
+            let mut values = Vec::new();
+            let mut name = Some(class.name());
+            while let Some(record) = name.and_then(|name| classes.get(name)) {
+                for (_, basic_type, field) in &record.fields {
+                    values.push((*basic_type, memory.read(position + field, value_size(*basic_type))));
+                }
+                name = record.super_name.as_deref();
+            }
+            dump.put_u8(INSTANCE_DUMP);
+            dump.put_u64(object_id(offset));
+            dump.put_u32(STACK_TRACE_SERIAL);
+            dump.put_u64(class_ids[class.name()]);
+            dump.put_u32(values.iter().map(|(basic_type, _)| dump_size(*basic_type)).sum::<usize>() as u32);
+            for (basic_type, value) in values {
+                put_value(dump, basic_type, value);
+            }
+        }
+        Klass::ReferenceArray(_, element_class, dimensions) => {
+            dump.put_u8(OBJECT_ARRAY_DUMP);
+            dump.put_u64(object_id(offset));
+            dump.put_u32(STACK_TRACE_SERIAL);
+            dump.put_u32(length as u32);
+            dump.put_u64(class_ids[&reference_array_name(element_class, *dimensions)]);
+            for index in 0..length {
+                let element = memory.read(position + OBJECT_HEADER_SIZE + index * REFERENCE_SIZE, REFERENCE_SIZE);
+                dump.put_u64(object_id(element as usize));
+            }
+        }
+        Klass::TypeArray(array_type) => {
+            let size = element_size(*array_type);
+            dump.put_u8(PRIMITIVE_ARRAY_DUMP);
+            dump.put_u64(object_id(offset));
+            dump.put_u32(STACK_TRACE_SERIAL);
+            dump.put_u32(length as u32);
+            dump.put_u8(*array_type);
+            for index in 0..length {
+                put_value(dump, *array_type, memory.read(position + OBJECT_HEADER_SIZE + index * size, size));
+            }
+        }
+    }
+}
+
+fn object_id(offset: usize) -> u64 {
+    (offset * OBJECT_ALIGNMENT) as u64
+}
+
+fn basic_type(descriptor: &FieldDescriptor) -> u8 {
+    if descriptor.is_reference() {
+        return TYPE_OBJECT;
+    }
+    match descriptor.base() {
+        FieldType::Boolean => JVM_T_BOOLEAN,
+        FieldType::Byte => JVM_T_BYTE,
+        FieldType::Char => JVM_T_CHAR,
+        FieldType::Short => JVM_T_SHORT,
+        FieldType::Int => JVM_T_INT,
+        FieldType::Float => JVM_T_FLOAT,
+        FieldType::Long => JVM_T_LONG,
+        FieldType::Double => JVM_T_DOUBLE,
+        FieldType::Reference(_) => TYPE_OBJECT
+    }
+}
+
+// The number of bytes a value of the basic type takes up in the heap.
+fn value_size(basic_type: u8) -> usize {
+    match basic_type {
+        TYPE_OBJECT => REFERENCE_SIZE,
+        _ => element_size(basic_type)
+    }
+}
+
+// The number of bytes a value of the basic type takes up in the dump, where references are identifiers.
+fn dump_size(basic_type: u8) -> usize {
+    match basic_type {
+        TYPE_OBJECT => IDENTIFIER_SIZE as usize,
+        _ => element_size(basic_type)
+    }
+}
+
+fn put_value(dump: &mut BytesMut, basic_type: u8, value: u64) {
+    match dump_size(basic_type) {
+        1 => dump.put_u8(value as u8),
+        2 => dump.put_u16(value as u16),
+        4 => dump.put_u32(value as u32),
+        _ if basic_type == TYPE_OBJECT => dump.put_u64(object_id(value as usize)),
+        _ => dump.put_u64(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use bytes::{Buf, Bytes};
+    use crate::assembler::assemble;
+    use crate::class_file::ClassLoader;
+    use crate::constants::*;
+    use crate::objects::{HeapSpace, NoRoots};
+    use crate::test_support::Offsets;
+    use crate::types::Class;
+    use crate::utils::descriptors::FieldDescriptor;
+    use super::*;
+
+    // What a dump holds, with classes and fields named and everything else keyed by identifier.
+    #[derive(Default)]
+    struct Dump {
+        classes: HashMap<u64, String>,
+        roots: Vec<(u8, u64)>,
+        instance_fields: HashMap<String, Vec<(String, u8)>>,
+        statics: HashMap<String, Vec<(String, u64)>>,
+        instances: HashMap<u64, (u64, Bytes)>,
+        object_arrays: HashMap<u64, (u64, Vec<u64>)>,
+        primitive_arrays: HashMap<u64, (u8, Bytes)>
+    }
+
+    fn read_dump(mut bytes: Bytes) -> Dump {
+        assert_eq!(&bytes[..MAGIC.len()], MAGIC);
+        bytes.advance(MAGIC.len());
+        assert_eq!(bytes.get_u32(), IDENTIFIER_SIZE);
+        bytes.get_u64();
+        let mut dump = Dump::default();
+        let mut strings = HashMap::new();
+        let mut ended = false;
+        while bytes.has_remaining() {
+            let tag = bytes.get_u8();
+            bytes.get_u32();
+            let length = bytes.get_u32() as usize;
+            let mut body = bytes.split_to(length);
+            match tag {
+                TAG_STRING => {
+                    let id = body.get_u64();
+                    strings.insert(id, String::from_utf8(body.to_vec()).unwrap());
+                }
+                TAG_LOAD_CLASS => {
+                    body.get_u32();
+                    let id = body.get_u64();
+                    body.get_u32();
+                    dump.classes.insert(id, strings[&body.get_u64()].clone());
+                }
+                TAG_HEAP_DUMP_SEGMENT => read_segment(&mut dump, &strings, body),
+                TAG_HEAP_DUMP_END => ended = true,
+                _ => {}
+            }
+        }
+        assert!(ended);
+        dump
+    }
+
+    fn read_segment(dump: &mut Dump, strings: &HashMap<u64, String>, mut body: Bytes) {
+        while body.has_remaining() {
+            let tag = body.get_u8();
+            match tag {
+                ROOT_UNKNOWN | ROOT_STICKY_CLASS => dump.roots.push((tag, body.get_u64())),
+                ROOT_JNI_GLOBAL => {
+                    dump.roots.push((tag, body.get_u64()));
+                    body.get_u64();
+                }
+                ROOT_JAVA_FRAME => {
+                    dump.roots.push((tag, body.get_u64()));
+                    body.advance(8);
+                }
+                CLASS_DUMP => {
+                    let name = dump.classes[&body.get_u64()].clone();
+                    body.advance(4 + 6 * 8 + 4);
+                    assert_eq!(body.get_u16(), 0);
+                    let statics = (0..body.get_u16())
+                        .map(|_| {
+                            let field = strings[&body.get_u64()].clone();
+                            assert_eq!(body.get_u8(), TYPE_OBJECT);
+                            (field, body.get_u64())
+                        })
+                        .collect();
+                    let fields = (0..body.get_u16())
+                        .map(|_| (strings[&body.get_u64()].clone(), body.get_u8()))
+                        .collect();
+                    dump.statics.insert(name.clone(), statics);
+                    dump.instance_fields.insert(name, fields);
+                }
+                INSTANCE_DUMP => {
+                    let id = body.get_u64();
+                    body.get_u32();
+                    let class = body.get_u64();
+                    let length = body.get_u32() as usize;
+                    dump.instances.insert(id, (class, body.split_to(length)));
+                }
+                OBJECT_ARRAY_DUMP => {
+                    let id = body.get_u64();
+                    body.get_u32();
+                    let length = body.get_u32();
+                    let class = body.get_u64();
+                    dump.object_arrays.insert(id, (class, (0..length).map(|_| body.get_u64()).collect()));
+                }
+                PRIMITIVE_ARRAY_DUMP => {
+                    let id = body.get_u64();
+                    body.get_u32();
+                    let length = body.get_u32() as usize;
+                    let array_type = body.get_u8();
+                    dump.primitive_arrays.insert(id, (array_type, body.split_to(length * element_size(array_type))));
+                }
+                _ => panic!("Invalid heap dump sub-record {}!", tag)
+            }
+        }
+    }
+
+    fn node_class() -> Arc<Class> {
+        let source = ".class Node\n.super java/lang/Object\n.field static first LNode;\n.field value J\n\
+            .field next LNode;\n";
+        Arc::new(ClassLoader::new()).define_class(assemble(source).unwrap())
+    }
+
+    fn field_offset(heap: &HeapSpace, offset: usize, name: &str, descriptor: &str) -> usize {
+        heap.get_ref(offset).unwrap().field_offset(name, &FieldDescriptor::parse(descriptor).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn writes_objects_classes_and_roots() {
+        let class = node_class();
+        let heap = HeapSpace::new(4096);
+        let first = heap.allocate_ref(&class, &mut NoRoots).unwrap().offset();
+        let second = heap.allocate_ref(&class, &mut NoRoots).unwrap().offset();
+        let first_ref = heap.get_ref(first).unwrap();
+        first_ref.set_long(field_offset(&heap, first, "value", "J"), 0x0102030405060708);
+        first_ref.set_ref(field_offset(&heap, first, "next", "LNode;"), second as u32);
+        let array = heap.allocate_ref_array(&class, &class, 2, &mut NoRoots).unwrap();
+        array.set(1, first as u32);
+        let chars = heap.allocate_type_array(JVM_T_CHAR, 2, &mut NoRoots).unwrap();
+        chars.set_char(0, 'h');
+        chars.set_char(1, 'i');
+        heap.roots().set_static("Node", "first", first);
+        heap.roots().new_global_handle(chars.offset());
+
+        let mut out = Vec::new();
+        heap.dump_heap(&mut out, &mut Offsets(vec![array.offset()])).unwrap();
+        let dump = read_dump(Bytes::from(out));
+        let class_id = |name: &str| *dump.classes.iter().find(|(_, class)| class.as_str() == name).unwrap().0;
+
+        let mut names = dump.classes.values().cloned().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec!["Node", "[LNode;", "java/lang/Object"]);
+        let fields = vec![(String::from("value"), JVM_T_LONG), (String::from("next"), TYPE_OBJECT)];
+        assert_eq!(dump.instance_fields["Node"], fields);
+        assert_eq!(dump.statics["Node"], vec![(String::from("first"), object_id(first))]);
+        assert!(dump.roots.contains(&(ROOT_JAVA_FRAME, object_id(array.offset()))));
+        assert!(dump.roots.contains(&(ROOT_JNI_GLOBAL, object_id(chars.offset()))));
+        assert!(dump.roots.contains(&(ROOT_STICKY_CLASS, class_id("Node"))));
+
+        let (instance_class, values) = &dump.instances[&object_id(first)];
+        assert_eq!(*instance_class, class_id("Node"));
+        assert_eq!(&values[..8], &[1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(&values[8..], &object_id(second).to_be_bytes());
+        assert_eq!(&dump.instances[&object_id(second)].1[..], &[0; 16]);
+        let elements = &dump.object_arrays[&object_id(array.offset())];
+        assert_eq!(elements, &(class_id("[LNode;"), vec![0, object_id(first)]));
+        let chars = &dump.primitive_arrays[&object_id(chars.offset())];
+        assert_eq!(chars, &(JVM_T_CHAR, Bytes::from_static(&[0, b'h', 0, b'i'])));
+    }
+
+    // Where a test can read back what the heap reported after handing it over.
+    #[derive(Clone, Default)]
+    struct Log(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Log {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn dumps_the_heap_on_out_of_memory() {
+        let class = node_class();
+        let path = std::env::temp_dir().join(format!("astatine-{}-oom.hprof", std::process::id()));
+        let heap = HeapSpace::with_sizes(0, 64);
+        let log = Log::default();
+        heap.dump_heap_on_out_of_memory(&path, log.clone());
+        let mut roots = Offsets(Vec::new());
+        while let Ok(node) = heap.allocate_ref(&class, &mut roots) {
+            roots.0.push(node.offset());
+        }
+        let dump = read_dump(Bytes::from(std::fs::read(&path).unwrap()));
+        std::fs::remove_file(&path).unwrap();
+        let log = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        assert!(log.starts_with(&format!("Dumping heap to {} ...\nHeap dump file created [", path.display())));
+        assert_eq!(dump.instances.len(), 2);
+        assert_eq!(dump.roots.iter().filter(|(tag, _)| *tag == ROOT_JAVA_FRAME).count(), 2);
+    }
+}
@@ -0,0 +1,98 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+// The word at the start of every object, which the collector, identity hash codes and monitors all
+// share:
+//
+//   bits  0-3   the number of minor collections the object has survived
+//   bit   4     set while a full collection has the object marked
+//   bit   5     set once a minor collection has evacuated the object, with the high half of the word
+//               left behind holding the offset it was moved to
+//   bit   6     set while the monitor is inflated, with its owner and count kept in a side table
+//               instead of in the word
+//   bits  8-15  the number of times the object's monitor has been entered and not yet exited
+//   bits 16-31  the id of the thread holding the monitor, while it's held by one
+//   bits 32-62  the identity hash code, or 0 if nothing has asked for it yet
+//
+// Monitors are inflated when the id of the thread entering them or the number of times they've been
+// entered doesn't fit in its bits, so that nothing is ever cut short. Moving an object copies its mark
+// word along with the rest of it, so the hash and the monitor go wherever the object does. Every other
+// bit is left as 0.
+pub(super) const AGE_MASK: u64 = 0xF;
+pub(super) const MARKED: u64 = 1 << 4;
+pub(super) const FORWARDED: u64 = 1 << 5;
+// The bits that the collector owns, which get reset whenever an object is moved.
+pub(super) const FLAGS_MASK: u64 = AGE_MASK | MARKED | FORWARDED;
+pub(super) const INFLATED: u64 = 1 << 6;
+
+const LOCK_SHIFT: u32 = 8;
+const LOCK_MASK: u64 = 0xFF << LOCK_SHIFT;
+pub(super) const MAXIMUM_LOCK_COUNT: u64 = LOCK_MASK >> LOCK_SHIFT;
+
+const OWNER_SHIFT: u32 = 16;
+const OWNER_MASK: u64 = 0xFFFF << OWNER_SHIFT;
+pub(super) const MAXIMUM_OWNER: u64 = OWNER_MASK >> OWNER_SHIFT;
+
+const HASH_SHIFT: u32 = 32;
+const HASH_MASK: u64 = 0x7FFF_FFFF << HASH_SHIFT;
+
+pub(super) fn hash(mark: u64) -> i32 {
+    ((mark & HASH_MASK) >> HASH_SHIFT) as i32
+}
+
+pub(super) fn with_hash(mark: u64, hash: i32) -> u64 {
+    (mark & !HASH_MASK) | (((hash as u64) << HASH_SHIFT) & HASH_MASK)
+}
+
+pub(super) fn lock_count(mark: u64) -> u64 {
+    (mark & LOCK_MASK) >> LOCK_SHIFT
+}
+
+pub(super) fn with_lock_count(mark: u64, count: u64) -> u64 {
+    (mark & !LOCK_MASK) | ((count << LOCK_SHIFT) & LOCK_MASK)
+}
+
+pub(super) fn is_owned_by(mark: u64, owner: u64) -> bool {
+    (mark & OWNER_MASK) >> OWNER_SHIFT == owner
+}
+
+pub(super) fn with_owner(mark: u64, owner: u64) -> u64 {
+    (mark & !OWNER_MASK) | ((owner << OWNER_SHIFT) & OWNER_MASK)
+}
+
+// Hands out identity hash codes with Marsaglia's xor-shift, like HotSpot does by default, keeping them
+// to 31 bits and never 0, as that means an object doesn't have one yet.
+pub(super) struct HashGenerator {
+    state: u32
+}
+
+impl HashGenerator {
+    pub(super) fn new() -> Self {
+        HashGenerator { state: 0x2545_F491 }
+    }
+
+    pub(super) fn next_hash(&mut self) -> i32 {
+        loop {
+            self.state ^= self.state << 13;
+            self.state ^= self.state >> 17;
+            self.state ^= self.state << 5;
+            let hash = (self.state & 0x7FFF_FFFF) as i32;
+            if hash != 0 {
+                return hash;
+            }
+        }
+    }
+}
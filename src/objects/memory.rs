@@ -0,0 +1,202 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicU16, AtomicU32, AtomicU64, AtomicU8, Ordering};
+
+// The access modes that Java has for fields and array elements, from weakest to strongest, which
+// VarHandle and Unsafe name their methods after. Normal fields are accessed plainly, and volatile
+// fields with volatile accesses, which are sequentially consistent. Plain accesses are still atomic,
+// so even they never see half of a long or double.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MemoryOrder {
+    Plain,
+    Opaque,
+    AcquireRelease,
+    Volatile
+}
+
+impl MemoryOrder {
+    fn load(self) -> Ordering {
+        match self {
+            MemoryOrder::Plain | MemoryOrder::Opaque => Ordering::Relaxed,
+            MemoryOrder::AcquireRelease => Ordering::Acquire,
+            MemoryOrder::Volatile => Ordering::SeqCst
+        }
+    }
+
+    fn store(self) -> Ordering {
+        match self {
+            MemoryOrder::Plain | MemoryOrder::Opaque => Ordering::Relaxed,
+            MemoryOrder::AcquireRelease => Ordering::Release,
+            MemoryOrder::Volatile => Ordering::SeqCst
+        }
+    }
+
+    // The ordering of operations that both read and write, like compare and set.
+    fn update(self) -> Ordering {
+        match self {
+            MemoryOrder::Plain | MemoryOrder::Opaque => Ordering::Relaxed,
+            MemoryOrder::AcquireRelease => Ordering::AcqRel,
+            MemoryOrder::Volatile => Ordering::SeqCst
+        }
+    }
+}
+
+// Runs the body with the atomic of the given size at the position, and with the name given to the
+// unsigned integer type of that size. The body is the same for every size, so it converts to and from
+// u64 even when that's what the type already is.
+macro_rules! atomic {
+    ($memory:expr, $position:expr, $size:expr, |$atomic:ident, $int:ident| $body:expr) => {{
+        let pointer = $memory.pointer($position, $size);
+        unsafe {
+            match $size {
+                1 => {
+                    #[allow(non_camel_case_types)]
+                    type $int = u8;
+                    let $atomic = &*(pointer as *const AtomicU8);
+                    #[allow(clippy::unnecessary_cast, clippy::useless_conversion)]
+                    let result = $body;
+                    result
+                }
+                2 => {
+                    #[allow(non_camel_case_types)]
+                    type $int = u16;
+                    let $atomic = &*(pointer as *const AtomicU16);
+                    #[allow(clippy::unnecessary_cast, clippy::useless_conversion)]
+                    let result = $body;
+                    result
+                }
+                4 => {
+                    #[allow(non_camel_case_types)]
+                    type $int = u32;
+                    let $atomic = &*(pointer as *const AtomicU32);
+                    #[allow(clippy::unnecessary_cast, clippy::useless_conversion)]
+                    let result = $body;
+                    result
+                }
+                8 => {
+                    #[allow(non_camel_case_types)]
+                    type $int = u64;
+                    let $atomic = &*(pointer as *const AtomicU64);
+                    #[allow(clippy::unnecessary_cast, clippy::useless_conversion)]
+                    let result = $body;
+                    result
+                }
+                _ => panic!("Invalid memory access size {}!", $size)
+            }
+        }
+    }}
+}
+
+// A block of memory that is reserved for the whole heap up front. Everything in it is read and
+// written through atomics of the width being accessed, so that threads racing on a field can never
+// see a value that was only partly written, and only the collector, which has the heap to itself,
+// copies memory around.
+pub(super) struct Memory {
+    base: NonNull<u8>,
+    size: usize
+}
+
+// Memory is only ever accessed through atomics, or while the collector has exclusive access.
+unsafe impl Send for Memory {}
+unsafe impl Sync for Memory {}
+
+impl Memory {
+    pub(super) fn new(size: usize) -> Self {
+        let layout = Memory::layout(size);
+        let base = NonNull::new(unsafe { alloc_zeroed(layout) })
+            .unwrap_or_else(|| panic!("Invalid heap size {}! Failed to reserve memory for it!", size));
+        Memory { base, size: layout.size() }
+    }
+
+    fn layout(size: usize) -> Layout {
+        Layout::from_size_align(size.max(8), 8)
+            .unwrap_or_else(|_| panic!("Invalid heap size {}!", size))
+    }
+
+    pub(super) fn size(&self) -> usize {
+        self.size
+    }
+
+    // The value of the given number of bytes at the given position, which must be aligned to them.
+    pub(super) fn read(&self, position: usize, size: usize) -> u64 {
+        self.load(position, size, MemoryOrder::Plain)
+    }
+
+    pub(super) fn write(&self, position: usize, size: usize, value: u64) {
+        self.store(position, size, value, MemoryOrder::Plain)
+    }
+
+    pub(super) fn load(&self, position: usize, size: usize, order: MemoryOrder) -> u64 {
+        atomic!(self, position, size, |atomic, _int| atomic.load(order.load()) as u64)
+    }
+
+    pub(super) fn store(&self, position: usize, size: usize, value: u64, order: MemoryOrder) {
+        atomic!(self, position, size, |atomic, int| atomic.store(value as int, order.store()))
+    }
+
+    // Returns the value that was there before, which is only the expected value if it was replaced.
+    pub(super) fn compare_exchange(
+        &self,
+        position: usize,
+        size: usize,
+        expected: u64,
+        value: u64,
+        order: MemoryOrder
+    ) -> Result<u64, u64> {
+        atomic!(self, position, size, |atomic, int| {
+            atomic.compare_exchange(expected as int, value as int, order.update(), order.load())
+                .map(u64::from)
+                .map_err(u64::from)
+        })
+    }
+
+    // Adds to the value, wrapping around on overflow, and returns the value from before.
+    pub(super) fn get_and_add(&self, position: usize, size: usize, delta: u64, order: MemoryOrder) -> u64 {
+        atomic!(self, position, size, |atomic, int| atomic.fetch_add(delta as int, order.update()) as u64)
+    }
+
+    pub(super) fn get_and_set(&self, position: usize, size: usize, value: u64, order: MemoryOrder) -> u64 {
+        atomic!(self, position, size, |atomic, int| atomic.swap(value as int, order.update()) as u64)
+    }
+
+    // Copies the given number of bytes from one position to another, which may overlap. Only the
+    // collector does this, so it takes exclusive access to make sure nothing else is reading.
+    pub(super) fn copy(&mut self, from: usize, to: usize, length: usize) {
+        assert!(from.max(to) + length <= self.size, "Invalid copy of {} bytes from {} to {}!", length, from, to);
+        unsafe { std::ptr::copy(self.base.as_ptr().add(from), self.base.as_ptr().add(to), length) }
+    }
+
+    pub(super) fn clear(&self, position: usize, length: usize) {
+        assert!(position % 8 == 0 && length % 8 == 0, "Invalid clear of {} bytes at {}!", length, position);
+        for word in (position..position + length).step_by(8) {
+            self.write(word, 8, 0);
+        }
+    }
+
+    fn pointer(&self, position: usize, size: usize) -> *mut u8 {
+        assert!(position % size == 0 && position + size <= self.size, "Invalid memory access at {}!", position);
+        unsafe { self.base.as_ptr().add(position) }
+    }
+}
+
+impl Drop for Memory {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.base.as_ptr(), Memory::layout(self.size)) }
+    }
+}
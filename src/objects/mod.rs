@@ -15,10 +15,24 @@
  */
 
 mod object;
+mod mark_word;
+mod memory;
 mod heap;
 mod reference;
+mod gc;
+mod spaces;
+mod hprof;
+mod histogram;
+mod references;
 pub mod handles;
 
-pub use object::{InstanceObject, ReferenceArrayObject, TypeArrayObject};
+pub use object::{
+    element_size, field_size, FieldLayout, HeapObject, InstanceObject, ReferenceArrayObject, TypeArrayObject,
+    OBJECT_HEADER_SIZE, REFERENCE_SIZE
+};
 pub use heap::HeapSpace;
+pub use memory::MemoryOrder;
+pub use histogram::{ClassHistogram, HistogramEntry};
+pub use gc::{Mutators, NoRoots, OutOfMemoryError, RootSet, RootSource, Unblocked, WithRoot};
 pub use reference::Reference;
+pub use references::ReferenceKind;
@@ -14,199 +14,443 @@
  * Street, Fifth Floor, Boston, MA 02110-1301, USA.
  */
 
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
+use crate::utils::IStr;
+use crate::constants::*;
 use crate::types::Class;
+use crate::utils::descriptors::{FieldDescriptor, FieldType};
+use super::heap::HeapSpace;
+use super::memory::MemoryOrder;
 
+// Every object starts with a mark word, which holds its identity hash code and the state of its
+// monitor along with what the collector needs for marking, forwarding and ages. That is followed by
+// the 32-bit index of its class in the heap's class table and a 32-bit array length.
+pub const OBJECT_HEADER_SIZE: usize = 16;
+
+// Objects are aligned to, and offsets count in, units of this many bytes.
+pub const OBJECT_ALIGNMENT: usize = 8;
+
+// References held in fields and array elements are compressed offsets, so they only take up 32 bits.
+pub const REFERENCE_SIZE: usize = 4;
+
+pub fn align_object_size(size: usize) -> usize {
+    (size + OBJECT_ALIGNMENT - 1) & !(OBJECT_ALIGNMENT - 1)
+}
+
+// The number of bytes a value of the given type takes up in a field.
+pub fn field_size(descriptor: &FieldDescriptor) -> usize {
+    if descriptor.array_dimensions() > 0 {
+        return REFERENCE_SIZE;
+    }
+    match descriptor.base() {
+        FieldType::Byte | FieldType::Boolean => 1,
+        FieldType::Char | FieldType::Short => 2,
+        FieldType::Int | FieldType::Float => 4,
+        FieldType::Long | FieldType::Double => 8,
+        FieldType::Reference(_) => REFERENCE_SIZE
+    }
+}
+
+// The number of bytes each element of a primitive array with the given JVM_T_* type takes up.
+pub fn element_size(array_type: u8) -> usize {
+    match array_type {
+        JVM_T_BOOLEAN | JVM_T_BYTE => 1,
+        JVM_T_CHAR | JVM_T_SHORT => 2,
+        JVM_T_INT | JVM_T_FLOAT => 4,
+        JVM_T_LONG | JVM_T_DOUBLE => 8,
+        _ => panic!("Invalid primitive array type {}!", array_type)
+    }
+}
+
+// Where each instance field of a class lives in its instances. The fields of a superclass come first,
+// laid out just as they are in instances of the superclass, and the class's own fields are packed
+// largest first after them, so that every field is naturally aligned with as little padding as we
+// can get away with. Volatile fields are remembered, as every access to them has to be volatile too.
+#[derive(Debug)]
+pub struct FieldLayout {
+    fields: Vec<(IStr, FieldDescriptor, usize)>,
+    volatile_offsets: Vec<usize>,
+    declared: usize,
+    size: usize,
+    reference_offsets: Vec<usize>
+}
+
+impl FieldLayout {
+    // Superclasses that can't be found are treated as having no fields.
+    pub fn of(class: &Class) -> Self {
+        let parent = class.super_class_name()
+            .and_then(|name| class.loader().find_class(name.as_str()))
+            .map(|parent| FieldLayout::of(&parent));
+        let (mut fields, mut offset, mut reference_offsets, mut volatile_offsets) = match parent {
+            Some(parent) => {
+                let end = parent.fields.last().map_or(OBJECT_HEADER_SIZE, |(_, descriptor, offset)| {
+                    offset + field_size(descriptor)
+                });
+                (parent.fields, end, parent.reference_offsets, parent.volatile_offsets)
+            }
+            None => (Vec::new(), OBJECT_HEADER_SIZE, Vec::new(), Vec::new())
+        };
+        let declared = fields.len();
+
+        let mut own = class.fields().iter()
+            .filter(|field| !field.is_static())
+            .map(|field| (IStr::new(field.name()), field.descriptor().clone(), field.is_volatile()))
+            .collect::<Vec<_>>();
+        own.sort_by_key(|(_, descriptor, _)| std::cmp::Reverse(field_size(descriptor)));
+        for (name, descriptor, volatile) in own {
+            let size = field_size(&descriptor);
+            offset = (offset + size - 1) & !(size - 1);
+            if descriptor.is_reference() {
+                reference_offsets.push(offset);
+            }
+            if volatile {
+                volatile_offsets.push(offset);
+            }
+            fields.push((name, descriptor, offset));
+            offset += size;
+        }
+        FieldLayout { fields, volatile_offsets, declared, size: align_object_size(offset), reference_offsets }
+    }
+
+    // The total size of an instance, including the header.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    // Fields declared by a subclass hide ones with the same name and descriptor in its superclasses.
+    pub fn field_offset(&self, name: &str, descriptor: &FieldDescriptor) -> Option<usize> {
+        self.fields.iter()
+            .rev()
+            .find(|(field_name, field_descriptor, _)| field_name.as_str() == name && field_descriptor == descriptor)
+            .map(|(_, _, offset)| *offset)
+    }
+
+    // Like field_offset, along with how the field has to be accessed.
+    pub fn find_field(&self, name: &str, descriptor: &FieldDescriptor) -> Option<(usize, MemoryOrder)> {
+        self.field_offset(name, descriptor).map(|offset| (offset, self.order(offset)))
+    }
+
+    pub fn order(&self, offset: usize) -> MemoryOrder {
+        if self.volatile_offsets.contains(&offset) {
+            MemoryOrder::Volatile
+        } else {
+            MemoryOrder::Plain
+        }
+    }
+
+    pub fn reference_offsets(&self) -> &[usize] {
+        &self.reference_offsets
+    }
+
+    // The name, descriptor and offset of every field, in the order they are laid out.
+    pub fn fields(&self) -> impl Iterator<Item = (&str, &FieldDescriptor, usize)> {
+        self.fields.iter().map(|(name, descriptor, offset)| (name.as_str(), descriptor, *offset))
+    }
+
+    // Like fields, but only the ones declared by the class itself rather than by its superclasses.
+    pub fn declared_fields(&self) -> impl Iterator<Item = (&str, &FieldDescriptor, usize)> {
+        self.fields().skip(self.declared)
+    }
+}
+
+// Anything that lives in the heap, which is identified by its offset.
+pub trait HeapObject {
+    fn offset(&self) -> usize;
+}
+
+macro_rules! impl_heap_object {
+    ($T:ident) => {
+        impl<'a> $T<'a> {
+            pub(super) fn new(heap: &'a HeapSpace, offset: usize) -> Self {
+                $T { heap, offset }
+            }
+
+            // Objects get moved by the collector, so the offset is only valid until the next safepoint.
+            pub fn offset(&self) -> usize {
+                self.offset
+            }
+
+            pub fn equals(&self, other: &Self) -> bool {
+                self.offset == other.offset
+            }
+
+            pub fn identity_hash(&self) -> i32 {
+                self.heap.identity_hash(self.offset)
+            }
+
+            fn read(&self, position: usize, size: usize) -> u64 {
+                self.heap.read(self.offset, position, size)
+            }
+
+            fn write(&self, position: usize, size: usize, value: u64) {
+                self.heap.write(self.offset, position, size, value)
+            }
+        }
+
+        impl HeapObject for $T<'_> {
+            fn offset(&self) -> usize {
+                self.offset
+            }
+        }
+    }
+}
+
+// Generates the typed accessors. Positions are converted to byte offsets from the start of the
+// object by the given function, which is also where bounds get checked.
 macro_rules! impl_getter_setter {
-    ($field_name:ident) => {
+    ($position:ident) => {
         pub fn get_bool(&self, index: usize) -> bool {
-            self.get(index) != 0
+            self.read(self.$position(index, 1), 1) != 0
         }
 
         pub fn get_byte(&self, index: usize) -> i8 {
-            self.get(index) as i8
+            self.read(self.$position(index, 1), 1) as i8
         }
 
         pub fn get_char(&self, index: usize) -> char {
-            char::from_u32(self.get(index)).expect(&format!("Invalid character at index {}!", index))
+            let value = self.read(self.$position(index, 2), 2) as u32;
+            char::from_u32(value).unwrap_or_else(|| panic!("Invalid character at index {}!", index))
         }
 
         pub fn get_short(&self, index: usize) -> i16 {
-            self.get(index) as i16
+            self.read(self.$position(index, 2), 2) as i16
         }
 
         pub fn get_int(&self, index: usize) -> i32 {
-            self.get(index) as i32
+            self.read(self.$position(index, 4), 4) as i32
         }
 
         pub fn get_float(&self, index: usize) -> f32 {
-            f32::from_bits(self.get(index))
+            f32::from_bits(self.read(self.$position(index, 4), 4) as u32)
         }
 
         pub fn get_long(&self, index: usize) -> i64 {
-            let most = self.get(index) as u64;
-            let least = self.get(index + 1) as u64;
-            ((most << 32) | least) as i64
+            self.read(self.$position(index, 8), 8) as i64
         }
 
         pub fn get_double(&self, index: usize) -> f64 {
-            let most = self.get(index) as u64;
-            let least = self.get(index + 1) as u64;
-            f64::from_bits((most << 32) | least)
+            f64::from_bits(self.read(self.$position(index, 8), 8))
         }
 
         pub fn set_bool(&self, index: usize, value: bool) {
-            self.set(index, value as u32);
+            self.write(self.$position(index, 1), 1, value as u64);
         }
 
         pub fn set_byte(&self, index: usize, value: i8) {
-            self.set(index, value as u32);
+            self.write(self.$position(index, 1), 1, value as u8 as u64);
         }
 
         pub fn set_char(&self, index: usize, value: char) {
-            self.set(index, value as u32);
+            self.write(self.$position(index, 2), 2, value as u64);
         }
 
         pub fn set_short(&self, index: usize, value: i16) {
-            self.set(index, value as u32);
+            self.write(self.$position(index, 2), 2, value as u16 as u64);
         }
 
         pub fn set_int(&self, index: usize, value: i32) {
-            self.set(index, value as u32);
+            self.write(self.$position(index, 4), 4, value as u32 as u64);
         }
 
         pub fn set_float(&self, index: usize, value: f32) {
-            self.set(index, value.to_bits());
+            self.write(self.$position(index, 4), 4, value.to_bits() as u64);
         }
 
         pub fn set_long(&self, index: usize, value: i64) {
-            self.set(index, (value >> 32) as u32);
-            self.set(index + 1, value as u32);
+            self.write(self.$position(index, 8), 8, value as u64);
         }
 
         pub fn set_double(&self, index: usize, value: f64) {
-            let bits = value.to_bits();
-            self.set(index, (bits >> 32) as u32);
-            self.set(index + 1, bits as u32);
+            self.write(self.$position(index, 8), 8, value.to_bits());
         }
+    }
+}
 
-        pub fn get(&self, index: usize) -> u32 {
-            self.$field_name.read().unwrap().get(index).map_or(0, |value| *value)
-        }
+// A view of an instance in the heap. Fields are accessed by the byte offsets that the class's
+// FieldLayout gives for them.
+pub struct InstanceObject<'a> {
+    heap: &'a HeapSpace,
+    offset: usize
+}
 
-        pub fn set(&self, index: usize, value: u32) {
-            assert!(index < self.len(), "Index {} out of bounds for length {}!", index, self.len());
-            self.$field_name.write().unwrap().insert(index, value);
-        }
+impl_heap_object!(InstanceObject);
+
+impl InstanceObject<'_> {
+    pub fn class(&self) -> Arc<Class> {
+        self.heap.class_of(self.offset)
     }
-}
 
-macro_rules! impl_heap_object {
-    ($T:ident) => {
-        impl $T {
-            pub fn offset(&self) -> usize {
-                self.offset
-            }
+    // Where the field with the given name and descriptor is in this object, as given by the class's
+    // FieldLayout.
+    pub fn field_offset(&self, name: &str, descriptor: &FieldDescriptor) -> Option<usize> {
+        self.find_field(name, descriptor).map(|(offset, _)| offset)
+    }
 
-            pub fn len(&self) -> usize {
-                self.length
-            }
+    // Like field_offset, along with how the field has to be accessed, which is volatile for volatile
+    // fields and plain for the rest.
+    pub fn find_field(&self, name: &str, descriptor: &FieldDescriptor) -> Option<(usize, MemoryOrder)> {
+        self.heap.field_of(self.offset, name, descriptor)
+    }
 
-            pub fn equals(&self, other: &Self) -> bool {
-                self as *const Self == other as *const Self
-            }
-        }
+    pub fn get_ref(&self, position: usize) -> u32 {
+        self.read(self.field(position, REFERENCE_SIZE), REFERENCE_SIZE) as u32
+    }
+
+    pub fn set_ref(&self, position: usize, value: u32) {
+        self.write(self.field(position, REFERENCE_SIZE), REFERENCE_SIZE, value as u64);
+    }
+
+    impl_getter_setter!(field);
+
+    fn field(&self, position: usize, size: usize) -> usize {
+        assert!(position >= OBJECT_HEADER_SIZE && position % size == 0, "Invalid field offset {}!", position);
+        position
     }
 }
 
-// TODO: Look in to storing a pointer to the start of memory instead of using a vec, which should
-//  offer greater performance and lower memory footprint.
-pub struct InstanceObject {
-    offset: usize,
-    class: Arc<Class>,
-    length: usize,
-    fields: RwLock<Vec<u32>>
+// A view of an array of references in the heap, whose elements are the offsets of the objects in it.
+pub struct ReferenceArrayObject<'a> {
+    heap: &'a HeapSpace,
+    offset: usize
 }
 
-impl InstanceObject {
-    pub fn new(offset: usize, class: Arc<Class>, length: usize) -> Self {
-        InstanceObject {
-            offset,
-            class,
-            length,
-            fields: RwLock::new(Vec::with_capacity(length))
-        }
+impl_heap_object!(ReferenceArrayObject);
+
+impl ReferenceArrayObject<'_> {
+    pub fn class(&self) -> Arc<Class> {
+        self.heap.class_of(self.offset)
     }
 
-    pub fn class(&self) -> &Class {
-        &self.class
+    // The class that the array holds, or that its innermost arrays hold if it's an array of arrays.
+    pub fn element_class(&self) -> Arc<Class> {
+        self.heap.element_class_of(self.offset)
     }
 
-    impl_getter_setter!(fields);
-}
+    pub fn dimensions(&self) -> u8 {
+        self.heap.dimensions_of(self.offset)
+    }
 
-impl_heap_object!(InstanceObject);
+    pub fn len(&self) -> usize {
+        self.heap.length_of(self.offset)
+    }
 
-// TODO: Look in to storing a pointer to the start of memory instead of using a vec, which should
-//  offer greater performance and lower memory footprint.
-pub struct ReferenceArrayObject {
-    offset: usize,
-    class: Arc<Class>,
-    element_class: Arc<Class>,
-    length: usize,
-    elements: RwLock<Vec<Arc<InstanceObject>>>
-}
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 
-impl ReferenceArrayObject {
-    pub fn new(offset: usize, class: Arc<Class>, element_class: Arc<Class>, length: usize) -> Self {
-        ReferenceArrayObject {
-            offset,
-            class,
-            element_class,
-            length,
-            elements: RwLock::new(Vec::with_capacity(length))
-        }
+    pub fn get(&self, index: usize) -> u32 {
+        self.read(self.element(index, REFERENCE_SIZE), REFERENCE_SIZE) as u32
     }
 
-    pub fn class(&self) -> &Class {
-        &self.class
+    pub fn set(&self, index: usize, value: u32) {
+        self.write(self.element(index, REFERENCE_SIZE), REFERENCE_SIZE, value as u64);
     }
 
-    pub fn element_class(&self) -> &Class {
-        &self.element_class
+    fn element(&self, index: usize, size: usize) -> usize {
+        let length = self.len();
+        assert!(index < length, "Index {} out of bounds for length {}!", index, length);
+        OBJECT_HEADER_SIZE + index * size
+    }
+}
+
+// A view of an array of primitives in the heap, whose elements take up as many bytes as their type
+// needs.
+pub struct TypeArrayObject<'a> {
+    heap: &'a HeapSpace,
+    offset: usize
+}
+
+impl_heap_object!(TypeArrayObject);
+
+impl TypeArrayObject<'_> {
+    pub fn array_type(&self) -> u8 {
+        self.heap.array_type_of(self.offset)
     }
 
-    pub fn get(&self, index: usize) -> Option<Arc<InstanceObject>> {
-        self.elements.read().unwrap().get(index).map(|value| Arc::clone(value))
+    pub fn len(&self) -> usize {
+        self.heap.length_of(self.offset)
     }
 
-    pub fn set(&self, index: usize, value: Arc<InstanceObject>) {
-        assert!(index < self.length, "Index {} out of bounds for length {}!", index, self.length);
-        self.elements.write().unwrap().insert(index, value);
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
     }
-}
 
-impl_heap_object!(ReferenceArrayObject);
+    impl_getter_setter!(element);
 
-// TODO: Look in to storing a pointer to the start of memory instead of using a vec, which should
-//  offer greater performance and lower memory footprint.
-pub struct TypeArrayObject {
-    offset: usize,
-    array_type: u8,
-    length: usize,
-    elements: RwLock<Vec<u32>>
+    fn element(&self, index: usize, size: usize) -> usize {
+        let length = self.len();
+        assert!(index < length, "Index {} out of bounds for length {}!", index, length);
+        assert_eq!(element_size(self.array_type()), size, "Invalid access to array of type {}!", self.array_type());
+        OBJECT_HEADER_SIZE + index * size
+    }
 }
 
-impl TypeArrayObject {
-    pub fn new(offset: usize, array_type: u8, length: usize) -> Self {
-        TypeArrayObject { offset, array_type, length, elements: RwLock::new(Vec::with_capacity(length)) }
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use crate::assembler::assemble;
+    use crate::class_file::ClassLoader;
+    use crate::constants::*;
+    use crate::objects::{FieldLayout, HeapSpace, NoRoots, OBJECT_HEADER_SIZE};
+    use crate::utils::descriptors::FieldDescriptor;
+
+    fn descriptor(value: &str) -> FieldDescriptor {
+        FieldDescriptor::parse(value).unwrap()
     }
 
-    pub fn array_type(&self) -> u8 {
-        self.array_type
+    #[test]
+    fn packs_fields_largest_first() {
+        let source = ".class Mixed\n.super java/lang/Object\n.field flag Z\n.field count I\n.field static shared J\n\
+            .field total J\n.field letter C\n.field next LMixed;\n";
+        let class = Arc::new(ClassLoader::new()).define_class(assemble(source).unwrap());
+        let layout = FieldLayout::of(&class);
+        assert_eq!(layout.field_offset("total", &descriptor("J")), Some(16));
+        assert_eq!(layout.field_offset("count", &descriptor("I")), Some(24));
+        assert_eq!(layout.field_offset("next", &descriptor("LMixed;")), Some(28));
+        assert_eq!(layout.field_offset("letter", &descriptor("C")), Some(32));
+        assert_eq!(layout.field_offset("flag", &descriptor("Z")), Some(34));
+        assert_eq!(layout.field_offset("shared", &descriptor("J")), None);
+        assert_eq!(layout.reference_offsets(), &[28]);
+        assert_eq!(layout.size(), 40);
+
+        let heap = HeapSpace::new(1024);
+        let object = heap.allocate_ref(&class, &mut NoRoots).unwrap();
+        object.set_long(16, -2);
+        object.set_int(24, 7);
+        object.set_bool(34, true);
+        assert_eq!((object.get_long(16), object.get_int(24), object.get_bool(34)), (-2, 7, true));
+        assert_eq!(object.get_ref(28), 0);
     }
 
-    impl_getter_setter!(elements);
-}
+    #[test]
+    fn lays_out_superclass_fields_first() {
+        let loader = Arc::new(ClassLoader::new());
+        loader.define_class(assemble(".class Base\n.super java/lang/Object\n.field count I\n.field flag Z\n").unwrap());
+        let source = ".class Derived\n.super Base\n.field total J\n.field count I\n.field letter C\n";
+        let class = loader.define_class(assemble(source).unwrap());
+        let layout = FieldLayout::of(&class);
+        assert_eq!(layout.fields().map(|(name, _, offset)| (name, offset)).collect::<Vec<_>>(),
+                   vec![("count", 16), ("flag", 20), ("total", 24), ("count", 32), ("letter", 36)]);
+        assert_eq!(layout.declared_fields().count(), 3);
+        // The subclass's own field hides the one it inherits.
+        assert_eq!(layout.field_offset("count", &descriptor("I")), Some(32));
+        assert_eq!(layout.size(), 40);
+    }
 
-impl_heap_object!(TypeArrayObject);
+    #[test]
+    fn stores_array_elements_natively() {
+        let heap = HeapSpace::new(4096);
+        let bytes = heap.allocate_type_array(JVM_T_BYTE, 1024, &mut NoRoots).unwrap();
+        assert_eq!(heap.used(), OBJECT_HEADER_SIZE + 1024);
+        bytes.set_byte(1023, -1);
+        bytes.set_byte(1, 5);
+        // Setting an element overwrites it in place rather than shifting the ones after it along.
+        bytes.set_byte(1, 6);
+        assert_eq!((bytes.len(), bytes.get_byte(0), bytes.get_byte(1), bytes.get_byte(1023)), (1024, 0, 6, -1));
+
+        let doubles = heap.allocate_type_array(JVM_T_DOUBLE, 3, &mut NoRoots).unwrap();
+        doubles.set_double(2, 1.5);
+        assert_eq!(doubles.get_double(2), 1.5);
+        assert_eq!(heap.used(), OBJECT_HEADER_SIZE * 2 + 1024 + 24);
+    }
+}
@@ -14,24 +14,23 @@
  * Street, Fifth Floor, Boston, MA 02110-1301, USA.
  */
 
-use std::sync::Arc;
-use crate::utils::IdentEq;
+use super::object::HeapObject;
 
 #[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Clone)]
 pub enum Reference<T> {
-    Value(Arc<T>),
+    Value(T),
     Null
 }
 
 impl<T> Reference<T> {
-    pub fn expect(self, message: &str) -> Arc<T> {
+    pub fn expect(self, message: &str) -> T {
         match self {
             Reference::Value(value) => value,
             Reference::Null => panic!("{}", message)
         }
     }
 
-    pub fn unwrap(self) -> Arc<T> {
+    pub fn unwrap(self) -> T {
         match self {
             Reference::Value(value) => value,
             Reference::Null => panic!("called `Reference::unwrap()` on a `Null` value"),
@@ -45,20 +44,21 @@ impl<T> Reference<T> {
     pub fn is_null(&self) -> bool {
         matches!(self, Reference::Null)
     }
+}
 
+impl<T: HeapObject> Reference<T> {
+    // The same object is always at the same offset, so references are equal if their offsets are.
     pub fn equals(self, other: Self) -> bool {
-        if self.is_null() && other.is_null() {
-            return true;
-        }
-        if self.is_not_null() && other.is_not_null() {
-            return self.unwrap().ident_eq(&other.unwrap())
+        match (self, other) {
+            (Reference::Value(first), Reference::Value(second)) => first.offset() == second.offset(),
+            (Reference::Null, Reference::Null) => true,
+            _ => false
         }
-        return false;
     }
 }
 
-impl<T> From<Option<Arc<T>>> for Reference<T> {
-    fn from(option: Option<Arc<T>>) -> Self {
+impl<T> From<Option<T>> for Reference<T> {
+    fn from(option: Option<T>) -> Self {
         match option {
             Some(value) => Reference::Value(value),
             None => Reference::Null
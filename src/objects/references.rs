@@ -0,0 +1,218 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+use std::sync::Arc;
+use crate::types::Class;
+use crate::utils::descriptors::FieldDescriptor;
+use super::object::FieldLayout;
+
+// How strongly a reference object holds on to its referent, which depends on which of the classes in
+// java.lang.ref it extends. Anything else that extends Reference, like FinalReference, is strong.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ReferenceKind {
+    // Kept for as long as there is room, and only cleared when the heap would otherwise run out.
+    Soft,
+    // Cleared as soon as the referent is only reachable through weak references.
+    Weak,
+    // Cleared and enqueued once the referent isn't reachable at all, so that it can be cleaned up.
+    Phantom
+}
+
+impl ReferenceKind {
+    // Walks up the superclasses of the class, stopping at the first one that can't be found.
+    pub fn of(class: &Arc<Class>) -> Option<ReferenceKind> {
+        let mut class = Arc::clone(class);
+        loop {
+            match class.name() {
+                "java/lang/ref/SoftReference" => return Some(ReferenceKind::Soft),
+                "java/lang/ref/WeakReference" => return Some(ReferenceKind::Weak),
+                "java/lang/ref/PhantomReference" => return Some(ReferenceKind::Phantom),
+                "java/lang/ref/Reference" | "java/lang/Object" => return None,
+                _ => {}
+            }
+            let super_name = class.super_class_name()?;
+            class = class.loader().find_class(super_name.as_str())?;
+        }
+    }
+}
+
+// Where the fields of java.lang.ref.Reference that the collector works with live in instances of one
+// of its subclasses. Enqueueing them is left to the reference handler thread, which goes through the
+// queue's own lock like the rest of Java does.
+#[derive(Copy, Clone)]
+pub(super) struct ReferenceFields {
+    kind: ReferenceKind,
+    referent: usize
+}
+
+impl ReferenceFields {
+    // Returns None for classes that aren't soft, weak or phantom references, or don't have a referent.
+    pub(super) fn of(class: &Arc<Class>, layout: &FieldLayout) -> Option<Self> {
+        let kind = ReferenceKind::of(class)?;
+        let referent = layout.field_offset("referent", &descriptor("Ljava/lang/Object;"))?;
+        Some(ReferenceFields { kind, referent })
+    }
+
+    pub(super) fn kind(&self) -> ReferenceKind {
+        self.kind
+    }
+
+    pub(super) fn referent(&self) -> usize {
+        self.referent
+    }
+}
+
+fn descriptor(value: &str) -> FieldDescriptor {
+    FieldDescriptor::parse(value).unwrap_or_else(|| panic!("Invalid field descriptor {}!", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use crate::assembler::assemble;
+    use crate::class_file::ClassLoader;
+    use crate::constants::*;
+    use crate::objects::{HeapSpace, NoRoots, ReferenceKind};
+    use crate::test_support::Offsets;
+    use crate::types::Class;
+    use crate::utils::descriptors::FieldDescriptor;
+
+    // Just enough of java.lang.ref for the collector to find the fields it needs.
+    fn define_classes() -> Arc<ClassLoader> {
+        let loader = Arc::new(ClassLoader::new());
+        let sources = [
+            ".class java/lang/ref/Reference\n.super java/lang/Object\n.field referent Ljava/lang/Object;\n\
+                .field queue Ljava/lang/ref/ReferenceQueue;\n.field next Ljava/lang/ref/Reference;\n\
+                .field discovered Ljava/lang/ref/Reference;\n",
+            ".class java/lang/ref/SoftReference\n.super java/lang/ref/Reference\n.field timestamp J\n",
+            ".class java/lang/ref/WeakReference\n.super java/lang/ref/Reference\n",
+            ".class java/lang/ref/PhantomReference\n.super java/lang/ref/Reference\n",
+            ".class java/lang/ref/ReferenceQueue\n.super java/lang/Object\n.field head Ljava/lang/ref/Reference;\n\
+                .field queueLength J\n",
+            ".class Cache\n.super java/lang/ref/WeakReference\n.field key I\n",
+            ".class Node\n.super java/lang/Object\n.field next LNode;\n"
+        ];
+        for source in sources {
+            loader.define_class(assemble(source).unwrap());
+        }
+        loader
+    }
+
+    fn class(loader: &Arc<ClassLoader>, name: &str) -> Arc<Class> {
+        loader.get_class(name).unwrap()
+    }
+
+    fn allocate(heap: &HeapSpace, loader: &Arc<ClassLoader>, name: &str) -> usize {
+        heap.allocate_ref(&class(loader, name), &mut NoRoots).unwrap().offset()
+    }
+
+    fn field(heap: &HeapSpace, offset: usize, name: &str, descriptor: &str) -> usize {
+        heap.get_ref(offset).unwrap().field_offset(name, &FieldDescriptor::parse(descriptor).unwrap()).unwrap()
+    }
+
+    fn new_reference(heap: &HeapSpace, loader: &Arc<ClassLoader>, name: &str, referent: usize, queue: usize) -> usize {
+        let reference = allocate(heap, loader, name);
+        let object = heap.get_ref(reference).unwrap();
+        object.set_ref(field(heap, reference, "referent", "Ljava/lang/Object;"), referent as u32);
+        object.set_ref(field(heap, reference, "queue", "Ljava/lang/ref/ReferenceQueue;"), queue as u32);
+        heap.write_barrier(reference, referent);
+        heap.write_barrier(reference, queue);
+        reference
+    }
+
+    fn referent(heap: &HeapSpace, reference: usize) -> usize {
+        heap.get_ref(reference).unwrap().get_ref(field(heap, reference, "referent", "Ljava/lang/Object;")) as usize
+    }
+
+    fn queue_head(heap: &HeapSpace, queue: usize) -> usize {
+        heap.get_ref(queue).unwrap().get_ref(field(heap, queue, "head", "Ljava/lang/ref/Reference;")) as usize
+    }
+
+    #[test]
+    fn finds_the_kind_of_reference_classes() {
+        let loader = define_classes();
+        assert_eq!(ReferenceKind::of(&class(&loader, "java/lang/ref/SoftReference")), Some(ReferenceKind::Soft));
+        assert_eq!(ReferenceKind::of(&class(&loader, "Cache")), Some(ReferenceKind::Weak));
+        assert_eq!(ReferenceKind::of(&class(&loader, "java/lang/ref/Reference")), None);
+        assert_eq!(ReferenceKind::of(&class(&loader, "Node")), None);
+    }
+
+    #[test]
+    fn clears_weak_references_on_to_the_pending_list() {
+        let loader = define_classes();
+        let heap = HeapSpace::new(64 * 1024);
+        let queue = allocate(&heap, &loader, "java/lang/ref/ReferenceQueue");
+        let live = allocate(&heap, &loader, "Node");
+        let dead = allocate(&heap, &loader, "Node");
+        let cleared = new_reference(&heap, &loader, "Cache", dead, queue);
+        let kept = new_reference(&heap, &loader, "java/lang/ref/WeakReference", live, queue);
+        let mut roots = Offsets(vec![queue, live, cleared, kept]);
+
+        heap.collect(&mut roots);
+        let [queue, live, cleared, kept] = <[usize; 4]>::try_from(roots.0.clone()).unwrap();
+        assert_eq!(heap.object_count(), 4);
+        assert_eq!(referent(&heap, cleared), 0);
+        assert_eq!(referent(&heap, kept), live);
+        // The collector leaves the queue alone, for the reference handler to enqueue it in.
+        assert_eq!(queue_head(&heap, queue), 0);
+        assert!(heap.wait_for_pending_references(&mut roots, Duration::from_secs(0)));
+        assert_eq!(heap.take_pending_references(), vec![cleared]);
+        assert!(!heap.wait_for_pending_references(&mut roots, Duration::from_secs(0)));
+        assert!(heap.take_pending_references().is_empty());
+    }
+
+    #[test]
+    fn keeps_soft_references_until_memory_runs_out() {
+        let loader = define_classes();
+        let heap = HeapSpace::with_sizes(0, 2048);
+        let array = heap.allocate_type_array(JVM_T_INT, 200, &mut NoRoots).unwrap().offset();
+        let mut roots = Offsets(vec![new_reference(&heap, &loader, "java/lang/ref/SoftReference", array, 0)]);
+        heap.collect(&mut roots);
+        assert_ne!(referent(&heap, roots.0[0]), 0);
+
+        // There's only room for this once the soft reference lets go of the other array.
+        assert!(heap.allocate_type_array(JVM_T_INT, 300, &mut roots).is_ok());
+        assert_eq!(referent(&heap, roots.0[0]), 0);
+    }
+
+    #[test]
+    fn clears_phantom_references_in_minor_collections() {
+        let loader = define_classes();
+        let heap = HeapSpace::new(64 * 1024);
+        let queue = allocate(&heap, &loader, "java/lang/ref/ReferenceQueue");
+        let live = allocate(&heap, &loader, "Node");
+        let phantom = new_reference(&heap, &loader, "java/lang/ref/PhantomReference", allocate(&heap, &loader, "Node"),
+                                    queue);
+        let soft = new_reference(&heap, &loader, "java/lang/ref/SoftReference", allocate(&heap, &loader, "Node"), 0);
+        let weak = new_reference(&heap, &loader, "java/lang/ref/WeakReference", live, queue);
+        let mut roots = Offsets(vec![queue, live, phantom, soft, weak]);
+
+        heap.collect_nursery(&mut roots);
+        let [queue, live, phantom, soft, weak] = <[usize; 5]>::try_from(roots.0.clone()).unwrap();
+        assert_eq!(referent(&heap, phantom), 0);
+        assert_ne!(referent(&heap, soft), 0);
+        assert_eq!(referent(&heap, weak), live);
+        assert_eq!(queue_head(&heap, queue), 0);
+        // The pending list keeps the reference alive until the reference handler takes it.
+        let mut roots = Offsets(vec![queue]);
+        heap.collect(&mut roots);
+        assert_eq!(heap.object_count(), 2);
+        assert_eq!(heap.take_pending_references().len(), 1);
+        heap.collect(&mut roots);
+        assert_eq!(heap.object_count(), 1);
+    }
+}
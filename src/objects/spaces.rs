@@ -0,0 +1,722 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use crate::runtime::StackTrace;
+use crate::types::Class;
+use crate::utils::descriptors::array_of;
+use super::mark_word::{self, HashGenerator, AGE_MASK, FLAGS_MASK, FORWARDED, INFLATED, MARKED, MAXIMUM_LOCK_COUNT,
+                       MAXIMUM_OWNER};
+use super::memory::Memory;
+use super::object::{align_object_size, element_size, FieldLayout, OBJECT_ALIGNMENT, OBJECT_HEADER_SIZE, REFERENCE_SIZE};
+use super::references::{ReferenceFields, ReferenceKind};
+
+// Each card covers this many old generation offsets, as a power of two.
+const CARD_SHIFT: usize = 6;
+
+// The number of minor collections an object has to survive before it gets promoted.
+pub(super) const TENURING_THRESHOLD: u8 = 3;
+
+// Visits every root with a function that may change it, as objects that a root points to can move.
+pub(super) type RootVisitor<'a> = dyn FnMut(&mut dyn FnMut(&mut usize)) + 'a;
+
+// What an object is, which its header refers to by an index in to the class table rather than
+// holding a pointer to its class. Instances of soft, weak and phantom references also know where
+// their referent is, as the collector doesn't treat it like the rest of their fields. Arrays of
+// references count their dimensions, so that String[][] is two dimensions of String.
+pub(super) enum Klass {
+    Instance(Arc<Class>, FieldLayout, Option<ReferenceFields>),
+    ReferenceArray(Arc<Class>, Arc<Class>, u8),
+    TypeArray(u8)
+}
+
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq)]
+enum KlassKey {
+    Instance(usize),
+    ReferenceArray(usize, usize, u8),
+    TypeArray(u8)
+}
+
+impl Klass {
+    // The number of bytes an object of this kind with the given length takes up, including its header.
+    pub(super) fn object_size(&self, length: usize) -> usize {
+        match self {
+            Klass::Instance(_, layout, _) => layout.size(),
+            Klass::ReferenceArray(_, _, _) => align_object_size(OBJECT_HEADER_SIZE + length * REFERENCE_SIZE),
+            Klass::TypeArray(array_type) => align_object_size(OBJECT_HEADER_SIZE + length * element_size(*array_type))
+        }
+    }
+}
+
+// The internal name of an array of references, like [[Ljava/lang/String; for two dimensions of String.
+pub(super) fn reference_array_name(element_class: &Class, dimensions: u8) -> String {
+    (1..dimensions).fold(array_of(element_class.name()), |name, _| array_of(&name))
+}
+
+// A range of the heap's memory that objects are bump allocated in, from start to top. Positions are
+// in bytes from the start of the heap.
+struct Space {
+    start: usize,
+    top: usize,
+    end: usize
+}
+
+impl Space {
+    fn new(start: usize, size: usize) -> Self {
+        Space { start, top: start, end: start + size }
+    }
+
+    fn used(&self) -> usize {
+        self.top - self.start
+    }
+
+    fn contains(&self, position: usize) -> bool {
+        position >= self.start && position < self.top
+    }
+
+    fn bump(&mut self, size: usize) -> Option<usize> {
+        if self.end - self.top < size {
+            return None;
+        }
+        self.top += size;
+        Some(self.top - size)
+    }
+}
+
+// The memory of the heap, split in to a nursery that new objects are bump allocated in and an old
+// generation that objects get promoted to once they have survived long enough. The nursery is two
+// semispaces, and a minor collection copies what survives out of the one being allocated in to the
+// other. The old generation is only collected by a full collection, which slides what survives
+// down to the start of it.
+//
+// All of it is one block of memory, with the first word left unused so that offset 0 can be null,
+// then the old generation, and then the two semispaces. Offsets are positions in that block divided
+// by the object alignment, which lets 32 bits address 32 GB.
+//
+// Reference objects that get cleared are kept on a pending list until the reference handler takes
+// them to enqueue. Stack traces of throwables and inflated monitors are kept alongside the objects
+// they belong to, and go away along with their object.
+pub(super) struct Spaces {
+    memory: Memory,
+    klasses: Vec<Klass>,
+    klass_ids: HashMap<KlassKey, u32>,
+    old: Space,
+    nursery: [Space; 2],
+    current: usize,
+    cards: Vec<bool>,
+    hashes: HashGenerator,
+    pending: Vec<usize>,
+    stack_traces: HashMap<usize, StackTrace>,
+    // The owner and count of each monitor that has outgrown its object's mark word
+    monitors: HashMap<usize, (u64, u64)>
+}
+
+impl Spaces {
+    pub(super) fn new(nursery_size: usize, old_size: usize) -> Self {
+        let old_size = old_size & !(OBJECT_ALIGNMENT - 1);
+        let semispace_size = (nursery_size / 2) & !(OBJECT_ALIGNMENT - 1);
+        let old = Space::new(OBJECT_ALIGNMENT, old_size);
+        let nursery = [Space::new(old.end, semispace_size), Space::new(old.end + semispace_size, semispace_size)];
+        let memory = Memory::new(nursery[1].end);
+        assert!(memory.size() / OBJECT_ALIGNMENT <= u32::MAX as usize, "Invalid heap size {}! Offsets must fit in \
+            32 bits!", memory.size());
+        Spaces {
+            memory,
+            klasses: Vec::new(),
+            klass_ids: HashMap::new(),
+            old,
+            nursery,
+            current: 0,
+            cards: Vec::new(),
+            hashes: HashGenerator::new(),
+            pending: Vec::new(),
+            stack_traces: HashMap::new(),
+            monitors: HashMap::new()
+        }
+    }
+
+    // Offsets from this one up are in the nursery.
+    pub(super) fn nursery_base(&self) -> usize {
+        self.nursery[0].start / OBJECT_ALIGNMENT
+    }
+
+    pub(super) fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    pub(super) fn used(&self) -> usize {
+        self.old.used() + self.nursery[self.current].used()
+    }
+
+    // The number of bytes that objects can be allocated in, which leaves out the semispace that is
+    // only copied in to by collections.
+    pub(super) fn capacity(&self) -> usize {
+        (self.old.end - self.old.start) + (self.nursery[0].end - self.nursery[0].start)
+    }
+
+    pub(super) fn nursery_used(&self) -> usize {
+        self.nursery[self.current].used()
+    }
+
+    pub(super) fn object_count(&self) -> usize {
+        self.all_objects().len()
+    }
+
+    // The offsets of every object in the heap, including ones that are no longer reachable.
+    pub(super) fn all_objects(&self) -> Vec<usize> {
+        let mut objects = self.objects(&self.old);
+        objects.extend(self.objects(&self.nursery[self.current]));
+        objects
+    }
+
+    // Whether the offset is somewhere that objects are currently allocated.
+    pub(super) fn contains(&self, offset: usize) -> bool {
+        let position = offset * OBJECT_ALIGNMENT;
+        self.old.contains(position) || self.nursery[self.current].contains(position)
+    }
+
+    pub(super) fn instance_klass(&mut self, class: &Arc<Class>) -> u32 {
+        self.klass_id(KlassKey::Instance(Arc::as_ptr(class) as usize), || {
+            let layout = FieldLayout::of(class);
+            let reference = ReferenceFields::of(class, &layout);
+            Klass::Instance(Arc::clone(class), layout, reference)
+        })
+    }
+
+    pub(super) fn reference_array_klass(
+        &mut self,
+        class: &Arc<Class>,
+        element_class: &Arc<Class>,
+        dimensions: u8
+    ) -> u32 {
+        let key = KlassKey::ReferenceArray(Arc::as_ptr(class) as usize, Arc::as_ptr(element_class) as usize,
+                                           dimensions);
+        self.klass_id(key, || Klass::ReferenceArray(Arc::clone(class), Arc::clone(element_class), dimensions))
+    }
+
+    pub(super) fn type_array_klass(&mut self, array_type: u8) -> u32 {
+        self.klass_id(KlassKey::TypeArray(array_type), || Klass::TypeArray(array_type))
+    }
+
+    // Finds the index of the kind of object in the class table, adding it if it isn't there yet. The
+    // table holds on to the classes, so their addresses can't be reused for other classes.
+    fn klass_id(&mut self, key: KlassKey, create: impl FnOnce() -> Klass) -> u32 {
+        if let Some(id) = self.klass_ids.get(&key) {
+            return *id;
+        }
+        self.klasses.push(create());
+        let id = (self.klasses.len() - 1) as u32;
+        self.klass_ids.insert(key, id);
+        id
+    }
+
+    pub(super) fn object_size(&self, klass: u32, length: usize) -> usize {
+        self.klasses[klass as usize].object_size(length)
+    }
+
+    pub(super) fn klass(&self, offset: usize) -> &Klass {
+        let id = self.memory.read(offset * OBJECT_ALIGNMENT + 8, 4) as usize;
+        self.klasses.get(id).unwrap_or_else(|| panic!("Invalid class index {} in object at {}!", id, offset))
+    }
+
+    pub(super) fn length(&self, offset: usize) -> usize {
+        self.memory.read(offset * OBJECT_ALIGNMENT + 12, 4) as usize
+    }
+
+    pub(super) fn size_of(&self, offset: usize) -> usize {
+        self.klass(offset).object_size(self.length(offset))
+    }
+
+    // Finds room for an object and writes its header, with every field or element set to 0. Objects
+    // too big for the nursery go straight in to the old generation.
+    pub(super) fn allocate(&mut self, klass: u32, length: usize) -> Option<usize> {
+        let size = self.object_size(klass, length);
+        let position = if size <= self.nursery[self.current].end - self.nursery[self.current].start {
+            self.nursery[self.current].bump(size)?
+        } else {
+            self.old.bump(size)?
+        };
+        self.memory.clear(position, size);
+        self.memory.write(position + 8, 4, klass as u64);
+        self.memory.write(position + 12, 4, length as u64);
+        Some(position / OBJECT_ALIGNMENT)
+    }
+
+    // Records that an old object may now point in to the nursery, so that the next minor collection
+    // treats it as a root.
+    pub(super) fn mark_card(&mut self, offset: usize) {
+        let card = offset >> CARD_SHIFT;
+        if card >= self.cards.len() {
+            self.cards.resize(card + 1, false);
+        }
+        self.cards[card] = true;
+    }
+
+    // Returns the identity hash code of the object, choosing one the first time it's asked for, which
+    // it then keeps for the rest of its life.
+    pub(super) fn identity_hash(&mut self, offset: usize) -> i32 {
+        let position = offset * OBJECT_ALIGNMENT;
+        let mark = self.memory.read(position, 8);
+        if mark_word::hash(mark) != 0 {
+            return mark_word::hash(mark);
+        }
+        let hash = self.hashes.next_hash();
+        self.memory.write(position, 8, mark_word::with_hash(mark, hash));
+        hash
+    }
+
+    // Enters the monitor of the object for the owner, which is the id of a thread, returning false
+    // without entering it if it's held by a different one. A monitor can be entered again by the
+    // thread holding it, which just counts how many times it has been.
+    pub(super) fn monitor_enter(&mut self, offset: usize, owner: u64) -> bool {
+        let position = offset * OBJECT_ALIGNMENT;
+        let mark = self.memory.read(position, 8);
+        if mark & INFLATED != 0 {
+            let (holder, count) = self.monitors.get_mut(&offset).unwrap();
+            if *holder != owner {
+                return false;
+            }
+            *count += 1;
+            return true;
+        }
+        let count = mark_word::lock_count(mark);
+        if count == 0 {
+            if owner > MAXIMUM_OWNER {
+                self.inflate(offset, owner, 1);
+            } else {
+                self.memory.write(position, 8, mark_word::with_owner(mark_word::with_lock_count(mark, 1), owner));
+            }
+            return true;
+        }
+        if !mark_word::is_owned_by(mark, owner) {
+            return false;
+        }
+        if count == MAXIMUM_LOCK_COUNT {
+            self.inflate(offset, owner, count + 1);
+        } else {
+            self.memory.write(position, 8, mark_word::with_lock_count(mark, count + 1));
+        }
+        true
+    }
+
+    // Returns false if the owner doesn't hold the monitor, which is an IllegalMonitorStateException.
+    pub(super) fn monitor_exit(&mut self, offset: usize, owner: u64) -> bool {
+        let position = offset * OBJECT_ALIGNMENT;
+        let mark = self.memory.read(position, 8);
+        if mark & INFLATED != 0 {
+            let (holder, count) = self.monitors.get_mut(&offset).unwrap();
+            if *holder != owner {
+                return false;
+            }
+            *count -= 1;
+            // Once it's released, the monitor goes back to living in the mark word
+            if *count == 0 {
+                self.monitors.remove(&offset);
+                self.memory.write(position, 8, mark & !INFLATED);
+            }
+            return true;
+        }
+        let count = mark_word::lock_count(mark);
+        if count == 0 || !mark_word::is_owned_by(mark, owner) {
+            return false;
+        }
+        let mark = mark_word::with_lock_count(mark, count - 1);
+        self.memory.write(position, 8, if count == 1 { mark_word::with_owner(mark, 0) } else { mark });
+        true
+    }
+
+    // Lets go of the monitor however many times the owner has entered it, returning how many that was,
+    // which is 0 if the owner doesn't hold it. This is how Object.wait gives up the monitor.
+    pub(super) fn monitor_release(&mut self, offset: usize, owner: u64) -> u64 {
+        let mut count = 0;
+        while self.monitor_exit(offset, owner) {
+            count += 1;
+        }
+        count
+    }
+
+    pub(super) fn holds_monitor(&self, offset: usize, owner: u64) -> bool {
+        let mark = self.memory.read(offset * OBJECT_ALIGNMENT, 8);
+        if mark & INFLATED != 0 {
+            return self.monitors.get(&offset).map_or(false, |(holder, _)| *holder == owner);
+        }
+        mark_word::lock_count(mark) != 0 && mark_word::is_owned_by(mark, owner)
+    }
+
+    // Moves the monitor's owner and count out of the mark word, for when they don't fit in it.
+    fn inflate(&mut self, offset: usize, owner: u64, count: u64) {
+        let position = offset * OBJECT_ALIGNMENT;
+        let mark = self.memory.read(position, 8);
+        let mark = mark_word::with_owner(mark_word::with_lock_count(mark, 0), 0);
+        self.memory.write(position, 8, mark | INFLATED);
+        self.monitors.insert(offset, (owner, count));
+    }
+
+    pub(super) fn has_pending_references(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    // Hands the references that collections have cleared over to the reference handler, which the
+    // collector stops keeping track of from then on.
+    pub(super) fn take_pending_references(&mut self) -> Vec<usize> {
+        std::mem::take(&mut self.pending)
+    }
+
+    pub(super) fn set_stack_trace(&mut self, offset: usize, stack_trace: StackTrace) {
+        self.stack_traces.insert(offset, stack_trace);
+    }
+
+    pub(super) fn stack_trace(&self, offset: usize) -> Option<StackTrace> {
+        self.stack_traces.get(&offset).map(Arc::clone)
+    }
+
+    // Copies everything in the nursery that is reachable from the roots or from old objects on dirty
+    // cards, returning the number of bytes freed. Returns None without collecting if the old
+    // generation might not have room for everything that would be promoted. Soft references always
+    // keep their referents through minor collections.
+    pub(super) fn collect_nursery(&mut self, roots: &mut RootVisitor) -> Option<usize> {
+        let from = self.current;
+        let to = 1 - from;
+        if self.old.end - self.old.top < self.nursery[from].used() {
+            return None;
+        }
+        let before = self.used();
+        let mut promoted = Vec::new();
+        let mut discovered = Vec::new();
+        roots(&mut |offset| *offset = self.evacuate(*offset, &mut promoted));
+        let mut pending = std::mem::take(&mut self.pending);
+        pending.iter_mut().for_each(|offset| *offset = self.evacuate(*offset, &mut promoted));
+        self.pending = pending;
+
+        let dirty = std::mem::take(&mut self.cards);
+        for offset in self.objects(&self.old) {
+            if dirty.get(offset >> CARD_SHIFT) == Some(&true) {
+                self.scavenge(offset, &mut promoted, &mut discovered);
+            }
+        }
+        let mut scanned = self.nursery[to].start;
+        self.scan(&mut scanned, &mut promoted, &mut discovered);
+        let mut index = 0;
+        while index < discovered.len() {
+            let reference = discovered[index];
+            index += 1;
+            let field = self.referent_field(reference);
+            if self.reference_kind(reference) == Some(ReferenceKind::Soft) {
+                let referent = self.evacuate(self.read_reference(reference, field), &mut promoted);
+                self.write_reference(reference, field, referent);
+                self.scan(&mut scanned, &mut promoted, &mut discovered);
+            }
+        }
+        for reference in discovered {
+            let field = self.referent_field(reference);
+            let referent = self.forwardee(self.read_reference(reference, field));
+            self.write_reference(reference, field, referent.unwrap_or(0));
+            if referent.is_none() {
+                self.pending.push(reference);
+            }
+        }
+        self.stack_traces = std::mem::take(&mut self.stack_traces).into_iter()
+            .filter_map(|(offset, stack_trace)| Some((self.forwardee(offset)?, stack_trace)))
+            .collect();
+        self.monitors = std::mem::take(&mut self.monitors).into_iter()
+            .filter_map(|(offset, monitor)| Some((self.forwardee(offset)?, monitor)))
+            .collect();
+
+        self.nursery[from].top = self.nursery[from].start;
+        self.current = to;
+        Some(before - self.used())
+    }
+
+    // Marks everything reachable from the roots, then slides what survives in the old generation down
+    // and promotes what survives in the nursery for as long as there is room, returning the number of
+    // bytes freed. Soft references are only cleared if asked to, which is left for when the heap
+    // would otherwise run out.
+    pub(super) fn collect(&mut self, roots: &mut RootVisitor, clear_soft_references: bool) -> usize {
+        let before = self.used();
+        self.mark(roots, clear_soft_references);
+        let stack_traces = std::mem::take(&mut self.stack_traces);
+        self.stack_traces = stack_traces.into_iter().filter(|(offset, _)| self.is_marked(*offset)).collect();
+        let monitors = std::mem::take(&mut self.monitors);
+        self.monitors = monitors.into_iter().filter(|(offset, _)| self.is_marked(*offset)).collect();
+
+        // Work out where everything is going before anything moves, so that references can be
+        // updated while every object is still where they point.
+        let from = self.current;
+        let to = 1 - from;
+        let mut forwarding = HashMap::new();
+        let mut moves = Vec::new();
+        let mut old_top = self.old.start;
+        for offset in self.objects(&self.old).into_iter().filter(|offset| self.is_marked(*offset)) {
+            let size = self.size_of(offset);
+            forwarding.insert(offset, old_top / OBJECT_ALIGNMENT);
+            moves.push((offset * OBJECT_ALIGNMENT, old_top, size));
+            old_top += size;
+        }
+        let mut survivor_top = self.nursery[to].start;
+        for offset in self.objects(&self.nursery[from]).into_iter().filter(|offset| self.is_marked(*offset)) {
+            let size = self.size_of(offset);
+            let target = if self.old.end - old_top >= size {
+                old_top += size;
+                old_top - size
+            } else {
+                survivor_top += size;
+                survivor_top - size
+            };
+            forwarding.insert(offset, target / OBJECT_ALIGNMENT);
+            moves.push((offset * OBJECT_ALIGNMENT, target, size));
+        }
+
+        let forward = |offset: usize| forwarding.get(&offset).copied().unwrap_or(offset);
+        for (position, _, _) in &moves {
+            self.update_references(position / OBJECT_ALIGNMENT, &mut |value| forward(value));
+        }
+        roots(&mut |offset| *offset = forward(*offset));
+        self.pending.iter_mut().for_each(|offset| *offset = forward(*offset));
+        self.stack_traces = std::mem::take(&mut self.stack_traces).into_iter()
+            .map(|(offset, stack_trace)| (forward(offset), stack_trace))
+            .collect();
+        self.monitors = std::mem::take(&mut self.monitors).into_iter()
+            .map(|(offset, monitor)| (forward(offset), monitor))
+            .collect();
+
+        let nursery_start = self.nursery[0].start;
+        for (position, target, size) in moves {
+            self.memory.copy(position, target, size);
+            let mark = self.memory.read(target, 8);
+            let age = if target >= nursery_start { ((mark & AGE_MASK) + 1).min(AGE_MASK) } else { mark & AGE_MASK };
+            self.memory.write(target, 8, (mark & !FLAGS_MASK) | age);
+        }
+        self.old.top = old_top;
+        self.nursery[from].top = self.nursery[from].start;
+        self.nursery[to].top = survivor_top;
+        self.current = to;
+
+        self.cards.clear();
+        for offset in self.objects(&self.old) {
+            let mut young = false;
+            self.update_references(offset, &mut |value| {
+                young |= value * OBJECT_ALIGNMENT >= nursery_start;
+                value
+            });
+            if young {
+                self.mark_card(offset);
+            }
+        }
+        before - self.used()
+    }
+
+    // The offsets of every object in the space, in the order they are laid out.
+    fn objects(&self, space: &Space) -> Vec<usize> {
+        let mut objects = Vec::new();
+        let mut position = space.start;
+        while position < space.top {
+            objects.push(position / OBJECT_ALIGNMENT);
+            position += self.size_of(position / OBJECT_ALIGNMENT);
+        }
+        objects
+    }
+
+    // The positions of the fields or elements of the object that hold references.
+    fn reference_positions(&self, offset: usize) -> Vec<usize> {
+        let position = offset * OBJECT_ALIGNMENT;
+        match self.klass(offset) {
+            Klass::Instance(_, layout, _) => layout.reference_offsets().iter().map(|field| position + field).collect(),
+            Klass::ReferenceArray(_, _, _) => (0..self.length(offset))
+                .map(|index| position + OBJECT_HEADER_SIZE + index * REFERENCE_SIZE)
+                .collect(),
+            Klass::TypeArray(_) => Vec::new()
+        }
+    }
+
+    // Replaces every non-null reference the object holds with what the updater returns for it.
+    fn update_references(&self, offset: usize, updater: &mut dyn FnMut(usize) -> usize) {
+        for position in self.reference_positions(offset) {
+            let value = self.memory.read(position, REFERENCE_SIZE) as usize;
+            if value != 0 {
+                self.memory.write(position, REFERENCE_SIZE, updater(value) as u64);
+            }
+        }
+    }
+
+    fn is_marked(&self, offset: usize) -> bool {
+        self.memory.read(offset * OBJECT_ALIGNMENT, 8) & MARKED != 0
+    }
+
+    // Marks everything reachable from the roots and the pending list. Soft references that aren't
+    // being cleared keep their referents, and then every reference whose referent didn't get marked
+    // is cleared and added to the pending list.
+    fn mark(&mut self, roots: &mut RootVisitor, clear_soft_references: bool) {
+        let mut stack = self.pending.clone();
+        roots(&mut |offset| stack.push(*offset));
+        let mut discovered = Vec::new();
+        self.trace(&mut stack, &mut discovered);
+        if !clear_soft_references {
+            let mut index = 0;
+            while index < discovered.len() {
+                let reference = discovered[index];
+                index += 1;
+                if self.reference_kind(reference) == Some(ReferenceKind::Soft) {
+                    stack.push(self.read_reference(reference, self.referent_field(reference)));
+                    self.trace(&mut stack, &mut discovered);
+                }
+            }
+        }
+        for reference in discovered {
+            let field = self.referent_field(reference);
+            if !self.is_marked(self.read_reference(reference, field)) {
+                self.write_reference(reference, field, 0);
+                self.pending.push(reference);
+            }
+        }
+    }
+
+    // Marks everything reachable from the stack apart from the referents of reference objects, which
+    // are left for later along with the references that hold them.
+    fn trace(&self, stack: &mut Vec<usize>, discovered: &mut Vec<usize>) {
+        while let Some(offset) = stack.pop() {
+            if offset == 0 || self.is_marked(offset) {
+                continue;
+            }
+            let mark = self.memory.read(offset * OBJECT_ALIGNMENT, 8);
+            self.memory.write(offset * OBJECT_ALIGNMENT, 8, mark | MARKED);
+            let referent = self.reference_fields(offset).map(|fields| offset * OBJECT_ALIGNMENT + fields.referent());
+            for position in self.reference_positions(offset) {
+                let value = self.memory.read(position, REFERENCE_SIZE) as usize;
+                if Some(position) == referent {
+                    if value != 0 {
+                        discovered.push(offset);
+                    }
+                } else {
+                    stack.push(value);
+                }
+            }
+        }
+    }
+
+    // Scavenges everything copied in to the semispace in the order it was copied, and anything
+    // promoted along the way, until there is nothing left that hasn't been.
+    fn scan(&mut self, scanned: &mut usize, promoted: &mut Vec<usize>, discovered: &mut Vec<usize>) {
+        let to = 1 - self.current;
+        loop {
+            if *scanned < self.nursery[to].top {
+                let offset = *scanned / OBJECT_ALIGNMENT;
+                *scanned += self.size_of(offset);
+                self.scavenge(offset, promoted, discovered);
+            } else if let Some(offset) = promoted.pop() {
+                self.scavenge(offset, promoted, discovered);
+            } else {
+                break;
+            }
+        }
+    }
+
+    // Evacuates everything the object points to that is still in the semispace being collected, and
+    // keeps the card of an old object dirty if it still points in to the nursery afterwards. Referents
+    // that haven't been evacuated yet are left for once everything else has been.
+    fn scavenge(&mut self, offset: usize, promoted: &mut Vec<usize>, discovered: &mut Vec<usize>) {
+        let nursery_start = self.nursery[0].start;
+        let referent = self.reference_fields(offset).map(|fields| offset * OBJECT_ALIGNMENT + fields.referent());
+        let mut young = false;
+        for position in self.reference_positions(offset) {
+            let value = self.memory.read(position, REFERENCE_SIZE) as usize;
+            if value != 0 && Some(position) == referent && self.forwardee(value).is_none() {
+                discovered.push(offset);
+            } else if value != 0 {
+                let value = self.evacuate(value, promoted);
+                young |= value * OBJECT_ALIGNMENT >= nursery_start;
+                self.memory.write(position, REFERENCE_SIZE, value as u64);
+            }
+        }
+        if young && offset * OBJECT_ALIGNMENT < nursery_start {
+            self.mark_card(offset);
+        }
+    }
+
+    // Moves an object out of the semispace being collected if it hasn't been moved yet, returning
+    // where it is now. Offsets outside of the semispace are left as they are.
+    fn evacuate(&mut self, offset: usize, promoted: &mut Vec<usize>) -> usize {
+        let position = offset * OBJECT_ALIGNMENT;
+        if !self.nursery[self.current].contains(position) {
+            return offset;
+        }
+        let mark = self.memory.read(position, 8);
+        if mark & FORWARDED != 0 {
+            return (mark >> 32) as usize;
+        }
+        let size = self.size_of(offset);
+        let age = ((mark & AGE_MASK) + 1).min(AGE_MASK);
+        let target = if age >= TENURING_THRESHOLD as u64 {
+            let target = self.old.bump(size)
+                .unwrap_or_else(|| panic!("Invalid promotion of {} bytes! The old generation is full!", size));
+            promoted.push(target / OBJECT_ALIGNMENT);
+            target
+        } else {
+            self.nursery[1 - self.current].bump(size)
+                .unwrap_or_else(|| panic!("Invalid evacuation of {} bytes! The survivor space is full!", size))
+        };
+        self.memory.copy(position, target, size);
+        self.memory.write(target, 8, (mark & !FLAGS_MASK) | age);
+        self.memory.write(position, 8, FORWARDED | ((target / OBJECT_ALIGNMENT) as u64) << 32);
+        target / OBJECT_ALIGNMENT
+    }
+
+    // Where an object in the semispace being collected has been evacuated to, or None if it hasn't
+    // been. Objects anywhere else stay where they are.
+    fn forwardee(&self, offset: usize) -> Option<usize> {
+        let position = offset * OBJECT_ALIGNMENT;
+        if !self.nursery[self.current].contains(position) {
+            return Some(offset);
+        }
+        let mark = self.memory.read(position, 8);
+        if mark & FORWARDED != 0 {
+            return Some((mark >> 32) as usize);
+        }
+        None
+    }
+
+    fn reference_fields(&self, offset: usize) -> Option<ReferenceFields> {
+        match self.klass(offset) {
+            Klass::Instance(_, _, fields) => *fields,
+            _ => None
+        }
+    }
+
+    fn reference_kind(&self, offset: usize) -> Option<ReferenceKind> {
+        self.reference_fields(offset).map(|fields| fields.kind())
+    }
+
+    // Only valid for objects that have been discovered as references.
+    fn referent_field(&self, offset: usize) -> usize {
+        self.reference_fields(offset)
+            .unwrap_or_else(|| panic!("Invalid reference object at {}! It has no referent!", offset))
+            .referent()
+    }
+
+    fn read_reference(&self, offset: usize, field: usize) -> usize {
+        self.memory.read(offset * OBJECT_ALIGNMENT + field, REFERENCE_SIZE) as usize
+    }
+
+    // Stores a reference in a field of the object, dirtying its card if that makes an old object
+    // point in to the nursery.
+    fn write_reference(&mut self, offset: usize, field: usize, value: usize) {
+        self.memory.write(offset * OBJECT_ALIGNMENT + field, REFERENCE_SIZE, value as u64);
+        let nursery_base = self.nursery_base();
+        if offset < nursery_base && value >= nursery_base {
+            self.mark_card(offset);
+        }
+    }
+}
@@ -0,0 +1,39 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+mod reference_handler;
+mod stack_trace;
+mod stack_walker;
+mod strings;
+mod thread;
+mod threads;
+mod thread_dump;
+mod vm;
+mod vm_thread;
+
+pub use stack_trace::{
+    fill_in_stack_trace, print_stack_trace, print_uncaught_exception, stack_trace, StackTrace,
+    StackTraceElement
+};
+pub use stack_walker::{
+    caller_class, FrameBatches, StackFrame, StackFrames, StackWalker, StackWalkerError, StackWalkerOption
+};
+pub use strings::{from_modified_utf8, new_string, string_chars, string_value, to_modified_utf8};
+pub use thread::{FrameRecord, Frames, Interrupted, JavaThread, Monitor, ThreadState};
+pub use threads::Threads;
+pub use thread_dump::{ThreadDump, ThreadSnapshot};
+pub use vm::{GlobalRef, JavaException, Value, Vm, VmBuilder, VmError, DEFAULT_HEAP_SIZE};
+pub use vm_thread::VmOperation;
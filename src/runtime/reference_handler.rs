@@ -0,0 +1,346 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+use std::sync::Arc;
+use std::time::Duration;
+use crate::code::{Interpreter, MethodResult};
+use crate::objects::{HeapSpace, RootSource};
+use crate::types::Class;
+use crate::utils::descriptors::FieldDescriptor;
+use super::stack_trace::print_uncaught_exception;
+use super::thread::{JavaThread, Monitor};
+use super::threads::{find_virtual_method, Threads};
+
+const NAME: &str = "Reference Handler";
+const QUEUE_CLASS: &str = "java/lang/ref/ReferenceQueue";
+const QUEUE_DESCRIPTOR: &str = "Ljava/lang/ref/ReferenceQueue;";
+const NULL_QUEUE_CLASS: &str = "java/lang/ref/ReferenceQueue$Null";
+const CLEANER_CLASS: &str = "jdk/internal/ref/Cleaner";
+// How long the handler waits for references to be cleared before checking that the heap is still there.
+const PENDING_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+// How long the handler waits before trying to enter a queue's lock again when another thread holds it.
+const MONITOR_RETRY_INTERVAL: Duration = Duration::from_millis(1);
+
+// The references the handler has taken off of the pending list and not got to yet, which are only kept
+// alive by being in here. The one it's working on is last, followed by the lock of its queue once it has
+// found it.
+struct Taken(Vec<usize>);
+
+impl RootSource for Taken {
+    fn visit_roots(&mut self, visitor: &mut dyn FnMut(&mut usize)) {
+        self.0.iter_mut().filter(|root| **root != 0).for_each(visitor);
+    }
+}
+
+// Starts the daemon thread that enqueues the references that collections clear in their queues, which
+// it does the same way as ReferenceQueue.enqueue, with the queue's lock held and the threads waiting
+// on it woken up afterwards. Cleaners are cleaned instead, like they are in HotSpot. The thread exits
+// once the heap is gone.
+pub(super) fn start_reference_handler(threads: &Arc<Threads>) {
+    let heap = match threads.heap() {
+        Some(heap) => Arc::downgrade(&heap),
+        None => return
+    };
+    let threads = Arc::clone(threads);
+    std::thread::Builder::new()
+        .name(String::from(NAME))
+        .spawn(move || {
+            let thread = threads.attach_current_thread(NAME, true, None);
+            let mut taken = Taken(Vec::new());
+            while let Some(heap) = heap.upgrade() {
+                if !heap.wait_for_pending_references(&mut taken, PENDING_CHECK_INTERVAL) {
+                    continue;
+                }
+                taken.0 = heap.take_pending_references();
+                while !taken.0.is_empty() {
+                    handle_reference(&heap, &threads, &thread, &mut taken);
+                    taken.0.pop();
+                    heap.safepoint(&mut taken);
+                }
+            }
+            threads.detach_current_thread();
+        })
+        .unwrap_or_else(|error| panic!("Failed to start the reference handler thread! {}", error));
+}
+
+// Cleans the last of the taken references if it's a cleaner, or otherwise enqueues it in its queue if it
+// has one that it hasn't already been enqueued in.
+fn handle_reference(heap: &HeapSpace, threads: &Threads, thread: &JavaThread, taken: &mut Taken) {
+    let index = taken.0.len() - 1;
+    let class = heap.get_ref(taken.0[index]).expect("Invalid pending reference! Cannot be null!").class();
+    if extends(&class, CLEANER_CLASS) {
+        clean(heap, &class, taken);
+        return;
+    }
+    let lock = match queue(heap, taken.0[index]) {
+        Some(queue) => queue_lock(heap, queue),
+        None => return
+    };
+    taken.0.push(lock);
+    enter_monitor(heap, thread, taken, index + 1);
+    let (reference, lock) = (taken.0[index], taken.0[index + 1]);
+    // Only the handler enqueues references, but a reference can enqueue itself while it waits for the lock.
+    if let Some(queue) = queue(heap, reference) {
+        enqueue(heap, reference, queue);
+        threads.notify_all(heap.identity_hash(lock));
+    }
+    heap.monitor_exit(lock, thread.id());
+    thread.monitor_exited(heap.identity_hash(lock));
+    taken.0.truncate(index + 1);
+}
+
+// The queue that the reference is to be enqueued in, which it doesn't have if it was created without
+// one or has already been enqueued.
+fn queue(heap: &HeapSpace, reference: usize) -> Option<usize> {
+    let instance = heap.get_ref(reference).unwrap();
+    let queue = instance.get_ref(instance.field_offset("queue", &descriptor(QUEUE_DESCRIPTOR))?) as usize;
+    let roots = heap.roots();
+    let none = [0, roots.get_static(QUEUE_CLASS, "NULL"), roots.get_static(QUEUE_CLASS, "ENQUEUED")];
+    if none.contains(&queue) || heap.get_ref(queue).unwrap().class().name() == NULL_QUEUE_CLASS {
+        return None;
+    }
+    Some(queue)
+}
+
+// ReferenceQueue synchronizes on an object of its own rather than itself, which older ones don't have.
+fn queue_lock(heap: &HeapSpace, queue: usize) -> usize {
+    let instance = heap.get_ref(queue).unwrap();
+    instance.field_offset("lock", &descriptor("Ljava/lang/ref/ReferenceQueue$Lock;"))
+        .map(|field| instance.get_ref(field) as usize)
+        .filter(|lock| *lock != 0)
+        .unwrap_or(queue)
+}
+
+// Links the reference in at the head of the queue like ReferenceQueue.enqueue does, with the reference's
+// next field pointing at itself when it's the only one in there.
+fn enqueue(heap: &HeapSpace, reference: usize, queue: usize) {
+    let (instance, queue_instance) = (heap.get_ref(reference).unwrap(), heap.get_ref(queue).unwrap());
+    let reference_descriptor = descriptor("Ljava/lang/ref/Reference;");
+    let head_field = queue_instance.field_offset("head", &reference_descriptor)
+        .unwrap_or_else(|| panic!("Invalid reference queue! {} has no head!", heap.class_name(queue)));
+    let head = queue_instance.get_ref(head_field) as usize;
+    let enqueued = heap.roots().get_static(QUEUE_CLASS, "ENQUEUED");
+    if let Some(field) = instance.field_offset("queue", &descriptor(QUEUE_DESCRIPTOR)) {
+        instance.set_ref(field, enqueued as u32);
+        heap.write_barrier(reference, enqueued);
+    }
+    if let Some(field) = instance.field_offset("next", &reference_descriptor) {
+        let next = if head == 0 { reference } else { head };
+        instance.set_ref(field, next as u32);
+        heap.write_barrier(reference, next);
+    }
+    queue_instance.set_ref(head_field, reference as u32);
+    heap.write_barrier(queue, reference);
+    if let Some(field) = queue_instance.field_offset("queueLength", &descriptor("J")) {
+        queue_instance.set_long(field, queue_instance.get_long(field) + 1);
+    }
+}
+
+// Runs the clean method of the cleaner that's last of the taken references. What it throws is reported
+// rather than stopping the handler.
+fn clean(heap: &HeapSpace, class: &Arc<Class>, taken: &mut Taken) {
+    let (class, method) = find_virtual_method(class, "clean", "()V")
+        .unwrap_or_else(|| panic!("Invalid cleaner! {} has no clean method!", class.name()));
+    let cleaner = *taken.0.last().unwrap();
+    let result = heap.holding(taken, || Interpreter::execute(heap, &class, &method, &[cleaner as u32]));
+    match result {
+        MethodResult::Exception(exception) => print_uncaught_exception(heap, NAME, exception.offset()),
+        MethodResult::OutOfMemory(error) => eprintln!("Exception in thread \"{}\" java.lang.OutOfMemoryError: {}",
+                                                       NAME, error),
+        _ => {}
+    }
+}
+
+// Stops for as long as another thread holds the monitor of the taken object at the index, like
+// monitorenter.
+fn enter_monitor(heap: &HeapSpace, thread: &JavaThread, taken: &mut Taken, index: usize) {
+    loop {
+        let offset = taken.0[index];
+        let monitor = Monitor::new(heap.identity_hash(offset), heap.class_name(offset));
+        if heap.monitor_enter(offset, thread.id()) {
+            thread.monitor_entered(monitor);
+            return;
+        }
+        thread.monitor_blocked(monitor);
+        heap.blocking(taken, || std::thread::sleep(MONITOR_RETRY_INTERVAL));
+    }
+}
+
+fn extends(class: &Arc<Class>, name: &str) -> bool {
+    let mut class = Arc::clone(class);
+    loop {
+        if class.name() == name {
+            return true;
+        }
+        match class.super_class_name().and_then(|super_name| class.loader().find_class(&super_name)) {
+            Some(super_class) => class = super_class,
+            None => return false
+        }
+    }
+}
+
+fn descriptor(value: &str) -> FieldDescriptor {
+    FieldDescriptor::parse(value).unwrap_or_else(|| panic!("Invalid field descriptor {}!", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use crate::assembler::assemble;
+    use crate::class_file::ClassLoader;
+    use crate::code::MethodResult;
+    use crate::objects::{HeapSpace, NoRoots};
+    use crate::runtime::{ThreadState, Threads};
+    use crate::test_support::{call, jdk_loader, Offsets};
+    use crate::types::Class;
+    use crate::utils::descriptors::FieldDescriptor;
+    use super::start_reference_handler;
+
+    // Just enough of java.lang.ref for references to be enqueued, and for a thread to wait on a queue.
+    const SOURCES: [&str; 9] = [
+        ".class java/lang/Object\n.method public final native wait(J)V\n.end method\n",
+        ".class java/lang/ref/Reference\n.super java/lang/Object\n.field referent Ljava/lang/Object;\n\
+            .field queue Ljava/lang/ref/ReferenceQueue;\n.field next Ljava/lang/ref/Reference;\n",
+        ".class java/lang/ref/WeakReference\n.super java/lang/ref/Reference\n",
+        ".class java/lang/ref/PhantomReference\n.super java/lang/ref/Reference\n",
+        ".class java/lang/ref/ReferenceQueue$Lock\n.super java/lang/Object\n",
+        ".class java/lang/ref/ReferenceQueue\n.super java/lang/Object\n.field head Ljava/lang/ref/Reference;\n\
+            .field queueLength J\n.field lock Ljava/lang/ref/ReferenceQueue$Lock;\n",
+        ".class State\n.super java/lang/Object\n.field cleaned Z\n",
+        r#"
+.class jdk/internal/ref/Cleaner
+.super java/lang/ref/PhantomReference
+.field thunk LState;
+.method public clean()V
+.limit stack 2
+.limit locals 1
+    aload_0
+    getfield jdk/internal/ref/Cleaner/thunk LState;
+    iconst_1
+    putfield State/cleaned Z
+    return
+.end method
+"#,
+        ".class Node\n.super java/lang/Object\n"
+    ];
+
+    fn define_classes() -> Arc<ClassLoader> {
+        let loader = jdk_loader();
+        SOURCES.iter().for_each(|source| drop(loader.define_class(assemble(source).unwrap())));
+        loader
+    }
+
+    fn allocate(heap: &HeapSpace, loader: &ClassLoader, name: &str) -> usize {
+        heap.allocate_ref(&loader.get_class(name).unwrap(), &mut NoRoots).unwrap().offset()
+    }
+
+    fn field(heap: &HeapSpace, offset: usize, name: &str, descriptor: &str) -> usize {
+        heap.get_ref(offset).unwrap().field_offset(name, &FieldDescriptor::parse(descriptor).unwrap()).unwrap()
+    }
+
+    fn get(heap: &HeapSpace, offset: usize, name: &str, descriptor: &str) -> usize {
+        heap.get_ref(offset).unwrap().get_ref(field(heap, offset, name, descriptor)) as usize
+    }
+
+    fn set(heap: &HeapSpace, offset: usize, name: &str, descriptor: &str, value: usize) {
+        heap.get_ref(offset).unwrap().set_ref(field(heap, offset, name, descriptor), value as u32);
+        heap.write_barrier(offset, value);
+    }
+
+    fn new_reference(heap: &HeapSpace, loader: &ClassLoader, name: &str, queue: usize) -> usize {
+        let reference = allocate(heap, loader, name);
+        set(heap, reference, "referent", "Ljava/lang/Object;", allocate(heap, loader, "Node"));
+        set(heap, reference, "queue", "Ljava/lang/ref/ReferenceQueue;", queue);
+        reference
+    }
+
+    fn lock(heap: &HeapSpace, queue: usize) -> usize {
+        get(heap, queue, "lock", "Ljava/lang/ref/ReferenceQueue$Lock;")
+    }
+
+    fn head(heap: &HeapSpace, queue: usize) -> usize {
+        get(heap, queue, "head", "Ljava/lang/ref/Reference;")
+    }
+
+    // Waits on the queue's lock until something is enqueued, like ReferenceQueue.remove does.
+    fn remove(heap: &HeapSpace, threads: &Threads, object: &Arc<Class>, queue: usize) {
+        let owner = Threads::current().unwrap().id();
+        let mut roots = Offsets(vec![queue]);
+        while !heap.monitor_enter(lock(heap, roots.0[0]), owner) {
+            threads.sleep(Duration::from_millis(1), &mut roots).unwrap();
+        }
+        while head(heap, roots.0[0]) == 0 {
+            let lock = lock(heap, roots.0[0]) as u32;
+            let result = heap.holding(&mut roots, || call(heap, object, "wait", &[lock, 0, 0]));
+            assert!(matches!(result, MethodResult::Void));
+        }
+        assert!(heap.monitor_exit(lock(heap, roots.0[0]), owner));
+    }
+
+    #[test]
+    fn enqueues_cleared_references_through_the_queue_lock_and_runs_cleaners() {
+        let loader = define_classes();
+        let heap = Arc::new(HeapSpace::new(1 << 16));
+        let threads = Threads::new(&heap);
+        start_reference_handler(&threads);
+        threads.attach_current_thread("main", false, None);
+
+        let queue = allocate(&heap, &loader, "java/lang/ref/ReferenceQueue");
+        set(&heap, queue, "lock", "Ljava/lang/ref/ReferenceQueue$Lock;",
+            allocate(&heap, &loader, "java/lang/ref/ReferenceQueue$Lock"));
+        let weak = new_reference(&heap, &loader, "java/lang/ref/WeakReference", queue);
+        let state = allocate(&heap, &loader, "State");
+        let cleaner = new_reference(&heap, &loader, "jdk/internal/ref/Cleaner", 0);
+        set(&heap, cleaner, "thunk", "LState;", state);
+        let mut roots = Offsets(vec![queue, weak, state, cleaner]);
+
+        let handle = heap.roots().new_global_handle(queue);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let remover = {
+            let (heap, threads) = (Arc::clone(&heap), Arc::clone(&threads));
+            let object = loader.get_class("java/lang/Object").unwrap();
+            std::thread::spawn(move || {
+                sender.send(threads.attach_current_thread("remover", false, None)).unwrap();
+                remove(&heap, &threads, &object, heap.roots().global_handle(handle));
+                threads.detach_current_thread();
+            })
+        };
+        let thread = receiver.recv().unwrap();
+        while thread.state() != ThreadState::Waiting {
+            threads.sleep(Duration::from_millis(1), &mut roots).unwrap();
+        }
+        heap.roots().delete_global_handle(handle);
+
+        heap.collect(&mut roots);
+        // The remover only wakes up once the handler has enqueued the reference and notified the lock.
+        threads.native(&mut roots, || remover.join().unwrap());
+        let [queue, weak, state, _] = <[usize; 4]>::try_from(roots.0.clone()).unwrap();
+        assert_eq!(get(&heap, weak, "referent", "Ljava/lang/Object;"), 0);
+        assert_eq!(head(&heap, queue), weak);
+        assert_eq!(get(&heap, weak, "next", "Ljava/lang/ref/Reference;"), weak);
+        assert_eq!(get(&heap, weak, "queue", "Ljava/lang/ref/ReferenceQueue;"), 0);
+        assert_eq!(heap.get_ref(queue).unwrap().get_long(field(&heap, queue, "queueLength", "J")), 1);
+
+        let cleaned = field(&heap, state, "cleaned", "Z");
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !heap.get_ref(roots.0[2]).unwrap().get_bool(cleaned) {
+            assert!(Instant::now() < deadline, "The cleaner was never cleaned!");
+            threads.sleep(Duration::from_millis(1), &mut roots).unwrap();
+        }
+        threads.detach_current_thread();
+    }
+}
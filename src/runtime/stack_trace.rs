@@ -0,0 +1,305 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::io::{self, Write};
+use std::sync::Arc;
+use crate::objects::HeapSpace;
+use crate::utils::descriptors::FieldDescriptor;
+use super::strings::string_value;
+use super::thread::FrameRecord;
+use super::threads::Threads;
+
+// The frames of a throwable at the point it was filled in, with the one that filled it in first, which
+// is what Throwable.getStackTrace returns.
+pub type StackTrace = Arc<[StackTraceElement]>;
+
+// A frame in a stack trace, like java.lang.StackTraceElement, which keeps what it needs to print itself
+// rather than the frame's class and method.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackTraceElement {
+    declaring_class: String,
+    method_name: String,
+    file_name: Option<String>,
+    line_number: i32
+}
+
+// What StackTraceElement uses for the line numbers of native methods and of frames with none.
+const NATIVE_LINE_NUMBER: i32 = -2;
+const UNKNOWN_LINE_NUMBER: i32 = -1;
+
+impl StackTraceElement {
+    // The line number is negative if there isn't one, with -2 meaning the method is native.
+    pub fn new(declaring_class: &str, method_name: &str, file_name: Option<&str>, line_number: i32) -> Self {
+        StackTraceElement {
+            declaring_class: String::from(declaring_class),
+            method_name: String::from(method_name),
+            file_name: file_name.map(String::from),
+            line_number
+        }
+    }
+
+    pub fn of(frame: &FrameRecord) -> Self {
+        let line_number = match frame.line_number() {
+            _ if frame.method().is_native() => NATIVE_LINE_NUMBER,
+            Some(line) => line as i32,
+            None => UNKNOWN_LINE_NUMBER
+        };
+        StackTraceElement {
+            declaring_class: frame.class().name().replace('/', "."),
+            method_name: String::from(frame.method().name()),
+            file_name: frame.class().source_file_name().map(String::from),
+            line_number
+        }
+    }
+
+    // The name of the class declaring the method, in the form that Class.getName returns.
+    pub fn declaring_class(&self) -> &str {
+        &self.declaring_class
+    }
+
+    pub fn method_name(&self) -> &str {
+        &self.method_name
+    }
+
+    pub fn file_name(&self) -> Option<&str> {
+        self.file_name.as_deref()
+    }
+
+    pub fn line_number(&self) -> i32 {
+        self.line_number
+    }
+
+    pub fn is_native_method(&self) -> bool {
+        self.line_number == NATIVE_LINE_NUMBER
+    }
+}
+
+impl Display for StackTraceElement {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}(", self.declaring_class, self.method_name)?;
+        match &self.file_name {
+            _ if self.is_native_method() => write!(f, "Native Method)"),
+            Some(file) if self.line_number >= 0 => write!(f, "{}:{})", file, self.line_number),
+            Some(file) => write!(f, "{})", file),
+            None => write!(f, "Unknown Source)")
+        }
+    }
+}
+
+// Like Throwable.fillInStackTrace, which records the frames of the current thread for the throwable.
+// The frames of fillInStackTrace itself and of the constructors of the throwable are left out, as
+// they're where the trace is being filled in rather than where the throwable came from. Threads that
+// aren't attached don't record their frames, so they fill in empty traces.
+pub fn fill_in_stack_trace(heap: &HeapSpace, throwable: usize) {
+    let class = heap.get_ref(throwable).expect("Invalid throwable! Cannot fill in the stack trace of null!").class();
+    let frames = Threads::current().map_or_else(Vec::new, |thread| thread.frames());
+    let stack_trace = frames.iter().rev()
+        .skip_while(|frame| frame.method().name() == "fillInStackTrace")
+        .skip_while(|frame| frame.method().is_constructor() && class.is_subclass(frame.class()))
+        .map(StackTraceElement::of)
+        .collect::<Vec<_>>();
+    heap.set_stack_trace(throwable, stack_trace.into());
+}
+
+// Like Throwable.getStackTrace, which is empty if the stack trace was never filled in.
+pub fn stack_trace(heap: &HeapSpace, throwable: usize) -> StackTrace {
+    heap.stack_trace(throwable).unwrap_or_else(|| Arc::new([]))
+}
+
+// Like Throwable.printStackTrace, which prints the throwable and its stack trace, followed by each of
+// its causes with the frames they share with the throwable they caused left out.
+pub fn print_stack_trace(heap: &HeapSpace, throwable: usize, out: &mut dyn Write) -> io::Result<()> {
+    let trace = stack_trace(heap, throwable);
+    writeln!(out, "{}", describe(heap, throwable))?;
+    for element in trace.iter() {
+        writeln!(out, "\tat {}", element)?;
+    }
+
+    // Causes are told apart by their offsets, as identity hashes can be shared by different objects.
+    let mut seen = HashSet::new();
+    seen.insert(throwable);
+    let mut enclosing = trace;
+    let mut current = cause(heap, throwable);
+    while let Some(throwable) = current {
+        if !seen.insert(throwable) {
+            writeln!(out, "\t[CIRCULAR REFERENCE: {}]", describe(heap, throwable))?;
+            break;
+        }
+        let trace = stack_trace(heap, throwable);
+        let common = trace.iter().rev().zip(enclosing.iter().rev())
+            .take_while(|(frame, enclosing)| frame == enclosing)
+            .count();
+        writeln!(out, "Caused by: {}", describe(heap, throwable))?;
+        for element in &trace[..trace.len() - common] {
+            writeln!(out, "\tat {}", element)?;
+        }
+        if common > 0 {
+            writeln!(out, "\t... {} more", common)?;
+        }
+        enclosing = trace;
+        current = cause(heap, throwable);
+    }
+    Ok(())
+}
+
+// What the default uncaught exception handler prints when the throwable escapes the run method of the
+// thread, or main.
+pub fn print_uncaught_exception(heap: &HeapSpace, thread_name: &str, throwable: usize) {
+    let mut out = Vec::new();
+    let _ = write!(out, "Exception in thread \"{}\" ", thread_name);
+    let _ = print_stack_trace(heap, throwable, &mut out);
+    let _ = io::stderr().write_all(&out);
+}
+
+// Like Throwable.toString, which is the name of the class followed by the message, if there is one.
+pub(super) fn describe(heap: &HeapSpace, throwable: usize) -> String {
+    let instance = heap.get_ref(throwable).expect("Invalid throwable! Reference cannot be null!");
+    let descriptor = FieldDescriptor::parse("Ljava/lang/String;").unwrap();
+    match instance.field_offset("detailMessage", &descriptor).map_or(0, |field| instance.get_ref(field) as usize) {
+        0 => heap.class_name(throwable),
+        message => format!("{}: {}", heap.class_name(throwable), string_value(heap, message))
+    }
+}
+
+// Throwable's cause field holds the throwable itself until a cause is given, which means there isn't
+// one, just like null does.
+fn cause(heap: &HeapSpace, throwable: usize) -> Option<usize> {
+    let instance = heap.get_ref(throwable).expect("Invalid throwable! Reference cannot be null!");
+    let descriptor = FieldDescriptor::parse("Ljava/lang/Throwable;").unwrap();
+    let cause = instance.field_offset("cause", &descriptor).map_or(0, |field| instance.get_ref(field) as usize);
+    if cause == 0 || cause == throwable {
+        return None;
+    }
+    Some(cause)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use crate::assembler::assemble;
+    use crate::class_file::ClassLoader;
+    use crate::class_file::attributes::AttributeRegistry;
+    use crate::code::{Interpreter, MethodResult};
+    use crate::objects::{HeapSpace, NoRoots};
+    use crate::runtime::Threads;
+    use crate::types::Class;
+    use crate::utils::descriptors::FieldDescriptor;
+    use crate::verifier::VerifyMode;
+    use super::{fill_in_stack_trace, print_stack_trace, stack_trace, StackTraceElement};
+
+    const THROWABLE_SOURCE: &str = r#"
+.class java/lang/Throwable
+.super java/lang/Object
+.field cause Ljava/lang/Throwable;
+"#;
+
+    const FAILURE_SOURCE: &str = r#"
+.class Failure
+.super java/lang/Throwable
+"#;
+
+    // Throws a failure caused by another one made on the line after it.
+    const THROWER_SOURCE: &str = r#"
+.class Thrower
+.super java/lang/Object
+.source Thrower.java
+.method public static fail()V
+.limit stack 3
+.line 5
+    new Failure
+    dup
+.line 6
+    new Failure
+    putfield java/lang/Throwable/cause Ljava/lang/Throwable;
+.line 7
+    athrow
+.end method
+"#;
+
+    // There are no constructors to run yet, so the thrower has to go unverified to use what it makes.
+    fn define_classes() -> (Arc<Class>, Arc<Class>) {
+        let loader = Arc::new(ClassLoader::with_options(AttributeRegistry::default(), VerifyMode::None));
+        loader.define_class(assemble(THROWABLE_SOURCE).unwrap());
+        let failure = loader.define_class(assemble(FAILURE_SOURCE).unwrap());
+        (failure, loader.define_class(assemble(THROWER_SOURCE).unwrap()))
+    }
+
+    fn print(heap: &HeapSpace, throwable: usize) -> String {
+        let mut out = Vec::new();
+        print_stack_trace(heap, throwable, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn fills_in_stack_traces_where_throwables_are_filled_in() {
+        let (_, thrower) = define_classes();
+        let heap = Arc::new(HeapSpace::new(1 << 10));
+        let threads = Threads::new(&heap);
+        let thread = threads.attach_current_thread("main", false, None);
+
+        let method = thrower.methods().iter().find(|method| method.name() == "fail").unwrap();
+        let exception = match Interpreter::execute(&heap, &thrower, method, &[]) {
+            MethodResult::Exception(exception) => exception.offset(),
+            _ => panic!("Expected fail to throw!")
+        };
+        // The constructor is what fills the trace in, and there isn't one to run yet.
+        assert!(stack_trace(&heap, exception).is_empty());
+        {
+            let _entered = thread.enter_frame(&thrower, method);
+            thread.set_pc(4);
+            fill_in_stack_trace(&heap, exception);
+        }
+        let expected = StackTraceElement::new("Thrower", "fail", Some("Thrower.java"), 6);
+        assert_eq!(&*stack_trace(&heap, exception), [expected]);
+        assert_eq!(print(&heap, exception), "Failure\n\tat Thrower.fail(Thrower.java:6)\nCaused by: Failure\n");
+        threads.detach_current_thread();
+    }
+
+    #[test]
+    fn leaves_frames_in_common_with_enclosing_traces_out_of_causes() {
+        let (failure, _) = define_classes();
+        let heap = HeapSpace::new(1 << 10);
+        let frame = |method: &str, line: i32| StackTraceElement::new("Main", method, Some("Main.java"), line);
+        let mut throwables = Vec::new();
+        let traces = [
+            vec![frame("wrap", 20), frame("main", 3)],
+            vec![frame("read", 9), frame("wrap", 18), frame("main", 3)]
+        ];
+        for trace in traces {
+            let throwable = heap.allocate_ref(&failure, &mut NoRoots).unwrap().offset();
+            heap.set_stack_trace(throwable, trace.into());
+            throwables.push(throwable);
+        }
+        let outer = heap.get_ref(throwables[0]).unwrap();
+        let cause = outer.field_offset("cause", &FieldDescriptor::parse("Ljava/lang/Throwable;").unwrap()).unwrap();
+        outer.set_ref(cause, throwables[1] as u32);
+        heap.get_ref(throwables[1]).unwrap().set_ref(cause, throwables[1] as u32);
+
+        assert_eq!(print(&heap, throwables[0]), "\
+Failure
+\tat Main.wrap(Main.java:20)
+\tat Main.main(Main.java:3)
+Caused by: Failure
+\tat Main.read(Main.java:9)
+\tat Main.wrap(Main.java:18)
+\t... 1 more
+");
+        assert!(stack_trace(&heap, heap.allocate_ref(&failure, &mut NoRoots).unwrap().offset()).is_empty());
+    }
+}
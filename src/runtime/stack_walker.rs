@@ -0,0 +1,373 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+
+use std::cell::RefCell;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use crate::types::Class;
+use super::stack_trace::StackTraceElement;
+use super::thread::{FrameRecord, Frames, JavaThread};
+use super::threads::Threads;
+
+// The classes whose frames are those of reflection calling methods on behalf of others, which walks
+// skip unless they're asked to show them.
+const REFLECTION_PACKAGES: [&str; 3] = ["java/lang/reflect/", "jdk/internal/reflect/", "sun/reflect/"];
+const STACK_WALKER_NAME: &str = "java/lang/StackWalker";
+const ABSTRACT_STACK_WALKER_NAME: &str = "java/lang/StackStreamFactory$AbstractStackWalker";
+
+thread_local! {
+    // The walks that the thread is in the middle of handing out in batches, with the latest last.
+    static BATCHED_WALKS: RefCell<BatchedWalks> = RefCell::new(BatchedWalks { next_anchor: 1, walks: Vec::new() });
+}
+
+// The options of java.lang.StackWalker.Option.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum StackWalkerOption {
+    // Lets frames give out their declaring classes, which getCallerClass needs too.
+    RetainClassReference,
+    ShowReflectFrames,
+    // Shows the frames of lambda forms and hidden classes, along with those of reflection.
+    ShowHiddenFrames
+}
+
+// Like java.lang.StackWalker, which walks the frames of the current thread for code that cares about
+// who called it, like loggers working out where a message came from.
+pub struct StackWalker {
+    retain_class_reference: bool,
+    show_reflect_frames: bool,
+    show_hidden_frames: bool
+}
+
+impl StackWalker {
+    pub fn new(options: &[StackWalkerOption]) -> Self {
+        StackWalker {
+            retain_class_reference: options.contains(&StackWalkerOption::RetainClassReference),
+            show_reflect_frames: options.contains(&StackWalkerOption::ShowReflectFrames),
+            show_hidden_frames: options.contains(&StackWalkerOption::ShowHiddenFrames)
+        }
+    }
+
+    // Like StackWalker.walk, which hands the frames of the current thread to the function, starting
+    // with the one it's running. Frames are only looked at as the function gets to them, so one that
+    // stops early never pays for the rest of the stack. Threads that aren't attached have no frames.
+    pub fn walk<R>(&self, function: impl FnOnce(StackFrames<'_>) -> R) -> R {
+        let thread = Threads::current();
+        function(StackFrames { walker: self, frames: thread.as_deref().map(JavaThread::walk_frames) })
+    }
+
+    pub fn for_each(&self, action: impl FnMut(StackFrame)) {
+        self.walk(|frames| frames.for_each(action));
+    }
+
+    // Like StackWalker.getCallerClass, which is the class of the method that called the one calling
+    // this, skipping reflection and hidden frames whatever the options are.
+    pub fn get_caller_class(&self) -> Result<Arc<Class>, StackWalkerError> {
+        if !self.retain_class_reference {
+            return Err(StackWalkerError::NoClassReference);
+        }
+        caller_class().ok_or(StackWalkerError::NoCaller)
+    }
+
+    fn shows(&self, frame: &FrameRecord) -> bool {
+        let name = frame.class().name();
+        if is_hidden(name) {
+            return self.show_hidden_frames;
+        }
+        !is_reflection(name) || self.show_reflect_frames || self.show_hidden_frames
+    }
+}
+
+// Like Reflection.getCallerClass, which is what caller-sensitive methods use to find the class of the
+// method that called them. The native that asks is in a reflection package, so its frame is skipped
+// along with the rest of reflection's and the hidden ones, leaving the method that asked followed by
+// its caller.
+pub fn caller_class() -> Option<Arc<Class>> {
+    let thread = Threads::current()?;
+    let caller = thread.walk_frames()
+        .filter(|frame| !is_reflection(frame.class().name()) && !is_hidden(frame.class().name()))
+        .nth(1)?;
+    Some(Arc::clone(caller.class()))
+}
+
+struct BatchedWalks {
+    next_anchor: i64,
+    walks: Vec<BatchedWalk>
+}
+
+struct BatchedWalk {
+    anchor: i64,
+    remaining: usize,
+    show_hidden_frames: bool
+}
+
+// A walk of the current thread's frames that Java code fetches a batch at a time, which is how
+// StackStreamFactory walks them, lasting until this is dropped. The code fetching the batches only has
+// the walk's anchor to go on, so that's what they're fetched by. The walk keeps its place by how many
+// frames it has left, which are the ones at the bottom of the stack, so that the frames the code
+// fetching them pushes on top don't move it.
+pub struct FrameBatches {
+    anchor: i64
+}
+
+impl FrameBatches {
+    // Starts below the frames of StackWalker and StackStreamFactory's walkers, which are the ones doing
+    // the walking, and skips that many more frames after them, like HotSpot does.
+    pub fn start(skip_frames: usize, show_hidden_frames: bool) -> Self {
+        let frames = Threads::current().map_or_else(Vec::new, |thread| thread.frames());
+        let walker_frames = frames.iter().rev().take_while(|frame| is_stack_walker(frame.class())).count();
+        let remaining = frames.len().saturating_sub(walker_frames + skip_frames);
+        BATCHED_WALKS.with(|walks| {
+            let mut walks = walks.borrow_mut();
+            let anchor = walks.next_anchor;
+            walks.next_anchor += 1;
+            walks.walks.push(BatchedWalk { anchor, remaining, show_hidden_frames });
+            FrameBatches { anchor }
+        })
+    }
+
+    pub fn anchor(&self) -> i64 {
+        self.anchor
+    }
+
+    // The next batch of up to the given number of frames of the walk with the anchor, leaving out those
+    // of hidden classes unless the walk shows them. None if the thread isn't in the middle of that walk.
+    pub fn next_batch(anchor: i64, size: usize) -> Option<Vec<FrameRecord>> {
+        let frames = Threads::current().map_or_else(Vec::new, |thread| thread.frames());
+        BATCHED_WALKS.with(|walks| {
+            let mut walks = walks.borrow_mut();
+            let walk = walks.walks.iter_mut().find(|walk| walk.anchor == anchor)?;
+            let mut batch = Vec::new();
+            walk.remaining = walk.remaining.min(frames.len());
+            while batch.len() < size && walk.remaining > 0 {
+                walk.remaining -= 1;
+                let frame = &frames[walk.remaining];
+                if walk.show_hidden_frames || !is_hidden(frame.class().name()) {
+                    batch.push(frame.clone());
+                }
+            }
+            Some(batch)
+        })
+    }
+}
+
+impl Drop for FrameBatches {
+    fn drop(&mut self) {
+        let anchor = self.anchor;
+        BATCHED_WALKS.with(|walks| walks.borrow_mut().walks.retain(|walk| walk.anchor != anchor));
+    }
+}
+
+fn is_stack_walker(class: &Class) -> bool {
+    let name = class.name();
+    name == STACK_WALKER_NAME || name == ABSTRACT_STACK_WALKER_NAME
+        || class.super_class_name().map_or(false, |name| &*name == ABSTRACT_STACK_WALKER_NAME)
+}
+
+fn is_reflection(class_name: &str) -> bool {
+    REFLECTION_PACKAGES.iter().any(|package| class_name.starts_with(package))
+}
+
+// Lambda forms and the classes that lambdas are spun in to, which are hidden classes in HotSpot.
+fn is_hidden(class_name: &str) -> bool {
+    class_name.starts_with("java/lang/invoke/LambdaForm$") || class_name.contains("$$Lambda")
+}
+
+// The frames that a walk goes through, which is the stream that StackWalker.walk hands over.
+pub struct StackFrames<'a> {
+    walker: &'a StackWalker,
+    frames: Option<Frames<'a>>
+}
+
+impl Iterator for StackFrames<'_> {
+    type Item = StackFrame;
+
+    fn next(&mut self) -> Option<StackFrame> {
+        let walker = self.walker;
+        let frame = self.frames.as_mut()?.find(|frame| walker.shows(frame))?;
+        Some(StackFrame { frame, retain_class_reference: walker.retain_class_reference })
+    }
+}
+
+// Like StackWalker.StackFrame.
+pub struct StackFrame {
+    frame: FrameRecord,
+    retain_class_reference: bool
+}
+
+impl StackFrame {
+    // The name of the declaring class in the form that Class.getName returns.
+    pub fn class_name(&self) -> String {
+        self.frame.class().name().replace('/', ".")
+    }
+
+    pub fn method_name(&self) -> &str {
+        self.frame.method().name()
+    }
+
+    // Only walkers with RETAIN_CLASS_REFERENCE give out classes.
+    pub fn declaring_class(&self) -> Result<&Arc<Class>, StackWalkerError> {
+        if !self.retain_class_reference {
+            return Err(StackWalkerError::NoClassReference);
+        }
+        Ok(self.frame.class())
+    }
+
+    // The method's descriptor in the form it appears in class files, like (I)V.
+    pub fn descriptor(&self) -> String {
+        let class = self.frame.class();
+        class.constant_pool().get_utf8(self.frame.method().descriptor_index() as usize)
+            .map(|descriptor| String::from(descriptor.as_str()))
+            .unwrap_or_else(|| panic!("Invalid method {}! Descriptor isn't in constant pool!", self.method_name()))
+    }
+
+    pub fn byte_code_index(&self) -> u32 {
+        self.frame.pc()
+    }
+
+    pub fn file_name(&self) -> Option<&str> {
+        self.frame.class().source_file_name()
+    }
+
+    // Negative if there isn't one, with -2 meaning the method is native, like a stack trace element.
+    pub fn line_number(&self) -> i32 {
+        self.to_stack_trace_element().line_number()
+    }
+
+    pub fn is_native_method(&self) -> bool {
+        self.frame.method().is_native()
+    }
+
+    pub fn to_stack_trace_element(&self) -> StackTraceElement {
+        StackTraceElement::of(&self.frame)
+    }
+}
+
+impl Display for StackFrame {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_stack_trace_element())
+    }
+}
+
+// What StackWalker throws when it's asked for something it can't give.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StackWalkerError {
+    // Asked for a class without RETAIN_CLASS_REFERENCE, which is an UnsupportedOperationException.
+    NoClassReference,
+    // Asked for the caller of the bottom frame, which is an IllegalCallerException.
+    NoCaller
+}
+
+impl Display for StackWalkerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StackWalkerError::NoClassReference => write!(f, "java.lang.UnsupportedOperationException: This stack \
+                walker does not have RETAIN_CLASS_REFERENCE access"),
+            StackWalkerError::NoCaller => write!(f, "java.lang.IllegalCallerException: no caller frame")
+        }
+    }
+}
+
+impl Error for StackWalkerError {}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use crate::assembler::assemble;
+    use crate::class_file::ClassLoader;
+    use crate::objects::HeapSpace;
+    use crate::runtime::Threads;
+    use crate::types::{Class, Method};
+    use super::{caller_class, StackWalker, StackWalkerError, StackWalkerOption};
+
+    const APP_SOURCE: &str = r#"
+.class App
+.super java/lang/Object
+.source App.java
+.method public static main()V
+.line 3
+    return
+.end method
+"#;
+
+    const LOGGER_SOURCE: &str = r#"
+.class Logger
+.super java/lang/Object
+.source Logger.java
+.method public static log(I)V
+.limit locals 1
+.line 8
+    return
+.end method
+"#;
+
+    const INVOKER_SOURCE: &str = r#"
+.class jdk/internal/reflect/Invoker
+.super java/lang/Object
+.method public static invoke()V
+    return
+.end method
+"#;
+
+    fn method(class: &Arc<Class>, name: &str) -> Arc<Method> {
+        Arc::clone(class.methods().iter().find(|method| method.name() == name).unwrap())
+    }
+
+    fn walk(walker: &StackWalker) -> Vec<String> {
+        walker.walk(|frames| frames.map(|frame| format!("{}.{}", frame.class_name(), frame.method_name())).collect())
+    }
+
+    // Walks as Logger.log, called from App.main through reflection.
+    #[test]
+    fn walks_frames_of_the_current_thread() {
+        let loader = Arc::new(ClassLoader::new());
+        let app = loader.define_class(assemble(APP_SOURCE).unwrap());
+        let logger = loader.define_class(assemble(LOGGER_SOURCE).unwrap());
+        let invoker = loader.define_class(assemble(INVOKER_SOURCE).unwrap());
+        let heap = Arc::new(HeapSpace::new(1 << 10));
+        let threads = Threads::new(&heap);
+        let thread = threads.attach_current_thread("main", false, None);
+        let main = thread.enter_frame(&app, &method(&app, "main"));
+        let invoke = thread.enter_frame(&invoker, &method(&invoker, "invoke"));
+        let log = thread.enter_frame(&logger, &method(&logger, "log"));
+
+        let walker = StackWalker::new(&[]);
+        assert_eq!(walk(&walker), ["Logger.log", "App.main"]);
+        assert_eq!(walk(&StackWalker::new(&[StackWalkerOption::ShowReflectFrames])),
+                   ["Logger.log", "jdk.internal.reflect.Invoker.invoke", "App.main"]);
+        walker.walk(|mut frames| {
+            let frame = frames.next().unwrap();
+            assert_eq!(frame.descriptor(), "(I)V");
+            assert_eq!(frame.to_string(), "Logger.log(Logger.java:8)");
+            assert_eq!(frame.declaring_class().err(), Some(StackWalkerError::NoClassReference));
+        });
+        assert_eq!(walker.get_caller_class().err(), Some(StackWalkerError::NoClassReference));
+
+        // Logger.log is the one asking, so the caller is the class of the method that called it.
+        let walker = StackWalker::new(&[StackWalkerOption::RetainClassReference]);
+        assert!(Arc::ptr_eq(&walker.get_caller_class().unwrap(), &app));
+        assert!(Arc::ptr_eq(&caller_class().unwrap(), &app));
+        let mut classes = Vec::new();
+        walker.for_each(|frame| classes.push(Arc::clone(frame.declaring_class().unwrap())));
+        assert!(Arc::ptr_eq(&classes[0], &logger) && Arc::ptr_eq(&classes[1], &app));
+
+        drop((log, invoke));
+        assert_eq!(walker.get_caller_class().err(), Some(StackWalkerError::NoCaller));
+        drop(main);
+        threads.detach_current_thread();
+    }
+}
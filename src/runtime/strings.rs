@@ -0,0 +1,155 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+
+use std::sync::Arc;
+use crate::class_file::ClassLoader;
+use crate::constants::JVM_T_BYTE;
+use crate::objects::{HeapSpace, InstanceObject, OutOfMemoryError, Reference, RootSource, WithRoot};
+use crate::utils::constants::JAVA_LANG_STRING_NAME;
+use crate::utils::descriptors::FieldDescriptor;
+
+const LATIN1: i8 = 0;
+const UTF16: i8 = 1;
+
+// Creates a java.lang.String holding the value. Strings are laid out like they are from JDK 9 on, with
+// their characters in a byte array that holds Latin-1 if every character fits in it and UTF-16 if not,
+// which the coder says.
+pub fn new_string(
+    heap: &HeapSpace,
+    loader: &Arc<ClassLoader>,
+    chars: &[u16],
+    roots: &mut dyn RootSource
+) -> Result<usize, OutOfMemoryError> {
+    let class = loader.find_class(JAVA_LANG_STRING_NAME)
+        .unwrap_or_else(|| panic!("Invalid string! Class {} could not be found!", JAVA_LANG_STRING_NAME));
+    let coder = if chars.iter().all(|char| *char <= 0xFF) { LATIN1 } else { UTF16 };
+    let bytes = match coder {
+        LATIN1 => chars.iter().map(|char| *char as u8).collect::<Vec<_>>(),
+        _ => chars.iter().flat_map(|char| utf16_bytes(*char)).collect()
+    };
+    let value = heap.allocate_type_array(JVM_T_BYTE, bytes.len(), roots)?;
+    bytes.iter().enumerate().for_each(|(index, byte)| value.set_byte(index, *byte as i8));
+
+    let mut value = value.offset();
+    let string = heap.allocate_ref(&class, &mut WithRoot::new(roots, &mut value))?;
+    string.set_ref(field(&string, "value", "[B"), value as u32);
+    heap.write_barrier(string.offset(), value);
+    string.set_byte(field(&string, "coder", "B"), coder);
+    Ok(string.offset())
+}
+
+// The characters of a java.lang.String, as UTF-16.
+pub fn string_chars(heap: &HeapSpace, string: usize) -> Vec<u16> {
+    let string = heap.get_ref(string).expect("Invalid string! String cannot be null!");
+    let value = match heap.get_type_array(string.get_ref(field(&string, "value", "[B")) as usize) {
+        Reference::Value(value) => value,
+        Reference::Null => return Vec::new()
+    };
+    let bytes = (0..value.len()).map(|index| value.get_byte(index) as u8).collect::<Vec<_>>();
+    match string.get_byte(field(&string, "coder", "B")) {
+        LATIN1 => bytes.iter().map(|byte| *byte as u16).collect(),
+        _ => bytes.chunks_exact(2).map(|pair| utf16_char([pair[0], pair[1]])).collect()
+    }
+}
+
+// The value of a java.lang.String, with any unpaired surrogates replaced.
+pub fn string_value(heap: &HeapSpace, string: usize) -> String {
+    String::from_utf16_lossy(&string_chars(heap, string))
+}
+
+// Encodes UTF-16 in the modified UTF-8 that JNI and class files use, where the null character takes two
+// bytes and surrogates are encoded on their own rather than as the pair's code point.
+pub fn to_modified_utf8(chars: &[u16]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(chars.len());
+    for char in chars.iter().map(|char| *char as u32) {
+        match char {
+            0x01..=0x7F => bytes.push(char as u8),
+            0x00..=0x7FF => bytes.extend([0xC0 | (char >> 6) as u8, 0x80 | (char & 0x3F) as u8]),
+            _ => {
+                bytes.extend([0xE0 | (char >> 12) as u8, 0x80 | ((char >> 6) & 0x3F) as u8, 0x80 | (char & 0x3F) as u8])
+            }
+        }
+    }
+    bytes
+}
+
+// Decodes modified UTF-8 in to UTF-16, replacing anything malformed.
+pub fn from_modified_utf8(bytes: &[u8]) -> Vec<u16> {
+    let mut chars = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let continuation = |offset: usize| bytes.get(index + offset).filter(|byte| **byte & 0xC0 == 0x80);
+        let byte = bytes[index] as u16;
+        let (char, length) = match byte {
+            0x00..=0x7F => (byte, 1),
+            0xC0..=0xDF => match continuation(1) {
+                Some(second) => (((byte & 0x1F) << 6) | (*second as u16 & 0x3F), 2),
+                None => (char::REPLACEMENT_CHARACTER as u16, 1)
+            },
+            0xE0..=0xEF => match (continuation(1), continuation(2)) {
+                (Some(second), Some(third)) => {
+                    (((byte & 0x0F) << 12) | ((*second as u16 & 0x3F) << 6) | (*third as u16 & 0x3F), 3)
+                }
+                _ => (char::REPLACEMENT_CHARACTER as u16, 1)
+            },
+            _ => (char::REPLACEMENT_CHARACTER as u16, 1)
+        };
+        chars.push(char);
+        index += length;
+    }
+    chars
+}
+
+#[cfg(target_endian = "little")]
+fn utf16_bytes(char: u16) -> [u8; 2] {
+    char.to_le_bytes()
+}
+
+#[cfg(target_endian = "big")]
+fn utf16_bytes(char: u16) -> [u8; 2] {
+    char.to_be_bytes()
+}
+
+// UTF-16 strings are in the platform's byte order, like they are in HotSpot.
+#[cfg(target_endian = "little")]
+fn utf16_char(bytes: [u8; 2]) -> u16 {
+    u16::from_le_bytes(bytes)
+}
+
+#[cfg(target_endian = "big")]
+fn utf16_char(bytes: [u8; 2]) -> u16 {
+    u16::from_be_bytes(bytes)
+}
+
+fn field(string: &InstanceObject, name: &str, descriptor: &str) -> usize {
+    string.field_offset(name, &FieldDescriptor::parse(descriptor).unwrap())
+        .unwrap_or_else(|| panic!("Invalid string class! Expected a field {} {}!", name, descriptor))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_modified_utf8, to_modified_utf8};
+
+    #[test]
+    fn round_trips_modified_utf8() {
+        let chars = "a\u{0}é€😀".encode_utf16().collect::<Vec<_>>();
+        let bytes = to_modified_utf8(&chars);
+        assert_eq!(&bytes[..4], [b'a', 0xC0, 0x80, 0xC3]);
+        assert_eq!(bytes.len(), 1 + 2 + 2 + 3 + 6);
+        assert_eq!(from_modified_utf8(&bytes), chars);
+    }
+}
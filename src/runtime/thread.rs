@@ -0,0 +1,364 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use crate::types::{Class, Method};
+
+// The states of java.lang.Thread.State, which is what thread dumps and getState report.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ThreadState {
+    New,
+    Runnable,
+    Blocked,
+    Waiting,
+    TimedWaiting,
+    Terminated
+}
+
+impl Display for ThreadState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ThreadState::New => "NEW",
+            ThreadState::Runnable => "RUNNABLE",
+            ThreadState::Blocked => "BLOCKED",
+            ThreadState::Waiting => "WAITING",
+            ThreadState::TimedWaiting => "TIMED_WAITING",
+            ThreadState::Terminated => "TERMINATED"
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// A thread that runs Java code, which is always backed by a thread of its own in the OS. Its
+// java.lang.Thread object, if it has one, is kept in a global handle, as the object can move.
+pub struct JavaThread {
+    id: u64,
+    name: String,
+    daemon: bool,
+    handle: Option<usize>,
+    state: Mutex<ThreadState>,
+    state_changed: Condvar,
+    interrupted: AtomicBool,
+    notified: AtomicBool,
+    frames: Mutex<Vec<FrameRecord>>,
+    // The monitors the thread holds, each along with the depth of the frame that entered it.
+    monitors: Mutex<Vec<(usize, Monitor)>>,
+    blocked_on: Mutex<Option<Monitor>>
+}
+
+impl JavaThread {
+    pub(super) fn new(id: u64, name: String, daemon: bool, handle: Option<usize>) -> Self {
+        JavaThread {
+            id,
+            name,
+            daemon,
+            handle,
+            state: Mutex::new(ThreadState::New),
+            state_changed: Condvar::new(),
+            interrupted: AtomicBool::new(false),
+            notified: AtomicBool::new(false),
+            frames: Mutex::new(Vec::new()),
+            monitors: Mutex::new(Vec::new()),
+            blocked_on: Mutex::new(None)
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // The VM exits once every thread that isn't a daemon has terminated, without waiting for daemons.
+    pub fn is_daemon(&self) -> bool {
+        self.daemon
+    }
+
+    // The index of the global handle that holds the thread's java.lang.Thread object.
+    pub fn handle(&self) -> Option<usize> {
+        self.handle
+    }
+
+    pub fn state(&self) -> ThreadState {
+        *self.state.lock().unwrap()
+    }
+
+    pub fn is_alive(&self) -> bool {
+        !matches!(self.state(), ThreadState::New | ThreadState::Terminated)
+    }
+
+    // Sets the thread's interrupt status, and wakes it up if it's sleeping, waiting on a monitor or joining
+    // another thread.
+    pub fn interrupt(&self) {
+        self.interrupted.store(true, Ordering::SeqCst);
+        let _state = self.state.lock().unwrap();
+        self.state_changed.notify_all();
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::SeqCst)
+    }
+
+    // Returns the interrupt status and clears it, like Thread.interrupted does.
+    pub fn clear_interrupted(&self) -> bool {
+        self.interrupted.swap(false, Ordering::SeqCst)
+    }
+
+    // The frames of the methods that the thread is running, with the one it called first first. These
+    // are only up to date while the thread is at a safepoint, as that's the only time it records where
+    // in its method each frame is.
+    pub fn frames(&self) -> Vec<FrameRecord> {
+        self.frames.lock().unwrap().clone()
+    }
+
+    // Walks the frames of the thread from the one it's running back to the one it called first, only
+    // looking at each one once the walk gets to it. Frames that get entered or exited while the walk is
+    // going on shift the ones it hasn't got to yet, so this is only for the thread to walk itself.
+    pub fn walk_frames(&self) -> Frames<'_> {
+        Frames { thread: self, depth: 0 }
+    }
+
+    // The monitors the thread holds, in the order it entered them, each along with the index in frames
+    // of the frame that entered it.
+    pub fn monitors(&self) -> Vec<(usize, Monitor)> {
+        self.monitors.lock().unwrap().clone()
+    }
+
+    // The monitor that the thread is waiting to enter, if it's blocked on one.
+    pub fn blocked_on(&self) -> Option<Monitor> {
+        self.blocked_on.lock().unwrap().clone()
+    }
+
+    // Records that the thread has started running the method, until what's returned is dropped.
+    pub(crate) fn enter_frame(&self, class: &Arc<Class>, method: &Arc<Method>) -> EnteredFrame<'_> {
+        let frame = FrameRecord { class: Arc::clone(class), method: Arc::clone(method), pc: 0 };
+        self.frames.lock().unwrap().push(frame);
+        EnteredFrame(self)
+    }
+
+    // Records where the thread is in the method it's running, which it does before it stops anywhere.
+    pub(crate) fn set_pc(&self, pc: u32) {
+        if let Some(frame) = self.frames.lock().unwrap().last_mut() {
+            frame.pc = pc;
+        }
+    }
+
+    pub(crate) fn monitor_blocked(&self, monitor: Monitor) {
+        *self.blocked_on.lock().unwrap() = Some(monitor);
+        self.set_state(ThreadState::Blocked);
+    }
+
+    pub(crate) fn monitor_entered(&self, monitor: Monitor) {
+        let depth = self.frames.lock().unwrap().len().saturating_sub(1);
+        self.monitors.lock().unwrap().push((depth, monitor));
+        if self.blocked_on.lock().unwrap().take().is_some() {
+            self.set_state(ThreadState::Runnable);
+        }
+    }
+
+    // Forgets the last time the thread entered the monitor with the hash, as monitors are exited in the
+    // opposite order to the one they were entered in.
+    pub(crate) fn monitor_exited(&self, hash: i32) {
+        let mut monitors = self.monitors.lock().unwrap();
+        if let Some(index) = monitors.iter().rposition(|(_, monitor)| monitor.hash == hash) {
+            monitors.remove(index);
+        }
+    }
+
+    pub(super) fn set_state(&self, state: ThreadState) {
+        *self.state.lock().unwrap() = state;
+        self.state_changed.notify_all();
+    }
+
+    // Only ever called by the thread itself.
+    pub(super) fn sleep(&self, duration: Duration) -> Result<(), Interrupted> {
+        let deadline = Instant::now() + duration;
+        let mut state = self.state.lock().unwrap();
+        *state = ThreadState::TimedWaiting;
+        let result = loop {
+            if self.clear_interrupted() {
+                break Err(Interrupted);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                break Ok(());
+            }
+            state = self.state_changed.wait_timeout(state, deadline - now).unwrap().0;
+        };
+        *state = ThreadState::Runnable;
+        result
+    }
+
+    // Waits for notify to be called, like Object.wait, or for the timeout to run out if there is one. Only
+    // ever called by the thread itself.
+    pub(super) fn wait_for_notify(&self, timeout: Option<Duration>) -> Result<(), Interrupted> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut state = self.state.lock().unwrap();
+        *state = if deadline.is_some() { ThreadState::TimedWaiting } else { ThreadState::Waiting };
+        let result = loop {
+            if self.notified.swap(false, Ordering::SeqCst) {
+                break Ok(());
+            }
+            if self.clear_interrupted() {
+                break Err(Interrupted);
+            }
+            state = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break Ok(());
+                    }
+                    self.state_changed.wait_timeout(state, deadline - now).unwrap().0
+                }
+                None => self.state_changed.wait(state).unwrap()
+            };
+        };
+        // A notify that came in after the timeout ran out has been taken by this wait all the same.
+        self.notified.store(false, Ordering::SeqCst);
+        *state = ThreadState::Runnable;
+        result
+    }
+
+    pub(super) fn notify(&self) {
+        self.notified.store(true, Ordering::SeqCst);
+        let _state = self.state.lock().unwrap();
+        self.state_changed.notify_all();
+    }
+
+    // Waits for the other thread to terminate, which the thread calling it can be interrupted out of.
+    // Interrupting the calling thread wakes up its own condition rather than the one it's waiting on,
+    // so it wakes up every so often to check.
+    pub(super) fn join(&self, other: &JavaThread) -> Result<(), Interrupted> {
+        self.set_state(ThreadState::Waiting);
+        let result = {
+            let mut state = other.state.lock().unwrap();
+            loop {
+                if *state == ThreadState::Terminated {
+                    break Ok(());
+                }
+                if self.clear_interrupted() {
+                    break Err(Interrupted);
+                }
+                state = other.state_changed.wait_timeout(state, JOIN_INTERRUPT_CHECK).unwrap().0;
+            }
+        };
+        self.set_state(ThreadState::Runnable);
+        result
+    }
+}
+
+// A method that a thread is running, which is what a line of a thread dump or a stack trace is made
+// from.
+#[derive(Clone)]
+pub struct FrameRecord {
+    class: Arc<Class>,
+    method: Arc<Method>,
+    pc: u32
+}
+
+impl FrameRecord {
+    pub fn class(&self) -> &Arc<Class> {
+        &self.class
+    }
+
+    pub fn method(&self) -> &Arc<Method> {
+        &self.method
+    }
+
+    pub fn pc(&self) -> u32 {
+        self.pc
+    }
+
+    // The line that the frame is on, if the method has a LineNumberTable.
+    pub fn line_number(&self) -> Option<u16> {
+        self.method.code()?.line_number(self.pc as u16)
+    }
+}
+
+// The frames of a thread, with the one it's running first.
+pub struct Frames<'a> {
+    thread: &'a JavaThread,
+    depth: usize
+}
+
+impl Iterator for Frames<'_> {
+    type Item = FrameRecord;
+
+    fn next(&mut self) -> Option<FrameRecord> {
+        let frames = self.thread.frames.lock().unwrap();
+        let frame = frames.len().checked_sub(self.depth + 1).map(|index| frames[index].clone())?;
+        self.depth += 1;
+        Some(frame)
+    }
+}
+
+// Pops the frame of a method that a thread entered once the method returns, however it does.
+pub(crate) struct EnteredFrame<'a>(&'a JavaThread);
+
+impl Drop for EnteredFrame<'_> {
+    fn drop(&mut self) {
+        self.0.frames.lock().unwrap().pop();
+    }
+}
+
+// The monitor of an object, named by its identity hash code, as that stays the same while the object
+// moves around.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Monitor {
+    hash: i32,
+    class_name: String
+}
+
+impl Monitor {
+    pub fn new(hash: i32, class_name: String) -> Self {
+        Monitor { hash, class_name }
+    }
+
+    pub fn hash(&self) -> i32 {
+        self.hash
+    }
+
+    // The name of the object's class, in the form that Class.getName returns.
+    pub fn class_name(&self) -> &str {
+        &self.class_name
+    }
+}
+
+impl Display for Monitor {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<0x{:016x}> (a {})", self.hash, self.class_name)
+    }
+}
+
+const JOIN_INTERRUPT_CHECK: Duration = Duration::from_millis(10);
+
+// Thrown by sleep, wait and join when the thread doing it gets interrupted, which is an InterruptedException.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Interrupted;
+
+impl Display for Interrupted {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "java.lang.InterruptedException")
+    }
+}
+
+impl Error for Interrupted {}
@@ -0,0 +1,286 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use super::stack_trace::StackTraceElement;
+use super::thread::{FrameRecord, JavaThread, Monitor, ThreadState};
+
+// What every thread was doing at a safepoint, printed like HotSpot prints one on SIGQUIT, along with
+// any deadlocks between threads blocked on monitors that the others hold.
+pub struct ThreadDump {
+    threads: Vec<ThreadSnapshot>,
+    deadlocks: Vec<Vec<usize>>
+}
+
+// One thread in a thread dump.
+pub struct ThreadSnapshot {
+    id: u64,
+    name: String,
+    daemon: bool,
+    state: ThreadState,
+    frames: Vec<FrameRecord>,
+    monitors: Vec<(usize, Monitor)>,
+    blocked_on: Option<Monitor>
+}
+
+impl ThreadDump {
+    // The threads have to be stopped at safepoints for where they are in their methods to be right.
+    pub(super) fn of(threads: &[Arc<JavaThread>]) -> Self {
+        let threads = threads.iter()
+            .map(|thread| ThreadSnapshot {
+                id: thread.id(),
+                name: String::from(thread.name()),
+                daemon: thread.is_daemon(),
+                state: thread.state(),
+                frames: thread.frames(),
+                monitors: thread.monitors(),
+                blocked_on: thread.blocked_on()
+            })
+            .collect::<Vec<_>>();
+        let deadlocks = find_deadlocks(&threads);
+        ThreadDump { threads, deadlocks }
+    }
+
+    pub fn threads(&self) -> &[ThreadSnapshot] {
+        &self.threads
+    }
+
+    // Every cycle of threads that are each blocked on a monitor held by the next, which none of them
+    // can ever get out of.
+    pub fn deadlocks(&self) -> Vec<Vec<&ThreadSnapshot>> {
+        self.deadlocks.iter()
+            .map(|cycle| cycle.iter().map(|index| &self.threads[*index]).collect())
+            .collect()
+    }
+}
+
+impl ThreadSnapshot {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_daemon(&self) -> bool {
+        self.daemon
+    }
+
+    pub fn state(&self) -> ThreadState {
+        self.state
+    }
+
+    // The frames of the thread, with the one it called first first.
+    pub fn frames(&self) -> &[FrameRecord] {
+        &self.frames
+    }
+
+    pub fn monitors(&self) -> &[(usize, Monitor)] {
+        &self.monitors
+    }
+
+    pub fn blocked_on(&self) -> Option<&Monitor> {
+        self.blocked_on.as_ref()
+    }
+
+    // What HotSpot puts after the thread's name, and after its state on the line below.
+    fn status(&self) -> (&'static str, &'static str) {
+        match self.state {
+            ThreadState::New => ("new", ""),
+            ThreadState::Runnable => ("runnable", ""),
+            ThreadState::Blocked => ("waiting for monitor entry", " (on object monitor)"),
+            ThreadState::Waiting => ("waiting on condition", " (parking)"),
+            ThreadState::TimedWaiting => ("sleeping", " (sleeping)"),
+            ThreadState::Terminated => ("terminated", "")
+        }
+    }
+}
+
+impl Display for ThreadDump {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Full thread dump astatine ({}):", env!("CARGO_PKG_VERSION"))?;
+        for thread in &self.threads {
+            writeln!(f)?;
+            write!(f, "{}", thread)?;
+        }
+        for cycle in self.deadlocks() {
+            writeln!(f)?;
+            writeln!(f, "Found one Java-level deadlock:")?;
+            writeln!(f, "=============================")?;
+            for (index, thread) in cycle.iter().enumerate() {
+                let holder = cycle[(index + 1) % cycle.len()];
+                let monitor = thread.blocked_on.as_ref().expect("Invalid deadlock! Thread isn't blocked!");
+                writeln!(f, "\"{}\":", thread.name)?;
+                writeln!(f, "  waiting to lock monitor {},", monitor)?;
+                writeln!(f, "  which is held by \"{}\"", holder.name)?;
+            }
+        }
+        match self.deadlocks.len() {
+            0 => Ok(()),
+            1 => write!(f, "\nFound 1 deadlock.\n"),
+            count => write!(f, "\nFound {} deadlocks.\n", count)
+        }
+    }
+}
+
+impl Display for ThreadSnapshot {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let (status, detail) = self.status();
+        let daemon = if self.daemon { " daemon" } else { "" };
+        writeln!(f, "\"{}\" #{}{} prio=5 {}", self.name, self.id, daemon, status)?;
+        writeln!(f, "   java.lang.Thread.State: {}{}", self.state, detail)?;
+        for (depth, frame) in self.frames.iter().enumerate().rev() {
+            writeln!(f, "\tat {}", StackTraceElement::of(frame))?;
+            if depth + 1 == self.frames.len() {
+                if let Some(monitor) = &self.blocked_on {
+                    writeln!(f, "\t- waiting to lock {}", monitor)?;
+                }
+            }
+            for (_, monitor) in self.monitors.iter().rev().filter(|(entered, _)| *entered == depth) {
+                writeln!(f, "\t- locked {}", monitor)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Follows each blocked thread to the thread holding the monitor it's waiting for, and on from there,
+// which only ends up back at a thread it's already been through if they're deadlocked. No thread is
+// followed from twice, so each cycle is only reported once.
+fn find_deadlocks(threads: &[ThreadSnapshot]) -> Vec<Vec<usize>> {
+    let holders = threads.iter().enumerate()
+        .flat_map(|(index, thread)| thread.monitors.iter().map(move |(_, monitor)| (monitor.hash(), index)))
+        .collect::<HashMap<_, _>>();
+    let waiting_for = |index: usize| {
+        threads[index].blocked_on.as_ref().and_then(|monitor| holders.get(&monitor.hash())).copied()
+    };
+
+    let mut deadlocks = Vec::new();
+    let mut visited = vec![false; threads.len()];
+    for start in 0..threads.len() {
+        let mut path = Vec::new();
+        let mut current = Some(start);
+        while let Some(index) = current {
+            if let Some(position) = path.iter().position(|other| *other == index) {
+                deadlocks.push(path.split_off(position));
+                break;
+            }
+            if visited[index] {
+                break;
+            }
+            visited[index] = true;
+            path.push(index);
+            current = waiting_for(index);
+        }
+    }
+    deadlocks
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, mpsc};
+    use crate::assembler::assemble;
+    use crate::class_file::ClassLoader;
+    use crate::objects::HeapSpace;
+    use crate::runtime::{Threads, ThreadState};
+    use crate::test_support::Offsets;
+    use crate::utils::descriptors::FieldDescriptor;
+
+    // Each locker enters its own monitor, waits for the other one to enter its own, then tries to enter
+    // that one too, which neither ever can.
+    const SOURCE: &str = r#"
+.class Locker
+.super java/lang/Object
+.source Locker.java
+.field other LLocker;
+.field volatile locked Z
+.method public run()V
+.limit stack 2
+.limit locals 1
+.line 10
+    aload_0
+    monitorenter
+.line 11
+    aload_0
+    iconst_1
+    putfield Locker/locked Z
+Wait:
+.line 12
+    aload_0
+    getfield Locker/other LLocker;
+    getfield Locker/locked Z
+    ifeq Wait
+.line 13
+    aload_0
+    getfield Locker/other LLocker;
+    monitorenter
+    return
+.end method
+"#;
+
+    #[test]
+    fn finds_deadlocked_threads() {
+        let class = Arc::new(ClassLoader::new()).define_class(assemble(SOURCE).unwrap());
+        let heap = Arc::new(HeapSpace::new(1 << 10));
+        let threads = Threads::new(&heap);
+        threads.attach_current_thread("main", false, None);
+
+        let mut roots = Offsets(Vec::new());
+        for _ in 0..2 {
+            let locker = heap.allocate_ref(&class, &mut roots).unwrap().offset();
+            roots.0.push(locker);
+        }
+        let other = FieldDescriptor::parse("LLocker;").unwrap();
+        for (locker, other_locker) in [(roots.0[0], roots.0[1]), (roots.0[1], roots.0[0])] {
+            let locker = heap.get_ref(locker).unwrap();
+            locker.set_ref(locker.field_offset("other", &other).unwrap(), other_locker as u32);
+        }
+        let started = roots.0.iter().map(|locker| threads.start(*locker)).collect::<Vec<_>>();
+        while started.iter().any(|thread| thread.state() != ThreadState::Blocked) {
+            std::thread::yield_now();
+        }
+
+        let dump = threads.thread_dump(&mut roots);
+        assert_eq!(dump.threads().len(), 3);
+        let deadlocks = dump.deadlocks();
+        assert_eq!(deadlocks.len(), 1);
+        let mut names = deadlocks[0].iter().map(|thread| thread.name()).collect::<Vec<_>>();
+        names.sort_unstable();
+        assert_eq!(names, ["Thread-0", "Thread-1"]);
+
+        let text = dump.to_string();
+        let hash = heap.identity_hash(roots.0[0]);
+        assert!(text.contains("\"Thread-0\" #2 prio=5 waiting for monitor entry\n"));
+        assert!(text.contains("   java.lang.Thread.State: BLOCKED (on object monitor)\n"));
+        assert!(text.contains("\tat Locker.run(Locker.java:13)\n"));
+        assert!(text.contains(&format!("\t- locked <0x{:016x}> (a Locker)\n", hash)));
+        assert!(text.contains(&format!("\t- waiting to lock <0x{:016x}> (a Locker)\n", hash)));
+        assert!(text.contains(&format!("  waiting to lock monitor <0x{:016x}> (a Locker),\n", hash)));
+        assert!(text.ends_with("Found 1 deadlock.\n"));
+
+        // The same dump can be handed over at a safepoint instead, which this thread has to let happen.
+        let (sender, receiver) = mpsc::channel();
+        threads.request_thread_dump(move |dump| sender.send(dump.deadlocks().len()).unwrap());
+        assert_eq!(threads.native(&mut roots, || receiver.recv().unwrap()), 1);
+
+        // The lockers never get out, so they're left blocked for as long as the tests run.
+        threads.detach_current_thread();
+    }
+}
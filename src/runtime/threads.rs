@@ -0,0 +1,794 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak, mpsc};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread::ThreadId;
+use std::time::Duration;
+use crate::code::{Interpreter, MethodResult};
+use crate::objects::{HeapSpace, Mutators, RootSource};
+use crate::types::{Class, Method};
+use crate::utils::descriptors::{FieldDescriptor, MethodDescriptor};
+use super::stack_trace::print_uncaught_exception;
+use super::thread::{Interrupted, JavaThread, ThreadState};
+use super::thread_dump::ThreadDump;
+use super::vm_thread::{self, VmOperation, VmOperationQueue};
+
+thread_local! {
+    // The Java thread that the OS thread is, if it's attached to a VM.
+    static CURRENT: RefCell<Option<Arc<JavaThread>>> = RefCell::new(None);
+    // The threads of the VM that the OS thread is attached to, for natives like Thread.start.
+    static ATTACHED: RefCell<Option<Arc<Threads>>> = RefCell::new(None);
+}
+
+// The roots of a thread that has stopped, which stay where they are for as long as it stays stopped.
+struct StoppedRoots(*mut (dyn RootSource + 'static));
+
+// Only the thread that is stopping the world ever follows the pointer, and only while the thread that
+// it belongs to is stopped.
+unsafe impl Send for StoppedRoots {}
+
+// The roots of every thread while the world is stopped, starting with those of the thread that
+// stopped it.
+struct WorldRoots<'a> {
+    caller: &'a mut dyn RootSource,
+    stopped: Vec<*mut (dyn RootSource + 'static)>
+}
+
+impl RootSource for WorldRoots<'_> {
+    fn visit_roots(&mut self, visitor: &mut dyn FnMut(&mut usize)) {
+        self.caller.visit_roots(visitor);
+        for roots in &self.stopped {
+            unsafe { (**roots).visit_roots(visitor) }
+        }
+    }
+}
+
+struct Safepoint {
+    // The number of attached threads that are running, and so might be using references that
+    // the collector can't see.
+    running: usize,
+    // Set while a thread is stopping the world or running an operation with it stopped.
+    stopping: bool,
+    // The OS thread that has stopped the world, which can stop it again without waiting for itself.
+    owner: Option<ThreadId>,
+    stopped: HashMap<u64, StoppedRoots>,
+    // The roots of threads in native code that are running again for a while, which the threads are
+    // given back once they stop again.
+    unblocked: HashMap<u64, Vec<StoppedRoots>>,
+    // Roots that threads hold on to while they run, for references further down their stacks.
+    held: HashMap<u64, Vec<StoppedRoots>>
+}
+
+// Every Java thread in a VM. A thread is either running, in which case it has to stop at its next
+// safepoint whenever another thread wants the world stopped, or stopped, which includes sleeping,
+// joining and running native code, with its roots left where the thread stopping the world can get
+// to them. Interpreted code polls for safepoints at backward branches and returns, so a running
+// thread never goes long without reaching one.
+pub struct Threads {
+    heap: Weak<HeapSpace>,
+    threads: Arc<Mutex<Vec<Arc<JavaThread>>>>,
+    thread_exited: Condvar,
+    next_id: AtomicU64,
+    next_number: AtomicU64,
+    safepoint: Mutex<Safepoint>,
+    safepoint_changed: Condvar,
+    stop_requested: AtomicBool,
+    // The threads waiting on each monitor, by the identity hash of its object, in the order they started
+    // waiting. Objects that share a hash share a wait set, which only ever wakes threads up early.
+    wait_sets: Mutex<HashMap<i32, Vec<Arc<JavaThread>>>>,
+    operations: Arc<VmOperationQueue>
+}
+
+impl Threads {
+    // The heap stops every thread in here before it collects. This also starts the VM thread, which
+    // runs the operations that are queued up for safepoints.
+    pub fn new(heap: &Arc<HeapSpace>) -> Arc<Threads> {
+        let threads = Arc::new(Threads {
+            heap: Arc::downgrade(heap),
+            threads: Arc::new(Mutex::new(Vec::new())),
+            thread_exited: Condvar::new(),
+            next_id: AtomicU64::new(1),
+            next_number: AtomicU64::new(0),
+            safepoint: Mutex::new(Safepoint {
+                running: 0,
+                stopping: false,
+                owner: None,
+                stopped: HashMap::new(),
+                unblocked: HashMap::new(),
+                held: HashMap::new()
+            }),
+            safepoint_changed: Condvar::new(),
+            stop_requested: AtomicBool::new(false),
+            wait_sets: Mutex::new(HashMap::new()),
+            operations: Arc::new(VmOperationQueue::new())
+        });
+        heap.set_mutators(Arc::clone(&threads) as Arc<dyn Mutators>);
+        vm_thread::start_vm_thread(Arc::downgrade(&threads), Arc::clone(&threads.operations));
+        threads
+    }
+
+    // The Java thread that is running on the calling OS thread, which is what Thread.currentThread
+    // returns the object of.
+    pub fn current() -> Option<Arc<JavaThread>> {
+        CURRENT.with(|current| current.borrow().as_ref().map(Arc::clone))
+    }
+
+    // The threads of the VM that the calling OS thread is attached to.
+    pub fn attached() -> Option<Arc<Threads>> {
+        ATTACHED.with(|attached| attached.borrow().as_ref().map(Arc::clone))
+    }
+
+    pub(crate) fn heap(&self) -> Option<Arc<HeapSpace>> {
+        self.heap.upgrade()
+    }
+
+    // Every thread that has started and not yet terminated.
+    pub fn all(&self) -> Vec<Arc<JavaThread>> {
+        self.threads.lock().unwrap().clone()
+    }
+
+    // Turns the calling OS thread in to a Java thread, like JNI's AttachCurrentThread, which is how
+    // the main thread becomes one.
+    pub fn attach_current_thread(self: &Arc<Self>, name: &str, daemon: bool, handle: Option<usize>) -> Arc<JavaThread> {
+        let thread = Arc::new(JavaThread::new(self.next_id.fetch_add(1, Ordering::SeqCst), String::from(name), daemon,
+                                              handle));
+        self.threads.lock().unwrap().push(Arc::clone(&thread));
+        self.attach(&thread);
+        thread
+    }
+
+    pub fn detach_current_thread(&self) {
+        let thread = CURRENT.with(|current| current.borrow_mut().take())
+            .unwrap_or_else(|| panic!("Invalid detach! The current thread isn't attached!"));
+        ATTACHED.with(|attached| attached.borrow_mut().take());
+        let mut safepoint = self.safepoint.lock().unwrap();
+        safepoint.running -= 1;
+        self.safepoint_changed.notify_all();
+        drop(safepoint);
+        self.threads.lock().unwrap().retain(|other| other.id() != thread.id());
+        self.thread_exited.notify_all();
+        thread.set_state(ThreadState::Terminated);
+    }
+
+    // Starts a new thread for the java.lang.Thread object, like Thread.start, which runs the run method
+    // of the object's class, or that of its target if that's the one from Thread. The thread is a
+    // daemon if the object's daemon field is set.
+    pub fn start(self: &Arc<Self>, object: usize) -> Arc<JavaThread> {
+        let heap = self.heap.upgrade().unwrap_or_else(|| panic!("Invalid thread start! The heap has been dropped!"));
+        let instance = heap.get_ref(object).expect("Invalid thread start! Thread object cannot be null!");
+        let daemon = instance.field_offset("daemon", &descriptor("Z")).map_or(false, |field| instance.get_bool(field));
+        let name = format!("Thread-{}", self.next_number.fetch_add(1, Ordering::SeqCst));
+        let handle = heap.roots().new_global_handle(object);
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let thread = Arc::new(JavaThread::new(id, name.clone(), daemon, Some(handle)));
+        thread.set_state(ThreadState::Runnable);
+        self.threads.lock().unwrap().push(Arc::clone(&thread));
+
+        let threads = Arc::clone(self);
+        let started = Arc::clone(&thread);
+        std::thread::Builder::new()
+            .name(name)
+            .spawn(move || {
+                threads.attach(&started);
+                let result = run_thread(&heap, heap.roots().global_handle(handle));
+                report_uncaught(&heap, &started, result);
+                heap.roots().delete_global_handle(handle);
+                threads.detach_current_thread();
+            })
+            .unwrap_or_else(|error| panic!("Failed to start thread {}! {}", thread.name(), error));
+        thread
+    }
+
+    // The thread that the java.lang.Thread object belongs to, if it has started and not yet terminated.
+    pub fn find(&self, object: usize) -> Option<Arc<JavaThread>> {
+        let heap = self.heap.upgrade()?;
+        let roots = heap.roots();
+        self.all().into_iter()
+            .find(|thread| thread.handle().map_or(false, |handle| roots.global_handle(handle) == object))
+    }
+
+    // Like Thread.sleep, with the current thread stopped while it sleeps, so the roots are those that
+    // it holds.
+    pub fn sleep(&self, duration: Duration, roots: &mut dyn RootSource) -> Result<(), Interrupted> {
+        let thread = current_thread("sleep");
+        self.block(&thread, roots, || thread.sleep(duration))
+    }
+
+    // Like Thread.join, with the current thread stopped while it waits.
+    pub fn join(&self, other: &JavaThread, roots: &mut dyn RootSource) -> Result<(), Interrupted> {
+        let thread = current_thread("join");
+        self.block(&thread, roots, || thread.join(other))
+    }
+
+    // Like Object.wait on the monitor with the hash, with the current thread stopped while it waits for
+    // notify, notify_all, an interrupt or the timeout, whichever comes first. The thread is waiting from
+    // before release is called, which is where it lets go of the monitor, so a notify that comes in
+    // straight after that isn't missed. Entering the monitor again is left to the caller.
+    pub fn wait(
+        &self,
+        hash: i32,
+        timeout: Option<Duration>,
+        roots: &mut dyn RootSource,
+        release: impl FnOnce()
+    ) -> Result<(), Interrupted> {
+        let thread = current_thread("wait");
+        self.wait_sets.lock().unwrap().entry(hash).or_default().push(Arc::clone(&thread));
+        release();
+        let result = self.block(&thread, roots, || thread.wait_for_notify(timeout));
+        let mut wait_sets = self.wait_sets.lock().unwrap();
+        if let Some(waiting) = wait_sets.get_mut(&hash) {
+            waiting.retain(|other| other.id() != thread.id());
+            if waiting.is_empty() {
+                wait_sets.remove(&hash);
+            }
+        }
+        result
+    }
+
+    // Wakes up the thread that has been waiting on the monitor with the hash for longest, if any are.
+    pub fn notify(&self, hash: i32) {
+        let mut wait_sets = self.wait_sets.lock().unwrap();
+        if let Some(waiting) = wait_sets.get_mut(&hash) {
+            waiting.remove(0).notify();
+            if waiting.is_empty() {
+                wait_sets.remove(&hash);
+            }
+        }
+    }
+
+    pub fn notify_all(&self, hash: i32) {
+        if let Some(waiting) = self.wait_sets.lock().unwrap().remove(&hash) {
+            waiting.iter().for_each(|thread| thread.notify());
+        }
+    }
+
+    // Runs native code, which can't touch the heap other than through handles, so the thread counts
+    // as stopped for as long as it's in there, and never holds up a safepoint. Threads that aren't
+    // attached just run it.
+    pub fn native<R>(&self, roots: &mut dyn RootSource, native: impl FnOnce() -> R) -> R {
+        match Threads::current() {
+            Some(thread) => self.block(&thread, roots, native),
+            None => native()
+        }
+    }
+
+    // Stops the current thread with the roots until leave_native, for threads that native code attaches,
+    // which are in native code whenever they aren't in a JNI function. The roots have to stay put until then.
+    pub(crate) fn enter_native(&self, roots: &mut dyn RootSource) {
+        self.stop(&current_thread("native entry"), roots);
+    }
+
+    pub(crate) fn leave_native(&self) {
+        let thread = current_thread("native exit");
+        self.resume(self.safepoint.lock().unwrap()).stopped.remove(&thread.id());
+    }
+
+    // Counts the current thread as running again if it's in native code, for code that uses the heap
+    // from in there, like the JNI functions, until reblock is called. The roots it stopped with are left
+    // out in the meantime, as that code allocates with them itself. Returns whether the thread was in
+    // native code, as only then does it have to be stopped again.
+    pub fn unblock(&self) -> bool {
+        let thread = match Threads::current() {
+            Some(thread) => thread,
+            None => return false
+        };
+        let safepoint = self.safepoint.lock().unwrap();
+        if !safepoint.stopped.contains_key(&thread.id()) {
+            return false;
+        }
+        let mut safepoint = self.resume(safepoint);
+        let roots = safepoint.stopped.remove(&thread.id())
+            .unwrap_or_else(|| panic!("Invalid unblock! Thread {} isn't stopped!", thread.id()));
+        safepoint.unblocked.entry(thread.id()).or_default().push(roots);
+        true
+    }
+
+    // Stops the current thread again after unblock, with the roots that it was stopped with before.
+    pub fn reblock(&self) {
+        let thread = current_thread("reblock");
+        let mut safepoint = self.safepoint.lock().unwrap();
+        let roots = safepoint.unblocked.get_mut(&thread.id()).and_then(Vec::pop)
+            .unwrap_or_else(|| panic!("Invalid reblock! Thread {} isn't unblocked!", thread.id()));
+        safepoint.stopped.insert(thread.id(), roots);
+        safepoint.running -= 1;
+        self.safepoint_changed.notify_all();
+    }
+
+    // Runs code with the roots counted among those of the current thread for as long as it runs, for
+    // references that it holds further down its stack, like those of a native that calls back in to
+    // Java. Threads that aren't attached just run it.
+    pub fn hold<R>(&self, roots: &mut dyn RootSource, operation: impl FnOnce() -> R) -> R {
+        let thread = match Threads::current() {
+            Some(thread) => thread,
+            None => return operation()
+        };
+        self.safepoint.lock().unwrap().held.entry(thread.id()).or_default().push(stopped_roots(roots));
+        let result = operation();
+        // The roots have to stay put until the thread has let go of them, which it can only do while running.
+        self.safepoint.lock().unwrap().held.get_mut(&thread.id()).and_then(Vec::pop);
+        result
+    }
+
+    // Queues an operation for the VM thread to run at the next safepoint, without waiting for it.
+    // This can be called from any thread, including signal handling ones that aren't Java threads.
+    pub fn submit(&self, operation: impl FnOnce(&mut dyn RootSource) + Send + 'static) {
+        self.operations.push(Box::new(operation) as VmOperation);
+    }
+
+    // Like submit, but waits for the operation to have run, with the current thread stopped while it
+    // waits if it's a Java thread. The roots are those of the current thread. If the operation panics,
+    // the panic is carried on in the current thread once the VM thread has moved on.
+    pub fn execute(&self, roots: &mut dyn RootSource, operation: impl FnOnce(&mut dyn RootSource) + Send + 'static) {
+        let done = Arc::new((Mutex::new(None), Condvar::new()));
+        let signal = Arc::clone(&done);
+        self.submit(move |roots| {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| operation(roots)));
+            *signal.0.lock().unwrap() = Some(result);
+            signal.1.notify_all();
+        });
+        let result = self.native(roots, || {
+            let mut finished = done.0.lock().unwrap();
+            loop {
+                if let Some(result) = finished.take() {
+                    return result;
+                }
+                finished = done.1.wait(finished).unwrap();
+            }
+        });
+        if let Err(payload) = result {
+            panic::resume_unwind(payload);
+        }
+    }
+
+    // Takes a dump of every thread at a safepoint, with the current thread stopped while it waits for
+    // one. The roots are those of the current thread.
+    pub fn thread_dump(&self, roots: &mut dyn RootSource) -> ThreadDump {
+        let (sender, receiver) = mpsc::channel();
+        let threads = Arc::clone(&self.threads);
+        self.execute(roots, move |_| {
+            let dump = ThreadDump::of(&threads.lock().unwrap());
+            sender.send(dump).unwrap_or_else(|_| panic!("Invalid thread dump! Nothing is waiting for it!"));
+        });
+        receiver.recv().unwrap_or_else(|_| panic!("Invalid thread dump! The operation never ran!"))
+    }
+
+    // Takes a thread dump at the next safepoint without waiting for it and hands it to the callback,
+    // which is what the VM does when it gets a SIGQUIT.
+    pub fn request_thread_dump(&self, callback: impl FnOnce(ThreadDump) + Send + 'static) {
+        let threads = Arc::clone(&self.threads);
+        self.submit(move |_| callback(ThreadDump::of(&threads.lock().unwrap())));
+    }
+
+    // What the VM does once main returns, which is to detach the main thread and then wait for every
+    // thread that isn't a daemon to terminate. Daemon threads are left to die with the process.
+    pub fn wait_for_non_daemon_threads(&self) {
+        if Threads::current().is_some() {
+            self.detach_current_thread();
+        }
+        let mut threads = self.threads.lock().unwrap();
+        while threads.iter().any(|thread| !thread.is_daemon()) {
+            threads = self.thread_exited.wait(threads).unwrap();
+        }
+    }
+
+    fn attach(self: &Arc<Self>, thread: &Arc<JavaThread>) {
+        CURRENT.with(|current| {
+            assert!(current.borrow().is_none(), "Invalid attach! The current thread is already attached!");
+            *current.borrow_mut() = Some(Arc::clone(thread));
+        });
+        ATTACHED.with(|attached| *attached.borrow_mut() = Some(Arc::clone(self)));
+        drop(self.resume(self.safepoint.lock().unwrap()));
+        thread.set_state(ThreadState::Runnable);
+    }
+
+    // Runs something that may take a while with the thread stopped, so that it doesn't hold up the
+    // rest of the world in the meantime.
+    fn block<R>(&self, thread: &JavaThread, roots: &mut dyn RootSource, blocking: impl FnOnce() -> R) -> R {
+        // Native code can block too, and the thread is already stopped while it does.
+        if self.safepoint.lock().unwrap().stopped.contains_key(&thread.id()) {
+            return blocking();
+        }
+        self.stop(thread, roots);
+        let result = blocking();
+        // The roots have to stay put until the world has started again, even if it hasn't stopped yet.
+        self.resume(self.safepoint.lock().unwrap()).stopped.remove(&thread.id());
+        result
+    }
+
+    fn stop(&self, thread: &JavaThread, roots: &mut dyn RootSource) {
+        let mut safepoint = self.safepoint.lock().unwrap();
+        safepoint.stopped.insert(thread.id(), stopped_roots(roots));
+        safepoint.running -= 1;
+        self.safepoint_changed.notify_all();
+    }
+
+    // Waits for the world to be started again before counting the thread as running.
+    fn resume<'a>(&self, mut safepoint: MutexGuard<'a, Safepoint>) -> MutexGuard<'a, Safepoint> {
+        while safepoint.stopping {
+            safepoint = self.safepoint_changed.wait(safepoint).unwrap();
+        }
+        safepoint.running += 1;
+        safepoint
+    }
+}
+
+impl Drop for Threads {
+    fn drop(&mut self) {
+        self.operations.shut_down();
+    }
+}
+
+impl Mutators for Threads {
+    fn poll(&self, roots: &mut dyn RootSource) {
+        if !self.stop_requested.load(Ordering::SeqCst) {
+            return;
+        }
+        if let Some(thread) = Threads::current() {
+            self.block(&thread, roots, || ());
+        }
+    }
+
+    fn stop_the_world(&self, roots: &mut dyn RootSource, operation: &mut dyn FnMut(&mut dyn RootSource)) {
+        // Operations run with the world stopped, like those of the VM thread, can collect, and the roots
+        // they have already include those of every thread.
+        if self.safepoint.lock().unwrap().owner == Some(std::thread::current().id()) {
+            operation(roots);
+            return;
+        }
+        let current = Threads::current();
+        if let Some(thread) = &current {
+            // Another thread might already be stopping the world, which it can't do until this one stops.
+            self.stop(thread, roots);
+        }
+        let mut safepoint = self.safepoint.lock().unwrap();
+        while safepoint.stopping {
+            safepoint = self.safepoint_changed.wait(safepoint).unwrap();
+        }
+        safepoint.stopping = true;
+        safepoint.owner = Some(std::thread::current().id());
+        self.stop_requested.store(true, Ordering::SeqCst);
+        if let Some(thread) = &current {
+            safepoint.stopped.remove(&thread.id());
+        }
+        while safepoint.running > 0 {
+            safepoint = self.safepoint_changed.wait(safepoint).unwrap();
+        }
+
+        // Nothing else changes while the world is stopped, so the lock isn't held while the operation
+        // runs, as it might need to stop the world again.
+        let held = safepoint.held.values().flatten();
+        let stopped = safepoint.stopped.values().chain(held).map(|roots| roots.0).collect();
+        drop(safepoint);
+        operation(&mut WorldRoots { caller: roots, stopped });
+
+        let mut safepoint = self.safepoint.lock().unwrap();
+        safepoint.stopping = false;
+        safepoint.owner = None;
+        self.stop_requested.store(false, Ordering::SeqCst);
+        if current.is_some() {
+            safepoint.running += 1;
+        }
+        self.safepoint_changed.notify_all();
+    }
+
+    fn block(&self, roots: &mut dyn RootSource, blocking: &mut dyn FnMut()) {
+        self.native(roots, blocking);
+    }
+
+    fn unblock(&self) -> bool {
+        Threads::unblock(self)
+    }
+
+    fn reblock(&self) {
+        Threads::reblock(self);
+    }
+
+    fn hold(&self, roots: &mut dyn RootSource, operation: &mut dyn FnMut()) {
+        Threads::hold(self, roots, operation);
+    }
+}
+
+fn current_thread(operation: &str) -> Arc<JavaThread> {
+    Threads::current().unwrap_or_else(|| panic!("Invalid {}! The current thread isn't attached!", operation))
+}
+
+fn descriptor(value: &str) -> FieldDescriptor {
+    FieldDescriptor::parse(value).unwrap_or_else(|| panic!("Invalid field descriptor {}!", value))
+}
+
+// Forgets the lifetime of the roots, which is fine as long as they're taken out of the stopped roots
+// before the borrow of them ends.
+fn stopped_roots<'a>(roots: &'a mut (dyn RootSource + 'a)) -> StoppedRoots {
+    let roots = roots as *mut (dyn RootSource + 'a);
+    StoppedRoots(unsafe { std::mem::transmute::<*mut (dyn RootSource + 'a), *mut (dyn RootSource + 'static)>(roots) })
+}
+
+fn run_thread(heap: &HeapSpace, object: usize) -> MethodResult<'_> {
+    let class = heap.get_ref(object).expect("Invalid thread object! Cannot run null!").class();
+    let (class, method) = match find_virtual_method(&class, "run", "()V") {
+        Some((declaring, method)) if declaring.name() != "java/lang/Thread" => (declaring, method),
+        _ => return run_target(heap, object)
+    };
+    Interpreter::execute(heap, &class, &method, &[object as u32])
+}
+
+// Thread's own run method only runs the target it was given, so that's what gets run here too.
+fn run_target(heap: &HeapSpace, object: usize) -> MethodResult<'_> {
+    let instance = heap.get_ref(object).expect("Invalid thread object! Cannot run null!");
+    let target = instance.field_offset("target", &descriptor("Ljava/lang/Runnable;"))
+        .map_or(0, |field| instance.get_ref(field) as usize);
+    if target == 0 {
+        return MethodResult::Void;
+    }
+    let class = heap.get_ref(target).expect("Invalid thread target!").class();
+    let (class, method) = find_virtual_method(&class, "run", "()V")
+        .unwrap_or_else(|| panic!("Invalid thread target! {} has no run method!", class.name()));
+    Interpreter::execute(heap, &class, &method, &[target as u32])
+}
+
+// Finds the method that a virtual call to it on an instance of the class would, along with the class that
+// declares it.
+pub(super) fn find_virtual_method(
+    class: &Arc<Class>,
+    name: &str,
+    descriptor: &str
+) -> Option<(Arc<Class>, Arc<Method>)> {
+    let descriptor = MethodDescriptor::parse(descriptor).unwrap();
+    let mut current = Some(Arc::clone(class));
+    while let Some(class) = current {
+        let method = class.methods().iter()
+            .find(|method| method.name() == name && !method.is_static() && method.descriptor() == &descriptor)
+            .map(Arc::clone);
+        if let Some(method) = method {
+            return Some((class, method));
+        }
+        current = class.super_class_name().and_then(|name| class.loader().find_class(&name));
+    }
+    None
+}
+
+// Like the default uncaught exception handler, which prints the exception and lets the thread die.
+fn report_uncaught(heap: &HeapSpace, thread: &JavaThread, result: MethodResult) {
+    match result {
+        MethodResult::OutOfMemory(error) => {
+            eprintln!("Exception in thread \"{}\" java.lang.OutOfMemoryError: {}", thread.name(), error)
+        }
+        MethodResult::Exception(exception) => print_uncaught_exception(heap, thread.name(), exception.offset()),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::{Arc, mpsc};
+    use std::time::Duration;
+    use crate::assembler::assemble;
+    use crate::class_file::ClassLoader;
+    use crate::objects::{FieldLayout, HeapSpace, MemoryOrder, NoRoots};
+    use crate::test_support::Offsets;
+    use crate::types::Class;
+    use crate::utils::descriptors::{FieldDescriptor, FieldType};
+    use super::Threads;
+
+    const SOURCE: &str = r#"
+.class Worker
+.super java/lang/Object
+.field count I
+.method public run()V
+.limit stack 3
+.limit locals 2
+    sipush 500
+    istore_1
+Loop:
+    iload_1
+    ifle End
+    aload_0
+    dup
+    getfield Worker/count I
+    iconst_1
+    iadd
+    putfield Worker/count I
+    new Worker
+    pop
+    iinc 1 -1
+    goto Loop
+End:
+    return
+.end method
+"#;
+
+    const SPINNER_SOURCE: &str = r#"
+.class Spinner
+.super java/lang/Object
+.field volatile started Z
+.field volatile stop Z
+.method public run()V
+.limit stack 2
+.limit locals 1
+    aload_0
+    iconst_1
+    putfield Spinner/started Z
+Loop:
+    aload_0
+    getfield Spinner/stop Z
+    ifeq Loop
+    return
+.end method
+"#;
+
+    fn worker_class() -> Arc<Class> {
+        Arc::new(ClassLoader::new()).define_class(assemble(SOURCE).unwrap())
+    }
+
+    #[test]
+    fn collects_while_threads_allocate() {
+        let class = worker_class();
+        let size = FieldLayout::of(&class).size();
+        let heap = Arc::new(HeapSpace::with_sizes(size * 16, size * 16));
+        let threads = Threads::new(&heap);
+        threads.attach_current_thread("main", false, None);
+
+        let mut roots = Offsets(Vec::new());
+        for _ in 0..4 {
+            let worker = heap.allocate_ref(&class, &mut roots).unwrap().offset();
+            roots.0.push(worker);
+        }
+        let started = roots.0.clone().into_iter().map(|worker| threads.start(worker)).collect::<Vec<_>>();
+        for thread in &started {
+            threads.join(thread, &mut roots).unwrap();
+            assert!(!thread.is_alive());
+        }
+
+        let count = FieldDescriptor::new(FieldType::Int, 0);
+        for worker in &roots.0 {
+            let worker = heap.get_ref(*worker).unwrap();
+            assert_eq!(worker.get_int(worker.field_offset("count", &count).unwrap()), 500);
+        }
+        assert!(threads.all().iter().all(|thread| thread.name() == "main"));
+        threads.detach_current_thread();
+    }
+
+    #[test]
+    fn interrupts_sleeping_threads() {
+        let heap = Arc::new(HeapSpace::new(1 << 10));
+        let threads = Threads::new(&heap);
+        let thread = threads.attach_current_thread("main", false, None);
+        assert_eq!(Threads::current().unwrap().id(), thread.id());
+
+        let interrupter = {
+            let thread = Arc::clone(&thread);
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(20));
+                thread.interrupt();
+            })
+        };
+        assert!(threads.sleep(Duration::from_secs(60), &mut NoRoots).is_err());
+        assert!(!thread.is_interrupted());
+        interrupter.join().unwrap();
+        threads.detach_current_thread();
+        assert!(Threads::current().is_none());
+    }
+
+    #[test]
+    fn does_not_wait_for_daemon_threads() {
+        let heap = Arc::new(HeapSpace::new(1 << 10));
+        let threads = Threads::new(&heap);
+        threads.attach_current_thread("main", false, None);
+
+        let (sender, receiver) = mpsc::channel();
+        let daemon = {
+            let threads = Arc::clone(&threads);
+            std::thread::spawn(move || {
+                sender.send(threads.attach_current_thread("daemon", true, None)).unwrap();
+                let result = threads.sleep(Duration::from_secs(60), &mut NoRoots);
+                threads.detach_current_thread();
+                result
+            })
+        };
+        let thread = receiver.recv().unwrap();
+        threads.wait_for_non_daemon_threads();
+        assert!(thread.is_alive());
+
+        thread.interrupt();
+        assert!(daemon.join().unwrap().is_err());
+        assert!(!thread.is_alive());
+    }
+
+    #[test]
+    fn runs_operations_once_spinning_threads_stop() {
+        let class = Arc::new(ClassLoader::new()).define_class(assemble(SPINNER_SOURCE).unwrap());
+        let heap = Arc::new(HeapSpace::new(1 << 10));
+        let threads = Threads::new(&heap);
+        threads.attach_current_thread("main", false, None);
+
+        let mut roots = Offsets(Vec::new());
+        for _ in 0..2 {
+            let spinner = heap.allocate_ref(&class, &mut roots).unwrap().offset();
+            roots.0.push(spinner);
+        }
+        let started = roots.0.iter().map(|spinner| threads.start(*spinner)).collect::<Vec<_>>();
+        let boolean = FieldDescriptor::new(FieldType::Boolean, 0);
+        let flag = |spinner: usize, name: &str| {
+            (spinner, heap.get_ref(spinner).unwrap().field_offset(name, &boolean).unwrap())
+        };
+        for spinner in &roots.0 {
+            let (spinner, started) = flag(*spinner, "started");
+            while heap.load(spinner, started, 1, MemoryOrder::Volatile) == 0 {
+                std::thread::yield_now();
+            }
+        }
+
+        // The spinners never allocate or return, so the only place they can stop is their backward branch.
+        let (sender, receiver) = mpsc::channel();
+        threads.execute(&mut roots, move |roots| {
+            let mut count = 0;
+            roots.visit_roots(&mut |_| count += 1);
+            sender.send(count).unwrap();
+        });
+        // Each spinner is in the roots of the main thread and in its own frame. Their global handles are
+        // held by the heap rather than the threads.
+        assert_eq!(receiver.recv().unwrap(), 4);
+
+        for spinner in &roots.0 {
+            let (spinner, stop) = flag(*spinner, "stop");
+            heap.store(spinner, stop, 1, 1, MemoryOrder::Volatile);
+        }
+        for thread in &started {
+            threads.join(thread, &mut roots).unwrap();
+        }
+        threads.detach_current_thread();
+    }
+
+    #[test]
+    fn does_not_wait_for_threads_in_native_code() {
+        let heap = Arc::new(HeapSpace::new(1 << 10));
+        let threads = Threads::new(&heap);
+        threads.attach_current_thread("main", false, None);
+
+        let (sender, receiver) = mpsc::channel();
+        let operations = Arc::clone(&threads);
+        let result = threads.native(&mut NoRoots, move || {
+            // The operation can only run while this thread is in native code, as it never polls.
+            operations.execute(&mut NoRoots, move |_| sender.send(()).unwrap());
+            receiver.recv().is_ok()
+        });
+        assert!(result);
+        threads.detach_current_thread();
+    }
+
+    #[test]
+    fn carries_on_after_operations_panic() {
+        let heap = Arc::new(HeapSpace::new(1 << 10));
+        let threads = Threads::new(&heap);
+        threads.attach_current_thread("main", false, None);
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            threads.execute(&mut NoRoots, |_| panic!("Invalid operation! It always panics!"));
+        }));
+        assert!(result.is_err());
+        threads.submit(|_| panic!("Invalid operation! It always panics!"));
+        // The VM thread has to still be there, with the world started again, to run the next one.
+        let (sender, receiver) = mpsc::channel();
+        threads.execute(&mut NoRoots, move |_| sender.send(()).unwrap());
+        assert!(receiver.recv().is_ok());
+        threads.detach_current_thread();
+    }
+}
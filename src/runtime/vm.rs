@@ -0,0 +1,654 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use std::path::PathBuf;
+use std::sync::Arc;
+use bytes::Bytes;
+use enum_as_inner::EnumAsInner;
+use crate::class_file::ClassLoader;
+use crate::class_file::attributes::AttributeRegistry;
+use crate::code::{Interpreter, MethodResult, parameter_slots};
+use crate::constants::JVM_OBJECT_INITIALIZER_NAME;
+use crate::objects::{field_size, FieldLayout, HeapSpace, MemoryOrder, NoRoots, OutOfMemoryError, Reference};
+use crate::types::{Class, Method};
+use crate::utils::descriptors::{FieldDescriptor, FieldType, MethodDescriptor};
+use crate::verifier::VerifyMode;
+use super::reference_handler;
+use super::stack_trace::describe;
+use super::{new_string, print_stack_trace, string_value, Threads};
+
+// The heap size used when the builder isn't given one, which is what -Xmx defaults to.
+pub const DEFAULT_HEAP_SIZE: usize = 64 << 20;
+
+const CLASS_PATH_PROPERTY: &str = "java.class.path";
+const LIBRARY_PATH_PROPERTY: &str = "java.library.path";
+
+// Builds a VM for embedding, like the options given to the java launcher. The class path and library
+// path become the java.class.path and java.library.path properties unless those are given as well.
+#[derive(Debug, Clone)]
+pub struct VmBuilder {
+    class_path: Vec<PathBuf>,
+    library_path: Vec<PathBuf>,
+    heap_size: usize,
+    verify_mode: VerifyMode,
+    properties: Vec<(String, String)>
+}
+
+impl VmBuilder {
+    pub fn new() -> Self {
+        VmBuilder::default()
+    }
+
+    pub fn class_path(&mut self, directory: impl Into<PathBuf>) -> &mut Self {
+        self.class_path.push(directory.into());
+        self
+    }
+
+    pub fn library_path(&mut self, directory: impl Into<PathBuf>) -> &mut Self {
+        self.library_path.push(directory.into());
+        self
+    }
+
+    // The most that the heap can hold, in bytes, like -Xmx.
+    pub fn heap_size(&mut self, heap_size: usize) -> &mut Self {
+        self.heap_size = heap_size;
+        self
+    }
+
+    pub fn verify_mode(&mut self, verify_mode: VerifyMode) -> &mut Self {
+        self.verify_mode = verify_mode;
+        self
+    }
+
+    // A system property, like -D, which replaces any given before it with the same key.
+    pub fn property(&mut self, key: &str, value: &str) -> &mut Self {
+        self.properties.retain(|(other, _)| other != key);
+        self.properties.push((String::from(key), String::from(value)));
+        self
+    }
+
+    // Fails if a directory can't be put in a path, like one with the platform's path separator in it.
+    pub fn build(&self) -> Result<Vm, VmError> {
+        let loader = Arc::new(ClassLoader::with_options(AttributeRegistry::default(), self.verify_mode));
+        for (key, paths) in [(CLASS_PATH_PROPERTY, &self.class_path), (LIBRARY_PATH_PROPERTY, &self.library_path)] {
+            let value = std::env::join_paths(paths)
+                .map_err(|error| VmError::InvalidPath(format!("{} {:?}: {}", key, paths, error)))?;
+            loader.set_property(key, &value.to_string_lossy());
+        }
+        self.properties.iter().for_each(|(key, value)| loader.set_property(key, value));
+        let split = |key| std::env::split_paths(&loader.property(key).unwrap_or_default()).collect::<Vec<_>>();
+        split(CLASS_PATH_PROPERTY).into_iter().for_each(|directory| loader.add_class_path(directory));
+        split(LIBRARY_PATH_PROPERTY).into_iter().for_each(|directory| loader.libraries().add_search_path(directory));
+
+        let heap = Arc::new(HeapSpace::new(self.heap_size));
+        let threads = Threads::new(&heap);
+        reference_handler::start_reference_handler(&threads);
+        Ok(Vm { loader, heap, threads })
+    }
+}
+
+impl Default for VmBuilder {
+    fn default() -> Self {
+        VmBuilder {
+            class_path: Vec::new(),
+            library_path: Vec::new(),
+            heap_size: DEFAULT_HEAP_SIZE,
+            verify_mode: VerifyMode::default(),
+            properties: Vec::new()
+        }
+    }
+}
+
+// A VM that Rust code can call in to, with a heap and threads of its own. Classes are given by their
+// internal names and members by their descriptors, like they are in class files, and objects are
+// handed out as global references so that they stay alive, and stay valid, as the heap is collected.
+// Threads that call in are attached as Java threads for the length of the call if they aren't already.
+pub struct Vm {
+    loader: Arc<ClassLoader>,
+    heap: Arc<HeapSpace>,
+    threads: Arc<Threads>
+}
+
+impl Vm {
+    pub fn builder() -> VmBuilder {
+        VmBuilder::new()
+    }
+
+    pub fn loader(&self) -> &Arc<ClassLoader> {
+        &self.loader
+    }
+
+    pub fn heap(&self) -> &Arc<HeapSpace> {
+        &self.heap
+    }
+
+    pub fn threads(&self) -> &Arc<Threads> {
+        &self.threads
+    }
+
+    pub fn property(&self, key: &str) -> Option<String> {
+        self.loader.property(key)
+    }
+
+    pub fn find_class(&self, name: &str) -> Result<Arc<Class>, VmError> {
+        self.loader.find_class(name).ok_or_else(|| VmError::ClassNotFound(String::from(name)))
+    }
+
+    pub fn define_class(&self, bytes: Bytes) -> Arc<Class> {
+        self.loader.define_class(bytes)
+    }
+
+    pub fn call_static(
+        &self,
+        class_name: &str,
+        name: &str,
+        descriptor: &str,
+        arguments: &[Value]
+    ) -> Result<Value, VmError> {
+        let class = self.find_class(class_name)?;
+        let parsed = parse_method_descriptor(descriptor)?;
+        let (class, method) = class.find_method(|method| {
+            method.is_static() && method.name() == name && method.descriptor() == &parsed
+        }).ok_or_else(|| VmError::NoSuchMethod(format!("{}.{}{}", class_name, name, descriptor)))?;
+        self.call(&class, &method, None, arguments)
+    }
+
+    // Calls the method on the object, which is the override of it in the object's class, if there is one.
+    pub fn call_method(
+        &self,
+        object: &GlobalRef,
+        name: &str,
+        descriptor: &str,
+        arguments: &[Value]
+    ) -> Result<Value, VmError> {
+        let class = self.class_of(object);
+        let parsed = parse_method_descriptor(descriptor)?;
+        let (class, method) = class.find_method(|method| {
+            !method.is_static() && method.name() == name && method.descriptor() == &parsed
+        }).ok_or_else(|| VmError::NoSuchMethod(format!("{}.{}{}", class.name(), name, descriptor)))?;
+        self.call(&class, &method, Some(object), arguments)
+    }
+
+    // Creates an object of the class and runs the constructor with the descriptor on it.
+    pub fn new_object(&self, class_name: &str, descriptor: &str, arguments: &[Value]) -> Result<GlobalRef, VmError> {
+        let class = self.find_class(class_name)?;
+        if class.is_abstract() || class.is_interface() {
+            return Err(VmError::InvalidArguments(format!("Cannot create an object of abstract class {}", class_name)));
+        }
+        let parsed = parse_method_descriptor(descriptor)?;
+        let constructor = class.methods().iter()
+            .find(|method| method.name() == JVM_OBJECT_INITIALIZER_NAME && method.descriptor() == &parsed)
+            .ok_or_else(|| VmError::NoSuchMethod(format!("{}.<init>{}", class_name, descriptor)))?;
+        let _attached = self.attach();
+        let object = GlobalRef::new(&self.heap, self.heap.allocate_ref(&class, &mut NoRoots)?.offset());
+        self.call(&class, constructor, Some(&object), arguments)?;
+        Ok(object)
+    }
+
+    pub fn get_field(&self, object: &GlobalRef, name: &str, descriptor: &str) -> Result<Value, VmError> {
+        let _attached = self.attach();
+        let (descriptor, position, order) = self.find_field(object, name, descriptor)?;
+        let raw = self.heap.load(object.offset(), position, field_size(&descriptor), order);
+        if descriptor.is_reference() {
+            return Ok(Value::Reference(GlobalRef::from_offset(&self.heap, raw as usize)));
+        }
+        Ok(match descriptor.base() {
+            FieldType::Boolean => Value::Boolean(raw as u8 != 0),
+            FieldType::Byte => Value::Byte(raw as i8),
+            FieldType::Char => Value::Char(raw as u16),
+            FieldType::Short => Value::Short(raw as i16),
+            FieldType::Long => Value::Long(raw as i64),
+            FieldType::Float => Value::Float(f32::from_bits(raw as u32)),
+            FieldType::Double => Value::Double(f64::from_bits(raw)),
+            _ => Value::Int(raw as i32)
+        })
+    }
+
+    pub fn set_field(&self, object: &GlobalRef, name: &str, descriptor: &str, value: Value) -> Result<(), VmError> {
+        let _attached = self.attach();
+        let (parsed, position, order) = self.find_field(object, name, descriptor)?;
+        let raw = match to_slots(&value, &parsed) {
+            Some(slots) => slots.iter().fold(0, |raw, slot| (raw << 32) | *slot as u64),
+            None => return Err(VmError::InvalidArguments(format!("Expected {} for field {}, got {:?}", descriptor,
+                                                                  name, value)))
+        };
+        self.heap.store(object.offset(), position, field_size(&parsed), raw, order);
+        if parsed.is_reference() {
+            self.heap.write_barrier(object.offset(), raw as usize);
+        }
+        Ok(())
+    }
+
+    pub fn new_string(&self, value: &str) -> Result<GlobalRef, VmError> {
+        let chars = value.encode_utf16().collect::<Vec<_>>();
+        let _attached = self.attach();
+        let string = new_string(&self.heap, &self.loader, &chars, &mut NoRoots)?;
+        Ok(GlobalRef::new(&self.heap, string))
+    }
+
+    pub fn string_value(&self, string: &GlobalRef) -> String {
+        let _attached = self.attach();
+        string_value(&self.heap, string.offset())
+    }
+
+    fn class_of(&self, object: &GlobalRef) -> Arc<Class> {
+        let _attached = self.attach();
+        self.heap.get_ref(object.offset()).expect("Invalid global reference! Object cannot be null!").class()
+    }
+
+    fn find_field(
+        &self,
+        object: &GlobalRef,
+        name: &str,
+        descriptor: &str
+    ) -> Result<(FieldDescriptor, usize, MemoryOrder), VmError> {
+        let class = self.class_of(object);
+        let parsed = FieldDescriptor::parse(descriptor)
+            .ok_or_else(|| VmError::InvalidDescriptor(String::from(descriptor)))?;
+        let (position, order) = FieldLayout::of(&class).find_field(name, &parsed)
+            .ok_or_else(|| VmError::NoSuchField(format!("{}.{} {}", class.name(), name, descriptor)))?;
+        Ok((parsed, position, order))
+    }
+
+    fn call(
+        &self,
+        class: &Arc<Class>,
+        method: &Arc<Method>,
+        receiver: Option<&GlobalRef>,
+        arguments: &[Value]
+    ) -> Result<Value, VmError> {
+        let descriptor = method.descriptor();
+        if arguments.len() != descriptor.parameters().len() {
+            return Err(VmError::InvalidArguments(format!("Expected {} arguments for {}, got {}",
+                                                         descriptor.parameters().len(), method.name(),
+                                                         arguments.len())));
+        }
+        // Attached first, so that the objects can't move between reading their offsets and the call.
+        let _attached = self.attach();
+        let mut parameters = Vec::new();
+        parameters.extend(receiver.map(|receiver| receiver.offset() as u32));
+        for (argument, parameter) in arguments.iter().zip(descriptor.parameters()) {
+            let slots = to_slots(argument, parameter).ok_or_else(|| {
+                VmError::InvalidArguments(format!("Expected {} for {}, got {:?}", parameter, method.name(), argument))
+            })?;
+            parameters.extend(slots);
+        }
+
+        let value = match Interpreter::execute(&self.heap, class, method, &parameters) {
+            MethodResult::Void => Value::Void,
+            MethodResult::Integer(value) => match descriptor.return_type().map(FieldDescriptor::base) {
+                Some(FieldType::Boolean) => Value::Boolean(value != 0),
+                Some(FieldType::Byte) => Value::Byte(value as i8),
+                Some(FieldType::Char) => Value::Char(value as u16),
+                Some(FieldType::Short) => Value::Short(value as i16),
+                _ => Value::Int(value)
+            },
+            MethodResult::Long(value) => Value::Long(value),
+            MethodResult::Float(value) => Value::Float(value),
+            MethodResult::Double(value) => Value::Double(value),
+            MethodResult::Reference(Reference::Value(object)) => {
+                Value::Reference(Some(GlobalRef::new(&self.heap, object.offset())))
+            }
+            MethodResult::Reference(Reference::Null) => Value::Reference(None),
+            MethodResult::Exception(exception) => {
+                return Err(VmError::Exception(JavaException::new(&self.heap, exception.offset())));
+            }
+            MethodResult::OutOfMemory(error) => return Err(VmError::OutOfMemory(error))
+        };
+        Ok(value)
+    }
+
+    fn attach(&self) -> Attached<'_> {
+        if Threads::current().is_some() {
+            return Attached(None);
+        }
+        let current = std::thread::current();
+        self.threads.attach_current_thread(current.name().unwrap_or("Attached Thread"), false, None);
+        Attached(Some(&self.threads))
+    }
+}
+
+// Detaches the thread that a call attached when the call returns.
+struct Attached<'a>(Option<&'a Threads>);
+
+impl Drop for Attached<'_> {
+    fn drop(&mut self) {
+        if let Some(threads) = self.0 {
+            threads.detach_current_thread();
+        }
+    }
+}
+
+fn parse_method_descriptor(descriptor: &str) -> Result<MethodDescriptor, VmError> {
+    MethodDescriptor::parse(descriptor).ok_or_else(|| VmError::InvalidDescriptor(String::from(descriptor)))
+}
+
+// The slots that the value takes up as a parameter of the type, or nothing if it isn't of the type.
+fn to_slots(value: &Value, parameter: &FieldDescriptor) -> Option<Vec<u32>> {
+    let bits = if parameter.is_reference() {
+        match value {
+            Value::Reference(object) => object.as_ref().map_or(0, GlobalRef::offset) as u64,
+            _ => return None
+        }
+    } else {
+        match (parameter.base(), value) {
+            (FieldType::Boolean, Value::Boolean(value)) => *value as u64,
+            (FieldType::Byte, Value::Byte(value)) => *value as i32 as u32 as u64,
+            (FieldType::Char, Value::Char(value)) => *value as u64,
+            (FieldType::Short, Value::Short(value)) => *value as i32 as u32 as u64,
+            (FieldType::Int, Value::Int(value)) => *value as u32 as u64,
+            (FieldType::Long, Value::Long(value)) => *value as u64,
+            (FieldType::Float, Value::Float(value)) => value.to_bits() as u64,
+            (FieldType::Double, Value::Double(value)) => value.to_bits(),
+            _ => return None
+        }
+    };
+    Some(parameter_slots(parameter, bits))
+}
+
+// A value passed to or returned from Java, with references held as global references.
+#[derive(Debug, Clone, PartialEq, EnumAsInner)]
+pub enum Value {
+    Void,
+    Boolean(bool),
+    Byte(i8),
+    Char(u16),
+    Short(i16),
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    Reference(Option<GlobalRef>)
+}
+
+macro_rules! value_from {
+    ($($type:ty => $variant:ident),*) => {
+        $(
+            impl From<$type> for Value {
+                fn from(value: $type) -> Self {
+                    Value::$variant(value)
+                }
+            }
+        )*
+    }
+}
+
+value_from!(
+    bool => Boolean,
+    i8 => Byte,
+    u16 => Char,
+    i16 => Short,
+    i32 => Int,
+    i64 => Long,
+    f32 => Float,
+    f64 => Double,
+    Option<GlobalRef> => Reference
+);
+
+impl From<GlobalRef> for Value {
+    fn from(value: GlobalRef) -> Self {
+        Value::Reference(Some(value))
+    }
+}
+
+// A reference to an object that keeps it alive until it's dropped, like a JNI global reference. It's
+// held in the heap's global handles, which are roots that the collector updates when it moves the
+// object, so it stays valid across collections where a bare offset wouldn't.
+pub struct GlobalRef {
+    heap: Arc<HeapSpace>,
+    index: usize
+}
+
+impl GlobalRef {
+    fn new(heap: &Arc<HeapSpace>, offset: usize) -> Self {
+        GlobalRef { heap: Arc::clone(heap), index: heap.roots().new_global_handle(offset) }
+    }
+
+    fn from_offset(heap: &Arc<HeapSpace>, offset: usize) -> Option<Self> {
+        if offset == 0 { None } else { Some(GlobalRef::new(heap, offset)) }
+    }
+
+    // Where the object is now, which is only good until the heap is next collected.
+    pub fn offset(&self) -> usize {
+        self.heap.roots().global_handle(self.index)
+    }
+}
+
+impl Clone for GlobalRef {
+    fn clone(&self) -> Self {
+        GlobalRef { heap: Arc::clone(&self.heap), index: self.heap.roots().copy_global_handle(self.index) }
+    }
+}
+
+impl Drop for GlobalRef {
+    fn drop(&mut self) {
+        self.heap.roots().delete_global_handle(self.index);
+    }
+}
+
+// References are equal when they refer to the same object, like IsSameObject.
+impl PartialEq for GlobalRef {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.heap, &other.heap) && self.heap.roots().same_global_handles(self.index, other.index)
+    }
+}
+
+// The offset is read in one go under the handles' lock, so it's where the object was at some point, even if
+// a collection has moved it since.
+impl Debug for GlobalRef {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GlobalRef").field("index", &self.index).field("offset", &self.offset()).finish()
+    }
+}
+
+// An exception that Java threw back to Rust, described like Throwable.toString when it was caught.
+#[derive(Debug, Clone)]
+pub struct JavaException {
+    description: String,
+    throwable: GlobalRef
+}
+
+impl JavaException {
+    fn new(heap: &Arc<HeapSpace>, throwable: usize) -> Self {
+        JavaException { description: describe(heap, throwable), throwable: GlobalRef::new(heap, throwable) }
+    }
+
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+
+    pub fn throwable(&self) -> &GlobalRef {
+        &self.throwable
+    }
+
+    pub fn class_name(&self) -> &str {
+        self.description.split(": ").next().unwrap_or(&self.description)
+    }
+
+    // Prints the stack trace and the causes of the exception to stderr, like printStackTrace.
+    pub fn print_stack_trace(&self) {
+        let mut out = Vec::new();
+        let _ = print_stack_trace(&self.throwable.heap, self.throwable.offset(), &mut out);
+        eprint!("{}", String::from_utf8_lossy(&out));
+    }
+}
+
+impl Display for JavaException {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.description)
+    }
+}
+
+impl Error for JavaException {}
+
+// What calls in to the VM fail with, which is either a mistake in the call or something Java threw.
+#[derive(Debug, Clone)]
+pub enum VmError {
+    ClassNotFound(String),
+    NoSuchMethod(String),
+    NoSuchField(String),
+    InvalidDescriptor(String),
+    InvalidArguments(String),
+    InvalidPath(String),
+    Exception(JavaException),
+    OutOfMemory(OutOfMemoryError)
+}
+
+impl From<OutOfMemoryError> for VmError {
+    fn from(error: OutOfMemoryError) -> Self {
+        VmError::OutOfMemory(error)
+    }
+}
+
+impl Display for VmError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VmError::ClassNotFound(name) => write!(f, "Class {} could not be found", name),
+            VmError::NoSuchMethod(method) => write!(f, "No such method {}", method),
+            VmError::NoSuchField(field) => write!(f, "No such field {}", field),
+            VmError::InvalidDescriptor(descriptor) => write!(f, "Invalid descriptor {}", descriptor),
+            VmError::InvalidArguments(message) => write!(f, "Invalid arguments! {}", message),
+            VmError::InvalidPath(message) => write!(f, "Invalid path! {}", message),
+            VmError::Exception(exception) => write!(f, "Exception {}", exception),
+            VmError::OutOfMemory(error) => write!(f, "java.lang.OutOfMemoryError: {}", error)
+        }
+    }
+}
+
+impl Error for VmError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            VmError::Exception(exception) => Some(exception),
+            VmError::OutOfMemory(error) => Some(error),
+            _ => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use crate::assembler::assemble;
+    use crate::objects::NoRoots;
+    use crate::test_support::JDK_SOURCES;
+    use crate::verifier::VerifyMode;
+    use super::{Value, Vm, VmError};
+
+    const COUNTER_SOURCE: &str = r#"
+.class pkg/Counter
+.super java/lang/Object
+.field count I
+.field next Lpkg/Counter;
+.method public <init>(I)V
+.limit stack 2
+.limit locals 2
+    aload_0
+    iload_1
+    putfield pkg/Counter/count I
+    return
+.end method
+.method public add(I)I
+.limit stack 3
+.limit locals 2
+    aload_0
+    dup
+    getfield pkg/Counter/count I
+    iload_1
+    iadd
+    dup_x1
+    putfield pkg/Counter/count I
+    ireturn
+.end method
+.method public static widen(I)J
+.limit stack 2
+.limit locals 1
+    iload_0
+    i2l
+    lreturn
+.end method
+.method public static fail()V
+.limit stack 1
+    new java/lang/IllegalStateException
+    athrow
+.end method
+"#;
+
+    const SHAPE_SOURCE: &str = ".class public abstract pkg/Shape\n.super java/lang/Object\n";
+
+    // Writes the classes out like they'd be laid out in a class path directory.
+    fn write_class_path(directory: &Path) {
+        for source in JDK_SOURCES.iter().chain(&[COUNTER_SOURCE, SHAPE_SOURCE]) {
+            let name = source.trim_start().lines().next().unwrap().split_whitespace().last().unwrap();
+            let path = directory.join(format!("{}.class", name));
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, assemble(source).unwrap()).unwrap();
+        }
+    }
+
+    #[test]
+    fn calls_in_to_classes_on_the_class_path() {
+        let directory = std::env::temp_dir().join(format!("astatine-vm-{}", std::process::id()));
+        write_class_path(&directory);
+        let vm = Vm::builder()
+            .class_path(&directory)
+            .heap_size(1 << 16)
+            .verify_mode(VerifyMode::None)
+            .property("app.name", "counter")
+            .build()
+            .unwrap();
+
+        assert_eq!(vm.call_static("pkg/Counter", "widen", "(I)J", &[Value::Int(-7)]).unwrap(), Value::Long(-7));
+        let counter = vm.new_object("pkg/Counter", "(I)V", &[Value::Int(40)]).unwrap();
+        let next = vm.new_object("pkg/Counter", "(I)V", &[Value::Int(1)]).unwrap();
+        vm.set_field(&counter, "next", "Lpkg/Counter;", Value::from(next.clone())).unwrap();
+        assert_eq!(vm.call_method(&counter, "add", "(I)I", &[Value::Int(2)]).unwrap(), Value::Int(42));
+
+        // Both objects only survive the collection through their global references.
+        let copy = counter.clone();
+        vm.heap().collect(&mut NoRoots);
+        assert_eq!(copy, counter);
+        assert_ne!(copy, next);
+        assert_eq!(vm.get_field(&counter, "count", "I").unwrap(), Value::Int(42));
+        assert_eq!(vm.get_field(&counter, "next", "Lpkg/Counter;").unwrap(), Value::Reference(Some(next)));
+
+        let error = vm.call_static("pkg/Counter", "fail", "()V", &[]).unwrap_err();
+        let class_name = match &error {
+            VmError::Exception(exception) => exception.class_name(),
+            _ => panic!("Expected fail to throw!")
+        };
+        assert_eq!(class_name, "java.lang.IllegalStateException");
+        assert!(matches!(vm.call_static("pkg/Counter", "widen", "(J)J", &[]), Err(VmError::NoSuchMethod(_))));
+        assert!(matches!(vm.call_static("pkg/Counter", "widen", "(I)J", &[Value::Long(1)]),
+            Err(VmError::InvalidArguments(_))));
+        assert!(matches!(vm.find_class("pkg/Missing"), Err(VmError::ClassNotFound(_))));
+        assert!(matches!(vm.new_object("pkg/Shape", "()V", &[]), Err(VmError::InvalidArguments(_))));
+        let name = vm.new_string("astatine").unwrap();
+        assert_eq!(vm.string_value(&name), "astatine");
+        assert_eq!(vm.property("app.name").unwrap(), "counter");
+        assert_eq!(vm.property("java.class.path").unwrap(), directory.to_string_lossy());
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn fails_to_build_with_paths_that_cannot_be_joined() {
+        let result = Vm::builder().class_path("classes").class_path("more:classes").build();
+        assert!(matches!(result, Err(VmError::InvalidPath(_))));
+    }
+}
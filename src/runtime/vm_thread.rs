@@ -0,0 +1,95 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use crate::objects::{Mutators, NoRoots, RootSource};
+use super::threads::Threads;
+
+// Something that needs every Java thread stopped at a safepoint to run, like a collection, a heap
+// dump or a thread dump. It's given the roots of every thread.
+pub type VmOperation = Box<dyn FnOnce(&mut dyn RootSource) + Send>;
+
+struct QueueState {
+    operations: VecDeque<VmOperation>,
+    shut_down: bool
+}
+
+// The operations waiting for the VM thread to run them.
+pub(super) struct VmOperationQueue {
+    state: Mutex<QueueState>,
+    changed: Condvar
+}
+
+impl VmOperationQueue {
+    pub(super) fn new() -> Self {
+        VmOperationQueue {
+            state: Mutex::new(QueueState { operations: VecDeque::new(), shut_down: false }),
+            changed: Condvar::new()
+        }
+    }
+
+    pub(super) fn push(&self, operation: VmOperation) {
+        self.state.lock().unwrap().operations.push_back(operation);
+        self.changed.notify_all();
+    }
+
+    pub(super) fn shut_down(&self) {
+        self.state.lock().unwrap().shut_down = true;
+        self.changed.notify_all();
+    }
+
+    // Waits for there to be operations and takes all of them, returning None once the queue has been
+    // shut down instead.
+    fn take(&self) -> Option<Vec<VmOperation>> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.shut_down {
+                return None;
+            }
+            if !state.operations.is_empty() {
+                return Some(state.operations.drain(..).collect());
+            }
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+}
+
+// Starts the thread that runs the operations in the queue, which isn't a Java thread itself, so it
+// never has to stop for anyone else. Every operation that has been queued by the time the world has
+// stopped is run at the same safepoint, in the order they were queued, even if one of them panics.
+// The thread exits once the threads it runs operations for are gone.
+pub(super) fn start_vm_thread(threads: Weak<Threads>, queue: Arc<VmOperationQueue>) {
+    std::thread::Builder::new()
+        .name(String::from("VM Thread"))
+        .spawn(move || {
+            while let Some(mut operations) = queue.take() {
+                let threads = match threads.upgrade() {
+                    Some(threads) => threads,
+                    None => return
+                };
+                threads.stop_the_world(&mut NoRoots, &mut |roots| {
+                    for operation in operations.drain(..) {
+                        // One that panics mustn't take the others, or the world it stopped, down with it.
+                        // The panic has already been reported by then.
+                        drop(panic::catch_unwind(AssertUnwindSafe(|| operation(roots))));
+                    }
+                });
+            }
+        })
+        .unwrap_or_else(|error| panic!("Failed to start the VM thread! {}", error));
+}
@@ -0,0 +1,59 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+use std::sync::Arc;
+use crate::assembler::assemble;
+use crate::class_file::ClassLoader;
+use crate::class_file::attributes::AttributeRegistry;
+use crate::code::{Interpreter, MethodResult};
+use crate::objects::{HeapSpace, RootSource};
+use crate::types::Class;
+use crate::verifier::VerifyMode;
+
+// Just enough of the JDK for strings, class mirrors and the exceptions the runtime throws.
+pub(crate) const JDK_SOURCES: [&str; 9] = [
+    ".class java/lang/Class\n.super java/lang/Object\n",
+    ".class java/lang/String\n.super java/lang/Object\n.field value [B\n.field coder B\n",
+    concat!(".class java/lang/Throwable\n.super java/lang/Object\n.field detailMessage Ljava/lang/String;\n",
+        ".field cause Ljava/lang/Throwable;\n"),
+    ".class java/lang/IllegalStateException\n.super java/lang/Throwable\n",
+    ".class java/lang/UnsatisfiedLinkError\n.super java/lang/Throwable\n",
+    ".class java/lang/NullPointerException\n.super java/lang/Throwable\n",
+    ".class java/lang/ArrayIndexOutOfBoundsException\n.super java/lang/Throwable\n",
+    ".class java/lang/ArrayStoreException\n.super java/lang/Throwable\n",
+    ".class java/lang/InternalError\n.super java/lang/Throwable\n"
+];
+
+// A loader that doesn't verify, with the JDK classes already defined in it.
+pub(crate) fn jdk_loader() -> Arc<ClassLoader> {
+    let loader = Arc::new(ClassLoader::with_options(AttributeRegistry::default(), VerifyMode::None));
+    JDK_SOURCES.iter().for_each(|source| drop(loader.define_class(assemble(source).unwrap())));
+    loader
+}
+
+pub(crate) fn call<'h>(heap: &'h HeapSpace, class: &Arc<Class>, name: &str, parameters: &[u32]) -> MethodResult<'h> {
+    let method = class.methods().iter().find(|method| method.name() == name).unwrap();
+    Interpreter::execute(heap, class, method, parameters)
+}
+
+// Offsets that a test holds on to as roots, so that they're updated when the heap is collected.
+pub(crate) struct Offsets(pub(crate) Vec<usize>);
+
+impl RootSource for Offsets {
+    fn visit_roots(&mut self, visitor: &mut dyn FnMut(&mut usize)) {
+        self.0.iter_mut().for_each(visitor);
+    }
+}
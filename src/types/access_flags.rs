@@ -70,6 +70,11 @@ impl AccessFlags {
         self.flags & JVM_ACC_WRITTEN_FLAGS
     }
 
+    #[inline]
+    pub fn raw(&self) -> u32 {
+        self.flags
+    }
+
     is_flag!(public);
     is_flag!(private);
     is_flag!(protected);
@@ -14,47 +14,51 @@
  * Street, Fifth Floor, Boston, MA 02110-1301, USA.
  */
 
-use bytes::{Buf, Bytes};
-use internship::IStr;
-use std::fs;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use std::ops::Deref;
 use std::sync::Arc;
-use astatine_macros::{Nameable, accessible};
-use crate::class_file::ClassLoader;
+use astatine_macros::{Attributed, Nameable, accessible};
+use crate::utils::IStr;
+use crate::class_file::{ClassLoader, write_attribute, write_attributes};
+use crate::class_file::attributes::{Attribute, AttributeRegistry};
 use crate::constants::*;
 use crate::types::method::BootstrapMethod;
 use crate::utils::{BufferExtras, IdentEq};
 use crate::utils::constants::JAVA_LANG_OBJECT_NAME;
+use crate::verifier;
 use super::access_flags::*;
 use super::ConstantPool;
+use super::constant_pool::CLASS_TAG;
 use super::field::Field;
 use super::method::Method;
 use super::RecordComponent;
 
 #[accessible(final, public, abstract, interface)]
-#[derive(Debug, Nameable)]
+#[derive(Debug, Nameable, Attributed)]
 pub struct Class {
     loader: Arc<ClassLoader>,
     minor_version: u16,
     major_version: u16,
     access_flags: AccessFlags,
+    raw_access_flags: u16,
     constant_pool: ConstantPool,
     name: IStr,
-    super_class: Option<Arc<Class>>,
-    interfaces: Vec<Arc<Class>>,
+    this_class_index: u16,
+    super_class_index: u16,
+    interface_indices: Vec<u16>,
     fields: Vec<Arc<Field>>,
     methods: Vec<Arc<Method>>,
+    source_file_index: u16,
     source_file_name: Option<IStr>,
     inner_classes: Vec<InnerClassInfo>,
     record_components: Vec<RecordComponent>,
-    bootstrap_methods: Vec<Arc<BootstrapMethod>>
+    bootstrap_methods: Vec<Arc<BootstrapMethod>>,
+    attribute_order: Vec<u16>,
+    attributes: Vec<Attribute>
 }
 
 impl Class {
-    pub(crate) fn parse(loader: Arc<ClassLoader>, file_name: &str) -> Self {
-        let contents = fs::read(file_name)
-            .expect(&format!("Class file name {} could not be read!", file_name));
-        let mut buf = Bytes::from(contents);
+    pub fn from_bytes(loader: Arc<ClassLoader>, mut buf: Bytes) -> Self {
         let magic = buf.get_u32();
         assert_eq!(magic, JAVA_CLASS_FILE_MAGIC, "Invalid class file magic header! Expected {}, \
             got {}!", JAVA_CLASS_FILE_MAGIC, magic);
@@ -63,10 +67,11 @@ impl Class {
         let major_version = buf.get_u16();
         let constant_pool = ConstantPool::parse(&mut buf);
 
+        let raw_access_flags = buf.get_u16();
         let mut access_flags = if major_version >= JAVA_VERSION_9 {
-            (buf.get_u16() as u32) & (JVM_RECOGNIZED_CLASS_MODIFIERS | JVM_ACC_MODULE)
+            (raw_access_flags as u32) & (JVM_RECOGNIZED_CLASS_MODIFIERS | JVM_ACC_MODULE)
         } else {
-            (buf.get_u16() as u32) & JVM_RECOGNIZED_CLASS_MODIFIERS
+            (raw_access_flags as u32) & JVM_RECOGNIZED_CLASS_MODIFIERS
         };
         if access_flags & JVM_ACC_INTERFACE != 0 && major_version < JAVA_VERSION_6 {
             // Set abstract flag for backwards compatibility
@@ -75,47 +80,118 @@ impl Class {
         verify_modifiers(major_version, access_flags);
         let access_flags = AccessFlags::from(access_flags);
 
-        let this_class = buf.get_u16();
-        let name = constant_pool.get_class_name(this_class as usize)
-            .expect(&format!("Invalid name for class file {}! Expected index {} to be in \
-                constant pool!", file_name, this_class));
-        let super_class = resolve_superclass(Arc::clone(&loader), name.as_str(), &constant_pool,
-                                             buf.get_u16(), access_flags);
-
-        let interfaces = buf.get_generic_u16_array(|buf| {
+        let this_class_index = buf.get_u16();
+        let name = constant_pool.get_class_name(this_class_index as usize)
+            .unwrap_or_else(|| panic!("Invalid name for class file! Expected index {} to be in \
+                constant pool!", this_class_index));
+        let super_class_index = buf.get_u16();
+        verify_superclass(name.as_str(), &constant_pool, super_class_index, access_flags);
+
+        // Super classes and interfaces are kept as the indices the class file gives, which are what the
+        // writer puts back, and are only loaded when they're first asked for. Loading them here would mean
+        // that writing a class out needed its entire hierarchy, which classes compiled against the JDK,
+        // like the round trip fixtures, don't have.
+        let interface_indices = buf.get_generic_u16_array(|buf| {
             let index = buf.get_u16();
-            constant_pool.get_class_no_holder(index as usize, Arc::clone(&loader))
-                .expect(&format!("Invalid class file {}! Expected super interface index {} to be \
-                    in constant pool!", file_name, index))
+            assert_eq!(constant_pool.get_tag(index as usize), Some(CLASS_TAG), "Invalid class file \
+                {}! Expected super interface index {} to be a class in constant pool!", name, index);
+            index
         });
         let fields = buf.get_generic_u16_array(|buf| {
-            Arc::new(Field::parse(&constant_pool, buf, major_version, access_flags))
+            Arc::new(Field::parse(&constant_pool, buf, loader.attributes(), major_version, access_flags))
         });
         let methods = buf.get_generic_u16_array(|buf| {
-            Arc::new(Method::parse(Arc::clone(&loader), file_name, &constant_pool, buf, major_version, access_flags))
+            Arc::new(Method::parse(Arc::clone(&loader), name.as_str(), &constant_pool, buf, major_version,
+                                   access_flags))
         });
 
-        let attributes = parse_attributes(&constant_pool, &mut buf);
-        assert_eq!(buf.remaining(), 0, "Extra bytes found in class file {}!", file_name);
+        let attributes = parse_attributes(&constant_pool, &mut buf, loader.attributes());
+        assert_eq!(buf.remaining(), 0, "Extra bytes found in class file {}!", name);
         Class {
             loader,
             minor_version,
             major_version,
             access_flags,
+            raw_access_flags,
             constant_pool,
             name,
-            super_class,
-            interfaces,
+            this_class_index,
+            super_class_index,
+            interface_indices,
             fields,
             methods,
-            source_file_name: attributes.0,
-            inner_classes: attributes.1.unwrap_or(Vec::new()),
-            record_components: attributes.2.unwrap_or(Vec::new()),
-            bootstrap_methods: attributes.3.unwrap_or(Vec::new())
+            source_file_index: attributes.0.as_ref().map_or(0, |value| value.0),
+            source_file_name: attributes.0.map(|value| value.1),
+            inner_classes: attributes.1.unwrap_or_default(),
+            record_components: attributes.2.unwrap_or_default(),
+            bootstrap_methods: attributes.3.unwrap_or_default(),
+            attribute_order: attributes.4,
+            attributes: attributes.5
         }
     }
 
+    pub fn to_bytes(&self) -> Bytes {
+        let mut buf = BytesMut::new();
+        self.write(&mut buf);
+        buf.freeze()
+    }
+
+    pub(crate) fn write(&self, buf: &mut BytesMut) {
+        let pool = &self.constant_pool;
+        buf.put_u32(JAVA_CLASS_FILE_MAGIC);
+        buf.put_u16(self.minor_version);
+        buf.put_u16(self.major_version);
+        pool.write(buf);
+        buf.put_u16(self.raw_access_flags);
+        buf.put_u16(self.this_class_index);
+        buf.put_u16(self.super_class_index);
+        buf.put_u16(self.interface_indices.len() as u16);
+        self.interface_indices.iter().for_each(|index| buf.put_u16(*index));
+        buf.put_u16(self.fields.len() as u16);
+        self.fields.iter().for_each(|field| field.write(pool, buf));
+        buf.put_u16(self.methods.len() as u16);
+        self.methods.iter().for_each(|method| method.write(pool, buf));
+        // The order only has the names of attributes that were present, so the ones that were parsed in to
+        // lists are written even when they're empty, like the Record attribute of a record with no components.
+        write_attributes(buf, pool, &self.attribute_order, &self.attributes, |buf, name_index, name| {
+            match name {
+                JVM_ATTRIBUTE_SOURCE_FILE if self.source_file_name.is_some() => {
+                    write_attribute(buf, name_index, |buf| buf.put_u16(self.source_file_index));
+                },
+                JVM_ATTRIBUTE_INNER_CLASSES => {
+                    write_attribute(buf, name_index, |buf| {
+                        buf.put_u16(self.inner_classes.len() as u16);
+                        self.inner_classes.iter().for_each(|class| class.write(buf));
+                    });
+                },
+                JVM_ATTRIBUTE_RECORD => {
+                    write_attribute(buf, name_index, |buf| {
+                        buf.put_u16(self.record_components.len() as u16);
+                        self.record_components.iter().for_each(|component| component.write(pool, buf));
+                    });
+                },
+                JVM_ATTRIBUTE_BOOTSTRAP_METHODS => {
+                    write_attribute(buf, name_index, |buf| {
+                        buf.put_u16(self.bootstrap_methods.len() as u16);
+                        self.bootstrap_methods.iter().for_each(|method| method.write(buf));
+                    });
+                },
+                _ => return false
+            }
+            true
+        });
+    }
+
     pub(crate) fn initialize(self: Arc<Class>) -> Arc<Class> {
+        if let Err(error) = verifier::check_constraints(&self) {
+            panic!("Invalid class file {}! {}", self.name, error);
+        }
+        let loader = self.loader();
+        if loader.verify_mode().should_verify(&self.name) {
+            if let Err(error) = verifier::verify(&self, &loader) {
+                panic!("Invalid class file {}! {}", self.name, error);
+            }
+        }
         self.constant_pool.set_holder(Arc::clone(&self));
         self
     }
@@ -136,14 +212,69 @@ impl Class {
         &self.constant_pool
     }
 
+    pub fn raw_access_flags(&self) -> u16 {
+        self.raw_access_flags
+    }
+
+    pub fn this_class_index(&self) -> u16 {
+        self.this_class_index
+    }
+
+    pub fn super_class_index(&self) -> u16 {
+        self.super_class_index
+    }
+
+    pub fn super_class_name(&self) -> Option<IStr> {
+        self.constant_pool.get_class_name(self.super_class_index as usize)
+    }
+
     pub fn super_class(&self) -> Option<Arc<Class>> {
-        self.super_class.as_ref().map(|value| Arc::clone(value))
+        if self.super_class_index == 0 {
+            return None;
+        }
+        self.constant_pool.get_class_no_holder(self.super_class_index as usize, self.loader())
+    }
+
+    pub fn interface_indices(&self) -> &[u16] {
+        self.interface_indices.as_slice()
+    }
+
+    pub fn interface_names(&self) -> Vec<IStr> {
+        self.interface_indices.iter()
+            .filter_map(|index| self.constant_pool.get_class_name(*index as usize))
+            .collect()
+    }
+
+    pub fn interfaces(&self) -> Vec<Arc<Class>> {
+        self.interface_indices.iter()
+            .filter_map(|index| self.constant_pool.get_class_no_holder(*index as usize, self.loader()))
+            .collect()
+    }
+
+    pub fn fields(&self) -> &[Arc<Field>] {
+        self.fields.as_slice()
     }
 
     pub fn field_count(&self) -> usize {
         self.fields.len()
     }
 
+    pub fn methods(&self) -> &[Arc<Method>] {
+        self.methods.as_slice()
+    }
+
+    // The first method that matches in the class or its superclasses, along with the class it's in.
+    pub fn find_method(self: &Arc<Self>, matches: impl Fn(&Method) -> bool) -> Option<(Arc<Class>, Arc<Method>)> {
+        let mut current = Some(Arc::clone(self));
+        while let Some(class) = current {
+            if let Some(method) = class.methods().iter().find(|method| matches(method)) {
+                return Some((Arc::clone(&class), Arc::clone(method)));
+            }
+            current = class.super_class_name().and_then(|name| class.loader().find_class(&name));
+        }
+        None
+    }
+
     pub fn source_file_name(&self) -> Option<&str> {
         self.source_file_name.as_ref().map(|value| value.as_str())
     }
@@ -184,28 +315,24 @@ impl Class {
     }
 }
 
-fn resolve_superclass(
-    loader: Arc<ClassLoader>,
-    name: &str,
-    pool: &ConstantPool,
-    index: u16,
-    flags: AccessFlags
-) -> Option<Arc<Class>> {
-    assert!(flags.is_interface() || index != 0, "Invalid super class! Interfaces must always have an \
+fn verify_superclass(name: &str, pool: &ConstantPool, index: u16, flags: AccessFlags) {
+    assert!(!flags.is_interface() || index != 0, "Invalid super class! Interfaces must always have an \
         explicit superclass!");
     if index == 0 {
         assert_eq!(name, JAVA_LANG_OBJECT_NAME, "Invalid super class! Every class other than {} must \
             have an explicit superclass of {} or one of its subclasses!", JAVA_LANG_OBJECT_NAME,
             JAVA_LANG_OBJECT_NAME);
-        return None;
+        return;
     }
-    pool.get_class_no_holder(index as usize, loader)
+    assert_eq!(pool.get_tag(index as usize), Some(CLASS_TAG), "Invalid super class for {}! Expected \
+        index {} to be a class in constant pool!", name, index);
 }
 
 #[accessible(final, public, abstract, private, protected, static, interface)]
 #[derive(Debug)]
 pub struct InnerClassInfo {
     index: u16,
+    name_index: u16,
     name: Option<IStr>,
     access_flags: AccessFlags,
     outer_index: u16
@@ -215,9 +342,17 @@ impl InnerClassInfo {
     pub(crate) fn parse(pool: &ConstantPool, buf: &mut Bytes) -> Self {
         let index = buf.get_u16();
         let outer_index = buf.get_u16();
-        let name = pool.get_utf8(buf.get_u16() as usize);
+        let name_index = buf.get_u16();
+        let name = pool.get_utf8(name_index as usize);
         let access_flags = AccessFlags::from(buf.get_u16());
-        InnerClassInfo { index, name, access_flags, outer_index }
+        InnerClassInfo { index, name_index, name, access_flags, outer_index }
+    }
+
+    pub(crate) fn write(&self, buf: &mut BytesMut) {
+        buf.put_u16(self.index);
+        buf.put_u16(self.outer_index);
+        buf.put_u16(self.name_index);
+        buf.put_u16(self.access_flags.value() as u16);
     }
 
     pub fn index(&self) -> u16 {
@@ -228,35 +363,47 @@ impl InnerClassInfo {
         self.name.as_ref().map(IStr::as_str)
     }
 
+    pub fn name_index(&self) -> u16 {
+        self.name_index
+    }
+
     pub fn outer_index(&self) -> u16 {
         self.outer_index
     }
+
+    pub fn raw_access_flags(&self) -> u16 {
+        self.access_flags.value() as u16
+    }
 }
 
-type ClassAttributes = (Option<IStr>, Option<Vec<InnerClassInfo>>, Option<Vec<RecordComponent>>,
-                        Option<Vec<Arc<BootstrapMethod>>>);
+type ClassAttributes = (Option<(u16, IStr)>, Option<Vec<InnerClassInfo>>, Option<Vec<RecordComponent>>,
+                        Option<Vec<Arc<BootstrapMethod>>>, Vec<u16>, Vec<Attribute>);
 
-fn parse_attributes(pool: &ConstantPool, buf: &mut Bytes) -> ClassAttributes {
+fn parse_attributes(pool: &ConstantPool, buf: &mut Bytes, registry: &AttributeRegistry) -> ClassAttributes {
     let mut source_file_name = None;
     let mut inner_classes = None;
     let mut record_components = None;
     let mut bootstrap_methods = None;
+    let mut attribute_order = Vec::new();
+    let mut attributes = Vec::new();
 
     let mut attribute_count = buf.get_u16();
     while attribute_count > 0 {
         assert!(buf.len() >= 6, "Truncated class attributes!");
-        let attribute_name = pool.get_utf8(buf.get_u16() as usize).unwrap();
+        let name_index = buf.get_u16();
+        let attribute_name = pool.get_utf8(name_index as usize).unwrap();
         let attribute_length = buf.get_u32();
+        attribute_order.push(name_index);
 
         if attribute_name == JVM_ATTRIBUTE_SOURCE_FILE {
             assert_eq!(attribute_length, 2, "Invalid source file attribute! Expected length of 2, \
                 was {}!", attribute_length);
             assert!(source_file_name.is_none(), "Duplicate source file attribute!");
             let source_file_index = buf.get_u16();
-            let source_file = pool.get_utf8(buf.get_u16() as usize)
-                .expect(&format!("Invalid source file attribute! Expected name index {} to be in \
-                    constant pool!", source_file_index));
-            source_file_name = Some(source_file);
+            let source_file = pool.get_utf8(source_file_index as usize)
+                .unwrap_or_else(|| panic!("Invalid source file attribute! Expected name index {} to \
+                    be in constant pool!", source_file_index));
+            source_file_name = Some((source_file_index, source_file));
         } else if attribute_name == JVM_ATTRIBUTE_INNER_CLASSES {
             assert!(inner_classes.is_none(), "Duplicate inner classes attribute!");
             let number_of_classes = buf.get_u16();
@@ -270,7 +417,7 @@ fn parse_attributes(pool: &ConstantPool, buf: &mut Bytes) -> ClassAttributes {
             let components_count = buf.get_u16();
             let mut components = Vec::with_capacity(components_count as usize);
             for _ in 0..components_count {
-                components.push(RecordComponent::parse(pool, buf));
+                components.push(RecordComponent::parse(pool, buf, registry));
             }
             record_components = Some(components);
         } else if attribute_name == JVM_ATTRIBUTE_BOOTSTRAP_METHODS {
@@ -281,9 +428,8 @@ fn parse_attributes(pool: &ConstantPool, buf: &mut Bytes) -> ClassAttributes {
                 methods.push(Arc::new(BootstrapMethod::parse(pool, buf)));
             }
             bootstrap_methods = Some(methods)
-        } else {
-            // Skip past any attribute that we don't recognise
-            buf.advance(attribute_length as usize);
+        } else if let Some(attribute) = registry.read(pool, attribute_name, buf, attribute_length) {
+            attributes.push(attribute);
         }
         attribute_count -= 1;
     }
@@ -292,7 +438,7 @@ fn parse_attributes(pool: &ConstantPool, buf: &mut Bytes) -> ClassAttributes {
         assert!(bootstrap_methods.is_some(), "Invalid class attributes! Bootstrap methods must be \
             present if the class file has a Dynamic or InvokeDynamic constant in the constant pool!");
     }
-    (source_file_name, inner_classes, record_components, bootstrap_methods)
+    (source_file_name, inner_classes, record_components, bootstrap_methods, attribute_order, attributes)
 }
 
 fn verify_modifiers(major_version: u16, flags: u32) {
@@ -316,4 +462,47 @@ fn verify_modifiers(major_version: u16, flags: u32) {
     assert!(!is_illegal, "Illegal class modifiers {}!", flags);
 }
 
-const JAVA_CLASS_FILE_MAGIC: u32 = 0xCAFEBABE;
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use std::fs;
+    use std::path::Path;
+    use std::sync::Arc;
+    use crate::class_file::ClassLoader;
+    use crate::class_file::attributes::AttributeRegistry;
+    use super::Class;
+
+    fn assert_round_trips(path: &Path) {
+        let original = Bytes::from(fs::read(path).unwrap());
+        let loader = Arc::new(ClassLoader::with_attributes(AttributeRegistry::new(true)));
+        let class = Class::from_bytes(loader, original.clone());
+        assert!(class.to_bytes() == original, "Class file {} did not round trip!", path.display());
+    }
+
+    #[test]
+    fn round_trip() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        assert_round_trips(&root.join("Hello.class"));
+        assert_round_trips(&root.join("Main.class"));
+        for entry in fs::read_dir(root.join("fixtures")).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().map_or(false, |extension| extension == "class") {
+                assert_round_trips(&path);
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_every_table_of_split_code_attributes() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures").join("SplitTables.class");
+        let loader = Arc::new(ClassLoader::with_attributes(AttributeRegistry::new(true)));
+        let class = Class::from_bytes(loader, Bytes::from(fs::read(path).unwrap()));
+        let sum = class.methods().iter().find(|method| method.name() == "sum").unwrap();
+        let code = sum.code().unwrap();
+        assert_eq!(code.line_numbers().len(), 2);
+        assert_eq!(code.local_variables().len(), 2);
+        assert_eq!(code.local_variable_types().len(), 2);
+        assert_eq!(code.line_number(26), Some(8));
+        assert_eq!(code.line_number(85), Some(14));
+    }
+}
@@ -14,12 +14,12 @@
  * Street, Fifth Floor, Boston, MA 02110-1301, USA.
  */
 
-use bytes::{Buf, Bytes};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use enum_as_inner::EnumAsInner;
-use internship::IStr;
 use paste::paste;
 use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
+use crate::utils::IStr;
 use crate::class_file::ClassLoader;
 use crate::objects::handles::{FieldRef, MethodHandle, MethodRef};
 use crate::types::Class;
@@ -84,7 +84,14 @@ impl ConstantPool {
             if tag == DYNAMIC_TAG || tag == INVOKE_DYNAMIC_TAG { has_dynamic = true }
             tags.push(tag);
             constants.push(PoolConstant::parse(tag, buf));
-            if tag == LONG_TAG || tag == DOUBLE_TAG { index += 2 } else { index += 1 }
+            if tag == LONG_TAG || tag == DOUBLE_TAG {
+                // Longs and doubles take up two entries, and the second one can't be used
+                tags.push(UNUSABLE_TAG);
+                constants.push(PoolConstant::Unusable);
+                index += 2
+            } else {
+                index += 1
+            }
         }
 
         // No funny business on my watch!
@@ -115,15 +122,26 @@ impl ConstantPool {
     }
 
     pub fn has(&self, index: usize) -> bool {
-        index < self.tags.len()
+        index > 0 && index <= self.tags.len() && self.tags[index - 1] != UNUSABLE_TAG
     }
 
     pub fn get_tag(&self, index: usize) -> Option<u8> {
-        self.tags.get(index - 1).map(|value| *value)
+        index.checked_sub(1).and_then(|index| self.tags.get(index)).copied()
+    }
+
+    pub(crate) fn write(&self, buf: &mut BytesMut) {
+        buf.put_u16((self.tags.len() + 1) as u16);
+        for (tag, constant) in self.tags.iter().zip(self.constants.iter()) {
+            if *tag == UNUSABLE_TAG {
+                continue;
+            }
+            buf.put_u8(*tag);
+            constant.write(buf);
+        }
     }
 
     pub fn get_utf8(&self, index: usize) -> Option<IStr> {
-        self.get(index).and_then(|value| value.as_utf8()).map(|value| value.clone())
+        self.get(index).and_then(|value| value.as_utf8()).map(|(value, _)| value.clone())
     }
 
     pub fn get_string(&self, index: usize) -> Option<IStr> {
@@ -213,7 +231,12 @@ impl ConstantPool {
     get_constant!(double, f64);
 
     fn get(&self, index: usize) -> Option<&PoolConstant> {
-        self.constants.get(index - 1)
+        index.checked_sub(1).and_then(|index| self.constants.get(index))
+    }
+
+    // The raw entry, without resolving anything, for tools that need to show the pool as it is.
+    pub fn get_constant(&self, index: usize) -> Option<&PoolConstant> {
+        self.get(index).filter(|value| !matches!(value, PoolConstant::Unusable))
     }
 
     pub(crate) fn get_class_name(&self, index: usize) -> Option<IStr> {
@@ -237,14 +260,22 @@ impl ConstantPool {
         resolver: impl FnOnce() -> Option<ResolvedPoolConstant>,
         converter: impl FnOnce(&ResolvedPoolConstant) -> Option<T>
     ) -> Option<T> {
+        if let Some(resolved) = self.resolution_cache.read().unwrap().get(&(index - 1)) {
+            return resolved.as_ref().and_then(converter);
+        }
+        // Resolving can resolve other constants, like the class of a field ref, so the cache can't be
+        // locked while it happens.
+        let resolved = resolver();
         self.resolution_cache.write().unwrap()
             .entry(index - 1)
-            .or_insert_with(resolver)
+            .or_insert(resolved)
             .as_ref()
             .and_then(converter)
     }
 }
 
+// Not a real tag, this marks the second entry taken up by longs and doubles
+pub const UNUSABLE_TAG: u8 = 0;
 pub const UTF8_TAG: u8 = 1;
 pub const INT_TAG: u8 = 3;
 pub const FLOAT_TAG: u8 = 4;
@@ -264,8 +295,8 @@ pub const MODULE_TAG: u8 = 19;
 pub const PACKAGE_TAG: u8 = 20;
 
 #[derive(Debug, EnumAsInner)]
-enum PoolConstant {
-    Utf8(IStr),
+pub enum PoolConstant {
+    Utf8(IStr, Bytes),
     Int(i32),
     Float(f32),
     Long(i64),
@@ -281,13 +312,14 @@ enum PoolConstant {
     Dynamic { bootstrap_method_index: u16, nat_index: u16 },
     InvokeDynamic { bootstrap_method_index: u16, nat_index: u16 },
     Module { name_index: u16 },
-    Package { name_index: u16 }
+    Package { name_index: u16 },
+    Unusable
 }
 
 impl PoolConstant {
     fn parse(tag: u8, buf: &mut Bytes) -> Self {
         match tag {
-            UTF8_TAG => PoolConstant::Utf8(PoolConstant::parse_utf8(buf)),
+            UTF8_TAG => PoolConstant::parse_utf8(buf),
             INT_TAG => PoolConstant::Int(buf.get_i32()),
             FLOAT_TAG => PoolConstant::Float(buf.get_f32()),
             LONG_TAG => PoolConstant::Long(buf.get_i64()),
@@ -329,11 +361,94 @@ impl PoolConstant {
         }
     }
 
-    fn parse_utf8(buf: &mut Bytes) -> IStr {
+    // The bytes are kept as they were, as strings can hold unpaired surrogates that don't survive being
+    // decoded in to a String, so that they're written back out unchanged.
+    fn parse_utf8(buf: &mut Bytes) -> Self {
         let length = buf.get_u16();
-        let bytes = buf.copy_to_bytes(length as usize).to_vec();
-        IStr::from_utf8(bytes.as_slice()).expect("Failed to convert bytes to string!")
+        let bytes = buf.copy_to_bytes(length as usize);
+        PoolConstant::Utf8(IStr::new(&decode_modified_utf8(&bytes)), bytes)
+    }
+
+    fn write(&self, buf: &mut BytesMut) {
+        match self {
+            PoolConstant::Utf8(_, bytes) => {
+                buf.put_u16(bytes.len() as u16);
+                buf.put_slice(bytes);
+            },
+            PoolConstant::Int(value) => buf.put_i32(*value),
+            PoolConstant::Float(value) => buf.put_f32(*value),
+            PoolConstant::Long(value) => buf.put_i64(*value),
+            PoolConstant::Double(value) => buf.put_f64(*value),
+            PoolConstant::Class { name_index } => buf.put_u16(*name_index),
+            PoolConstant::String { value_index } => buf.put_u16(*value_index),
+            PoolConstant::FieldRef { class_index, nat_index } |
+            PoolConstant::MethodRef { class_index, nat_index } |
+            PoolConstant::InterfaceMethodRef { class_index, nat_index } => {
+                buf.put_u16(*class_index);
+                buf.put_u16(*nat_index);
+            },
+            PoolConstant::NameAndType { name_index, descriptor_index } => {
+                buf.put_u16(*name_index);
+                buf.put_u16(*descriptor_index);
+            },
+            PoolConstant::MethodHandle { reference_kind, reference_index } => {
+                buf.put_u8(*reference_kind);
+                buf.put_u16(*reference_index);
+            },
+            PoolConstant::MethodType { descriptor_index } => buf.put_u16(*descriptor_index),
+            PoolConstant::Dynamic { bootstrap_method_index, nat_index } |
+            PoolConstant::InvokeDynamic { bootstrap_method_index, nat_index } => {
+                buf.put_u16(*bootstrap_method_index);
+                buf.put_u16(*nat_index);
+            },
+            PoolConstant::Module { name_index } | PoolConstant::Package { name_index } => {
+                buf.put_u16(*name_index)
+            },
+            PoolConstant::Unusable => {}
+        }
+    }
+}
+
+// Class files store strings in a "modified" UTF-8, where the null character is encoded in two
+// bytes, and supplementary characters are encoded as surrogate pairs, with each surrogate taking
+// three bytes, rather than as a single four byte sequence.
+pub(crate) fn decode_modified_utf8(bytes: &[u8]) -> String {
+    let mut units = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let first = bytes[index] as u16;
+        let continuation = |offset: usize| bytes.get(index + offset).map_or(0, |value| (*value & 0x3F) as u16);
+        if first & 0x80 == 0 {
+            units.push(first);
+            index += 1;
+        } else if first & 0xE0 == 0xC0 {
+            units.push(((first & 0x1F) << 6) | continuation(1));
+            index += 2;
+        } else {
+            units.push(((first & 0x0F) << 12) | (continuation(1) << 6) | continuation(2));
+            index += 3;
+        }
+    }
+    String::from_utf16_lossy(units.as_slice())
+}
+
+pub(crate) fn encode_modified_utf8(value: &str) -> Vec<u8> {
+    let mut result = Vec::with_capacity(value.len());
+    for unit in value.encode_utf16() {
+        match unit {
+            0x0001..=0x007F => result.push(unit as u8),
+            0x0000 | 0x0080..=0x07FF => {
+                result.push((0xC0 | (unit >> 6)) as u8);
+                result.push((0x80 | (unit & 0x3F)) as u8);
+            },
+            _ => {
+                result.push((0xE0 | (unit >> 12)) as u8);
+                result.push((0x80 | ((unit >> 6) & 0x3F)) as u8);
+                result.push((0x80 | (unit & 0x3F)) as u8);
+            }
+        }
     }
+    result
 }
 
 #[derive(Debug, EnumAsInner)]
@@ -14,23 +14,30 @@
  * Street, Fifth Floor, Boston, MA 02110-1301, USA.
  */
 
-use astatine_macros::{FieldDescribable, Nameable, Generic, accessible};
-use bytes::{Buf, Bytes};
-use internship::IStr;
-use crate::class_file::parse_generic_signature;
+use astatine_macros::{Attributed, FieldDescribable, Nameable, Generic, accessible};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use crate::utils::IStr;
+use crate::class_file::{parse_generic_signature, write_attribute, write_attributes};
+use crate::class_file::attributes::{Attribute, AttributeRegistry};
 use crate::constants::*;
 use crate::utils::descriptors::{FieldDescriptor, FieldType};
 use super::access_flags::*;
 use super::constant_pool::*;
 
 #[accessible(final, public, private, protected, static, volatile, transient)]
-#[derive(Debug, Nameable, FieldDescribable, Generic)]
+#[derive(Debug, Nameable, FieldDescribable, Generic, Attributed)]
 pub struct Field {
     name: IStr,
+    name_index: u16,
     descriptor: FieldDescriptor,
+    descriptor_index: u16,
     access_flags: AccessFlags,
     generic_signature: Option<IStr>,
-    constant_value: Option<ConstantValue>
+    generic_signature_index: u16,
+    constant_value: Option<ConstantValue>,
+    constant_value_index: u16,
+    attribute_order: Vec<u16>,
+    attributes: Vec<Attribute>
 }
 
 macro_rules! is_constant {
@@ -50,6 +57,7 @@ impl Field {
     pub(crate) fn parse(
         pool: &ConstantPool,
         buf: &mut Bytes,
+        registry: &AttributeRegistry,
         major_version: u16,
         class_flags: AccessFlags
     ) -> Self {
@@ -58,15 +66,65 @@ impl Field {
             assert_eq!(access_flags, PUBLIC_STATIC_FINAL, "Invalid field! All fields in interfaces \
                 must be public static final and not have any other modifiers!");
         }
-        let name = pool.get_utf8(buf.get_u16() as usize)
+        let name_index = buf.get_u16();
+        let name = pool.get_utf8(name_index as usize)
             .expect("Invalid field! Expected name in constant pool!");
-        let descriptor = pool.get_utf8(buf.get_u16() as usize)
+        let descriptor_index = buf.get_u16();
+        let descriptor = pool.get_utf8(descriptor_index as usize)
             .and_then(|value| FieldDescriptor::parse(value.as_str()))
-            .expect(&format!("Invalid field! Expected descriptor in constant pool!"));
+            .expect("Invalid field! Expected descriptor in constant pool!");
 
         let access_flags = AccessFlags::from(access_flags);
-        let attributes = parse_attributes(pool, buf, major_version, access_flags.is_static(), &descriptor);
-        Field { name, descriptor, access_flags, generic_signature: attributes.1, constant_value: attributes.0 }
+        let attributes = parse_attributes(pool, buf, registry, major_version, access_flags.is_static(),
+                                          &descriptor);
+        Field {
+            name,
+            name_index,
+            descriptor,
+            descriptor_index,
+            access_flags,
+            generic_signature_index: attributes.1.as_ref().map_or(0, |value| value.0),
+            generic_signature: attributes.1.map(|value| value.1),
+            constant_value_index: attributes.0.as_ref().map_or(0, |value| value.0),
+            constant_value: attributes.0.map(|value| value.1),
+            attribute_order: attributes.2,
+            attributes: attributes.3
+        }
+    }
+
+    pub(crate) fn write(&self, pool: &ConstantPool, buf: &mut BytesMut) {
+        buf.put_u16(self.access_flags.value() as u16);
+        buf.put_u16(self.name_index);
+        buf.put_u16(self.descriptor_index);
+        write_attributes(buf, pool, &self.attribute_order, &self.attributes, |buf, name_index, name| {
+            match name {
+                JVM_ATTRIBUTE_CONSTANT_VALUE if self.constant_value.is_some() => {
+                    write_attribute(buf, name_index, |buf| buf.put_u16(self.constant_value_index));
+                },
+                JVM_ATTRIBUTE_SYNTHETIC | JVM_ATTRIBUTE_DEPRECATED => write_attribute(buf, name_index, |_| {}),
+                JVM_ATTRIBUTE_SIGNATURE if self.generic_signature.is_some() => {
+                    write_attribute(buf, name_index, |buf| buf.put_u16(self.generic_signature_index));
+                },
+                _ => return false
+            }
+            true
+        });
+    }
+
+    pub fn name_index(&self) -> u16 {
+        self.name_index
+    }
+
+    pub fn descriptor_index(&self) -> u16 {
+        self.descriptor_index
+    }
+
+    pub fn raw_access_flags(&self) -> u16 {
+        self.access_flags.value() as u16
+    }
+
+    pub fn generic_signature_index(&self) -> u16 {
+        self.generic_signature_index
     }
 
     pub fn constant_value(&self) -> Option<&ConstantValue> {
@@ -86,23 +144,28 @@ impl Field {
     }
 }
 
-type FieldAttributes = (Option<ConstantValue>, Option<IStr>);
+type FieldAttributes = (Option<(u16, ConstantValue)>, Option<(u16, IStr)>, Vec<u16>, Vec<Attribute>);
 
 fn parse_attributes(
     pool: &ConstantPool,
     buf: &mut Bytes,
+    registry: &AttributeRegistry,
     major_version: u16,
     is_static: bool,
     descriptor: &FieldDescriptor
 ) -> FieldAttributes {
     let mut constant_value = None;
     let mut generic_signature = None;
+    let mut attribute_order = Vec::new();
+    let mut attributes = Vec::new();
 
     let mut attributes_count = buf.get_u16();
     while attributes_count > 0 {
         assert!(buf.len() >= 6, "Truncated field attributes!");
-        let attribute_name = pool.get_utf8(buf.get_u16() as usize).unwrap();
+        let name_index = buf.get_u16();
+        let attribute_name = pool.get_utf8(name_index as usize).unwrap();
         let attribute_length = buf.get_u32();
+        attribute_order.push(name_index);
 
         if is_static && attribute_name == JVM_ATTRIBUTE_CONSTANT_VALUE {
             if constant_value.is_some() {
@@ -111,21 +174,21 @@ fn parse_attributes(
             assert_eq!(attribute_length, 2, "Invalid ConstantValue attribute! Expected length \
                 of 2, was {}!", attribute_length);
             let constant_value_index = buf.get_u16();
-            constant_value = ConstantValue::parse(pool, constant_value_index, descriptor);
+            constant_value = ConstantValue::parse(pool, constant_value_index, descriptor)
+                .map(|value| (constant_value_index, value));
         } else if attribute_name == JVM_ATTRIBUTE_SYNTHETIC {
             assert_eq!(attribute_length, 0, "Invalid synthetic attribute length {} for field!", attribute_length);
         } else if attribute_name == JVM_ATTRIBUTE_DEPRECATED {
             assert_eq!(attribute_length, 0, "Invalid deprecated attribute length {} for field !", attribute_length);
         } else if major_version >= JAVA_VERSION_1_5 && attribute_name == JVM_ATTRIBUTE_SIGNATURE {
             assert!(generic_signature.is_none(), "Duplicate generic signature attribute found for field!");
-            generic_signature = parse_generic_signature(pool, buf, attribute_length, "field");
-        } else {
-            // Skip past any attribute that we don't recognise
-            buf.advance(attribute_length as usize);
+            generic_signature = Some(parse_generic_signature(pool, buf, attribute_length, "field"));
+        } else if let Some(attribute) = registry.read(pool, attribute_name, buf, attribute_length) {
+            attributes.push(attribute);
         }
         attributes_count -= 1;
     };
-    (constant_value, generic_signature)
+    (constant_value, generic_signature, attribute_order, attributes)
 }
 
 #[derive(Debug, Clone)]
@@ -137,12 +200,11 @@ pub enum ConstantValue {
     String(IStr)
 }
 
-const STRING_DESCRIPTOR: &str = "Ljava/lang/String;";
+const STRING_CLASS_NAME: &str = "java/lang/String";
 
 impl ConstantValue {
     fn parse(pool: &ConstantPool, index: u16, descriptor: &FieldDescriptor) -> Option<Self> {
-        assert!(index > 0 && index < (pool.len() as u16), "Bad constant value! Failed to find \
-            value at index {}!", index);
+        assert!(pool.has(index as usize), "Bad constant value! Failed to find value at index {}!", index);
         let value_type = pool.get_tag(index as usize)
             .expect("Invalid field constant value! Expected tag for constant value!");
         match &descriptor.base() {
@@ -164,9 +226,9 @@ impl ConstantValue {
                 pool.get_int(index as usize).map(|value| ConstantValue::Integer(value))
             },
             FieldType::Reference(name) => {
-                assert!(value_type == CLASS_TAG && name == STRING_DESCRIPTOR, "Inconsistent \
+                assert!(value_type == STRING_TAG && name == STRING_CLASS_NAME, "Inconsistent \
                     constant value type or descriptor! Expected string!");
-                pool.get_utf8(index as usize).map(|value| ConstantValue::String(value))
+                pool.get_string(index as usize).map(ConstantValue::String)
             }
         }
     }
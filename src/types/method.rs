@@ -14,45 +14,54 @@
  * Street, Fifth Floor, Boston, MA 02110-1301, USA.
  */
 
-use astatine_macros::{Nameable, MethodDescribable, accessible};
-use bytes::{Buf, Bytes};
-use internship::IStr;
+use astatine_macros::{Attributed, Nameable, MethodDescribable, Generic, accessible};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use std::sync::Arc;
-use crate::class_file::{ClassLoader, parse_generic_signature};
+use crate::utils::IStr;
+use crate::class_file::{ClassLoader, parse_generic_signature, write_attribute, write_attributes};
+use crate::class_file::attributes::Attribute;
 use crate::class_file::code::CodeBlock;
 use crate::constants::*;
 use crate::objects::handles::MethodHandle;
 use crate::utils::BufferExtras;
 use crate::utils::descriptors::MethodDescriptor;
 use super::access_flags::*;
-use super::constant_pool::ConstantPool;
+use super::constant_pool::{ConstantPool, METHOD_HANDLE_TAG};
 
 #[accessible(final, public, abstract, private, protected, static)]
-#[derive(Debug, Nameable, MethodDescribable)]
+#[derive(Debug, Nameable, MethodDescribable, Generic, Attributed)]
 pub struct Method {
     name: IStr,
+    name_index: u16,
     descriptor: MethodDescriptor,
+    descriptor_index: u16,
     access_flags: AccessFlags,
+    raw_access_flags: u16,
     generic_signature: Option<IStr>,
+    generic_signature_index: u16,
     parameters: Vec<MethodParameter>,
     code: Option<CodeBlock>,
-    checked_exception_indices: Vec<u16>
+    checked_exception_indices: Vec<u16>,
+    attribute_order: Vec<u16>,
+    attributes: Vec<Attribute>
 }
 
 impl Method {
     pub(crate) fn parse(
         loader: Arc<ClassLoader>,
-        class_file_name: &str,
+        class_name: &str,
         pool: &ConstantPool,
         buf: &mut Bytes,
         major_version: u16,
         class_flags: AccessFlags
     ) -> Self {
-        let mut access_flags = buf.get_u16() as u32;
+        let raw_access_flags = buf.get_u16();
+        let mut access_flags = raw_access_flags as u32;
         let name_index = buf.get_u16();
         let name = pool.get_utf8(name_index as usize)
-            .expect(&format!("Invalid method! Expected name index {} to be in constant pool!", name_index));
-        let descriptor = pool.get_utf8(buf.get_u16() as usize)
+            .unwrap_or_else(|| panic!("Invalid method! Expected name index {} to be in constant pool!", name_index));
+        let descriptor_index = buf.get_u16();
+        let descriptor = pool.get_utf8(descriptor_index as usize)
             .and_then(|value| MethodDescriptor::parse(value.as_str()))
             .expect("Invalid method descriptor!");
 
@@ -79,8 +88,8 @@ impl Method {
         }
         if name == JVM_OBJECT_INITIALIZER_NAME {
             access_flags |= JVM_ACC_CONSTRUCTOR;
-            assert!(class_flags.is_interface(), "Invalid class file {}! Interface cannot have a \
-                constructor!", class_file_name);
+            assert!(!class_flags.is_interface(), "Invalid class file {}! Interface cannot have a \
+                constructor!", class_name);
         }
 
         let attributes = parse_attributes(loader, pool, buf, major_version, access_flags);
@@ -93,15 +102,72 @@ impl Method {
         let access_flags = AccessFlags::new(access_flags);
         Method {
             name,
+            name_index,
             descriptor,
+            descriptor_index,
             access_flags,
-            generic_signature: attributes.3,
-            parameters: attributes.2.unwrap_or(Vec::new()),
+            raw_access_flags,
+            generic_signature_index: attributes.3.as_ref().map_or(0, |value| value.0),
+            generic_signature: attributes.3.map(|value| value.1),
+            parameters: attributes.2.unwrap_or_default(),
             code: attributes.0,
-            checked_exception_indices: attributes.1.unwrap_or(Vec::new())
+            checked_exception_indices: attributes.1.unwrap_or_default(),
+            attribute_order: attributes.4,
+            attributes: attributes.5
         }
     }
 
+    pub(crate) fn write(&self, pool: &ConstantPool, buf: &mut BytesMut) {
+        buf.put_u16(self.raw_access_flags);
+        buf.put_u16(self.name_index);
+        buf.put_u16(self.descriptor_index);
+        write_attributes(buf, pool, &self.attribute_order, &self.attributes, |buf, name_index, name| {
+            match name {
+                JVM_ATTRIBUTE_CODE if self.code.is_some() => {
+                    write_attribute(buf, name_index, |buf| self.code.as_ref().unwrap().write(pool, buf));
+                },
+                JVM_ATTRIBUTE_EXCEPTIONS => write_attribute(buf, name_index, |buf| {
+                    buf.put_u16(self.checked_exception_indices.len() as u16);
+                    self.checked_exception_indices.iter().for_each(|index| buf.put_u16(*index));
+                }),
+                JVM_ATTRIBUTE_METHOD_PARAMETERS => write_attribute(buf, name_index, |buf| {
+                    buf.put_u8(self.parameters.len() as u8);
+                    self.parameters.iter().for_each(|parameter| parameter.write(buf));
+                }),
+                JVM_ATTRIBUTE_SYNTHETIC | JVM_ATTRIBUTE_DEPRECATED => write_attribute(buf, name_index, |_| {}),
+                JVM_ATTRIBUTE_SIGNATURE if self.generic_signature.is_some() => {
+                    write_attribute(buf, name_index, |buf| buf.put_u16(self.generic_signature_index));
+                },
+                _ => return false
+            }
+            true
+        });
+    }
+
+    pub fn name_index(&self) -> u16 {
+        self.name_index
+    }
+
+    pub fn descriptor_index(&self) -> u16 {
+        self.descriptor_index
+    }
+
+    pub fn raw_access_flags(&self) -> u16 {
+        self.raw_access_flags
+    }
+
+    pub fn generic_signature_index(&self) -> u16 {
+        self.generic_signature_index
+    }
+
+    pub fn parameters(&self) -> &[MethodParameter] {
+        self.parameters.as_slice()
+    }
+
+    pub fn checked_exception_indices(&self) -> &[u16] {
+        self.checked_exception_indices.as_slice()
+    }
+
     pub fn code(&self) -> Option<&CodeBlock> {
         self.code.as_ref()
     }
@@ -135,21 +201,34 @@ impl Method {
     }
 }
 
+// The method handle isn't resolved until it's first needed, as resolving it requires the class
+// that holds the constant pool to be available.
 #[derive(Debug)]
 pub struct BootstrapMethod {
-    handle: Arc<MethodHandle>,
+    handle_index: u16,
     arguments: Vec<u16>
 }
 
 impl BootstrapMethod {
     pub(crate) fn parse(pool: &ConstantPool, buf: &mut Bytes) -> Self {
-        let handle = pool.get_method_handle(buf.get_u16() as usize)
-            .expect(&format!("Invalid bootstrap method! Expected index to be in constant pool!"));
-        BootstrapMethod { handle, arguments: buf.get_u16_array() }
+        let handle_index = buf.get_u16();
+        assert_eq!(pool.get_tag(handle_index as usize), Some(METHOD_HANDLE_TAG), "Invalid bootstrap \
+            method! Expected index {} to be a method handle in constant pool!", handle_index);
+        BootstrapMethod { handle_index, arguments: buf.get_u16_array() }
+    }
+
+    pub(crate) fn write(&self, buf: &mut BytesMut) {
+        buf.put_u16(self.handle_index);
+        buf.put_u16(self.arguments.len() as u16);
+        self.arguments.iter().for_each(|argument| buf.put_u16(*argument));
+    }
+
+    pub fn handle_index(&self) -> u16 {
+        self.handle_index
     }
 
-    pub fn handle(&self) -> &MethodHandle {
-        &self.handle
+    pub fn handle(&self, pool: &ConstantPool) -> Option<Arc<MethodHandle>> {
+        pool.get_method_handle(self.handle_index as usize)
     }
 
     pub fn arguments(&self) -> &[u16] {
@@ -161,6 +240,7 @@ impl BootstrapMethod {
 #[derive(Debug)]
 pub struct MethodParameter {
     name: Option<IStr>,
+    name_index: u16,
     access_flags: AccessFlags
 }
 
@@ -172,19 +252,29 @@ impl MethodParameter {
         assert!(name_index == 0 || pool.has(name_index as usize));
         let name = pool.get_utf8(name_index as usize);
         let access_flags = AccessFlags::from(buf.get_u16());
-        MethodParameter { name, access_flags }
+        MethodParameter { name, name_index, access_flags }
+    }
+
+    pub(crate) fn write(&self, buf: &mut BytesMut) {
+        buf.put_u16(self.name_index);
+        buf.put_u16(self.access_flags.raw() as u16);
     }
 
     pub fn name(&self) -> Option<&str> {
         self.name.as_ref().map(|value| value.as_str())
     }
 
+    pub fn raw_access_flags(&self) -> u16 {
+        self.access_flags.raw() as u16
+    }
+
     pub fn is_mandated(&self) -> bool {
-        self.access_flags.value() & ACC_MANDATED != 0
+        self.access_flags.raw() & ACC_MANDATED != 0
     }
 }
 
-type MethodAttributes = (Option<CodeBlock>, Option<Vec<u16>>, Option<Vec<MethodParameter>>, Option<IStr>);
+type MethodAttributes = (Option<CodeBlock>, Option<Vec<u16>>, Option<Vec<MethodParameter>>,
+                        Option<(u16, IStr)>, Vec<u16>, Vec<Attribute>);
 
 fn parse_attributes(
     loader: Arc<ClassLoader>,
@@ -197,12 +287,16 @@ fn parse_attributes(
     let mut checked_exception_indices = None;
     let mut parameters = None;
     let mut generic_signature = None;
+    let mut attribute_order = Vec::new();
+    let mut attributes = Vec::new();
 
     let mut attribute_count = buf.get_u16();
     while attribute_count > 0 {
         assert!(buf.len() >= 6, "Truncated method attributes!");
-        let attribute_name = pool.get_utf8(buf.get_u16() as usize).unwrap();
+        let name_index = buf.get_u16();
+        let attribute_name = pool.get_utf8(name_index as usize).unwrap();
         let attribute_length = buf.get_u32();
+        attribute_order.push(name_index);
 
         if attribute_name == JVM_ATTRIBUTE_CODE {
             assert!(code.is_none(), "Expected single code attribute for method!");
@@ -219,7 +313,7 @@ fn parse_attributes(
             checked_exception_indices = Some(exceptions)
         } else if attribute_name == JVM_ATTRIBUTE_METHOD_PARAMETERS {
             assert!(parameters.is_none(), "Expected single method parameters attribute for method!");
-            let count = buf.get_u16();
+            let count = buf.get_u8();
             let mut parameter_list = Vec::new();
             for _ in 0..count {
                 parameter_list.push(MethodParameter::parse(pool, buf));
@@ -231,14 +325,13 @@ fn parse_attributes(
             assert_eq!(attribute_length, 0, "Invalid deprecated attribute length {} for method!", attribute_length);
         } else if major_version >= JAVA_VERSION_1_5 && attribute_name == JVM_ATTRIBUTE_SIGNATURE {
             assert!(generic_signature.is_none(), "Duplicate generic signature attribute found for method!");
-            generic_signature = parse_generic_signature(pool, buf, attribute_length, "method");
-        } else {
-            // Skip past any attribute that we don't recognise
-            buf.advance(attribute_length as usize);
+            generic_signature = Some(parse_generic_signature(pool, buf, attribute_length, "method"));
+        } else if let Some(attribute) = loader.attributes().read(pool, attribute_name, buf, attribute_length) {
+            attributes.push(attribute);
         }
         attribute_count -= 1;
     }
-    (code, checked_exception_indices, parameters, generic_signature)
+    (code, checked_exception_indices, parameters, generic_signature, attribute_order, attributes)
 }
 
 fn verify_method_flags(major_version: u16, class_flags: AccessFlags, flags: u32, name: &str) {
@@ -16,7 +16,7 @@
 
 use astatine_macros::{Nameable, Versioned};
 use bytes::{Buf, Bytes};
-use internship::IStr;
+use crate::utils::IStr;
 use crate::constants::JAVA_VERSION_10;
 use crate::utils::BufferExtras;
 use super::access_flags::*;
@@ -14,51 +14,91 @@
  * Street, Fifth Floor, Boston, MA 02110-1301, USA.
  */
 
-use astatine_macros::{Nameable, FieldDescribable, Generic};
-use bytes::{Buf, Bytes};
-use internship::IStr;
-use crate::class_file::parse_generic_signature;
+use astatine_macros::{Attributed, Nameable, FieldDescribable, Generic};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use crate::utils::IStr;
+use crate::class_file::{parse_generic_signature, write_attribute, write_attributes};
+use crate::class_file::attributes::{Attribute, AttributeRegistry};
 use crate::constants::JVM_ATTRIBUTE_SIGNATURE;
 use crate::utils::descriptors::FieldDescriptor;
 use super::ConstantPool;
 
-#[derive(Debug, Nameable, FieldDescribable, Generic)]
+#[derive(Debug, Nameable, FieldDescribable, Generic, Attributed)]
 pub struct RecordComponent {
     name: IStr,
+    name_index: u16,
     descriptor: FieldDescriptor,
-    generic_signature: Option<IStr>
+    descriptor_index: u16,
+    generic_signature: Option<IStr>,
+    generic_signature_index: u16,
+    attribute_order: Vec<u16>,
+    attributes: Vec<Attribute>
 }
 
 impl RecordComponent {
-    pub(crate) fn parse(pool: &ConstantPool, buf: &mut Bytes) -> Self {
-        let name = pool.get_utf8(buf.get_u16() as usize)
+    pub(crate) fn parse(pool: &ConstantPool, buf: &mut Bytes, registry: &AttributeRegistry) -> Self {
+        let name_index = buf.get_u16();
+        let name = pool.get_utf8(name_index as usize)
             .expect("Invalid record component! Expected name in constant pool!");
-        let descriptor = pool.get_utf8(buf.get_u16() as usize)
+        let descriptor_index = buf.get_u16();
+        let descriptor = pool.get_utf8(descriptor_index as usize)
             .and_then(|value| FieldDescriptor::parse(value.as_str()))
             .expect("Invalid record component! Expected descriptor in constant pool!");
-        let generic_signature = parse_attributes(pool, buf);
-        RecordComponent { name, descriptor, generic_signature }
+        let (generic_signature, attribute_order, attributes) = parse_attributes(pool, buf, registry);
+        RecordComponent {
+            name,
+            name_index,
+            descriptor,
+            descriptor_index,
+            generic_signature_index: generic_signature.as_ref().map_or(0, |value| value.0),
+            generic_signature: generic_signature.map(|value| value.1),
+            attribute_order,
+            attributes
+        }
+    }
+
+    pub(crate) fn write(&self, pool: &ConstantPool, buf: &mut BytesMut) {
+        buf.put_u16(self.name_index);
+        buf.put_u16(self.descriptor_index);
+        write_attributes(buf, pool, &self.attribute_order, &self.attributes, |buf, name_index, name| {
+            if name == JVM_ATTRIBUTE_SIGNATURE && self.generic_signature.is_some() {
+                write_attribute(buf, name_index, |buf| buf.put_u16(self.generic_signature_index));
+                return true;
+            }
+            false
+        });
+    }
+
+    pub fn descriptor_index(&self) -> u16 {
+        self.descriptor_index
     }
 }
 
-fn parse_attributes(pool: &ConstantPool, buf: &mut Bytes) -> Option<IStr> {
+fn parse_attributes(
+    pool: &ConstantPool,
+    buf: &mut Bytes,
+    registry: &AttributeRegistry
+) -> (Option<(u16, IStr)>, Vec<u16>, Vec<Attribute>) {
     let mut generic_signature = None;
+    let mut attribute_order = Vec::new();
+    let mut attributes = Vec::new();
 
     let mut attribute_count = buf.get_u16();
     while attribute_count > 0 {
         assert!(buf.len() >= 6, "Truncated record component attributes!");
-        let attribute_name = pool.get_utf8(buf.get_u16() as usize).unwrap();
+        let name_index = buf.get_u16();
+        let attribute_name = pool.get_utf8(name_index as usize).unwrap();
         let attribute_length = buf.get_u32();
+        attribute_order.push(name_index);
 
         if attribute_name == JVM_ATTRIBUTE_SIGNATURE {
             assert!(generic_signature.is_none(), "Duplicate generic signature attribute found for \
                 record component!");
-            generic_signature = parse_generic_signature(pool, buf, attribute_length, "record component");
-        } else {
-            // Skip past any attribute that we don't recognise
-            buf.advance(attribute_length as usize);
+            generic_signature = Some(parse_generic_signature(pool, buf, attribute_length, "record component"));
+        } else if let Some(attribute) = registry.read(pool, attribute_name, buf, attribute_length) {
+            attributes.push(attribute);
         }
         attribute_count -= 1;
     }
-    generic_signature
+    (generic_signature, attribute_order, attributes)
 }
@@ -21,3 +21,7 @@ pub const JAVA_LANG_SYSTEM: &str = "java/lang/System";
 pub const JAVA_LANG_CLASSLOADER: &str = "java/lang/ClassLoader";
 pub const JAVA_LANG_THROWABLE_NAME: &str = "java/lang/Throwable";
 pub const JAVA_LANG_THREAD_NAME: &str = "java/lang/Thread";
+pub const JAVA_LANG_CLONEABLE_NAME: &str = "java/lang/Cloneable";
+pub const JAVA_IO_SERIALIZABLE_NAME: &str = "java/io/Serializable";
+pub const JAVA_LANG_INVOKE_METHOD_TYPE_NAME: &str = "java/lang/invoke/MethodType";
+pub const JAVA_LANG_INVOKE_METHOD_HANDLE_NAME: &str = "java/lang/invoke/MethodHandle";
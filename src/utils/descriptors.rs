@@ -15,7 +15,6 @@
  */
 
 use std::fmt::{Display, Formatter};
-use internship::IStr;
 use nom::IResult;
 use nom::branch::alt;
 use nom::bytes::streaming::is_not;
@@ -23,6 +22,8 @@ use nom::character::streaming::{anychar, char};
 use nom::combinator::{complete, fail, map};
 use nom::multi::{fold_many_m_n, many0};
 use nom::sequence::{delimited, pair, terminated};
+use crate::utils::IStr;
+use crate::constants::*;
 
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub struct FieldDescriptor {
@@ -46,6 +47,55 @@ impl FieldDescriptor {
     pub fn array_dimensions(&self) -> u8 {
         self.array_dimensions
     }
+
+    pub fn is_reference(&self) -> bool {
+        self.array_dimensions > 0 || matches!(self.base, FieldType::Reference(_))
+    }
+
+    // The number of local variable or operand stack slots that values of this type take up.
+    pub fn slot_size(&self) -> usize {
+        match self.base {
+            FieldType::Long | FieldType::Double if self.array_dimensions == 0 => 2,
+            _ => 1
+        }
+    }
+
+    // The descriptor in the form it appears in class files, such as [Ljava/lang/String;
+    pub fn descriptor_string(&self) -> String {
+        let mut result = String::from(JVM_SIGNATURE_ARRAY).repeat(self.array_dimensions as usize);
+        match &self.base {
+            FieldType::Byte => result.push(JVM_SIGNATURE_BYTE),
+            FieldType::Char => result.push(JVM_SIGNATURE_CHAR),
+            FieldType::Double => result.push(JVM_SIGNATURE_DOUBLE),
+            FieldType::Float => result.push(JVM_SIGNATURE_FLOAT),
+            FieldType::Int => result.push(JVM_SIGNATURE_INT),
+            FieldType::Long => result.push(JVM_SIGNATURE_LONG),
+            FieldType::Short => result.push(JVM_SIGNATURE_SHORT),
+            FieldType::Boolean => result.push(JVM_SIGNATURE_BOOLEAN),
+            FieldType::Reference(name) => {
+                result.push(JVM_SIGNATURE_CLASS);
+                result.push_str(name);
+                result.push(JVM_SIGNATURE_END_CLASS);
+            }
+        }
+        result
+    }
+
+    // The type in the form Java source writes it, such as java.lang.String[]
+    pub fn java_type(&self) -> String {
+        let base = match &self.base {
+            FieldType::Byte => String::from("byte"),
+            FieldType::Char => String::from("char"),
+            FieldType::Double => String::from("double"),
+            FieldType::Float => String::from("float"),
+            FieldType::Int => String::from("int"),
+            FieldType::Long => String::from("long"),
+            FieldType::Short => String::from("short"),
+            FieldType::Boolean => String::from("boolean"),
+            FieldType::Reference(name) => name.replace(JVM_SIGNATURE_SEPARATOR, ".")
+        };
+        base + &"[]".repeat(self.array_dimensions as usize)
+    }
 }
 
 impl From<FieldType> for FieldDescriptor {
@@ -133,6 +183,27 @@ impl From<MethodDescriptor> for Descriptor {
     }
 }
 
+// The class name for an array component descriptor, like java/lang/String for Ljava/lang/String; or [I
+// for [I. Primitive components don't have a class name.
+pub fn class_name_of(descriptor: &str) -> Option<&str> {
+    if descriptor.starts_with(JVM_SIGNATURE_ARRAY) {
+        Some(descriptor)
+    } else if descriptor.starts_with(JVM_SIGNATURE_CLASS) && descriptor.ends_with(JVM_SIGNATURE_END_CLASS) {
+        Some(&descriptor[1..descriptor.len() - 1])
+    } else {
+        None
+    }
+}
+
+// The array type with the given class as its elements, like [Ljava/lang/String; for java/lang/String.
+pub fn array_of(name: &str) -> String {
+    if name.starts_with(JVM_SIGNATURE_ARRAY) {
+        format!("{}{}", JVM_SIGNATURE_ARRAY, name)
+    } else {
+        format!("{}{}{}{}", JVM_SIGNATURE_ARRAY, JVM_SIGNATURE_CLASS, name, JVM_SIGNATURE_END_CLASS)
+    }
+}
+
 // The following parser functions are all from here:
 // https://github.com/powerboat9/java-desc/blob/1b4e15fd9014962c9704ab69386c12898b44562b/src/lib.rs
 
@@ -144,7 +215,7 @@ fn parse_type(input: &str) -> IResult<&str, FieldType> {
     let (input, char) = anychar(input)?;
     match char {
         'B' => Ok((input, FieldType::Byte)),
-        'C' => Ok((input, FieldType::Byte)),
+        'C' => Ok((input, FieldType::Char)),
         'D' => Ok((input, FieldType::Double)),
         'F' => Ok((input, FieldType::Float)),
         'I' => Ok((input, FieldType::Int)),
@@ -179,7 +250,7 @@ fn parse_method(input: &str) -> IResult<&str, MethodDescriptor> {
 
 #[cfg(test)]
 mod tests {
-    use internship::IStr;
+    use crate::utils::IStr;
     use super::{FieldDescriptor, FieldType, MethodDescriptor};
 
     #[test]
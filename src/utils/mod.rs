@@ -16,9 +16,11 @@
 
 pub mod constants;
 mod extras;
+mod istr;
 mod lateinit;
 pub mod descriptors;
 
 pub use extras::BufferExtras;
 pub use extras::IdentEq;
+pub use istr::IStr;
 pub use lateinit::LateInit;
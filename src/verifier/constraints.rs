@@ -0,0 +1,248 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+
+use std::collections::HashSet;
+use crate::class_file::bytecode::{Instruction, InstructionDecoder, ValueKind};
+use crate::class_file::code::CodeBlock;
+use crate::constants::*;
+use crate::types::{Class, Method};
+use crate::types::constant_pool::{CLASS_TAG, DOUBLE_TAG, DYNAMIC_TAG, FIELD_REF_TAG, FLOAT_TAG, INT_TAG,
+    INTERFACE_METHOD_REF_TAG, INVOKE_DYNAMIC_TAG, LONG_TAG, METHOD_HANDLE_TAG, METHOD_REF_TAG, METHOD_TYPE_TAG,
+    STRING_TAG};
+use crate::utils::descriptors::FieldType;
+use super::{ClassFormatError, LinkError, VerifyError};
+
+const MAX_CODE_LENGTH: usize = 65535;
+
+// Checks the static constraints on the code of every method from JVMS §4.9.1, which don't need any
+// knowledge of types. Problems with the tables in the Code attribute are class format errors, like they
+// are in HotSpot, and problems with the instructions themselves are verify errors.
+pub fn check_constraints(class: &Class) -> Result<(), LinkError> {
+    for method in class.methods() {
+        if let Some(code) = method.code() {
+            check_method(class, method, code)?;
+        }
+    }
+    Ok(())
+}
+
+fn check_method(class: &Class, method: &Method, code: &CodeBlock) -> Result<(), LinkError> {
+    let format_error = |message: String| LinkError::from(ClassFormatError::new(class, Some(method), message));
+    let verify_error = |offset: u32, message: String| {
+        LinkError::from(VerifyError::new(class, method, Some(offset), message))
+    };
+
+    let length = code.code().len();
+    if length == 0 || length > MAX_CODE_LENGTH {
+        return Err(format_error(format!("Invalid code length {}, expected between 1 and {}", length,
+                                        MAX_CODE_LENGTH)));
+    }
+    let instructions = InstructionDecoder::decode_all(code.code())
+        .map_err(|value| verify_error(value.offset(), value.message().to_string()))?;
+    let starts = instructions.iter().map(|value| value.offset()).collect::<HashSet<_>>();
+
+    for instruction in &instructions {
+        let offset = instruction.offset();
+        for target in instruction.instruction().branch_targets() {
+            if !starts.contains(&target) {
+                return Err(verify_error(offset, format!("Illegal target of jump or branch {}", target)));
+            }
+        }
+        check_operands(class, code, instruction.opcode(), instruction.instruction())
+            .map_err(|value| verify_error(offset, value))?;
+    }
+    let last = instructions.last().unwrap();
+    if !last.instruction().is_unconditional() {
+        return Err(verify_error(last.offset(), String::from("Falling off the end of the code")));
+    }
+
+    let is_boundary = |pc: u32| starts.contains(&pc);
+    let is_end = |pc: u32| pc as usize == length || starts.contains(&pc);
+    for handler in code.exception_handlers().iter() {
+        let (start, end, handler_pc) = (handler.start_pc() as u32, handler.end_pc() as u32,
+                                        handler.handler_pc() as u32);
+        if start >= end || !is_boundary(start) || !is_end(end) {
+            return Err(format_error(format!("Illegal exception table range {} to {}", start, end)));
+        }
+        if !is_boundary(handler_pc) {
+            return Err(format_error(format!("Illegal exception table handler {}", handler_pc)));
+        }
+        let catch_type = handler.catch_type_index();
+        if catch_type != 0 && class.constant_pool().get_tag(catch_type as usize) != Some(CLASS_TAG) {
+            return Err(format_error(format!("Catch type in exception table has bad constant type {}", catch_type)));
+        }
+    }
+    for line in code.line_numbers().iter().flat_map(|table| table.iter()) {
+        if line.start_pc() as usize >= length {
+            return Err(format_error(format!("Invalid pc {} in LineNumberTable", line.start_pc())));
+        }
+    }
+    let max_locals = code.max_locals() as u32;
+    for local in code.local_variables().iter().flat_map(|table| table.iter()) {
+        let size = if is_wide(local.descriptor().base()) && local.descriptor().array_dimensions() == 0 { 2 } else { 1 };
+        check_local_range(local.start_pc(), local.length(), local.index(), size, max_locals, "LocalVariableTable",
+                          &is_boundary, &is_end).map_err(format_error)?;
+    }
+    for local in code.local_variable_types().iter().flat_map(|table| table.iter()) {
+        check_local_range(local.start_pc(), local.length(), local.index(), 1, max_locals, "LocalVariableTypeTable",
+                          &is_boundary, &is_end).map_err(format_error)?;
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn check_local_range(
+    start_pc: u16,
+    length: u16,
+    index: u16,
+    size: u32,
+    max_locals: u32,
+    table: &str,
+    is_boundary: &impl Fn(u32) -> bool,
+    is_end: &impl Fn(u32) -> bool
+) -> Result<(), String> {
+    let start = start_pc as u32;
+    if !is_boundary(start) || !is_end(start + length as u32) {
+        return Err(format!("Invalid start_pc {} or length {} in {}", start_pc, length, table));
+    }
+    if index as u32 + size > max_locals {
+        return Err(format!("Invalid index {} in {}, max_locals is {}", index, table, max_locals));
+    }
+    Ok(())
+}
+
+fn is_wide(value: &FieldType) -> bool {
+    matches!(value, FieldType::Long | FieldType::Double)
+}
+
+// Checks that the constant pool entries and local variables that an instruction uses are of the right kind.
+fn check_operands(class: &Class, code: &CodeBlock, opcode: u8, instruction: &Instruction) -> Result<(), String> {
+    let pool = class.constant_pool();
+    let expect = |index: u16, tags: &[u8]| {
+        match pool.get_tag(index as usize) {
+            Some(tag) if tags.contains(&tag) => Ok(()),
+            _ => Err(format!("Illegal type at constant pool entry {}", index))
+        }
+    };
+    let check_local = |index: u16, size: u16| {
+        if index as u32 + size as u32 > code.max_locals() as u32 {
+            return Err(format!("Illegal local variable number {}", index));
+        }
+        Ok(())
+    };
+    // Interface methods can only be called with invokestatic and invokespecial from Java 8 onwards
+    let static_tags: &[u8] = if class.major_version() >= JAVA_VERSION_8 {
+        &[METHOD_REF_TAG, INTERFACE_METHOD_REF_TAG]
+    } else {
+        &[METHOD_REF_TAG]
+    };
+    match instruction {
+        Instruction::Ldc(index) if opcode == JVM_OPCODE_LDC2_W => expect(*index, &[LONG_TAG, DOUBLE_TAG, DYNAMIC_TAG]),
+        Instruction::Ldc(index) => {
+            expect(*index, &[INT_TAG, FLOAT_TAG, STRING_TAG, CLASS_TAG, METHOD_TYPE_TAG, METHOD_HANDLE_TAG,
+                DYNAMIC_TAG])
+        },
+        Instruction::Load(kind, index) | Instruction::Store(kind, index) => {
+            check_local(*index, if matches!(kind, ValueKind::Long | ValueKind::Double) { 2 } else { 1 })
+        },
+        Instruction::IInc(index, _) | Instruction::Ret(index) => check_local(*index, 1),
+        Instruction::GetStatic(index) | Instruction::PutStatic(index) | Instruction::GetField(index) |
+        Instruction::PutField(index) => expect(*index, &[FIELD_REF_TAG]),
+        Instruction::InvokeVirtual(index) => expect(*index, &[METHOD_REF_TAG]),
+        Instruction::InvokeSpecial(index) | Instruction::InvokeStatic(index) => expect(*index, static_tags),
+        Instruction::InvokeInterface(index, _) => expect(*index, &[INTERFACE_METHOD_REF_TAG]),
+        Instruction::InvokeDynamic(index) => expect(*index, &[INVOKE_DYNAMIC_TAG]),
+        Instruction::New(index) | Instruction::ANewArray(index) | Instruction::CheckCast(index) |
+        Instruction::InstanceOf(index) | Instruction::MultiANewArray(index, _) => expect(*index, &[CLASS_TAG]),
+        _ => Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use std::fs;
+    use std::path::Path;
+    use std::sync::Arc;
+    use crate::assembler::assemble;
+    use crate::class_file::ClassLoader;
+    use crate::constants::*;
+    use crate::types::Class;
+    use crate::verifier::LinkError;
+    use super::check_constraints;
+
+    // Assembles the method, then overwrites the start of its code with the given bytes, for code that the
+    // assembler won't write.
+    fn check_patched(body: &str, patch: &[u8]) -> Result<(), LinkError> {
+        let source = format!(".class Broken\n.method public static f()V\n.limit locals 1\n{}.end method\n", body);
+        let assembled = assemble(&source).unwrap();
+        let class = Class::from_bytes(Arc::new(ClassLoader::new()), Bytes::clone(&assembled));
+        let original = class.methods()[0].code().unwrap().code().to_vec();
+        let mut bytes = assembled.to_vec();
+        let start = bytes.windows(original.len()).position(|window| window == original.as_slice()).unwrap();
+        bytes[start..start + patch.len()].copy_from_slice(patch);
+        check_constraints(&Class::from_bytes(Arc::new(ClassLoader::new()), Bytes::from(bytes)))
+    }
+
+    fn verify_offset(result: Result<(), LinkError>) -> Option<u32> {
+        match result {
+            Err(LinkError::Verify(error)) => error.offset(),
+            other => panic!("Expected a verify error, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn accepts_compiled_classes() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let paths = ["Hello.class", "Main.class"].iter().map(|name| root.join(name))
+            .chain(fs::read_dir(root.join("fixtures")).unwrap().map(|entry| entry.unwrap().path()))
+            .filter(|path| path.extension().map_or(false, |value| value == "class"));
+        for path in paths {
+            let class = Class::from_bytes(Arc::new(ClassLoader::new()), Bytes::from(fs::read(&path).unwrap()));
+            let result = check_constraints(&class);
+            assert!(result.is_ok(), "{}: {}", path.display(), result.unwrap_err());
+        }
+    }
+
+    #[test]
+    fn rejects_bad_instructions() {
+        // goto 1 lands in the middle of the goto
+        let result = check_patched("    goto End\nEnd:\n    return\n", &[JVM_OPCODE_GOTO, 0, 1]);
+        assert_eq!(verify_offset(result), Some(0));
+
+        let result = check_patched("    nop\n    return\n", &[JVM_OPCODE_NOP, JVM_OPCODE_NOP]);
+        assert_eq!(verify_offset(result), Some(1));
+
+        let patch = [JVM_OPCODE_ICONST_0, JVM_OPCODE_ISTORE_1];
+        let result = check_patched("    iconst_0\n    istore_0\n    return\n", &patch);
+        assert_eq!(verify_offset(result), Some(1));
+
+        // The first constant in the pool is the class name, not a field
+        let patch = [JVM_OPCODE_GETSTATIC, 0, 1];
+        let result = check_patched("    getstatic Broken/value I\n    pop\n    return\n", &patch);
+        assert_eq!(verify_offset(result), Some(0));
+    }
+
+    #[test]
+    fn rejects_bad_tables() {
+        // Turning the first two instructions into a sipush leaves the end of the range inside it
+        let body = "Start:\n    iconst_0\n    pop\nEnd:\n    return\nHandler:\n    pop\n    return\n\
+            .catch all from Start to End using Handler\n";
+        let result = check_patched(body, &[JVM_OPCODE_SIPUSH, 0, 0]);
+        assert!(matches!(result, Err(LinkError::ClassFormat(_))), "{:?}", result);
+    }
+}
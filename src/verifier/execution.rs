@@ -0,0 +1,608 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+
+use std::collections::HashMap;
+use crate::utils::IStr;
+use crate::class_file::bytecode::{ArithmeticOp, ArrayKind, DecodedInstruction, Instruction, ValueKind};
+use crate::constants::*;
+use crate::types::{Class, ConstantPool};
+use crate::types::constant_pool::{self, PoolConstant};
+use crate::utils::constants::*;
+use crate::utils::descriptors::{array_of, FieldDescriptor, MethodDescriptor};
+use super::ClassHierarchy;
+use super::types::{Frame, VerifierType, is_assignable, is_class_assignable};
+
+// Everything about the method being verified that the per-instruction rules need to know. This is shared
+// between the type checker and the type inference verifier, which only differ in where frames come from.
+pub(super) struct MethodContext<'a> {
+    class: &'a Class,
+    pool: &'a ConstantPool,
+    hierarchy: &'a dyn ClassHierarchy,
+    max_stack: usize,
+    return_type: Option<VerifierType>,
+    is_constructor: bool,
+    // The classes created by each new instruction, keyed by offset, for resolving uninitialized types
+    new_types: HashMap<u32, IStr>
+}
+
+impl<'a> MethodContext<'a> {
+    pub(super) fn new(
+        class: &'a Class,
+        hierarchy: &'a dyn ClassHierarchy,
+        max_stack: usize,
+        descriptor: &MethodDescriptor,
+        is_constructor: bool,
+        instructions: &[DecodedInstruction]
+    ) -> Self {
+        let pool = class.constant_pool();
+        let new_types = instructions.iter()
+            .filter_map(|instruction| match instruction.instruction() {
+                Instruction::New(index) => pool.get_class_name(*index as usize)
+                    .map(|name| (instruction.offset(), name)),
+                _ => None
+            })
+            .collect();
+        MethodContext {
+            class,
+            pool,
+            hierarchy,
+            max_stack,
+            return_type: descriptor.return_type().map(VerifierType::from_descriptor),
+            is_constructor,
+            new_types
+        }
+    }
+
+    pub(super) fn hierarchy(&self) -> &dyn ClassHierarchy {
+        self.hierarchy
+    }
+
+    pub(super) fn is_new_offset(&self, offset: u32) -> bool {
+        self.new_types.contains_key(&offset)
+    }
+
+    // The type that a catch type index in an exception table refers to, with 0 meaning any throwable.
+    pub(super) fn catch_type(&self, index: u16) -> Result<VerifierType, String> {
+        if index == 0 {
+            return Ok(VerifierType::reference(JAVA_LANG_THROWABLE_NAME));
+        }
+        let name = self.class_name(index)?;
+        if !is_class_assignable(self.hierarchy, &name, JAVA_LANG_THROWABLE_NAME) {
+            return Err(format!("Catch type {} is not a subclass of {}", name, JAVA_LANG_THROWABLE_NAME));
+        }
+        Ok(VerifierType::Reference(name))
+    }
+
+    pub(super) fn class_name(&self, index: u16) -> Result<IStr, String> {
+        self.pool.get_class_name(index as usize)
+            .ok_or_else(|| format!("Constant pool index {} is not a class", index))
+    }
+
+    // Applies the typing rules for a single instruction to the frame, leaving the frame as it is after the
+    // instruction has executed. Subroutines aren't handled here, since the two verifiers treat them
+    // differently.
+    pub(super) fn execute(&self, frame: &mut Frame, decoded: &DecodedInstruction) -> Result<(), String> {
+        match decoded.instruction() {
+            Instruction::Nop => {},
+            Instruction::AConstNull => self.push(frame, VerifierType::Null)?,
+            Instruction::IConst(_) => self.push(frame, VerifierType::Integer)?,
+            Instruction::LConst(_) => self.push(frame, VerifierType::Long)?,
+            Instruction::FConst(_) => self.push(frame, VerifierType::Float)?,
+            Instruction::DConst(_) => self.push(frame, VerifierType::Double)?,
+            Instruction::Ldc(index) => {
+                let value = self.constant_type(*index)?;
+                if (decoded.opcode() == JVM_OPCODE_LDC2_W) != (value.size() == 2) {
+                    return Err(format!("Invalid constant type {} for {}", value, decoded.name()));
+                }
+                self.push(frame, value)?;
+            },
+            Instruction::Load(kind, index) => {
+                let value = frame.get_local(*index as usize, kind_size(*kind))?.clone();
+                let matches = match kind {
+                    ValueKind::Reference => value.is_reference(),
+                    _ => value == kind_type(*kind)
+                };
+                if !matches {
+                    return Err(format!("Bad local variable type, expected {} in local {} but found {}",
+                                       kind_name(*kind), index, value));
+                }
+                self.push(frame, value)?;
+            },
+            Instruction::Store(kind, index) => {
+                let value = frame.pop()?;
+                let matches = match kind {
+                    ValueKind::Reference => {
+                        value.is_reference() || matches!(value, VerifierType::ReturnAddress(_))
+                    },
+                    _ => value == kind_type(*kind)
+                };
+                if !matches {
+                    return Err(format!("Bad type on operand stack, expected {} but found {}",
+                                       kind_name(*kind), value));
+                }
+                frame.set_local(*index as usize, value)?;
+            },
+            Instruction::ArrayLoad(kind) => {
+                pop_expecting(frame, self.hierarchy, &VerifierType::Integer)?;
+                let array = frame.pop()?;
+                check_array(&array, *kind)?;
+                let value = match kind {
+                    ArrayKind::Reference => array.component_type().unwrap_or(VerifierType::Null),
+                    ArrayKind::Long => VerifierType::Long,
+                    ArrayKind::Float => VerifierType::Float,
+                    ArrayKind::Double => VerifierType::Double,
+                    _ => VerifierType::Integer
+                };
+                self.push(frame, value)?;
+            },
+            Instruction::ArrayStore(kind) => {
+                let value = frame.pop()?;
+                let expected = match kind {
+                    ArrayKind::Long => VerifierType::Long,
+                    ArrayKind::Float => VerifierType::Float,
+                    ArrayKind::Double => VerifierType::Double,
+                    ArrayKind::Reference => VerifierType::reference(JAVA_LANG_OBJECT_NAME),
+                    _ => VerifierType::Integer
+                };
+                // Whether a reference can be stored in the array is checked when the code runs
+                if !is_assignable(self.hierarchy, &value, &expected) {
+                    return Err(format!("Bad type on operand stack, expected {} but found {}", expected, value));
+                }
+                pop_expecting(frame, self.hierarchy, &VerifierType::Integer)?;
+                let array = frame.pop()?;
+                check_array(&array, *kind)?;
+            },
+            Instruction::Pop => {
+                frame.pop_slots(1)?;
+            },
+            Instruction::Pop2 => {
+                frame.pop_slots(2)?;
+            },
+            Instruction::Dup => {
+                let value = frame.pop_slots(1)?;
+                self.push_all(frame, &[&value, &value])?;
+            },
+            Instruction::DupX1 => {
+                let first = frame.pop_slots(1)?;
+                let second = frame.pop_slots(1)?;
+                self.push_all(frame, &[&first, &second, &first])?;
+            },
+            Instruction::DupX2 => {
+                let first = frame.pop_slots(1)?;
+                let second = frame.pop_slots(2)?;
+                self.push_all(frame, &[&first, &second, &first])?;
+            },
+            Instruction::Dup2 => {
+                let value = frame.pop_slots(2)?;
+                self.push_all(frame, &[&value, &value])?;
+            },
+            Instruction::Dup2X1 => {
+                let first = frame.pop_slots(2)?;
+                let second = frame.pop_slots(1)?;
+                self.push_all(frame, &[&first, &second, &first])?;
+            },
+            Instruction::Dup2X2 => {
+                let first = frame.pop_slots(2)?;
+                let second = frame.pop_slots(2)?;
+                self.push_all(frame, &[&first, &second, &first])?;
+            },
+            Instruction::Swap => {
+                let first = frame.pop_slots(1)?;
+                let second = frame.pop_slots(1)?;
+                self.push_all(frame, &[&first, &second])?;
+            },
+            Instruction::Arithmetic(kind, op) => {
+                let value = kind_type(*kind);
+                match op {
+                    ArithmeticOp::Neg => {
+                        pop_expecting(frame, self.hierarchy, &value)?;
+                    },
+                    ArithmeticOp::Shl | ArithmeticOp::Shr | ArithmeticOp::UShr => {
+                        pop_expecting(frame, self.hierarchy, &VerifierType::Integer)?;
+                        pop_expecting(frame, self.hierarchy, &value)?;
+                    },
+                    _ => {
+                        pop_expecting(frame, self.hierarchy, &value)?;
+                        pop_expecting(frame, self.hierarchy, &value)?;
+                    }
+                }
+                self.push(frame, value)?;
+            },
+            Instruction::IInc(index, _) => {
+                let value = frame.get_local(*index as usize, 1)?;
+                if *value != VerifierType::Integer {
+                    return Err(format!("Bad local variable type, expected int in local {} but found {}",
+                                       index, value));
+                }
+            },
+            Instruction::Convert(from, to) => {
+                pop_expecting(frame, self.hierarchy, &kind_type(*from))?;
+                self.push(frame, kind_type(*to))?;
+            },
+            Instruction::IntToByte | Instruction::IntToChar | Instruction::IntToShort => {
+                pop_expecting(frame, self.hierarchy, &VerifierType::Integer)?;
+                self.push(frame, VerifierType::Integer)?;
+            },
+            Instruction::LCmp => self.compare(frame, VerifierType::Long)?,
+            Instruction::FCmpL | Instruction::FCmpG => self.compare(frame, VerifierType::Float)?,
+            Instruction::DCmpL | Instruction::DCmpG => self.compare(frame, VerifierType::Double)?,
+            Instruction::If(_, _) | Instruction::TableSwitch { .. } | Instruction::LookupSwitch { .. } => {
+                pop_expecting(frame, self.hierarchy, &VerifierType::Integer)?;
+            },
+            Instruction::IfICmp(_, _) => {
+                pop_expecting(frame, self.hierarchy, &VerifierType::Integer)?;
+                pop_expecting(frame, self.hierarchy, &VerifierType::Integer)?;
+            },
+            Instruction::IfACmp(_, _) => {
+                pop_reference(frame)?;
+                pop_reference(frame)?;
+            },
+            Instruction::IfNull(_) | Instruction::IfNonNull(_) => {
+                pop_reference(frame)?;
+            },
+            Instruction::Goto(_) => {},
+            Instruction::Jsr(_) | Instruction::Ret(_) => {
+                return Err(format!("{} is not allowed in class files with stack map frames", decoded.name()));
+            },
+            Instruction::Return(kind) => self.method_return(frame, *kind)?,
+            Instruction::GetStatic(index) => {
+                let (_, _, descriptor) = self.field_ref(*index)?;
+                self.push(frame, VerifierType::from_descriptor(&descriptor))?;
+            },
+            Instruction::PutStatic(index) => {
+                let (_, _, descriptor) = self.field_ref(*index)?;
+                pop_expecting(frame, self.hierarchy, &VerifierType::from_descriptor(&descriptor))?;
+            },
+            Instruction::GetField(index) => {
+                let (class_name, _, descriptor) = self.field_ref(*index)?;
+                pop_expecting(frame, self.hierarchy, &VerifierType::Reference(class_name))?;
+                self.push(frame, VerifierType::from_descriptor(&descriptor))?;
+            },
+            Instruction::PutField(index) => {
+                let (class_name, _, descriptor) = self.field_ref(*index)?;
+                pop_expecting(frame, self.hierarchy, &VerifierType::from_descriptor(&descriptor))?;
+                // Constructors are allowed to set their own fields before calling the super constructor
+                let object = frame.pop()?;
+                let expected = VerifierType::Reference(class_name);
+                let allowed = match &object {
+                    VerifierType::UninitializedThis => expected == self.this_type(),
+                    _ => is_assignable(self.hierarchy, &object, &expected)
+                };
+                if !allowed {
+                    return Err(format!("Bad type on operand stack, expected {} but found {}", expected, object));
+                }
+            },
+            Instruction::InvokeVirtual(index) | Instruction::InvokeStatic(index) |
+            Instruction::InvokeSpecial(index) | Instruction::InvokeInterface(index, _) => {
+                self.invoke(frame, decoded, *index)?;
+            },
+            Instruction::InvokeDynamic(index) => {
+                let (name, descriptor) = match self.pool.get_constant(*index as usize) {
+                    Some(PoolConstant::InvokeDynamic { nat_index, .. }) => self.name_and_type(*nat_index)?,
+                    _ => return Err(format!("Constant pool index {} is not an invoke dynamic constant", index))
+                };
+                let descriptor = MethodDescriptor::parse(&descriptor)
+                    .ok_or_else(|| format!("Invalid method descriptor {} for {}", descriptor, name))?;
+                self.pop_arguments(frame, &descriptor)?;
+                if let Some(value) = descriptor.return_type() {
+                    self.push(frame, VerifierType::from_descriptor(value))?;
+                }
+            },
+            Instruction::New(index) => {
+                let name = self.class_name(*index)?;
+                if name.starts_with(JVM_SIGNATURE_ARRAY) {
+                    return Err(format!("Illegal use of new with array type {}", name));
+                }
+                // Anything left over from a previous run of this new instruction is now unusable
+                let value = VerifierType::Uninitialized(decoded.offset());
+                frame.initialize(&value, &VerifierType::Top);
+                self.push(frame, value)?;
+            },
+            Instruction::NewArray(basic_type) => {
+                pop_expecting(frame, self.hierarchy, &VerifierType::Integer)?;
+                let element = match basic_type {
+                    BasicType::Boolean => JVM_SIGNATURE_BOOLEAN,
+                    BasicType::Char => JVM_SIGNATURE_CHAR,
+                    BasicType::Float => JVM_SIGNATURE_FLOAT,
+                    BasicType::Double => JVM_SIGNATURE_DOUBLE,
+                    BasicType::Byte => JVM_SIGNATURE_BYTE,
+                    BasicType::Short => JVM_SIGNATURE_SHORT,
+                    BasicType::Int => JVM_SIGNATURE_INT,
+                    BasicType::Long => JVM_SIGNATURE_LONG,
+                    _ => return Err(format!("Invalid array type {:?} for newarray", basic_type))
+                };
+                self.push(frame, VerifierType::reference(&format!("{}{}", JVM_SIGNATURE_ARRAY, element)))?;
+            },
+            Instruction::ANewArray(index) => {
+                pop_expecting(frame, self.hierarchy, &VerifierType::Integer)?;
+                let name = self.class_name(*index)?;
+                self.push(frame, VerifierType::reference(&array_of(&name)))?;
+            },
+            Instruction::MultiANewArray(index, dimensions) => {
+                let name = self.class_name(*index)?;
+                if name.chars().take_while(|value| *value == JVM_SIGNATURE_ARRAY).count() < *dimensions as usize {
+                    return Err(format!("Array type {} has fewer than {} dimensions", name, dimensions));
+                }
+                for _ in 0..*dimensions {
+                    pop_expecting(frame, self.hierarchy, &VerifierType::Integer)?;
+                }
+                self.push(frame, VerifierType::Reference(name))?;
+            },
+            Instruction::ArrayLength => {
+                let array = frame.pop()?;
+                if array != VerifierType::Null && !array.is_array() {
+                    return Err(format!("Bad type on operand stack, expected an array but found {}", array));
+                }
+                self.push(frame, VerifierType::Integer)?;
+            },
+            Instruction::AThrow => {
+                pop_expecting(frame, self.hierarchy, &VerifierType::reference(JAVA_LANG_THROWABLE_NAME))?;
+            },
+            Instruction::CheckCast(index) => {
+                let name = self.class_name(*index)?;
+                pop_reference(frame)?;
+                self.push(frame, VerifierType::Reference(name))?;
+            },
+            Instruction::InstanceOf(index) => {
+                self.class_name(*index)?;
+                pop_reference(frame)?;
+                self.push(frame, VerifierType::Integer)?;
+            },
+            Instruction::MonitorEnter | Instruction::MonitorExit => {
+                pop_reference(frame)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn push(&self, frame: &mut Frame, value: VerifierType) -> Result<(), String> {
+        frame.push(value, self.max_stack)
+    }
+
+    fn push_all(&self, frame: &mut Frame, values: &[&Vec<VerifierType>]) -> Result<(), String> {
+        for value in values.iter().flat_map(|values| values.iter()) {
+            self.push(frame, value.clone())?;
+        }
+        Ok(())
+    }
+
+    fn compare(&self, frame: &mut Frame, value: VerifierType) -> Result<(), String> {
+        pop_expecting(frame, self.hierarchy, &value)?;
+        pop_expecting(frame, self.hierarchy, &value)?;
+        self.push(frame, VerifierType::Integer)
+    }
+
+    fn this_type(&self) -> VerifierType {
+        VerifierType::reference(self.class.name())
+    }
+
+    fn method_return(&self, frame: &mut Frame, kind: Option<ValueKind>) -> Result<(), String> {
+        match (kind, &self.return_type) {
+            (None, None) => {},
+            (Some(kind), Some(expected)) => {
+                let value = frame.pop()?;
+                let matches = match kind {
+                    ValueKind::Reference => expected.is_reference() && is_assignable(self.hierarchy, &value, expected),
+                    _ => kind_type(kind) == *expected && value == *expected
+                };
+                if !matches {
+                    return Err(format!("Bad return type, expected {} but found {}", expected, value));
+                }
+            },
+            (_, Some(expected)) => return Err(format!("Bad return type, expected {} but found void", expected)),
+            (Some(kind), None) => return Err(format!("Bad return type, expected void but found {}", kind_name(kind)))
+        }
+        if self.is_constructor && frame.is_this_uninitialized() {
+            return Err(String::from("Constructor must call super() or this() before return"));
+        }
+        Ok(())
+    }
+
+    fn invoke(&self, frame: &mut Frame, decoded: &DecodedInstruction, index: u16) -> Result<(), String> {
+        let (class_name, name, descriptor) = self.member_ref(index)?;
+        let descriptor = MethodDescriptor::parse(&descriptor)
+            .ok_or_else(|| format!("Invalid method descriptor {} for {}", descriptor, name))?;
+        let is_initializer = name == JVM_OBJECT_INITIALIZER_NAME;
+        let instruction = decoded.instruction();
+        if name == JVM_CLASS_INITIALIZER_NAME ||
+            (is_initializer && !matches!(instruction, Instruction::InvokeSpecial(_))) {
+            return Err(format!("Illegal call to {} with {}", name, decoded.name()));
+        }
+        if let Instruction::InvokeInterface(_, count) = instruction {
+            let slots = descriptor.parameters().iter()
+                .map(|value| VerifierType::from_descriptor(value).size())
+                .sum::<usize>() + 1;
+            if *count as usize != slots {
+                return Err(format!("Inconsistent args count operand {} for invokeinterface, expected {}", count,
+                                   slots));
+            }
+        }
+        self.pop_arguments(frame, &descriptor)?;
+        if matches!(instruction, Instruction::InvokeStatic(_)) {
+            // Nothing to do, there's no receiver
+        } else if is_initializer {
+            if descriptor.return_type().is_some() {
+                return Err(format!("{} must return void", JVM_OBJECT_INITIALIZER_NAME));
+            }
+            let receiver = frame.pop()?;
+            let initialized = match &receiver {
+                VerifierType::UninitializedThis => {
+                    let super_class = self.class.super_class_name();
+                    if class_name != self.class.name() && super_class.as_deref() != Some(class_name.as_str()) {
+                        return Err(format!("Bad {} call to {}, expected this class or its superclass",
+                                           JVM_OBJECT_INITIALIZER_NAME, class_name));
+                    }
+                    self.this_type()
+                },
+                VerifierType::Uninitialized(offset) => {
+                    let created = self.new_types.get(offset)
+                        .ok_or_else(|| format!("Uninitialized type at offset {} isn't from a new instruction",
+                                               offset))?;
+                    if *created != class_name {
+                        return Err(format!("Bad {} call, expected {} but found {}", JVM_OBJECT_INITIALIZER_NAME,
+                                           created, class_name));
+                    }
+                    VerifierType::Reference(class_name)
+                },
+                _ => return Err(format!("Bad type on operand stack, expected an uninitialized object but found {}",
+                                        receiver))
+            };
+            frame.initialize(&receiver, &initialized);
+        } else {
+            let receiver = frame.pop()?;
+            let expected = VerifierType::Reference(class_name);
+            if !receiver.is_reference() || receiver.is_uninitialized() ||
+                !is_assignable(self.hierarchy, &receiver, &expected) {
+                return Err(format!("Bad type on operand stack, expected {} but found {}", expected, receiver));
+            }
+        }
+        if let Some(value) = descriptor.return_type() {
+            self.push(frame, VerifierType::from_descriptor(value))?;
+        }
+        Ok(())
+    }
+
+    fn pop_arguments(&self, frame: &mut Frame, descriptor: &MethodDescriptor) -> Result<(), String> {
+        for parameter in descriptor.parameters().iter().rev() {
+            pop_expecting(frame, self.hierarchy, &VerifierType::from_descriptor(parameter))?;
+        }
+        Ok(())
+    }
+
+    fn constant_type(&self, index: u16) -> Result<VerifierType, String> {
+        let result = match self.pool.get_tag(index as usize) {
+            Some(constant_pool::INT_TAG) => VerifierType::Integer,
+            Some(constant_pool::FLOAT_TAG) => VerifierType::Float,
+            Some(constant_pool::LONG_TAG) => VerifierType::Long,
+            Some(constant_pool::DOUBLE_TAG) => VerifierType::Double,
+            Some(constant_pool::STRING_TAG) => VerifierType::reference(JAVA_LANG_STRING_NAME),
+            Some(constant_pool::CLASS_TAG) => VerifierType::reference(JAVA_LANG_CLASS_NAME),
+            Some(constant_pool::METHOD_TYPE_TAG) => VerifierType::reference(JAVA_LANG_INVOKE_METHOD_TYPE_NAME),
+            Some(constant_pool::METHOD_HANDLE_TAG) => VerifierType::reference(JAVA_LANG_INVOKE_METHOD_HANDLE_NAME),
+            Some(constant_pool::DYNAMIC_TAG) => {
+                let descriptor = match self.pool.get_constant(index as usize) {
+                    Some(PoolConstant::Dynamic { nat_index, .. }) => self.name_and_type(*nat_index)?.1,
+                    _ => return Err(format!("Constant pool index {} is not a dynamic constant", index))
+                };
+                FieldDescriptor::parse(&descriptor)
+                    .map(|value| VerifierType::from_descriptor(&value))
+                    .ok_or_else(|| format!("Invalid field descriptor {} for dynamic constant", descriptor))?
+            },
+            _ => return Err(format!("Constant pool index {} is not a loadable constant", index))
+        };
+        Ok(result)
+    }
+
+    fn field_ref(&self, index: u16) -> Result<(IStr, IStr, FieldDescriptor), String> {
+        if self.pool.get_tag(index as usize) != Some(constant_pool::FIELD_REF_TAG) {
+            return Err(format!("Constant pool index {} is not a field reference", index));
+        }
+        let (class_name, name, descriptor) = self.member_ref(index)?;
+        let descriptor = FieldDescriptor::parse(&descriptor)
+            .ok_or_else(|| format!("Invalid field descriptor {} for {}", descriptor, name))?;
+        Ok((class_name, name, descriptor))
+    }
+
+    // The class name, name and descriptor of a field or method reference.
+    fn member_ref(&self, index: u16) -> Result<(IStr, IStr, IStr), String> {
+        let (class_index, nat_index) = match self.pool.get_constant(index as usize) {
+            Some(PoolConstant::FieldRef { class_index, nat_index }) |
+            Some(PoolConstant::MethodRef { class_index, nat_index }) |
+            Some(PoolConstant::InterfaceMethodRef { class_index, nat_index }) => (*class_index, *nat_index),
+            _ => return Err(format!("Constant pool index {} is not a member reference", index))
+        };
+        let class_name = self.class_name(class_index)?;
+        let (name, descriptor) = self.name_and_type(nat_index)?;
+        Ok((class_name, name, descriptor))
+    }
+
+    fn name_and_type(&self, index: u16) -> Result<(IStr, IStr), String> {
+        match self.pool.get_constant(index as usize) {
+            Some(PoolConstant::NameAndType { name_index, descriptor_index }) => {
+                let name = self.pool.get_utf8(*name_index as usize);
+                let descriptor = self.pool.get_utf8(*descriptor_index as usize);
+                name.zip(descriptor).ok_or_else(|| format!("Invalid name and type at constant pool index {}", index))
+            },
+            _ => Err(format!("Constant pool index {} is not a name and type", index))
+        }
+    }
+}
+
+pub(super) fn pop_expecting(
+    frame: &mut Frame,
+    hierarchy: &dyn ClassHierarchy,
+    expected: &VerifierType
+) -> Result<VerifierType, String> {
+    let value = frame.pop()?;
+    if !is_assignable(hierarchy, &value, expected) {
+        return Err(format!("Bad type on operand stack, expected {} but found {}", expected, value));
+    }
+    Ok(value)
+}
+
+fn pop_reference(frame: &mut Frame) -> Result<VerifierType, String> {
+    let value = frame.pop()?;
+    if !value.is_reference() {
+        return Err(format!("Bad type on operand stack, expected a reference but found {}", value));
+    }
+    Ok(value)
+}
+
+fn check_array(array: &VerifierType, kind: ArrayKind) -> Result<(), String> {
+    let matches = match kind {
+        _ if *array == VerifierType::Null => true,
+        ArrayKind::Int => array.is_array_of(JVM_SIGNATURE_INT),
+        ArrayKind::Long => array.is_array_of(JVM_SIGNATURE_LONG),
+        ArrayKind::Float => array.is_array_of(JVM_SIGNATURE_FLOAT),
+        ArrayKind::Double => array.is_array_of(JVM_SIGNATURE_DOUBLE),
+        ArrayKind::Byte => array.is_array_of(JVM_SIGNATURE_BYTE) || array.is_array_of(JVM_SIGNATURE_BOOLEAN),
+        ArrayKind::Char => array.is_array_of(JVM_SIGNATURE_CHAR),
+        ArrayKind::Short => array.is_array_of(JVM_SIGNATURE_SHORT),
+        ArrayKind::Reference => array.component_type().map_or(false, |value| value.is_reference())
+    };
+    if !matches {
+        return Err(format!("Bad type on operand stack, expected an array of {:?} but found {}", kind, array));
+    }
+    Ok(())
+}
+
+fn kind_type(kind: ValueKind) -> VerifierType {
+    match kind {
+        ValueKind::Int => VerifierType::Integer,
+        ValueKind::Long => VerifierType::Long,
+        ValueKind::Float => VerifierType::Float,
+        ValueKind::Double => VerifierType::Double,
+        ValueKind::Reference => VerifierType::reference(JAVA_LANG_OBJECT_NAME)
+    }
+}
+
+fn kind_size(kind: ValueKind) -> usize {
+    match kind {
+        ValueKind::Long | ValueKind::Double => 2,
+        _ => 1
+    }
+}
+
+fn kind_name(kind: ValueKind) -> &'static str {
+    match kind {
+        ValueKind::Int => "int",
+        ValueKind::Long => "long",
+        ValueKind::Float => "float",
+        ValueKind::Double => "double",
+        ValueKind::Reference => "reference"
+    }
+}
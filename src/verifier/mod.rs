@@ -0,0 +1,294 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+
+mod constraints;
+mod execution;
+mod stack_map;
+mod type_checker;
+mod type_inference;
+mod types;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use crate::utils::IStr;
+use crate::class_file::ClassLoader;
+use crate::constants::JAVA_VERSION_6;
+use crate::types::{Class, Method};
+use crate::utils::constants::JAVA_LANG_OBJECT_NAME;
+
+pub use constraints::check_constraints;
+pub use stack_map::generate_stack_map;
+pub use types::{Frame, VerifierType, least_common_supertype};
+
+// Verifies the code of every method in the class, returning the first problem that was found.
+pub fn verify(class: &Class, hierarchy: &dyn ClassHierarchy) -> Result<(), VerifyError> {
+    for method in class.methods().iter().filter(|method| method.code().is_some()) {
+        // Older class files don't have stack map frames for the type checker to check against. Version 50
+        // files are allowed to fall back to inference if they fail type checking, like HotSpot does.
+        match class.major_version() {
+            version if version < JAVA_VERSION_6 => type_inference::check(class, method, hierarchy)?,
+            JAVA_VERSION_6 => type_checker::check(class, method, hierarchy)
+                .or_else(|_| type_inference::check(class, method, hierarchy))?,
+            _ => type_checker::check(class, method, hierarchy)?
+        }
+    }
+    Ok(())
+}
+
+// Which classes get verified when they're initialized, like HotSpot's -Xverify option.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum VerifyMode {
+    None,
+    // Everything except the platform's own classes
+    #[default]
+    Remote,
+    All
+}
+
+// We don't have a separate boot loader yet, so these packages stand in for the classes it would load.
+const TRUSTED_PACKAGES: [&str; 4] = ["java/", "javax/", "jdk/", "sun/"];
+
+impl VerifyMode {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "none" => Some(VerifyMode::None),
+            "remote" => Some(VerifyMode::Remote),
+            "all" => Some(VerifyMode::All),
+            _ => None
+        }
+    }
+
+    pub fn should_verify(&self, class_name: &str) -> bool {
+        match self {
+            VerifyMode::None => false,
+            VerifyMode::Remote => !TRUSTED_PACKAGES.iter().any(|package| class_name.starts_with(package)),
+            VerifyMode::All => true
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyError {
+    class_name: String,
+    method: String,
+    offset: Option<u32>,
+    message: String
+}
+
+impl VerifyError {
+    pub(crate) fn new(class: &Class, method: &Method, offset: Option<u32>, message: impl Into<String>) -> Self {
+        VerifyError {
+            class_name: class.name().to_string(),
+            method: method_name(class, method),
+            offset,
+            message: message.into()
+        }
+    }
+
+    pub fn class_name(&self) -> &str {
+        &self.class_name
+    }
+
+    // The name and descriptor of the method that failed verification.
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    // The offset of the instruction that failed verification, if the problem was with an instruction.
+    pub fn offset(&self) -> Option<u32> {
+        self.offset
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.offset {
+            Some(offset) => write!(f, "{}.{} @{}: {}", self.class_name, self.method, offset, self.message),
+            None => write!(f, "{}.{}: {}", self.class_name, self.method, self.message)
+        }
+    }
+}
+
+impl Error for VerifyError {}
+
+// A problem with the structure of a class file that parsing let through, like a Code attribute whose
+// tables point outside of the code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClassFormatError {
+    class_name: String,
+    method: Option<String>,
+    message: String
+}
+
+impl ClassFormatError {
+    pub(crate) fn new(class: &Class, method: Option<&Method>, message: impl Into<String>) -> Self {
+        ClassFormatError {
+            class_name: class.name().to_string(),
+            method: method.map(|value| method_name(class, value)),
+            message: message.into()
+        }
+    }
+
+    pub fn class_name(&self) -> &str {
+        &self.class_name
+    }
+
+    pub fn method(&self) -> Option<&str> {
+        self.method.as_deref()
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl Display for ClassFormatError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.method {
+            Some(method) => write!(f, "{}.{}: {}", self.class_name, method, self.message),
+            None => write!(f, "{}: {}", self.class_name, self.message)
+        }
+    }
+}
+
+impl Error for ClassFormatError {}
+
+// Either of the errors that linking a class can fail with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkError {
+    ClassFormat(ClassFormatError),
+    Verify(VerifyError)
+}
+
+impl From<ClassFormatError> for LinkError {
+    fn from(value: ClassFormatError) -> Self {
+        LinkError::ClassFormat(value)
+    }
+}
+
+impl From<VerifyError> for LinkError {
+    fn from(value: VerifyError) -> Self {
+        LinkError::Verify(value)
+    }
+}
+
+impl Display for LinkError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LinkError::ClassFormat(error) => write!(f, "ClassFormatError: {}", error),
+            LinkError::Verify(error) => write!(f, "VerifyError: {}", error)
+        }
+    }
+}
+
+impl Error for LinkError {}
+
+// The name and descriptor of a method, like main([Ljava/lang/String;)V, for error messages.
+fn method_name(class: &Class, method: &Method) -> String {
+    let descriptor = class.constant_pool()
+        .get_utf8(method.descriptor_index() as usize)
+        .map(|value| value.to_string())
+        .unwrap_or_default();
+    format!("{}{}", method.name(), descriptor)
+}
+
+// What the verifier needs to know about a class to check whether one type can be assigned to another.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClassInfo {
+    pub super_class: Option<IStr>,
+    pub is_interface: bool
+}
+
+// Answers questions about the class hierarchy, so that verification doesn't have to load classes itself.
+// Classes that can't be found aren't assignable to anything but themselves and java/lang/Object, so code
+// that relies on them fails verification.
+pub trait ClassHierarchy {
+    fn lookup(&self, name: &str) -> Option<ClassInfo>;
+
+    // Whether the class is the other class or extends it. Like assignability, a class we can't find isn't
+    // a subclass of anything but itself and java/lang/Object.
+    fn is_subclass(&self, name: &str, other: &str) -> bool {
+        if name == other || other == JAVA_LANG_OBJECT_NAME {
+            return true;
+        }
+        let mut current = IStr::new(name);
+        while let Some(super_class) = self.lookup(&current).and_then(|info| info.super_class) {
+            if super_class == other {
+                return true;
+            }
+            current = super_class;
+        }
+        false
+    }
+}
+
+impl ClassHierarchy for Arc<ClassLoader> {
+    fn lookup(&self, name: &str) -> Option<ClassInfo> {
+        self.find_class(name).map(|class| ClassInfo {
+            super_class: class.super_class_name(),
+            is_interface: class.is_interface()
+        })
+    }
+
+    fn is_subclass(&self, name: &str, other: &str) -> bool {
+        match (self.find_class(name), self.find_class(other)) {
+            (Some(class), Some(other)) => class.is_subclass(&other),
+            _ => name == other || other == JAVA_LANG_OBJECT_NAME
+        }
+    }
+}
+
+impl ClassHierarchy for HashMap<String, ClassInfo> {
+    fn lookup(&self, name: &str) -> Option<ClassInfo> {
+        self.get(name).cloned()
+    }
+}
+
+// The part of the JDK's hierarchy that the compiled fixtures use, for tests that verify them without a JDK
+// to load classes from.
+#[cfg(test)]
+pub(crate) fn fixture_hierarchy() -> HashMap<String, ClassInfo> {
+    let classes = [
+        ("java/lang/Object", None),
+        ("java/lang/String", Some("java/lang/Object")),
+        ("java/lang/Throwable", Some("java/lang/Object")),
+        ("java/lang/Exception", Some("java/lang/Throwable")),
+        ("java/lang/RuntimeException", Some("java/lang/Exception")),
+        ("java/lang/IndexOutOfBoundsException", Some("java/lang/RuntimeException")),
+        ("java/lang/NullPointerException", Some("java/lang/RuntimeException")),
+        ("java/io/IOException", Some("java/lang/Exception")),
+        ("java/util/AbstractCollection", Some("java/lang/Object")),
+        ("java/util/AbstractList", Some("java/util/AbstractCollection")),
+        ("java/util/ArrayList", Some("java/util/AbstractList"))
+    ];
+    let interfaces = ["java/util/List"];
+    let mut result = HashMap::new();
+    for (name, super_class) in classes {
+        result.insert(String::from(name), ClassInfo { super_class: super_class.map(IStr::new), is_interface: false });
+    }
+    for name in interfaces {
+        let info = ClassInfo { super_class: Some(IStr::new(JAVA_LANG_OBJECT_NAME)), is_interface: true };
+        result.insert(String::from(name), info);
+    }
+    result
+}
@@ -0,0 +1,242 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+
+use std::collections::BTreeSet;
+use crate::class_file::verification::{StackMapFrame, StackMapTable, VerificationType};
+use crate::constants::*;
+use crate::types::{Class, Method};
+use super::{ClassHierarchy, VerifyError};
+use super::type_inference;
+use super::types::VerifierType;
+
+const SAME_LOCALS_1_STACK_ITEM: u8 = 64;
+const SAME_LOCALS_1_STACK_ITEM_EXTENDED: u8 = 247;
+const SAME_FRAME_EXTENDED: u8 = 251;
+const FULL_FRAME: u8 = 255;
+
+// Works out the StackMapTable for a method from its code, for classes that have been assembled or
+// rewritten. The types are inferred the same way as the verifier does for old class files, asking the
+// hierarchy what two classes have in common where control flow joins. Class names in the frames are
+// turned into constant pool indices with class_index, since adding them to the pool is up to whoever is
+// writing the class.
+pub fn generate_stack_map(
+    class: &Class,
+    method: &Method,
+    hierarchy: &dyn ClassHierarchy,
+    class_index: &mut dyn FnMut(&str) -> u16
+) -> Result<StackMapTable, VerifyError> {
+    let inference = match type_inference::infer(class, method, hierarchy)? {
+        Some(value) => value,
+        None => return Ok(StackMapTable::new(Vec::new()))
+    };
+    let instructions = &inference.instructions;
+    let error = |offset: u32, message: &str| VerifyError::new(class, method, Some(offset), message);
+
+    // Frames are needed wherever control flow can arrive from somewhere other than the instruction before
+    let mut targets = BTreeSet::new();
+    for (index, instruction) in instructions.iter().enumerate() {
+        targets.extend(instruction.instruction().branch_targets());
+        if instruction.instruction().is_unconditional() && index + 1 < instructions.len() {
+            targets.insert(instructions[index + 1].offset());
+        }
+    }
+    if let Some(code) = method.code() {
+        targets.extend(code.exception_handlers().iter().map(|handler| handler.handler_pc() as u32));
+    }
+
+    let mut frames = Vec::with_capacity(targets.len());
+    for offset in targets {
+        let index = instructions.iter().position(|value| value.offset() == offset).unwrap();
+        let frame = inference.frames[index].as_ref()
+            .ok_or_else(|| error(offset, "Unreachable code can't be described by a stack map frame"))?;
+        let is_return_address = |value: &VerifierType| matches!(value, VerifierType::ReturnAddress(_));
+        if frame.locals().iter().chain(frame.stack()).any(is_return_address) {
+            return Err(error(offset, "Subroutines can't be described by stack map frames"));
+        }
+        frames.push((offset, frame.locals(), frame.stack()));
+    }
+    Ok(encode_frames(inference.initial.locals(), frames, class_index))
+}
+
+// Encodes frames given as their offsets, uncompacted locals and stacks, each relative to the one before it,
+// starting from the method's initial locals.
+fn encode_frames<'a>(
+    initial: &[VerifierType],
+    frames: impl IntoIterator<Item = (u32, &'a [VerifierType], &'a [VerifierType])>,
+    class_index: &mut dyn FnMut(&str) -> u16
+) -> StackMapTable {
+    let mut result = Vec::new();
+    let mut previous_locals = compact_locals(initial);
+    let mut previous_offset = None;
+    for (offset, locals, stack) in frames {
+        let delta = match previous_offset {
+            Some(previous) => offset - previous - 1,
+            None => offset
+        } as u16;
+        previous_offset = Some(offset);
+        let locals = compact_locals(locals);
+        result.push(encode_frame(delta, &previous_locals, &locals, stack, class_index));
+        previous_locals = locals;
+    }
+    StackMapTable::new(result)
+}
+
+// Picks the smallest kind of frame that can describe the frame relative to the one before it.
+fn encode_frame(
+    delta: u16,
+    previous: &[VerifierType],
+    locals: &[VerifierType],
+    stack: &[VerifierType],
+    class_index: &mut dyn FnMut(&str) -> u16
+) -> StackMapFrame {
+    let mut convert = |values: &[VerifierType]| {
+        values.iter().map(|value| verification_type(value, class_index)).collect::<Vec<_>>()
+    };
+    let same_locals = locals == previous;
+    if stack.is_empty() && same_locals {
+        let frame_type = if delta < 64 { delta as u8 } else { SAME_FRAME_EXTENDED };
+        return StackMapFrame::new(frame_type, delta, Vec::new(), Vec::new());
+    }
+    if stack.len() == 1 && same_locals {
+        let frame_type = if delta < 64 {
+            SAME_LOCALS_1_STACK_ITEM + delta as u8
+        } else {
+            SAME_LOCALS_1_STACK_ITEM_EXTENDED
+        };
+        return StackMapFrame::new(frame_type, delta, Vec::new(), convert(stack));
+    }
+    if stack.is_empty() && locals.len() < previous.len() && previous.len() - locals.len() <= 3 &&
+        previous.starts_with(locals) {
+        let frame_type = SAME_FRAME_EXTENDED - (previous.len() - locals.len()) as u8;
+        return StackMapFrame::new(frame_type, delta, Vec::new(), Vec::new());
+    }
+    if stack.is_empty() && locals.len() > previous.len() && locals.len() - previous.len() <= 3 &&
+        locals.starts_with(previous) {
+        let frame_type = SAME_FRAME_EXTENDED + (locals.len() - previous.len()) as u8;
+        return StackMapFrame::new(frame_type, delta, convert(&locals[previous.len()..]), Vec::new());
+    }
+    StackMapFrame::new(FULL_FRAME, delta, convert(locals), convert(stack))
+}
+
+// Stack map frames only have one entry for longs and doubles, and leave off any trailing tops.
+fn compact_locals(locals: &[VerifierType]) -> Vec<VerifierType> {
+    let mut result = Vec::with_capacity(locals.len());
+    let mut index = 0;
+    while index < locals.len() {
+        result.push(locals[index].clone());
+        index += locals[index].size();
+    }
+    while result.last() == Some(&VerifierType::Top) {
+        result.pop();
+    }
+    result
+}
+
+fn verification_type(value: &VerifierType, class_index: &mut dyn FnMut(&str) -> u16) -> VerificationType {
+    match value {
+        VerifierType::Integer => VerificationType::new(JVM_ITEM_INTEGER, 0),
+        VerifierType::Float => VerificationType::new(JVM_ITEM_FLOAT, 0),
+        VerifierType::Long => VerificationType::new(JVM_ITEM_LONG, 0),
+        VerifierType::Double => VerificationType::new(JVM_ITEM_DOUBLE, 0),
+        VerifierType::Null => VerificationType::new(JVM_ITEM_NULL, 0),
+        VerifierType::UninitializedThis => VerificationType::new(JVM_ITEM_UNINITIALIZED_THIS, 0),
+        VerifierType::Uninitialized(offset) => VerificationType::new(JVM_ITEM_UNINITIALIZED, *offset as u16),
+        VerifierType::Reference(name) => VerificationType::new(JVM_ITEM_OBJECT, class_index(name)),
+        // Return addresses are rejected before we get here
+        VerifierType::Top | VerifierType::ReturnAddress(_) => VerificationType::new(JVM_ITEM_TOP, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::Path;
+    use std::sync::Arc;
+    use crate::utils::IStr;
+    use crate::assembler::assemble;
+    use crate::class_file::ClassLoader;
+    use crate::class_file::verification::StackMapFrame;
+    use crate::constants::*;
+    use crate::types::Class;
+    use crate::verifier::{ClassInfo, fixture_hierarchy};
+    use super::generate_stack_map;
+
+    // Where each frame in a table applies, worked out from the deltas.
+    fn offsets<'a>(frames: impl Iterator<Item = &'a StackMapFrame>) -> Vec<u32> {
+        let mut result: Vec<u32> = Vec::new();
+        for frame in frames {
+            let delta = frame.offset_delta() as u32;
+            result.push(result.last().map_or(delta, |previous| previous + delta + 1));
+        }
+        result
+    }
+
+    #[test]
+    fn places_frames_like_javac() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures").join("Kitchen.class");
+        let class = Class::from_bytes(Arc::new(ClassLoader::new()), Bytes::from(fs::read(path).unwrap()));
+        let hierarchy = fixture_hierarchy();
+        for method in class.methods() {
+            let expected = match method.code().and_then(|code| code.stack_map_table()) {
+                Some(table) => offsets(table.iter()),
+                None => Vec::new()
+            };
+            let generated = generate_stack_map(&class, method, &hierarchy, &mut |_| 1).unwrap();
+            assert_eq!(offsets(generated.iter()), expected, "{}", method.name());
+        }
+    }
+
+    #[test]
+    fn uses_compact_frames() {
+        let bytes = assemble(r#"
+.class Legacy
+.method public static pick(ZLFirst;LSecond;)LBase;
+    iload_0
+    ifeq Other
+    aload_1
+    goto End
+Other:
+    aload_2
+End:
+    astore_3
+    aload_3
+    areturn
+.end method
+"#).unwrap();
+        let class = Class::from_bytes(Arc::new(ClassLoader::new()), bytes);
+        let mut hierarchy = HashMap::new();
+        for (name, super_class) in [("First", "Base"), ("Second", "Base"), ("Base", "java/lang/Object")] {
+            let info = ClassInfo { super_class: Some(IStr::new(super_class)), is_interface: false };
+            hierarchy.insert(String::from(name), info);
+        }
+        let mut names = Vec::new();
+        let table = generate_stack_map(&class, &class.methods()[0], &hierarchy, &mut |name| {
+            names.push(name.to_string());
+            names.len() as u16
+        }).unwrap();
+        // Other only needs the same locals, and End has the merged type on the stack
+        assert_eq!(table.len(), 2);
+        let other = table.get(0).unwrap();
+        assert_eq!((other.frame_type(), other.offset_delta()), (8, 8));
+        let end = table.get(1).unwrap();
+        assert_eq!(end.frame_type(), 64);
+        assert_eq!(end.stack()[0].item(), JVM_ITEM_OBJECT);
+        assert_eq!(names, vec![String::from("Base")]);
+    }
+}
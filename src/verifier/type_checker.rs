@@ -0,0 +1,382 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+
+use std::collections::{HashMap, HashSet};
+use crate::utils::IStr;
+use crate::class_file::bytecode::{DecodedInstruction, Instruction, InstructionDecoder};
+use crate::class_file::code::CodeBlock;
+use crate::class_file::verification::VerificationType;
+use crate::constants::*;
+use crate::types::{Class, Method};
+use crate::utils::constants::JAVA_LANG_OBJECT_NAME;
+use super::{ClassHierarchy, VerifyError};
+use super::execution::MethodContext;
+use super::types::{Frame, VerifierType};
+
+// Checks a method against the frames in its StackMapTable, following JVMS §4.10.1. Every instruction is
+// visited once, in order, and the frames tell us what the types are wherever control flow joins.
+pub(super) fn check(class: &Class, method: &Method, hierarchy: &dyn ClassHierarchy) -> Result<(), VerifyError> {
+    let code = match method.code() {
+        Some(value) => value,
+        None => return Ok(())
+    };
+    let error = |offset: Option<u32>, message: String| VerifyError::new(class, method, offset, message);
+
+    let instructions = InstructionDecoder::decode_all(code.code())
+        .map_err(|value| error(Some(value.offset()), value.message().to_string()))?;
+    if instructions.is_empty() {
+        return Err(error(None, String::from("Code must not be empty")));
+    }
+    let is_constructor = method.name() == JVM_OBJECT_INITIALIZER_NAME;
+    let context = MethodContext::new(class, hierarchy, code.max_stack() as usize, method.descriptor(),
+                                     is_constructor, &instructions);
+    let initial = initial_locals(class, method);
+    let frames = expand_frames(&context, code, &initial).map_err(|value| error(None, value))?;
+    let starts = instructions.iter().map(DecodedInstruction::offset).collect::<HashSet<_>>();
+    if let Some(offset) = frames.keys().filter(|offset| !starts.contains(offset)).min() {
+        return Err(error(Some(*offset), String::from("Stack map frame is not at the start of an instruction")));
+    }
+
+    let mut current = Some(expand_locals(&initial, code.max_locals() as usize).map_err(|value| error(None, value))?);
+    for instruction in &instructions {
+        let offset = instruction.offset();
+        check_instruction(&context, code, &frames, &mut current, instruction)
+            .map_err(|value| error(Some(offset), value))?;
+    }
+    if current.is_some() {
+        let last = instructions.last().unwrap().offset();
+        return Err(error(Some(last), String::from("Falling off the end of the code")));
+    }
+    Ok(())
+}
+
+fn check_instruction(
+    context: &MethodContext,
+    code: &CodeBlock,
+    frames: &HashMap<u32, Frame>,
+    current: &mut Option<Frame>,
+    instruction: &DecodedInstruction
+) -> Result<(), String> {
+    let offset = instruction.offset();
+    if let Some(expected) = frames.get(&offset) {
+        if let Some(frame) = current {
+            if !frame.is_assignable_to(expected, context.hierarchy()) {
+                return Err(String::from("Current frame is not assignable to the stack map frame"));
+            }
+        }
+        *current = Some(expected.clone());
+    }
+    let mut frame = current.take().ok_or_else(|| String::from("Expecting a stack map frame"))?;
+    check_handlers(context, code, frames, offset, &frame)?;
+    context.execute(&mut frame, instruction)?;
+    // Anything thrown after a store sees the new value in the local
+    if matches!(instruction.instruction(), Instruction::Store(_, _) | Instruction::IInc(_, _)) {
+        check_handlers(context, code, frames, offset, &frame)?;
+    }
+    for target in instruction.instruction().branch_targets() {
+        check_target(context, frames, target, &frame)?;
+    }
+    if !instruction.instruction().is_unconditional() {
+        *current = Some(frame);
+    }
+    Ok(())
+}
+
+fn check_target(
+    context: &MethodContext,
+    frames: &HashMap<u32, Frame>,
+    target: u32,
+    frame: &Frame
+) -> Result<(), String> {
+    let expected = frames.get(&target)
+        .ok_or_else(|| format!("Expecting a stack map frame at branch target {}", target))?;
+    if !frame.is_assignable_to(expected, context.hierarchy()) {
+        return Err(format!("Frame is not assignable to the stack map frame at branch target {}", target));
+    }
+    Ok(())
+}
+
+fn check_handlers(
+    context: &MethodContext,
+    code: &CodeBlock,
+    frames: &HashMap<u32, Frame>,
+    offset: u32,
+    frame: &Frame
+) -> Result<(), String> {
+    let handlers = code.exception_handlers().iter()
+        .filter(|handler| handler.start_pc() as u32 <= offset && offset < handler.end_pc() as u32);
+    for handler in handlers {
+        let catch_type = context.catch_type(handler.catch_type_index())?;
+        let handler_frame = frame.with_stack(vec![catch_type]);
+        check_target(context, frames, handler.handler_pc() as u32, &handler_frame)?;
+    }
+    Ok(())
+}
+
+// The locals on entry to the method, with one entry per value, rather than one per slot.
+pub(super) fn initial_locals(class: &Class, method: &Method) -> Vec<VerifierType> {
+    let mut result = Vec::new();
+    if !method.is_static() {
+        if method.name() == JVM_OBJECT_INITIALIZER_NAME && class.name() != JAVA_LANG_OBJECT_NAME {
+            result.push(VerifierType::UninitializedThis);
+        } else {
+            result.push(VerifierType::reference(class.name()));
+        }
+    }
+    result.extend(method.descriptor().parameters().iter().map(VerifierType::from_descriptor));
+    result
+}
+
+pub(super) fn expand_locals(locals: &[VerifierType], max_locals: usize) -> Result<Frame, String> {
+    let mut frame = Frame::new(max_locals);
+    let mut index = 0;
+    for local in locals {
+        frame.set_local(index, local.clone())?;
+        index += local.size();
+    }
+    Ok(frame)
+}
+
+// Turns the deltas and compressed frames in the StackMapTable into full frames keyed by offset.
+fn expand_frames(
+    context: &MethodContext,
+    code: &CodeBlock,
+    initial: &[VerifierType]
+) -> Result<HashMap<u32, Frame>, String> {
+    let mut result = HashMap::new();
+    let table = match code.stack_map_table() {
+        Some(value) => value,
+        None => return Ok(result)
+    };
+    let max_locals = code.max_locals() as usize;
+    let mut locals = initial.to_vec();
+    let mut previous: Option<u32> = None;
+    for (index, stack_frame) in table.iter().enumerate() {
+        let delta = stack_frame.offset_delta() as u32;
+        let offset = previous.map_or(delta, |value| value + delta + 1);
+        let frame_type = stack_frame.frame_type();
+        match frame_type {
+            0..=127 | 247 | 251 => {},
+            248..=250 => {
+                let count = (251 - frame_type) as usize;
+                if count > locals.len() {
+                    return Err(format!("Stack map frame {} chops more locals than there are", index));
+                }
+                locals.truncate(locals.len() - count);
+            },
+            252..=254 => {
+                for local in stack_frame.locals() {
+                    locals.push(convert_type(context, local)?);
+                }
+            },
+            _ => {
+                locals = stack_frame.locals().iter()
+                    .map(|value| convert_type(context, value))
+                    .collect::<Result<_, _>>()?;
+            }
+        }
+        let stack = stack_frame.stack().iter()
+            .map(|value| convert_type(context, value))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut frame = expand_locals(&locals, max_locals)
+            .map_err(|value| format!("Invalid stack map frame {}: {}", index, value))?;
+        frame = frame.with_stack(stack);
+        if frame.stack_size > code.max_stack() as usize {
+            return Err(format!("Stack map frame {} has a stack larger than max_stack", index));
+        }
+        result.insert(offset, frame);
+        previous = Some(offset);
+    }
+    Ok(result)
+}
+
+fn convert_type(context: &MethodContext, value: &VerificationType) -> Result<VerifierType, String> {
+    let result = match value.item() {
+        JVM_ITEM_TOP => VerifierType::Top,
+        JVM_ITEM_INTEGER => VerifierType::Integer,
+        JVM_ITEM_FLOAT => VerifierType::Float,
+        JVM_ITEM_DOUBLE => VerifierType::Double,
+        JVM_ITEM_LONG => VerifierType::Long,
+        JVM_ITEM_NULL => VerifierType::Null,
+        JVM_ITEM_UNINITIALIZED_THIS => VerifierType::UninitializedThis,
+        JVM_ITEM_OBJECT => VerifierType::Reference(IStr::clone(&context.class_name(value.offset())?)),
+        JVM_ITEM_UNINITIALIZED => {
+            let offset = value.offset() as u32;
+            if !context.is_new_offset(offset) {
+                return Err(format!("Uninitialized type offset {} is not a new instruction", offset));
+            }
+            VerifierType::Uninitialized(offset)
+        },
+        item => return Err(format!("Invalid verification type {}", item))
+    };
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use std::fs;
+    use std::path::Path;
+    use std::sync::Arc;
+    use crate::assembler::assemble;
+    use crate::class_file::ClassLoader;
+    use crate::types::Class;
+    use crate::verifier::{VerifyError, fixture_hierarchy, verify};
+
+    fn verify_bytes(bytes: Bytes) -> Result<(), VerifyError> {
+        let class = Class::from_bytes(Arc::new(ClassLoader::new()), bytes);
+        verify(&class, &fixture_hierarchy())
+    }
+
+    fn verify_source(source: &str) -> Result<(), VerifyError> {
+        verify_bytes(assemble(source).unwrap())
+    }
+
+    #[test]
+    fn accepts_compiled_classes() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let paths = ["Hello.class", "Main.class"].iter().map(|name| root.join(name))
+            .chain(fs::read_dir(root.join("fixtures")).unwrap().map(|entry| entry.unwrap().path()))
+            .filter(|path| path.extension().map_or(false, |value| value == "class"));
+        for path in paths {
+            let result = verify_bytes(Bytes::from(fs::read(&path).unwrap()));
+            assert!(result.is_ok(), "{}: {}", path.display(), result.unwrap_err());
+        }
+    }
+
+    #[test]
+    fn accepts_assembled_classes() {
+        verify_source(r#"
+.class public Counter
+.method public <init>()V
+    aload_0
+    invokespecial java/lang/Object/<init>()V
+    return
+.end method
+
+.method public static sum([I)J
+    lconst_0
+    lstore_1
+    iconst_0
+    istore_3
+Loop:
+    iload_3
+    aload_0
+    arraylength
+    if_icmpge End
+    lload_1
+    aload_0
+    iload_3
+    iaload
+    i2l
+    ladd
+    lstore_1
+    iinc 3 1
+    goto Loop
+End:
+    lload_1
+    lreturn
+.end method
+"#).unwrap();
+    }
+
+    #[test]
+    fn rejects_bad_types() {
+        let error = verify_source(r#"
+.class Broken
+.method public static f()Ljava/lang/Object;
+    iconst_0
+    areturn
+.end method
+"#).unwrap_err();
+        assert_eq!(error.offset(), Some(1));
+        assert_eq!(error.method(), "f()Ljava/lang/Object;");
+
+        let error = verify_source(r#"
+.class Broken
+.method public static f(Ljava/lang/String;)V
+    aload_0
+    athrow
+.end method
+"#).unwrap_err();
+        assert_eq!(error.offset(), Some(1));
+        assert!(error.message().contains("java/lang/Throwable"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_classes_it_cannot_find() {
+        let error = verify_source(r#"
+.class Broken
+.method public static f(LMissing;)Ljava/lang/String;
+    aload_0
+    areturn
+.end method
+"#).unwrap_err();
+        assert_eq!(error.offset(), Some(1));
+        assert!(error.message().contains("Missing"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_bad_limits() {
+        let error = verify_source(r#"
+.class Broken
+.method public static f()I
+.limit stack 1
+    iconst_1
+    iconst_2
+    iadd
+    ireturn
+.end method
+"#).unwrap_err();
+        assert_eq!(error.offset(), Some(1));
+
+        let error = verify_source(r#"
+.class Broken
+.method public static f()V
+.limit locals 1
+    iconst_1
+    istore_1
+    return
+.end method
+"#).unwrap_err();
+        assert_eq!(error.offset(), Some(1));
+    }
+
+    #[test]
+    fn rejects_uninitialized_this() {
+        let error = verify_source(r#"
+.class Broken
+.method public <init>()V
+    return
+.end method
+"#).unwrap_err();
+        assert_eq!(error.offset(), Some(0));
+
+        let error = verify_source(r#"
+.class Broken
+.method public <init>()V
+    aload_0
+    invokevirtual java/lang/Object/hashCode()I
+    pop
+    aload_0
+    invokespecial java/lang/Object/<init>()V
+    return
+.end method
+"#).unwrap_err();
+        assert_eq!(error.offset(), Some(1));
+    }
+}
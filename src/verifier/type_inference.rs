@@ -0,0 +1,386 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+
+use std::collections::{HashMap, HashSet};
+use crate::class_file::bytecode::{DecodedInstruction, Instruction, InstructionDecoder, ValueKind};
+use crate::class_file::code::CodeBlock;
+use crate::constants::*;
+use crate::types::{Class, Method};
+use super::{ClassHierarchy, VerifyError};
+use super::execution::MethodContext;
+use super::type_checker::{expand_locals, initial_locals};
+use super::types::{Frame, VerifierType, merge};
+
+// A subroutine, entered with jsr and left with ret, as used by older compilers for finally blocks.
+struct Subroutine {
+    // The indices of the jsr instructions that call this subroutine
+    callers: Vec<usize>,
+    // The locals that the subroutine, or any subroutine it calls, may store to
+    written: HashSet<usize>
+}
+
+// The types inferred for a method, with the state at the start of each instruction, or None for
+// instructions that can never be reached.
+pub(super) struct Inference {
+    pub(super) instructions: Vec<DecodedInstruction>,
+    pub(super) initial: Frame,
+    pub(super) frames: Vec<Option<Frame>>
+}
+
+// Infers the types at every instruction by data-flow analysis, following JVMS §4.10.2. This is used for
+// class files older than version 50, which don't have the StackMapTable the type checker needs.
+pub(super) fn check(class: &Class, method: &Method, hierarchy: &dyn ClassHierarchy) -> Result<(), VerifyError> {
+    infer(class, method, hierarchy).map(|_| ())
+}
+
+pub(super) fn infer(
+    class: &Class,
+    method: &Method,
+    hierarchy: &dyn ClassHierarchy
+) -> Result<Option<Inference>, VerifyError> {
+    let code = match method.code() {
+        Some(value) => value,
+        None => return Ok(None)
+    };
+    let error = |offset: Option<u32>, message: String| VerifyError::new(class, method, offset, message);
+
+    let instructions = InstructionDecoder::decode_all(code.code())
+        .map_err(|value| error(Some(value.offset()), value.message().to_string()))?;
+    if instructions.is_empty() {
+        return Err(error(None, String::from("Code must not be empty")));
+    }
+    let is_constructor = method.name() == JVM_OBJECT_INITIALIZER_NAME;
+    let context = MethodContext::new(class, hierarchy, code.max_stack() as usize, method.descriptor(),
+                                     is_constructor, &instructions);
+    let initial = expand_locals(&initial_locals(class, method), code.max_locals() as usize)
+        .map_err(|value| error(None, value))?;
+    let mut analyzer = Analyzer {
+        context: &context,
+        code,
+        indices: instructions.iter().enumerate().map(|(index, value)| (value.offset(), index)).collect(),
+        subroutines: HashMap::new(),
+        instructions: &instructions,
+        frames: vec![None; instructions.len()],
+        return_frames: HashMap::new(),
+        queue: Vec::new(),
+        queued: vec![false; instructions.len()]
+    };
+    analyzer.find_subroutines().map_err(|(offset, message)| error(Some(offset), message))?;
+    analyzer.flow(0, initial.clone()).map_err(|value| error(None, value))?;
+    while let Some(index) = analyzer.queue.pop() {
+        analyzer.queued[index] = false;
+        let offset = instructions[index].offset();
+        analyzer.visit(index).map_err(|value| error(Some(offset), value))?;
+    }
+    let frames = analyzer.frames;
+    Ok(Some(Inference { instructions, initial, frames }))
+}
+
+struct Analyzer<'a> {
+    context: &'a MethodContext<'a>,
+    code: &'a CodeBlock,
+    instructions: &'a [DecodedInstruction],
+    indices: HashMap<u32, usize>,
+    subroutines: HashMap<u32, Subroutine>,
+    // The state at the start of each instruction, or None if we haven't reached it yet
+    frames: Vec<Option<Frame>>,
+    // The merged state at every ret from each subroutine, keyed by the start of the subroutine
+    return_frames: HashMap<u32, Frame>,
+    queue: Vec<usize>,
+    queued: Vec<bool>
+}
+
+impl<'a> Analyzer<'a> {
+    fn index_of(&self, offset: u32) -> Result<usize, String> {
+        self.indices.get(&offset).copied()
+            .ok_or_else(|| format!("Branch target {} is not the start of an instruction", offset))
+    }
+
+    // Merges the frame into the state at the start of the instruction, queueing it again if anything changed.
+    fn flow(&mut self, index: usize, frame: Frame) -> Result<(), String> {
+        let merged = match &self.frames[index] {
+            Some(existing) => match merge(self.context.hierarchy(), existing, &frame)? {
+                Some(value) => value,
+                None => return Ok(())
+            },
+            None => frame
+        };
+        self.frames[index] = Some(merged);
+        if !self.queued[index] {
+            self.queued[index] = true;
+            self.queue.push(index);
+        }
+        Ok(())
+    }
+
+    fn visit(&mut self, index: usize) -> Result<(), String> {
+        let instruction = &self.instructions[index];
+        let frame = self.frames[index].clone().unwrap();
+        self.flow_handlers(instruction.offset(), &frame)?;
+        match instruction.instruction() {
+            Instruction::Jsr(target) => {
+                let mut next = frame.clone();
+                next.push(VerifierType::ReturnAddress(*target), self.code.max_stack() as usize)?;
+                self.flow(self.index_of(*target)?, next)?;
+                // If we've already seen the subroutine return, the state after this jsr may have changed
+                if let Some(returned) = self.return_frames.get(target).cloned() {
+                    self.flow_return(index, &frame, &returned, *target)?;
+                }
+            },
+            Instruction::Ret(local) => {
+                let target = match frame.get_local(*local as usize, 1)? {
+                    VerifierType::ReturnAddress(target) => *target,
+                    value => return Err(format!("Bad local variable type, expected a return address in local {} \
+                        but found {}", local, value))
+                };
+                let returned = match self.return_frames.get(&target) {
+                    Some(existing) => match merge(self.context.hierarchy(), existing, &frame)? {
+                        Some(value) => value,
+                        None => return Ok(())
+                    },
+                    None => frame
+                };
+                self.return_frames.insert(target, returned.clone());
+                let callers = self.subroutines[&target].callers.clone();
+                for caller in callers {
+                    if let Some(called) = self.frames[caller].clone() {
+                        self.flow_return(caller, &called, &returned, target)?;
+                    }
+                }
+            },
+            _ => {
+                let mut next = frame;
+                self.context.execute(&mut next, instruction)?;
+                if matches!(instruction.instruction(), Instruction::Store(_, _) | Instruction::IInc(_, _)) {
+                    self.flow_handlers(instruction.offset(), &next)?;
+                }
+                for target in instruction.instruction().branch_targets() {
+                    self.flow(self.index_of(target)?, next.clone())?;
+                }
+                if !instruction.instruction().is_unconditional() {
+                    if index + 1 >= self.instructions.len() {
+                        return Err(String::from("Falling off the end of the code"));
+                    }
+                    self.flow(index + 1, next)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Continues after a jsr once its subroutine has returned. Locals that the subroutine stored to come from
+    // the ret, and everything else is as it was when the subroutine was called.
+    fn flow_return(&mut self, caller: usize, called: &Frame, returned: &Frame, target: u32) -> Result<(), String> {
+        if caller + 1 >= self.instructions.len() {
+            return Err(String::from("Falling off the end of the code"));
+        }
+        let written = &self.subroutines[&target].written;
+        let mut frame = returned.clone();
+        for (index, local) in frame.locals.iter_mut().enumerate() {
+            if !written.contains(&index) {
+                *local = called.locals[index].clone();
+            }
+        }
+        self.flow(caller + 1, frame)
+    }
+
+    fn flow_handlers(&mut self, offset: u32, frame: &Frame) -> Result<(), String> {
+        let handlers = self.code.exception_handlers().iter()
+            .filter(|handler| handler.start_pc() as u32 <= offset && offset < handler.end_pc() as u32)
+            .map(|handler| (handler.handler_pc() as u32, handler.catch_type_index()))
+            .collect::<Vec<_>>();
+        for (handler_pc, catch_type_index) in handlers {
+            let catch_type = self.context.catch_type(catch_type_index)?;
+            self.flow(self.index_of(handler_pc)?, frame.with_stack(vec![catch_type]))?;
+        }
+        Ok(())
+    }
+
+    // Works out who calls each subroutine and which locals it may change, by following every path from its
+    // start up to the ret instructions that leave it.
+    fn find_subroutines(&mut self) -> Result<(), (u32, String)> {
+        for (index, instruction) in self.instructions.iter().enumerate() {
+            if let Instruction::Jsr(target) = instruction.instruction() {
+                self.index_of(*target).map_err(|value| (instruction.offset(), value))?;
+                self.subroutines.entry(*target)
+                    .or_insert_with(|| Subroutine { callers: Vec::new(), written: HashSet::new() })
+                    .callers.push(index);
+            }
+        }
+        let targets = self.subroutines.keys().copied().collect::<Vec<_>>();
+        for target in targets {
+            let mut written = HashSet::new();
+            self.collect_written(target, &mut written, &mut HashSet::new())?;
+            self.subroutines.get_mut(&target).unwrap().written = written;
+        }
+        Ok(())
+    }
+
+    fn collect_written(
+        &self,
+        target: u32,
+        written: &mut HashSet<usize>,
+        entered: &mut HashSet<u32>
+    ) -> Result<(), (u32, String)> {
+        // A subroutine calling itself, directly or not, is caught when the frames are merged
+        if !entered.insert(target) {
+            return Ok(());
+        }
+        let mut visited = HashSet::new();
+        let mut pending = vec![self.indices[&target]];
+        while let Some(index) = pending.pop() {
+            if !visited.insert(index) {
+                continue;
+            }
+            let instruction = &self.instructions[index];
+            let error = |message: String| (instruction.offset(), message);
+            match instruction.instruction() {
+                Instruction::Store(kind, local) => {
+                    let size = if matches!(kind, ValueKind::Long | ValueKind::Double) { 2 } else { 1 };
+                    written.extend(*local as usize..*local as usize + size);
+                },
+                Instruction::IInc(local, _) => {
+                    written.insert(*local as usize);
+                },
+                Instruction::Jsr(inner) => self.collect_written(*inner, written, entered)?,
+                Instruction::Ret(_) => continue,
+                _ => {}
+            }
+            for branch in instruction.instruction().branch_targets() {
+                if !matches!(instruction.instruction(), Instruction::Jsr(_)) {
+                    pending.push(self.index_of(branch).map_err(error)?);
+                }
+            }
+            if !instruction.instruction().is_unconditional() && index + 1 < self.instructions.len() {
+                pending.push(index + 1);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use crate::utils::IStr;
+    use crate::assembler::assemble;
+    use crate::class_file::ClassLoader;
+    use crate::constants::*;
+    use crate::types::Class;
+    use crate::verifier::{ClassInfo, VerifyError, least_common_supertype, verify};
+
+    fn hierarchy() -> HashMap<String, ClassInfo> {
+        let mut result = HashMap::new();
+        let mut insert = |name: &str, super_class: Option<&str>| {
+            let info = ClassInfo { super_class: super_class.map(IStr::new), is_interface: false };
+            result.insert(String::from(name), info);
+        };
+        insert("java/lang/Object", None);
+        insert("Base", Some("java/lang/Object"));
+        insert("First", Some("Base"));
+        insert("Second", Some("Base"));
+        result
+    }
+
+    // Assembles a method with a placeholder body, then swaps in the given code, so that we can test
+    // instructions like jsr that the assembler won't write.
+    fn verify_code(descriptor: &str, max_stack: u16, max_locals: u16, code: &[u8]) -> Result<(), VerifyError> {
+        let placeholder = "    nop\n".repeat(code.len() - 1);
+        let source = format!(".class Legacy\n.bytecode 49.0\n.method public static f{}\n.limit stack {}\n\
+            .limit locals {}\n{}    return\n.end method\n", descriptor, max_stack, max_locals, placeholder);
+        let mut bytes = assemble(&source).unwrap().to_vec();
+        let mut pattern = (code.len() as u32).to_be_bytes().to_vec();
+        pattern.extend(vec![0; code.len() - 1]);
+        pattern.push(JVM_OPCODE_RETURN);
+        let start = bytes.windows(pattern.len()).position(|window| window == pattern.as_slice()).unwrap() + 4;
+        bytes[start..start + code.len()].copy_from_slice(code);
+        let class = Class::from_bytes(Arc::new(ClassLoader::new()), Bytes::from(bytes));
+        verify(&class, &hierarchy())
+    }
+
+    fn verify_source(source: &str) -> Result<(), VerifyError> {
+        let class = Class::from_bytes(Arc::new(ClassLoader::new()), assemble(source).unwrap());
+        verify(&class, &hierarchy())
+    }
+
+    #[test]
+    fn merges_types() {
+        let hierarchy = hierarchy();
+        assert_eq!(least_common_supertype(&hierarchy, "First", "Second").as_str(), "Base");
+        assert_eq!(least_common_supertype(&hierarchy, "First", "Base").as_str(), "Base");
+        assert_eq!(least_common_supertype(&hierarchy, "[LFirst;", "[LSecond;").as_str(), "[LBase;");
+        assert_eq!(least_common_supertype(&hierarchy, "[I", "[J").as_str(), "java/lang/Object");
+        assert_eq!(least_common_supertype(&hierarchy, "First", "[I").as_str(), "java/lang/Object");
+
+        verify_source(r#"
+.class Legacy
+.bytecode 49.0
+.method public static pick(ZLFirst;LSecond;)LBase;
+    iload_0
+    ifeq Other
+    aload_1
+    goto End
+Other:
+    aload_2
+End:
+    areturn
+.end method
+"#).unwrap();
+
+        let error = verify_source(r#"
+.class Legacy
+.bytecode 49.0
+.method public static f()Ljava/lang/Object;
+    iconst_0
+    areturn
+.end method
+"#).unwrap_err();
+        assert_eq!(error.offset(), Some(1));
+    }
+
+    #[test]
+    fn follows_subroutines() {
+        let code = [
+            JVM_OPCODE_ICONST_0,
+            JVM_OPCODE_ISTORE_1,
+            JVM_OPCODE_JSR, 0, 6,
+            JVM_OPCODE_ILOAD_1,
+            JVM_OPCODE_IRETURN,
+            JVM_OPCODE_NOP,
+            JVM_OPCODE_ASTORE_2,
+            JVM_OPCODE_ICONST_1,
+            JVM_OPCODE_ISTORE_1,
+            JVM_OPCODE_RET, 2
+        ];
+        verify_code("(Z)I", 1, 3, &code).unwrap();
+
+        // The subroutine leaves a reference in local 1, which is then used as an int
+        let mut broken = code;
+        broken[9] = JVM_OPCODE_ACONST_NULL;
+        broken[10] = JVM_OPCODE_ASTORE_1;
+        let error = verify_code("(Z)I", 1, 3, &broken).unwrap_err();
+        assert_eq!(error.offset(), Some(5));
+
+        // Returning from a subroutine with an int rather than a return address
+        let mut broken = code;
+        broken[12] = 1;
+        let error = verify_code("(Z)I", 1, 3, &broken).unwrap_err();
+        assert_eq!(error.offset(), Some(11));
+    }
+}
@@ -0,0 +1,363 @@
+/*
+ * Copyright (C) 2022 Callum Seabrook <callum.seabrook@prevarinite.com>
+ *
+ * This program is free software; you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation; version 2.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program; if not, write to the Free Software Foundation, Inc., 51 Franklin
+ * Street, Fifth Floor, Boston, MA 02110-1301, USA.
+ */
+
+
+use std::fmt::{Display, Formatter};
+use crate::utils::IStr;
+use crate::constants::*;
+use crate::utils::constants::{JAVA_IO_SERIALIZABLE_NAME, JAVA_LANG_CLONEABLE_NAME, JAVA_LANG_OBJECT_NAME};
+use crate::utils::descriptors::{array_of, class_name_of, FieldDescriptor, FieldType};
+use super::ClassHierarchy;
+
+// The types from JVMS §4.10.1.2. Booleans, bytes, chars and shorts are all integers as far as the
+// verifier is concerned, and array types are references named by their descriptor, such as [I.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum VerifierType {
+    Top,
+    Integer,
+    Float,
+    Long,
+    Double,
+    Null,
+    UninitializedThis,
+    // The offset of the new instruction that created the object
+    Uninitialized(u32),
+    Reference(IStr),
+    // Only used by the type inference verifier, for the targets of jsr instructions
+    ReturnAddress(u32)
+}
+
+impl VerifierType {
+    pub fn from_descriptor(descriptor: &FieldDescriptor) -> Self {
+        if descriptor.array_dimensions() > 0 {
+            return VerifierType::Reference(IStr::new(&descriptor.descriptor_string()));
+        }
+        match descriptor.base() {
+            FieldType::Byte | FieldType::Char | FieldType::Int | FieldType::Short | FieldType::Boolean => {
+                VerifierType::Integer
+            },
+            FieldType::Float => VerifierType::Float,
+            FieldType::Long => VerifierType::Long,
+            FieldType::Double => VerifierType::Double,
+            FieldType::Reference(name) => VerifierType::Reference(IStr::clone(name))
+        }
+    }
+
+    pub fn reference(name: &str) -> Self {
+        VerifierType::Reference(IStr::new(name))
+    }
+
+    pub fn size(&self) -> usize {
+        match self {
+            VerifierType::Long | VerifierType::Double => 2,
+            _ => 1
+        }
+    }
+
+    pub fn is_reference(&self) -> bool {
+        matches!(self, VerifierType::Null | VerifierType::UninitializedThis | VerifierType::Uninitialized(_) |
+            VerifierType::Reference(_))
+    }
+
+    pub fn is_uninitialized(&self) -> bool {
+        matches!(self, VerifierType::UninitializedThis | VerifierType::Uninitialized(_))
+    }
+
+    // The type of the elements of an array type, or None if this isn't an array.
+    pub fn component_type(&self) -> Option<VerifierType> {
+        match self {
+            VerifierType::Reference(name) if name.starts_with(JVM_SIGNATURE_ARRAY) => {
+                FieldDescriptor::parse(&name[1..]).map(|value| VerifierType::from_descriptor(&value))
+            },
+            _ => None
+        }
+    }
+
+    // Whether this is an array with elements of the given primitive type, such as [I for I.
+    pub fn is_array_of(&self, element: char) -> bool {
+        match self {
+            VerifierType::Reference(name) => {
+                name.len() == 2 && name.starts_with(JVM_SIGNATURE_ARRAY) && name.ends_with(element)
+            },
+            _ => false
+        }
+    }
+
+    pub fn is_array(&self) -> bool {
+        matches!(self, VerifierType::Reference(name) if name.starts_with(JVM_SIGNATURE_ARRAY))
+    }
+}
+
+impl Display for VerifierType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifierType::Top => write!(f, "top"),
+            VerifierType::Integer => write!(f, "int"),
+            VerifierType::Float => write!(f, "float"),
+            VerifierType::Long => write!(f, "long"),
+            VerifierType::Double => write!(f, "double"),
+            VerifierType::Null => write!(f, "null"),
+            VerifierType::UninitializedThis => write!(f, "uninitializedThis"),
+            VerifierType::Uninitialized(offset) => write!(f, "uninitialized({})", offset),
+            VerifierType::Reference(name) => write!(f, "{}", name),
+            VerifierType::ReturnAddress(target) => write!(f, "returnAddress({})", target)
+        }
+    }
+}
+
+// Checks whether a value of one type can be used where another is expected, following the rules in
+// JVMS §4.10.1.2. Interfaces are treated like java/lang/Object, since the verifier can't check them.
+pub fn is_assignable(hierarchy: &dyn ClassHierarchy, from: &VerifierType, to: &VerifierType) -> bool {
+    if from == to {
+        return true;
+    }
+    match (from, to) {
+        (_, VerifierType::Top) => true,
+        (VerifierType::Null, VerifierType::Reference(_)) => true,
+        (VerifierType::Reference(from), VerifierType::Reference(to)) => is_class_assignable(hierarchy, from, to),
+        _ => false
+    }
+}
+
+pub fn is_class_assignable(hierarchy: &dyn ClassHierarchy, from: &str, to: &str) -> bool {
+    if from == to || to == JAVA_LANG_OBJECT_NAME {
+        return true;
+    }
+    let from_array = from.starts_with(JVM_SIGNATURE_ARRAY);
+    let to_array = to.starts_with(JVM_SIGNATURE_ARRAY);
+    if from_array && to_array {
+        // Arrays of primitives are only assignable to arrays of exactly the same primitive
+        let from_component = &from[1..];
+        let to_component = &to[1..];
+        return match (class_name_of(from_component), class_name_of(to_component)) {
+            (Some(from), Some(to)) => is_class_assignable(hierarchy, from, to),
+            _ => false
+        };
+    }
+    if from_array {
+        return to == JAVA_LANG_CLONEABLE_NAME || to == JAVA_IO_SERIALIZABLE_NAME || is_interface(hierarchy, to);
+    }
+    if to_array {
+        return false;
+    }
+    if is_interface(hierarchy, to) {
+        return true;
+    }
+    let mut current = IStr::new(from);
+    loop {
+        match hierarchy.lookup(&current) {
+            Some(info) => match info.super_class {
+                Some(super_class) if super_class == to => return true,
+                Some(super_class) => current = super_class,
+                None => return false
+            },
+            // A class we can't find can't be shown to be assignable, so it fails verification
+            None => return false
+        }
+    }
+}
+
+// Finds the most specific class that both classes extend, for merging two types where control flow joins.
+// Interfaces don't take part in this, so any two unrelated types merge to java/lang/Object.
+pub fn least_common_supertype(hierarchy: &dyn ClassHierarchy, first: &str, second: &str) -> IStr {
+    if first == second {
+        return IStr::new(first);
+    }
+    let first_array = first.starts_with(JVM_SIGNATURE_ARRAY);
+    let second_array = second.starts_with(JVM_SIGNATURE_ARRAY);
+    if first_array && second_array {
+        return match (class_name_of(&first[1..]), class_name_of(&second[1..])) {
+            (Some(first), Some(second)) => {
+                IStr::new(&array_of(&least_common_supertype(hierarchy, first, second)))
+            },
+            _ => IStr::new(JAVA_LANG_OBJECT_NAME)
+        };
+    }
+    if first_array || second_array || is_interface(hierarchy, first) || is_interface(hierarchy, second) {
+        return IStr::new(JAVA_LANG_OBJECT_NAME);
+    }
+    if hierarchy.is_subclass(first, second) {
+        return IStr::new(second);
+    }
+    if hierarchy.is_subclass(second, first) {
+        return IStr::new(first);
+    }
+    let mut current = IStr::new(first);
+    while let Some(super_class) = hierarchy.lookup(&current).and_then(|info| info.super_class) {
+        if hierarchy.is_subclass(second, &super_class) {
+            return super_class;
+        }
+        current = super_class;
+    }
+    IStr::new(JAVA_LANG_OBJECT_NAME)
+}
+
+fn is_interface(hierarchy: &dyn ClassHierarchy, name: &str) -> bool {
+    hierarchy.lookup(name).map_or(false, |info| info.is_interface)
+}
+
+// The state of the locals and the operand stack at a point in a method. Longs and doubles take up two
+// locals, with the second being top, but only a single entry on the stack, with the size of the stack
+// being tracked separately.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub(super) locals: Vec<VerifierType>,
+    pub(super) stack: Vec<VerifierType>,
+    pub(super) stack_size: usize
+}
+
+impl Frame {
+    pub fn new(max_locals: usize) -> Self {
+        Frame { locals: vec![VerifierType::Top; max_locals], stack: Vec::new(), stack_size: 0 }
+    }
+
+    pub fn locals(&self) -> &[VerifierType] {
+        &self.locals
+    }
+
+    pub fn stack(&self) -> &[VerifierType] {
+        &self.stack
+    }
+
+    // Whether this is part of a constructor that hasn't called its super constructor yet.
+    pub fn is_this_uninitialized(&self) -> bool {
+        self.locals.contains(&VerifierType::UninitializedThis)
+    }
+
+    pub(super) fn with_stack(&self, stack: Vec<VerifierType>) -> Self {
+        let stack_size = stack.iter().map(VerifierType::size).sum();
+        Frame { locals: self.locals.clone(), stack, stack_size }
+    }
+
+    // Checks whether this frame can flow into the other one, per frameIsAssignable in JVMS §4.10.1.4.
+    pub fn is_assignable_to(&self, other: &Frame, hierarchy: &dyn ClassHierarchy) -> bool {
+        self.locals.len() == other.locals.len() &&
+            self.stack.len() == other.stack.len() &&
+            self.locals.iter().zip(&other.locals).all(|(from, to)| is_assignable(hierarchy, from, to)) &&
+            self.stack.iter().zip(&other.stack).all(|(from, to)| is_assignable(hierarchy, from, to)) &&
+            (!self.is_this_uninitialized() || other.is_this_uninitialized())
+    }
+
+    pub(super) fn set_local(&mut self, index: usize, value: VerifierType) -> Result<(), String> {
+        let size = value.size();
+        if index + size > self.locals.len() {
+            return Err(format!("Local variable index {} is out of range for max_locals {}", index,
+                               self.locals.len()));
+        }
+        // Writing over half of a long or double leaves the other half unusable
+        if index > 0 && self.locals[index - 1].size() == 2 {
+            self.locals[index - 1] = VerifierType::Top;
+        }
+        if self.locals[index].size() == 2 && size == 1 {
+            self.locals[index + 1] = VerifierType::Top;
+        }
+        if size == 2 {
+            if self.locals[index + 1].size() == 2 && index + 2 < self.locals.len() {
+                self.locals[index + 2] = VerifierType::Top;
+            }
+            self.locals[index + 1] = VerifierType::Top;
+        }
+        self.locals[index] = value;
+        Ok(())
+    }
+
+    pub(super) fn get_local(&self, index: usize, size: usize) -> Result<&VerifierType, String> {
+        if index + size > self.locals.len() {
+            return Err(format!("Local variable index {} is out of range for max_locals {}", index,
+                               self.locals.len()));
+        }
+        Ok(&self.locals[index])
+    }
+
+    pub(super) fn push(&mut self, value: VerifierType, max_stack: usize) -> Result<(), String> {
+        self.stack_size += value.size();
+        if self.stack_size > max_stack {
+            return Err(format!("Operand stack overflow, max_stack is {}", max_stack));
+        }
+        self.stack.push(value);
+        Ok(())
+    }
+
+    pub(super) fn pop(&mut self) -> Result<VerifierType, String> {
+        let value = self.stack.pop().ok_or_else(|| String::from("Operand stack underflow"))?;
+        self.stack_size -= value.size();
+        Ok(value)
+    }
+
+    // Pops entries until the given number of slots have been removed, for the instructions like dup2 that
+    // work on slots rather than values. Splitting a long or double in half isn't allowed.
+    pub(super) fn pop_slots(&mut self, slots: usize) -> Result<Vec<VerifierType>, String> {
+        let mut result = Vec::new();
+        let mut popped = 0;
+        while popped < slots {
+            let value = self.pop()?;
+            popped += value.size();
+            result.push(value);
+        }
+        if popped != slots {
+            return Err(String::from("Attempt to split a long or double on the operand stack"));
+        }
+        result.reverse();
+        Ok(result)
+    }
+
+    // Replaces every occurrence of an uninitialized type once its constructor has been called.
+    pub(super) fn initialize(&mut self, uninitialized: &VerifierType, initialized: &VerifierType) {
+        for value in self.locals.iter_mut().chain(self.stack.iter_mut()) {
+            if value == uninitialized {
+                *value = initialized.clone();
+            }
+        }
+    }
+}
+
+// Merges the frame coming in to an instruction with the one it already has, returning the merged frame, or
+// None if the existing frame already covers the incoming one. Locals that can't be merged become unusable,
+// but the stack has to agree.
+pub(super) fn merge(
+    hierarchy: &dyn ClassHierarchy,
+    existing: &Frame,
+    incoming: &Frame
+) -> Result<Option<Frame>, String> {
+    if existing.stack.len() != incoming.stack.len() || existing.stack_size != incoming.stack_size {
+        return Err(format!("Inconsistent stack height {} != {}", existing.stack_size, incoming.stack_size));
+    }
+    let mut stack = Vec::with_capacity(existing.stack.len());
+    for (first, second) in existing.stack.iter().zip(&incoming.stack) {
+        let value = merge_type(hierarchy, first, second)
+            .ok_or_else(|| format!("Mismatched stack types {} and {}", first, second))?;
+        stack.push(value);
+    }
+    let locals = existing.locals.iter().zip(&incoming.locals)
+        .map(|(first, second)| merge_type(hierarchy, first, second).unwrap_or(VerifierType::Top))
+        .collect::<Vec<_>>();
+    if locals == existing.locals && stack == existing.stack {
+        return Ok(None);
+    }
+    Ok(Some(Frame { locals, stack, stack_size: existing.stack_size }))
+}
+
+fn merge_type(hierarchy: &dyn ClassHierarchy, first: &VerifierType, second: &VerifierType) -> Option<VerifierType> {
+    match (first, second) {
+        _ if first == second => Some(first.clone()),
+        (VerifierType::Null, VerifierType::Reference(_)) => Some(second.clone()),
+        (VerifierType::Reference(_), VerifierType::Null) => Some(first.clone()),
+        (VerifierType::Reference(first), VerifierType::Reference(second)) => {
+            Some(VerifierType::Reference(least_common_supertype(hierarchy, first, second)))
+        },
+        _ => None
+    }
+}